@@ -9,6 +9,10 @@ pub type CameraLed = Peri<'static, peripherals::PIN_23>;
 pub type PauseButton = Peri<'static, peripherals::PIN_19>;
 pub type HopperServo = Peri<'static, peripherals::PIN_18>;
 pub type ChutesServo = Peri<'static, peripherals::PIN_26>;
+// VSYS through the Pico's divide-by-three resistors.
+pub type VsysSense = Peri<'static, peripherals::PIN_29>;
+// Across a resistor in the servo supply's return, if one is fitted.
+pub type CurrentSense = Peri<'static, peripherals::PIN_27>;
 
 // I2C
 pub type I2cData = peripherals::PIN_12;
@@ -61,6 +65,10 @@ pub struct Board {
     pub camera_mclk_pwm: Peri<'static, peripherals::PWM_SLICE4>,
     pub camera_led_pwm: Peri<'static, peripherals::PWM_SLICE3>,
 
+    pub adc: Peri<'static, peripherals::ADC>,
+    pub vsys_sense: VsysSense,
+    pub current_sense: CurrentSense,
+
     pub i2c0: Peri<'static, peripherals::I2C0>,
     pub i2c_sda: Peri<'static, I2cData>,
     pub i2c_scl: Peri<'static, I2cClock>,
@@ -68,6 +76,10 @@ pub struct Board {
     pub cam_pins: OVCamPins,
 
     pub usb: Peri<'static, peripherals::USB>,
+
+    pub flash: Peri<'static, peripherals::FLASH>,
+
+    pub watchdog: Peri<'static, peripherals::WATCHDOG>,
 }
 
 impl Board {
@@ -88,6 +100,10 @@ impl Board {
             camera_mclk_pwm: p.PWM_SLICE4,
             camera_led_pwm: p.PWM_SLICE3,
 
+            adc: p.ADC,
+            vsys_sense: p.PIN_29,
+            current_sense: p.PIN_27,
+
             i2c0: p.I2C0,
             i2c_sda: p.PIN_12,
             i2c_scl: p.PIN_13,
@@ -108,6 +124,10 @@ impl Board {
             },
 
             usb: p.USB,
+
+            flash: p.FLASH,
+
+            watchdog: p.WATCHDOG,
         }
     }
 }
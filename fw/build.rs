@@ -1,7 +1,9 @@
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -17,4 +19,62 @@ fn main() {
     // It is good practice to add this line to prevent missing rebuilds when only `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
     println!("cargo:rerun-if-changed=build.rs");
+
+    // Identify the build for the `GetInfo` command.
+    println!("cargo:rustc-env=BEAD_SORTER_GIT_HASH={}", git_hash());
+    println!("cargo:rustc-env=BEAD_SORTER_BUILD_TIME={}", build_time());
+    println!(
+        "cargo:rustc-env=BEAD_SORTER_BSP_REVISION={}",
+        bsp_revision()
+    );
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=../bsp/Cargo.toml");
+    // Rebuild after a commit or checkout so the hash stays current.
+    for git in ["../.git/HEAD", "../.git/index"] {
+        if Path::new(git).exists() {
+            println!("cargo:rerun-if-changed={}", git);
+        }
+    }
+}
+
+/// Short hash of HEAD, with `-dirty` if the tree has changes; `unknown` outside a checkout.
+fn git_hash() -> String {
+    let git = |args: &[&str]| Command::new("git").args(args).output().ok();
+    let Some(head) = git(&["rev-parse", "--short=8", "HEAD"]).filter(|o| o.status.success()) else {
+        return "unknown".into();
+    };
+    let hash = String::from_utf8_lossy(&head.stdout).trim().to_string();
+    let dirty = git(&["status", "--porcelain"]).is_some_and(|o| !o.stdout.is_empty());
+    if dirty {
+        format!("{}-dirty", hash)
+    } else {
+        hash
+    }
+}
+
+/// Unix seconds; `SOURCE_DATE_EPOCH` wins for reproducible builds.
+fn build_time() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        })
+}
+
+/// The `version` of the bsp crate.
+fn bsp_revision() -> String {
+    let manifest = std::fs::read_to_string("../bsp/Cargo.toml").unwrap_or_default();
+    manifest
+        .lines()
+        .find_map(|line| {
+            let value = line
+                .strip_prefix("version")?
+                .trim_start()
+                .strip_prefix('=')?;
+            Some(value.trim().trim_matches('"').to_string())
+        })
+        .unwrap_or_else(|| "unknown".into())
 }
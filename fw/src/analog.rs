@@ -0,0 +1,89 @@
+//! Analog readings, sampled in the background by [`sampler`].
+//!
+//! A servo move can pull the supply down for only a few milliseconds, which a reading taken
+//! once per cycle would almost always miss. The sampler reads often and keeps the lowest
+//! value until the main loop takes it.
+//!
+//! It also watches the servo current, when a sense resistor is fitted, and stops the servos
+//! itself on a stall rather than leaving them to grind until the main loop next looks.
+
+use embassy_rp::adc::{Adc, Async, Channel};
+use embassy_time::{Duration, Ticker};
+use portable_atomic::{AtomicU16, Ordering};
+use sorter_logic::stall::{sense_mv, StallDetector};
+use sorter_logic::supply::vsys_mv;
+
+use crate::logging;
+use crate::servo;
+
+// With `STALL_SAMPLES`, a stall is 200 ms of high current after a move.
+const SAMPLE_PERIOD: Duration = Duration::from_millis(10);
+
+// Lowest VSYS since last taken, in millivolts; u16::MAX for none.
+static SUPPLY_LOW_MV: AtomicU16 = AtomicU16::new(u16::MAX);
+// `Setting::StallSenseMv`; 0 for no sense resistor.
+static STALL_SENSE_MV: AtomicU16 = AtomicU16::new(0);
+// Sense voltage of a stall not yet taken, in millivolts; 0 for none.
+static STALLED_MV: AtomicU16 = AtomicU16::new(0);
+
+/// The lowest supply voltage since the last call, in millivolts, or `None` if nothing has
+/// been read since.
+pub fn take_supply_low_mv() -> Option<u16> {
+    match SUPPLY_LOW_MV.swap(u16::MAX, Ordering::Relaxed) {
+        u16::MAX => None,
+        mv => Some(mv),
+    }
+}
+
+/// Current-sense voltage that means a stall; `None` when no sense resistor is fitted.
+pub fn set_stall_sense_mv(threshold_mv: Option<u16>) {
+    STALL_SENSE_MV.store(threshold_mv.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// The sense voltage, in millivolts, of a stall since the last call. The servos have
+/// already been stopped.
+pub fn take_stall_mv() -> Option<u16> {
+    match STALLED_MV.swap(0, Ordering::Relaxed) {
+        0 => None,
+        mv => Some(mv),
+    }
+}
+
+#[embassy_executor::task]
+pub async fn sampler(
+    mut adc: Adc<'static, Async>,
+    mut vsys: Channel<'static>,
+    mut current: Channel<'static>,
+) {
+    let mut ticker = Ticker::every(SAMPLE_PERIOD);
+    let mut stall = StallDetector::new(None);
+    let mut threshold = 0;
+    let mut failed = false;
+    loop {
+        ticker.next().await;
+        let readings = match adc.read(&mut vsys).await {
+            Ok(supply) => adc.read(&mut current).await.map(|sense| (supply, sense)),
+            Err(e) => Err(e),
+        };
+        match readings {
+            Ok((supply, sense)) => {
+                SUPPLY_LOW_MV.fetch_min(vsys_mv(supply), Ordering::Relaxed);
+                let next = STALL_SENSE_MV.load(Ordering::Relaxed);
+                if next != threshold {
+                    threshold = next;
+                    stall.set_threshold((threshold != 0).then_some(threshold));
+                }
+                let mv = sense_mv(sense);
+                if stall.record(mv, servo::any_moving()) {
+                    servo::emergency_stop();
+                    STALLED_MV.store(mv.max(1), Ordering::Relaxed);
+                }
+            }
+            Err(e) if !failed => {
+                logging::warn!("analog reading failed: {}", e);
+                failed = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
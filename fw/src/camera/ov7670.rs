@@ -1,15 +1,59 @@
+use core::future::Future;
+
+use embassy_futures::join::join;
 use embassy_rp::dma::Channel;
 use embassy_rp::i2c::{Async, I2c, Instance as I2cInstance};
 use embassy_rp::peripherals::PWM_SLICE4;
 use embassy_rp::pio::{Common, Instance as PioInstance, StateMachine};
 use embassy_rp::pwm::{Config as PwmConfig, Pwm};
 use embassy_rp::Peri;
+use embassy_time::{with_timeout, Duration, Timer};
+use sorter_logic::hdr::{self, EXPOSURE_STEP};
+use sorter_protocol::FRAME_BYTES;
 // use embedded_hal_async::i2c::I2c as I2cTrait; // Unused
 
 use crate::camera::dvp::Dvp;
 use crate::camera::sccb::Sccb;
+use crate::logging;
 use bead_sorter_bsp::OVCamPins;
 
+/// A 40x30 RGB565 frame, as the DMA delivers it.
+pub const FRAME_WORDS: usize = FRAME_BYTES / 4;
+/// Longest a capture may take; a frame normally arrives well within this.
+const CAPTURE_TIMEOUT: Duration = Duration::from_millis(1000);
+/// How long automatic exposure and gain get to settle before [`Ov7670::auto_exposure`] locks
+/// them.
+const EXPOSURE_SETTLE: Duration = Duration::from_millis(2000);
+/// How long an exposure change takes to reach a whole frame.
+const EXPOSURE_CHANGE: Duration = Duration::from_millis(100);
+/// How long the sensor gets to come out of standby, with its clock running again.
+const WAKE_SETTLE: Duration = Duration::from_millis(300);
+/// What the sensor reports in its `PID` register.
+pub const OV7670_PID: u8 = 0x76;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum CaptureError {
+    /// No frame arrived (no PCLK/VSYNC). The sensor was set up again; the next capture may
+    /// work.
+    Timeout,
+}
+
+// COM8 with fast AEC, unlimited AEC step and banding filter, and the automatic controls.
+const COM8_BASE: u8 = 0xC0 | 0x20;
+const COM8_AGC: u8 = 0x04;
+const COM8_AEC: u8 = 0x01;
+// COM2 soft sleep, over the 1x output drive of the configuration.
+const COM2_SOFT_SLEEP: u8 = 0x10;
+
+/// Exposure and gain, as the sensor's automatic controls left them or as locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Exposure {
+    /// Exposure time in rows, 16 bits over AECHH, AECH and COM1.
+    aec: u16,
+    /// Analog gain, 10 bits over VREF and GAIN.
+    gain: u16,
+}
+
 #[derive(Clone, Copy)]
 pub struct Register {
     pub addr: u8,
@@ -26,7 +70,12 @@ pub struct Ov7670<'d, PIO: PioInstance, I2C: I2cInstance, DMA: Channel, const SM
     dvp: Dvp<'d, PIO, SM>,
     sccb: Sccb<'d, I2C>,
     dma: Peri<'d, DMA>,
-    _mclk_pwm: Pwm<'d>,
+    mclk_pwm: Pwm<'d>,
+    mclk_config: PwmConfig,
+    // Put back after the sensor is set up again.
+    locked: Option<Exposure>,
+    // Fuse two exposures into each capture, while the exposure is locked.
+    hdr: bool,
 }
 
 impl<'d, PIO: PioInstance, I2C: I2cInstance, DMA: Channel, const SM: usize>
@@ -45,43 +94,11 @@ impl<'d, PIO: PioInstance, I2C: I2cInstance, DMA: Channel, const SM: usize>
         mclk_config.divider = fixed::FixedU16::from_num(1);
         mclk_config.top = 6; // ~17.8 MHz
         mclk_config.compare_a = 3; // Duty cycle 50%
-        let mclk_pwm = Pwm::new_output_a(mclk_slice, pins.mclk, mclk_config);
+        let mclk_pwm = Pwm::new_output_a(mclk_slice, pins.mclk, mclk_config.clone());
 
         // 2. Initialize SCCB
         let mut sccb_ctrl = Sccb::new(i2c);
-
-        // Soft Reset
-        sccb_ctrl.write_reg(reg::COM7, COM7_RESET).await.ok();
-        embassy_time::Timer::after(embassy_time::Duration::from_millis(100)).await;
-
-        // Write Init Sequence
-        for reg in ADAFRUIT_OV7670_INIT {
-            sccb_ctrl.write_reg(reg.addr, reg.val).await.ok();
-            embassy_time::Timer::after(embassy_time::Duration::from_micros(1000)).await;
-        }
-
-        for reg in OV7670_RGB565 {
-            sccb_ctrl.write_reg(reg.addr, reg.val).await.ok();
-            embassy_time::Timer::after(embassy_time::Duration::from_micros(1000)).await;
-        }
-
-        for reg in OV7670_DIV16_40X30 {
-            sccb_ctrl.write_reg(reg.addr, reg.val).await.ok();
-            embassy_time::Timer::after(embassy_time::Duration::from_micros(1000)).await;
-        }
-
-        // Wait for AEC/AGC to settle
-        embassy_time::Timer::after(embassy_time::Duration::from_millis(500)).await;
-
-        // Verify PID (0x76)
-        match sccb_ctrl.read_reg(reg::PID).await {
-            Ok(pid) => {
-                defmt::info!("OV7670 PID: 0x{:02x}", pid);
-            }
-            Err(_) => {
-                defmt::error!("OV7670 PID Read Failed!");
-            }
-        }
+        init_sensor(&mut sccb_ctrl).await;
 
         // 3. Initialize DVP (PIO)
         // Pass pins individually; Dvp::new handles conversion to PioPin
@@ -94,31 +111,223 @@ impl<'d, PIO: PioInstance, I2C: I2cInstance, DMA: Channel, const SM: usize>
             dvp,
             sccb: sccb_ctrl,
             dma,
-            _mclk_pwm: mclk_pwm,
+            mclk_pwm,
+            mclk_config,
+            locked: None,
+            hdr: false,
+        }
+    }
+
+    /// Capture one frame, fused from two exposures if [`Ov7670::set_hdr`] is on and the
+    /// exposure is locked.
+    pub async fn capture(&mut self, buf: &mut [u32; FRAME_WORDS]) -> Result<(), CaptureError> {
+        let Some(locked) = self.locked.filter(|_| self.hdr) else {
+            return self.capture_once(buf).await;
+        };
+        let step = EXPOSURE_STEP as u16;
+        let short = Exposure {
+            aec: (locked.aec / step).max(1),
+            ..locked
+        };
+        let long = Exposure {
+            aec: locked.aec.saturating_mul(step),
+            ..locked
+        };
+        let _ = self.write_exposure(short).await;
+        Timer::after(EXPOSURE_CHANGE).await;
+        let mut captured = self.capture_once(buf).await;
+        let mut long_buf = [0u32; FRAME_WORDS];
+        if captured.is_ok() {
+            let _ = self.write_exposure(long).await;
+            Timer::after(EXPOSURE_CHANGE).await;
+            captured = self.capture_once(&mut long_buf).await;
+        }
+        let _ = self.write_exposure(locked).await;
+        captured?;
+        let mut short = frame_bytes(buf);
+        hdr::merge(&mut short, &frame_bytes(&long_buf));
+        for (word, bytes) in buf.iter_mut().zip(short.chunks_exact(4)) {
+            *word = u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         }
+        Ok(())
+    }
+
+    /// Fuse a short and a long exposure into each capture from now on, once the exposure is
+    /// locked.
+    pub fn set_hdr(&mut self, on: bool) {
+        self.hdr = on;
     }
 
-    pub async fn capture(&mut self, buf: &mut [u32]) -> Result<(), ()> {
+    /// Capture one frame. If none arrives within [`CAPTURE_TIMEOUT`] the transfer is dropped
+    /// and the sensor set up again; the PIO is re-armed by the next capture.
+    async fn capture_once(&mut self, buf: &mut [u32]) -> Result<(), CaptureError> {
         // 1. Prepare DVP (PIO)
         self.dvp.prepare_capture();
-        self.dvp
-            .rx()
-            .dma_pull(self.dma.reborrow(), buf, false)
-            .await;
+        let pulled = with_timeout(
+            CAPTURE_TIMEOUT,
+            self.dvp.rx().dma_pull(self.dma.reborrow(), buf, false),
+        )
+        .await;
         self.dvp.stop();
+        if pulled.is_err() {
+            logging::error!("camera capture timed out, resetting the sensor");
+            init_sensor(&mut self.sccb).await;
+            if let Some(exposure) = self.locked {
+                let _ = self.write_exposure(exposure).await;
+            }
+            return Err(CaptureError::Timeout);
+        }
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub async fn enable_test_pattern(&mut self) {
-        // Enable Color Bar Test Pattern (Bit 7 of SCALING_XSC and SCALING_YSC)
-        // Assuming DIV16 40x30 config (0x40 base).
-        let val = 0x40 | 0x80;
+    /// Set the sensor up from scratch, with automatic exposure, e.g. to look again for a
+    /// camera that did not answer.
+    pub async fn reset(&mut self) {
+        self.locked = None;
+        init_sensor(&mut self.sccb).await;
+    }
+
+    /// Put the sensor in standby and stop its clock, for idle sleep. Nothing can be captured
+    /// until [`Ov7670::wake`].
+    pub async fn sleep(&mut self) {
+        let _ = self.sccb.write_reg(reg::COM2, COM2_SOFT_SLEEP).await;
+        let mut stopped = self.mclk_config.clone();
+        stopped.compare_a = 0;
+        self.mclk_pwm.set_config(&stopped);
+    }
+
+    /// Start the clock again and bring the sensor out of standby. It keeps its settings,
+    /// the locked exposure included.
+    pub async fn wake(&mut self) {
+        self.mclk_pwm.set_config(&self.mclk_config);
+        Timer::after(WAKE_SETTLE).await;
+        let _ = self.sccb.write_reg(reg::COM2, 0x00).await;
+        Timer::after(WAKE_SETTLE).await;
+    }
+
+    /// Capture into `buf` while `work` runs, so the caller can process the previous frame
+    /// from a second buffer while the DMA fills this one. Returns once both are done.
+    pub async fn capture_during<F: Future>(
+        &mut self,
+        buf: &mut [u32; FRAME_WORDS],
+        work: F,
+    ) -> (Result<(), CaptureError>, F::Output) {
+        join(self.capture(buf), work).await
+    }
+
+    /// The sensor's product id, or `None` if it does not answer.
+    pub async fn product_id(&mut self) -> Option<u8> {
+        self.sccb.read_reg(reg::PID).await.ok()
+    }
+
+    /// Let automatic exposure and gain settle under the current lighting, then lock them.
+    /// Light the LED first.
+    pub async fn auto_exposure(&mut self) {
+        self.unlock_exposure().await;
+        embassy_time::Timer::after(EXPOSURE_SETTLE).await;
+        match self.lock_exposure().await {
+            Some(e) => logging::info!("exposure locked: {} rows, gain {}", e.aec, e.gain),
+            None => logging::warn!("exposure not locked, the sensor did not answer"),
+        }
+    }
+
+    /// Turn off automatic exposure and gain and hold what they last chose, so colors do not
+    /// shift from bead to bead. `None` if the sensor does not answer.
+    async fn lock_exposure(&mut self) -> Option<Exposure> {
+        let exposure = self.read_exposure().await.ok()?;
+        self.write_exposure(exposure).await.ok()?;
+        self.locked = Some(exposure);
+        Some(exposure)
+    }
+
+    /// Hand exposure and gain back to the sensor's automatic controls.
+    async fn unlock_exposure(&mut self) {
+        self.locked = None;
+        let com8 = COM8_BASE | COM8_AGC | COM8_AEC;
+        let _ = self.sccb.write_reg(reg::COM8, com8).await;
+    }
+
+    async fn read_exposure(&mut self) -> Result<Exposure, embassy_rp::i2c::Error> {
+        let aechh = self.sccb.read_reg(reg::AECHH).await? as u16;
+        let aech = self.sccb.read_reg(reg::AECH).await? as u16;
+        let com1 = self.sccb.read_reg(reg::COM1).await? as u16;
+        let vref = self.sccb.read_reg(reg::VREF).await? as u16;
+        let gain = self.sccb.read_reg(reg::GAIN).await? as u16;
+        Ok(Exposure {
+            aec: (aechh & 0x3F) << 10 | aech << 2 | (com1 & 0x03),
+            gain: (vref & 0xC0) << 2 | gain,
+        })
+    }
+
+    // Manual exposure and gain: the automatic controls off, then the values.
+    async fn write_exposure(&mut self, e: Exposure) -> Result<(), embassy_rp::i2c::Error> {
+        self.sccb.write_reg(reg::COM8, COM8_BASE).await?;
+        let aechh = self.sccb.read_reg(reg::AECHH).await?;
+        let com1 = self.sccb.read_reg(reg::COM1).await?;
+        let vref = self.sccb.read_reg(reg::VREF).await?;
+        let aechh = (aechh & 0xC0) | (e.aec >> 10) as u8 & 0x3F;
+        let com1 = (com1 & 0xFC) | e.aec as u8 & 0x03;
+        let vref = (vref & 0x3F) | ((e.gain >> 2) as u8 & 0xC0);
+        self.sccb.write_reg(reg::AECHH, aechh).await?;
+        self.sccb.write_reg(reg::AECH, (e.aec >> 2) as u8).await?;
+        self.sccb.write_reg(reg::COM1, com1).await?;
+        self.sccb.write_reg(reg::VREF, vref).await?;
+        self.sccb.write_reg(reg::GAIN, e.gain as u8).await
+    }
+
+    /// Switch the 8-bar color bar test pattern on or off.
+    pub async fn set_test_pattern(&mut self, on: bool) {
+        // Bit 7 of SCALING_XSC and SCALING_YSC, over the DIV16 40x30 config (0x40 base).
+        let val = 0x40 | if on { 0x80 } else { 0 };
         let _ = self.sccb.write_reg(reg::SCALING_YSC, val).await;
         let _ = self.sccb.write_reg(reg::SCALING_XSC, val).await;
     }
 }
 
+/// A captured frame as the camera sent it: RGB565 big endian, row major.
+pub fn frame_bytes(buf: &[u32; FRAME_WORDS]) -> [u8; FRAME_BYTES] {
+    let mut bytes = [0u8; FRAME_BYTES];
+    for (dst, word) in bytes.chunks_exact_mut(4).zip(buf.iter()) {
+        dst.copy_from_slice(&word.to_ne_bytes());
+    }
+    bytes
+}
+
+/// Soft reset the sensor and load the 40x30 RGB565 configuration.
+async fn init_sensor<I2C: I2cInstance>(sccb: &mut Sccb<'_, I2C>) {
+    // Soft Reset
+    sccb.write_reg(reg::COM7, COM7_RESET).await.ok();
+    embassy_time::Timer::after(embassy_time::Duration::from_millis(100)).await;
+
+    // Write Init Sequence
+    for reg in ADAFRUIT_OV7670_INIT {
+        sccb.write_reg(reg.addr, reg.val).await.ok();
+        embassy_time::Timer::after(embassy_time::Duration::from_micros(1000)).await;
+    }
+
+    for reg in OV7670_RGB565 {
+        sccb.write_reg(reg.addr, reg.val).await.ok();
+        embassy_time::Timer::after(embassy_time::Duration::from_micros(1000)).await;
+    }
+
+    for reg in OV7670_DIV16_40X30 {
+        sccb.write_reg(reg.addr, reg.val).await.ok();
+        embassy_time::Timer::after(embassy_time::Duration::from_micros(1000)).await;
+    }
+
+    // Wait for AEC/AGC to settle
+    embassy_time::Timer::after(embassy_time::Duration::from_millis(500)).await;
+
+    match sccb.read_reg(reg::PID).await {
+        Ok(pid) => {
+            logging::info!("OV7670 PID: 0x{:02x}", pid);
+        }
+        Err(_) => {
+            logging::error!("OV7670 PID Read Failed!");
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub mod reg {
     // Register Addresses
@@ -274,7 +483,7 @@ pub const ADAFRUIT_OV7670_INIT: &[Register] = &[
     Register::new(reg::GAM_BASE + 12, 0xC4),
     Register::new(reg::GAM_BASE + 13, 0xD7),
     Register::new(reg::GAM_BASE + 14, 0xE8),
-    Register::new(reg::COM8, 0xC0 | 0x20), // FASTAEC, AECSTEP, BANDING
+    Register::new(reg::COM8, COM8_BASE), // FASTAEC, AECSTEP, BANDING
     Register::new(reg::GAIN, 0x00),
     Register::new(reg::COM2, 0x00), // Output Drive Capability 1x
     Register::new(reg::COM4, 0x00),
@@ -292,7 +501,7 @@ pub const ADAFRUIT_OV7670_INIT: &[Register] = &[
     Register::new(reg::HAECC5, 0xF0),
     Register::new(reg::HAECC6, 0x90),
     Register::new(reg::HAECC7, 0x94),
-    Register::new(reg::COM8, 0xC0 | 0x20 | 0x04 | 0x01), // + AGC, AEC (No AWB)
+    Register::new(reg::COM8, COM8_BASE | COM8_AGC | COM8_AEC), // + AGC, AEC (No AWB)
     Register::new(reg::COM5, 0x61),
     Register::new(reg::COM6, 0x4B),
     Register::new(0x16, 0x02),
@@ -0,0 +1,166 @@
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_rp::Peri;
+use sorter_logic::config::{self, MigrateError, CONFIG_BYTES, LAYOUT_OFFSET, SETTINGS_OFFSET};
+use sorter_logic::layout::{TubeLayout, LAYOUT_BYTES};
+use sorter_logic::profile::Profile;
+use sorter_logic::router::ROUTER_STATE_MAX;
+use sorter_logic::settings::{Settings, SETTINGS_BYTES};
+
+use crate::logging;
+
+const FLASH_SIZE: usize = 16 * 1024 * 1024;
+/// Settings live in the last flash sector, clear of the firmware image. See
+/// `sorter_logic::config` for what is where within it.
+const CONFIG_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+// The learned palette and tube map, in the sectors just below the config sector. Saved far
+// more often than the settings, so they get sectors of their own.
+const STATE_SECTORS: usize = ROUTER_STATE_MAX.div_ceil(ERASE_SIZE);
+const STATE_OFFSET: u32 = CONFIG_OFFSET - (STATE_SECTORS * ERASE_SIZE) as u32;
+
+pub struct ConfigStore {
+    flash: Flash<'static, FLASH, Blocking, FLASH_SIZE>,
+}
+
+impl ConfigStore {
+    pub fn new(flash: Peri<'static, FLASH>) -> Self {
+        Self {
+            flash: Flash::new_blocking(flash),
+        }
+    }
+
+    fn read(&mut self) -> Option<[u8; CONFIG_BYTES]> {
+        let mut bytes = [0u8; CONFIG_BYTES];
+        self.flash.blocking_read(CONFIG_OFFSET, &mut bytes).ok()?;
+        Some(bytes)
+    }
+
+    /// Bring a settings sector written by older firmware up to date. Run once at power-up,
+    /// before anything is read.
+    pub fn migrate(&mut self) {
+        let Some(mut bytes) = self.read() else {
+            return;
+        };
+        match config::migrate(&mut bytes) {
+            Ok(None) => {}
+            Ok(Some(from)) => {
+                let saved = self.write(&bytes);
+                logging::info!("config: migrated from version {} (saved: {})", from, saved);
+            }
+            Err(MigrateError::Newer(version)) => {
+                logging::warn!("config: version {} is newer than this firmware", version);
+            }
+        }
+    }
+
+    /// Load the tube layout, falling back to the default 30-tube build if none is stored or
+    /// it does not fit the servo ranges.
+    pub fn layout(&mut self, chute_range: (u16, u16), hopper_range: (u16, u16)) -> TubeLayout {
+        let Some(bytes) = self.read() else {
+            logging::warn!("tube layout: flash read failed, using default");
+            return TubeLayout::default();
+        };
+
+        let layout = match TubeLayout::from_bytes(&bytes[LAYOUT_OFFSET..]) {
+            Ok(layout) => layout,
+            Err(e) => {
+                logging::info!("tube layout: {}, using default", defmt::Debug2Format(&e));
+                return TubeLayout::default();
+            }
+        };
+
+        if !layout.fits(chute_range, hopper_range) {
+            logging::warn!("tube layout: position outside servo range, using default");
+            return TubeLayout::default();
+        }
+
+        logging::info!(
+            "tube layout: {} slices x {} rows",
+            layout.slices,
+            layout.rows
+        );
+        layout
+    }
+
+    /// Store the tube layout, keeping the rest of the settings sector.
+    pub fn save_layout(&mut self, layout: &TubeLayout) -> bool {
+        self.update(|bytes| {
+            bytes[LAYOUT_OFFSET..LAYOUT_OFFSET + LAYOUT_BYTES].copy_from_slice(&layout.to_bytes())
+        })
+    }
+
+    /// The stored sorting profile, or the default if none was saved.
+    pub fn profile(&mut self) -> Profile {
+        self.read()
+            .and_then(|bytes| config::profile(&bytes))
+            .unwrap_or_default()
+    }
+
+    /// Store the sorting profile, keeping the rest of the settings sector.
+    pub fn save_profile(&mut self, profile: Profile) -> bool {
+        self.update(|bytes| config::set_profile(bytes, profile))
+    }
+
+    /// The stored machine settings, or the defaults if none are stored or the record is
+    /// damaged.
+    pub fn settings(&mut self) -> Settings {
+        let Some(bytes) = self.read() else {
+            logging::warn!("settings: flash read failed, using defaults");
+            return Settings::default();
+        };
+        match Settings::from_bytes(&bytes[SETTINGS_OFFSET..]) {
+            Ok(settings) => settings,
+            Err(e) => {
+                logging::info!("settings: {}, using defaults", defmt::Debug2Format(&e));
+                Settings::default()
+            }
+        }
+    }
+
+    /// Store the machine settings, keeping the rest of the settings sector.
+    pub fn save_settings(&mut self, settings: &Settings) -> bool {
+        self.update(|bytes| {
+            bytes[SETTINGS_OFFSET..SETTINGS_OFFSET + SETTINGS_BYTES]
+                .copy_from_slice(&settings.to_bytes());
+        })
+    }
+
+    /// Read the saved router state (see `TubeRouter::encode_state`) into `buf`.
+    pub fn router_state(&mut self, buf: &mut [u8; ROUTER_STATE_MAX]) -> bool {
+        self.flash.blocking_read(STATE_OFFSET, buf).is_ok()
+    }
+
+    /// Replace the saved router state.
+    pub fn save_router_state(&mut self, state: &[u8]) -> bool {
+        self.flash
+            .blocking_erase(STATE_OFFSET, CONFIG_OFFSET)
+            .is_ok()
+            && self.flash.blocking_write(STATE_OFFSET, state).is_ok()
+    }
+
+    /// Erase the settings sector and the saved router state, so everything loads as the
+    /// defaults.
+    pub fn erase(&mut self) -> bool {
+        self.flash
+            .blocking_erase(STATE_OFFSET, CONFIG_OFFSET + ERASE_SIZE as u32)
+            .is_ok()
+    }
+
+    // Read the settings bytes, apply `change`, and rewrite the sector. Whatever wrote it
+    // before, the records in it are now this build's.
+    fn update(&mut self, change: impl FnOnce(&mut [u8; CONFIG_BYTES])) -> bool {
+        let Some(mut bytes) = self.read() else {
+            return false;
+        };
+        change(&mut bytes);
+        config::stamp(&mut bytes);
+        self.write(&bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8; CONFIG_BYTES]) -> bool {
+        self.flash
+            .blocking_erase(CONFIG_OFFSET, CONFIG_OFFSET + ERASE_SIZE as u32)
+            .is_ok()
+            && self.flash.blocking_write(CONFIG_OFFSET, bytes).is_ok()
+    }
+}
@@ -0,0 +1,218 @@
+//! Photographing the bead in the hopper slot, for
+//! [`SorterFsm::drive`](sorter_logic::cycle::SorterFsm::drive).
+//!
+//! An [`Inspector`] lasts one sort cycle. It streams each capture to the host, steers the
+//! camera LED, keeps the hopper refill monitor up to date and fuses the photos into
+//! [`Inspector::bead`] for the cycle to route. [`CameraRig`] is the camera and its LED.
+
+use embassy_rp::peripherals::{DMA_CH1, I2C0, PIO0};
+use embassy_rp::pwm::{Config as PwmConfig, Pwm};
+use embassy_time::Instant;
+use sorter_logic::cycle::Inspection;
+use sorter_logic::event_log::LogEvent;
+use sorter_logic::exposure::{LedControl, LED_TOP};
+use sorter_logic::hardware::{BeadCamera, Indicator};
+use sorter_logic::hopper::HopperEvent;
+use sorter_logic::settings::{Setting, MAX_CAMERA_FRAMES, MAX_RETAKES};
+use sorter_logic::text::{English, Locale, Msg};
+use sorter_logic::{background_patch, BeadAnalysis, FrameAccumulator, FRAME_AGREEMENT_THRESHOLD};
+use sorter_protocol::{Event, EVENT_PACKET_LEN, FRAME_BYTES};
+
+use crate::camera::ov7670::{frame_bytes, Ov7670, FRAME_WORDS};
+use crate::sequence::Sequencer;
+use crate::{logging, now_ms, protocol, NEOPIXEL_OFF};
+
+// Every frame of a bead and of its retakes.
+const SHOTS: usize = (MAX_CAMERA_FRAMES + MAX_RETAKES) as usize;
+
+/// The camera and the LED lighting the hopper slot.
+pub struct CameraRig {
+    pub camera: Ov7670<'static, PIO0, I2C0, DMA_CH1, 1>,
+    pub led_control: LedControl,
+    led: Pwm<'static>,
+    led_config: PwmConfig,
+}
+
+impl CameraRig {
+    /// `led_config` is what `led` was set up with.
+    pub fn new(
+        camera: Ov7670<'static, PIO0, I2C0, DMA_CH1, 1>,
+        led: Pwm<'static>,
+        led_config: PwmConfig,
+        led_control: LedControl,
+    ) -> Self {
+        Self {
+            camera,
+            led_control,
+            led,
+            led_config,
+        }
+    }
+
+    /// Light the slot at the duty the LED is steered to.
+    pub fn led_on(&mut self) {
+        self.set_led(self.led_control.duty());
+    }
+
+    pub fn led_off(&mut self) {
+        self.set_led(0);
+    }
+
+    fn set_led(&mut self, duty: u16) {
+        self.led_config.compare_b = duty;
+        self.led.set_config(&self.led_config);
+    }
+}
+
+pub struct Inspector<'a> {
+    /// Also for routing the bead and sending its dataset label, once it has been inspected.
+    pub seq: &'a mut Sequencer,
+    /// The first frame of the bead.
+    pub first: [u8; FRAME_BYTES],
+    /// The fused photos, once there are any.
+    pub bead: Option<BeadAnalysis>,
+    /// When the first frame came in.
+    pub photographed: Instant,
+    // Two capture buffers: the DMA fills one while the other is analyzed.
+    frames: [[u32; FRAME_WORDS]; 2],
+    shots: FrameAccumulator<SHOTS>,
+}
+
+impl<'a> Inspector<'a> {
+    /// `photographed` is the cycle start until a frame comes in.
+    pub fn new(seq: &'a mut Sequencer, cycle_start: Instant) -> Self {
+        Self {
+            seq,
+            first: [0; FRAME_BYTES],
+            bead: None,
+            photographed: cycle_start,
+            frames: [[0; FRAME_WORDS]; 2],
+            shots: FrameAccumulator::new(),
+        }
+    }
+
+    // The first photo: is there a bead, and what is it?
+    async fn first_look(&mut self) -> Inspection {
+        if let Err(e) = self.seq.rig.camera.capture(&mut self.frames[0]).await {
+            // The bead stays in the slot for the next pickup.
+            logging::warn!("no frame ({}), picking up again", e);
+            self.seq.event_log.push(now_ms(), LogEvent::CameraError);
+            return Inspection::NoFrame;
+        }
+        self.photographed = Instant::now();
+        self.first = frame_bytes(&self.frames[0]);
+
+        // Steer the LED for the next capture by the background patch.
+        let rig = &mut self.seq.rig;
+        if let Some(duty) =
+            background_patch(&self.first, 40, 30).and_then(|p| rig.led_control.update(p))
+        {
+            logging::debug!("camera LED duty {}/{}", duty, LED_TOP);
+            rig.set_led(duty);
+        }
+
+        // Stream every capture while the host holds DTR on the data port.
+        protocol::send_frame(&mut self.seq.data_tx, &self.first).await;
+
+        let empty = self.seq.sorter.is_slot_empty(&self.first, 40, 30);
+        if !empty {
+            self.seq.idle_since = Instant::now();
+        }
+        match self.seq.pickups.record(!empty) {
+            HopperEvent::RefillNeeded => {
+                logging::warn!("{=str}", English.msg(Msg::RefillHopper));
+            }
+            HopperEvent::Refilled => {
+                logging::info!("{=str}", English.msg(Msg::HopperRefilled));
+                self.seq.neopixel.show(NEOPIXEL_OFF).await;
+            }
+            HopperEvent::Stalled => {
+                logging::error!("{=str}", English.msg(Msg::HopperStalled));
+                self.seq.running = false;
+                let empty_pickups = self.seq.pickups.empty_streak();
+                self.seq
+                    .event_log
+                    .push(now_ms(), LogEvent::HopperStalled { empty_pickups });
+                let event = Event::HopperStalled { empty_pickups };
+                let mut packet = [0u8; EVENT_PACKET_LEN];
+                let len = event.encode(&mut packet);
+                protocol::send_packet(&mut self.seq.data_tx, &packet[..len]).await;
+            }
+            HopperEvent::None => {}
+        }
+        if empty {
+            return Inspection::Empty;
+        }
+
+        // Fuse a few back-to-back frames to average out sensor noise.
+        let camera_frames = self.seq.settings.get(Setting::CameraFrames) as usize;
+        for i in 0..camera_frames {
+            let [even, odd] = &mut self.frames;
+            let (done, next) = if i % 2 == 0 { (even, odd) } else { (odd, even) };
+            let bytes = frame_bytes(done);
+            let data_tx = &mut self.seq.data_tx;
+            let sorter = &mut self.seq.sorter;
+            let shots = &mut self.shots;
+            let work = async {
+                if i > 0 {
+                    protocol::send_frame(data_tx, &bytes).await;
+                }
+                match sorter.analyze(&bytes, 40, 30) {
+                    Some(analysis) => shots.push(analysis),
+                    None => shots.push_failed(),
+                };
+            };
+            if i + 1 == camera_frames {
+                work.await;
+            } else if self
+                .seq
+                .rig
+                .camera
+                .capture_during(next, work)
+                .await
+                .0
+                .is_err()
+            {
+                // Fuse the frames we have.
+                break;
+            }
+        }
+        self.fused()
+    }
+
+    // A retake of a doubtful bead, which the cycle has nudged and brought back.
+    async fn retake(&mut self, retake: u8) -> Inspection {
+        logging::info!("doubtful bead, retake {}", retake);
+        if self
+            .seq
+            .rig
+            .camera
+            .capture(&mut self.frames[0])
+            .await
+            .is_ok()
+        {
+            let bytes = frame_bytes(&self.frames[0]);
+            protocol::send_frame(&mut self.seq.data_tx, &bytes).await;
+            match self.seq.sorter.analyze(&bytes, 40, 30) {
+                Some(analysis) => self.shots.push(analysis),
+                None => self.shots.push_failed(),
+            };
+        }
+        self.fused()
+    }
+
+    fn fused(&mut self) -> Inspection {
+        self.bead = self.shots.fuse(FRAME_AGREEMENT_THRESHOLD);
+        let doubtful = self.seq.sorter.is_doubtful(self.bead.as_ref());
+        Inspection::Bead { doubtful }
+    }
+}
+
+impl BeadCamera for Inspector<'_> {
+    async fn capture(&mut self, retake: u8) -> Inspection {
+        match retake {
+            0 => self.first_look().await,
+            n => self.retake(n).await,
+        }
+    }
+}
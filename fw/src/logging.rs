@@ -0,0 +1,60 @@
+//! Log level that can be changed at runtime.
+//!
+//! `DEFMT_LOG` decides which messages are compiled in; the macros here drop those below the
+//! level the host last set with `Command::SetLogLevel` before they reach the USB console.
+//! [`LogLevel::Off`] silences the console altogether. The level starts at
+//! [`LogLevel::Trace`], so everything compiled in is logged until told otherwise.
+//!
+//! Only the level changes at runtime: output always goes to the USB console. Switching it to
+//! RTT, or mirroring to both, would take a second defmt global logger, an unsafe `Logger`
+//! impl, which is left out until that is agreed on.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub use sorter_protocol::LogLevel;
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Trace as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// True if a message at `level` should be logged.
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Error) {
+            ::defmt::error!($($arg)*);
+        }
+    };
+}
+
+// Named apart from the built-in `warn` attribute, and exported as `warn`.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Warn) {
+            ::defmt::warn!($($arg)*);
+        }
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Info) {
+            ::defmt::info!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Debug) {
+            ::defmt::debug!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {debug, error, info, warning as warn};
@@ -3,57 +3,92 @@
 
 use embassy_executor::Spawner;
 use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use embassy_rp::adc::{Adc, Channel as AdcChannel, Config as AdcConfig};
 use embassy_rp::bind_interrupts;
 use embassy_rp::gpio::{Input, Pull};
 use embassy_rp::peripherals::{PIO0, USB};
 use embassy_rp::pio::Pio;
 use embassy_rp::pio_programs::ws2812::{PioWs2812, PioWs2812Program};
-use embassy_rp::pwm::{Config as PwmConfig, Pwm};
+use embassy_rp::pwm::{Config as PwmConfig, Pwm, Slice};
 use embassy_rp::usb;
-use embassy_time::{Duration, Timer};
+use embassy_rp::watchdog::{ResetReason, Watchdog};
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
-use panic_probe as _;
 use static_cell::{ConstStaticCell, StaticCell};
 
+mod analog;
 mod camera;
+mod config;
+mod inspect;
+mod logging;
 mod neopixel;
+mod panic;
+mod planner;
+mod protocol;
+mod selftest;
+mod sequence;
 mod servo;
 mod sorter;
+mod stats;
 mod switch;
 
 use crate::camera::ov7670::Ov7670;
+use crate::config::ConfigStore;
+use crate::inspect::CameraRig;
 use crate::neopixel::Neopixel;
-use crate::servo::{Channel, Servo};
+use crate::planner::{Planner, Pose};
+use crate::sequence::{Machine, Sequencer};
+use crate::servo::{Channel, ServoDriver};
 use crate::sorter::BeadSorter;
 use crate::switch::Switch;
 
 use bead_sorter_bsp::Board;
-
-const HOPPER_MIN: u16 = 500;
-const HOPPER_MAX: u16 = 2266;
-
-// Hopper States
-const HOPPER_PICKUP_POS: u16 = 760;
-const HOPPER_CAMERA_POS: u16 = 1493;
-const HOPPER_ROW_POSITIONS: [u16; 4] = [2153, 2020, 1887, 1780];
-const HOPPER_DROP_POS: u16 = 1613;
-
-const CHUTES_MIN: u16 = 500;
-const CHUTES_MAX: u16 = 1167;
-
-const CHUTE_SLICE_POSITIONS: [u16; 15] = [
-    545, 586, 632, 675, 718, 762, 802, 842, 879, 920, 958, 999, 1041, 1085, 1132,
-];
-
-fn get_chute_pos(index: u8) -> u16 {
-    let slice_idx = index as usize % 15;
-    CHUTE_SLICE_POSITIONS[slice_idx]
+use sorter_logic::event_log::{EventLog, LogEvent};
+use sorter_logic::exposure::{LedControl, DEFAULT_LED_DUTY};
+use sorter_logic::hardware::Indicator;
+use sorter_logic::hopper::PickupMonitor;
+use sorter_logic::profile::Profile;
+use sorter_logic::settings::Setting;
+use sorter_logic::supply::SupplyMonitor;
+use sorter_logic::text::{English, Locale, Msg};
+use sorter_logic::Rgb;
+use sorter_protocol::SELF_TEST_PACKET_LEN;
+
+// Shown steadily after a failed self test.
+const SELF_TEST_FAIL_COLOR: Rgb = Rgb { r: 255, g: 0, b: 0 };
+const NEOPIXEL_OFF: Rgb = Rgb { r: 0, g: 0, b: 0 };
+
+fn profile_color(profile: Profile) -> Rgb {
+    match profile {
+        Profile::Learning => Rgb { r: 0, g: 0, b: 255 },
+        Profile::Production => Rgb { r: 0, g: 255, b: 0 },
+        Profile::Strict => Rgb {
+            r: 160,
+            g: 0,
+            b: 255,
+        },
+    }
 }
 
+// Holding the button at power-up and letting go within this long selects the next profile.
+const PROFILE_MENU_MS: u64 = 3000;
+// Holding it this long from power-up erases the stored configuration.
+const FACTORY_RESET_MS: u64 = 10_000;
+// Shown while the button is held past the profile menu, as a warning.
+const FACTORY_RESET_COLOR: Rgb = Rgb {
+    r: 255,
+    g: 0,
+    b: 255,
+};
+// Events kept for the host's log dump.
+const EVENT_LOG_LEN: usize = 64;
+
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => usb::InterruptHandler<USB>;
     PIO0_IRQ_0 => embassy_rp::pio::InterruptHandler<PIO0>;
     I2C0_IRQ => embassy_rp::i2c::InterruptHandler<embassy_rp::peripherals::I2C0>;
+    ADC_IRQ_FIFO => embassy_rp::adc::InterruptHandler;
 });
 
 static USB_CDC_ACM_STATE: StaticCell<State> = StaticCell::new();
@@ -75,6 +110,8 @@ async fn usb_defmt_logger(
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
     let board = Board::new(p);
+    let watchdog = Watchdog::new(board.watchdog);
+    let watchdog_reset = watchdog.reset_reason() == Some(ResetReason::TimedOut);
 
     // --- USB Setup ---
     let driver = embassy_rp::usb::Driver::new(board.usb, Irqs);
@@ -101,12 +138,24 @@ async fn main(spawner: Spawner) {
 
     let data_state = USB_DATA_CDC_ACM_STATE.init(State::new());
     let data_class = CdcAcmClass::new(&mut builder, data_state, 64);
-    let (mut data_tx, _data_rx) = data_class.split();
+    let (mut data_tx, data_rx, data_control) = data_class.split_with_control();
 
     let usb = builder.build();
     spawner.must_spawn(usb_defmt_logger(usb, tx));
-
-    defmt::info!("USB Logging initialized");
+    spawner.must_spawn(protocol::command_reader(data_rx, data_control));
+
+    logging::info!("USB Logging initialized");
+    let info = protocol::firmware_info();
+    logging::info!(
+        "firmware {=str}, protocol {}, bsp {=str}",
+        info.git_hash(),
+        info.protocol_version,
+        info.bsp_revision()
+    );
+    let last_panic = panic::take_last();
+    if let Some(message) = &last_panic {
+        logging::error!("panicked before this boot: {=str}", message.as_str());
+    }
 
     // 1. PIO0 (Shared by Neopixel and DVP)
     let mut pio = Pio::new(board.neopixel_pio, Irqs);
@@ -120,30 +169,50 @@ async fn main(spawner: Spawner) {
         board.neopixel,
         &program,
     );
-    let _neopixel: Neopixel<0, 1> = Neopixel::new(ws2812);
-
-    // 3. Servos (50Hz)
+    let mut neopixel: Neopixel<0, 1> = Neopixel::new(ws2812);
+
+    // 3. Servos (50Hz). Endpoints and stops come from the flash settings; changed endpoints
+    // take effect at the next boot.
+    let mut config = ConfigStore::new(board.flash);
+    config.migrate();
+    let settings = config.settings();
+    let (hopper_min, hopper_max) = settings.hopper_range();
+    let (chutes_min, chutes_max) = settings.chutes_range();
     let mut servo_config = PwmConfig::default();
     servo_config.divider = fixed::FixedU16::from_num(125); // 1MHz
     servo_config.top = 20000; // 20ms
 
     // Hopper (PWM Slice 1 A)
+    let hopper_slice = board.hopper_pwm.number();
     let hopper_pwm = Pwm::new_output_a(board.hopper_pwm, board.hopper_servo, servo_config.clone());
-    let mut hopper = Servo::new(hopper_pwm, Channel::A, HOPPER_MIN, HOPPER_MAX, 5250); // 2000us/s speed
+    let hopper = ServoDriver::new(hopper_pwm, hopper_slice, Channel::A, hopper_min, hopper_max);
+    let hopper = servo::spawn(spawner, &servo::HOPPER, hopper);
 
     // Chutes (PWM Slice 5 A)
+    let chutes_slice = board.chutes_pwm.number();
     let chutes_pwm = Pwm::new_output_a(board.chutes_pwm, board.chutes_servo, servo_config);
-    let mut chutes = Servo::new(chutes_pwm, Channel::A, CHUTES_MIN, CHUTES_MAX, 6000); // 2000us/s speed
+    let chutes = ServoDriver::new(chutes_pwm, chutes_slice, Channel::A, chutes_min, chutes_max);
+    let chutes = servo::spawn(spawner, &servo::CHUTES, chutes);
+    let planner = Planner::spawn(spawner, hopper, chutes);
 
-    // 4. Pause Switch
+    // 4. Button: a short press pauses and resumes, a long press retakes the empty slot
+    // reference, a double press toggles the dry run.
     let pause_input = Input::new(board.pause_button, Pull::Up);
-    let switch = Switch::new(pause_input);
+    let mut switch = Switch::new(pause_input);
+
+    // 6. Supply voltage (VSYS/3 on ADC3) and servo current (ADC1), sampled in the
+    // background.
+    let adc = Adc::new(board.adc, Irqs, AdcConfig::default());
+    let vsys = AdcChannel::new_pin(board.vsys_sense, Pull::None);
+    let current = AdcChannel::new_pin(board.current_sense, Pull::None);
+    analog::set_stall_sense_mv(settings.stall_sense_mv());
+    spawner.must_spawn(analog::sampler(adc, vsys, current));
 
     // 5. Camera LED (PWM Slice 3 B, Pin 23)
     let mut led_config = PwmConfig::default();
     led_config.divider = fixed::FixedU16::from_num(125); // 1MHz (1us tick)
     led_config.top = 1000; // 1kHz (1ms period)
-    led_config.compare_b = 500; // 50% Duty Cycle
+    led_config.compare_b = DEFAULT_LED_DUTY; // 50% Duty Cycle, until steered
     let mut led = Pwm::new_output_b(board.camera_led_pwm, board.camera_led, led_config.clone());
 
     // 7. I2C0 For ov7670 configuration
@@ -154,18 +223,25 @@ async fn main(spawner: Spawner) {
     let i2c =
         embassy_rp::i2c::I2c::new_async(board.i2c0, board.i2c_scl, board.i2c_sda, Irqs, i2c_config);
 
+    // 8. Tube layout and sorting profile (flash config)
+    let layout = config.layout((chutes_min, chutes_max), (hopper_min, hopper_max));
+    let mut profile = config.profile();
+
     // --- Tasks ---
     let main_fut = async {
-        // Ensure LED is ON (50%)
+        // Ensure LED is ON (50% until the first frame steers it)
         led.set_config(&led_config);
 
         // Homing
-        let chutes_fut = chutes.move_to(CHUTE_SLICE_POSITIONS[7]);
-        let hopper_align_fut = async {
-            hopper.move_to(HOPPER_DROP_POS).await;
-            Timer::after(Duration::from_millis(300)).await;
+        let chute_home = layout.chute_positions[layout.slices as usize / 2];
+        hopper.set_panic_park(settings.get(Setting::HopperPark));
+        chutes.set_panic_park(chute_home);
+        let home = Pose {
+            hopper: settings.get(Setting::HopperDrop),
+            chutes: chute_home,
         };
-        join(chutes_fut, hopper_align_fut).await;
+        planner.goto_pose(home).await;
+        Timer::after(Duration::from_millis(300)).await;
 
         // Initialize Ov7670 Camera
         let mut camera = Ov7670::new(
@@ -178,85 +254,118 @@ async fn main(spawner: Spawner) {
         )
         .await;
 
-        // Sorting State
-        let mut sorter = BeadSorter::new();
-
-        loop {
-            if switch.is_active() {
-                // Paused
-                // Turn OFF LED when paused
-                led_config.compare_b = 0;
-                led.set_config(&led_config);
-                defmt::info!("Paused");
-                Timer::after(Duration::from_millis(1000)).await;
-                continue;
-            }
-            // Turn ON LED (50%) when running
-            led_config.compare_b = 500;
-            led.set_config(&led_config);
-
-            // 1. Pickup Bead (Agitate to capture)
-            let pickup_center = HOPPER_PICKUP_POS;
-            hopper.move_to(pickup_center - 250).await;
-            hopper.move_to(pickup_center + 250).await;
-            hopper.move_to(pickup_center - 150).await;
-            hopper.move_to(pickup_center + 150).await;
-            hopper.move_to(pickup_center - 75).await;
-            hopper.move_to(pickup_center + 75).await;
-            hopper.move_to(pickup_center).await;
-            Timer::after(Duration::from_millis(100)).await;
-
-            // 2. Move to Camera
-            hopper.move_to(HOPPER_CAMERA_POS).await;
-            Timer::after(Duration::from_millis(200)).await; // Settle for stable image
-
-            let mut buf = [0u32; 600];
-            let _ = camera.capture(&mut buf).await;
-
-            // Safety: Transmuting valid u32 slice to u8 slice.
-            // The helper function keeps the lifetimes tied together.
-            unsafe fn u32_slice_to_u8_slice(input: &[u32]) -> &[u8] {
-                unsafe { core::slice::from_raw_parts(input.as_ptr() as *const u8, input.len() * 4) }
-            }
-            let buf_bytes = unsafe { u32_slice_to_u8_slice(&buf) };
-
-            if data_tx.dtr() {
-                // If host is connected to second ACM port, send image data
-                // Image data is a magic u32 followed by 1200 bytes of rgb565
-                // (30x40 pixels)
-                let header = [0xBE, 0xAD, 0x1F, 0x01];
-                let _ = data_tx.write_packet(&header).await;
-
-                // Write in chunks to avoid overwhelming USB buffer if necessary
-                for chunk in buf_bytes.chunks(64) {
-                    let _ = data_tx.write_packet(chunk).await;
+        // Profile menu: the button held at power-up and released again advances the profile.
+        // Held on past the menu until the warning color, it is a factory reset; let go before
+        // then and nothing changes.
+        if switch.is_active() {
+            match select(
+                switch.wait_for_inactive(),
+                Timer::after(Duration::from_millis(PROFILE_MENU_MS)),
+            )
+            .await
+            {
+                Either::First(()) => {
+                    profile = profile.next();
+                    if !config.save_profile(profile) {
+                        logging::warn!("Failed to save profile");
+                    }
+                }
+                Either::Second(()) => {
+                    neopixel.show(FACTORY_RESET_COLOR).await;
+                    if let Either::Second(()) = select(
+                        switch.wait_for_inactive(),
+                        Timer::after(Duration::from_millis(FACTORY_RESET_MS - PROFILE_MENU_MS)),
+                    )
+                    .await
+                    {
+                        factory_reset(&mut config).await;
+                    }
+                    neopixel.show(NEOPIXEL_OFF).await;
                 }
             }
+        }
+        logging::info!("{=str}: {=str}", English.msg(Msg::Profile), profile.name());
+        spawner.must_spawn(switch::gesture_reader(switch));
+        neopixel.show(profile_color(profile)).await;
+        Timer::after(Duration::from_millis(1000)).await;
 
-            let tube_index = sorter.get_tube_for_image(buf_bytes, 40, 30).unwrap_or(0);
-            let chute_target = get_chute_pos(tube_index);
-
-            let row_index = ((tube_index / 15) << 1) | ((tube_index % 15) & 1);
-            defmt::info!(
-                "Dropping bead into tube: {} row: {} chute: {}",
-                tube_index,
-                row_index,
-                chute_target
-            );
-            let drop_row = HOPPER_ROW_POSITIONS[row_index as usize];
-
-            let chutes_fut = chutes.move_to(chute_target);
-            let hopper_align_fut = async {
-                hopper.move_to(drop_row).await;
-                Timer::after(Duration::from_millis(200)).await;
-            };
-
-            join(chutes_fut, hopper_align_fut).await;
-
-            hopper.move_to(HOPPER_DROP_POS).await;
-            Timer::after(Duration::from_millis(350)).await;
+        // Sorting State
+        let mut sorter = BeadSorter::new(layout.tube_count());
+        sorter.apply_settings(&settings);
+        sorter.set_profile(profile);
+        sorter.load_learned(&mut config);
+        let mut pickups = PickupMonitor::default();
+        pickups.set_stall_after(settings.get(Setting::StallAfter));
+        let supply = SupplyMonitor::new(settings.brownout_mv());
+        let led_control = LedControl::new(settings.led_target_luma());
+        neopixel.show(NEOPIXEL_OFF).await;
+
+        // Power-on self test. After a failure the machine stays homed and will not start.
+        // Without a camera it keeps answering the host and looks for the camera again every
+        // so often, so the rest of the machine can still be set up and diagnosed.
+        let self_test = selftest::run(&mut camera, hopper, chutes, &mut neopixel).await;
+        let mut packet = [0u8; SELF_TEST_PACKET_LEN];
+        let len = self_test.encode(&mut packet);
+        protocol::send_packet(&mut data_tx, &packet[..len]).await;
+        if !self_test.passed() {
+            logging::error!("self test failed; halted");
+            neopixel.show(SELF_TEST_FAIL_COLOR).await;
+        }
+        // Automatic exposure and gain drift from bead to bead; hold what they settle on with
+        // the LED lit.
+        if selftest::camera_ok(&self_test) {
+            camera.auto_exposure().await;
         }
+        camera.set_hdr(settings.get(Setting::HdrCapture) == 1);
+
+        // Cleared by a host stop command. After a watchdog reboot the machine stays parked
+        // until told to start, rather than resuming whatever hung.
+        let running = !watchdog_reset && self_test.passed();
+        // Recent events, for post-mortem debugging from the host.
+        let mut event_log = EventLog::<EVENT_LOG_LEN>::new();
+        if watchdog_reset {
+            logging::error!("rebooted by the watchdog; parked until started");
+            event_log.push(now_ms(), LogEvent::WatchdogReset);
+        }
+
+        let machine = Machine {
+            watchdog,
+            config,
+            settings,
+            layout,
+            hopper,
+            chutes,
+            planner,
+            rig: CameraRig::new(camera, led, led_config, led_control),
+            data_tx,
+            neopixel,
+            sorter,
+            pickups,
+            supply,
+            event_log,
+            self_test,
+            last_panic,
+        };
+        Sequencer::new(machine, running).run().await
     };
 
     main_fut.await
 }
+
+/// Timestamp for the event log.
+fn now_ms() -> u32 {
+    Instant::now().as_millis() as u32
+}
+
+/// Erase the stored configuration and reboot with the defaults. Returns only if the erase
+/// failed.
+async fn factory_reset(config: &mut ConfigStore) {
+    logging::warn!("factory reset: erasing the stored configuration");
+    if !config.erase() {
+        logging::error!("factory reset failed, flash erase refused");
+        return;
+    }
+    // Give the log a moment to reach the host.
+    Timer::after(Duration::from_millis(100)).await;
+    cortex_m::peripheral::SCB::sys_reset();
+}
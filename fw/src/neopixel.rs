@@ -1,6 +1,9 @@
+use embassy_rp::pac;
 use embassy_rp::pio_programs::ws2812::{Grb, PioWs2812};
 
 use smart_leds::RGB8;
+use sorter_logic::hardware::Indicator;
+use sorter_logic::Rgb;
 
 pub struct Neopixel<'d, const SM_IDX: usize, const N: usize> {
     driver: PioWs2812<'d, embassy_rp::peripherals::PIO0, SM_IDX, N, Grb>,
@@ -30,3 +33,19 @@ impl<'d, const SM_IDX: usize, const N: usize> Neopixel<'d, SM_IDX, N> {
         }
     }
 }
+
+impl<const SM_IDX: usize> Indicator for Neopixel<'_, SM_IDX, 1> {
+    async fn show(&mut self, color: Rgb) {
+        self.write(&[RGB8::new(color.r, color.g, color.b)]).await;
+    }
+}
+
+/// Show `color` on the first LED of the neopixel on state machine `SM_IDX` of PIO0, pushing
+/// it straight into the state machine's FIFO. For the panic handler, which cannot wait on the
+/// DMA the driver uses.
+pub fn show_now<const SM_IDX: usize>(color: RGB8) {
+    let pio = pac::PIO0;
+    while pio.fstat().read().txfull() & (1 << SM_IDX) != 0 {}
+    let grb = (color.g as u32) << 24 | (color.r as u32) << 16 | (color.b as u32) << 8;
+    pio.txf(SM_IDX).write_value(grb);
+}
@@ -0,0 +1,120 @@
+//! The panic handler: parks the servos, flashes the neopixel red, and keeps the panic message
+//! for [`take_last`] to find after the reboot, so `Command::GetInfo` can report it.
+//!
+//! Nothing here can wait on a task, so the servos and the neopixel are driven through their
+//! registers. The message goes in the watchdog's scratch registers, which survive the
+//! watchdog reset that reboots the board once nothing feeds it, and are cleared at power-up.
+//! A panic before the watchdog is started flashes until the board is power cycled.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_rp::pac;
+use smart_leds::RGB8;
+use sorter_protocol::PANIC_TEXT_LEN;
+
+use crate::logging;
+use crate::{neopixel, servo};
+
+// In scratch 0 while scratches 1 to 7 hold a message. The boot ROM only acts on scratch 4
+// holding its own magic, which is not UTF-8.
+const MAGIC: u32 = u32::from_le_bytes(*b"PANC");
+const _: () = assert!(PANIC_TEXT_LEN == 7 * 4);
+const PANIC_COLOR: RGB8 = RGB8::new(255, 0, 0);
+// Half a flash, in cycles at 125 MHz.
+const FLASH_CYCLES: u32 = 125_000_000 / 4;
+
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// A panic message, cut short to [`PANIC_TEXT_LEN`] bytes.
+#[derive(Default)]
+pub struct Message {
+    bytes: [u8; PANIC_TEXT_LEN],
+    len: usize,
+}
+
+impl Message {
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let end = self.len + c.len_utf8();
+            if end > PANIC_TEXT_LEN {
+                return Err(fmt::Error);
+            }
+            c.encode_utf8(&mut self.bytes[self.len..end]);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+/// The message of a panic before this boot, if there was one. Only the first call after the
+/// reboot gets it.
+pub fn take_last() -> Option<Message> {
+    let watchdog = pac::WATCHDOG;
+    if watchdog.scratch0().read() != MAGIC {
+        return None;
+    }
+    watchdog.scratch0().write_value(0);
+    let mut message = Message::default();
+    for (chunk, scratch) in message.bytes.chunks_exact_mut(4).zip(scratches()) {
+        chunk.copy_from_slice(&scratch.read().to_le_bytes());
+    }
+    message.len = message
+        .bytes
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(PANIC_TEXT_LEN);
+    Some(message)
+}
+
+fn save(message: &Message) {
+    for (chunk, scratch) in message.bytes.chunks_exact(4).zip(scratches()) {
+        scratch.write_value(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+    }
+    pac::WATCHDOG.scratch0().write_value(MAGIC);
+}
+
+// The scratch registers that hold the message.
+fn scratches() -> [pac::common::Reg<u32, pac::common::RW>; 7] {
+    let watchdog = pac::WATCHDOG;
+    [
+        watchdog.scratch1(),
+        watchdog.scratch2(),
+        watchdog.scratch3(),
+        watchdog.scratch4(),
+        watchdog.scratch5(),
+        watchdog.scratch6(),
+        watchdog.scratch7(),
+    ]
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    // A panic while handling one goes straight to flashing.
+    if !PANICKED.load(Ordering::Relaxed) {
+        PANICKED.store(true, Ordering::Relaxed);
+        servo::park_now();
+        let mut message = Message::default();
+        if let Some(location) = info.location() {
+            let file = location.file().rsplit('/').next().unwrap_or_default();
+            let _ = write!(message, "{}:{} ", file, location.line());
+        }
+        let _ = write!(message, "{}", info.message());
+        save(&message);
+        logging::error!("panicked: {}", defmt::Display2Format(info));
+    }
+    let mut on = true;
+    loop {
+        neopixel::show_now::<0>(if on { PANIC_COLOR } else { RGB8::default() });
+        on = !on;
+        cortex_m::asm::delay(FLASH_CYCLES);
+    }
+}
@@ -0,0 +1,210 @@
+//! Moving the hopper and chutes together.
+//!
+//! A drop needs both servos in place, and whichever has the shorter move only has to get there
+//! as the other does. The [`Planner`] slows it down to arrive together, which is gentler on the
+//! servo and the bead in the hopper slot and costs no time.
+//!
+//! While sorting, the chutes are a stage of their own: [`chutes_stage`] takes jobs from the
+//! sort loop over a channel. It turns the chutes to the next tube while the hopper carries the
+//! bead to its drop row, and holds them still while a dropped bead falls through, so the hopper
+//! can start the next pickup as soon as the bead has left its slot. [`CyclePositioner`]
+//! carries out the sort cycle's moves with them.
+
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use sorter_logic::cycle::{HopperMove, HOPPER_RELEASE_MS};
+use sorter_logic::hardware::Positioner;
+
+use crate::servo::{self, Motion, Servo, Speed};
+
+const HOPPER_SPEED: Speed = Speed::Normal;
+const CHUTES_SPEED: Speed = Speed::Fast;
+// After a drop the hopper moves on once the bead has left its slot (`HOPPER_RELEASE_MS`),
+// starting the next pickup while the bead is still falling; the chutes hold still until it
+// has cleared them.
+const CHUTES_CLEAR_MS: u64 = 350;
+
+static CHUTES_JOBS: Channel<CriticalSectionRawMutex, ChutesJob, 2> = Channel::new();
+// Answers a `ChutesJob::Deliver` as soon as it is planned.
+static CHUTES_PLANNED: Signal<CriticalSectionRawMutex, ChutesPlan> = Signal::new();
+// Raised when the chutes have finished a `Deliver` or a `Clear`.
+static CHUTES_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Where both servos should be, in microseconds.
+#[derive(Clone, Copy)]
+pub struct Pose {
+    pub hopper: u16,
+    pub chutes: u16,
+}
+
+enum ChutesJob {
+    /// Turn to `us` once the last bead has cleared, arriving no sooner than the hopper does
+    /// at `hopper_arrival`.
+    Deliver { us: u16, hopper_arrival: Instant },
+    /// The hopper let go of a bead at this instant; hold still until it has fallen through.
+    Released(Instant),
+    /// Finish once the last bead has cleared.
+    Clear,
+}
+
+// What the chutes stage made of a `ChutesJob::Deliver`.
+#[derive(Clone, Copy)]
+struct ChutesPlan {
+    arrival: Instant,
+    // Time the stages saved by running side by side: the part of the last bead's fall the
+    // hopper spent on the next pickup, and the chutes' move made while the hopper travelled.
+    overlap: Duration,
+}
+
+#[derive(Clone, Copy)]
+pub struct Planner {
+    hopper: Servo,
+    chutes: Servo,
+}
+
+impl Planner {
+    /// Start the [`chutes_stage`] task for `chutes`.
+    pub fn spawn(spawner: Spawner, hopper: Servo, chutes: Servo) -> Self {
+        spawner.must_spawn(chutes_stage(chutes));
+        Self { hopper, chutes }
+    }
+
+    /// Move both servos to `pose` so they arrive at the same time, as soon as both can, and
+    /// return once they have (or were stopped). For moves outside a sort cycle, which do not
+    /// wait for a falling bead.
+    pub async fn goto_pose(&self, pose: Pose) {
+        let now = Instant::now();
+        let hopper_arrival = now
+            + self
+                .hopper
+                .duration_to(pose.hopper, HOPPER_SPEED, Motion::EaseOut);
+        let chutes_arrival = now
+            + self
+                .chutes
+                .duration_to(pose.chutes, CHUTES_SPEED, Motion::EaseOut);
+        let arrival = hopper_arrival.max(chutes_arrival);
+        join(
+            self.hopper
+                .move_lasting(pose.hopper, HOPPER_SPEED, Motion::EaseOut, arrival - now),
+            self.chutes
+                .move_lasting(pose.chutes, CHUTES_SPEED, Motion::EaseOut, arrival - now),
+        )
+        .await;
+    }
+
+    /// Carry a bead to `pose` with the chutes stage, and return once both servos are there
+    /// (or were stopped) with the time saved by overlapping the stages.
+    pub async fn deliver(&self, pose: Pose) -> Duration {
+        CHUTES_PLANNED.reset();
+        CHUTES_DONE.reset();
+        let hopper_arrival = Instant::now()
+            + self
+                .hopper
+                .duration_to(pose.hopper, HOPPER_SPEED, Motion::EaseOut);
+        CHUTES_JOBS
+            .send(ChutesJob::Deliver {
+                us: pose.chutes,
+                hopper_arrival,
+            })
+            .await;
+        let plan = CHUTES_PLANNED.wait().await;
+        let duration = plan.arrival.saturating_duration_since(Instant::now());
+        self.hopper
+            .move_lasting(pose.hopper, HOPPER_SPEED, Motion::EaseOut, duration)
+            .await;
+        CHUTES_DONE.wait().await;
+        plan.overlap
+    }
+
+    /// Tell the chutes stage the hopper has just let go of a bead.
+    pub async fn released(&self) {
+        CHUTES_JOBS.send(ChutesJob::Released(Instant::now())).await;
+    }
+
+    /// Wait until the last dropped bead has cleared the chutes.
+    pub async fn chutes_clear(&self) {
+        CHUTES_DONE.reset();
+        CHUTES_JOBS.send(ChutesJob::Clear).await;
+        CHUTES_DONE.wait().await;
+    }
+}
+
+/// The chutes' stage of the sort pipeline, taking jobs from the [`Planner`].
+#[embassy_executor::task]
+async fn chutes_stage(chutes: Servo) {
+    // When the last dropped bead will have cleared the chutes.
+    let mut free_at: Option<Instant> = None;
+    loop {
+        match CHUTES_JOBS.receive().await {
+            ChutesJob::Deliver { us, hopper_arrival } => {
+                let now = Instant::now();
+                // Whatever is left of the previous bead's fall; the rest overlapped.
+                let held = free_at.take().map(|at| at.saturating_duration_since(now));
+                let start = now + held.unwrap_or_default();
+                let travel = chutes.duration_to(us, CHUTES_SPEED, Motion::EaseOut);
+                let arrival = hopper_arrival.max(start + travel);
+                let fall = Duration::from_millis(CHUTES_CLEAR_MS - HOPPER_RELEASE_MS as u64);
+                let saved_fall = held.map_or(Duration::from_ticks(0), |h| {
+                    fall.checked_sub(h).unwrap_or_default()
+                });
+                // Done one after the other, the chutes' move would have followed the hopper's.
+                let hopper_travel = hopper_arrival.saturating_duration_since(now);
+                let overlap = saved_fall + travel.min(hopper_travel);
+                CHUTES_PLANNED.signal(ChutesPlan { arrival, overlap });
+                Timer::at(start).await;
+                chutes
+                    .move_lasting(us, CHUTES_SPEED, Motion::EaseOut, arrival - start)
+                    .await;
+                CHUTES_DONE.signal(());
+            }
+            ChutesJob::Released(at) => {
+                free_at = Some(at + Duration::from_millis(CHUTES_CLEAR_MS));
+            }
+            ChutesJob::Clear => {
+                if let Some(at) = free_at.take() {
+                    Timer::at(at).await;
+                }
+                CHUTES_DONE.signal(());
+            }
+        }
+    }
+}
+
+/// The sort cycle's moves, for [`SorterFsm::drive`](sorter_logic::cycle::SorterFsm::drive).
+pub struct CyclePositioner {
+    pub planner: Planner,
+    /// How agitating pickups ramp.
+    pub agitation: Motion,
+    /// Time the cycle saved by overlapping with the one before, for the stats.
+    pub overlap: Duration,
+}
+
+impl Positioner for CyclePositioner {
+    async fn move_hopper(&mut self, us: u16, how: HopperMove) {
+        let hopper = self.planner.hopper;
+        match how {
+            HopperMove::Agitate => hopper.move_with(us, Speed::Normal, self.agitation).await,
+            HopperMove::Approach => hopper.move_to(us, Speed::Gentle).await,
+            HopperMove::Release => {
+                hopper.move_to(us, Speed::Normal).await;
+                self.planner.released().await;
+            }
+        }
+    }
+
+    async fn move_to_pose(&mut self, hopper: u16, chutes: u16) {
+        self.overlap += self.planner.deliver(Pose { hopper, chutes }).await;
+    }
+
+    async fn settle(&mut self, ms: u32) {
+        Timer::after(Duration::from_millis(ms as u64)).await;
+    }
+
+    fn is_stopped(&self) -> bool {
+        servo::is_stopped()
+    }
+}
@@ -0,0 +1,226 @@
+//! Host commands on the data port.
+//!
+//! The wire format (command frames, reply magics) is in `sorter_protocol`.
+//! [`command_reader`] parses the port and queues [`Request`]s on [`COMMANDS`]; the sort loop
+//! carries them out between beads. Frame acknowledgements are handled by the reader itself,
+//! so a waiting [`send_frame`] sees them right away, and so is a request for the bootloader:
+//! [`Command::ResetToBootloader`], or the host opening the port at [`BOOTLOADER_BAUD`].
+//! [`Command::Stop`] is an emergency stop: the reader stops the servos itself before queueing
+//! it.
+
+use core::cell::Cell;
+
+use embassy_futures::select::{select, Either};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Timer};
+use embassy_usb::class::cdc_acm::{ControlChanged, Receiver, Sender};
+use sorter_logic::dataset::{self, Label};
+use sorter_protocol::{image, Command, CycleRecord, Info, Parser, RECORD_PACKET_MAX};
+
+use crate::logging;
+use crate::servo;
+use crate::sorter::COMMAND_BUFFER_LEN;
+
+/// A parsed command, with the size of the USB read it arrived in and how many commands were
+/// queued once it was (for telemetry).
+#[derive(Debug, Clone, Copy)]
+pub struct Request {
+    pub command: Command,
+    pub read_len: usize,
+    pub queued: usize,
+}
+
+/// Capacity of [`COMMANDS`].
+pub const COMMAND_QUEUE_LEN: usize = 4;
+
+pub static COMMANDS: Channel<CriticalSectionRawMutex, Request, COMMAND_QUEUE_LEN> = Channel::new();
+
+/// Opening the data port at this baud rate reboots into the bootloader (the "1200 baud
+/// touch" picotool and the Arduino tools use).
+pub const BOOTLOADER_BAUD: u32 = 1200;
+
+/// How long a frame waits for the host to acknowledge an earlier one before the device
+/// assumes the acks were lost and sends anyway.
+const FRAME_ACK_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy)]
+struct FlowControl {
+    // 0 when off.
+    window: u8,
+    in_flight: u8,
+}
+
+static FLOW: Mutex<CriticalSectionRawMutex, Cell<FlowControl>> =
+    Mutex::new(Cell::new(FlowControl {
+        window: 0,
+        in_flight: 0,
+    }));
+static FRAME_ACKED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn update_flow(f: impl FnOnce(&mut FlowControl)) -> FlowControl {
+    FLOW.lock(|cell| {
+        let mut flow = cell.get();
+        f(&mut flow);
+        cell.set(flow);
+        flow
+    })
+}
+
+// Wait for room in the host's window and count one more frame in flight.
+async fn take_frame_credit() {
+    loop {
+        let flow = update_flow(|_| {});
+        if flow.window == 0 || flow.in_flight < flow.window {
+            break;
+        }
+        if with_timeout(FRAME_ACK_TIMEOUT, FRAME_ACKED.wait())
+            .await
+            .is_err()
+        {
+            logging::warn!("no frame ack from the host, resetting the frame window");
+            update_flow(|f| f.in_flight = 0);
+        }
+    }
+    update_flow(|f| f.in_flight = f.in_flight.saturating_add(1));
+}
+
+#[embassy_executor::task]
+pub async fn command_reader(
+    mut rx: Receiver<'static, Driver<'static, USB>>,
+    control: ControlChanged<'static>,
+) {
+    let mut parser = Parser::new();
+    let mut buf = [0u8; COMMAND_BUFFER_LEN];
+    loop {
+        rx.wait_connection().await;
+        loop {
+            let n = match select(rx.read_packet(&mut buf), control.control_changed()).await {
+                Either::First(Ok(n)) => n,
+                Either::First(Err(_)) => break,
+                Either::Second(()) => {
+                    if rx.line_coding().data_rate() == BOOTLOADER_BAUD {
+                        reset_to_bootloader().await;
+                    }
+                    continue;
+                }
+            };
+            for &byte in &buf[..n] {
+                let Some(command) = parser.push(byte) else {
+                    continue;
+                };
+                match command {
+                    Command::FrameAck => {
+                        update_flow(|f| f.in_flight = f.in_flight.saturating_sub(1));
+                        FRAME_ACKED.signal(());
+                    }
+                    Command::FrameWindow(window) => {
+                        update_flow(|f| {
+                            *f = FlowControl {
+                                window,
+                                in_flight: 0,
+                            }
+                        });
+                        FRAME_ACKED.signal(());
+                        logging::info!("frame window {}", window);
+                    }
+                    Command::ResetToBootloader => reset_to_bootloader().await,
+                    _ => {
+                        if command == Command::Stop {
+                            // Right away, not once the sort loop gets to it.
+                            servo::emergency_stop();
+                        }
+                        // Counting this one, which the sort loop cannot have taken yet.
+                        let queued = COMMANDS.len() + 1;
+                        COMMANDS
+                            .send(Request {
+                                command,
+                                read_len: n,
+                                queued: queued.min(COMMAND_QUEUE_LEN),
+                            })
+                            .await
+                    }
+                }
+            }
+        }
+        // Disconnected mid-frame: start over. The next host turns flow control on itself.
+        parser = Parser::new();
+        update_flow(|f| {
+            *f = FlowControl {
+                window: 0,
+                in_flight: 0,
+            }
+        });
+    }
+}
+
+/// This build's identity, from the environment `build.rs` sets up.
+pub fn firmware_info() -> Info {
+    let build_time = env!("BEAD_SORTER_BUILD_TIME").parse().unwrap_or(0);
+    Info::new(
+        build_time,
+        env!("BEAD_SORTER_GIT_HASH"),
+        env!("BEAD_SORTER_BSP_REVISION"),
+    )
+}
+
+/// Reboot into the RP2040's USB mass-storage bootloader, for reflashing.
+async fn reset_to_bootloader() -> ! {
+    logging::warn!("rebooting into the USB bootloader");
+    // Give the log a moment to reach the host.
+    Timer::after(Duration::from_millis(100)).await;
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+    // The ROM does not return.
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Write `packet` in USB-sized chunks. Does nothing unless the host holds DTR.
+pub async fn send_packet(tx: &mut Sender<'static, Driver<'static, USB>>, packet: &[u8]) {
+    if !tx.dtr() {
+        return;
+    }
+    for chunk in packet.chunks(64) {
+        let _ = tx.write_packet(chunk).await;
+    }
+}
+
+/// Send what a sort cycle did as a `sorter_protocol::record` packet.
+pub async fn send_record(tx: &mut Sender<'static, Driver<'static, USB>>, record: &CycleRecord) {
+    let mut packet = [0u8; RECORD_PACKET_MAX];
+    let len = record.encode(&mut packet);
+    send_packet(tx, &packet[..len]).await;
+}
+
+/// Send a captured frame as a `sorter_protocol::image` packet (header, frame, CRC). With flow
+/// control on, first waits for the host to make room.
+pub async fn send_frame(tx: &mut Sender<'static, Driver<'static, USB>>, frame: &[u8]) {
+    if !tx.dtr() {
+        return;
+    }
+    take_frame_credit().await;
+    send_packet(tx, &image::header(frame.len() as u16)).await;
+    send_packet(tx, frame).await;
+    send_packet(tx, &image::crc(frame)).await;
+}
+
+/// Send a frame and what the sorter made of it as one `sorter_logic::dataset` packet.
+pub async fn send_dataset(
+    tx: &mut Sender<'static, Driver<'static, USB>>,
+    label: &Label,
+    frame: &[u8],
+) {
+    if !tx.dtr() {
+        return;
+    }
+    take_frame_credit().await;
+    let header = dataset::header(label, frame.len() as u16);
+    send_packet(tx, &header).await;
+    send_packet(tx, frame).await;
+    send_packet(tx, &dataset::crc(&header, frame)).await;
+}
@@ -0,0 +1,95 @@
+use embassy_rp::dma::Channel as DmaChannel;
+use embassy_rp::i2c::Instance as I2cInstance;
+use embassy_rp::pio::Instance as PioInstance;
+use embassy_time::{Duration, Timer};
+use smart_leds::RGB8;
+use sorter_logic::test_pattern::{color_bars_matched, COLOR_BARS};
+use sorter_protocol::{SelfTestItem, SelfTestReport};
+
+use crate::camera::ov7670::{frame_bytes, Ov7670, OV7670_PID};
+use crate::logging;
+use crate::neopixel::Neopixel;
+use crate::servo::{Servo, Speed};
+
+/// Check the camera, sweep the servos and cycle the neopixel, logging each result. The
+/// servos end where they started.
+///
+/// Hobby servos and the neopixel report nothing back, so those items pass once the moves and
+/// writes finish; they are there for whoever is watching the machine.
+pub async fn run<PIO: PioInstance, I2C: I2cInstance, DMA: DmaChannel, const SM: usize>(
+    camera: &mut Ov7670<'_, PIO, I2C, DMA, SM>,
+    hopper: Servo,
+    chutes: Servo,
+    neopixel: &mut Neopixel<'_, 0, 1>,
+) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    check_camera(camera, &mut report).await;
+
+    for (item, servo) in [
+        (SelfTestItem::HopperServo, hopper),
+        (SelfTestItem::ChutesServo, chutes),
+    ] {
+        let home = servo.position();
+        let (min, max) = servo.range();
+        servo.move_to(min, Speed::Normal).await;
+        let reached_min = servo.position() == min;
+        servo.move_to(max, Speed::Normal).await;
+        let reached_max = servo.position() == max;
+        servo.move_to(home, Speed::Normal).await;
+        record(&mut report, item, reached_min && reached_max);
+    }
+
+    for color in [
+        RGB8::new(255, 0, 0),
+        RGB8::new(0, 255, 0),
+        RGB8::new(0, 0, 255),
+    ] {
+        neopixel.write(&[color]).await;
+        Timer::after(Duration::from_millis(200)).await;
+    }
+    neopixel.write(&[RGB8::new(0, 0, 0)]).await;
+    record(&mut report, SelfTestItem::Neopixel, true);
+
+    report
+}
+
+/// Check the camera's id and test pattern, recording both in `report`.
+pub async fn check_camera<PIO: PioInstance, I2C: I2cInstance, DMA: DmaChannel, const SM: usize>(
+    camera: &mut Ov7670<'_, PIO, I2C, DMA, SM>,
+    report: &mut SelfTestReport,
+) {
+    let pid = camera.product_id().await;
+    record(report, SelfTestItem::CameraId, pid == Some(OV7670_PID));
+
+    camera.set_test_pattern(true).await;
+    // Let the pattern reach a whole frame.
+    Timer::after(Duration::from_millis(100)).await;
+    let mut buf = [0u32; 600];
+    let matched = match camera.capture(&mut buf).await {
+        Ok(()) => color_bars_matched(&frame_bytes(&buf), 40, 30),
+        Err(_) => 0,
+    };
+    camera.set_test_pattern(false).await;
+    logging::debug!("color bars: {} of {}", matched, COLOR_BARS.len());
+    record(
+        report,
+        SelfTestItem::TestPattern,
+        matched == COLOR_BARS.len(),
+    );
+}
+
+/// True unless the camera was checked and failed.
+pub fn camera_ok(report: &SelfTestReport) -> bool {
+    [SelfTestItem::CameraId, SelfTestItem::TestPattern]
+        .into_iter()
+        .all(|item| report.result(item) != Some(false))
+}
+
+fn record(report: &mut SelfTestReport, item: SelfTestItem, passed: bool) {
+    report.record(item, passed);
+    if passed {
+        logging::info!("self test {=str}: pass", item.name());
+    } else {
+        logging::error!("self test {=str}: FAIL", item.name());
+    }
+}
@@ -0,0 +1,294 @@
+//! Host commands on the data port, handled between sort cycles.
+
+use sorter_logic::collect::{Collection, Extraction};
+use sorter_logic::event_log::{self, LogEvent};
+use sorter_logic::layout::LAYOUT_PACKET_LEN;
+use sorter_logic::profile::Profile;
+use sorter_logic::settings::{Setting, SETTINGS_PACKET_MAX};
+use sorter_logic::telemetry::TELEMETRY_PACKET_MAX;
+use sorter_logic::text::{English, Locale, Msg};
+use sorter_protocol::{
+    Command, ServoId, ServoPosition, INFO_PACKET_LEN, SERVO_PACKET_LEN, STATUS_PACKET_LEN,
+};
+
+use super::Sequencer;
+use crate::camera::ov7670::frame_bytes;
+use crate::protocol::{self, Request};
+use crate::servo::Speed;
+use crate::stats::STATS_PACKET_MAX;
+use crate::{analog, factory_reset, logging, now_ms, selftest, sorter, EVENT_LOG_LEN};
+
+impl Sequencer {
+    /// Carry out a command the command reader queued.
+    pub(super) async fn command(&mut self, request: Request) {
+        self.sorter.record_command(request.read_len, request.queued);
+        match request.command {
+            Command::SetProfile(id) => {
+                let Some(p) = Profile::from_id(id) else {
+                    logging::warn!("unknown profile id {}", id);
+                    return;
+                };
+                self.sorter.set_profile(p);
+                if p != Profile::Learning && self.sorter.is_learning() {
+                    logging::warn!("palette is empty, learning one first");
+                }
+                if !self.config.save_profile(p) {
+                    logging::warn!("Failed to save profile");
+                }
+                logging::info!("{=str}: {=str}", English.msg(Msg::Profile), p.name());
+            }
+            Command::ExportInventory => {
+                let mut packet = [0u8; sorter::INVENTORY_PACKET_MAX];
+                let len = self.sorter.encode_inventory(&mut packet);
+                protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+                logging::info!("Sent inventory ({} bytes)", len);
+            }
+            Command::Telemetry => {
+                let mut packet = [0u8; TELEMETRY_PACKET_MAX];
+                let len = self.sorter.encode_telemetry(&mut packet);
+                protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+                logging::info!("Sent telemetry ({} bytes)", len);
+            }
+            Command::Start => self.start(),
+            // The command reader has already stopped the servos.
+            Command::Stop => {
+                self.running = false;
+                self.event_log.push(now_ms(), LogEvent::EmergencyStop);
+            }
+            Command::RequestFrame if !selftest::camera_ok(&self.self_test) => {
+                logging::warn!("no camera, no frame to send");
+            }
+            Command::RequestFrame => {
+                let mut buf = [0u32; 600];
+                if self.rig.camera.capture(&mut buf).await.is_ok() {
+                    protocol::send_frame(&mut self.data_tx, &frame_bytes(&buf)).await;
+                }
+            }
+            Command::QueryStatus => self.send_status().await,
+            Command::Collect { count, .. } if count > 0 && self.sorter.reject_tube().is_none() => {
+                logging::warn!("no reject tube to pass the other beads to, not collecting");
+            }
+            Command::Collect { target, count } => {
+                self.collection = (count > 0).then(|| Collection::new(target, count));
+                self.extraction = None;
+                self.event_log.push(now_ms(), LogEvent::Collect { count });
+                logging::info!(
+                    "collecting {} beads of {}",
+                    count,
+                    defmt::Debug2Format(&target)
+                );
+            }
+            Command::Extract { l, a, b, tolerance } => {
+                self.extraction = (tolerance > 0).then(|| Extraction::new((l, a, b), tolerance));
+                self.collection = None;
+                let extracting = self.extraction.is_some();
+                self.event_log.push(now_ms(), LogEvent::Extract(extracting));
+                logging::info!("extracting ({}, {}, {}) within {}", l, a, b, tolerance);
+            }
+            Command::AutoExposure if !selftest::camera_ok(&self.self_test) => {
+                logging::warn!("no camera to set the exposure of");
+            }
+            Command::AutoExposure => {
+                self.rig.led_on();
+                self.rig.camera.auto_exposure().await;
+                self.recalibrate = true;
+            }
+            Command::DumpLog => {
+                let mut packet = [0u8; event_log::packet_len(EVENT_LOG_LEN)];
+                let len = self.event_log.encode(&mut packet);
+                protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+                logging::info!("Sent event log ({} entries)", self.event_log.len());
+            }
+            Command::SetLogLevel(level) => {
+                logging::set_level(level);
+                // Logged whatever the level, so the console shows what happened.
+                defmt::info!("log level {=str}", level.name());
+            }
+            Command::GetSelfTest => self.send_self_test().await,
+            Command::GetInfo => {
+                let mut packet = [0u8; INFO_PACKET_LEN];
+                let mut info = protocol::firmware_info();
+                if let Some(message) = &self.last_panic {
+                    info = info.with_panic(message.as_str());
+                }
+                let len = info.encode(&mut packet);
+                protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+            }
+            Command::SetThresholds {
+                match_threshold,
+                merge_margin,
+            } => {
+                self.sorter
+                    .set_thresholds(match_threshold as u32, merge_margin as u32);
+                logging::info!(
+                    "thresholds: match {} merge {}",
+                    match_threshold,
+                    merge_margin
+                );
+            }
+            Command::GetSettings => {
+                let mut packet = [0u8; SETTINGS_PACKET_MAX];
+                let len = self.settings.encode(&mut packet);
+                protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+            }
+            Command::SetSetting { id, value } | Command::TuneSetting { id, value } => {
+                let save = matches!(request.command, Command::SetSetting { .. });
+                self.set_setting(id, value, save);
+            }
+            Command::SaveSettings => {
+                if !self.config.save_settings(&self.settings) {
+                    logging::warn!("Failed to save settings");
+                }
+            }
+            Command::GetStats => {
+                let mut packet = [0u8; STATS_PACKET_MAX];
+                let sorter = &self.sorter;
+                let len = self.stats.encode(|t| sorter.purity(t), &mut packet);
+                protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+            }
+            Command::UploadChunk(chunk) => {
+                let data = chunk.bytes();
+                let at = chunk.offset as usize;
+                match self.upload.get_mut(at..at + data.len()) {
+                    Some(dst) => dst.copy_from_slice(data),
+                    None => logging::warn!("upload chunk at {} out of range", at),
+                }
+            }
+            Command::LoadPalette { len } => {
+                let state = self.upload.get(..len as usize).unwrap_or(&[]);
+                self.sorter.load_palette(state, &mut self.config);
+                self.send_status().await;
+            }
+            Command::SetServo { servo, .. } | Command::GetServo(servo) => {
+                if let Command::SetServo { us, .. } = request.command {
+                    // Hand the machine to the host until the next start.
+                    self.running = false;
+                    match servo {
+                        ServoId::Hopper => self.hopper.move_to(us, Speed::Normal).await,
+                        ServoId::Chutes => self.chutes.move_to(us, Speed::Normal).await,
+                    }
+                    logging::info!("jog {=str} to {}", servo.name(), us);
+                }
+                let us = match servo {
+                    ServoId::Hopper => self.hopper.position(),
+                    ServoId::Chutes => self.chutes.position(),
+                };
+                let mut packet = [0u8; SERVO_PACKET_LEN];
+                let len = ServoPosition { servo, us }.encode(&mut packet);
+                protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+            }
+            Command::DatasetMode(on) => {
+                self.dataset_mode = on;
+                self.event_log.push(now_ms(), LogEvent::DatasetMode(on));
+                logging::info!("dataset mode {}", on);
+            }
+            // Taken care of by the command reader.
+            Command::FrameWindow(_) | Command::FrameAck | Command::ResetToBootloader => {}
+            Command::Home => self.planner.goto_pose(self.home()).await,
+            Command::FactoryReset => {
+                self.hopper.relax().await;
+                self.chutes.relax().await;
+                factory_reset(&mut self.config).await;
+            }
+            Command::GetLayout => {
+                let mut packet = [0u8; LAYOUT_PACKET_LEN];
+                let len = self.pending_layout.encode(&mut packet);
+                protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+            }
+            Command::SetLayoutShape { slices, rows } => {
+                self.pending_layout.slices = slices;
+                self.pending_layout.rows = rows;
+            }
+            Command::SetChutePosition { slice, us } => {
+                match self.pending_layout.chute_positions.get_mut(slice as usize) {
+                    Some(p) => *p = us,
+                    None => logging::warn!("no chute slice {}", slice),
+                }
+            }
+            Command::SetDropPosition { row, us } => {
+                match self.pending_layout.drop_positions.get_mut(row as usize) {
+                    Some(p) => *p = us,
+                    None => logging::warn!("no drop row {}", row),
+                }
+            }
+            Command::SaveLayout => self.save_layout(),
+        }
+    }
+
+    async fn send_status(&mut self) {
+        let mut packet = [0u8; STATUS_PACKET_LEN];
+        let len = self
+            .sorter
+            .status(self.running, &self.supply)
+            .encode(&mut packet);
+        protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+    }
+
+    // Change a setting and pass it on to whatever it tunes; `save` also writes the settings to
+    // flash.
+    fn set_setting(&mut self, id: u8, value: u16, save: bool) {
+        let Some(setting) = Setting::from_id(id) else {
+            logging::warn!("unknown setting id {}", id);
+            return;
+        };
+        if self.settings.set(setting, value).is_err() {
+            logging::warn!("rejected {=str} = {}", setting.name(), value);
+            return;
+        }
+        self.sorter.apply_settings(&self.settings);
+        if self.collection.is_some() && self.sorter.reject_tube().is_none() {
+            logging::warn!("no reject tube left, collection ended");
+            self.collection = None;
+            self.event_log
+                .push(now_ms(), LogEvent::Collect { count: 0 });
+        }
+        self.pickups
+            .set_stall_after(self.settings.get(Setting::StallAfter));
+        self.supply.set_threshold(self.settings.brownout_mv());
+        analog::set_stall_sense_mv(self.settings.stall_sense_mv());
+        self.hopper
+            .set_panic_park(self.settings.get(Setting::HopperPark));
+        self.rig
+            .led_control
+            .set_target(self.settings.led_target_luma());
+        if setting == Setting::HdrCapture {
+            // The empty slot looks different through the other capture mode.
+            self.rig.camera.set_hdr(value == 1);
+            self.recalibrate = true;
+        }
+        if save && !self.config.save_settings(&self.settings) {
+            logging::warn!("Failed to save settings");
+        }
+        logging::info!("{=str} = {} (saved: {})", setting.name(), value, save);
+    }
+
+    fn save_layout(&mut self) {
+        let pending = self.pending_layout;
+        if let Err(e) = pending.validate() {
+            logging::warn!("layout not saved: {}", defmt::Debug2Format(&e));
+            return;
+        }
+        if !pending.fits(self.settings.chutes_range(), self.settings.hopper_range()) {
+            logging::warn!("layout not saved: position outside servo range");
+            return;
+        }
+        if !self.config.save_layout(&pending) {
+            logging::warn!("Failed to save layout");
+            return;
+        }
+        // The sorter and stats are sized by the tube count, so a new shape waits for a
+        // restart.
+        if (pending.slices, pending.rows) == (self.layout.slices, self.layout.rows) {
+            self.layout = pending;
+            self.chute_home = pending.chute_positions[pending.slices as usize / 2];
+            self.chutes.set_panic_park(self.chute_home);
+            logging::info!("layout saved");
+        } else {
+            logging::info!(
+                "layout saved: {} slices x {} rows after a restart",
+                pending.slices,
+                pending.rows
+            );
+        }
+    }
+}
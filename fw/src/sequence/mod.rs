@@ -0,0 +1,691 @@
+//! The sort loop: host commands, button gestures, pausing, parking and sleeping, and one sort
+//! cycle per pass.
+//!
+//! `main` sets the machine up and hands it over as a [`Machine`]; [`Sequencer::run`] then
+//! runs it for good. Host commands are handled in [`commands`].
+
+use embassy_futures::select::select;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_rp::watchdog::Watchdog;
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::class::cdc_acm::Sender;
+use sorter_logic::button::Gesture;
+use sorter_logic::collect::{Collection, Extraction};
+use sorter_logic::cycle::{
+    Action, CycleConfig, CycleEnd, CycleError, Event as CycleEvent, SorterFsm,
+};
+use sorter_logic::dataset::{Label, Measurement};
+use sorter_logic::event_log::{EventLog, LogEvent};
+use sorter_logic::hardware::Indicator;
+use sorter_logic::hopper::PickupMonitor;
+use sorter_logic::layout::TubeLayout;
+use sorter_logic::router::ROUTER_STATE_MAX;
+use sorter_logic::settings::{Setting, Settings};
+use sorter_logic::supply::{SupplyEvent, SupplyMonitor};
+use sorter_logic::telemetry::Bounded;
+use sorter_logic::text::{English, Locale, Msg};
+use sorter_logic::Rgb;
+use sorter_protocol::{
+    CycleRecord, CycleResult, Event, SelfTestReport, EVENT_PACKET_LEN, SELF_TEST_PACKET_LEN,
+};
+
+use crate::camera::ov7670::frame_bytes;
+use crate::config::ConfigStore;
+use crate::inspect::{CameraRig, Inspector};
+use crate::neopixel::Neopixel;
+use crate::panic::Message;
+use crate::planner::{CyclePositioner, Planner, Pose};
+use crate::servo::{self, Motion, Servo, Speed};
+use crate::sorter::BeadSorter;
+use crate::stats::{Outcome, Stats};
+use crate::{analog, logging, now_ms, protocol, selftest, switch, EVENT_LOG_LEN, NEOPIXEL_OFF};
+
+mod commands;
+
+// While waiting for a refill, probe with a pickup this often.
+const REFILL_RETRY_SECS: u32 = 5;
+// Save the learned palette at most this often while sorting (and whenever paused), to spare
+// the flash.
+const SAVE_LEARNED_SECS: u64 = 300;
+// Log the sorting stats this often while sorting.
+const STATS_LOG_SECS: u64 = 60;
+// How long a servo takes to get back to its parked position after being relaxed.
+const UNPARK_SETTLE_MS: u64 = 300;
+const REFILL_COLOR: Rgb = Rgb {
+    r: 255,
+    g: 100,
+    b: 0,
+};
+const STALL_COLOR: Rgb = Rgb { r: 255, g: 0, b: 0 };
+// Blinked three times a second while the camera is missing.
+const NO_CAMERA_COLOR: Rgb = Rgb {
+    r: 0,
+    g: 255,
+    b: 255,
+};
+// How often to look for a missing camera again.
+const CAMERA_RETRY_SECS: u64 = 10;
+// Shown steadily, and dimly, while asleep.
+const ASLEEP_COLOR: Rgb = Rgb { r: 0, g: 0, b: 8 };
+// Reboot if the sorting loop goes this long without completing a pass (a sorting cycle, a
+// skipped pickup or a paused tick). Close to the RP2040's 8.3 s limit, as a cycle with every
+// retake can take several seconds.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+// Servo speeds, in percent, while the supply is below `Setting::BrownoutMv`.
+const SAG_SPEED_PERCENT: u32 = 60;
+
+/// The machine as `main` leaves it: set up, homed and self tested.
+pub struct Machine {
+    pub watchdog: Watchdog,
+    pub config: ConfigStore,
+    pub settings: Settings,
+    pub layout: TubeLayout,
+    pub hopper: Servo,
+    pub chutes: Servo,
+    pub planner: Planner,
+    pub rig: CameraRig,
+    pub data_tx: Sender<'static, Driver<'static, USB>>,
+    pub neopixel: Neopixel<'static, 0, 1>,
+    pub sorter: BeadSorter,
+    pub pickups: PickupMonitor,
+    pub supply: SupplyMonitor,
+    pub event_log: EventLog<EVENT_LOG_LEN>,
+    pub self_test: SelfTestReport,
+    /// What the firmware panicked with before this boot, for the info packet.
+    pub last_panic: Option<Message>,
+}
+
+/// Runs the sort loop, and keeps what it needs from one pass to the next.
+pub struct Sequencer {
+    watchdog: Watchdog,
+    config: ConfigStore,
+    pub settings: Settings,
+    layout: TubeLayout,
+    // Where the chutes rest, over the middle slice.
+    chute_home: u16,
+    hopper: Servo,
+    chutes: Servo,
+    planner: Planner,
+    pub rig: CameraRig,
+    pub data_tx: Sender<'static, Driver<'static, USB>>,
+    pub neopixel: Neopixel<'static, 0, 1>,
+    pub sorter: BeadSorter,
+    stats: Stats,
+    pub pickups: PickupMonitor,
+    supply: SupplyMonitor,
+    pub event_log: EventLog<EVENT_LOG_LEN>,
+    self_test: SelfTestReport,
+    last_panic: Option<Message>,
+    last_camera_retry: Instant,
+    last_save: Instant,
+    last_stats_log: Instant,
+    // Capture the empty slot reference at the top of the loop: first thing, after a long
+    // press, and after the exposure changes.
+    recalibrate: bool,
+    // Toggled by a short press.
+    paused: bool,
+    // Toggled by a double press: beads are analyzed but all go to the reject tube, and
+    // nothing is learned.
+    dry_run: bool,
+    /// Cleared by a host stop command or a hopper stall.
+    pub running: bool,
+    // Send a labeled capture for every bead.
+    dataset_mode: bool,
+    // Set by a host collect command: only beads it wants are sorted, until it has enough.
+    collection: Option<Collection>,
+    // Set by a host extract command: matching beads go to tube 0, the rest to the last tube.
+    extraction: Option<Extraction>,
+    // The layout the host is editing, saved by a save layout command.
+    pending_layout: TubeLayout,
+    // Filled by upload chunks, for a following load command.
+    upload: [u8; ROUTER_STATE_MAX],
+    // Beads sorted since the last start, and when it was, for the run limit.
+    run_beads: u32,
+    run_started: Instant,
+    // The hopper is at its park stop (and the servos maybe relaxed) while paused.
+    parked: bool,
+    /// Since the last bead, press or host command, for idle sleep.
+    pub idle_since: Instant,
+    // Camera in standby, servos limp and the LEDs dimmed until a press or a command.
+    asleep: bool,
+}
+
+impl Sequencer {
+    /// Sorting starts at once if `running`, otherwise on a press or a host start command.
+    pub fn new(machine: Machine, running: bool) -> Self {
+        let Machine {
+            watchdog,
+            config,
+            settings,
+            layout,
+            hopper,
+            chutes,
+            planner,
+            rig,
+            data_tx,
+            neopixel,
+            sorter,
+            pickups,
+            supply,
+            event_log,
+            self_test,
+            last_panic,
+        } = machine;
+        Self {
+            watchdog,
+            config,
+            settings,
+            layout,
+            chute_home: layout.chute_positions[layout.slices as usize / 2],
+            hopper,
+            chutes,
+            planner,
+            rig,
+            data_tx,
+            neopixel,
+            stats: Stats::new(layout.tube_count()),
+            sorter,
+            pickups,
+            supply,
+            event_log,
+            recalibrate: self_test.passed(),
+            self_test,
+            last_panic,
+            last_camera_retry: Instant::now(),
+            last_save: Instant::now(),
+            last_stats_log: Instant::now(),
+            paused: false,
+            dry_run: false,
+            running,
+            dataset_mode: false,
+            collection: None,
+            extraction: None,
+            pending_layout: layout,
+            upload: [0; ROUTER_STATE_MAX],
+            run_beads: 0,
+            run_started: Instant::now(),
+            parked: false,
+            idle_since: Instant::now(),
+            asleep: false,
+        }
+    }
+
+    pub async fn run(mut self) -> ! {
+        // Pause the count while a debugger halts the core, so stepping through doesn't reboot.
+        self.watchdog.pause_on_debug(true);
+        self.watchdog.start(WATCHDOG_TIMEOUT);
+        loop {
+            // The previous pass completed.
+            self.watchdog.feed();
+            self.sorter.record(Bounded::EventLog, self.event_log.len());
+            self.sorter
+                .record(Bounded::ServoCommands, servo::take_queue_peak());
+
+            // A press or a host command wakes the machine, then is handled as usual.
+            if !protocol::COMMANDS.is_empty() || !switch::GESTURES.is_empty() {
+                self.idle_since = Instant::now();
+                if self.asleep {
+                    self.wake().await;
+                }
+            }
+
+            // Host commands on the data port, queued by the command reader.
+            while let Ok(request) = protocol::COMMANDS.try_receive() {
+                self.command(request).await;
+            }
+
+            // Button gestures, queued by the gesture reader.
+            while let Ok(gesture) = switch::GESTURES.try_receive() {
+                self.gesture(gesture);
+            }
+
+            if self.recalibrate && selftest::camera_ok(&self.self_test) {
+                self.capture_reference().await;
+            }
+
+            // The sampler has already stopped the servos; let them go limp so nothing keeps
+            // pushing on the jam, and stop like the host would.
+            if let Some(mv) = analog::take_stall_mv() {
+                logging::error!("{=str} ({} mV)", English.msg(Msg::ServoStalled), mv);
+                self.running = false;
+                self.hopper.relax().await;
+                self.chutes.relax().await;
+                self.event_log.push(now_ms(), LogEvent::ServoStalled { mv });
+                self.send_event(Event::ServoStalled { sense_mv: mv }).await;
+            }
+
+            let elapsed = self.run_started.elapsed().as_millis();
+            let limit = self.settings.run_limit();
+            if self.running && !self.paused && limit.reached(self.run_beads, elapsed) {
+                // Stop like the host would, so a press or a start begins a new run.
+                logging::info!("run limit reached after {} beads, pausing", self.run_beads);
+                self.running = false;
+                let beads = self.run_beads.min(u16::MAX as u32) as u16;
+                self.event_log
+                    .push(now_ms(), LogEvent::RunLimitReached { beads });
+            }
+
+            let idle_ms = self.idle_since.elapsed().as_millis();
+            let sleep = !self.asleep
+                && self
+                    .settings
+                    .idle_sleep_ms()
+                    .is_some_and(|ms| idle_ms >= ms);
+            if sleep && self.running {
+                // Stop like the host would, so a press or a start begins a new run.
+                logging::info!("idle, stopping");
+                self.running = false;
+            }
+
+            if self.paused || !self.running {
+                self.pause(sleep).await;
+                continue;
+            }
+            // Turn ON LED when running
+            self.rig.led_on();
+
+            if self.parked {
+                // Drive relaxed servos back to where they were parked (they may have been
+                // nudged by hand) so the next pickup starts from a known position.
+                self.hopper.hold().await;
+                self.chutes.hold().await;
+                Timer::after(Duration::from_millis(UNPARK_SETTLE_MS)).await;
+                self.parked = false;
+            }
+
+            if self.pickups.needs_refill() {
+                // Blink the refill prompt, then probe with one pickup.
+                for _ in 0..REFILL_RETRY_SECS {
+                    self.neopixel.show(REFILL_COLOR).await;
+                    Timer::after(Duration::from_millis(500)).await;
+                    self.neopixel.show(NEOPIXEL_OFF).await;
+                    Timer::after(Duration::from_millis(500)).await;
+                }
+                // The prompt is most of a timeout on its own.
+                self.watchdog.feed();
+            }
+
+            if self.last_stats_log.elapsed() >= Duration::from_secs(STATS_LOG_SECS) {
+                self.stats.log();
+                self.last_stats_log = Instant::now();
+            }
+
+            // The lowest the supply fell during the last cycle's moves.
+            if let Some(mv) = analog::take_supply_low_mv() {
+                self.record_supply(mv).await;
+            }
+
+            self.sort_one().await;
+
+            if self.last_save.elapsed() >= Duration::from_secs(SAVE_LEARNED_SECS) {
+                self.sorter.save_learned(&mut self.config);
+                self.last_save = Instant::now();
+            }
+        }
+    }
+
+    fn home(&self) -> Pose {
+        Pose {
+            hopper: self.settings.get(Setting::HopperDrop),
+            chutes: self.chute_home,
+        }
+    }
+
+    async fn send_event(&mut self, event: Event) {
+        let mut packet = [0u8; EVENT_PACKET_LEN];
+        let len = event.encode(&mut packet);
+        protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+    }
+
+    async fn send_self_test(&mut self) {
+        let mut packet = [0u8; SELF_TEST_PACKET_LEN];
+        let len = self.self_test.encode(&mut packet);
+        protocol::send_packet(&mut self.data_tx, &packet[..len]).await;
+    }
+
+    // Resume after a stop, a stall or a run limit, as a new run.
+    fn start(&mut self) {
+        if !self.self_test.passed() {
+            logging::warn!("self test failed, not starting");
+            return;
+        }
+        servo::release_stop();
+        self.running = true;
+        self.pickups.resume();
+        self.run_beads = 0;
+        self.run_started = Instant::now();
+        self.event_log.push(now_ms(), LogEvent::Started);
+    }
+
+    async fn wake(&mut self) {
+        self.asleep = false;
+        self.rig.camera.wake().await;
+        self.hopper.hold().await;
+        self.chutes.hold().await;
+        self.neopixel.show(NEOPIXEL_OFF).await;
+        // Park again, holding or relaxed as set, if still paused.
+        self.parked = false;
+        self.event_log.push(now_ms(), LogEvent::Asleep(false));
+        logging::info!("awake");
+    }
+
+    fn gesture(&mut self, gesture: Gesture) {
+        match gesture {
+            // Resume after a stop or a stall, otherwise pause or resume.
+            Gesture::Short if !self.running => {
+                self.start();
+                if self.running {
+                    self.paused = false;
+                }
+            }
+            Gesture::Short => {
+                self.paused = !self.paused;
+                let event = if self.paused {
+                    LogEvent::Stopped
+                } else {
+                    LogEvent::Started
+                };
+                self.event_log.push(now_ms(), event);
+            }
+            Gesture::Long => self.recalibrate = true,
+            Gesture::Double => {
+                self.dry_run = !self.dry_run;
+                self.event_log
+                    .push(now_ms(), LogEvent::DryRun(self.dry_run));
+                logging::info!("dry run {}", self.dry_run);
+            }
+            // The gesture reader has already stopped the servos.
+            Gesture::Triple => {
+                logging::warn!("emergency stop");
+                self.running = false;
+                self.event_log.push(now_ms(), LogEvent::EmergencyStop);
+            }
+        }
+    }
+
+    // Between cycles the slot is empty: the last bead has just been dropped.
+    async fn capture_reference(&mut self) {
+        self.hopper
+            .move_to(self.settings.get(Setting::HopperCamera), Speed::Gentle)
+            .await;
+        Timer::after(Duration::from_millis(200)).await;
+        let mut bg_buf = [0u32; 600];
+        let captured = self.rig.camera.capture(&mut bg_buf).await.is_ok();
+        let bg_bytes = frame_bytes(&bg_buf);
+        if !captured || !self.sorter.set_background(&bg_bytes, 40, 30) {
+            logging::warn!("Failed to capture empty slot reference");
+        } else {
+            logging::info!("empty slot reference captured");
+        }
+        self.recalibrate = false;
+        // Park again if paused.
+        self.parked = false;
+    }
+
+    async fn record_supply(&mut self, mv: u16) {
+        match self.supply.record(mv) {
+            SupplyEvent::Sagged => {
+                logging::warn!("supply sagged to {} mV, slowing the servos", mv);
+                self.event_log.push(now_ms(), LogEvent::SupplySag { mv });
+                self.hopper.scale_speeds(SAG_SPEED_PERCENT).await;
+                self.chutes.scale_speeds(SAG_SPEED_PERCENT).await;
+            }
+            SupplyEvent::Recovered => {
+                logging::info!("supply back to {} mV", mv);
+                self.hopper.scale_speeds(100).await;
+                self.chutes.scale_speeds(100).await;
+            }
+            SupplyEvent::None => {}
+        }
+    }
+
+    // A paused tick: park, maybe fall asleep, show what is wrong, and wait a second or for a
+    // host command or a press.
+    async fn pause(&mut self, sleep: bool) {
+        // Turn OFF LED when paused
+        self.rig.led_off();
+        // After an emergency stop the servos stay where they stopped.
+        if !self.parked && !servo::is_stopped() {
+            // The pause is only seen between cycles, so the last bead has been dropped; let it
+            // clear the chutes before moving anything.
+            self.planner.chutes_clear().await;
+            self.hopper
+                .move_to(self.settings.get(Setting::HopperPark), Speed::Normal)
+                .await;
+            let relax = self.settings.get(Setting::RelaxOnPause) == 1;
+            if relax {
+                self.hopper.relax().await;
+                self.chutes.relax().await;
+            }
+            logging::info!("parked (servos relaxed: {})", relax);
+            self.parked = true;
+        }
+        if sleep {
+            self.sorter.save_learned(&mut self.config);
+            self.hopper.relax().await;
+            self.chutes.relax().await;
+            self.rig.camera.sleep().await;
+            self.neopixel.show(ASLEEP_COLOR).await;
+            self.asleep = true;
+            self.event_log.push(now_ms(), LogEvent::Asleep(true));
+            logging::info!("asleep until a press or a host command");
+        }
+        if !self.asleep {
+            logging::info!("{=str}", English.msg(Msg::Paused));
+            self.sorter.save_learned(&mut self.config);
+            if !selftest::camera_ok(&self.self_test) {
+                self.look_for_camera().await;
+            }
+            if self.pickups.is_stalled() {
+                // Stall pattern: a red double blink each second, until a press or a host
+                // start resumes.
+                for _ in 0..2 {
+                    self.neopixel.show(STALL_COLOR).await;
+                    Timer::after(Duration::from_millis(150)).await;
+                    self.neopixel.show(NEOPIXEL_OFF).await;
+                    Timer::after(Duration::from_millis(150)).await;
+                }
+            }
+        }
+        // Wake early for a host command (e.g. start) or a press.
+        select(
+            Timer::after(Duration::from_millis(1000)),
+            select(
+                protocol::COMMANDS.ready_to_receive(),
+                switch::GESTURES.ready_to_receive(),
+            ),
+        )
+        .await;
+    }
+
+    // Blink the no camera pattern, and every `CAMERA_RETRY_SECS` see if it has turned up.
+    async fn look_for_camera(&mut self) {
+        // No camera pattern: three quick blinks each second.
+        for _ in 0..3 {
+            self.neopixel.show(NO_CAMERA_COLOR).await;
+            Timer::after(Duration::from_millis(100)).await;
+            self.neopixel.show(NEOPIXEL_OFF).await;
+            Timer::after(Duration::from_millis(100)).await;
+        }
+        if self.last_camera_retry.elapsed() < Duration::from_secs(CAMERA_RETRY_SECS) {
+            return;
+        }
+        self.rig.camera.reset().await;
+        selftest::check_camera(&mut self.rig.camera, &mut self.self_test).await;
+        if selftest::camera_ok(&self.self_test) {
+            logging::info!("camera found");
+            self.rig.led_on();
+            self.rig.camera.auto_exposure().await;
+            self.recalibrate = self.self_test.passed();
+        }
+        // Let the host see the change, as at power-up.
+        self.send_self_test().await;
+        self.last_camera_retry = Instant::now();
+        // Setting up and checking the camera takes seconds of the timeout.
+        self.watchdog.feed();
+    }
+
+    // One sort cycle, sequenced by the cycle FSM and carried out by the servos and camera
+    // through the hardware traits. Only the routing is done here.
+    async fn sort_one(&mut self) {
+        let cycle_start = Instant::now();
+        // Ramped moves: a sudden start flicks beads back out of the slot. Gentler still while
+        // the supply is sagging.
+        let accel = self.settings.agitation_accel();
+        let agitation = Motion::Trapezoid {
+            accel: if self.supply.is_sagging() {
+                accel / 2
+            } else {
+                accel
+            },
+        };
+        let layout = self.layout;
+        let mut fsm = SorterFsm::new();
+        let mut action = fsm.start(CycleConfig {
+            pickup_us: self.settings.get(Setting::HopperPickup),
+            camera_us: self.settings.get(Setting::HopperCamera),
+            drop_us: self.settings.get(Setting::HopperDrop),
+            // Extra full-width passes after consecutive empty pickups.
+            agitation_level: self.pickups.agitation_level(),
+            retakes: self.settings.get(Setting::Retakes) as u8,
+            layout,
+        });
+        let mut positioner = CyclePositioner {
+            planner: self.planner,
+            agitation,
+            overlap: Duration::from_ticks(0),
+        };
+        let mut inspector = Inspector::new(self, cycle_start);
+        let mut decided = cycle_start;
+        let mut distance = None;
+        let mut routed = None;
+        let end = loop {
+            if let Action::Finish(end) = fsm.drive(action, &mut positioner, &mut inspector).await {
+                break end;
+            }
+            // The cycle wants a tube for the bead.
+            let bead = inspector.bead;
+            let seq = &mut *inspector.seq;
+            // Before routing, which may learn the bead's color.
+            distance = bead
+                .as_ref()
+                .and_then(|b| seq.sorter.match_distance(&b.average_color));
+            routed = match bead {
+                Some(b) if seq.dry_run => {
+                    let c = b.average_color;
+                    logging::info!("dry run: bead ({}, {}, {}) not routed", c.r, c.g, c.b);
+                    None
+                }
+                Some(b) => {
+                    let (collection, extraction) =
+                        (seq.collection.as_ref(), seq.extraction.as_ref());
+                    seq.sorter.decide(&b, collection, extraction)
+                }
+                None => None,
+            };
+            decided = Instant::now();
+            if seq.dataset_mode {
+                let label = Label {
+                    tube: routed,
+                    bead: bead.as_ref().map(Measurement::from),
+                };
+                protocol::send_dataset(&mut seq.data_tx, &label, &inspector.first).await;
+            }
+            let tube_index = seq.sorter.drop_tube(routed, seq.extraction.is_some());
+            if let Some(spot) = layout.locate(tube_index) {
+                logging::info!(
+                    "Dropping bead into tube: {} row: {} chute: {}",
+                    tube_index,
+                    spot.drop_row,
+                    spot.chute_position
+                );
+            }
+            action = fsm.next(if servo::is_stopped() {
+                CycleEvent::Stopped
+            } else {
+                CycleEvent::Classified(tube_index)
+            });
+        };
+        let (bead, photographed) = (inspector.bead, inspector.photographed);
+        self.stats.record_overlap(positioner.overlap);
+        let tube_index = match end {
+            CycleEnd::Dropped { tube } => tube,
+            CycleEnd::Empty => {
+                // Nothing picked up; agitate again instead of dropping into a tube.
+                logging::info!("{=str}", English.msg(Msg::SlotEmptyRetrying));
+                self.stats.record(Outcome::Empty, cycle_start);
+                let record = CycleRecord {
+                    uptime_ms: now_ms(),
+                    result: CycleResult::Empty,
+                    tube: None,
+                    color: None,
+                    distance: None,
+                    pickup_ms: (photographed - cycle_start).as_millis() as u32,
+                    camera_ms: photographed.elapsed().as_millis() as u32,
+                    cycle_ms: cycle_start.elapsed().as_millis() as u32,
+                };
+                protocol::send_record(&mut self.data_tx, &record).await;
+                return;
+            }
+            // Logged as it happened.
+            CycleEnd::Failed(CycleError::NoFrame) => return,
+            CycleEnd::Failed(CycleError::Stopped) => {
+                // Stopped mid-cycle: the servos are not where the cycle thinks, so neither
+                // learn from nor drop this bead.
+                logging::warn!("emergency stop, cycle abandoned");
+                return;
+            }
+            CycleEnd::Failed(CycleError::NotInLayout(tube)) => {
+                // The sorter never hands out a tube past the layout's tube count.
+                logging::error!("tube {} is not in the layout", tube);
+                self.stats.record(Outcome::Rejected, cycle_start);
+                return;
+            }
+            CycleEnd::Failed(CycleError::Unexpected) => {
+                logging::error!("sort cycle out of step, abandoned");
+                return;
+            }
+        };
+        let outcome = match routed {
+            Some(tube) => Outcome::Sorted(tube),
+            None => Outcome::Rejected,
+        };
+        self.stats.record(outcome, cycle_start);
+        self.run_beads += 1;
+        let logged = match (routed, bead) {
+            (Some(tube), Some(b)) => LogEvent::Sorted {
+                tube,
+                color: b.average_color,
+            },
+            _ => LogEvent::Rejected,
+        };
+        self.event_log.push(now_ms(), logged);
+        let record = CycleRecord {
+            uptime_ms: now_ms(),
+            result: match routed {
+                Some(_) => CycleResult::Sorted,
+                None => CycleResult::Rejected,
+            },
+            tube: Some(tube_index),
+            color: bead.map(|b| {
+                let c = b.average_color;
+                [c.r, c.g, c.b]
+            }),
+            distance,
+            pickup_ms: (photographed - cycle_start).as_millis() as u32,
+            camera_ms: (decided - photographed).as_millis() as u32,
+            cycle_ms: cycle_start.elapsed().as_millis() as u32,
+        };
+        protocol::send_record(&mut self.data_tx, &record).await;
+
+        let collected = routed.is_some() && self.collection.as_mut().is_some_and(|c| c.record());
+        if let Some(done) = self.collection.filter(|_| collected) {
+            logging::info!("collected {} beads", done.collected());
+            self.collection = None;
+            self.running = false;
+            let beads = done.collected();
+            self.send_event(Event::TargetReached { beads }).await;
+        }
+    }
+}
@@ -1,6 +1,91 @@
-use embassy_rp::pwm::{Pwm, SetDutyCycle};
+//! Hobby servos, each driven by its own task.
+//!
+//! [`spawn`] hands a [`ServoDriver`] (the PWM) to a task and returns a [`Servo`] handle to
+//! command it with. Handles are `Copy`, so the sort loop and host commands can share a servo.
+//! Commands queue on the servo's [`ServoState`] and are taken even mid-move: a new
+//! [`ServoCommand::MoveTo`] retargets the move from wherever the servo has got to, and
+//! [`ServoCommand::Stop`] ends it there. Each move picks its [`Speed`] and [`Motion`].
+//!
+//! The servo PWMs count microseconds, so a pulse width is also the compare value.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
 
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_rp::pac;
+use embassy_rp::pwm::{Pwm, SetDutyCycle};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel;
+use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicU32, AtomicUsize};
+use sorter_logic::motion::Trapezoid;
+
+// One step of a move per PWM period (50Hz).
+const STEP: Duration = Duration::from_millis(20);
+// `ServoState::output` before the task is spawned.
+const NO_OUTPUT: u8 = u8::MAX;
+/// Commands a servo's task can have queued.
+pub const COMMAND_QUEUE_LEN: usize = 4;
+
+pub static HOPPER: ServoState = ServoState::new();
+pub static CHUTES: ServoState = ServoState::new();
+
+// Set by `emergency_stop` until `release_stop`.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Stop every servo where it is. A move in progress ends right away, or at its next step if
+/// the servo's queue is full, and new moves do nothing until [`release_stop`].
+pub fn emergency_stop() {
+    STOPPED.store(true, Ordering::Relaxed);
+    for state in [&HOPPER, &CHUTES] {
+        let _ = state.commands.try_send(ServoCommand::Stop);
+    }
+}
+
+/// Let the servos move again after [`emergency_stop`].
+pub fn release_stop() {
+    STOPPED.store(false, Ordering::Relaxed);
+}
+
+pub fn is_stopped() -> bool {
+    STOPPED.load(Ordering::Relaxed)
+}
+
+/// Drive every servo straight to its [`Servo::set_panic_park`] pulse through the PWM
+/// registers, taking over from its task. For the panic handler, which cannot wait on tasks.
+pub fn park_now() {
+    for state in [&HOPPER, &CHUTES] {
+        let output = state.output.load(Ordering::Relaxed);
+        let us = state.panic_park_us.load(Ordering::Relaxed);
+        if output == NO_OUTPUT || us == 0 {
+            continue;
+        }
+        pac::PWM.ch(output as usize >> 1).cc().modify(|w| {
+            if output & 1 == 0 {
+                w.set_a(us)
+            } else {
+                w.set_b(us)
+            }
+        });
+    }
+}
+
+/// The most commands either servo has had queued since the last call, for telemetry.
+pub fn take_queue_peak() -> usize {
+    [&HOPPER, &CHUTES]
+        .iter()
+        .map(|state| state.queue_peak.swap(0, Ordering::Relaxed))
+        .max()
+        .unwrap_or(0)
+}
+
+/// True while either servo is in the middle of a move.
+pub fn any_moving() -> bool {
+    [&HOPPER, &CHUTES]
+        .iter()
+        .any(|state| state.moving.load(Ordering::Relaxed))
+}
 
 pub enum Channel {
     A,
@@ -8,28 +93,401 @@ pub enum Channel {
     B,
 }
 
-pub struct Servo<'d> {
+/// Named top speeds, so each move can trade time for steadiness.
+#[derive(Clone, Copy)]
+pub enum Speed {
+    /// For moves where a little overshoot does no harm, such as turning the chutes.
+    Fast,
+    /// The all-round speed the hopper always used to move at.
+    Normal,
+    /// For stops that must settle without wobble, such as the camera approach.
+    Gentle,
+}
+
+impl Speed {
+    pub const ALL: [Speed; 3] = [Speed::Fast, Speed::Normal, Speed::Gentle];
+
+    /// Top speed in microseconds per second, until changed with [`ServoCommand::SetSpeed`].
+    const fn default_us_per_sec(self) -> u32 {
+        match self {
+            Speed::Fast => 8000,
+            Speed::Normal => 5250,
+            Speed::Gentle => 3500,
+        }
+    }
+}
+
+/// How a move gets up to speed and back down.
+#[derive(Clone, Copy)]
+pub enum Motion {
+    /// Ease out quartic: fastest at the start, with a long gentle stop.
+    EaseOut,
+    /// Constant acceleration, in microseconds per second squared, up to the top speed and back
+    /// down, so the move starts as gently as it ends ([`Trapezoid`]).
+    Trapezoid { accel: u32 },
+}
+
+impl Motion {
+    // `None` for EaseOut.
+    fn trapezoid(self, distance: u32, max_speed: u32) -> Option<Trapezoid> {
+        match self {
+            Motion::EaseOut => None,
+            Motion::Trapezoid { accel } => Some(Trapezoid::new(distance, max_speed, accel)),
+        }
+    }
+
+    fn duration(self, distance: u32, max_speed: u32) -> Duration {
+        let duration_ms = match self.trapezoid(distance, max_speed) {
+            Some(trapezoid) => trapezoid.duration_ms(),
+            // Calculate duration based on max_speed
+            // time = distance / speed
+            // duration (ms) = (us / (us/sec)) * 1000
+            // Multiply by 4 because EaseOutQuartic peak velocity is 4x average velocity.
+            None => (distance * 1000 * 4) / max_speed,
+        };
+        // Ensure at least some duration to avoid div by zero or instant jumps
+        Duration::from_millis(duration_ms.max(1) as u64)
+    }
+}
+
+pub enum ServoCommand {
+    /// Move to `us`, retargeting any move in progress. `seq` numbers the move, so
+    /// [`Servo::move_to`] can tell when it has ended.
+    MoveTo {
+        us: u16,
+        speed: Speed,
+        motion: Motion,
+        /// Slow the move down if need be to take at least this long.
+        min_duration: Duration,
+        seq: u32,
+    },
+    /// End the move in progress where the servo has got to.
+    Stop,
+    /// Top speed of a [`Speed`], in microseconds per second, from the next move on.
+    SetSpeed(Speed, u32),
+    /// See [`ServoDriver::relax`]. Ends any move in progress.
+    Relax,
+    /// See [`ServoDriver::hold`].
+    Hold,
+}
+
+/// What a servo's task shares with its handles.
+pub struct ServoState {
+    commands: channel::Channel<CriticalSectionRawMutex, ServoCommand, COMMAND_QUEUE_LEN>,
+    // Most commands queued since `take_queue_peak` last looked.
+    queue_peak: AtomicUsize,
+    // The `seq` of the last move to end, however it ended.
+    settled: Signal<CriticalSectionRawMutex, u32>,
+    position: AtomicU16,
+    moving: AtomicBool,
+    last_seq: AtomicU32,
+    // Top speeds by `Speed`, in microseconds per second.
+    speeds: [AtomicU32; 3],
+    // The PWM slice times two, plus one for channel B.
+    output: AtomicU8,
+    // Where `park_now` sends the servo; 0 leaves it be.
+    panic_park_us: AtomicU16,
+}
+
+impl ServoState {
+    fn speed(&self, speed: Speed) -> u32 {
+        self.speeds[speed as usize].load(Ordering::Relaxed)
+    }
+
+    const fn new() -> Self {
+        Self {
+            commands: channel::Channel::new(),
+            queue_peak: AtomicUsize::new(0),
+            settled: Signal::new(),
+            position: AtomicU16::new(0),
+            moving: AtomicBool::new(false),
+            last_seq: AtomicU32::new(0),
+            speeds: [
+                AtomicU32::new(Speed::Fast.default_us_per_sec()),
+                AtomicU32::new(Speed::Normal.default_us_per_sec()),
+                AtomicU32::new(Speed::Gentle.default_us_per_sec()),
+            ],
+            output: AtomicU8::new(NO_OUTPUT),
+            panic_park_us: AtomicU16::new(0),
+        }
+    }
+}
+
+/// Start a task driving `driver` with the commands queued on `state`.
+pub fn spawn(spawner: Spawner, state: &'static ServoState, driver: ServoDriver<'static>) -> Servo {
+    let (min_us, max_us) = driver.range();
+    state.position.store(driver.position(), Ordering::Relaxed);
+    state.output.store(driver.output(), Ordering::Relaxed);
+    spawner.must_spawn(servo_task(driver, state));
+    Servo {
+        state,
+        min_us,
+        max_us,
+    }
+}
+
+/// A handle on a servo's task.
+#[derive(Clone, Copy)]
+pub struct Servo {
+    state: &'static ServoState,
+    min_us: u16,
+    max_us: u16,
+}
+
+impl Servo {
+    /// The endpoints, in microseconds.
+    pub fn range(&self) -> (u16, u16) {
+        (self.min_us, self.max_us)
+    }
+
+    /// The pulse width last sent to the servo, in microseconds; mid-move, where it has got to.
+    pub fn position(&self) -> u16 {
+        self.state.position.load(Ordering::Relaxed)
+    }
+
+    /// Move to `us` with [`Motion::EaseOut`] and wait until the move ends: there, or wherever
+    /// it was stopped. A move retargeted by someone else ends when the new one does.
+    ///
+    /// Only one caller at a time may wait on a servo.
+    pub async fn move_to(&self, us: u16, speed: Speed) {
+        self.move_with(us, speed, Motion::EaseOut).await;
+    }
+
+    /// [`Servo::move_to`] with the given [`Motion`].
+    pub async fn move_with(&self, us: u16, speed: Speed, motion: Motion) {
+        self.move_lasting(us, speed, motion, Duration::from_ticks(0))
+            .await;
+    }
+
+    /// [`Servo::move_with`], slowed down if need be to take at least `duration`.
+    pub async fn move_lasting(&self, us: u16, speed: Speed, motion: Motion, duration: Duration) {
+        let seq = self.start_move(us, speed, motion, duration).await;
+        while self.state.settled.wait().await < seq {}
+    }
+
+    /// Start a move to `us` taking at least `min_duration`, retargeting any move in progress,
+    /// without waiting for it. Returns the move's number.
+    pub async fn start_move(
+        &self,
+        us: u16,
+        speed: Speed,
+        motion: Motion,
+        min_duration: Duration,
+    ) -> u32 {
+        let seq = self.state.last_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        let command = ServoCommand::MoveTo {
+            us,
+            speed,
+            motion,
+            min_duration,
+            seq,
+        };
+        self.send(command).await;
+        seq
+    }
+
+    /// How long a move from here to `us` takes, unless slowed down.
+    pub fn duration_to(&self, us: u16, speed: Speed, motion: Motion) -> Duration {
+        let distance = (us as i32 - self.position() as i32).unsigned_abs();
+        motion.duration(distance, self.state.speed(speed))
+    }
+
+    /// Where to send the servo if the firmware panics (see [`park_now`]).
+    pub fn set_panic_park(&self, us: u16) {
+        let us = us.clamp(self.min_us, self.max_us);
+        self.state.panic_park_us.store(us, Ordering::Relaxed);
+    }
+
+    /// End the move in progress where the servo has got to.
+    #[allow(dead_code)]
+    pub async fn stop(&self) {
+        self.send(ServoCommand::Stop).await;
+    }
+
+    /// Top speed of a [`Speed`], in microseconds per second, from the next move on.
+    pub async fn set_speed(&self, speed: Speed, us_per_sec: u32) {
+        self.send(ServoCommand::SetSpeed(speed, us_per_sec)).await;
+    }
+
+    /// Set every [`Speed`] to `percent` of its default; 100 restores them.
+    pub async fn scale_speeds(&self, percent: u32) {
+        for speed in Speed::ALL {
+            let us_per_sec = speed.default_us_per_sec() * percent / 100;
+            self.set_speed(speed, us_per_sec).await;
+        }
+    }
+
+    pub async fn relax(&self) {
+        self.send(ServoCommand::Relax).await;
+    }
+
+    pub async fn hold(&self) {
+        self.send(ServoCommand::Hold).await;
+    }
+
+    async fn send(&self, command: ServoCommand) {
+        self.state.commands.send(command).await;
+        let queued = self.state.commands.len();
+        self.state.queue_peak.fetch_max(queued, Ordering::Relaxed);
+    }
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn servo_task(mut driver: ServoDriver<'static>, state: &'static ServoState) {
+    let mut moving: Option<Move> = None;
+    let mut next_step = Instant::now();
+    loop {
+        state.moving.store(moving.is_some(), Ordering::Relaxed);
+        let command = match moving {
+            None => state.commands.receive().await,
+            Some(m) => match select(Timer::at(next_step), state.commands.receive()).await {
+                Either::First(()) => {
+                    if is_stopped() {
+                        // Hold wherever the last step left it.
+                        moving = None;
+                    } else if let Some(us) = m.at(Instant::now()) {
+                        driver.set_pulse_width(us);
+                        next_step += STEP;
+                    } else {
+                        // Ensure final position is set exactly
+                        driver.set_pulse_width(m.to);
+                        moving = None;
+                    }
+                    state.position.store(driver.position(), Ordering::Relaxed);
+                    if moving.is_none() {
+                        state.settled.signal(m.seq);
+                    }
+                    continue;
+                }
+                Either::Second(command) => command,
+            },
+        };
+        match command {
+            ServoCommand::MoveTo {
+                us,
+                speed,
+                motion,
+                min_duration,
+                seq,
+            } => {
+                if is_stopped() {
+                    moving = None;
+                    state.settled.signal(seq);
+                } else {
+                    moving = Some(driver.plan(us, state.speed(speed), motion, min_duration, seq));
+                    next_step = Instant::now();
+                }
+            }
+            ServoCommand::Stop => {
+                if let Some(m) = moving.take() {
+                    state.settled.signal(m.seq);
+                }
+            }
+            ServoCommand::SetSpeed(speed, us_per_sec) => {
+                state.speeds[speed as usize].store(us_per_sec.max(1), Ordering::Relaxed)
+            }
+            ServoCommand::Relax => {
+                if let Some(m) = moving.take() {
+                    state.settled.signal(m.seq);
+                }
+                driver.relax();
+            }
+            ServoCommand::Hold => driver.hold(),
+        }
+    }
+}
+
+// A move in progress.
+#[derive(Clone, Copy)]
+struct Move {
+    from: u16,
+    to: u16,
+    start: Instant,
+    duration: Duration,
+    // `None` for Motion::EaseOut.
+    trapezoid: Option<Trapezoid>,
+    seq: u32,
+}
+
+impl Move {
+    // The pulse width at `now`, or `None` once the move is over.
+    fn at(&self, now: Instant) -> Option<u16> {
+        let elapsed = now.duration_since(self.start);
+        if elapsed >= self.duration {
+            return None;
+        }
+        let eased_progress = match self.trapezoid {
+            // A slowed-down move runs the same curve, stretched.
+            Some(trapezoid) => trapezoid.progress(
+                (elapsed.as_millis() * trapezoid.duration_ms() as u64 / self.duration.as_millis())
+                    as u32,
+            ),
+            None => easing_curve(elapsed.as_millis() as f32 / self.duration.as_millis() as f32),
+        };
+
+        // Interpolate
+        let diff = (self.to as i32) - (self.from as i32);
+        Some((self.from as i32 + (diff as f32 * eased_progress) as i32) as u16)
+    }
+}
+
+// Ease Out Quartic: 1 - (1 - x)^4
+// Starts fast, decelerates aggressively and has a long gentle stop.
+fn easing_curve(x: f32) -> f32 {
+    let t = 1.0 - x;
+    1.0 - (t * t * t * t)
+}
+
+/// A servo's PWM output, owned by its task.
+pub struct ServoDriver<'d> {
     pwm: Pwm<'d>,
-    #[allow(unused)]
-    channel: Channel, // Kept for reference, though new_output_a/b might bind it.
+    slice: usize,
+    channel: Channel,
     min_us: u16,
     max_us: u16,
     current_us: u16,
-    max_speed: u32, // us per second
 }
 
-impl<'d> Servo<'d> {
-    pub fn new(pwm: Pwm<'d>, channel: Channel, min_us: u16, max_us: u16, max_speed: u32) -> Self {
+impl<'d> ServoDriver<'d> {
+    /// `slice` and `channel` are the ones `pwm` was made with.
+    pub fn new(pwm: Pwm<'d>, slice: usize, channel: Channel, min_us: u16, max_us: u16) -> Self {
         Self {
             pwm,
+            slice,
             channel,
             min_us,
             max_us,
             current_us: min_us, // Default to min position
-            max_speed,
         }
     }
 
+    /// The endpoints, in microseconds.
+    pub fn range(&self) -> (u16, u16) {
+        (self.min_us, self.max_us)
+    }
+
+    /// The last commanded pulse width, in microseconds.
+    pub fn position(&self) -> u16 {
+        self.current_us
+    }
+
+    // See `ServoState::output`.
+    fn output(&self) -> u8 {
+        (self.slice as u8) << 1 | matches!(self.channel, Channel::B) as u8
+    }
+
+    /// Stop the pulses; most hobby servos then go limp. [`ServoDriver::hold`] or the next move
+    /// drives the servo again.
+    pub fn relax(&mut self) {
+        let _ = self.pwm.set_duty_cycle_fully_off();
+    }
+
+    /// Drive the servo to the last commanded position again, e.g. after
+    /// [`ServoDriver::relax`].
+    pub fn hold(&mut self) {
+        self.set_pulse_width(self.current_us);
+    }
+
     pub fn set_pulse_width(&mut self, us: u16) {
         let us = us.clamp(self.min_us, self.max_us);
         self.current_us = us;
@@ -45,49 +503,23 @@ impl<'d> Servo<'d> {
         let _ = self.pwm.set_duty_cycle_fraction(us, 20000);
     }
 
-    pub async fn move_to(&mut self, target_us: u16) {
-        let start_us = self.current_us;
-        let diff_abs = (target_us as i32 - start_us as i32).abs() as u32;
-
-        // Calculate duration based on max_speed
-        // time = distance / speed
-        // duration (ms) = (us / (us/sec)) * 1000
-        // Calculate duration based on max_speed
-        // time = distance / speed
-        // duration (ms) = (us / (us/sec)) * 1000
-        // Multiply by 4 because EaseOutQuartic peak velocity is 4x average velocity.
-        let duration_ms = (diff_abs * 1000 * 4) / self.max_speed;
-        // Ensure at least some duration to avoid div by zero or instant jumps
-        let duration = Duration::from_millis(duration_ms.max(1) as u64);
-
-        let start_time = Instant::now();
-
-        loop {
-            let elapsed = Instant::now().duration_since(start_time);
-            if elapsed >= duration {
-                break;
-            }
-
-            let progress = elapsed.as_millis() as f32 / duration.as_millis() as f32;
-            let eased_progress = Self::easing_curve(progress);
-
-            // Interpolate
-            let diff = (target_us as i32) - (start_us as i32);
-            let new_us = start_us as i32 + (diff as f32 * eased_progress) as i32;
-
-            self.set_pulse_width(new_us as u16);
-
-            Timer::after(Duration::from_millis(20)).await; // 50Hz update rate
+    // Plan a move from the current position, taking at least `min_duration`.
+    fn plan(
+        &self,
+        target_us: u16,
+        max_speed: u32,
+        motion: Motion,
+        min_duration: Duration,
+        seq: u32,
+    ) -> Move {
+        let distance = (target_us as i32 - self.current_us as i32).unsigned_abs();
+        Move {
+            from: self.current_us,
+            to: target_us,
+            start: Instant::now(),
+            duration: motion.duration(distance, max_speed).max(min_duration),
+            trapezoid: motion.trapezoid(distance, max_speed),
+            seq,
         }
-
-        // Ensure final position is set exactly
-        self.set_pulse_width(target_us);
-    }
-
-    // Ease Out Quartic: 1 - (1 - x)^4
-    // Starts fast, decelerates aggressively and has a long gentle stop.
-    fn easing_curve(x: f32) -> f32 {
-        let t = 1.0 - x;
-        1.0 - (t * t * t * t)
     }
 }
@@ -1,81 +1,335 @@
-use heapless::Vec;
-use sorter_logic::{analyze_image, Palette, PaletteEntry, PaletteMatch};
+use sorter_logic::collect::{Collection, Extraction};
+use sorter_logic::policy::{Decision, SortPolicy};
+use sorter_logic::profile::Profile;
+use sorter_logic::router::{RouteReason, PALETTE_SIZE, ROUTER_STATE_MAX};
+use sorter_logic::settings::{Setting, Settings};
+use sorter_logic::supply::SupplyMonitor;
+use sorter_logic::telemetry::{Bounded, Telemetry, TELEMETRY_PACKET_MAX};
+use sorter_logic::text::{English, Locale, Msg};
+use sorter_logic::{
+    analyze_image_debug, detect_empty, AnalysisConfig, BackgroundModel, BeadAnalysis, DriftTracker,
+    Rgb,
+};
+use sorter_protocol::{inventory, Status};
+
+use crate::config::ConfigStore;
+use crate::{logging, protocol, servo, EVENT_LOG_LEN};
+
+/// Largest inventory packet (see `sorter_protocol::inventory`).
+pub const INVENTORY_PACKET_MAX: usize = inventory::packet_len(PALETTE_SIZE);
+/// Size of one host command read on the data port.
+pub const COMMAND_BUFFER_LEN: usize = 64;
 
-const TUBE_COUNT: usize = 30;
 pub struct BeadSorter {
-    palette: Palette<128>,
-    tubes: Vec<PaletteEntry, TUBE_COUNT>,
-    palette_to_tube: [u8; 128],
+    policy: SortPolicy,
+    background: Option<BackgroundModel>,
+    // Where the last bead was found; seeds the next search.
+    last_center: Option<(i32, i32)>,
+    // Lighting drift since the background capture.
+    drift: Option<DriftTracker>,
+    telemetry: Telemetry,
+    settings: Settings,
+    // Beads routed since the learned state was last saved.
+    unsaved: bool,
 }
 
 impl BeadSorter {
-    pub fn new() -> Self {
+    pub fn new(tube_count: usize) -> Self {
         Self {
-            palette: Palette::new(),
-            tubes: Vec::new(),
-            palette_to_tube: [0xFF; 128],
+            policy: SortPolicy::new(tube_count),
+            background: None,
+            last_center: None,
+            drift: None,
+            telemetry: Telemetry::new([
+                PALETTE_SIZE,
+                tube_count,
+                COMMAND_BUFFER_LEN,
+                EVENT_LOG_LEN,
+                protocol::COMMAND_QUEUE_LEN,
+                servo::COMMAND_QUEUE_LEN,
+            ]),
+            settings: Settings::default(),
+            unsaved: false,
         }
     }
 
-    pub fn get_tube_for_image(&mut self, buf_bytes: &[u8], w: usize, h: usize) -> Option<u8> {
-        let analysis = analyze_image(buf_bytes, w, h)?;
+    /// Use the stored analysis thresholds, match threshold and merge margin overrides and
+    /// reject settings.
+    /// Changing the reject tube shifts the tubes after it, so set it before sorting.
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.settings = *settings;
+        self.policy.apply_settings(settings);
+        if self.policy.reject_tube().is_none() && settings.reject_tube().is_some() {
+            logging::warn!("reject tube is not in the layout, ignoring it");
+        }
+    }
 
+    /// The layout tube kept for unrouted beads, if there is one.
+    pub fn reject_tube(&self) -> Option<u8> {
+        self.policy.reject_tube()
+    }
+
+    /// Where beads that [`BeadSorter::decide`] does not sort go (see
+    /// [`SortPolicy::drop_tube`]).
+    pub fn drop_tube(&self, routed: Option<u8>, extracting: bool) -> u8 {
+        self.policy.drop_tube(routed, extracting)
+    }
+
+    /// The purity estimate of layout tube `tube`, once beads have been routed to it.
+    pub fn purity(&self, tube: u8) -> Option<u8> {
+        self.policy.purity(tube)
+    }
+
+    /// Override the profile's match threshold and merge margin until the next profile change.
+    pub fn set_thresholds(&mut self, match_threshold: u32, merge_margin: u32) {
+        self.policy.set_thresholds(match_threshold, merge_margin);
+    }
+
+    /// Switch palette mode and thresholds; the learned palette is kept. A stored match
+    /// threshold or tube merge margin overrides the profile's.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.policy.set_profile(profile);
+    }
+
+    /// Whether new colors still get palette entries. An empty palette learns whatever the
+    /// profile.
+    pub fn is_learning(&self) -> bool {
+        !self.policy.router().palette().is_frozen()
+    }
+
+    /// Record a capture of the empty slot as the reference for empty detection and lighting
+    /// drift.
+    pub fn set_background(&mut self, buf_bytes: &[u8], w: usize, h: usize) -> bool {
+        self.background = BackgroundModel::from_frame(buf_bytes, w, h);
+        self.drift = DriftTracker::from_frame(buf_bytes, w, h);
+        self.background.is_some()
+    }
+
+    /// True if the slot is confidently empty. Always false without a background reference.
+    pub fn is_slot_empty(&self, buf_bytes: &[u8], w: usize, h: usize) -> bool {
+        let Some(bg) = &self.background else {
+            return false;
+        };
+        let result = detect_empty(buf_bytes, w, h, bg, self.settings.analysis_config());
+        logging::debug!(
+            "empty detect: empty={} confidence={}",
+            result.empty,
+            result.confidence
+        );
+        result.empty && result.confidence >= self.settings.get(Setting::EmptyConfidence) as u8
+    }
+
+    /// Find and measure the bead in the frame, against the empty slot's background once one
+    /// is recorded.
+    pub fn analyze(&mut self, buf_bytes: &[u8], w: usize, h: usize) -> Option<BeadAnalysis> {
+        if let Some(drift) = &mut self.drift {
+            drift.update(buf_bytes, w, h);
+        }
+        let config = AnalysisConfig {
+            warm_start: self.last_center,
+            white_balance: self.drift.map(|d| d.correction()),
+            ..self.settings.analysis_config()
+        };
+        let background = self.background.as_ref();
+        let analysis = analyze_image_debug(buf_bytes, w, h, None, background, config)?;
+        self.last_center = Some(analysis.center);
+        Some(analysis)
+    }
+
+    /// True if the bead should be photographed again: it was not found, or its variance is
+    /// over `Setting::RetakeVariance`.
+    pub fn is_doubtful(&self, analysis: Option<&BeadAnalysis>) -> bool {
+        match (analysis, self.settings.retake_variance()) {
+            (None, _) => true,
+            (Some(a), Some(limit)) => a.variance > limit,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Distance from `color` to the nearest learned palette entry, if any.
+    pub fn match_distance(&self, color: &Rgb) -> Option<u32> {
+        self.policy.match_distance(color)
+    }
+
+    /// The layout tube for the bead while collecting, extracting or neither (see
+    /// [`SortPolicy::decide`]), or `None` if it is passed through, its color is too
+    /// unreliable (see `Setting::RejectConfidence`) or the router rejects it.
+    pub fn decide(
+        &mut self,
+        analysis: &BeadAnalysis,
+        collection: Option<&Collection>,
+        extraction: Option<&Extraction>,
+    ) -> Option<u8> {
         // Adaptive Learning
-        let match_result = self
-            .palette
-            .match_color(&analysis.average_color, analysis.variance, 15);
-
-        let p_idx = match match_result {
-            PaletteMatch::Match(i) => Some(i),
-            PaletteMatch::NewEntry(i) => Some(i),
-            PaletteMatch::Full => None,
-        }?;
-
-        self.palette
-            .add_sample(p_idx, &analysis.average_color, analysis.variance);
-
-        let tid = if self.palette_to_tube[p_idx] != 0xFF {
-            let t_idx = self.palette_to_tube[p_idx] as usize;
-            defmt::info!("bead matched palette entry: {}, tube: {}", p_idx, t_idx);
-            t_idx
-        } else {
-            if self.tubes.len() < self.tubes.capacity() {
-                defmt::info!(
+        let (tube, route) = match self.policy.decide(analysis, collection, extraction) {
+            Decision::Routed { tube, route } => (tube, route),
+            Decision::Extracted => return Some(0),
+            Decision::Passed if collection.is_some() => {
+                logging::info!("not a bead being collected, passing it through");
+                return None;
+            }
+            Decision::Passed => return None,
+            Decision::Unreliable => {
+                logging::info!(
+                    "bead confidence {}% under {}%, rejecting",
+                    analysis.confidence,
+                    self.settings.reject_confidence().unwrap_or(0)
+                );
+                return None;
+            }
+            Decision::NotRouted => {
+                logging::info!("bead not routed");
+                return None;
+            }
+        };
+        let p_idx = route.palette_index;
+        self.unsaved = true;
+        match route.reason {
+            RouteReason::Mapped => {
+                logging::info!("bead matched palette entry: {}, tube: {}", p_idx, tube);
+            }
+            RouteReason::NewTube => {
+                logging::info!(
                     "New Palette Entry: {} assigning to empty tube: {}",
                     p_idx,
-                    self.tubes.len()
+                    tube
                 );
-                let entry = PaletteEntry::new(analysis.average_color, analysis.variance);
-                self.tubes.push(entry).unwrap();
-                self.tubes.len() - 1
-            } else {
-                let mut best_t = 0;
-                let mut min_d = u32::MAX;
-                for (t_i, t_entry) in self.tubes.iter().enumerate() {
-                    let (t_avg, _) = t_entry.avg();
-                    let d = analysis.average_color.dist_lab(&t_avg);
-                    if d < min_d {
-                        min_d = d;
-                        best_t = t_i;
-                    }
-                }
-                defmt::info!(
+            }
+            RouteReason::Merged(dist) => {
+                logging::info!(
+                    "New Palette Entry: {} close to tube: {} (dist {}), sharing it",
+                    p_idx,
+                    tube,
+                    dist
+                );
+            }
+            RouteReason::NoFreeTube => {
+                logging::info!(
                     "New Palette Entry: {} no empty tubes; Next closest tube: {}",
                     p_idx,
-                    best_t
+                    tube
                 );
-                best_t
             }
-        };
+            RouteReason::Spillover { from } => {
+                logging::warn!(
+                    "{=str}: {} -> {}",
+                    English.msg(Msg::TubeSpillover),
+                    self.policy.layout_tube(from),
+                    tube
+                );
+            }
+        }
+        let router = self.policy.router();
+        let (palette_len, tubes_len) = (router.palette().len(), router.tubes().len());
+        self.record(Bounded::Palette, palette_len);
+        self.record(Bounded::Tubes, tubes_len);
+        logging::debug!(
+            "tube {} purity {}%",
+            tube,
+            self.policy.purity(tube).unwrap_or(100)
+        );
+
+        Some(tube)
+    }
+
+    /// Pick up the palette and tube map saved before the last reboot, if they fit this build.
+    pub fn load_learned(&mut self, config: &mut ConfigStore) {
+        let mut state = [0u8; ROUTER_STATE_MAX];
+        if !config.router_state(&mut state) {
+            logging::warn!("learned state: flash read failed");
+            return;
+        }
+        match self.policy.restore_state(&state) {
+            Ok(()) => logging::info!(
+                "learned state: {} palette entries, {} tubes",
+                self.policy.router().palette().len(),
+                self.policy.router().tubes().len()
+            ),
+            Err(e) => logging::info!("learned state: {}, starting fresh", defmt::Debug2Format(&e)),
+        }
+    }
+
+    /// Replace the learned palette and tube map with uploaded state and save it right away.
+    pub fn load_palette(&mut self, state: &[u8], config: &mut ConfigStore) {
+        if let Err(e) = self.policy.restore_state(state) {
+            logging::warn!("uploaded palette rejected: {}", defmt::Debug2Format(&e));
+            return;
+        }
+        logging::info!(
+            "uploaded palette: {} entries, {} tubes",
+            self.policy.router().palette().len(),
+            self.policy.router().tubes().len()
+        );
+        self.unsaved = true;
+        self.save_learned(config);
+    }
 
-        if p_idx < 128 {
-            self.palette_to_tube[p_idx] = tid as u8;
+    /// Save the palette and tube map if beads were sorted since the last save.
+    pub fn save_learned(&mut self, config: &mut ConfigStore) {
+        if !self.unsaved {
+            return;
+        }
+        let mut state = [0u8; ROUTER_STATE_MAX];
+        let len = self.policy.router().encode_state(&mut state);
+        if config.save_router_state(&state[..len]) {
+            self.unsaved = false;
+            logging::info!("learned state saved ({} bytes)", len);
+        } else {
+            logging::warn!("Failed to save learned state");
         }
+    }
 
-        if tid < self.tubes.len() {
-            self.tubes[tid].add(analysis.average_color, analysis.variance);
+    /// Note the length of a host command read and how many commands were queued with it.
+    pub fn record_command(&mut self, len: usize, queued: usize) {
+        self.record(Bounded::CommandBuffer, len);
+        self.record(Bounded::Commands, queued);
+    }
+
+    /// Note the fill of a bounded structure, warning the first time it is full.
+    pub fn record(&mut self, which: Bounded, len: usize) {
+        if self.telemetry.record(which, len) {
+            logging::warn!(
+                "{=str} full ({})",
+                which.name(),
+                self.telemetry.get(which).capacity
+            );
         }
+    }
+
+    /// Write the high-water marks of the bounded structures. Returns the length.
+    pub fn encode_telemetry(&self, out: &mut [u8; TELEMETRY_PACKET_MAX]) -> usize {
+        self.telemetry.encode(out)
+    }
+
+    /// Write the current tube counts and colors as an inventory packet. Returns the length.
+    pub fn encode_inventory(&self, out: &mut [u8; INVENTORY_PACKET_MAX]) -> usize {
+        let router = self.policy.router();
+        let palette = router.palette();
+        let entries = (0..palette.len()).filter_map(|i| {
+            let entry = palette.get_entry(i)?;
+            let (rgb, _) = entry.avg();
+            Some(inventory::Entry {
+                tube: router
+                    .tube_of(i)
+                    .map_or(inventory::NO_TUBE, |t| self.policy.layout_tube(t)),
+                rgb: (rgb.r, rgb.g, rgb.b),
+                count: entry.count,
+            })
+        });
+        inventory::encode(entries, out)
+    }
 
-        Some(tid as u8)
+    pub fn status(&self, running: bool, supply: &SupplyMonitor) -> Status {
+        let router = self.policy.router();
+        let tubes = router.tubes();
+        Status {
+            running,
+            profile_id: self.policy.profile().id(),
+            palette_entries: router.palette().len() as u8,
+            tubes_used: tubes.len() as u8,
+            beads_sorted: tubes.iter().map(|t| t.count).sum(),
+            supply_mv: supply.latest_mv(),
+            supply_min_mv: supply.lowest_mv(),
+        }
     }
 }
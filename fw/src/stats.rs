@@ -0,0 +1,112 @@
+use embassy_time::{Duration, Instant};
+use sorter_logic::layout::MAX_TUBES;
+use sorter_protocol::stats::{self, Summary, Tube};
+
+use crate::logging;
+
+/// Largest stats packet (see `sorter_protocol::stats`).
+pub const STATS_PACKET_MAX: usize = stats::packet_len(MAX_TUBES);
+
+/// How one pickup cycle ended.
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    /// Dropped into this tube.
+    Sorted(u8),
+    Empty,
+    /// Could not be analyzed or routed.
+    Rejected,
+}
+
+/// Sorting counters and cycle timing since power-up.
+pub struct Stats {
+    beads_sorted: u32,
+    empties: u32,
+    rejects: u32,
+    tube_counts: [u32; MAX_TUBES],
+    tube_count: usize,
+    cycles: u32,
+    cycle_total: Duration,
+    // Time saved by starting a stage before the previous one finished.
+    overlap_total: Duration,
+}
+
+impl Stats {
+    pub fn new(tube_count: usize) -> Self {
+        Self {
+            beads_sorted: 0,
+            empties: 0,
+            rejects: 0,
+            tube_counts: [0; MAX_TUBES],
+            tube_count: tube_count.min(MAX_TUBES),
+            cycles: 0,
+            cycle_total: Duration::from_ticks(0),
+            overlap_total: Duration::from_ticks(0),
+        }
+    }
+
+    /// Count a finished cycle that started at `start`.
+    pub fn record(&mut self, outcome: Outcome, start: Instant) {
+        self.cycles += 1;
+        self.cycle_total += start.elapsed();
+        match outcome {
+            Outcome::Sorted(tube) => {
+                self.beads_sorted += 1;
+                if let Some(count) = self.tube_counts.get_mut(tube as usize) {
+                    *count += 1;
+                }
+            }
+            Outcome::Empty => self.empties += 1,
+            Outcome::Rejected => self.rejects += 1,
+        }
+    }
+
+    /// Count time a cycle saved by overlapping with the one before.
+    pub fn record_overlap(&mut self, saved: Duration) {
+        self.overlap_total += saved;
+    }
+
+    pub fn summary(&self) -> Summary {
+        let per_cycle = |total: Duration| match self.cycles {
+            0 => 0,
+            n => (total.as_millis() / n as u64) as u32,
+        };
+        Summary {
+            uptime_secs: Instant::now().as_secs() as u32,
+            beads_sorted: self.beads_sorted,
+            empties: self.empties,
+            rejects: self.rejects,
+            avg_cycle_ms: per_cycle(self.cycle_total),
+            avg_overlap_ms: per_cycle(self.overlap_total),
+        }
+    }
+
+    /// Write a stats packet, with each tube's purity estimate from `purity`. Returns the
+    /// length.
+    pub fn encode(
+        &self,
+        purity: impl Fn(u8) -> Option<u8>,
+        out: &mut [u8; STATS_PACKET_MAX],
+    ) -> usize {
+        let tubes = self.tube_counts[..self.tube_count]
+            .iter()
+            .enumerate()
+            .map(|(t, &count)| Tube {
+                count,
+                purity: purity(t as u8),
+            });
+        stats::encode(&self.summary(), tubes, out)
+    }
+
+    pub fn log(&self) {
+        let s = self.summary();
+        logging::info!(
+            "stats: up {}s, {} sorted, {} empty, {} rejected, {}ms/cycle ({}ms overlapped)",
+            s.uptime_secs,
+            s.beads_sorted,
+            s.empties,
+            s.rejects,
+            s.avg_cycle_ms,
+            s.avg_overlap_ms
+        );
+    }
+}
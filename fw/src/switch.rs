@@ -1,4 +1,20 @@
+use core::future::pending;
+
+use embassy_futures::select::{select, Either};
 use embassy_rp::gpio::Input;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer};
+use sorter_logic::button::{Gesture, GestureDetector};
+
+use crate::logging;
+use crate::servo;
+
+/// How long the contacts must stay put before a change counts.
+const DEBOUNCE: Duration = Duration::from_millis(20);
+
+/// Gestures on the button, queued by [`gesture_reader`] for the main loop.
+pub static GESTURES: Channel<CriticalSectionRawMutex, Gesture, 4> = Channel::new();
 
 pub struct Switch<'d> {
     input: Input<'d>,
@@ -24,4 +40,51 @@ impl<'d> Switch<'d> {
     pub async fn wait_for_inactive(&mut self) {
         self.input.wait_for_high().await;
     }
+
+    /// Wait for an edge and return the level once it has settled. A bounce can return the
+    /// level from before the edge.
+    pub async fn wait_for_change(&mut self) -> bool {
+        self.input.wait_for_any_edge().await;
+        Timer::after(DEBOUNCE).await;
+        self.is_active()
+    }
+}
+
+/// Turn presses of the button into [`GESTURES`].
+#[embassy_executor::task]
+pub async fn gesture_reader(mut switch: Switch<'static>) {
+    let mut detector = GestureDetector::default();
+    // A press still held from power-up (the profile menu) only counts once released.
+    let mut active = switch.is_active();
+    loop {
+        let deadline = detector.deadline();
+        let timeout = async {
+            match deadline {
+                Some(at) => Timer::at(Instant::from_millis(at)).await,
+                None => pending().await,
+            }
+        };
+        let now = || Instant::now().as_millis();
+        let gesture = match select(switch.wait_for_change(), timeout).await {
+            Either::First(level) if level == active => continue,
+            Either::First(level) => {
+                active = level;
+                if level {
+                    detector.press(now())
+                } else {
+                    detector.release(now())
+                }
+            }
+            Either::Second(()) => detector.poll(now()),
+        };
+        if let Some(gesture) = gesture {
+            if gesture == Gesture::Triple {
+                // Right away, not once the sort loop gets to it.
+                servo::emergency_stop();
+            }
+            if GESTURES.try_send(gesture).is_err() {
+                logging::warn!("button gesture dropped");
+            }
+        }
+    }
 }
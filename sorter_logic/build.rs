@@ -0,0 +1,51 @@
+//! Write the sRGB to linear-light table used by `Rgb::to_oklab` to
+//! `$OUT_DIR/srgb_linear.rs`, so the firmware never calls `powf` for it.
+//!
+//! With the `lab_lut` feature, also write the RGB565 to Lab table used by
+//! `Rgb565::to_lab_lut` to `$OUT_DIR/lab_lut.bin`: three `i8`s (L*, a*, b*) per pixel
+//! value, 65536 × 3 bytes.
+
+use std::{env, fmt::Write, fs, path::Path};
+
+#[path = "src/lab.rs"]
+mod lab;
+
+// Same expansion as `Rgb::from_rgb565`.
+fn expand(v: u16, max: u16) -> u8 {
+    (v as u32 * 255 / max as u32) as u8
+}
+
+fn main() {
+    println!("cargo::rerun-if-changed=src/lab.rs");
+    println!("cargo::rerun-if-changed=build.rs");
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+
+    let mut linear = String::from("[");
+    for c in 0..=255u8 {
+        let c = c as f64 / 255.0;
+        let v = if c > 0.04045 {
+            ((c + 0.055) / 1.055).powf(2.4)
+        } else {
+            c / 12.92
+        };
+        write!(linear, "{:?}, ", v as f32).unwrap();
+    }
+    linear.push(']');
+    fs::write(Path::new(&out_dir).join("srgb_linear.rs"), linear).unwrap();
+
+    if env::var_os("CARGO_FEATURE_LAB_LUT").is_none() {
+        return;
+    }
+
+    let mut table = Vec::with_capacity(3 << 16);
+    for p in 0..=u16::MAX {
+        let r = expand(p >> 11, 0x1F);
+        let g = expand((p >> 5) & 0x3F, 0x3F);
+        let b = expand(p & 0x1F, 0x1F);
+        let (l, a, b) = lab::srgb_to_lab(r, g, b);
+        // Truncated like `Rgb::to_lab`; every sRGB color's L*, a* and b* fit in an i8.
+        table.extend([l as i8 as u8, a as i8 as u8, b as i8 as u8]);
+    }
+    let out = Path::new(&out_dir).join("lab_lut.bin");
+    fs::write(out, table).unwrap();
+}
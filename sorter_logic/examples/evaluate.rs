@@ -66,7 +66,7 @@ fn main() {
     for entry in WalkDir::new(data_dir).min_depth(2).max_depth(2) {
         let entry = entry.unwrap();
         let path = entry.path();
-        if path.extension().map_or(false, |e| e == "png") {
+        if path.extension().is_some_and(|e| e == "png") {
             let category = path
                 .parent()
                 .unwrap()
@@ -139,7 +139,7 @@ fn main() {
 
             // Algo 3: Brightest 20%
             let mut sorted_by_luma = pixels.clone();
-            sorted_by_luma.sort_by_key(|p| (p.r as u32 + p.g as u32 + p.b as u32));
+            sorted_by_luma.sort_by_key(|p| p.r as u32 + p.g as u32 + p.b as u32);
             let top_20_count = pixels.len() / 5;
             let brightest = &sorted_by_luma[pixels.len().saturating_sub(top_20_count)..];
             let avg_bright = average_rgb(brightest);
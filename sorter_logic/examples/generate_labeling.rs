@@ -20,7 +20,7 @@ fn main() -> std::io::Result<()> {
     for entry in WalkDir::new(data_dir).min_depth(2).max_depth(2) {
         let entry = entry?;
         let path = entry.path();
-        if path.extension().map_or(false, |e| e == "png") {
+        if path.extension().is_some_and(|e| e == "png") {
             let category = path
                 .parent()
                 .unwrap()
@@ -0,0 +1,245 @@
+//! Re-run classification over a session recorded by image_saver (`session_<id>.csv` plus the
+//! saved frames) with different settings, and report how many beads would have gone to a
+//! different tube.
+//!
+//! Usage: cargo run --example replay -- <session.csv> [--threshold N] [--metric lab|oklab|hue|dark]
+//!        [--filter-percent N] [--mad-k K] [--merge-margin N] [--tubes N]
+
+use clap::{Parser, ValueEnum};
+use sorter_logic::router::{DEFAULT_TUBE_MERGE_MARGIN, MATCH_THRESHOLD, TubeRouter};
+use sorter_logic::{AnalysisConfig, ColorMetric, analyze_image_debug};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Session log written by image_saver.
+    log: PathBuf,
+
+    /// Palette match threshold (squared distance under the metric).
+    #[arg(long, default_value_t = MATCH_THRESHOLD)]
+    threshold: u32,
+
+    #[arg(long, value_enum, default_value_t = Metric::Lab)]
+    metric: Metric,
+
+    /// Percent of the ring pixels kept by the outlier filter.
+    #[arg(long)]
+    filter_percent: Option<u8>,
+
+    /// Keep pixels within median + K MAD instead of a fixed percentage.
+    #[arg(long)]
+    mad_k: Option<f32>,
+
+    /// New colors closer than this (squared Lab) to a tube share it; 0 to never share.
+    #[arg(long, default_value_t = DEFAULT_TUBE_MERGE_MARGIN)]
+    merge_margin: u32,
+
+    /// Tubes in the rack, as in image_saver.
+    #[arg(long, default_value_t = 30)]
+    tubes: usize,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Metric {
+    Lab,
+    Oklab,
+    Hue,
+    Dark,
+}
+
+impl Metric {
+    fn color_metric(self) -> ColorMetric {
+        match self {
+            Metric::Lab => ColorMetric::Lab,
+            Metric::Oklab => ColorMetric::OkLab,
+            Metric::Hue => ColorMetric::HueWeighted,
+            Metric::Dark => ColorMetric::LAB_DARK,
+        }
+    }
+}
+
+struct Frame {
+    image: String,
+    // `None` for empty; `Some(FULL)` when the bead was not routed.
+    old: Option<u8>,
+    new: Option<u8>,
+}
+
+const FULL: u8 = u8::MAX;
+
+fn parse_decision(decision: &str, tube: &str) -> Option<Option<u8>> {
+    match decision {
+        "empty" => Some(None),
+        "full" => Some(Some(FULL)),
+        _ => tube.parse().ok().map(Some),
+    }
+}
+
+fn show(decision: Option<u8>) -> String {
+    match decision {
+        None => "empty".to_string(),
+        Some(FULL) => "full".to_string(),
+        Some(t) => format!("tube {}", t),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let metric = args.metric.color_metric();
+    let mut config = AnalysisConfig {
+        mad_k: args.mad_k,
+        ..Default::default()
+    };
+    if let Some(percent) = args.filter_percent {
+        config.filter_percent = percent;
+    }
+
+    let log = match fs::read_to_string(&args.log) {
+        Ok(log) => log,
+        Err(e) => {
+            println!("Failed to read {:?}: {}", args.log, e);
+            return;
+        }
+    };
+    let image_dir = args.log.parent().unwrap_or(Path::new("."));
+
+    let mut lines = log.lines().skip_while(|l| l.starts_with('#'));
+    let header: Vec<&str> = lines.next().unwrap_or_default().split(',').collect();
+    let column = |name: &str| header.iter().position(|&c| c == name);
+    let (Some(decision_col), Some(tube_col)) = (column("decision"), column("tube")) else {
+        println!(
+            "{:?} has no tube column; it was recorded before image_saver logged tubes",
+            args.log
+        );
+        return;
+    };
+
+    let mut router = TubeRouter::with_metric(args.tubes, metric);
+    router.set_match_threshold(args.threshold);
+    router.set_merge_margin(args.merge_margin);
+    let mut frames = Vec::new();
+    let mut skipped = 0;
+    for line in lines {
+        let cols: Vec<&str> = line.split(',').collect();
+        let old = cols
+            .get(decision_col)
+            .zip(cols.get(tube_col))
+            .and_then(|(d, t)| parse_decision(d, t));
+        let (Some(old), Some(image)) = (old, cols.first().filter(|i| !i.is_empty())) else {
+            skipped += 1; // Frame was not saved
+            continue;
+        };
+        let img = match image::open(image_dir.join(image)) {
+            Ok(img) => img.into_rgb8(),
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        let (w, h) = img.dimensions();
+        let mut data = Vec::with_capacity((w * h * 2) as usize);
+        for p in img.pixels() {
+            let r = (p[0] as u16 * 31) / 255;
+            let g = (p[1] as u16 * 63) / 255;
+            let b = (p[2] as u16 * 31) / 255;
+            let rgb565 = (r << 11) | (g << 5) | b;
+            data.extend_from_slice(&rgb565.to_be_bytes());
+        }
+
+        let new = analyze_image_debug(&data, w as usize, h as usize, None, None, config).map(|a| {
+            router
+                .route(a.average_color, a.variance)
+                .map_or(FULL, |route| route.tube)
+        });
+        frames.push(Frame {
+            image: image.to_string(),
+            old,
+            new,
+        });
+    }
+
+    // Tubes are claimed in arrival order, so compare groupings rather than raw numbers: each
+    // replayed tube stands for the recorded tube most of its beads went to.
+    let mut votes: HashMap<u8, HashMap<Option<u8>, u32>> = HashMap::new();
+    for f in &frames {
+        if let Some(n) = f.new {
+            *votes.entry(n).or_default().entry(f.old).or_default() += 1;
+        }
+    }
+    let mapping: HashMap<u8, Option<u8>> = votes
+        .into_iter()
+        .map(|(n, v)| (n, v.into_iter().max_by_key(|(_, c)| *c).unwrap().0))
+        .collect();
+
+    let changed: Vec<&Frame> = frames
+        .iter()
+        .filter(|f| f.new.and_then(|n| mapping[&n]) != f.old)
+        .collect();
+
+    let old_tubes = frames
+        .iter()
+        .filter_map(|f| f.old)
+        .filter(|&t| t != FULL)
+        .max()
+        .map_or(0, |t| t as usize + 1);
+    let count_empty =
+        |pick: fn(&Frame) -> Option<u8>| frames.iter().filter(|f| pick(f).is_none()).count();
+
+    println!("Replayed {} frames ({} skipped)", frames.len(), skipped);
+    match config.mad_k {
+        Some(k) => println!(
+            "Metric {:?}, threshold {}, filter median + {} MAD",
+            metric, args.threshold, k
+        ),
+        None => println!(
+            "Metric {:?}, threshold {}, filter {}%",
+            metric, args.threshold, config.filter_percent
+        ),
+    }
+    println!(
+        "Tubes used: {} recorded -> {} replayed ({} palette entries)",
+        old_tubes,
+        router.tubes().len(),
+        router.palette().len()
+    );
+    let stats = router.palette().stats();
+    if let Some((a, b, d)) = stats.closest_pair {
+        println!(
+            "Closest entries: {} and {} (distance {}), mean spread {}",
+            a,
+            b,
+            d,
+            stats.mean_within()
+        );
+    }
+    if let Some((a, b)) = stats.merge_candidate() {
+        println!(
+            "  Warning: entries {} and {} overlap; consider a larger threshold",
+            a, b
+        );
+    }
+    println!(
+        "Empty frames:    {} recorded -> {} replayed",
+        count_empty(|f| f.old),
+        count_empty(|f| f.new)
+    );
+    println!(
+        "Changed tubes: {} ({:.1}%)",
+        changed.len(),
+        changed.len() as f64 * 100.0 / frames.len().max(1) as f64
+    );
+    for f in changed.iter().take(20) {
+        println!(
+            "  {}: {} -> {} (grouped with recorded {})",
+            f.image,
+            show(f.old),
+            show(f.new),
+            show(f.new.and_then(|n| mapping[&n]))
+        );
+    }
+    if changed.len() > 20 {
+        println!("  ... {} more", changed.len() - 20);
+    }
+}
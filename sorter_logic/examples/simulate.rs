@@ -0,0 +1,202 @@
+//! Simulate sorting a random bead mix through [`TubeRouter`] and report tube purity, with and
+//! without a tube capacity limit.
+//!
+//! With `--shots N` each bead is captured N times and the shots' palette matches are combined
+//! with a [`ClassSmoother`]; a few shots are spoiled by glare.
+//!
+//! With `--save PATH` the palette and tube map learned without a capacity limit are written to
+//! PATH, ready for `sorterctl load-palette`.
+//!
+//! Usage: cargo run --example simulate -- [--beads N] [--tubes N] [--capacity N] [--seed N]
+//!        [--shots N] [--save PATH]
+
+use clap::Parser;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sorter_logic::Rgb;
+use sorter_logic::router::{ROUTER_STATE_MAX, RouteReason, TubeRouter};
+use sorter_logic::smoother::ClassSmoother;
+
+// True bead colors and how common each is in the mix (relative weight).
+const MIX: [((u8, u8, u8), u32); 10] = [
+    ((200, 20, 30), 20),
+    ((30, 60, 200), 15),
+    ((240, 240, 235), 15),
+    ((20, 20, 25), 10),
+    ((40, 170, 60), 10),
+    ((240, 200, 20), 8),
+    ((250, 120, 20), 8),
+    ((130, 50, 160), 6),
+    ((250, 150, 190), 5),
+    ((120, 80, 40), 3),
+];
+// Per-channel measurement noise.
+const NOISE: i32 = 6;
+// Chance (percent) that a shot catches a glint and reads far too bright.
+const GLARE_PERCENT: u32 = 10;
+const MAX_SHOTS: usize = 8;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(long, default_value_t = 2000)]
+    beads: usize,
+
+    #[arg(long, default_value_t = 30)]
+    tubes: usize,
+
+    #[arg(long, default_value_t = 150)]
+    capacity: u32,
+
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+
+    /// Captures per bead (at most 8).
+    #[arg(long, default_value_t = 1)]
+    shots: usize,
+
+    /// Write the learned palette and tube map here.
+    #[arg(long)]
+    save: Option<String>,
+}
+
+fn main() {
+    let Args {
+        beads,
+        tubes,
+        capacity,
+        seed,
+        shots,
+        save,
+    } = Args::parse();
+    let shots = shots.clamp(1, MAX_SHOTS);
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let total_weight: u32 = MIX.iter().map(|(_, w)| w).sum();
+    let stream: Vec<(usize, Vec<Rgb>)> = (0..beads)
+        .map(|_| {
+            let mut pick = rng.gen_range(0..total_weight);
+            let kind = MIX
+                .iter()
+                .position(|&(_, w)| {
+                    let hit = pick < w;
+                    pick = pick.saturating_sub(w);
+                    hit
+                })
+                .unwrap();
+            let ((r, g, b), _) = MIX[kind];
+            let colors = (0..shots)
+                .map(|_| {
+                    let glare = if rng.gen_range(0..100) < GLARE_PERCENT {
+                        80
+                    } else {
+                        0
+                    };
+                    let mut jitter = |c: u8| {
+                        (c as i32 + glare + rng.gen_range(-NOISE..=NOISE)).clamp(0, 255) as u8
+                    };
+                    Rgb {
+                        r: jitter(r),
+                        g: jitter(g),
+                        b: jitter(b),
+                    }
+                })
+                .collect();
+            (kind, colors)
+        })
+        .collect();
+
+    println!(
+        "{} beads, {} kinds, {} tubes, {} shots per bead",
+        beads,
+        MIX.len(),
+        tubes,
+        shots
+    );
+    let unlimited = run(&stream, tubes, None);
+    run(&stream, tubes, Some(capacity));
+
+    if let Some(path) = save {
+        let mut state = [0u8; ROUTER_STATE_MAX];
+        let len = unlimited.encode_state(&mut state);
+        std::fs::write(&path, &state[..len]).expect("failed to write --save");
+        println!(
+            "\nSaved the unlimited-capacity palette and tube map to {}",
+            path
+        );
+    }
+}
+
+// The shot to route: with several shots, the first one agreeing with the smoothed palette
+// match (shots count for more the closer they are to their entry).
+fn pick_shot(router: &TubeRouter, shots: &[Rgb]) -> Rgb {
+    let mut smoother: ClassSmoother<MAX_SHOTS> = ClassSmoother::new();
+    let matches: Vec<Option<usize>> = shots
+        .iter()
+        .map(|shot| {
+            let (i, dist) = router.palette().nearest(shot)?;
+            smoother.push(i, 100 / (1 + dist));
+            Some(i)
+        })
+        .collect();
+    smoother
+        .decision()
+        .and_then(|d| matches.iter().position(|&m| m == Some(d.class)))
+        .map_or(shots[0], |i| shots[i])
+}
+
+fn run(stream: &[(usize, Vec<Rgb>)], tube_count: usize, capacity: Option<u32>) -> TubeRouter {
+    let mut router = TubeRouter::new(tube_count);
+    router.set_capacity(capacity);
+
+    // contents[tube][kind]
+    let mut contents = vec![[0u32; MIX.len()]; tube_count];
+    let mut spills = 0;
+    let mut rejected = 0;
+    for (kind, shots) in stream {
+        let kind = *kind;
+        let color = pick_shot(&router, shots);
+        match router.route(color, 0) {
+            Some(route) => {
+                if let RouteReason::Spillover { .. } = route.reason {
+                    spills += 1;
+                }
+                contents[route.tube as usize][kind] += 1;
+            }
+            None => rejected += 1,
+        }
+    }
+
+    match capacity {
+        Some(cap) => println!("\nCapacity {} beads per tube", cap),
+        None => println!("\nUnlimited capacity"),
+    }
+    println!(
+        "{:>4} {:>6} {:>8} {:>7}",
+        "tube", "beads", "majority", "purity"
+    );
+    let (mut sorted, mut pure) = (0, 0);
+    for (t, kinds) in contents.iter().enumerate() {
+        let total: u32 = kinds.iter().sum();
+        if total == 0 {
+            continue;
+        }
+        let (major, &count) = kinds.iter().enumerate().max_by_key(|&(_, c)| c).unwrap();
+        println!(
+            "{:>4} {:>6} {:>8} {:>6.1}%",
+            t,
+            total,
+            major,
+            100.0 * count as f32 / total as f32
+        );
+        sorted += total;
+        pure += count;
+    }
+    println!(
+        "Overall purity {:.1}%, {} palette entries, {} spillovers, {} beads not routed",
+        100.0 * pure as f32 / sorted.max(1) as f32,
+        router.palette().len(),
+        spills,
+        rejected
+    );
+    router
+}
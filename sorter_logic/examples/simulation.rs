@@ -1,5 +1,10 @@
 use image::RgbaImage;
-use sorter_logic::{AnalysisConfig, Palette, PaletteEntry, PaletteMatch, Rgb, analyze_image_debug};
+use sorter_logic::calibrate::{THRESHOLD_SWEEP, calibrate_threshold};
+use sorter_logic::router::MATCH_THRESHOLD;
+use sorter_logic::{
+    AnalysisConfig, ColorMetric, MaskPixel, Palette, PaletteEntry, PaletteMatch, Rgb,
+    analyze_image_debug,
+};
 use std::collections::HashMap;
 use std::env;
 use std::fs::{self, File};
@@ -24,7 +29,7 @@ fn main() {
     for entry in WalkDir::new(data_dir).min_depth(2).max_depth(2) {
         let entry = entry.unwrap();
         let path = entry.path();
-        if path.extension().map_or(false, |e| e == "png") {
+        if path.extension().is_some_and(|e| e == "png") {
             let img = image::open(path).expect("failed to open image").into_rgb8();
             let (w, h) = img.dimensions();
             let mut data = Vec::with_capacity((w * h * 2) as usize);
@@ -46,6 +51,33 @@ fn main() {
 
     println!("Loaded {} beads.", images.len());
 
+    // Pick the match threshold from the labeled folders rather than hard-coding one.
+    let labeled: Vec<(Rgb, &str)> = images
+        .iter()
+        .filter_map(|(path, data, w, h)| {
+            let truth = path.parent()?.file_name()?.to_str()?;
+            if truth == "empty" {
+                return None;
+            }
+            let a = analyze_image_debug(data, *w, *h, None, None, AnalysisConfig::default())?;
+            Some((a.average_color, truth))
+        })
+        .collect();
+    let threshold = match calibrate_threshold::<128, _>(&labeled, THRESHOLD_SWEEP, ColorMetric::Lab)
+    {
+        Some(best) => {
+            println!(
+                "Calibrated threshold {}: {}% ({} splits, {} collisions)",
+                best.threshold,
+                best.accuracy(),
+                best.splits,
+                best.collisions
+            );
+            best.threshold
+        }
+        None => MATCH_THRESHOLD,
+    };
+
     // --- Simulation Param ---
     let mut palette: Palette<128> = Palette::new(); // 128 Palettes allowed, will cluster to 30
 
@@ -58,7 +90,10 @@ fn main() {
     let mut collision_errors = 0;
     let mut empty_count = 0;
 
-    println!("Running Analysis (30 Palettes, Lab Match, Thresh 200, VarWeight 0.10)...");
+    println!(
+        "Running Analysis (30 Palettes, Lab Match, Thresh {})...",
+        threshold
+    );
     println!("Generating 'simulation_report.html'...");
 
     // ... (HTML gen is assumed done in previous step) ...
@@ -157,14 +192,14 @@ fn main() {
             width,
             height,
             Some(&mut mask),
+            None,
             AnalysisConfig::default(),
         );
 
         total_processed += 1;
 
         if let Some(ana) = analysis {
-            // Adaptive Threshold: 15
-            let match_result = palette.match_color(&ana.average_color, ana.variance, 15);
+            let match_result = palette.match_color(&ana.average_color, ana.variance, threshold);
 
             let p_idx = match match_result {
                 PaletteMatch::Match(i) => Some(i),
@@ -176,12 +211,13 @@ fn main() {
             let mut mask_img = RgbaImage::new(width as u32, height as u32);
             for y in 0..height {
                 for x in 0..width {
-                    let val = mask[y * width + x];
-                    let pixel = match val {
-                        1 => image::Rgba([0, 255, 0, 255]), // Green Ring
-                        3 => image::Rgba([255, 0, 0, 255]), // Red Edge
-                        4 => image::Rgba([0, 0, 255, 255]), // Blue Center
-                        _ => image::Rgba([0, 0, 0, 0]),     // Transparent
+                    let pixel = match MaskPixel::from_u8(mask[y * width + x]) {
+                        Some(MaskPixel::Kept) => image::Rgba([0, 255, 0, 255]),
+                        Some(MaskPixel::Rejected) => image::Rgba([255, 0, 0, 255]),
+                        Some(MaskPixel::Background) => image::Rgba([255, 0, 255, 160]),
+                        Some(MaskPixel::SearchWindow) => image::Rgba([255, 255, 0, 160]),
+                        Some(MaskPixel::Center) => image::Rgba([0, 0, 255, 255]),
+                        Some(MaskPixel::Unused) | None => image::Rgba([0, 0, 0, 0]),
                     };
                     mask_img.put_pixel(x as u32, y as u32, pixel);
                 }
@@ -1,5 +1,5 @@
-use image::RgbaImage;
-use sorter_logic::{AnalysisConfig, Palette, PaletteMatch, Rgb, analyze_image_debug};
+use base64::Engine;
+use sorter_logic::{AnalysisConfig, MaskPixel, Palette, PaletteMatch, analyze_image_debug};
 use std::collections::HashMap;
 use std::env;
 use std::fs::{self, File};
@@ -67,6 +67,9 @@ const REPORT_TEMPLATE_HEAD: &str = r#"
 fn main() {
     let args: Vec<String> = env::args().collect();
     let mut data_dir_str = "image_data".to_string();
+    // Loose default for unlabeled data; the `simulation` example calibrates one from labeled
+    // folders.
+    let mut threshold = 200;
 
     // Simple arg parsing
     let mut i = 1;
@@ -81,6 +84,16 @@ fn main() {
                     return;
                 }
             }
+            "--threshold" | "-t" => match args.get(i + 1).and_then(|t| t.parse().ok()) {
+                Some(t) => {
+                    threshold = t;
+                    i += 1;
+                }
+                None => {
+                    eprintln!("Error: --threshold requires a number");
+                    return;
+                }
+            },
             _ => {}
         }
         i += 1;
@@ -91,7 +104,9 @@ fn main() {
 
     if !data_dir.exists() {
         println!("Error: Data directory not found: {:?}", data_dir);
-        println!("Usage: cargo run --example simulation_full --release -- --dir <path_to_images>");
+        println!(
+            "Usage: cargo run --example simulation_full --release -- --dir <path_to_images> [--threshold N]"
+        );
         return;
     }
 
@@ -101,7 +116,7 @@ fn main() {
     for entry in WalkDir::new(data_dir).min_depth(1).max_depth(10) {
         let entry = entry.unwrap();
         let path = entry.path();
-        if path.extension().map_or(false, |e| e == "png") {
+        if path.extension().is_some_and(|e| e == "png") {
             let img = image::open(path).expect("failed to open image").into_rgb8();
             let (w, h) = img.dimensions();
             let mut data = Vec::with_capacity((w * h * 2) as usize);
@@ -144,7 +159,7 @@ fn main() {
         let mut mask_buffer = vec![0u8; w * h];
 
         // Analyze
-        let analysis_opt = analyze_image_debug(data, *w, *h, Some(&mut mask_buffer), config);
+        let analysis_opt = analyze_image_debug(data, *w, *h, Some(&mut mask_buffer), None, config);
 
         // Generate Mask Image (PNG Base64) for HTML
         let mask_base64 = generate_mask_base64(&mask_buffer, *w as u32, *h as u32);
@@ -156,15 +171,15 @@ fn main() {
         let path_buf = PathBuf::from(rel_path);
 
         if let Some(analysis) = analysis_opt {
-            let match_result = palette.match_color(&analysis.average_color, analysis.variance, 200);
+            let match_result =
+                palette.match_color(&analysis.average_color, analysis.variance, threshold);
             match match_result {
                 PaletteMatch::Match(idx) | PaletteMatch::NewEntry(idx) => {
                     palette.add_sample(idx, &analysis.average_color, analysis.variance);
-                    palette_bins.entry(idx).or_insert_with(Vec::new).push((
-                        path_buf,
-                        analysis,
-                        mask_base64,
-                    ));
+                    palette_bins
+                        .entry(idx)
+                        .or_default()
+                        .push((path_buf, analysis, mask_base64));
                 }
                 PaletteMatch::Full => {
                     unclassified.push((path_buf, "Palette Full".to_string(), mask_base64));
@@ -279,15 +294,14 @@ fn generate_mask_base64(mask: &[u8], width: u32, height: u32) -> String {
     for y in 0..height {
         for x in 0..width {
             let idx = (y * width + x) as usize;
-            let val = if idx < mask.len() { mask[idx] } else { 0 };
-            // Use same Colors as simulaton.rs
-            // 1=Green(Selected), 2=Red, 3=Yellow, 4=Blue(Center)
-            let color = match val {
-                1 => image::Rgba([0, 255, 0, 100]),   // Green Translucent
-                2 => image::Rgba([255, 0, 0, 100]),   // Red
-                3 => image::Rgba([255, 255, 0, 100]), // Yellow
-                4 => image::Rgba([0, 0, 255, 255]),   // Blue Solid
-                _ => image::Rgba([0, 0, 0, 0]),
+            // Same colors as simulation.rs, more translucent.
+            let color = match mask.get(idx).copied().and_then(MaskPixel::from_u8) {
+                Some(MaskPixel::Kept) => image::Rgba([0, 255, 0, 100]),
+                Some(MaskPixel::Rejected) => image::Rgba([255, 0, 0, 100]),
+                Some(MaskPixel::Background) => image::Rgba([255, 0, 255, 60]),
+                Some(MaskPixel::SearchWindow) => image::Rgba([255, 255, 0, 60]),
+                Some(MaskPixel::Center) => image::Rgba([0, 0, 255, 255]),
+                Some(MaskPixel::Unused) | None => image::Rgba([0, 0, 0, 0]),
             };
             img.put_pixel(x, y, color);
         }
@@ -297,5 +311,5 @@ fn generate_mask_base64(mask: &[u8], width: u32, height: u32) -> String {
     let mut cursor = std::io::Cursor::new(&mut buffer);
     img.write_to(&mut cursor, image::ImageOutputFormat::Png)
         .unwrap();
-    base64::encode(buffer)
+    base64::engine::general_purpose::STANDARD.encode(buffer)
 }
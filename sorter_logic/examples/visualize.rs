@@ -20,7 +20,7 @@ fn main() {
         .unwrap()
         .filter_map(|res| res.ok())
         .map(|entry| entry.path())
-        .filter(|p| p.extension().map_or(false, |ext| ext == "png"))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "png"))
         .collect();
 
     entries.sort();
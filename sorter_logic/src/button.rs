@@ -0,0 +1,101 @@
+//! Gestures on the sorter's single button: short, double, triple and long presses.
+//!
+//! [`GestureDetector`] is fed debounced presses and releases with a millisecond timestamp,
+//! and polled at its [`deadline`](GestureDetector::deadline) for the gestures that are decided
+//! by time passing: a long press fires while the button is still held, and short and double
+//! presses only once no further press has followed within [`DOUBLE_PRESS_MS`]. A triple press
+//! fires as the third press goes down, as it is the emergency stop.
+//!
+//! ```
+//! use sorter_logic::button::{Gesture, GestureDetector, DOUBLE_PRESS_MS};
+//!
+//! let mut button = GestureDetector::default();
+//! button.press(0);
+//! assert_eq!(button.release(80), None);
+//! assert_eq!(button.poll(80 + DOUBLE_PRESS_MS), Some(Gesture::Short));
+//! ```
+
+/// Held at least this long, a press is a [`Gesture::Long`].
+pub const LONG_PRESS_MS: u64 = 1000;
+/// A press starting within this long of the previous release continues a double or triple
+/// press.
+pub const DOUBLE_PRESS_MS: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Short,
+    Double,
+    Triple,
+    Long,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GestureDetector {
+    // When the button went down, while it is held.
+    down_at: Option<u64>,
+    // The held press already fired (as a long or triple press); its release is ignored.
+    fired: bool,
+    // Short presses so far in a run, and when the last was released; the run ends once no
+    // press follows within DOUBLE_PRESS_MS.
+    taps: u8,
+    tap_at: Option<u64>,
+}
+
+impl GestureDetector {
+    /// The button went down.
+    pub fn press(&mut self, now_ms: u64) -> Option<Gesture> {
+        let fired = self.poll(now_ms);
+        self.down_at = Some(now_ms);
+        self.fired = false;
+        if self.tap_at.take().is_some() && self.taps == 2 {
+            self.taps = 0;
+            self.fired = true;
+            return Some(Gesture::Triple);
+        }
+        fired
+    }
+
+    /// The button came up.
+    pub fn release(&mut self, now_ms: u64) -> Option<Gesture> {
+        let fired = self.poll(now_ms);
+        if self.down_at.take().is_none() || self.fired {
+            return fired;
+        }
+        self.taps += 1;
+        self.tap_at = Some(now_ms);
+        fired
+    }
+
+    /// Report a gesture that time has decided by `now_ms`.
+    pub fn poll(&mut self, now_ms: u64) -> Option<Gesture> {
+        let held_long = self
+            .down_at
+            .is_some_and(|down| now_ms >= down + LONG_PRESS_MS);
+        if held_long && !self.fired {
+            self.fired = true;
+            self.taps = 0;
+            return Some(Gesture::Long);
+        }
+        if self.tap_at.is_some_and(|up| now_ms >= up + DOUBLE_PRESS_MS) {
+            self.tap_at = None;
+            return match core::mem::take(&mut self.taps) {
+                1 => Some(Gesture::Short),
+                _ => Some(Gesture::Double),
+            };
+        }
+        None
+    }
+
+    /// When [`poll`](Self::poll) next has something to decide, if anything is pending.
+    pub fn deadline(&self) -> Option<u64> {
+        let long = self
+            .down_at
+            .filter(|_| !self.fired)
+            .map(|down| down + LONG_PRESS_MS);
+        let tap = self.tap_at.map(|up| up + DOUBLE_PRESS_MS);
+        match (long, tap) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Pick a palette match threshold from labeled beads instead of by hand.
+//!
+//! Each candidate threshold is scored by feeding the labeled colors through a fresh
+//! [`Palette`] the way the sorter does (match, then add the sample) and counting the two
+//! ways the palette can go wrong:
+//!
+//! - a *split*: a bead starts a new entry although its label already has one, so one color
+//!   ends up in two tubes;
+//! - a *collision*: a bead joins an entry started by a different label, so two colors share
+//!   a tube.
+//!
+//! A low threshold splits, a high one collides; [`calibrate_threshold`] returns the candidate
+//! with the fewest of both.
+
+use crate::{ColorMetric, Palette, PaletteMatch, Rgb};
+
+/// Thresholds worth trying with the Lab metric, from tight to loose.
+pub const THRESHOLD_SWEEP: [u32; 12] = [5, 8, 10, 12, 15, 20, 25, 30, 40, 60, 100, 200];
+
+/// How a threshold did on a labeled set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThresholdScore {
+    pub threshold: u32,
+    pub splits: u32,
+    pub collisions: u32,
+    /// Beads turned away because the palette was full.
+    pub unmatched: u32,
+    /// Palette entries created.
+    pub entries: usize,
+    pub samples: u32,
+}
+
+impl ThresholdScore {
+    pub fn errors(&self) -> u32 {
+        self.splits + self.collisions + self.unmatched
+    }
+
+    /// Percentage of beads that were neither split, collided nor turned away.
+    pub fn accuracy(&self) -> u8 {
+        let ok = self.samples.saturating_sub(self.errors());
+        (ok as u64 * 100 / self.samples.max(1) as u64) as u8
+    }
+}
+
+/// Score one threshold on `samples` (in sorting order) with an `N`-entry palette.
+pub fn score_threshold<const N: usize, L: Copy + PartialEq>(
+    samples: &[(Rgb, L)],
+    threshold: u32,
+    metric: ColorMetric,
+) -> ThresholdScore {
+    let mut palette: Palette<N> = Palette::with_metric(metric);
+    // Label of the bead that started each entry.
+    let mut founders: [Option<L>; N] = [None; N];
+    let mut score = ThresholdScore {
+        threshold,
+        splits: 0,
+        collisions: 0,
+        unmatched: 0,
+        entries: 0,
+        samples: samples.len() as u32,
+    };
+
+    for &(rgb, label) in samples {
+        let idx = match palette.match_color(&rgb, 0, threshold) {
+            PaletteMatch::Match(i) => {
+                if founders[i] != Some(label) {
+                    score.collisions += 1;
+                }
+                i
+            }
+            PaletteMatch::NewEntry(i) => {
+                if founders[..i].contains(&Some(label)) {
+                    score.splits += 1;
+                }
+                founders[i] = Some(label);
+                i
+            }
+            PaletteMatch::Full => {
+                score.unmatched += 1;
+                continue;
+            }
+        };
+        palette.add_sample(idx, &rgb, 0);
+    }
+    score.entries = palette.len();
+    score
+}
+
+/// The threshold from `thresholds` with the fewest errors (the first one on a tie). `None`
+/// if `thresholds` is empty.
+///
+/// ```
+/// use sorter_logic::calibrate::{THRESHOLD_SWEEP, calibrate_threshold};
+/// use sorter_logic::{ColorMetric, Rgb};
+///
+/// let shade = |r: u8, g: u8, b: u8| Rgb { r, g, b };
+/// let samples = [
+///     (shade(200, 20, 30), "red"),
+///     (shade(190, 24, 34), "red"),
+///     (shade(20, 40, 200), "blue"),
+///     (shade(196, 22, 28), "red"),
+///     (shade(28, 44, 190), "blue"),
+///     (shade(200, 60, 90), "pink"),
+/// ];
+/// let best = calibrate_threshold::<32, _>(&samples, THRESHOLD_SWEEP, ColorMetric::Lab).unwrap();
+/// assert_eq!(best.errors(), 0);
+/// assert_eq!(best.entries, 3);
+/// ```
+pub fn calibrate_threshold<const N: usize, L: Copy + PartialEq>(
+    samples: &[(Rgb, L)],
+    thresholds: impl IntoIterator<Item = u32>,
+    metric: ColorMetric,
+) -> Option<ThresholdScore> {
+    thresholds
+        .into_iter()
+        .map(|t| score_threshold::<N, L>(samples, t, metric))
+        .reduce(|best, s| if s.errors() < best.errors() { s } else { best })
+}
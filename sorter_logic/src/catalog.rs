@@ -0,0 +1,120 @@
+//! Named bead colors for labelling palette entries (the `catalog` feature).
+//!
+//! There is no built-in table: manufacturers' charts differ between production runs and
+//! nobody has measured them under the sorter's camera and LED, so a catalog is loaded from
+//! a color chart the user supplies. Each line of a chart is a name and a color, either as
+//! hex or as decimal components:
+//!
+//! ```text
+//! # My Perler beads, photographed under daylight
+//! White,#F0F0F0
+//! Red,191,46,64
+//! ```
+//!
+//! Blank lines and lines starting with `#` are skipped. Reference Lab values are computed
+//! with [`Rgb::to_lab`], so they compare directly with measured colors.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::Rgb;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogColor {
+    pub name: String,
+    /// Reference CIELAB, same scale as [`Rgb::to_lab`].
+    pub lab: (i32, i32, i32),
+}
+
+/// A named list of bead colors (one brand / product line).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Catalog {
+    pub name: String,
+    pub colors: Vec<CatalogColor>,
+}
+
+/// Closest catalog color to a measured color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CatalogMatch<'a> {
+    pub color: &'a CatalogColor,
+    /// Squared Lab distance, comparable to [`Rgb::dist_lab`].
+    pub dist: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatalogError {
+    /// The line (1-based) has no name, or its color is neither `#RRGGBB` nor `R,G,B`.
+    BadLine(usize),
+    /// The chart has no colors.
+    Empty,
+}
+
+impl Catalog {
+    /// Parses a color chart (see the module docs).
+    pub fn parse(name: &str, chart: &str) -> Result<Self, CatalogError> {
+        let mut colors = Vec::new();
+        for (i, line) in chart.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (color, rgb) = line.split_once(',').ok_or(CatalogError::BadLine(i + 1))?;
+            let color = color.trim();
+            let rgb = parse_rgb(rgb).ok_or(CatalogError::BadLine(i + 1))?;
+            if color.is_empty() {
+                return Err(CatalogError::BadLine(i + 1));
+            }
+            colors.push(CatalogColor {
+                name: color.to_string(),
+                lab: rgb.to_lab(),
+            });
+        }
+        if colors.is_empty() {
+            return Err(CatalogError::Empty);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            colors,
+        })
+    }
+
+    pub fn nearest(&self, rgb: &Rgb) -> Option<CatalogMatch<'_>> {
+        let (l, a, b) = rgb.to_lab();
+        self.colors
+            .iter()
+            .map(|c| CatalogMatch {
+                color: c,
+                dist: ((c.lab.0 - l).pow(2) + (c.lab.1 - a).pow(2) + (c.lab.2 - b).pow(2)) as u32,
+            })
+            .min_by_key(|m| m.dist)
+    }
+
+    /// The color called `name`, ignoring case.
+    pub fn by_name(&self, name: &str) -> Option<&CatalogColor> {
+        self.colors
+            .iter()
+            .find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+}
+
+fn parse_rgb(text: &str) -> Option<Rgb> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let v = u32::from_str_radix(hex, 16).ok()?;
+        return Some(Rgb {
+            r: (v >> 16) as u8,
+            g: (v >> 8) as u8,
+            b: v as u8,
+        });
+    }
+    let mut parts = text.split(',').map(|p| p.trim().parse::<u8>());
+    let rgb = Rgb {
+        r: parts.next()?.ok()?,
+        g: parts.next()?.ok()?,
+        b: parts.next()?.ok()?,
+    };
+    parts.next().is_none().then_some(rgb)
+}
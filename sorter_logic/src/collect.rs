@@ -0,0 +1,98 @@
+//! Sorting for one color: a [`Collection`] sorts a number of beads of one color and lets the
+//! rest pass, and an [`Extraction`] picks out every bead close to a color.
+//!
+//! ```
+//! use sorter_logic::collect::Collection;
+//! use sorter_logic::{Palette, Rgb};
+//! use sorter_protocol::CollectTarget;
+//!
+//! let red = Rgb { r: 200, g: 20, b: 30 };
+//! let (l, a, b) = red.to_lab();
+//! let target = CollectTarget::Lab { l: l as u8, a: a as i8, b: b as i8 };
+//! let mut collection = Collection::new(target, 2);
+//!
+//! let palette: Palette<8> = Palette::new();
+//! assert!(collection.wants(&palette, &red, 15));
+//! assert!(!collection.wants(&palette, &Rgb { r: 30, g: 60, b: 200 }, 15));
+//! assert!(!collection.record());
+//! assert!(collection.record());
+//! ```
+
+use sorter_protocol::CollectTarget;
+
+use crate::{Palette, Rgb};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Collection {
+    target: CollectTarget,
+    wanted: u16,
+    collected: u16,
+}
+
+impl Collection {
+    pub fn new(target: CollectTarget, wanted: u16) -> Self {
+        Self {
+            target,
+            wanted,
+            collected: 0,
+        }
+    }
+
+    pub fn target(&self) -> CollectTarget {
+        self.target
+    }
+
+    /// True for a bead of `color` that the collection is after: its nearest palette entry is
+    /// the target entry, or it is within `threshold` (squared Lab) of the target color. A bead
+    /// farther than `threshold` from every palette entry matches no entry.
+    pub fn wants<const N: usize>(&self, palette: &Palette<N>, color: &Rgb, threshold: u32) -> bool {
+        match self.target {
+            CollectTarget::PaletteEntry(entry) => palette
+                .nearest(color)
+                .is_some_and(|(i, d)| i == entry as usize && d < threshold),
+            CollectTarget::Lab { l, a, b } => dist_lab(color, (l, a, b)) < threshold,
+        }
+    }
+
+    /// Count a collected bead. True once there are enough.
+    pub fn record(&mut self) -> bool {
+        self.collected = (self.collected + 1).min(self.wanted);
+        self.is_complete()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.collected >= self.wanted
+    }
+
+    pub fn collected(&self) -> u16 {
+        self.collected
+    }
+
+    pub fn wanted(&self) -> u16 {
+        self.wanted
+    }
+}
+
+/// Picks out beads within a tolerance of one color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extraction {
+    lab: (u8, i8, i8),
+    tolerance: u8,
+}
+
+impl Extraction {
+    /// Beads within `tolerance` (Delta E) of the CIELAB color `lab`.
+    pub fn new(lab: (u8, i8, i8), tolerance: u8) -> Self {
+        Self { lab, tolerance }
+    }
+
+    pub fn wants(&self, color: &Rgb) -> bool {
+        dist_lab(color, self.lab) <= (self.tolerance as u32).pow(2)
+    }
+}
+
+// Squared Lab distance, like `Rgb::dist_lab`.
+fn dist_lab(color: &Rgb, (l, a, b): (u8, i8, i8)) -> u32 {
+    let (cl, ca, cb) = color.to_lab();
+    ((cl - l as i32).pow(2) + (ca - a as i32).pow(2) + (cb - b as i32).pow(2)) as u32
+}
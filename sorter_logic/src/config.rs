@@ -0,0 +1,111 @@
+//! The config sector: where each saved record lives, and bringing a sector written by older
+//! firmware up to date.
+//!
+//! The sector holds the [tube layout](crate::layout) at [`LAYOUT_OFFSET`], the sorting
+//! [profile](crate::profile) at [`PROFILE_OFFSET`], the [machine settings](crate::settings) at
+//! [`SETTINGS_OFFSET`], and in its last bytes a header: magic `CONF` and the
+//! [`CONFIG_VERSION`] that wrote it. Firmware from before the header (version 1) left those
+//! bytes erased.
+//!
+//! Each record still reads its own older formats, so an upgrade never loses a calibration.
+//! [`migrate`] rewrites them in the current format once, at power-up, and leaves a sector
+//! written by newer firmware alone.
+//!
+//! ```
+//! use sorter_logic::config::{self, CONFIG_BYTES, CONFIG_VERSION};
+//! use sorter_logic::profile::Profile;
+//!
+//! let mut sector = [0xFF; CONFIG_BYTES];
+//! assert_eq!(config::version(&sector), None);
+//!
+//! // Saved by firmware from before the header.
+//! config::set_profile(&mut sector, Profile::Production);
+//! assert_eq!(config::version(&sector), Some(1));
+//!
+//! assert_eq!(config::migrate(&mut sector), Ok(Some(1)));
+//! assert_eq!(config::version(&sector), Some(CONFIG_VERSION));
+//! assert_eq!(config::profile(&sector), Some(Profile::Production));
+//! assert_eq!(config::migrate(&mut sector), Ok(None));
+//! ```
+
+use crate::layout::{LAYOUT_BYTES, TubeLayout};
+use crate::profile::Profile;
+use crate::settings::{SETTINGS_BYTES, Settings};
+
+/// Bytes of the sector that are read back and rewritten on save.
+pub const CONFIG_BYTES: usize = 512;
+pub const LAYOUT_OFFSET: usize = 0;
+pub const PROFILE_OFFSET: usize = 256;
+pub const SETTINGS_OFFSET: usize = 320;
+/// Version written by this build. 2 added the header and the layout record's CRC.
+pub const CONFIG_VERSION: u8 = 2;
+
+const PROFILE_MAGIC: [u8; 4] = *b"PROF";
+const HEADER_MAGIC: [u8; 4] = *b"CONF";
+const HEADER_OFFSET: usize = CONFIG_BYTES - 8;
+
+const _: () = assert!(LAYOUT_OFFSET + LAYOUT_BYTES <= PROFILE_OFFSET);
+const _: () = assert!(PROFILE_OFFSET + 5 <= SETTINGS_OFFSET);
+const _: () = assert!(SETTINGS_OFFSET + SETTINGS_BYTES <= HEADER_OFFSET);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrateError {
+    /// Written by newer firmware, with this version.
+    Newer(u8),
+}
+
+/// The version that wrote `sector`: from its header, 1 if it holds records but no header, or
+/// `None` if it is blank.
+pub fn version(sector: &[u8; CONFIG_BYTES]) -> Option<u8> {
+    let header = &sector[HEADER_OFFSET..];
+    if header[..4] == HEADER_MAGIC {
+        Some(header[4])
+    } else if sector.iter().all(|&b| b == 0xFF) {
+        None
+    } else {
+        Some(1)
+    }
+}
+
+/// Mark `sector` as written by this build.
+pub fn stamp(sector: &mut [u8; CONFIG_BYTES]) {
+    let header = &mut sector[HEADER_OFFSET..];
+    header[..4].copy_from_slice(&HEADER_MAGIC);
+    header[4] = CONFIG_VERSION;
+    header[5..].fill(0);
+}
+
+/// Rewrite the records of a sector from older firmware in the current format and stamp it.
+/// Records that cannot be read are left as they are. Returns the version migrated from, or
+/// `None` if the sector is blank or already current.
+pub fn migrate(sector: &mut [u8; CONFIG_BYTES]) -> Result<Option<u8>, MigrateError> {
+    let from = match version(sector) {
+        None => return Ok(None),
+        Some(CONFIG_VERSION) => return Ok(None),
+        Some(v) if v > CONFIG_VERSION => return Err(MigrateError::Newer(v)),
+        Some(v) => v,
+    };
+    if let Ok(layout) = TubeLayout::from_bytes(&sector[LAYOUT_OFFSET..]) {
+        sector[LAYOUT_OFFSET..LAYOUT_OFFSET + LAYOUT_BYTES].copy_from_slice(&layout.to_bytes());
+    }
+    if let Ok(settings) = Settings::from_bytes(&sector[SETTINGS_OFFSET..]) {
+        sector[SETTINGS_OFFSET..SETTINGS_OFFSET + SETTINGS_BYTES]
+            .copy_from_slice(&settings.to_bytes());
+    }
+    stamp(sector);
+    Ok(Some(from))
+}
+
+/// The stored sorting profile, if one was saved.
+pub fn profile(sector: &[u8; CONFIG_BYTES]) -> Option<Profile> {
+    let record = &sector[PROFILE_OFFSET..PROFILE_OFFSET + 5];
+    if record[..4] != PROFILE_MAGIC {
+        return None;
+    }
+    Profile::from_id(record[4])
+}
+
+pub fn set_profile(sector: &mut [u8; CONFIG_BYTES], profile: Profile) {
+    sector[PROFILE_OFFSET..PROFILE_OFFSET + 4].copy_from_slice(&PROFILE_MAGIC);
+    sector[PROFILE_OFFSET + 4] = profile.id();
+}
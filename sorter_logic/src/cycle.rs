@@ -0,0 +1,400 @@
+//! One sort cycle as a state machine: agitate a bead into the hopper slot, photograph it,
+//! pick its tube, carry it there and drop it.
+//!
+//! [`SorterFsm`] does no I/O. [`SorterFsm::start`] and [`SorterFsm::next`] return the next
+//! [`Action`], and `next` takes how the last one went. [`SorterFsm::drive`] carries the
+//! actions out through the [hardware traits](crate::hardware), which the firmware implements
+//! with its servo and camera drivers and a test with mocks; [`SorterFsm::run`] drives a whole
+//! cycle, picking the tube with a closure.
+//!
+//! ```
+//! use sorter_logic::cycle::{Action, CycleConfig, CycleEnd, Event, Inspection, SorterFsm, State};
+//! use sorter_logic::layout::TubeLayout;
+//!
+//! let config = CycleConfig {
+//!     pickup_us: 1500,
+//!     camera_us: 1900,
+//!     drop_us: 1200,
+//!     agitation_level: 0,
+//!     retakes: 0,
+//!     layout: TubeLayout::default(),
+//! };
+//! let mut fsm = SorterFsm::new();
+//! let mut action = fsm.start(config);
+//! while !matches!(action, Action::Capture { .. }) {
+//!     action = fsm.next(Event::Done);
+//! }
+//! assert_eq!(fsm.state(), State::Inspect);
+//! let action = fsm.next(Event::Inspected(Inspection::Empty));
+//! assert_eq!(action, Action::Finish(CycleEnd::Empty));
+//! ```
+
+use crate::hardware::{BeadCamera, Positioner};
+use crate::layout::TubeLayout;
+
+/// Offsets either side of the pickup stop for the agitation passes, widest first.
+pub const AGITATION_OFFSETS_US: [u16; 3] = [250, 150, 75];
+/// How far a doubtful bead is nudged off the camera stop before it is photographed again.
+pub const RETAKE_NUDGE_US: u16 = 30;
+/// Wait at the pickup stop before leaving for the camera.
+pub const PICKUP_SETTLE_MS: u32 = 100;
+/// Wait at the camera stop for a steady picture.
+pub const CAMERA_SETTLE_MS: u32 = 200;
+/// Wait at the drop pose before releasing the bead.
+pub const POSE_SETTLE_MS: u32 = 200;
+/// Wait at the drop stop for the bead to leave the slot.
+pub const HOPPER_RELEASE_MS: u32 = 150;
+
+/// Where the stage of a cycle is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Between cycles.
+    Idle,
+    /// Agitating a bead into the slot and carrying it to the camera.
+    Pickup,
+    /// Photographing the bead, again if it looks doubtful.
+    Inspect,
+    /// Waiting for the bead's tube.
+    Classify,
+    /// Moving the bead over its tube.
+    Deliver,
+    /// Releasing the bead.
+    Drop,
+    /// The cycle was abandoned.
+    Error(CycleError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CycleError {
+    /// No frame came back from the camera; the bead stays in the slot for the next pickup.
+    NoFrame,
+    /// An emergency stop froze the servos, so they are not where the cycle thinks.
+    Stopped,
+    /// The tube picked is not in the layout.
+    NotInLayout(u8),
+    /// An event the cycle had no use for at that point.
+    Unexpected,
+}
+
+/// How a hopper move is made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopperMove {
+    /// An agitation pass, ramped so a sudden start does not flick beads back out of the slot.
+    Agitate,
+    /// Onto (or nudging off) the camera stop, gently so it settles without wobble.
+    Approach,
+    /// To the drop stop, releasing the bead.
+    Release,
+}
+
+/// Something for the hardware to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Move the hopper to `us` and answer [`Event::Done`] once there.
+    MoveHopper { us: u16, how: HopperMove },
+    /// Move both servos to a drop pose and answer [`Event::Done`] once there.
+    MoveToPose { hopper: u16, chutes: u16 },
+    /// Wait, then answer [`Event::Done`].
+    Settle { ms: u32 },
+    /// Photograph the bead and answer [`Event::Inspected`]. `retake` counts the retakes of a
+    /// doubtful bead; a retake adds to the earlier photos.
+    Capture { retake: u8 },
+    /// Pick the bead's tube (the reject tube if it is not routed) and answer
+    /// [`Event::Classified`].
+    Classify,
+    /// The cycle is over.
+    Finish(CycleEnd),
+}
+
+/// How the last [`Action`] went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Done,
+    Inspected(Inspection),
+    Classified(u8),
+    /// An emergency stop froze the servos. Abandons the cycle whatever the action was.
+    Stopped,
+}
+
+/// What the photos showed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inspection {
+    /// No frame came back.
+    NoFrame,
+    /// Nothing was picked up.
+    Empty,
+    /// A bead, analyzed or not; `doubtful` if another photo might help.
+    Bead { doubtful: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CycleEnd {
+    /// The bead was dropped into `tube`.
+    Dropped {
+        tube: u8,
+    },
+    /// The slot came back empty.
+    Empty,
+    Failed(CycleError),
+}
+
+/// What a cycle needs to know, fixed when it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleConfig {
+    /// Hopper stops, in microseconds.
+    pub pickup_us: u16,
+    pub camera_us: u16,
+    pub drop_us: u16,
+    /// Extra full-width agitation passes (see [`crate::hopper::PickupMonitor`]).
+    pub agitation_level: u8,
+    /// Most retakes of a doubtful bead.
+    pub retakes: u8,
+    pub layout: TubeLayout,
+}
+
+#[derive(Debug, Clone)]
+pub struct SorterFsm {
+    state: State,
+    config: CycleConfig,
+    // Actions taken so far in this state.
+    step: usize,
+    retake: u8,
+    tube: u8,
+}
+
+impl Default for SorterFsm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SorterFsm {
+    pub fn new() -> Self {
+        Self {
+            state: State::Idle,
+            config: CycleConfig {
+                pickup_us: 0,
+                camera_us: 0,
+                drop_us: 0,
+                agitation_level: 0,
+                retakes: 0,
+                layout: TubeLayout::default(),
+            },
+            step: 0,
+            retake: 0,
+            tube: 0,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Start a cycle, abandoning any in progress. Returns the first action.
+    pub fn start(&mut self, config: CycleConfig) -> Action {
+        self.config = config;
+        self.retake = 0;
+        self.enter(State::Pickup);
+        self.pickup()
+    }
+
+    /// The action after the last one went as `event`.
+    pub fn next(&mut self, event: Event) -> Action {
+        match (self.state, event) {
+            (_, Event::Stopped) => self.fail(CycleError::Stopped),
+            (State::Pickup, Event::Done) => self.pickup(),
+            (State::Inspect, Event::Done) => self.retake(),
+            (State::Inspect, Event::Inspected(inspection)) => self.inspected(inspection),
+            (State::Classify, Event::Classified(tube)) => self.deliver(tube),
+            (State::Deliver, Event::Done) if self.step == 0 => {
+                self.step += 1;
+                Action::Settle { ms: POSE_SETTLE_MS }
+            }
+            (State::Deliver, Event::Done) => {
+                self.enter(State::Drop);
+                Action::MoveHopper {
+                    us: self.config.drop_us,
+                    how: HopperMove::Release,
+                }
+            }
+            (State::Drop, Event::Done) if self.step == 0 => {
+                self.step += 1;
+                Action::Settle {
+                    ms: HOPPER_RELEASE_MS,
+                }
+            }
+            (State::Drop, Event::Done) => self.finish(CycleEnd::Dropped { tube: self.tube }),
+            _ => self.fail(CycleError::Unexpected),
+        }
+    }
+
+    /// Carry out `action` and the ones after it through the hardware, until the cycle needs a
+    /// tube picked ([`Action::Classify`]) or is over ([`Action::Finish`]), and return that.
+    /// An emergency stop during a move ends the cycle.
+    pub async fn drive(
+        &mut self,
+        mut action: Action,
+        positioner: &mut impl Positioner,
+        camera: &mut impl BeadCamera,
+    ) -> Action {
+        loop {
+            let event = match action {
+                Action::MoveHopper { us, how } => {
+                    positioner.move_hopper(us, how).await;
+                    Event::Done
+                }
+                Action::MoveToPose { hopper, chutes } => {
+                    positioner.move_to_pose(hopper, chutes).await;
+                    Event::Done
+                }
+                Action::Settle { ms } => {
+                    positioner.settle(ms).await;
+                    Event::Done
+                }
+                Action::Capture { retake } => Event::Inspected(camera.capture(retake).await),
+                Action::Classify | Action::Finish(_) => return action,
+            };
+            action = self.next(if positioner.is_stopped() {
+                Event::Stopped
+            } else {
+                event
+            });
+        }
+    }
+
+    /// Run a whole cycle through the hardware, picking tubes with `classify`.
+    pub async fn run(
+        &mut self,
+        config: CycleConfig,
+        positioner: &mut impl Positioner,
+        camera: &mut impl BeadCamera,
+        classify: &mut dyn FnMut() -> u8,
+    ) -> CycleEnd {
+        let mut action = self.start(config);
+        loop {
+            action = self.drive(action, positioner, camera).await;
+            match action {
+                Action::Classify => {
+                    let tube = classify();
+                    action = self.next(if positioner.is_stopped() {
+                        Event::Stopped
+                    } else {
+                        Event::Classified(tube)
+                    });
+                }
+                Action::Finish(end) => return end,
+                _ => unreachable!("drive returned {:?}", action),
+            }
+        }
+    }
+
+    fn enter(&mut self, state: State) {
+        self.state = state;
+        self.step = 0;
+    }
+
+    fn finish(&mut self, end: CycleEnd) -> Action {
+        self.state = match end {
+            CycleEnd::Failed(e) => State::Error(e),
+            _ => State::Idle,
+        };
+        Action::Finish(end)
+    }
+
+    fn fail(&mut self, error: CycleError) -> Action {
+        self.finish(CycleEnd::Failed(error))
+    }
+
+    // Full-width passes for the agitation level, passes narrowing to the pickup stop, a
+    // pause there, then on to the camera.
+    fn pickup(&mut self) -> Action {
+        let c = self.config;
+        let wide = 2 * c.agitation_level as usize;
+        let passes = wide + 2 * AGITATION_OFFSETS_US.len();
+        let i = self.step;
+        self.step += 1;
+        let agitate = |us| Action::MoveHopper {
+            us,
+            how: HopperMove::Agitate,
+        };
+        if i < passes {
+            let offset = match i.checked_sub(wide) {
+                None => AGITATION_OFFSETS_US[0],
+                Some(j) => AGITATION_OFFSETS_US[j / 2],
+            };
+            return agitate(if i.is_multiple_of(2) {
+                c.pickup_us - offset
+            } else {
+                c.pickup_us + offset
+            });
+        }
+        match i - passes {
+            0 => agitate(c.pickup_us),
+            1 => Action::Settle {
+                ms: PICKUP_SETTLE_MS,
+            },
+            2 => Action::MoveHopper {
+                us: c.camera_us,
+                how: HopperMove::Approach,
+            },
+            3 => Action::Settle {
+                ms: CAMERA_SETTLE_MS,
+            },
+            _ => {
+                self.enter(State::Inspect);
+                Action::Capture { retake: 0 }
+            }
+        }
+    }
+
+    fn inspected(&mut self, inspection: Inspection) -> Action {
+        match inspection {
+            Inspection::NoFrame => self.fail(CycleError::NoFrame),
+            Inspection::Empty => self.finish(CycleEnd::Empty),
+            Inspection::Bead { doubtful: true } if self.retake < self.config.retakes => {
+                self.retake += 1;
+                self.step = 0;
+                self.retake()
+            }
+            Inspection::Bead { .. } => {
+                self.enter(State::Classify);
+                Action::Classify
+            }
+        }
+    }
+
+    // Nudge the bead off the camera stop and back, settle, photograph it again.
+    fn retake(&mut self) -> Action {
+        let camera_us = self.config.camera_us;
+        let i = self.step;
+        self.step += 1;
+        match i {
+            0 => Action::MoveHopper {
+                us: camera_us - RETAKE_NUDGE_US,
+                how: HopperMove::Approach,
+            },
+            1 => Action::MoveHopper {
+                us: camera_us,
+                how: HopperMove::Approach,
+            },
+            2 => Action::Settle {
+                ms: CAMERA_SETTLE_MS,
+            },
+            _ => Action::Capture {
+                retake: self.retake,
+            },
+        }
+    }
+
+    fn deliver(&mut self, tube: u8) -> Action {
+        let Some(spot) = self.config.layout.locate(tube) else {
+            return self.fail(CycleError::NotInLayout(tube));
+        };
+        self.tube = tube;
+        self.enter(State::Deliver);
+        Action::MoveToPose {
+            hopper: spot.drop_position,
+            chutes: spot.chute_position,
+        }
+    }
+}
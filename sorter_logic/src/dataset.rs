@@ -0,0 +1,177 @@
+//! Labeled captures for building a training set.
+//!
+//! In dataset mode the firmware sends one packet per bead: [`DATASET_MAGIC`], a [`Label`]
+//! ([`LABEL_BYTES`]), the frame length (u16 LE), the frame, then the CRC-16 of everything
+//! between the magic and the CRC (u16 LE). The frame is the first capture of the bead; the
+//! label holds the fused analysis and the routing decision, so frames and results never have
+//! to be matched up by time.
+//!
+//! ```
+//! use sorter_logic::dataset::{self, Label, PACKET_LEN};
+//! use sorter_protocol::FRAME_BYTES;
+//!
+//! let label = Label { tube: Some(3), bead: None };
+//! let frame = [0x21u8; FRAME_BYTES];
+//! let header = dataset::header(&label, FRAME_BYTES as u16);
+//! let mut packet = header.to_vec();
+//! packet.extend_from_slice(&frame);
+//! packet.extend_from_slice(&dataset::crc(&header, &frame));
+//! assert_eq!(packet.len(), PACKET_LEN);
+//!
+//! assert_eq!(dataset::decode(&packet), Some((label, &frame[..])));
+//! packet[100] ^= 1;
+//! assert_eq!(dataset::decode(&packet), None);
+//! ```
+
+use sorter_protocol::{Crc16, FRAME_BYTES};
+
+pub use sorter_protocol::DATASET_MAGIC;
+
+use crate::{BeadAnalysis, BeadFinish, Rgb};
+
+/// Size of an encoded [`Label`].
+pub const LABEL_BYTES: usize = 42;
+/// Magic, label and frame length.
+pub const HEADER_LEN: usize = 4 + LABEL_BYTES + 2;
+/// A packet carrying one [`FRAME_BYTES`] frame.
+pub const PACKET_LEN: usize = HEADER_LEN + FRAME_BYTES + 2;
+
+const NO_TUBE: u8 = 0xFF;
+
+/// What the sorter made of one bead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Label {
+    /// The tube it was routed to; `None` if it went to the reject tube.
+    pub tube: Option<u8>,
+    /// `None` if no bead was found in the frames.
+    pub bead: Option<Measurement>,
+}
+
+/// The parts of a [`BeadAnalysis`] worth training on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub color: Rgb,
+    pub variance: u32,
+    /// `var_r`, `var_g` and `var_b`.
+    pub channel_variance: [u32; 3],
+    pub pixel_count: u32,
+    pub center: (i32, i32),
+    pub finish: BeadFinish,
+    pub sparkle: u32,
+    pub diameter_px: f32,
+    pub eccentricity: f32,
+}
+
+impl From<&BeadAnalysis> for Measurement {
+    fn from(a: &BeadAnalysis) -> Self {
+        Self {
+            color: a.average_color,
+            variance: a.variance,
+            channel_variance: [a.var_r, a.var_g, a.var_b],
+            pixel_count: a.pixel_count,
+            center: a.center,
+            finish: a.finish,
+            sparkle: a.sparkle,
+            diameter_px: a.diameter_px,
+            eccentricity: a.eccentricity,
+        }
+    }
+}
+
+impl Label {
+    /// The tube (`0xFF` for none), a found flag, then the [`Measurement`] fields in order:
+    /// RGB, the variances, pixel count and sparkle as u32 LE, the center as two i16 LE, the
+    /// finish as a byte, and the two f32 LE. A missing measurement is all zeros.
+    pub fn encode(&self) -> [u8; LABEL_BYTES] {
+        let mut out = [0u8; LABEL_BYTES];
+        out[0] = self.tube.unwrap_or(NO_TUBE);
+        let Some(m) = self.bead else {
+            return out;
+        };
+        out[1] = 1;
+        out[2..5].copy_from_slice(&[m.color.r, m.color.g, m.color.b]);
+        let words = [
+            m.variance,
+            m.channel_variance[0],
+            m.channel_variance[1],
+            m.channel_variance[2],
+            m.pixel_count,
+            m.sparkle,
+        ];
+        for (chunk, w) in out[5..29].chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&w.to_le_bytes());
+        }
+        out[29..31].copy_from_slice(&(m.center.0 as i16).to_le_bytes());
+        out[31..33].copy_from_slice(&(m.center.1 as i16).to_le_bytes());
+        out[33] = m.finish.id();
+        out[34..38].copy_from_slice(&m.diameter_px.to_le_bytes());
+        out[38..42].copy_from_slice(&m.eccentricity.to_le_bytes());
+        out
+    }
+
+    /// `None` for an unknown finish.
+    pub fn decode(b: &[u8; LABEL_BYTES]) -> Option<Self> {
+        let tube = (b[0] != NO_TUBE).then_some(b[0]);
+        if b[1] == 0 {
+            return Some(Self { tube, bead: None });
+        }
+        let u32_at = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        let i16_at = |i: usize| i16::from_le_bytes([b[i], b[i + 1]]) as i32;
+        let f32_at = |i: usize| f32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        let bead = Measurement {
+            color: Rgb {
+                r: b[2],
+                g: b[3],
+                b: b[4],
+            },
+            variance: u32_at(5),
+            channel_variance: [u32_at(9), u32_at(13), u32_at(17)],
+            pixel_count: u32_at(21),
+            sparkle: u32_at(25),
+            center: (i16_at(29), i16_at(31)),
+            finish: BeadFinish::from_id(b[33])?,
+            diameter_px: f32_at(34),
+            eccentricity: f32_at(38),
+        };
+        Some(Self {
+            tube,
+            bead: Some(bead),
+        })
+    }
+}
+
+/// Magic, encoded label and frame length.
+pub fn header(label: &Label, frame_len: u16) -> [u8; HEADER_LEN] {
+    let mut out = [0u8; HEADER_LEN];
+    out[..4].copy_from_slice(&DATASET_MAGIC);
+    out[4..4 + LABEL_BYTES].copy_from_slice(&label.encode());
+    out[4 + LABEL_BYTES..].copy_from_slice(&frame_len.to_le_bytes());
+    out
+}
+
+/// The trailing CRC for a packet with this header and frame.
+pub fn crc(header: &[u8; HEADER_LEN], frame: &[u8]) -> [u8; 2] {
+    let mut crc = Crc16::new();
+    crc.update(&header[4..]);
+    crc.update(frame);
+    crc.finish().to_le_bytes()
+}
+
+/// Check a packet that starts with [`DATASET_MAGIC`] and return the label and frame. `None`
+/// if the packet is incomplete, the frame is not [`FRAME_BYTES`], or the CRC does not match.
+pub fn decode(packet: &[u8]) -> Option<(Label, &[u8])> {
+    let header: &[u8; HEADER_LEN] = packet.get(..HEADER_LEN)?.try_into().ok()?;
+    if header[..4] != DATASET_MAGIC {
+        return None;
+    }
+    let len = u16::from_le_bytes([header[HEADER_LEN - 2], header[HEADER_LEN - 1]]) as usize;
+    if len != FRAME_BYTES {
+        return None;
+    }
+    let frame = packet.get(HEADER_LEN..HEADER_LEN + len)?;
+    if packet.get(HEADER_LEN + len..HEADER_LEN + len + 2)? != crc(header, frame) {
+        return None;
+    }
+    let label = Label::decode(header[4..4 + LABEL_BYTES].try_into().ok()?)?;
+    Some((label, frame))
+}
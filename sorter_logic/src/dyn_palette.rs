@@ -0,0 +1,225 @@
+//! A [`Palette`](crate::Palette) that grows on the heap (the `alloc` feature).
+//!
+//! The firmware's palette is sized at compile time. Host tools training on a large labeled
+//! set don't know up front how many entries they will need, and a few thousand fixed
+//! entries are too large for the stack. [`DynPalette`] has the same API and gives the same
+//! answers as [`Palette<N>`](crate::Palette) with an unlimited `N`: it never reports
+//! [`PaletteMatch::Full`] unless frozen.
+
+use alloc::vec::Vec;
+
+use crate::{
+    CentroidUpdate, ColorMetric, PaletteEntry, PaletteMatch, PaletteMode, Rgb, fill_stats,
+    find_nearest, mean_within, merge_candidate, metric_coords,
+};
+
+#[derive(Default)]
+pub struct DynPalette {
+    colors: Vec<PaletteEntry>,
+    // As in `Palette`: each centroid in the metric's color space, and the sum of each
+    // sample's distance from the centroid as it was added.
+    coords: Vec<(i32, i32, i32)>,
+    spread: Vec<u64>,
+    metric: ColorMetric,
+    mode: PaletteMode,
+    update: CentroidUpdate,
+}
+
+impl DynPalette {
+    pub const fn new() -> Self {
+        Self::with_metric(ColorMetric::Lab)
+    }
+
+    pub const fn with_metric(metric: ColorMetric) -> Self {
+        Self {
+            colors: Vec::new(),
+            coords: Vec::new(),
+            spread: Vec::new(),
+            metric,
+            mode: PaletteMode::Learning,
+            update: CentroidUpdate::Cumulative,
+        }
+    }
+
+    pub fn metric(&self) -> ColorMetric {
+        self.metric
+    }
+
+    pub fn mode(&self) -> PaletteMode {
+        self.mode
+    }
+
+    /// See [`Palette::set_mode`](crate::Palette::set_mode).
+    pub fn set_mode(&mut self, mode: PaletteMode) {
+        self.mode = match mode {
+            PaletteMode::TrainFor(0) => PaletteMode::Frozen,
+            mode => mode,
+        };
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.mode == PaletteMode::Frozen
+    }
+
+    pub fn centroid_update(&self) -> CentroidUpdate {
+        self.update
+    }
+
+    /// See [`Palette::set_centroid_update`](crate::Palette::set_centroid_update).
+    pub fn set_centroid_update(&mut self, update: CentroidUpdate) {
+        self.update = update;
+    }
+
+    /// Add each color as an entry of its own, in order. Returns how many were added (all of
+    /// them).
+    pub fn seed_from(&mut self, colors: &[Rgb]) -> usize {
+        for rgb in colors {
+            self.push(PaletteEntry::new(*rgb, 0));
+        }
+        colors.len()
+    }
+
+    /// See [`Palette::seed_entry`](crate::Palette::seed_entry); never full.
+    pub fn seed_entry(&mut self, entry: PaletteEntry) -> usize {
+        self.push(entry)
+    }
+
+    fn push(&mut self, entry: PaletteEntry) -> usize {
+        self.colors.push(entry);
+        self.coords.push((0, 0, 0));
+        self.spread.push(0);
+        let idx = self.colors.len() - 1;
+        self.update_coords(idx);
+        idx
+    }
+
+    fn update_coords(&mut self, index: usize) {
+        if let Some(coords) = metric_coords(self.metric, &self.colors[index].avg().0) {
+            self.coords[index] = coords;
+        }
+    }
+
+    /// See [`Palette::match_color`](crate::Palette::match_color). A new color always gets an
+    /// entry unless the palette is frozen.
+    ///
+    /// ```
+    /// use sorter_logic::dyn_palette::DynPalette;
+    /// use sorter_logic::{PaletteMatch, Rgb};
+    ///
+    /// // A thousand colors, ten levels a channel, far enough apart in Lab to be told apart.
+    /// let mut palette = DynPalette::new();
+    /// for i in 0..1000u32 {
+    ///     let level = |n: u32| (60 + n % 10 * 16) as u8;
+    ///     let rgb = Rgb { r: level(i), g: level(i / 10), b: level(i / 100) };
+    ///     assert_eq!(palette.match_color(&rgb, 0, 1), PaletteMatch::NewEntry(i as usize));
+    /// }
+    /// assert_eq!(palette.len(), 1000);
+    /// ```
+    pub fn match_color(&mut self, rgb: &Rgb, variance: u32, threshold: u32) -> PaletteMatch {
+        if let Some((idx, _)) = self.find_nearest(rgb, threshold) {
+            return PaletteMatch::Match(idx);
+        }
+        if self.is_frozen() {
+            PaletteMatch::Full
+        } else {
+            PaletteMatch::NewEntry(self.push(PaletteEntry::new(*rgb, variance)))
+        }
+    }
+
+    /// Closest entry and its distance under the palette's metric. `None` when empty.
+    pub fn nearest(&self, rgb: &Rgb) -> Option<(usize, u32)> {
+        self.find_nearest(rgb, u32::MAX)
+    }
+
+    fn find_nearest(&self, rgb: &Rgb, limit: u32) -> Option<(usize, u32)> {
+        find_nearest(
+            self.metric,
+            &self.coords,
+            self.colors.iter().map(|e| Some(e.avg().0)),
+            rgb,
+            limit,
+        )
+    }
+
+    /// See [`Palette::add_sample`](crate::Palette::add_sample).
+    pub fn add_sample(&mut self, index: usize, rgb: &Rgb, variance: u32) {
+        if self.is_frozen() {
+            return;
+        }
+        if let Some(entry) = self.colors.get_mut(index) {
+            self.spread[index] += rgb.dist_metric(&entry.avg().0, self.metric) as u64;
+            self.update.apply(entry, *rgb, variance);
+            self.update_coords(index);
+        }
+        if let PaletteMode::TrainFor(n) = self.mode {
+            self.set_mode(PaletteMode::TrainFor(n - 1));
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Rgb> {
+        self.colors.get(index).map(|e| e.avg().0)
+    }
+
+    pub fn get_entry(&self, index: usize) -> Option<PaletteEntry> {
+        self.colors.get(index).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    /// See [`Palette::stats`](crate::Palette::stats).
+    pub fn stats(&self) -> DynPaletteStats {
+        let mut stats = DynPaletteStats {
+            len: self.len(),
+            closest_pair: None,
+            counts: alloc::vec![0; self.len()],
+            within: alloc::vec![0; self.len()],
+        };
+        stats.closest_pair = fill_stats(
+            self.metric,
+            &self.colors,
+            &self.spread,
+            &mut stats.counts,
+            &mut stats.within,
+        );
+        stats
+    }
+
+    /// Nearest catalog color for each entry.
+    #[cfg(feature = "catalog")]
+    pub fn label_entries<'a>(
+        &self,
+        catalog: &'a crate::catalog::Catalog,
+    ) -> Vec<Option<crate::catalog::CatalogMatch<'a>>> {
+        self.colors
+            .iter()
+            .map(|e| catalog.nearest(&e.avg().0))
+            .collect()
+    }
+}
+
+/// [`PaletteStats`](crate::PaletteStats) for a [`DynPalette`], one element per entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynPaletteStats {
+    pub len: usize,
+    pub closest_pair: Option<(usize, usize, u32)>,
+    pub counts: Vec<u32>,
+    pub within: Vec<u32>,
+}
+
+impl DynPaletteStats {
+    /// See [`PaletteStats::mean_within`](crate::PaletteStats::mean_within).
+    pub fn mean_within(&self) -> u32 {
+        mean_within(&self.counts, &self.within)
+    }
+
+    /// See [`PaletteStats::merge_candidate`](crate::PaletteStats::merge_candidate).
+    pub fn merge_candidate(&self) -> Option<(usize, usize)> {
+        merge_candidate(self.closest_pair, &self.within)
+    }
+}
@@ -0,0 +1,223 @@
+//! A record of what the machine did recently, kept in RAM for post-mortem debugging.
+//!
+//! The firmware pushes an entry for each bead sorted or rejected, each error, jam and mode
+//! change. Once the log is full the oldest entries are overwritten. The host asks for it with
+//! [`CMD_DUMP_LOG`], which works even when nothing was watching the debug console.
+//!
+//! The reply is [`LOG_MAGIC`], an entry count, then per entry `[time (ms since boot, u32
+//! LE), event code, four argument bytes]`, oldest first.
+//!
+//! ```
+//! use sorter_logic::Rgb;
+//! use sorter_logic::event_log::{self, EventLog, LogEvent};
+//!
+//! let mut log: EventLog<2> = EventLog::new();
+//! log.push(100, LogEvent::Started);
+//! log.push(2500, LogEvent::Sorted { tube: 4, color: Rgb { r: 200, g: 20, b: 30 } });
+//! log.push(4100, LogEvent::Rejected);
+//!
+//! let mut packet = [0u8; event_log::packet_len(2)];
+//! let len = log.encode(&mut packet);
+//! let entries: Vec<_> = event_log::decode(&packet[4..len]).unwrap().collect();
+//! assert_eq!(entries[0].at_ms, 2500);
+//! assert_eq!(entries[1].event, LogEvent::Rejected);
+//! ```
+
+pub use sorter_protocol::{CMD_DUMP_LOG, LOG_MAGIC};
+
+use crate::Rgb;
+
+pub const LOG_ENTRY_BYTES: usize = 9;
+
+/// Packet length for a log of `capacity` entries, at most 255.
+pub const fn packet_len(capacity: usize) -> usize {
+    5 + capacity * LOG_ENTRY_BYTES
+}
+
+/// Something worth remembering.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogEvent {
+    /// Sorting started or resumed.
+    Started,
+    /// Sorting paused by a button press.
+    Stopped,
+    /// An emergency stop froze the servos.
+    EmergencyStop,
+    /// The firmware came back up after the watchdog reset it.
+    WatchdogReset,
+    /// A bead was dropped into `tube`.
+    Sorted { tube: u8, color: Rgb },
+    /// A bead went to the reject (or bulk return) tube: it could not be analyzed or routed,
+    /// or was not one being collected or extracted.
+    Rejected,
+    /// No frame came back from the camera.
+    CameraError,
+    /// Pickups kept coming back empty and sorting stopped.
+    HopperStalled { empty_pickups: u16 },
+    /// A run limit stopped sorting after this many beads.
+    RunLimitReached { beads: u16 },
+    /// Dry run was turned on or off.
+    DryRun(bool),
+    /// Dataset mode was turned on or off.
+    DatasetMode(bool),
+    /// Collecting this many beads of one color; 0 went back to sorting everything.
+    Collect { count: u16 },
+    /// Single-color extraction was turned on or off.
+    Extract(bool),
+    /// The supply fell below the brownout warning threshold.
+    SupplySag { mv: u16 },
+    /// A servo kept drawing current after its move and sorting stopped; the sense voltage.
+    ServoStalled { mv: u16 },
+    /// The machine went to sleep after idling, or woke up again.
+    Asleep(bool),
+}
+
+impl LogEvent {
+    fn encode(&self) -> (u8, [u8; 4]) {
+        let flag = |on: bool| [on as u8, 0, 0, 0];
+        let word = |v: u16| {
+            let [lo, hi] = v.to_le_bytes();
+            [lo, hi, 0, 0]
+        };
+        match *self {
+            LogEvent::Started => (0x01, [0; 4]),
+            LogEvent::Stopped => (0x02, [0; 4]),
+            LogEvent::EmergencyStop => (0x03, [0; 4]),
+            LogEvent::WatchdogReset => (0x04, [0; 4]),
+            LogEvent::Sorted { tube, color } => (0x05, [tube, color.r, color.g, color.b]),
+            LogEvent::Rejected => (0x06, [0; 4]),
+            LogEvent::CameraError => (0x07, [0; 4]),
+            LogEvent::HopperStalled { empty_pickups } => (0x08, word(empty_pickups)),
+            LogEvent::RunLimitReached { beads } => (0x09, word(beads)),
+            LogEvent::DryRun(on) => (0x0A, flag(on)),
+            LogEvent::DatasetMode(on) => (0x0B, flag(on)),
+            LogEvent::Collect { count } => (0x0C, word(count)),
+            LogEvent::Extract(on) => (0x0D, flag(on)),
+            LogEvent::SupplySag { mv } => (0x0E, word(mv)),
+            LogEvent::ServoStalled { mv } => (0x0F, word(mv)),
+            LogEvent::Asleep(on) => (0x10, flag(on)),
+        }
+    }
+
+    fn decode(code: u8, arg: [u8; 4]) -> Option<Self> {
+        let word = u16::from_le_bytes([arg[0], arg[1]]);
+        let flag = arg[0] != 0;
+        Some(match code {
+            0x01 => LogEvent::Started,
+            0x02 => LogEvent::Stopped,
+            0x03 => LogEvent::EmergencyStop,
+            0x04 => LogEvent::WatchdogReset,
+            0x05 => LogEvent::Sorted {
+                tube: arg[0],
+                color: Rgb {
+                    r: arg[1],
+                    g: arg[2],
+                    b: arg[3],
+                },
+            },
+            0x06 => LogEvent::Rejected,
+            0x07 => LogEvent::CameraError,
+            0x08 => LogEvent::HopperStalled {
+                empty_pickups: word,
+            },
+            0x09 => LogEvent::RunLimitReached { beads: word },
+            0x0A => LogEvent::DryRun(flag),
+            0x0B => LogEvent::DatasetMode(flag),
+            0x0C => LogEvent::Collect { count: word },
+            0x0D => LogEvent::Extract(flag),
+            0x0E => LogEvent::SupplySag { mv: word },
+            0x0F => LogEvent::ServoStalled { mv: word },
+            0x10 => LogEvent::Asleep(flag),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogEntry {
+    /// Milliseconds since boot.
+    pub at_ms: u32,
+    pub event: LogEvent,
+}
+
+/// The last `N` events. `N` is at most 255 so the count fits the reply.
+#[derive(Debug, Clone)]
+pub struct EventLog<const N: usize> {
+    entries: [LogEntry; N],
+    // Where the next entry goes, and how many are kept.
+    next: usize,
+    len: usize,
+}
+
+impl<const N: usize> Default for EventLog<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> EventLog<N> {
+    pub const fn new() -> Self {
+        Self {
+            entries: [LogEntry {
+                at_ms: 0,
+                event: LogEvent::Started,
+            }; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Add an event, overwriting the oldest once full.
+    pub fn push(&mut self, at_ms: u32, event: LogEvent) {
+        if N == 0 {
+            return;
+        }
+        self.entries[self.next] = LogEntry { at_ms, event };
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The kept entries, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &LogEntry> {
+        let start = (self.next + N - self.len) % N.max(1);
+        (0..self.len).map(move |i| &self.entries[(start + i) % N])
+    }
+
+    /// Write a log packet (magic included) into `out`, which must hold [`packet_len`]`(N)`
+    /// bytes. Returns the length.
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        out[..4].copy_from_slice(&LOG_MAGIC);
+        out[4] = self.len as u8;
+        let mut len = 5;
+        for entry in self.iter() {
+            let (code, arg) = entry.event.encode();
+            let bytes = &mut out[len..len + LOG_ENTRY_BYTES];
+            bytes[..4].copy_from_slice(&entry.at_ms.to_le_bytes());
+            bytes[4] = code;
+            bytes[5..].copy_from_slice(&arg);
+            len += LOG_ENTRY_BYTES;
+        }
+        len
+    }
+}
+
+/// The entries in the body of a log packet (everything after the magic), skipping events this
+/// build does not know. `None` if the body is truncated.
+pub fn decode(body: &[u8]) -> Option<impl Iterator<Item = LogEntry> + '_> {
+    let (&n, rest) = body.split_first()?;
+    let entries = rest.get(..n as usize * LOG_ENTRY_BYTES)?;
+    Some(entries.chunks_exact(LOG_ENTRY_BYTES).filter_map(|e| {
+        Some(LogEntry {
+            at_ms: u32::from_le_bytes([e[0], e[1], e[2], e[3]]),
+            event: LogEvent::decode(e[4], [e[5], e[6], e[7], e[8]])?,
+        })
+    }))
+}
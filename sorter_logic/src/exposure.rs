@@ -0,0 +1,83 @@
+//! Camera LED brightness, steered by the luminance of the background patch so every frame
+//! is exposed alike whatever the LED's age, the room light or the camera's mood that session.
+//!
+//! The LED duty only changes when the patch leaves the target band, so a background
+//! reference taken inside the band stays valid while sorting.
+//!
+//! ```
+//! use sorter_logic::Rgb;
+//! use sorter_logic::exposure::{DEFAULT_LED_DUTY, LedControl};
+//!
+//! let mut led = LedControl::new(Some(150));
+//! // Inside the band: leave it be.
+//! assert_eq!(led.update(Rgb { r: 145, g: 155, b: 150 }), None);
+//! // Too dark: brighten, a step at a time.
+//! let duty = led.update(Rgb { r: 90, g: 90, b: 90 }).unwrap();
+//! assert!(duty > DEFAULT_LED_DUTY);
+//! ```
+
+use crate::{Rgb, luma};
+
+/// LED duty is in thousandths of the PWM period.
+pub const LED_TOP: u16 = 1000;
+/// Duty before any adjustment, and the fixed duty without a target.
+pub const DEFAULT_LED_DUTY: u16 = 500;
+/// Least duty the control will go down to, so the LED never goes out.
+pub const MIN_LED_DUTY: u16 = 20;
+/// Default for [`crate::settings::Setting::LedTargetLuma`].
+pub const DEFAULT_TARGET_LUMA: u16 = 150;
+/// How far the patch luma may be from the target before the duty changes.
+pub const LUMA_BAND: u8 = 12;
+// Largest change in one step, in percent of the duty, so one odd frame cannot swing it.
+const MAX_STEP_PERCENT: u32 = 25;
+
+/// Closed-loop camera LED duty.
+#[derive(Debug, Clone, Copy)]
+pub struct LedControl {
+    target: Option<u8>,
+    duty: u16,
+}
+
+impl LedControl {
+    /// Hold the background patch at `target` luma; `None` keeps [`DEFAULT_LED_DUTY`].
+    pub const fn new(target: Option<u8>) -> Self {
+        Self {
+            target,
+            duty: DEFAULT_LED_DUTY,
+        }
+    }
+
+    /// Change the target. Without one the duty goes back to [`DEFAULT_LED_DUTY`].
+    pub fn set_target(&mut self, target: Option<u8>) {
+        self.target = target;
+        if target.is_none() {
+            self.duty = DEFAULT_LED_DUTY;
+        }
+    }
+
+    /// Duty to drive the LED at, out of [`LED_TOP`].
+    pub fn duty(&self) -> u16 {
+        self.duty
+    }
+
+    /// Fold in a frame's background patch color. Returns the new duty if it changed.
+    pub fn update(&mut self, patch: Rgb) -> Option<u16> {
+        let target = self.target? as u32;
+        let measured = luma(patch);
+        if measured.abs_diff(target) <= LUMA_BAND as u32 {
+            return None;
+        }
+        // The patch brightens about in proportion to the duty.
+        let duty = self.duty as u32;
+        let wanted = duty * target / measured.max(1);
+        let step = (duty * MAX_STEP_PERCENT / 100).max(1);
+        let next = wanted
+            .clamp(duty.saturating_sub(step), duty + step)
+            .clamp(MIN_LED_DUTY as u32, LED_TOP as u32) as u16;
+        if next == self.duty {
+            return None;
+        }
+        self.duty = next;
+        Some(next)
+    }
+}
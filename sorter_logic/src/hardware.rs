@@ -0,0 +1,36 @@
+//! What the sort cycle needs from the machine, as traits, so [`SorterFsm::drive`] runs the
+//! same against the firmware's drivers or a test's mocks.
+//!
+//! The firmware implements these with its embassy-rp drivers; nothing here depends on them.
+//!
+//! [`SorterFsm::drive`]: crate::cycle::SorterFsm::drive
+
+use crate::Rgb;
+use crate::cycle::{HopperMove, Inspection};
+
+/// Moves the bead between the hopper stops and over the tubes.
+#[allow(async_fn_in_trait)]
+pub trait Positioner {
+    /// Move the hopper to `us` and return once it is there (or was stopped).
+    async fn move_hopper(&mut self, us: u16, how: HopperMove);
+    /// Move the hopper and chutes to a drop pose and return once both are there.
+    async fn move_to_pose(&mut self, hopper: u16, chutes: u16);
+    /// Wait while the servos and the bead settle.
+    async fn settle(&mut self, ms: u32);
+    /// True while an emergency stop holds the servos.
+    fn is_stopped(&self) -> bool;
+}
+
+/// Photographs the bead in the hopper slot.
+#[allow(async_fn_in_trait)]
+pub trait BeadCamera {
+    /// Photograph the bead and say what the photos show. `retake` counts the retakes of a
+    /// doubtful bead; a retake adds to the earlier photos.
+    async fn capture(&mut self, retake: u8) -> Inspection;
+}
+
+/// A status light.
+#[allow(async_fn_in_trait)]
+pub trait Indicator {
+    async fn show(&mut self, color: Rgb);
+}
@@ -0,0 +1,60 @@
+//! Two-exposure capture: a short frame that keeps white and pearl beads off the sensor's
+//! ceiling, and a long one that lifts black and navy beads off its floor, fused into one
+//! frame for the usual analysis.
+//!
+//! The frames are taken [`EXPOSURE_STEP`] times shorter and longer than the locked
+//! exposure. Each pixel's light is estimated from the long frame, which is less noisy in the
+//! dark, unless it is close to clipping, then from the short frame. The estimate is then
+//! squeezed back into 8 bits along a curve that stretches shadows and compresses highlights,
+//! so the fused frame is not linear in light: colors learned with one capture mode do not
+//! carry over to the other.
+//!
+//! ```
+//! use sorter_logic::{Rgb, hdr};
+//!
+//! let px = |v: u8| Rgb { r: v, g: v, b: v }.to_rgb565().to_be_bytes();
+//! // A dark pixel, and one the long exposure clipped.
+//! let mut short = [px(10), px(120)].concat();
+//! let long = [px(40), px(255)].concat();
+//! assert_eq!(hdr::merge(&mut short, &long), 2);
+//! let fused: Vec<u8> = sorter_logic::decode_rgb565_be(&short).map(|p| p.g).collect();
+//! assert!(fused[0] > 20 && fused[1] < 255);
+//! ```
+
+use crate::{Rgb, decode_rgb565_be};
+
+/// How many times shorter and longer than the locked exposure the two frames are.
+pub const EXPOSURE_STEP: u32 = 2;
+// Brightest light estimate, at the locked exposure's scale.
+const MAX_LIGHT: u32 = 255 * EXPOSURE_STEP;
+// Where the tone curve bends: lower knees stretch the shadows more.
+const KNEE: u32 = 128;
+// Long frame channel above which the short frame takes over, fully so at 255.
+const CLIP_START: u32 = 192;
+
+/// Fuse two big-endian RGB565 frames of the same scene, the short exposure in `short` and the
+/// long in `long`, into `short`. Merges as many pixels as both hold and returns the count.
+pub fn merge(short: &mut [u8], long: &[u8]) -> usize {
+    let mut n = 0;
+    for (s, l) in short.chunks_exact_mut(2).zip(decode_rgb565_be(long)) {
+        let dim = Rgb::from_rgb565(u16::from_be_bytes([s[0], s[1]]));
+        // Weight of the short frame, out of 64: how close the long frame is to clipping.
+        let peak = l.r.max(l.g).max(l.b) as u32;
+        let w = peak.saturating_sub(CLIP_START);
+        let fuse = |s: u8, l: u8| {
+            let light = (s as u32 * EXPOSURE_STEP * w
+                + l as u32 * (255 - CLIP_START - w) / EXPOSURE_STEP)
+                / (255 - CLIP_START);
+            // Reinhard-style: 0 stays 0 and MAX_LIGHT maps to 255.
+            (255 * light * (MAX_LIGHT + KNEE) / (MAX_LIGHT * (light + KNEE))) as u8
+        };
+        let fused = Rgb {
+            r: fuse(dim.r, l.r),
+            g: fuse(dim.g, l.g),
+            b: fuse(dim.b, l.b),
+        };
+        s.copy_from_slice(&fused.to_rgb565().to_be_bytes());
+        n += 1;
+    }
+    n
+}
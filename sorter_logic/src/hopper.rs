@@ -0,0 +1,121 @@
+//! Hopper feed monitoring: escalates agitation on empty pickups, decides when the hopper
+//! has most likely run dry, and gives up when it stays dry (or jammed) so the sorter stops
+//! instead of probing forever.
+
+/// Highest agitation level [`PickupMonitor::agitation_level`] will report.
+pub const MAX_AGITATION: u8 = 3;
+/// Default for [`PickupMonitor::set_stall_after`].
+pub const DEFAULT_STALL_AFTER: u16 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopperEvent {
+    None,
+    /// Pickups kept failing at maximum agitation; ask the operator to refill.
+    RefillNeeded,
+    /// A pickup succeeded while waiting for a refill.
+    Refilled,
+    /// Still nothing after the stall limit while waiting for a refill: the hopper is starved
+    /// or jammed. Stop until [`PickupMonitor::resume`].
+    Stalled,
+}
+
+/// Tracks consecutive empty pickups.
+///
+/// Every `empties_per_level` empties raise the agitation level by one (up to
+/// [`MAX_AGITATION`]). After `refill_after` further empties at maximum agitation the hopper is
+/// reported empty until a pickup succeeds again, and after
+/// [`set_stall_after`](Self::set_stall_after) more it is reported stalled.
+#[derive(Debug, Clone, Copy)]
+pub struct PickupMonitor {
+    empty_streak: u16,
+    empties_per_level: u16,
+    refill_after: u16,
+    stall_after: u16,
+    refill: bool,
+    stalled: bool,
+}
+
+impl Default for PickupMonitor {
+    fn default() -> Self {
+        Self::new(2, 4)
+    }
+}
+
+impl PickupMonitor {
+    pub const fn new(empties_per_level: u16, refill_after: u16) -> Self {
+        Self {
+            empty_streak: 0,
+            empties_per_level,
+            refill_after,
+            stall_after: DEFAULT_STALL_AFTER,
+            refill: false,
+            stalled: false,
+        }
+    }
+
+    /// Empty pickups, after the refill prompt, before reporting [`HopperEvent::Stalled`].
+    pub fn set_stall_after(&mut self, empties: u16) {
+        self.stall_after = empties;
+    }
+
+    // Empties in a row at which the refill prompt starts.
+    fn refill_streak(&self) -> u16 {
+        self.empties_per_level
+            .saturating_mul(MAX_AGITATION as u16)
+            .saturating_add(self.refill_after)
+    }
+
+    /// Record the outcome of one pickup attempt.
+    pub fn record(&mut self, picked_up: bool) -> HopperEvent {
+        if picked_up {
+            self.empty_streak = 0;
+            self.stalled = false;
+            if self.refill {
+                self.refill = false;
+                return HopperEvent::Refilled;
+            }
+            return HopperEvent::None;
+        }
+
+        self.empty_streak = self.empty_streak.saturating_add(1);
+        let refill_streak = self.refill_streak();
+        if !self.refill && self.empty_streak >= refill_streak {
+            self.refill = true;
+            return HopperEvent::RefillNeeded;
+        }
+        if !self.stalled && self.empty_streak >= refill_streak.saturating_add(self.stall_after) {
+            self.stalled = true;
+            return HopperEvent::Stalled;
+        }
+        HopperEvent::None
+    }
+
+    /// Clear a stall (the operator has looked at the hopper). Pickups stay at maximum
+    /// agitation with the refill prompt, and the full stall limit applies again.
+    pub fn resume(&mut self) {
+        if self.stalled {
+            self.stalled = false;
+            self.empty_streak = self.refill_streak();
+        }
+    }
+
+    /// 0 for normal agitation, rising with consecutive empty pickups.
+    pub fn agitation_level(&self) -> u8 {
+        match self.empties_per_level {
+            0 => MAX_AGITATION,
+            n => (self.empty_streak / n).min(MAX_AGITATION as u16) as u8,
+        }
+    }
+
+    pub fn needs_refill(&self) -> bool {
+        self.refill
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.stalled
+    }
+
+    pub fn empty_streak(&self) -> u16 {
+        self.empty_streak
+    }
+}
@@ -0,0 +1,124 @@
+//! A k-d tree over palette centroids, for palettes too large for a linear scan.
+//!
+//! [`Palette::nearest`] checks every entry, which is fine at the firmware's 128 entries.
+//! Host training runs with thousands of entries can keep a [`PaletteIndex`] next to the
+//! palette and match through [`Palette::match_color_indexed`] instead. The tree is rebuilt
+//! on the next lookup after any centroid changes, so it pays off when lookups outnumber
+//! palette updates (production sorting, replaying a log against a trained palette).
+//!
+//! The index gives exactly the same answers as [`Palette::nearest`], ties included. The
+//! Lab-based and OKLab metrics can be indexed; with [`crate::ColorMetric::HueWeighted`]
+//! lookups fall back to the linear scan.
+
+use crate::{Palette, Rgb};
+
+type Coords = (i32, i32, i32);
+
+fn axis(c: Coords, depth: usize) -> i32 {
+    match depth % 3 {
+        0 => c.0,
+        1 => c.1,
+        _ => c.2,
+    }
+}
+
+fn dist(a: Coords, b: Coords) -> u32 {
+    ((a.0 - b.0).pow(2) + (a.1 - b.1).pow(2) + (a.2 - b.2).pow(2)) as u32
+}
+
+/// Nearest-centroid index for a [`Palette<N>`]. Holds entry indices only; the centroids stay
+/// in the palette.
+pub struct PaletteIndex<const N: usize> {
+    // Implicit balanced tree: the middle of each range is the node splitting it on axis
+    // `depth % 3`, with the halves either side as its subtrees.
+    order: [u16; N],
+    len: usize,
+    // Palette generation the tree was built for.
+    built: Option<u32>,
+}
+
+impl<const N: usize> Default for PaletteIndex<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> PaletteIndex<N> {
+    pub const fn new() -> Self {
+        Self {
+            order: [0; N],
+            len: 0,
+            built: None,
+        }
+    }
+
+    /// Closest entry and its distance, as [`Palette::nearest`]. Rebuilds the tree first if
+    /// the palette changed since the last lookup.
+    pub fn nearest(&mut self, palette: &Palette<N>, rgb: &Rgb) -> Option<(usize, u32)> {
+        let Some(query) = palette.to_coords(rgb) else {
+            return palette.nearest(rgb);
+        };
+        if self.built != Some(palette.generation()) {
+            self.rebuild(palette);
+        }
+
+        let mut best = None;
+        self.search(palette.coords(), 0, self.len, 0, query, &mut best);
+        best
+    }
+
+    fn rebuild(&mut self, palette: &Palette<N>) {
+        let coords = palette.coords();
+        self.len = coords.len();
+        for (i, slot) in self.order[..self.len].iter_mut().enumerate() {
+            *slot = i as u16;
+        }
+        Self::build(&mut self.order[..self.len], coords, 0);
+        self.built = Some(palette.generation());
+    }
+
+    fn build(order: &mut [u16], coords: &[Coords], depth: usize) {
+        if order.len() <= 1 {
+            return;
+        }
+        let mid = order.len() / 2;
+        order.select_nth_unstable_by_key(mid, |&i| axis(coords[i as usize], depth));
+        let (left, right) = order.split_at_mut(mid);
+        Self::build(left, coords, depth + 1);
+        Self::build(&mut right[1..], coords, depth + 1);
+    }
+
+    fn search(
+        &self,
+        coords: &[Coords],
+        lo: usize,
+        hi: usize,
+        depth: usize,
+        query: Coords,
+        best: &mut Option<(usize, u32)>,
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let node = self.order[mid] as usize;
+        let d = dist(query, coords[node]);
+        // Lower index wins a tie, like the linear scan.
+        if best.is_none_or(|(i, bd)| (d, node) < (bd, i)) {
+            *best = Some((node, d));
+        }
+
+        let diff = axis(query, depth) - axis(coords[node], depth);
+        let (near, far) = if diff < 0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.search(coords, near.0, near.1, depth + 1, query, best);
+        // The far side can only hold entries at least `diff` away along this axis. Equal
+        // distances are still searched for the tie-break.
+        if best.is_none_or(|(_, bd)| (diff.pow(2) as u32) <= bd) {
+            self.search(coords, far.0, far.1, depth + 1, query, best);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! sRGB to CIELAB (D65). Kept free of crate items so the build script can include it and
+//! generate the `lab_lut` table from exactly the same arithmetic.
+
+// Called as `F32Ext::powf` rather than as a method: in the (std) build script the inherent
+// `f32::powf` would win and give slightly different results.
+use micromath::F32Ext;
+
+fn linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c > 0.04045 {
+        F32Ext::powf((c + 0.055) / 1.055, 2.4)
+    } else {
+        c / 12.92
+    }
+}
+
+fn f(t: f32) -> f32 {
+    if t > 0.008856 {
+        F32Ext::powf(t, 1.0 / 3.0)
+    } else {
+        (7.787 * t) + (16.0 / 116.0)
+    }
+}
+
+pub fn srgb_to_lab(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+
+    let x = (r * 0.4124 + g * 0.3576 + b * 0.1805) * 100.0;
+    let y = (r * 0.2126 + g * 0.7152 + b * 0.0722) * 100.0;
+    let z = (r * 0.0193 + g * 0.1192 + b * 0.9505) * 100.0;
+
+    let x = f(x / 95.047);
+    let y = f(y / 100.000);
+    let z = f(z / 108.883);
+
+    let l = (116.0 * y) - 16.0;
+    let a = 500.0 * (x - y);
+    let b = 200.0 * (y - z);
+
+    (l, a, b)
+}
@@ -0,0 +1,216 @@
+//! Physical tube layout: how a tube index maps onto a chute slice and a hopper drop row, and
+//! the servo positions for each.
+//!
+//! Tubes are numbered slice-first: tube `t` sits under chute slice `t % slices` in row
+//! `t / slices`. Neighboring slices are staggered, so each row has two hopper drop positions
+//! (even and odd slices).
+//!
+//! ```
+//! use sorter_logic::layout::TubeLayout;
+//!
+//! let layout = TubeLayout::default(); // 15 slices x 2 rows
+//! assert_eq!(layout.tube_count(), 30);
+//! let spot = layout.locate(16).unwrap(); // row 1, slice 1
+//! assert_eq!((spot.slice, spot.drop_row), (1, 3));
+//! assert!(layout.validate().is_ok());
+//! ```
+//!
+//! The layout is kept in flash, so a rebuilt machine only needs new positions: the host reads
+//! it with `Command::GetLayout`, edits it one position at a time and saves it with
+//! `Command::SaveLayout`.
+
+use sorter_protocol::{LAYOUT_MAGIC, crc16};
+
+/// Most chute slices the chutes servo can address.
+pub const MAX_SLICES: usize = 15;
+/// Most tube rows (15-, 30- and 45-tube builds).
+pub const MAX_ROWS: usize = 3;
+pub const MAX_TUBES: usize = MAX_SLICES * MAX_ROWS;
+/// Two staggered hopper positions per row.
+pub const MAX_DROP_ROWS: usize = MAX_ROWS * 2;
+
+/// Size of [`TubeLayout::to_bytes`].
+pub const LAYOUT_BYTES: usize = 8 + MAX_SLICES * 2 + MAX_DROP_ROWS * 2 + 2;
+const STORED_MAGIC: [u8; 4] = *b"TUBE";
+const LAYOUT_VERSION: u8 = 2;
+// Before the record had a CRC.
+const LAYOUT_VERSION_NO_CRC: u8 = 1;
+/// Size of [`TubeLayout::encode`]: magic, then the stored layout.
+pub const LAYOUT_PACKET_LEN: usize = 4 + LAYOUT_BYTES;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError {
+    /// Stored bytes are not a layout (blank flash, or an unknown format).
+    BadHeader,
+    /// The record is damaged.
+    BadCrc,
+    /// No slices or no rows.
+    Empty,
+    /// More slices or rows than [`MAX_SLICES`] / [`MAX_ROWS`].
+    TooLarge,
+    /// A slice in use has no chute position.
+    MissingChutePosition(u8),
+    /// A drop row in use has no hopper position.
+    MissingDropPosition(u8),
+}
+
+/// Where a tube is, and the servo positions that reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TubeSpot {
+    pub slice: u8,
+    /// Index into [`TubeLayout::drop_positions`].
+    pub drop_row: u8,
+    pub chute_position: u16,
+    pub drop_position: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TubeLayout {
+    pub slices: u8,
+    pub rows: u8,
+    /// Chutes servo position per slice; 0 marks a slice without a position.
+    pub chute_positions: [u16; MAX_SLICES],
+    /// Hopper servo position per drop row (`row * 2 + slice parity`); 0 marks a missing one.
+    pub drop_positions: [u16; MAX_DROP_ROWS],
+}
+
+impl Default for TubeLayout {
+    /// The original 30-tube build.
+    fn default() -> Self {
+        Self {
+            slices: 15,
+            rows: 2,
+            chute_positions: [
+                545, 586, 632, 675, 718, 762, 802, 842, 879, 920, 958, 999, 1041, 1085, 1132,
+            ],
+            drop_positions: [2153, 2020, 1887, 1780, 0, 0],
+        }
+    }
+}
+
+impl TubeLayout {
+    pub const fn tube_count(&self) -> usize {
+        self.slices as usize * self.rows as usize
+    }
+
+    /// Check that every tube in the layout has a chute and a hopper position.
+    pub fn validate(&self) -> Result<(), LayoutError> {
+        if self.slices == 0 || self.rows == 0 {
+            return Err(LayoutError::Empty);
+        }
+        if self.slices as usize > MAX_SLICES || self.rows as usize > MAX_ROWS {
+            return Err(LayoutError::TooLarge);
+        }
+        if let Some(s) = (0..self.slices).find(|&s| self.chute_positions[s as usize] == 0) {
+            return Err(LayoutError::MissingChutePosition(s));
+        }
+        // A single slice never uses the odd drop position.
+        let drop_rows = if self.slices > 1 { 2 } else { 1 };
+        for row in 0..self.rows {
+            for parity in 0..drop_rows {
+                let d = row * 2 + parity;
+                if self.drop_positions[d as usize] == 0 {
+                    return Err(LayoutError::MissingDropPosition(d));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// True if every tube's positions are inside the servo ranges, `(min, max)` in
+    /// microseconds.
+    pub fn fits(&self, chute_range: (u16, u16), hopper_range: (u16, u16)) -> bool {
+        let in_range = |p: u16, (min, max): (u16, u16)| (min..=max).contains(&p);
+        (0..self.tube_count() as u8).all(|t| {
+            self.locate(t).is_some_and(|spot| {
+                in_range(spot.chute_position, chute_range)
+                    && in_range(spot.drop_position, hopper_range)
+            })
+        })
+    }
+
+    /// Servo targets for `tube`, or `None` if the layout has no such tube.
+    pub fn locate(&self, tube: u8) -> Option<TubeSpot> {
+        if tube as usize >= self.tube_count() {
+            return None;
+        }
+        let slice = tube % self.slices;
+        let drop_row = (tube / self.slices) * 2 + (slice & 1);
+        Some(TubeSpot {
+            slice,
+            drop_row,
+            chute_position: *self.chute_positions.get(slice as usize)?,
+            drop_position: *self.drop_positions.get(drop_row as usize)?,
+        })
+    }
+
+    /// Serialize for storage in flash: magic `TUBE`, version, slices, rows, a reserved byte,
+    /// the chute and drop positions as `u16` LE, then a CRC-16 of everything before it.
+    pub fn to_bytes(&self) -> [u8; LAYOUT_BYTES] {
+        let mut out = [0u8; LAYOUT_BYTES];
+        out[..4].copy_from_slice(&STORED_MAGIC);
+        out[4] = LAYOUT_VERSION;
+        out[5] = self.slices;
+        out[6] = self.rows;
+        let positions = self.chute_positions.iter().chain(&self.drop_positions);
+        let end = LAYOUT_BYTES - 2;
+        for (chunk, p) in out[8..end].chunks_exact_mut(2).zip(positions) {
+            chunk.copy_from_slice(&p.to_le_bytes());
+        }
+        let crc = crc16(&out[..end]);
+        out[end..].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// Parse and validate a stored layout. A layout saved before records had a CRC is read
+    /// too.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LayoutError> {
+        let layout = Self::parse(bytes)?;
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    // The layout in `bytes`, valid or not.
+    fn parse(bytes: &[u8]) -> Result<Self, LayoutError> {
+        let header = bytes.get(..8).ok_or(LayoutError::BadHeader)?;
+        if header[..4] != STORED_MAGIC {
+            return Err(LayoutError::BadHeader);
+        }
+        let end = LAYOUT_BYTES - 2;
+        let record = match header[4] {
+            LAYOUT_VERSION => {
+                let record = bytes.get(..LAYOUT_BYTES).ok_or(LayoutError::BadHeader)?;
+                if crc16(&record[..end]).to_le_bytes() != record[end..] {
+                    return Err(LayoutError::BadCrc);
+                }
+                record
+            }
+            LAYOUT_VERSION_NO_CRC => bytes.get(..end).ok_or(LayoutError::BadHeader)?,
+            _ => return Err(LayoutError::BadHeader),
+        };
+        let mut positions = record[8..end]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]));
+        Ok(Self {
+            slices: record[5],
+            rows: record[6],
+            chute_positions: core::array::from_fn(|_| positions.next().unwrap_or(0)),
+            drop_positions: core::array::from_fn(|_| positions.next().unwrap_or(0)),
+        })
+    }
+
+    /// Write a layout reply packet: [`LAYOUT_MAGIC`] then [`TubeLayout::to_bytes`]. Returns
+    /// the length.
+    pub fn encode(&self, out: &mut [u8; LAYOUT_PACKET_LEN]) -> usize {
+        out[..4].copy_from_slice(&LAYOUT_MAGIC);
+        out[4..].copy_from_slice(&self.to_bytes());
+        LAYOUT_PACKET_LEN
+    }
+
+    /// Decode the body of a layout reply (everything after the magic). Unlike
+    /// [`TubeLayout::from_bytes`] an unfinished edit is returned as is, so check it with
+    /// [`TubeLayout::validate`]; `None` if the body is truncated, damaged or not a layout.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        Self::parse(body).ok()
+    }
+}
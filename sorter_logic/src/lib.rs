@@ -1,6 +1,71 @@
+//! Bead color analysis and clustering for the bead sorter.
+//!
+//! The crate is `no_std` and allocation free so the same code runs on the RP2040 firmware
+//! and in host tools (the `alloc` feature adds a heap-backed palette for the host, and
+//! `lab_lut` a 192KB RGB565 to Lab table for [`Rgb565::to_lab_lut`]). Frames are RGB565,
+//! big endian, row major (`2 * width * height` bytes), as produced by the OV7670 in the
+//! sorter's 40x30 mode; the ring search expects the bead near the center of such a frame.
+//!
+//! A sorting step is: analyze a frame, match its color against an adaptive [`Palette`], and
+//! route by palette index.
+//!
+//! ```
+//! # use sorter_logic::doc_frame as frame;
+//! use sorter_logic::{Palette, PaletteMatch, analyze_image};
+//!
+//! let mut palette: Palette<32> = Palette::new();
+//!
+//! let red = analyze_image(&frame((200, 20, 30)), 40, 30).expect("bead present");
+//! assert_eq!(palette.match_color(&red.average_color, red.variance, 15), PaletteMatch::NewEntry(0));
+//! palette.add_sample(0, &red.average_color, red.variance);
+//!
+//! // The same color again joins the existing entry; a different one starts a new entry.
+//! let again = analyze_image(&frame((200, 20, 30)), 40, 30).unwrap();
+//! assert_eq!(palette.match_color(&again.average_color, again.variance, 15), PaletteMatch::Match(0));
+//! let blue = analyze_image(&frame((20, 40, 200)), 40, 30).unwrap();
+//! assert_eq!(palette.match_color(&blue.average_color, blue.variance, 15), PaletteMatch::NewEntry(1));
+//! ```
+
 #![no_std]
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+// Unused wherever std is linked (tests, and host builds that pull it in through a
+// dependency), as std's own f32 methods take precedence.
+#[allow(unused_imports)]
 use micromath::F32Ext;
 
+pub mod button;
+pub mod calibrate;
+#[cfg(feature = "catalog")]
+pub mod catalog;
+pub mod collect;
+pub mod config;
+pub mod cycle;
+pub mod dataset;
+#[cfg(feature = "alloc")]
+pub mod dyn_palette;
+pub mod event_log;
+pub mod exposure;
+pub mod hardware;
+pub mod hdr;
+pub mod hopper;
+pub mod index;
+mod lab;
+pub mod layout;
+pub mod motion;
+pub mod policy;
+pub mod profile;
+pub mod router;
+pub mod settings;
+pub mod smoother;
+pub mod stall;
+pub mod subsample;
+pub mod supply;
+pub mod telemetry;
+pub mod test_pattern;
+pub mod text;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Rgb {
     pub r: u8,
@@ -12,7 +77,7 @@ pub struct Rgb {
 pub enum PaletteMatch {
     Match(usize),    // Index of matched entry
     NewEntry(usize), // Index of newly added entry
-    Full,            // Palette is full, no match found
+    Full,            // Palette is full (or frozen), no match found
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -43,25 +108,129 @@ impl PaletteEntry {
         self.count += 1;
     }
 
-    pub fn avg(&self) -> (Rgb, u32) {
+    /// Move the centroid a fraction `alpha` (0..=1) of the way toward a sample, so old
+    /// samples fade out instead of counting forever. Until an entry holds `1 / alpha`
+    /// samples this is the plain average, as with [`PaletteEntry::add`]; `count` keeps
+    /// counting every sample either way.
+    ///
+    /// ```
+    /// use sorter_logic::{PaletteEntry, Rgb};
+    ///
+    /// let grey = |v| Rgb { r: v, g: v, b: v };
+    /// let mut cumulative = PaletteEntry::new(grey(100), 0);
+    /// let mut ewma = PaletteEntry::new(grey(100), 0);
+    /// for _ in 0..20 {
+    ///     cumulative.add(grey(200), 0);
+    ///     ewma.add_ewma(grey(200), 0, 0.25);
+    /// }
+    /// assert_eq!(cumulative.avg().0, grey(195));
+    /// // The founding sample's weight has decayed away.
+    /// assert_eq!(ewma.avg().0, grey(199));
+    /// ```
+    pub fn add_ewma(&mut self, rgb: Rgb, var: u32, alpha: f32) {
         if self.count == 0 {
-            (Rgb { r: 0, g: 0, b: 0 }, 0)
-        } else {
-            (
+            *self = Self::new(rgb, var);
+            return;
+        }
+        let n = self.count as f32;
+        let next = n + 1.0;
+        let alpha = alpha.clamp(1.0 / next, 1.0);
+        // Scale the sums so that sum / (count + 1) is the new weighted mean.
+        let blend =
+            |sum: f32, sample: f32| ((sum / n * (1.0 - alpha) + sample * alpha) * next).round();
+        self.sum_r = blend(self.sum_r as f32, rgb.r as f32) as u32;
+        self.sum_g = blend(self.sum_g as f32, rgb.g as f32) as u32;
+        self.sum_b = blend(self.sum_b as f32, rgb.b as f32) as u32;
+        self.sum_var = blend(self.sum_var as f32, var as f32) as u64;
+        self.count += 1;
+    }
+
+    pub fn avg(&self) -> (Rgb, u32) {
+        match self.count {
+            0 => (Rgb { r: 0, g: 0, b: 0 }, 0),
+            count => (
                 Rgb {
-                    r: (self.sum_r / self.count) as u8,
-                    g: (self.sum_g / self.count) as u8,
-                    b: (self.sum_b / self.count) as u8,
+                    r: (self.sum_r / count) as u8,
+                    g: (self.sum_g / count) as u8,
+                    b: (self.sum_b / count) as u8,
                 },
-                (self.sum_var / self.count as u64) as u32,
-            )
+                (self.sum_var / count as u64) as u32,
+            ),
+        }
+    }
+}
+
+/// Distance used by [`Palette::match_color`] to compare colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMetric {
+    /// Squared CIELAB distance ([`Rgb::dist_lab`]).
+    #[default]
+    Lab,
+    /// Squared OKLab distance ([`Rgb::dist_oklab`]).
+    OkLab,
+    /// Hue-first distance that mostly ignores brightness ([`Rgb::dist_hue`]).
+    HueWeighted,
+    /// Squared CIELAB distance with chroma amplified below an L* of `l_floor`, up to `gain`
+    /// times at black ([`Rgb::dist_lab_dark`]). Black, navy and dark purple beads differ
+    /// mostly in a little chroma that plain Lab weighs too lightly. Same as `Lab` above the
+    /// floor.
+    LabDark { l_floor: u8, gain: u8 },
+}
+
+impl ColorMetric {
+    /// [`ColorMetric::LabDark`] with settings that separate black, navy and dark purple.
+    pub const LAB_DARK: ColorMetric = ColorMetric::LabDark {
+        l_floor: 35,
+        gain: 3,
+    };
+}
+
+/// Whether a [`Palette`] still learns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaletteMode {
+    /// Unmatched colors claim new entries and samples move centroids.
+    #[default]
+    Learning,
+    /// Learn from the next `n` samples ([`Palette::add_sample`] calls), then freeze.
+    TrainFor(u32),
+    /// Classify only: no new entries, and [`Palette::add_sample`] is ignored.
+    Frozen,
+}
+
+/// How [`Palette::add_sample`] moves an entry's centroid.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CentroidUpdate {
+    /// Mean of every sample the entry has seen ([`PaletteEntry::add`]).
+    #[default]
+    Cumulative,
+    /// Exponentially weighted mean ([`PaletteEntry::add_ewma`]): each sample moves the
+    /// centroid `alpha` of the way toward it, so entries follow slow lighting drift and an
+    /// early mis-sample fades out.
+    Ewma { alpha: f32 },
+}
+
+impl CentroidUpdate {
+    fn apply(self, entry: &mut PaletteEntry, rgb: Rgb, variance: u32) {
+        match self {
+            CentroidUpdate::Cumulative => entry.add(rgb, variance),
+            CentroidUpdate::Ewma { alpha } => entry.add_ewma(rgb, variance, alpha),
         }
     }
 }
 
 pub struct Palette<const N: usize> {
     colors: [Option<PaletteEntry>; N],
+    // Each entry's centroid in the metric's color space, kept in step with `colors` so a
+    // match converts only the bead's color. Unused for `HueWeighted`.
+    coords: [(i32, i32, i32); N],
     count: usize,
+    metric: ColorMetric,
+    mode: PaletteMode,
+    update: CentroidUpdate,
+    // Bumped whenever a centroid moves or is added; lets a PaletteIndex notice it is stale.
+    generation: u32,
+    // Per entry, the sum of each sample's distance from the centroid as it was added.
+    spread: [u64; N],
 }
 
 impl<const N: usize> Default for Palette<N> {
@@ -72,270 +241,2085 @@ impl<const N: usize> Default for Palette<N> {
 
 impl<const N: usize> Palette<N> {
     pub const fn new() -> Self {
+        Self::with_metric(ColorMetric::Lab)
+    }
+
+    pub const fn with_metric(metric: ColorMetric) -> Self {
         Self {
             colors: [None; N],
+            coords: [(0, 0, 0); N],
             count: 0,
+            metric,
+            mode: PaletteMode::Learning,
+            update: CentroidUpdate::Cumulative,
+            generation: 0,
+            spread: [0; N],
         }
     }
 
+    pub fn metric(&self) -> ColorMetric {
+        self.metric
+    }
+
+    pub fn mode(&self) -> PaletteMode {
+        self.mode
+    }
+
+    /// Switch between learning and classifying. The entries learned so far are kept.
+    pub fn set_mode(&mut self, mode: PaletteMode) {
+        self.mode = match mode {
+            PaletteMode::TrainFor(0) => PaletteMode::Frozen,
+            mode => mode,
+        };
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.mode == PaletteMode::Frozen
+    }
+
+    pub fn centroid_update(&self) -> CentroidUpdate {
+        self.update
+    }
+
+    /// Choose how later samples move the centroids. Entries keep their current centroids.
+    pub fn set_centroid_update(&mut self, update: CentroidUpdate) {
+        self.update = update;
+    }
+
+    /// Add each color as an entry of its own (one sample, no variance), in order, until the
+    /// palette is full. Works in any mode, so a known set of colors can be loaded and then
+    /// frozen. Returns how many were added.
+    ///
+    /// ```
+    /// use sorter_logic::{Palette, PaletteMatch, PaletteMode, Rgb};
+    ///
+    /// let red = Rgb { r: 200, g: 20, b: 30 };
+    /// let blue = Rgb { r: 20, g: 40, b: 200 };
+    /// let green = Rgb { r: 30, g: 180, b: 40 };
+    ///
+    /// let mut palette: Palette<8> = Palette::new();
+    /// assert_eq!(palette.seed_from(&[red, blue]), 2);
+    /// palette.set_mode(PaletteMode::Frozen);
+    ///
+    /// assert_eq!(palette.match_color(&blue, 0, 15), PaletteMatch::Match(1));
+    /// // A new color is not learned while frozen.
+    /// assert_eq!(palette.match_color(&green, 0, 15), PaletteMatch::Full);
+    /// assert_eq!(palette.len(), 2);
+    /// ```
+    pub fn seed_from(&mut self, colors: &[Rgb]) -> usize {
+        colors
+            .iter()
+            .map_while(|rgb| self.seed_entry(PaletteEntry::new(*rgb, 0)))
+            .count()
+    }
+
+    /// Add a learned entry (sums and count, as saved from another palette) as is. Works in
+    /// any mode. Returns its index, or `None` if the palette is full.
+    pub fn seed_entry(&mut self, entry: PaletteEntry) -> Option<usize> {
+        if self.count == N {
+            return None;
+        }
+        let idx = self.count;
+        self.colors[idx] = Some(entry);
+        self.update_coords(idx);
+        self.count += 1;
+        Some(idx)
+    }
+
+    // Color space coordinates whose squared Euclidean distance is the metric, if it has one.
+    pub(crate) fn to_coords(&self, rgb: &Rgb) -> Option<(i32, i32, i32)> {
+        metric_coords(self.metric, rgb)
+    }
+
+    fn update_coords(&mut self, index: usize) {
+        if let Some(entry) = self.colors[index]
+            && let Some(coords) = self.to_coords(&entry.avg().0)
+        {
+            self.coords[index] = coords;
+        }
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    pub(crate) fn coords(&self) -> &[(i32, i32, i32)] {
+        &self.coords[..self.count]
+    }
+
+    pub(crate) fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn find_nearest(&self, rgb: &Rgb, limit: u32) -> Option<(usize, u32)> {
+        find_nearest(
+            self.metric,
+            self.coords(),
+            self.colors[..self.count]
+                .iter()
+                .map(|e| e.map(|e| e.avg().0)),
+            rgb,
+            limit,
+        )
+    }
+
     /// Match a bead color & variance against the palette.
-    /// Recommended Threshold: 100.
-    /// Match a bead color & variance against the palette.
-    /// Recommended Threshold: 30 (CIELAB DeltaE).
+    ///
+    /// Returns the closest entry if its distance (squared, under the palette's
+    /// [`ColorMetric`]) is below `threshold`, otherwise claims a new entry (unless the
+    /// palette is [`PaletteMode::Frozen`], which reports `Full` instead). The entry's
+    /// centroid is not updated; call [`Palette::add_sample`] for that. The firmware uses a
+    /// threshold of 15 with the Lab metric (a Delta E of about 4); 30 is a looser starting
+    /// point for uncalibrated lighting.
+    ///
+    /// ```
+    /// use sorter_logic::{Palette, PaletteMatch, Rgb};
+    ///
+    /// let mut palette: Palette<2> = Palette::new();
+    /// let red = Rgb { r: 200, g: 20, b: 30 };
+    /// let darker_red = Rgb { r: 196, g: 20, b: 30 };
+    /// let blue = Rgb { r: 20, g: 40, b: 200 };
+    /// let green = Rgb { r: 30, g: 180, b: 40 };
+    ///
+    /// assert_eq!(palette.match_color(&red, 0, 15), PaletteMatch::NewEntry(0));
+    /// assert_eq!(palette.match_color(&darker_red, 0, 15), PaletteMatch::Match(0));
+    /// assert_eq!(palette.match_color(&blue, 0, 15), PaletteMatch::NewEntry(1));
+    /// assert_eq!(palette.match_color(&green, 0, 15), PaletteMatch::Full);
+    /// ```
     pub fn match_color(&mut self, rgb: &Rgb, _variance: u32, threshold: u32) -> PaletteMatch {
-        let mut best_idx = None;
-        let mut min_dist = u32::MAX;
-
-        for (i, entry) in self.colors.iter().enumerate() {
-            if let Some(entry) = entry {
-                let (center_rgb, _) = entry.avg();
-                let dist_lab = rgb.dist_lab(&center_rgb);
-
-                // Pure Color Matching (No Variance Penalty)
-                if dist_lab < min_dist {
-                    min_dist = dist_lab;
-                    best_idx = Some(i);
+        // Pure Color Matching (No Variance Penalty). Only an entry within the threshold can
+        // match, so farther ones need not be measured in full.
+        let best = self.find_nearest(rgb, threshold);
+        self.claim(best, rgb, _variance, threshold)
+    }
+
+    /// [`Palette::match_color`] with the nearest entry found through `index`, for large
+    /// palettes. The result is the same.
+    pub fn match_color_indexed(
+        &mut self,
+        index: &mut index::PaletteIndex<N>,
+        rgb: &Rgb,
+        variance: u32,
+        threshold: u32,
+    ) -> PaletteMatch {
+        let best = index.nearest(self, rgb);
+        self.claim(best, rgb, variance, threshold)
+    }
+
+    // Return the match if `best` is within `threshold`, otherwise add a new entry.
+    fn claim(
+        &mut self,
+        best: Option<(usize, u32)>,
+        rgb: &Rgb,
+        variance: u32,
+        threshold: u32,
+    ) -> PaletteMatch {
+        if let Some((idx, min_dist)) = best
+            && min_dist < threshold
+        {
+            return PaletteMatch::Match(idx);
+        }
+
+        if self.count < N && !self.is_frozen() {
+            let idx = self.count;
+            self.colors[idx] = Some(PaletteEntry::new(*rgb, variance));
+            self.update_coords(idx);
+            self.count += 1;
+            PaletteMatch::NewEntry(idx)
+        } else {
+            PaletteMatch::Full
+        }
+    }
+
+    /// Closest entry and its distance under the palette's metric, without claiming a new
+    /// entry. `None` for an empty palette.
+    pub fn nearest(&self, rgb: &Rgb) -> Option<(usize, u32)> {
+        self.find_nearest(rgb, u32::MAX)
+    }
+
+    /// [`Palette::nearest`] among the entries `eligible` accepts.
+    pub fn nearest_where(
+        &self,
+        rgb: &Rgb,
+        eligible: impl Fn(usize) -> bool,
+    ) -> Option<(usize, u32)> {
+        let query = self.to_coords(rgb);
+        (0..self.count)
+            .filter(|&i| eligible(i))
+            .filter_map(|i| match query {
+                Some(q) => {
+                    let c = self.coords[i];
+                    let d = (q.0 - c.0).pow(2) + (q.1 - c.1).pow(2) + (q.2 - c.2).pow(2);
+                    Some((i, d as u32))
                 }
+                None => Some((i, rgb.dist_metric(&self.get(i)?, self.metric))),
+            })
+            .min_by_key(|&(_, d)| d)
+    }
+
+    /// [`Palette::match_color`] against the entries `eligible` accepts only; a color near an
+    /// entry it does not accept claims a new entry.
+    pub fn match_color_where(
+        &mut self,
+        rgb: &Rgb,
+        variance: u32,
+        threshold: u32,
+        eligible: impl Fn(usize) -> bool,
+    ) -> PaletteMatch {
+        let best = self.nearest_where(rgb, eligible);
+        self.claim(best, rgb, variance, threshold)
+    }
+
+    /// Move an entry's centroid toward a sample, as set by
+    /// [`Palette::set_centroid_update`]. Ignored while the palette is frozen; a sample for an
+    /// existing entry counts toward [`PaletteMode::TrainFor`].
+    pub fn add_sample(&mut self, index: usize, rgb: &Rgb, variance: u32) {
+        if self.is_frozen() {
+            return;
+        }
+        if index < N
+            && let Some(centroid) = self.get(index)
+        {
+            self.spread[index] += rgb.dist_metric(&centroid, self.metric) as u64;
+            if let Some(entry) = &mut self.colors[index] {
+                self.update.apply(entry, *rgb, variance);
+            }
+            self.update_coords(index);
+            if let PaletteMode::TrainFor(n) = self.mode {
+                self.set_mode(PaletteMode::TrainFor(n - 1));
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Rgb> {
+        if index < N {
+            self.colors[index].map(|e| e.avg().0)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_entry(&self, index: usize) -> Option<PaletteEntry> {
+        if index < N { self.colors[index] } else { None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Separation and spread of the entries, for spotting entries that should be one.
+    ///
+    /// ```
+    /// use sorter_logic::{Palette, Rgb};
+    ///
+    /// let mut palette: Palette<4> = Palette::new();
+    /// let red = Rgb { r: 200, g: 20, b: 30 };
+    /// let also_red = Rgb { r: 192, g: 24, b: 30 };
+    /// let blue = Rgb { r: 20, g: 40, b: 200 };
+    /// palette.seed_from(&[red, blue, also_red]);
+    /// // Both shades keep landing in both red entries: one color split in two.
+    /// for _ in 0..5 {
+    ///     for entry in [0, 2] {
+    ///         palette.add_sample(entry, &red, 0);
+    ///         palette.add_sample(entry, &also_red, 0);
+    ///     }
+    /// }
+    ///
+    /// let stats = palette.stats();
+    /// assert_eq!(stats.closest_pair.map(|(a, b, _)| (a, b)), Some((0, 2)));
+    /// assert_eq!(stats.counts[..3], [11, 1, 11]);
+    /// assert_eq!(stats.merge_candidate(), Some((0, 2)));
+    /// ```
+    pub fn stats(&self) -> PaletteStats<N> {
+        let mut stats = PaletteStats {
+            len: self.count,
+            closest_pair: None,
+            counts: [0; N],
+            within: [0; N],
+        };
+        stats.closest_pair = fill_stats(
+            self.metric,
+            &self.colors[..self.count],
+            &self.spread,
+            &mut stats.counts,
+            &mut stats.within,
+        );
+        stats
+    }
+
+    /// Nearest catalog color for each entry (`None` past [`Palette::len`]).
+    #[cfg(feature = "catalog")]
+    pub fn label_entries<'a>(
+        &self,
+        catalog: &'a catalog::Catalog,
+    ) -> [Option<catalog::CatalogMatch<'a>>; N] {
+        let mut labels = [None; N];
+        for (label, entry) in labels.iter_mut().zip(&self.colors) {
+            *label = entry.and_then(|e| catalog.nearest(&e.avg().0));
+        }
+        labels
+    }
+}
+
+/// Palette quality figures from [`Palette::stats`]. Distances are squared, under the
+/// palette's metric.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaletteStats<const N: usize> {
+    /// Number of entries; the arrays are zero past it.
+    pub len: usize,
+    /// The two closest entries `(a, b, distance)` with `a < b`. `None` with fewer than two.
+    pub closest_pair: Option<(usize, usize, u32)>,
+    /// Samples per entry.
+    pub counts: [u32; N],
+    /// Mean distance of an entry's samples from its centroid at the time each was added.
+    pub within: [u32; N],
+}
+
+impl<const N: usize> PaletteStats<N> {
+    /// Mean within-entry distance over all samples.
+    pub fn mean_within(&self) -> u32 {
+        mean_within(&self.counts[..self.len], &self.within[..self.len])
+    }
+
+    /// The closest pair, if its centroids are nearer each other than the larger of their
+    /// spreads allows: closer than twice the typical sample distance (four times in squared
+    /// units). Such entries are probably one color split in two, and merging them is worth a
+    /// look.
+    pub fn merge_candidate(&self) -> Option<(usize, usize)> {
+        merge_candidate(self.closest_pair, &self.within)
+    }
+}
+
+// Color space coordinates whose squared Euclidean distance is the metric, if it has one.
+fn metric_coords(metric: ColorMetric, rgb: &Rgb) -> Option<(i32, i32, i32)> {
+    match metric {
+        ColorMetric::Lab => Some(rgb.to_lab()),
+        ColorMetric::OkLab => Some(rgb.to_oklab()),
+        ColorMetric::LabDark { l_floor, gain } => Some(rgb.to_lab_dark(l_floor, gain)),
+        ColorMetric::HueWeighted => None,
+    }
+}
+
+// Closest entry closer than `limit` and its distance (the first one on a tie). `coords`
+// holds each entry's cached `metric_coords` and `centroids` their colors, in entry order.
+//
+// For Lab and OKLab each comparison uses the cached entry coordinates and gives up as soon
+// as the partial distance reaches the best so far (or `limit`), so most far-off entries cost
+// one subtraction and multiply. The result is the same as comparing against every centroid.
+fn find_nearest(
+    metric: ColorMetric,
+    coords: &[(i32, i32, i32)],
+    centroids: impl Iterator<Item = Option<Rgb>>,
+    rgb: &Rgb,
+    limit: u32,
+) -> Option<(usize, u32)> {
+    let Some(query) = metric_coords(metric, rgb) else {
+        return centroids
+            .enumerate()
+            .filter_map(|(i, c)| Some((i, rgb.dist_metric(&c?, metric))))
+            .filter(|&(_, d)| d < limit)
+            .min_by_key(|&(_, d)| d);
+    };
+
+    let mut best: Option<(usize, u32)> = None;
+    for (i, &entry) in coords.iter().enumerate() {
+        let bound = best.map_or(limit, |(_, d)| d);
+        if let Some(dist) = dist_below(query, entry, bound) {
+            best = Some((i, dist));
+        }
+    }
+    best
+}
+
+// Cube root of a non-negative `x`: an exponent-dividing bit trick for the first guess, then
+// Newton steps, each of which roughly squares the relative error (5% -> 0.3% -> 1e-5 -> f32).
+fn cbrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let mut y = f32::from_bits(x.to_bits() / 3 + 0x2a51_4067);
+    for _ in 0..3 {
+        y = (2.0 * y + x / (y * y)) / 3.0;
+    }
+    y
+}
+
+// Squared Euclidean distance between two color space points if it is below `limit`. Adds
+// one axis at a time and stops once the sum reaches `limit`.
+fn dist_below(a: (i32, i32, i32), b: (i32, i32, i32), limit: u32) -> Option<u32> {
+    let mut dist = 0u32;
+    for d in [a.0 - b.0, a.1 - b.1, a.2 - b.2] {
+        dist += (d * d) as u32;
+        if dist >= limit {
+            return None;
+        }
+    }
+    Some(dist)
+}
+
+// Fill in per-entry sample counts and mean spreads, and return the closest pair.
+fn fill_stats<E: Copy + Into<Option<PaletteEntry>>>(
+    metric: ColorMetric,
+    entries: &[E],
+    spread: &[u64],
+    counts: &mut [u32],
+    within: &mut [u32],
+) -> Option<(usize, usize, u32)> {
+    let mut closest_pair = None;
+    for (i, &entry) in entries.iter().enumerate() {
+        let Some(entry) = entry.into() else {
+            continue;
+        };
+        counts[i] = entry.count;
+        // The first sample founded the entry and has no distance.
+        within[i] = (spread[i] / (entry.count.max(2) - 1) as u64) as u32;
+
+        let a = entry.avg().0;
+        for (j, &other) in entries.iter().enumerate().skip(i + 1) {
+            let Some(other) = other.into() else {
+                continue;
+            };
+            let d = a.dist_metric(&other.avg().0, metric);
+            if closest_pair.is_none_or(|(_, _, best)| d < best) {
+                closest_pair = Some((i, j, d));
+            }
+        }
+    }
+    closest_pair
+}
+
+fn mean_within(counts: &[u32], within: &[u32]) -> u32 {
+    let (sum, n) = counts
+        .iter()
+        .zip(within)
+        .filter(|&(&count, _)| count > 1)
+        .fold((0u64, 0u64), |(sum, n), (&count, &within)| {
+            let samples = (count - 1) as u64;
+            (sum + within as u64 * samples, n + samples)
+        });
+    (sum / n.max(1)) as u32
+}
+
+fn merge_candidate(
+    closest_pair: Option<(usize, usize, u32)>,
+    within: &[u32],
+) -> Option<(usize, usize)> {
+    let (a, b, d) = closest_pair?;
+    let spread = within[a].max(within[b]);
+    (d < spread.saturating_mul(4)).then_some((a, b))
+}
+
+// 5- and 6-bit channel values scaled to 8 bits (`v * 255 / max`, rounded down).
+const EXPAND_5: [u8; 32] = expand_table();
+const EXPAND_6: [u8; 64] = expand_table();
+
+const fn expand_table<const M: usize>() -> [u8; M] {
+    let mut table = [0; M];
+    let mut v = 0;
+    while v < M {
+        table[v] = (v * 255 / (M - 1)) as u8;
+        v += 1;
+    }
+    table
+}
+
+/// Decode a big-endian RGB565 frame (as the camera sends it) pixel by pixel. A trailing odd
+/// byte is ignored.
+///
+/// ```
+/// use sorter_logic::{Rgb, decode_rgb565_be};
+///
+/// let frame = [0xF8, 0x00, 0x07, 0xE0, 0xFF, 0xFF];
+/// let pixels: Vec<Rgb> = decode_rgb565_be(&frame).collect();
+/// assert_eq!(pixels[0], Rgb { r: 255, g: 0, b: 0 });
+/// assert_eq!(pixels[1], Rgb { r: 0, g: 255, b: 0 });
+/// assert_eq!(pixels[2], Rgb { r: 255, g: 255, b: 255 });
+/// ```
+pub fn decode_rgb565_be(data: &[u8]) -> impl Iterator<Item = Rgb> + '_ {
+    data.chunks_exact(2)
+        .map(|c| Rgb::from_rgb565(u16::from_be_bytes([c[0], c[1]])))
+}
+
+/// [`decode_rgb565_be`] into a buffer. Decodes as many pixels as both hold and returns the
+/// count.
+pub fn decode_rgb565_be_into(data: &[u8], out: &mut [Rgb]) -> usize {
+    let mut n = 0;
+    for (px, rgb) in out.iter_mut().zip(decode_rgb565_be(data)) {
+        *px = rgb;
+        n += 1;
+    }
+    n
+}
+
+// A 40x30 frame of a 7 pixel radius bead centered at (20, 17) on a light background, for the
+// examples in the docs.
+#[doc(hidden)]
+pub fn doc_frame(bead: (u8, u8, u8)) -> [u8; 40 * 30 * 2] {
+    let mut data = [0; 40 * 30 * 2];
+    for (i, px) in data.chunks_exact_mut(2).enumerate() {
+        let (x, y) = ((i % 40) as i32, (i / 40) as i32);
+        let inside = (x - 20).pow(2) + (y - 17).pow(2) <= 49;
+        let (r, g, b) = if inside { bead } else { (200, 200, 190) };
+        let v =
+            ((r as u16 * 31 / 255) << 11) | ((g as u16 * 63 / 255) << 5) | (b as u16 * 31 / 255);
+        px.copy_from_slice(&v.to_be_bytes());
+    }
+    data
+}
+
+// Pixel `i` of a big-endian RGB565 frame. Panics if the frame is too short.
+fn pixel_at(data: &[u8], i: usize) -> Rgb {
+    Rgb::from_rgb565(u16::from_be_bytes([data[i * 2], data[i * 2 + 1]]))
+}
+
+/// A raw RGB565 pixel (native `u16`; see [`Rgb::from_rgb565`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb565(pub u16);
+
+impl Rgb565 {
+    pub fn to_rgb(self) -> Rgb {
+        Rgb::from_rgb565(self.0)
+    }
+
+    /// Same as `self.to_rgb().to_lab()`.
+    pub fn to_lab(self) -> (i32, i32, i32) {
+        self.to_rgb().to_lab()
+    }
+
+    /// [`Rgb565::to_lab`] from a table built at compile time instead of two `powf` per
+    /// channel: one flash read. The table is 192KB (3 bytes for each of the 65536 pixel
+    /// values), so it is behind the `lab_lut` feature for targets that can spare the flash.
+    ///
+    /// ```
+    /// use sorter_logic::Rgb565;
+    ///
+    /// let px = Rgb565(u16::from_be_bytes([0xF8, 0x00]));
+    /// assert_eq!(px.to_lab_lut(), px.to_lab());
+    /// ```
+    #[cfg(feature = "lab_lut")]
+    pub fn to_lab_lut(self) -> (i32, i32, i32) {
+        static LAB_LUT: &[u8; 3 << 16] = include_bytes!(concat!(env!("OUT_DIR"), "/lab_lut.bin"));
+        let i = self.0 as usize * 3;
+        (
+            LAB_LUT[i] as i8 as i32,
+            LAB_LUT[i + 1] as i8 as i32,
+            LAB_LUT[i + 2] as i8 as i32,
+        )
+    }
+}
+
+impl From<Rgb565> for Rgb {
+    fn from(p: Rgb565) -> Self {
+        p.to_rgb()
+    }
+}
+
+impl Rgb {
+    /// Expand an RGB565 pixel (as a native `u16`; camera frames are big endian) to 8 bits
+    /// per channel.
+    ///
+    /// ```
+    /// use sorter_logic::Rgb;
+    ///
+    /// let px = u16::from_be_bytes([0xF8, 0x00]);
+    /// assert_eq!(Rgb::from_rgb565(px), Rgb { r: 255, g: 0, b: 0 });
+    /// assert_eq!(Rgb::from_rgb565(0xFFFF), Rgb { r: 255, g: 255, b: 255 });
+    /// ```
+    pub fn from_rgb565(p: u16) -> Self {
+        Self {
+            r: EXPAND_5[(p >> 11) as usize & 0x1F],
+            g: EXPAND_6[(p >> 5) as usize & 0x3F],
+            b: EXPAND_5[p as usize & 0x1F],
+        }
+    }
+
+    /// The nearest RGB565 pixel (native `u16`); undoes [`Rgb::from_rgb565`] exactly.
+    pub fn to_rgb565(self) -> u16 {
+        let scale = |v: u8, max: u32| (v as u32 * max + 127) / 255;
+        (scale(self.r, 31) << 11 | scale(self.g, 63) << 5 | scale(self.b, 31)) as u16
+    }
+
+    pub fn dist(&self, other: &Rgb) -> u32 {
+        // Use squared Euclidean
+        let rd = (self.r as i32 - other.r as i32).pow(2);
+        let gd = (self.g as i32 - other.g as i32).pow(2);
+        let bd = (self.b as i32 - other.b as i32).pow(2);
+        (rd + gd + bd) as u32
+    }
+
+    pub fn to_lab(&self) -> (i32, i32, i32) {
+        let (l, a, b) = self.lab_f32();
+        (l as i32, a as i32, b as i32)
+    }
+
+    fn lab_f32(&self) -> (f32, f32, f32) {
+        lab::srgb_to_lab(self.r, self.g, self.b)
+    }
+
+    /// Squared CIELAB distance (Delta E 1976, squared). This is the default palette metric.
+    pub fn dist_lab(&self, other: &Rgb) -> u32 {
+        let (l1, a1, b1) = self.to_lab();
+        let (l2, a2, b2) = other.to_lab();
+        ((l1 - l2).pow(2) + (a1 - a2).pow(2) + (b1 - b2).pow(2)) as u32
+    }
+
+    /// [`Rgb::to_lab`] with a and b scaled up for dark colors: by 1 at L* = `l_floor`,
+    /// rising linearly to `gain` at L* = 0.
+    pub fn to_lab_dark(&self, l_floor: u8, gain: u8) -> (i32, i32, i32) {
+        let (l, a, b) = self.lab_f32();
+        let floor = l_floor as f32;
+        // Scaled before rounding, so the boost does not magnify rounding steps.
+        let scale = if l < floor {
+            1.0 + (gain.max(1) - 1) as f32 * (floor - l.max(0.0)) / floor
+        } else {
+            1.0
+        };
+        (l as i32, (a * scale) as i32, (b * scale) as i32)
+    }
+
+    /// Squared distance between [`Rgb::to_lab_dark`] coordinates.
+    ///
+    /// ```
+    /// use sorter_logic::Rgb;
+    ///
+    /// let black = Rgb { r: 22, g: 22, b: 24 };
+    /// let navy = Rgb { r: 18, g: 22, b: 40 };
+    /// let red = Rgb { r: 200, g: 20, b: 30 };
+    /// let orange = Rgb { r: 220, g: 110, b: 20 };
+    ///
+    /// assert!(black.dist_lab_dark(&navy, 35, 3) > 4 * black.dist_lab(&navy));
+    /// // Bright colors are measured as in plain Lab.
+    /// assert_eq!(red.dist_lab_dark(&orange, 35, 3), red.dist_lab(&orange));
+    /// ```
+    pub fn dist_lab_dark(&self, other: &Rgb, l_floor: u8, gain: u8) -> u32 {
+        let (l1, a1, b1) = self.to_lab_dark(l_floor, gain);
+        let (l2, a2, b2) = other.to_lab_dark(l_floor, gain);
+        ((l1 - l2).pow(2) + (a1 - a2).pow(2) + (b1 - b2).pow(2)) as u32
+    }
+
+    /// Whether [`Rgb::dist_lab`] is below `limit`, without finishing the sum once it is
+    /// known not to be.
+    ///
+    /// ```
+    /// use sorter_logic::Rgb;
+    ///
+    /// let red = Rgb { r: 200, g: 20, b: 30 };
+    /// let blue = Rgb { r: 20, g: 40, b: 200 };
+    /// assert!(!red.dist_lab_less_than(&blue, 15));
+    /// assert_eq!(red.dist_lab_less_than(&blue, u32::MAX), red.dist_lab(&blue) < u32::MAX);
+    /// ```
+    pub fn dist_lab_less_than(&self, other: &Rgb, limit: u32) -> bool {
+        dist_below(self.to_lab(), other.to_lab(), limit).is_some()
+    }
+
+    /// OKLab (Björn Ottosson, 2020) scaled by 100, so L is 0..=100 and a/b are roughly
+    /// -40..=40, close to the magnitudes of [`Rgb::to_lab`].
+    pub fn to_oklab(&self) -> (i32, i32, i32) {
+        // sRGB channel value to linear light, generated by build.rs.
+        static SRGB_LINEAR: [f32; 256] = include!(concat!(env!("OUT_DIR"), "/srgb_linear.rs"));
+        let r = SRGB_LINEAR[self.r as usize];
+        let g = SRGB_LINEAR[self.g as usize];
+        let b = SRGB_LINEAR[self.b as usize];
+
+        let l = cbrt(0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b);
+        let m = cbrt(0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b);
+        let s = cbrt(0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b);
+
+        let ok_l = 0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s;
+        let ok_a = 1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s;
+        let ok_b = 0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s;
+
+        (
+            (ok_l * 100.0).round() as i32,
+            (ok_a * 100.0).round() as i32,
+            (ok_b * 100.0).round() as i32,
+        )
+    }
+
+    pub fn dist_oklab(&self, other: &Rgb) -> u32 {
+        let (l1, a1, b1) = self.to_oklab();
+        let (l2, a2, b2) = other.to_oklab();
+        ((l1 - l2).pow(2) + (a1 - a2).pow(2) + (b1 - b2).pow(2)) as u32
+    }
+
+    /// Hue in degrees (0..360), saturation and value (0..=255).
+    pub fn to_hsv(&self) -> (u16, u8, u8) {
+        let r = self.r as i32;
+        let g = self.g as i32;
+        let b = self.b as i32;
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        if delta == 0 {
+            return (0, 0, max as u8);
+        }
+
+        let h = if max == r {
+            60 * (g - b) / delta
+        } else if max == g {
+            120 + 60 * (b - r) / delta
+        } else {
+            240 + 60 * (r - g) / delta
+        };
+        let s = delta * 255 / max;
+
+        (h.rem_euclid(360) as u16, s as u8, max as u8)
+    }
+
+    /// Squared hue-first distance. Hue difference (degrees, scaled by the lower of the two
+    /// saturations since hue is meaningless for grays) dominates, saturation counts in
+    /// percent and value only at an eighth of that so illumination drift barely moves it.
+    pub fn dist_hue(&self, other: &Rgb) -> u32 {
+        let (h1, s1, v1) = self.to_hsv();
+        let (h2, s2, v2) = other.to_hsv();
+
+        let dh = (h1 as i32 - h2 as i32).abs();
+        let dh = dh.min(360 - dh);
+        let hue = dh * s1.min(s2) as i32 / 255;
+        let sat = (s1 as i32 - s2 as i32) * 100 / 255;
+        let val = (v1 as i32 - v2 as i32) * 100 / 255 / 8;
+
+        (hue.pow(2) + sat.pow(2) + val.pow(2)) as u32
+    }
+
+    /// Squared distance under `metric`.
+    pub fn dist_metric(&self, other: &Rgb, metric: ColorMetric) -> u32 {
+        match metric {
+            ColorMetric::Lab => self.dist_lab(other),
+            ColorMetric::OkLab => self.dist_oklab(other),
+            ColorMetric::HueWeighted => self.dist_hue(other),
+            ColorMetric::LabDark { l_floor, gain } => self.dist_lab_dark(other, l_floor, gain),
+        }
+    }
+}
+
+/// Largest [`AnalysisConfig::ring_outer`]: a whole ring that size still fits the 256 pixels
+/// the analysis keeps.
+pub const MAX_RING_OUTER: i32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnalysisConfig {
+    /// RGB distance from the background above which a pixel counts as part of the bead when
+    /// measuring its size and shape.
+    pub edge_threshold: i32,
+    /// Reject a detection with fewer kept ring pixels than this
+    /// ([`BeadAnalysis::pixel_count`]); too few to trust the color.
+    pub min_pixel_count: u32,
+    /// Reject a detection whose [`BeadAnalysis::variance`] is above this: a region that
+    /// mixes colors (debris, two beads, a bead half out of the slot) rather than one bead.
+    /// Elongated regions are caught by `max_eccentricity`.
+    pub max_variance: Option<u32>,
+    pub filter_percent: u8,
+    /// Adaptive outlier rejection: keep pixels whose distance from the ring mean is at most
+    /// `median + k * MAD` (median absolute deviation of those distances), instead of a fixed
+    /// `filter_percent`. A clean bead keeps nearly every pixel; a noisy one sheds more.
+    /// Typical `k` is 2.0 to 3.0.
+    pub mad_k: Option<f32>,
+    /// Minimum mean per-pixel squared RGB difference from a [`BackgroundModel`] for a bead to be
+    /// detected.
+    pub background_min_contrast: u32,
+    /// Search around this center (usually the previous bead's [`BeadAnalysis::center`])
+    /// before falling back to the full search window.
+    pub warm_start: Option<(i32, i32)>,
+    /// Half-size of the warm start neighborhood, in pixels.
+    pub warm_start_radius: i32,
+    /// Radii of the ring of pixels read around a candidate center, in pixels: the hole of a
+    /// bead sits inside `ring_inner` and the background outside `ring_outer`. The outer radius
+    /// is at most [`MAX_RING_OUTER`], and the inner one under it.
+    pub ring_inner: i32,
+    pub ring_outer: i32,
+    pub color_mode: ColorMode,
+    /// How [`ColorMode::Mean`] averages the ring pixels.
+    pub color_estimator: ColorEstimator,
+    /// Average in linear light instead of on sRGB bytes. Plain sRGB averaging biases mixed
+    /// (shaded, highlighted) pixels dark. Applies to the mean estimators; variance is always
+    /// measured on sRGB values.
+    pub linear_average: bool,
+    /// Correct the measured colors with gains from a gray-card capture.
+    pub white_balance: Option<WhiteBalance>,
+    /// Scale for [`BeadAnalysis::diameter_mm`].
+    pub camera: Option<CameraCalibration>,
+    /// Reject regions with a larger [`BeadAnalysis::eccentricity`] as debris. Whole beads
+    /// measure under about 0.4.
+    pub max_eccentricity: Option<f32>,
+    /// Read at most this many pixels of each ring, spread evenly over it (see
+    /// [`subsample`]), to bound analysis time on larger frames. The same pixels are read
+    /// every time, so results stay reproducible. `None` reads the whole ring.
+    pub pixel_budget: Option<u16>,
+}
+
+/// Image scale at the camera slot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraCalibration {
+    pub px_per_mm: f32,
+}
+
+impl CameraCalibration {
+    /// Calibrate from a bead of known diameter, e.g. a midi bead (5 mm) measured at
+    /// [`BeadAnalysis::diameter_px`].
+    pub fn from_reference(diameter_px: f32, diameter_mm: f32) -> Self {
+        Self {
+            px_per_mm: diameter_px / diameter_mm,
+        }
+    }
+
+    pub fn to_mm(&self, px: f32) -> f32 {
+        px / self.px_per_mm
+    }
+}
+
+/// Bead size class from a measured diameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BeadSize {
+    /// 2.6 mm beads.
+    Mini,
+    /// 5 mm beads (standard Perler/Hama midi).
+    Midi,
+}
+
+impl BeadSize {
+    /// Halfway between the nominal mini and midi diameters.
+    pub const SPLIT_MM: f32 = 3.8;
+
+    pub fn from_diameter_mm(mm: f32) -> Self {
+        if mm < Self::SPLIT_MM {
+            BeadSize::Mini
+        } else {
+            BeadSize::Midi
+        }
+    }
+}
+
+/// How [`BeadAnalysis::average_color`] is derived from the bead pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+    /// Mean of the pixels kept by the outlier filter.
+    #[default]
+    Mean,
+    /// Most populated bin of a coarse RGB histogram (8 levels per channel), averaged over the
+    /// pixels in that bin. Keeps two-tone and striped beads from washing out into a blend, and
+    /// reports the second color in [`BeadAnalysis::secondary_color`].
+    Dominant,
+    /// Split the bead pixels into two clusters (2-means). When both are large and distinct
+    /// enough, the larger cluster's mean is the color and the smaller one is reported in
+    /// [`BeadAnalysis::secondary_color`]; otherwise the same as `Mean`. Unlike `Dominant` the
+    /// clusters are not tied to histogram bins, so a color straddling a bin edge is not split.
+    TwoTone,
+}
+
+/// Averaging used by [`ColorMode::Mean`].
+///
+/// [`BeadAnalysis::variance`] and [`BeadAnalysis::pixel_count`] always come from the
+/// `filter_percent` filter, whichever estimator picks the color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorEstimator {
+    /// Mean of the `filter_percent` pixels closest to the ring mean.
+    #[default]
+    FilteredMean,
+    /// Per-channel median of the ring pixels. Robust to specular highlights, which only ever
+    /// pull the upper tail.
+    Median,
+    /// Per-channel mean after dropping this percentage of the lowest and of the highest values
+    /// (clamped so at least one pixel remains).
+    TrimmedMean(u8),
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        Self {
+            edge_threshold: 40, // Increased threshold for robust empty detection
+            min_pixel_count: 8,
+            max_variance: None,
+            filter_percent: 60,
+            mad_k: None,
+            background_min_contrast: 300,
+            warm_start: None,
+            warm_start_radius: 1,
+            ring_inner: 3,
+            ring_outer: 7,
+            color_mode: ColorMode::Mean,
+            color_estimator: ColorEstimator::FilteredMean,
+            linear_average: false,
+            white_balance: None,
+            camera: None,
+            max_eccentricity: None,
+            pixel_budget: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeadAnalysis {
+    pub average_color: Rgb,
+    pub pixel_count: u32,
+    /// Sum of `var_r`, `var_g` and `var_b`.
+    pub variance: u32,
+    /// Per-channel variance of the kept pixels. Spread shared by all three channels is mostly
+    /// luminance (shading, highlights); spread in one channel is chroma.
+    pub var_r: u32,
+    pub var_g: u32,
+    pub var_b: u32,
+    /// Ring center `(x, y)` the bead was measured at.
+    pub center: (i32, i32),
+    pub finish: BeadFinish,
+    /// Isolated bright pixels (glitter flecks) inside the bead ring; enough of them make the
+    /// finish [`BeadFinish::Glitter`].
+    pub sparkle: u32,
+    /// Second color of a multi-tone bead, in [`ColorMode::Dominant`] and
+    /// [`ColorMode::TwoTone`] when it covers more than [`SECONDARY_COLOR_MIN_PERCENT`] of the
+    /// bead.
+    pub secondary_color: Option<Rgb>,
+    /// Share of the bead pixels (percent) in `secondary_color`; 0 without one.
+    pub secondary_percent: u8,
+    /// Outer diameter in pixels: the bead's extent from the center along four axes (pixels
+    /// differing from the background by more than `edge_threshold`), averaged.
+    pub diameter_px: f32,
+    /// `diameter_px` in millimeters, with a [`AnalysisConfig::camera`] calibration.
+    pub diameter_mm: Option<f32>,
+    /// Eccentricity of the ellipse fitted to the bead pixels: 0 for a round bead, toward 1
+    /// for an elongated region (lint, a broken bead, two beads touching).
+    pub eccentricity: f32,
+    /// How far the measured color can be trusted, 0..=100: the share (percent) of the ring
+    /// pixels within [`CONFIDENT_DIST`] of the ring's mean color. A clean capture of a
+    /// single-color bead scores high; a ring that takes in background, a shadow, a second
+    /// color or glare scores low.
+    pub confidence: u8,
+}
+
+/// Squared RGB distance from the ring's mean color within which a ring pixel agrees with it
+/// (see [`BeadAnalysis::confidence`]).
+pub const CONFIDENT_DIST: u32 = 1200;
+
+pub const SECONDARY_COLOR_MIN_PERCENT: u32 = 20;
+
+// Histogram bins are 3 bits per channel.
+const HIST_SHIFT: u8 = 5;
+const HIST_BINS: usize = 1 << (3 * (8 - HIST_SHIFT));
+
+fn hist_bin(rgb: Rgb) -> usize {
+    let q = |c: u8| (c >> HIST_SHIFT) as usize;
+    (q(rgb.r) << 6) | (q(rgb.g) << 3) | q(rgb.b)
+}
+
+/// Squared Lab distance two 2-means clusters must be apart to count as two colors (a Delta E
+/// of 10).
+pub const TWO_TONE_MIN_DIST: u32 = 100;
+const TWO_MEANS_ROUNDS: usize = 8;
+
+/// A 2-means split of the pixels: the larger cluster's mean, the smaller one's and its share
+/// (percent). `None` if the clusters are too small or too close to be two colors.
+fn two_tone_split(pixels: &[(u16, u32, usize)]) -> Option<(Rgb, Rgb, u8)> {
+    let rgb = |i: usize| Rgb::from_rgb565(pixels[i].0);
+    // Seed with the pixel farthest from the mean (`pixels` is sorted by that distance) and
+    // the pixel farthest from it.
+    let far = pixels.len() - 1;
+    let other = (0..pixels.len())
+        .max_by_key(|&i| rgb(i).dist(&rgb(far)))
+        .unwrap_or(0);
+    let mut centers = [rgb(far), rgb(other)];
+
+    let mut sizes = [0u32; 2];
+    for _ in 0..TWO_MEANS_ROUNDS {
+        let mut sums = [[0u32; 3]; 2];
+        sizes = [0; 2];
+        for i in 0..pixels.len() {
+            let c = rgb(i);
+            let k = (c.dist(&centers[1]) < c.dist(&centers[0])) as usize;
+            sums[k][0] += c.r as u32;
+            sums[k][1] += c.g as u32;
+            sums[k][2] += c.b as u32;
+            sizes[k] += 1;
+        }
+        let mut next = centers;
+        for ((center, sum), size) in next.iter_mut().zip(sums).zip(sizes) {
+            // An empty cluster keeps its center.
+            let Some(size) = core::num::NonZeroU32::new(size) else {
+                continue;
+            };
+            *center = Rgb {
+                r: (sum[0] / size) as u8,
+                g: (sum[1] / size) as u8,
+                b: (sum[2] / size) as u8,
+            };
+        }
+        if next == centers {
+            break;
+        }
+        centers = next;
+    }
+
+    let (major, minor) = if sizes[0] >= sizes[1] { (0, 1) } else { (1, 0) };
+    let percent = sizes[minor] * 100 / pixels.len().max(1) as u32;
+    (percent > SECONDARY_COLOR_MIN_PERCENT && centers[0].dist_lab(&centers[1]) >= TWO_TONE_MIN_DIST)
+        .then_some((centers[major], centers[minor], percent as u8))
+}
+
+/// Dominant color and (if large enough) a secondary color from a coarse RGB histogram.
+fn dominant_colors(pixels: &[(u16, u32, usize)]) -> (Rgb, Option<(Rgb, u8)>) {
+    let mut counts = [0u16; HIST_BINS];
+    for (p, _, _) in pixels {
+        counts[hist_bin(Rgb::from_rgb565(*p))] += 1;
+    }
+
+    let top = (0..HIST_BINS).max_by_key(|&b| counts[b]).unwrap_or(0);
+    // Neighboring bins are usually the same color split across a bin edge.
+    let adjacent = |a: usize, b: usize| {
+        let axis = |shift: usize| ((a >> shift) & 7).abs_diff((b >> shift) & 7) <= 1;
+        axis(6) && axis(3) && axis(0)
+    };
+    let second = (0..HIST_BINS)
+        .filter(|&b| !adjacent(b, top))
+        .max_by_key(|&b| counts[b])
+        .filter(|&b| counts[b] as u32 * 100 > pixels.len() as u32 * SECONDARY_COLOR_MIN_PERCENT);
+
+    // The dominant color also takes in its neighboring bins; the secondary only its own bin
+    // (its neighbors may hold the dominant color's spill-over).
+    let mean_of = |bin: usize| {
+        let (mut r, mut g, mut b, mut n) = (0u32, 0u32, 0u32, 0u32);
+        for (p, _, _) in pixels {
+            let rgb = Rgb::from_rgb565(*p);
+            let member = if bin == top {
+                adjacent(hist_bin(rgb), bin)
             } else {
-                break;
+                hist_bin(rgb) == bin
+            };
+            if member {
+                r += rgb.r as u32;
+                g += rgb.g as u32;
+                b += rgb.b as u32;
+                n += 1;
+            }
+        }
+        let n = n.max(1);
+        Rgb {
+            r: (r / n) as u8,
+            g: (g / n) as u8,
+            b: (b / n) as u8,
+        }
+    };
+
+    let share = |bin: usize| (counts[bin] as u32 * 100 / pixels.len() as u32) as u8;
+    (mean_of(top), second.map(|bin| (mean_of(bin), share(bin))))
+}
+
+// How many of `pixels` (sorted by squared distance from the mean) lie within
+// `median + k * MAD` of the mean.
+fn mad_keep_count(pixels: &[(u16, u32, usize)], k: f32) -> usize {
+    let n = pixels.len();
+    let median = |sorted: &[f32]| (sorted[(n - 1) / 2] + sorted[n / 2]) / 2.0;
+
+    let mut dist = [0f32; 256];
+    for (d, (_, dist_sq, _)) in dist.iter_mut().zip(pixels) {
+        *d = (*dist_sq as f32).sqrt();
+    }
+    // Already in order: the pixels are sorted by distance.
+    let med = median(&dist[..n]);
+
+    let mut dev = [0f32; 256];
+    for (dv, d) in dev.iter_mut().zip(&dist[..n]) {
+        *dv = (d - med).abs();
+    }
+    let dev = &mut dev[..n];
+    dev.sort_unstable_by(|a, b| a.total_cmp(b));
+    let cutoff = med + k * median(dev);
+
+    dist[..n].iter().filter(|&&d| d <= cutoff).count()
+}
+
+// sRGB byte -> linear light, scaled to 0..=65535.
+const SRGB_TO_LINEAR: [u16; 256] = [
+    0, 20, 40, 60, 80, 99, 119, 139, 159, 179, 199, 219, 241, 264, 288, 313, 340, 367, 396, 427,
+    458, 491, 526, 562, 599, 637, 677, 718, 761, 805, 851, 898, 947, 997, 1048, 1101, 1156, 1212,
+    1270, 1330, 1391, 1453, 1517, 1583, 1651, 1720, 1790, 1863, 1937, 2013, 2090, 2170, 2250, 2333,
+    2418, 2504, 2592, 2681, 2773, 2866, 2961, 3058, 3157, 3258, 3360, 3464, 3570, 3678, 3788, 3900,
+    4014, 4129, 4247, 4366, 4488, 4611, 4736, 4864, 4993, 5124, 5257, 5392, 5530, 5669, 5810, 5953,
+    6099, 6246, 6395, 6547, 6700, 6856, 7014, 7174, 7335, 7500, 7666, 7834, 8004, 8177, 8352, 8528,
+    8708, 8889, 9072, 9258, 9445, 9635, 9828, 10022, 10219, 10417, 10619, 10822, 11028, 11235,
+    11446, 11658, 11873, 12090, 12309, 12530, 12754, 12980, 13209, 13440, 13673, 13909, 14146,
+    14387, 14629, 14874, 15122, 15371, 15623, 15878, 16135, 16394, 16656, 16920, 17187, 17456,
+    17727, 18001, 18277, 18556, 18837, 19121, 19407, 19696, 19987, 20281, 20577, 20876, 21177,
+    21481, 21787, 22096, 22407, 22721, 23038, 23357, 23678, 24002, 24329, 24658, 24990, 25325,
+    25662, 26001, 26344, 26688, 27036, 27386, 27739, 28094, 28452, 28813, 29176, 29542, 29911,
+    30282, 30656, 31033, 31412, 31794, 32179, 32567, 32957, 33350, 33745, 34143, 34544, 34948,
+    35355, 35764, 36176, 36591, 37008, 37429, 37852, 38278, 38706, 39138, 39572, 40009, 40449,
+    40891, 41337, 41785, 42236, 42690, 43147, 43606, 44069, 44534, 45002, 45473, 45947, 46423,
+    46903, 47385, 47871, 48359, 48850, 49344, 49841, 50341, 50844, 51349, 51858, 52369, 52884,
+    53401, 53921, 54445, 54971, 55500, 56032, 56567, 57105, 57646, 58190, 58737, 59287, 59840,
+    60396, 60955, 61517, 62082, 62650, 63221, 63795, 64372, 64952, 65535,
+];
+
+fn to_linear(c: u8) -> u32 {
+    SRGB_TO_LINEAR[c as usize] as u32
+}
+
+// Nearest sRGB byte for a linear-light value.
+fn from_linear(l: u32) -> u8 {
+    let above = SRGB_TO_LINEAR.partition_point(|&v| (v as u32) < l);
+    if above == 0 {
+        return 0;
+    }
+    if above == 256 {
+        return 255;
+    }
+    let below = above - 1;
+    if l - SRGB_TO_LINEAR[below] as u32 <= SRGB_TO_LINEAR[above] as u32 - l {
+        below as u8
+    } else {
+        above as u8
+    }
+}
+
+// Per-channel trimmed mean of the ring pixels, dropping `trim` values from each end of each
+// channel. `trim = (n - 1) / 2` is the median.
+fn trimmed_mean(pixels: &[(u16, u32, usize)], trim: usize, linear: bool) -> Rgb {
+    let n = pixels.len();
+    let trim = trim.min(n.saturating_sub(1) / 2);
+    let mut channel = [0u8; 256];
+    let mut estimate = |get: fn(Rgb) -> u8| {
+        for (c, (p, _, _)) in channel.iter_mut().zip(pixels) {
+            *c = get(Rgb::from_rgb565(*p));
+        }
+        let kept = &mut channel[..n];
+        kept.sort_unstable();
+        let kept = &kept[trim..n - trim];
+        if linear {
+            from_linear(kept.iter().map(|&c| to_linear(c)).sum::<u32>() / kept.len() as u32)
+        } else {
+            (kept.iter().map(|&c| c as u32).sum::<u32>() / kept.len() as u32) as u8
+        }
+    };
+    Rgb {
+        r: estimate(|c| c.r),
+        g: estimate(|c| c.g),
+        b: estimate(|c| c.b),
+    }
+}
+
+/// Surface finish, guessed from the spread of the ring pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BeadFinish {
+    #[default]
+    Opaque,
+    /// Background shows through: high variance and a wide saturation spread.
+    Translucent,
+    /// A few specular highlights on an otherwise even, low-spread color.
+    Pearl,
+    /// Scattered flecks: at least [`GLITTER_MIN_SPARKLES`] of [`BeadAnalysis::sparkle`].
+    Glitter,
+}
+
+impl BeadFinish {
+    pub const ALL: [BeadFinish; 4] = [
+        BeadFinish::Opaque,
+        BeadFinish::Translucent,
+        BeadFinish::Pearl,
+        BeadFinish::Glitter,
+    ];
+
+    /// Stable id, as stored in datasets and the router state.
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+}
+
+/// Sparkles that make a bead [`BeadFinish::Glitter`].
+pub const GLITTER_MIN_SPARKLES: u32 = 6;
+
+// Finish heuristic thresholds (ring pixels, 8-bit RGB).
+const HIGHLIGHT_MIN_VALUE: u8 = 235;
+const HIGHLIGHT_MAX_CHROMA: u8 = 40;
+// Past this share of the ring (percent) the bright pixels are the bead's own color (a white
+// or pale bead), not highlights on it.
+const HIGHLIGHT_MAX_PERCENT: u32 = 25;
+const PEARL_MIN_HIGHLIGHTS: u32 = 2;
+const PEARL_MAX_SAT_SPREAD: u8 = 60;
+const TRANSLUCENT_MIN_VARIANCE: u32 = 600;
+const TRANSLUCENT_MIN_SAT_SPREAD: u8 = 120;
+
+// A sparkle is a ring pixel this much brighter (luma) than the ring average, with no
+// neighbor inside the bead that bright.
+const SPARKLE_LUMA_DELTA: u32 = 60;
+
+fn luma(rgb: Rgb) -> u32 {
+    (rgb.r as u32 * 77 + rgb.g as u32 * 150 + rgb.b as u32 * 29) >> 8
+}
+
+fn count_sparkles(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    ring: Ring,
+    (cx, cy): (i32, i32),
+    pixels: &[(u16, u32, usize)],
+) -> u32 {
+    if pixels.is_empty() {
+        return 0;
+    }
+    let mean = pixels
+        .iter()
+        .map(|(p, _, _)| luma(Rgb::from_rgb565(*p)))
+        .sum::<u32>()
+        / pixels.len() as u32;
+    let bright = |x: i32, y: i32| {
+        // Background around the bead is often brighter; only the bead itself counts.
+        if (x - cx).pow(2) + (y - cy).pow(2) > ring.outer.pow(2)
+            || x < 0
+            || y < 0
+            || x >= width as i32
+            || y >= height as i32
+        {
+            return false;
+        }
+        luma(pixel_at(data, y as usize * width + x as usize)) > mean + SPARKLE_LUMA_DELTA
+    };
+
+    let mut sparkles = 0;
+    for &(_, _, i) in pixels {
+        let (x, y) = ((i % width) as i32, (i / width) as i32);
+        if !bright(x, y) {
+            continue;
+        }
+        let isolated = (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+            .filter(|&d| d != (0, 0))
+            .all(|(dx, dy)| !bright(x + dx, y + dy));
+        if isolated {
+            sparkles += 1;
+        }
+    }
+    sparkles
+}
+
+fn classify_finish(pixels: &[(u16, u32, usize)], ring_variance: u32, sparkle: u32) -> BeadFinish {
+    let mut highlights = 0u32;
+    let mut min_sat = u8::MAX;
+    let mut max_sat = 0u8;
+    for (p, _, _) in pixels {
+        let rgb = Rgb::from_rgb565(*p);
+        let max = rgb.r.max(rgb.g).max(rgb.b);
+        let min = rgb.r.min(rgb.g).min(rgb.b);
+        if max >= HIGHLIGHT_MIN_VALUE && max - min <= HIGHLIGHT_MAX_CHROMA {
+            // Specular highlights are excluded from the saturation spread.
+            highlights += 1;
+            continue;
+        }
+        let (_, sat, _) = rgb.to_hsv();
+        min_sat = min_sat.min(sat);
+        max_sat = max_sat.max(sat);
+    }
+    let sat_spread = max_sat.saturating_sub(min_sat);
+    if highlights * 100 > pixels.len() as u32 * HIGHLIGHT_MAX_PERCENT {
+        highlights = 0;
+    }
+
+    if sparkle >= GLITTER_MIN_SPARKLES {
+        BeadFinish::Glitter
+    } else if highlights >= PEARL_MIN_HIGHLIGHTS && sat_spread <= PEARL_MAX_SAT_SPREAD {
+        BeadFinish::Pearl
+    } else if ring_variance >= TRANSLUCENT_MIN_VARIANCE || sat_spread >= TRANSLUCENT_MIN_SAT_SPREAD
+    {
+        BeadFinish::Translucent
+    } else {
+        BeadFinish::Opaque
+    }
+}
+
+/// Find the bead in a frame and measure its color.
+///
+/// `data` is RGB565, big endian, row major. Returns `None` if no bead stands out from the
+/// background (empty slot) or the buffer is too short.
+///
+/// ```
+/// # use sorter_logic::doc_frame as frame;
+/// use sorter_logic::{BeadFinish, Rgb, analyze_image};
+///
+/// let bead = analyze_image(&frame((200, 20, 30)), 40, 30).unwrap();
+/// assert!(bead.average_color.dist_lab(&Rgb { r: 200, g: 20, b: 30 }) < 15);
+/// assert_eq!(bead.center, (20, 17));
+/// assert_eq!(bead.finish, BeadFinish::Opaque);
+/// ```
+pub fn analyze_image(data: &[u8], width: usize, height: usize) -> Option<BeadAnalysis> {
+    analyze_image_debug(data, width, height, None, None, AnalysisConfig::default())
+}
+
+/// Analyze several captures of the same bead and fuse them into a single result.
+///
+/// Each frame is analyzed on its own; frames whose color disagrees with the consensus
+/// (see [`FrameAccumulator::fuse`]) are dropped before averaging, and a frame without a bead
+/// found in it counts as disagreeing. At most [`MAX_FUSED_FRAMES`] frames are considered.
+pub fn analyze_frames(frames: &[&[u8]], width: usize, height: usize) -> Option<BeadAnalysis> {
+    let mut acc = FrameAccumulator::<MAX_FUSED_FRAMES>::new();
+    for frame in frames {
+        match analyze_image(frame, width, height) {
+            Some(analysis) => acc.push(analysis),
+            None => acc.push_failed(),
+        };
+    }
+    acc.fuse(FRAME_AGREEMENT_THRESHOLD)
+}
+
+pub const MAX_FUSED_FRAMES: usize = 8;
+
+/// Max squared Lab distance from the consensus color for a frame to be kept.
+pub const FRAME_AGREEMENT_THRESHOLD: u32 = 100;
+
+/// Collects per-frame analyses so callers that capture into a single buffer
+/// (the firmware) can fuse results without keeping every frame around.
+pub struct FrameAccumulator<const N: usize> {
+    frames: [Option<BeadAnalysis>; N],
+    count: usize,
+}
+
+impl<const N: usize> Default for FrameAccumulator<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FrameAccumulator<N> {
+    pub const fn new() -> Self {
+        Self {
+            frames: [None; N],
+            count: 0,
+        }
+    }
+
+    /// Add a frame's analysis. Returns false (and drops it) once N frames are held.
+    pub fn push(&mut self, analysis: BeadAnalysis) -> bool {
+        self.push_frame(Some(analysis))
+    }
+
+    /// Count a frame whose analysis failed; it disagrees with every other. Returns false once
+    /// N frames are held.
+    pub fn push_failed(&mut self) -> bool {
+        self.push_frame(None)
+    }
+
+    fn push_frame(&mut self, analysis: Option<BeadAnalysis>) -> bool {
+        if self.count < N {
+            self.frames[self.count] = analysis;
+            self.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.frames = [None; N];
+        self.count = 0;
+    }
+
+    /// Fuse the collected frames.
+    ///
+    /// The consensus is the frame with the smallest total Lab distance to all others
+    /// (the medoid). Frames further than `agreement` from it are rejected and the rest
+    /// are averaged; their finish is the one most of them saw (the medoid's on a tie). The
+    /// center and the secondary color are the medoid's. Returns `None` if no frames were
+    /// collected or if fewer than half of them (failed frames included) agree.
+    pub fn fuse(&self, agreement: u32) -> Option<BeadAnalysis> {
+        let frames = &self.frames[..self.count];
+
+        let mut medoid = None;
+        let mut min_total = u32::MAX;
+        for a in frames.iter().flatten() {
+            let total = frames
+                .iter()
+                .flatten()
+                .map(|b| a.average_color.dist_lab(&b.average_color))
+                .fold(0u32, |acc, d| acc.saturating_add(d));
+            if total < min_total {
+                min_total = total;
+                medoid = Some(*a);
+            }
+        }
+        let BeadAnalysis {
+            average_color: medoid,
+            center,
+            finish,
+            secondary_color,
+            secondary_percent,
+            ..
+        } = medoid?;
+        let kept_frames = || {
+            frames
+                .iter()
+                .flatten()
+                .filter(move |a| a.average_color.dist_lab(&medoid) <= agreement)
+        };
+
+        let mut sum_r = 0u32;
+        let mut sum_g = 0u32;
+        let mut sum_b = 0u32;
+        let mut sum_var = 0u32;
+        let mut sum_var_rgb = [0u32; 3];
+        let mut sum_pixels = 0u32;
+        let mut sum_confidence = 0u32;
+        let mut sum_sparkle = 0u32;
+        let mut sum_diameter = 0.0;
+        let mut sum_eccentricity = 0.0;
+        let (mut sum_mm, mut measured) = (0.0, 0u32);
+        let mut kept = 0u32;
+        for a in kept_frames() {
+            sum_confidence += a.confidence as u32;
+            sum_sparkle += a.sparkle;
+            sum_diameter += a.diameter_px;
+            sum_eccentricity += a.eccentricity;
+            if let Some(mm) = a.diameter_mm {
+                sum_mm += mm;
+                measured += 1;
+            }
+            sum_r += a.average_color.r as u32;
+            sum_g += a.average_color.g as u32;
+            sum_b += a.average_color.b as u32;
+            sum_var += a.variance;
+            sum_var_rgb[0] += a.var_r;
+            sum_var_rgb[1] += a.var_g;
+            sum_var_rgb[2] += a.var_b;
+            sum_pixels += a.pixel_count;
+            kept += 1;
+        }
+
+        if (kept as usize) * 2 < frames.len() {
+            return None;
+        }
+        let votes = |f: BeadFinish| kept_frames().filter(|a| a.finish == f).count();
+        let finish =
+            BeadFinish::ALL.into_iter().fold(
+                finish,
+                |best, f| if votes(f) > votes(best) { f } else { best },
+            );
+
+        Some(BeadAnalysis {
+            average_color: Rgb {
+                r: (sum_r / kept) as u8,
+                g: (sum_g / kept) as u8,
+                b: (sum_b / kept) as u8,
+            },
+            pixel_count: sum_pixels / kept,
+            variance: sum_var / kept,
+            var_r: sum_var_rgb[0] / kept,
+            var_g: sum_var_rgb[1] / kept,
+            var_b: sum_var_rgb[2] / kept,
+            center,
+            finish,
+            sparkle: sum_sparkle / kept,
+            secondary_color,
+            secondary_percent,
+            diameter_px: sum_diameter / kept as f32,
+            diameter_mm: (measured > 0).then(|| sum_mm / measured as f32),
+            eccentricity: sum_eccentricity / kept as f32,
+            confidence: (sum_confidence / kept) as u8,
+        })
+    }
+}
+
+/// What the analyzer did with a pixel, as written to the debug mask of
+/// [`analyze_image_debug`] (one byte per pixel, `MaskPixel as u8`). Where stages overlap, the
+/// later one wins: background patch, search window, ring pixels, center.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskPixel {
+    /// Not looked at.
+    Unused = 0,
+    /// In the ring, used for the bead color.
+    Kept = 1,
+    /// In the ring, but dropped by the outlier filter.
+    Rejected = 2,
+    /// In the fixed patch sampled for the background color (not written when a
+    /// [`BackgroundModel`] is used instead).
+    Background = 3,
+    /// A candidate ring center the search scored.
+    SearchWindow = 4,
+    /// The ring center the search picked.
+    Center = 5,
+}
+
+impl MaskPixel {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::Unused,
+            1 => Self::Kept,
+            2 => Self::Rejected,
+            3 => Self::Background,
+            4 => Self::SearchWindow,
+            5 => Self::Center,
+            _ => return None,
+        })
+    }
+}
+
+/// [`analyze_image`] with explicit settings. If `mask` is given (one byte per pixel) it is
+/// filled with a [`MaskPixel`] classification for each pixel.
+///
+/// With a `background` model, candidate bead positions are scored by their per-pixel
+/// difference from that capture of the empty slot instead of the fixed background rectangle,
+/// and frames that do not differ from it by at least `config.background_min_contrast` are
+/// reported as empty (`None`). A model built for a different frame size also yields `None`.
+pub fn analyze_image_debug(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    mask: Option<&mut [u8]>,
+    background: Option<&BackgroundModel>,
+    config: AnalysisConfig,
+) -> Option<BeadAnalysis> {
+    if background.is_some_and(|bg| bg.width() != width || bg.height() != height) {
+        return None;
+    }
+    analyze(data, width, height, mask, config, background)
+}
+
+/// Mean color of the fixed background patch the ring search compares against (the rectangle
+/// (10,3)..=(15,6)). `None` if the frame does not cover it.
+pub fn background_patch(data: &[u8], width: usize, height: usize) -> Option<Rgb> {
+    let mut c_r: u32 = 0;
+    let mut c_g: u32 = 0;
+    let mut c_b: u32 = 0;
+    let mut c_cnt = 0;
+
+    // Sample Specific Rectangle (10,3) -> (15,6)
+    // User estimation: Edges are raised, this region is a better representation of the background.
+    let (min_bg_x, max_bg_x, min_bg_y, max_bg_y) = BACKGROUND_PATCH;
+
+    for y in min_bg_y..=max_bg_y {
+        for x in min_bg_x..=max_bg_x {
+            // Bounds check
+            if x >= width || y >= height {
+                continue;
             }
+
+            let i = y * width + x;
+            if i * 2 + 1 >= data.len() {
+                continue;
+            }
+            let rgb = pixel_at(data, i);
+            c_r += rgb.r as u32;
+            c_g += rgb.g as u32;
+            c_b += rgb.b as u32;
+            c_cnt += 1;
         }
+    }
+    match c_cnt {
+        0 => None,
+        c_cnt => Some(Rgb {
+            r: (c_r / c_cnt) as u8,
+            g: (c_g / c_cnt) as u8,
+            b: (c_b / c_cnt) as u8,
+        }),
+    }
+}
+
+/// Follows slow illumination drift (LED warm-up) through the background patch color.
+///
+/// Seed it with the patch color at the start of the run, [`DriftTracker::update`] it with
+/// every frame, and pass [`DriftTracker::correction`] as [`AnalysisConfig::white_balance`]
+/// (or apply it to the measured color) to map bead colors back to the reference lighting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DriftTracker {
+    reference: [f32; 3],
+    current: [f32; 3],
+    alpha: f32,
+}
 
-        if let Some(idx) = best_idx
-            && min_dist < threshold
-        {
-            return PaletteMatch::Match(idx);
+impl DriftTracker {
+    /// Default EWMA weight of a new sample: follows minutes-long drift, ignores a single
+    /// odd frame.
+    pub const DEFAULT_ALPHA: f32 = 0.05;
+
+    pub fn new(reference: Rgb, alpha: f32) -> Self {
+        let reference = [reference.r as f32, reference.g as f32, reference.b as f32];
+        Self {
+            reference,
+            current: reference,
+            alpha: alpha.clamp(0.0, 1.0),
         }
+    }
 
-        if self.count < N {
-            let idx = self.count;
-            self.colors[idx] = Some(PaletteEntry::new(*rgb, _variance));
-            self.count += 1;
-            PaletteMatch::NewEntry(idx)
-        } else {
-            PaletteMatch::Full
+    /// Seed from a frame's background patch, with [`DriftTracker::DEFAULT_ALPHA`].
+    pub fn from_frame(data: &[u8], width: usize, height: usize) -> Option<Self> {
+        background_patch(data, width, height).map(|c| Self::new(c, Self::DEFAULT_ALPHA))
+    }
+
+    /// Fold in a frame's background patch. Returns false if the frame does not cover it.
+    pub fn update(&mut self, data: &[u8], width: usize, height: usize) -> bool {
+        let Some(patch) = background_patch(data, width, height) else {
+            return false;
+        };
+        for (cur, sample) in self.current.iter_mut().zip([patch.r, patch.g, patch.b]) {
+            *cur += self.alpha * (sample as f32 - *cur);
         }
+        true
     }
 
-    pub fn add_sample(&mut self, index: usize, rgb: &Rgb, variance: u32) {
-        if index < N
-            && let Some(entry) = &mut self.colors[index]
-        {
-            entry.add(*rgb, variance);
+    /// Smoothed background patch color.
+    pub fn current(&self) -> Rgb {
+        let [r, g, b] = self.current.map(|c| (c + 0.5) as u8);
+        Rgb { r, g, b }
+    }
+
+    /// Per-channel gains that undo the drift since the reference.
+    pub fn correction(&self) -> WhiteBalance {
+        let gain = |i: usize| self.reference[i] / self.current[i].max(1.0);
+        WhiteBalance {
+            gain_r: gain(0),
+            gain_g: gain(1),
+            gain_b: gain(2),
         }
     }
+}
 
-    pub fn get(&self, index: usize) -> Option<Rgb> {
-        if index < N {
-            self.colors[index].map(|e| e.avg().0)
-        } else {
-            None
+/// Max frame size (in pixels) a [`BackgroundModel`] can hold; matches the 40x30 camera mode.
+pub const MAX_FRAME_PIXELS: usize = 40 * 30;
+
+/// Per-pixel reference image of the empty bead slot.
+///
+/// Build it once from a capture taken with no bead present and pass it to
+/// [`analyze_image_debug`].
+#[derive(Clone)]
+pub struct BackgroundModel {
+    pixels: [Rgb; MAX_FRAME_PIXELS],
+    width: usize,
+    height: usize,
+}
+
+impl BackgroundModel {
+    /// Build a model from an RGB565 (big endian) capture of the empty slot.
+    /// Returns `None` if the frame is larger than [`MAX_FRAME_PIXELS`] or `data` is short.
+    pub fn from_frame(data: &[u8], width: usize, height: usize) -> Option<Self> {
+        let n = width * height;
+        if n == 0 || n > MAX_FRAME_PIXELS || data.len() < n * 2 {
+            return None;
         }
+
+        let mut pixels = [Rgb { r: 0, g: 0, b: 0 }; MAX_FRAME_PIXELS];
+        decode_rgb565_be_into(&data[..n * 2], &mut pixels);
+
+        Some(Self {
+            pixels,
+            width,
+            height,
+        })
     }
 
-    pub fn get_entry(&self, index: usize) -> Option<PaletteEntry> {
-        if index < N { self.colors[index] } else { None }
+    pub fn width(&self) -> usize {
+        self.width
     }
 
-    pub fn len(&self) -> usize {
-        self.count
+    pub fn height(&self) -> usize {
+        self.height
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.count == 0
+    /// Reference color at pixel index `y * width + x`.
+    pub fn pixel(&self, index: usize) -> Rgb {
+        self.pixels[index]
     }
-}
 
-impl Rgb {
-    pub fn from_rgb565(p: u16) -> Self {
-        let r = ((p >> 11) & 0x1F) as u8;
-        let g = ((p >> 5) & 0x3F) as u8;
-        let b = (p & 0x1F) as u8;
+    /// Mean reference color over the whole frame.
+    pub fn mean(&self) -> Rgb {
+        let n = (self.width * self.height) as u32;
+        let (r, g, b) = self.pixels[..n as usize]
+            .iter()
+            .fold((0u32, 0u32, 0u32), |(r, g, b), p| {
+                (r + p.r as u32, g + p.g as u32, b + p.b as u32)
+            });
+        Rgb {
+            r: (r / n) as u8,
+            g: (g / n) as u8,
+            b: (b / n) as u8,
+        }
+    }
+}
 
-        // Scale to 8-bit
-        let r8 = ((r as u16 * 255) / 31) as u8;
-        let g8 = ((g as u16 * 255) / 63) as u8;
-        let b8 = ((b as u16 * 255) / 31) as u8;
+/// Per-channel gains that make a neutral gray reference read as gray.
+///
+/// The camera's auto white balance drifts between sessions, which shifts every bead color
+/// and splits identical beads across palette entries. Calibrate once per session from a
+/// capture of a gray card and set it as [`AnalysisConfig::white_balance`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WhiteBalance {
+    pub gain_r: f32,
+    pub gain_g: f32,
+    pub gain_b: f32,
+}
 
+impl Default for WhiteBalance {
+    fn default() -> Self {
         Self {
-            r: r8,
-            g: g8,
-            b: b8,
+            gain_r: 1.0,
+            gain_g: 1.0,
+            gain_b: 1.0,
         }
     }
+}
 
-    pub fn dist(&self, other: &Rgb) -> u32 {
-        // Use squared Euclidean
-        let rd = (self.r as i32 - other.r as i32).pow(2);
-        let gd = (self.g as i32 - other.g as i32).pow(2);
-        let bd = (self.b as i32 - other.b as i32).pow(2);
-        (rd + gd + bd) as u32
+impl WhiteBalance {
+    // Channel means below this are too dark (or too saturated) to calibrate from.
+    const MIN_CHANNEL_MEAN: u32 = 16;
+
+    /// Compute gains from an RGB565 (big endian) capture of a gray card filling the view.
+    ///
+    /// Only the central half of the frame is used, away from vignetting at the edges. The
+    /// gains keep the overall brightness. Returns `None` if `data` is short or the card is too
+    /// dark in any channel.
+    pub fn from_frame(data: &[u8], width: usize, height: usize) -> Option<Self> {
+        if width < 2 || height < 2 || data.len() < width * height * 2 {
+            return None;
+        }
+        let (mut r, mut g, mut b, mut n) = (0u32, 0u32, 0u32, 0u32);
+        for y in height / 4..height - height / 4 {
+            for x in width / 4..width - width / 4 {
+                let rgb = pixel_at(data, y * width + x);
+                r += rgb.r as u32;
+                g += rgb.g as u32;
+                b += rgb.b as u32;
+                n += 1;
+            }
+        }
+        let (r, g, b) = (r / n, g / n, b / n);
+        if r.min(g).min(b) < Self::MIN_CHANNEL_MEAN {
+            return None;
+        }
+        let gray = (r + g + b) as f32 / 3.0;
+        Some(Self {
+            gain_r: gray / r as f32,
+            gain_g: gray / g as f32,
+            gain_b: gray / b as f32,
+        })
     }
 
-    pub fn to_lab(&self) -> (i32, i32, i32) {
-        let r = self.r as f32 / 255.0;
-        let g = self.g as f32 / 255.0;
-        let b = self.b as f32 / 255.0;
+    /// Gains of `self` followed by `other` (e.g. a gray-card calibration plus a
+    /// [`DriftTracker::correction`]).
+    pub fn combine(&self, other: &WhiteBalance) -> WhiteBalance {
+        WhiteBalance {
+            gain_r: self.gain_r * other.gain_r,
+            gain_g: self.gain_g * other.gain_g,
+            gain_b: self.gain_b * other.gain_b,
+        }
+    }
 
-        let r = if r > 0.04045 {
-            ((r + 0.055) / 1.055).powf(2.4)
-        } else {
-            r / 12.92
-        };
-        let g = if g > 0.04045 {
-            ((g + 0.055) / 1.055).powf(2.4)
-        } else {
-            g / 12.92
-        };
-        let b = if b > 0.04045 {
-            ((b + 0.055) / 1.055).powf(2.4)
-        } else {
-            b / 12.92
-        };
+    pub fn apply(&self, rgb: Rgb) -> Rgb {
+        let scale = |c: u8, gain: f32| (c as f32 * gain + 0.5).min(255.0) as u8;
+        Rgb {
+            r: scale(rgb.r, self.gain_r),
+            g: scale(rgb.g, self.gain_g),
+            b: scale(rgb.b, self.gain_b),
+        }
+    }
+}
 
-        let x = (r * 0.4124 + g * 0.3576 + b * 0.1805) * 100.0;
-        let y = (r * 0.2126 + g * 0.7152 + b * 0.0722) * 100.0;
-        let z = (r * 0.0193 + g * 0.1192 + b * 0.9505) * 100.0;
+/// Outcome of [`detect_empty`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmptyResult {
+    /// No bead is present in the slot.
+    pub empty: bool,
+    /// How sure the detector is of `empty`, 0-100.
+    pub confidence: u8,
+}
 
-        let x = x / 95.047;
-        let y = y / 100.000;
-        let z = z / 108.883;
+/// Decide whether the bead slot is empty by comparing the frame against a reference capture
+/// of the empty slot.
+///
+/// Runs the same ring search as [`analyze_image_debug`] with a background model, so with the
+/// same `config` a frame reported as not empty here is one the analysis would attempt to
+/// measure. Confidence scales with how far the contrast is from `background_min_contrast`.
+///
+/// A model built for a different frame size (or a short buffer) yields
+/// `EmptyResult { empty: false, confidence: 0 }`.
+///
+/// ```
+/// # use sorter_logic::doc_frame as frame;
+/// # fn empty() -> [u8; 2400] { frame((200, 200, 190)) }
+/// use sorter_logic::{AnalysisConfig, BackgroundModel, detect_empty};
+///
+/// let model = BackgroundModel::from_frame(&empty(), 40, 30).unwrap();
+/// let config = AnalysisConfig::default();
+/// assert!(detect_empty(&empty(), 40, 30, &model, config).empty);
+/// assert!(!detect_empty(&frame((20, 40, 200)), 40, 30, &model, config).empty);
+/// ```
+pub fn detect_empty(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    background: &BackgroundModel,
+    config: AnalysisConfig,
+) -> EmptyResult {
+    let unknown = EmptyResult {
+        empty: false,
+        confidence: 0,
+    };
+    if width == 0
+        || height == 0
+        || background.width() != width
+        || background.height() != height
+        || data.len() < width * height * 2
+    {
+        return unknown;
+    }
 
-        let x = if x > 0.008856 {
-            x.powf(1.0 / 3.0)
-        } else {
-            (7.787 * x) + (16.0 / 116.0)
-        };
-        let y = if y > 0.008856 {
-            y.powf(1.0 / 3.0)
-        } else {
-            (7.787 * y) + (16.0 / 116.0)
-        };
-        let z = if z > 0.008856 {
-            z.powf(1.0 / 3.0)
-        } else {
-            (7.787 * z) + (16.0 / 116.0)
-        };
+    let bg_color = background_patch(data, width, height).unwrap_or(Rgb { r: 0, g: 0, b: 0 });
+    let scan = scan_window(
+        data,
+        width,
+        height,
+        bg_color,
+        Some(background),
+        Ring::of(&config),
+        SEARCH_WINDOW,
+    );
+
+    let threshold = config.background_min_contrast.max(1) as i64;
+    let empty = scan.contrast < threshold;
+    let margin = (scan.contrast - threshold).abs();
+    let confidence = (margin * 100 / threshold).min(100) as u8;
+    EmptyResult { empty, confidence }
+}
 
-        let l = (116.0 * y) - 16.0;
-        let a = 500.0 * (x - y);
-        let b = 200.0 * (y - z);
+/// Best ring position found by [`scan_window`].
+struct RingScan {
+    score: i64,
+    contrast: i64,
+    cx: i32,
+    cy: i32,
+    stats: Option<(Rgb, u32, u32)>,
+}
 
-        (l as i32, a as i32, b as i32)
-    }
+/// Candidate centers `(min_cx, max_cx, min_cy, max_cy)`, inclusive.
+type SearchWindow = (i32, i32, i32, i32);
 
-    pub fn dist_lab(&self, other: &Rgb) -> u32 {
-        let (l1, a1, b1) = self.to_lab();
-        let (l2, a2, b2) = other.to_lab();
-        ((l1 - l2).pow(2) + (a1 - a2).pow(2) + (b1 - b2).pow(2)) as u32
+// Where the ring search looks for the bead center: x 16..=24, y 16..=18.
+const SEARCH_WINDOW: SearchWindow = (16, 24, 16, 18);
+
+// Fixed background sample rectangle, (min_x, max_x, min_y, max_y) inclusive.
+const BACKGROUND_PATCH: (usize, usize, usize, usize) = (10, 15, 3, 6);
+
+// Set every pixel of an inclusive rectangle (clipped to the frame) in a debug mask.
+fn mark_rect(
+    mask: &mut [u8],
+    width: usize,
+    (min_x, max_x, min_y, max_y): (usize, usize, usize, usize),
+    value: MaskPixel,
+) {
+    for y in min_y..=max_y {
+        for x in min_x..=max_x.min(width.saturating_sub(1)) {
+            if let Some(m) = mask.get_mut(y * width + x) {
+                *m = value as u8;
+            }
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct AnalysisConfig {
-    pub edge_threshold: i32,
-    pub min_dimension: usize,
-    pub aspect_ratio_min: f32,
-    pub aspect_ratio_max: f32,
-    pub filter_percent: u8,
+/// The annulus of pixels read around a candidate center, from
+/// [`AnalysisConfig::ring_inner`], [`AnalysisConfig::ring_outer`] and
+/// [`AnalysisConfig::pixel_budget`].
+#[derive(Clone, Copy)]
+struct Ring {
+    inner: i32,
+    outer: i32,
+    budget: Option<u16>,
 }
 
-impl Default for AnalysisConfig {
-    fn default() -> Self {
+impl Ring {
+    fn of(config: &AnalysisConfig) -> Self {
+        let outer = config.ring_outer.clamp(1, MAX_RING_OUTER);
         Self {
-            edge_threshold: 40, // Increased threshold for robust empty detection
-            min_dimension: 10,
-            aspect_ratio_min: 0.6,
-            aspect_ratio_max: 1.6,
-            filter_percent: 60,
+            inner: config.ring_inner.clamp(0, outer),
+            outer,
+            budget: config.pixel_budget,
         }
     }
+
+    // Offsets from the center, row by row.
+    fn offsets(self) -> impl Iterator<Item = (i32, i32)> {
+        let Ring { inner, outer, .. } = self;
+        (-outer..=outer)
+            .flat_map(move |dy| (-outer..=outer).map(move |dx| (dx, dy)))
+            .filter(move |&(dx, dy)| {
+                let dist_sq = dx * dx + dy * dy;
+                dist_sq >= inner * inner && dist_sq <= outer * outer
+            })
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct BeadAnalysis {
-    pub average_color: Rgb,
-    pub pixel_count: u32,
-    pub variance: u32,
+// Fixed so that a frame always gets the same pixels.
+const SUBSAMPLE_SEED: u32 = 0x2545_F491;
+
+/// Frame indices of the ring around `(cx, cy)`, row by row, thinned to the ring's budget of
+/// pixels when set. The picks are made over the whole ring, so a ring clipped by the frame edge yields
+/// fewer.
+fn ring_pixels(
+    ring: Ring,
+    (cx, cy): (i32, i32),
+    width: usize,
+    height: usize,
+) -> impl Iterator<Item = usize> {
+    let mut picks = ring.budget.map(|b| {
+        let whole = ring.offsets().count() as u32;
+        subsample::Subsample::new(whole, b as u32, SUBSAMPLE_SEED).peekable()
+    });
+    ring.offsets()
+        .enumerate()
+        .filter(move |&(n, _)| match &mut picks {
+            Some(picks) => picks.next_if_eq(&(n as u32)).is_some(),
+            None => true,
+        })
+        .filter_map(move |(_, (dx, dy))| {
+            let (x, y) = (cx + dx, cy + dy);
+            (x >= 0 && y >= 0 && x < width as i32 && y < height as i32)
+                .then_some(y as usize * width + x as usize)
+        })
 }
 
-pub fn analyze_image(data: &[u8], width: usize, height: usize) -> Option<BeadAnalysis> {
-    analyze_image_debug(data, width, height, None, AnalysisConfig::default())
+// Rays for measuring the bead's extent: E, SE, S, SW, W, NW, N, NE. Opposite rays are four
+// apart.
+const EXTENT_RAYS: [(i32, i32); 8] = [
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+];
+
+/// Size and shape of the bead region around a ring center.
+struct Shape {
+    /// Mean of four center-crossing diameters, in pixels.
+    diameter: f32,
+    /// See [`BeadAnalysis::eccentricity`].
+    eccentricity: f32,
 }
 
-pub fn analyze_image_debug(
+/// Measure the bead region around `center`.
+///
+/// A pixel belongs to the bead if it differs from the background (the model's pixel, or the
+/// background patch color) by more than `edge_threshold` in RGB. The diameter comes from rays
+/// cast along [`EXTENT_RAYS`]: the bead's hole shows background too, so a ray skips
+/// background pixels until it first reaches the bead, then runs to the last bead pixel. A ray
+/// that never reaches the bead measures 0. The eccentricity comes from the second moments of
+/// the bead pixels.
+fn measure_shape(
     data: &[u8],
     width: usize,
     height: usize,
-    mut mask: Option<&mut [u8]>,
-    config: AnalysisConfig,
-) -> Option<BeadAnalysis> {
-    if width == 0 || height == 0 || data.len() < width * height * 2 {
-        return None;
-    }
+    (cx, cy): (i32, i32),
+    bg_color: Rgb,
+    background: Option<&BackgroundModel>,
+    config: &AnalysisConfig,
+) -> Shape {
+    let ring = Ring::of(config);
+    let threshold_sq = config.edge_threshold.pow(2) as u32;
+    let is_bead = |x: i32, y: i32| {
+        let i = y as usize * width + x as usize;
+        let rgb = pixel_at(data, i);
+        let reference = background.map_or(bg_color, |bg| bg.pixel(i));
+        rgb.dist(&reference) > threshold_sq
+    };
 
-    if let Some(m) = &mut mask {
-        m.fill(0);
+    let (mut sum_x, mut sum_y, mut n) = (0i32, 0i32, 0i32);
+    let (mut sum_xx, mut sum_yy, mut sum_xy) = (0i32, 0i32, 0i32);
+    let reach = ring.outer + 2;
+    for y in (cy - reach).max(0)..=(cy + reach).min(height as i32 - 1) {
+        for x in (cx - reach).max(0)..=(cx + reach).min(width as i32 - 1) {
+            if is_bead(x, y) {
+                // Relative to the ring center to keep the sums small.
+                let (dx, dy) = (x - cx, y - cy);
+                sum_x += dx;
+                sum_y += dy;
+                sum_xx += dx * dx;
+                sum_yy += dy * dy;
+                sum_xy += dx * dy;
+                n += 1;
+            }
+        }
+    }
+    if n == 0 {
+        return Shape {
+            diameter: 0.0,
+            eccentricity: 1.0,
+        };
     }
 
-    // --- Background Color Estimation ---
-    let mut c_r: u32 = 0;
-    let mut c_g: u32 = 0;
-    let mut c_b: u32 = 0;
-    let mut c_cnt = 0;
+    // Eigenvalues of the pixel covariance are the squared axes of the best-fit ellipse.
+    let nf = n as f32;
+    let (mx, my) = (sum_x as f32 / nf, sum_y as f32 / nf);
+    let cov_xx = sum_xx as f32 / nf - mx * mx;
+    let cov_yy = sum_yy as f32 / nf - my * my;
+    let cov_xy = sum_xy as f32 / nf - mx * my;
+    let half_sum = (cov_xx + cov_yy) / 2.0;
+    let spread = (((cov_xx - cov_yy) / 2.0).powi(2) + cov_xy * cov_xy).sqrt();
+    let (major, minor) = (half_sum + spread, (half_sum - spread).max(0.0));
+    let eccentricity = if major > 0.0 {
+        (1.0 - minor / major).max(0.0).sqrt()
+    } else {
+        1.0
+    };
 
-    // Sample Specific Rectangle (10,3) -> (15,6)
-    // User estimation: Edges are raised, this region is a better representation of the background.
-    let min_bg_x = 10;
-    let max_bg_x = 15;
-    let min_bg_y = 3;
-    let max_bg_y = 6;
+    // The ring search fits a fixed ring size, so it can sit off center on a small bead. Cast
+    // the rays from the centroid of the bead pixels instead.
+    let cx = cx + (sum_x + n / 2).div_euclid(n);
+    let cy = cy + (sum_y + n / 2).div_euclid(n);
 
-    for y in min_bg_y..=max_bg_y {
-        for x in min_bg_x..=max_bg_x {
-            // Bounds check
-            if x >= width || y >= height {
-                continue;
+    let mut extent = [0.0; 8];
+    for (radius, &(dx, dy)) in extent.iter_mut().zip(EXTENT_RAYS.iter()) {
+        let step_len = if dx != 0 && dy != 0 {
+            core::f32::consts::SQRT_2
+        } else {
+            1.0
+        };
+        let mut entered = false;
+        let mut last = None;
+        // Furthest a ray looks, in steps.
+        for step in 1..=2 * ring.outer {
+            let (x, y) = (cx + dx * step, cy + dy * step);
+            if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+                break;
             }
-
-            let idx = (y * width + x) * 2;
-            if idx + 1 >= data.len() {
-                continue;
+            if is_bead(x, y) {
+                entered = true;
+                last = Some(step);
+            } else if entered {
+                break;
             }
-            let p = u16::from_be_bytes([data[idx], data[idx + 1]]);
-            let rgb = Rgb::from_rgb565(p);
-            c_r += rgb.r as u32;
-            c_g += rgb.g as u32;
-            c_b += rgb.b as u32;
-            c_cnt += 1;
         }
-    }
-    let bg_color = if c_cnt > 0 {
-        Rgb {
-            r: (c_r / c_cnt) as u8,
-            g: (c_g / c_cnt) as u8,
-            b: (c_b / c_cnt) as u8,
+        // The edge lies about half a pixel beyond the last bead pixel's center.
+        if let Some(step) = last {
+            *radius = (step as f32 + 0.5) * step_len;
         }
-    } else {
-        Rgb { r: 0, g: 0, b: 0 }
-    };
+    }
 
-    // --- Ring Search Configuration ---
-    // User Constraints:
-    // x[16,24], y[16,18]
-    // Ring Radii 3, 7 (Optimal Variance)
-    let r_inner = 3i32;
-    let r_outer = 7i32;
-    let r_inner_sq = r_inner.pow(2);
-    let r_outer_sq = r_outer.pow(2);
-
-    // Constrained Search Range
-    let min_cx = 16;
-    let max_cx = 24; // Restored from 29
-    let min_cy = 16;
-    let max_cy = 18;
+    Shape {
+        diameter: (0..4).map(|i| extent[i] + extent[i + 4]).sum::<f32>() / 4.0,
+        eccentricity,
+    }
+}
 
+fn scan_window(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    bg_color: Rgb,
+    background: Option<&BackgroundModel>,
+    ring: Ring,
+    (min_cx, max_cx, min_cy, max_cy): SearchWindow,
+) -> RingScan {
     let mut best_score = i64::MIN;
+    let mut best_contrast = 0i64;
     let mut best_stats = None;
     let mut best_cx = (min_cx + max_cx) / 2;
     let mut best_cy = (min_cy + max_cy) / 2;
@@ -349,40 +2333,29 @@ pub fn analyze_image_debug(
             let mut sum_sq_r = 0u32;
             let mut sum_sq_g = 0u32;
             let mut sum_sq_b = 0u32;
+            // Per-pixel squared difference from the background model (if any)
+            let mut sum_diff_sq = 0u32;
             let mut count = 0u32;
 
-            // Scan Bounding Box of Ring
-            let min_y = (cy - r_outer).max(0);
-            let max_y = (cy + r_outer).min(height as i32 - 1);
-            let min_x = (cx - r_outer).max(0);
-            let max_x = (cx + r_outer).min(width as i32 - 1);
-
-            for y in min_y..=max_y {
-                for x in min_x..=max_x {
-                    let dy = y - cy;
-                    let dx = x - cx;
-                    let dist_sq = dx * dx + dy * dy;
-
-                    if dist_sq >= r_inner_sq && dist_sq <= r_outer_sq {
-                        let idx = (y as usize * width + x as usize) * 2;
-                        if idx + 1 >= data.len() {
-                            continue;
-                        }
-                        let p = u16::from_be_bytes([data[idx], data[idx + 1]]);
-                        let rgb = Rgb::from_rgb565(p);
-                        let r = rgb.r as u32;
-                        let g = rgb.g as u32;
-                        let b = rgb.b as u32;
-
-                        sum_r += r;
-                        sum_g += g;
-                        sum_b += b;
-                        sum_sq_r += r * r;
-                        sum_sq_g += g * g;
-                        sum_sq_b += b * b;
-                        count += 1;
-                    }
+            for i in ring_pixels(ring, (cx, cy), width, height) {
+                if i * 2 + 1 >= data.len() {
+                    continue;
+                }
+                let rgb = pixel_at(data, i);
+                let r = rgb.r as u32;
+                let g = rgb.g as u32;
+                let b = rgb.b as u32;
+
+                sum_r += r;
+                sum_g += g;
+                sum_b += b;
+                sum_sq_r += r * r;
+                sum_sq_g += g * g;
+                sum_sq_b += b * b;
+                if let Some(bg) = background {
+                    sum_diff_sq += rgb.dist(&bg.pixel(i));
                 }
+                count += 1;
             }
 
             // count check removed to ensure we always score if possible
@@ -407,8 +2380,12 @@ pub fn analyze_image_debug(
             let total_variance = var_r + var_g + var_b;
 
             // Score Heuristic (Center Scoring)
-            // PRIMARY: Contrast against Global BG.
-            let contrast = avg.dist(&bg_color) as i64;
+            // PRIMARY: Contrast against Global BG (or the per-pixel background model).
+            let contrast = if background.is_some() {
+                (sum_diff_sq / count) as i64
+            } else {
+                avg.dist(&bg_color) as i64
+            };
 
             // SECONDARY: Variance Penalty (/8).
             let variance_penalty = (total_variance as i64) / 8;
@@ -417,6 +2394,7 @@ pub fn analyze_image_debug(
 
             if score > best_score {
                 best_score = score;
+                best_contrast = contrast;
                 best_cx = cx;
                 best_cy = cy;
                 // Temporary stats, will be refined below
@@ -425,13 +2403,103 @@ pub fn analyze_image_debug(
         }
     }
 
+    RingScan {
+        score: best_score,
+        contrast: best_contrast,
+        cx: best_cx,
+        cy: best_cy,
+        stats: best_stats,
+    }
+}
+
+fn analyze(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    mut mask: Option<&mut [u8]>,
+    config: AnalysisConfig,
+    background: Option<&BackgroundModel>,
+) -> Option<BeadAnalysis> {
+    if width == 0 || height == 0 || data.len() < width * height * 2 {
+        return None;
+    }
+
+    if let Some(m) = &mut mask {
+        m.fill(MaskPixel::Unused as u8);
+        if background.is_none() {
+            mark_rect(m, width, BACKGROUND_PATCH, MaskPixel::Background);
+        }
+    }
+
+    // --- Background Color Estimation ---
+    let bg_color = background_patch(data, width, height).unwrap_or(Rgb { r: 0, g: 0, b: 0 });
+
+    // --- Ring Search Configuration ---
+    let full = SEARCH_WINDOW;
+    let (min_cx, max_cx, min_cy, max_cy) = full;
+    let ring = Ring::of(&config);
+    let failed = |scan: &RingScan| {
+        scan.score < -200000
+            || (background.is_some() && scan.contrast < config.background_min_contrast as i64)
+    };
+
+    let mut scan_window = |window: SearchWindow| {
+        if let Some(m) = &mut mask {
+            let (x0, x1, y0, y1) = window;
+            let rect = (x0 as usize, x1 as usize, y0 as usize, y1 as usize);
+            mark_rect(m, width, rect, MaskPixel::SearchWindow);
+        }
+        scan_window(data, width, height, bg_color, background, ring, window)
+    };
+
+    // Warm start: search a small neighborhood of the previous center first, and only fall back
+    // to the full window if that fails or the best center sits on the neighborhood's edge
+    // (the true optimum may lie outside it).
+    let scan = match config.warm_start {
+        Some((x, y)) => {
+            let r = config.warm_start_radius.max(0);
+            let near: SearchWindow = (
+                (x - r).max(min_cx),
+                (x + r).min(max_cx),
+                (y - r).max(min_cy),
+                (y + r).min(max_cy),
+            );
+            let scan = if near.0 <= near.1 && near.2 <= near.3 {
+                Some(scan_window(near))
+            } else {
+                None
+            };
+            match scan {
+                Some(scan)
+                    if !failed(&scan)
+                        && (scan.cx != near.0 || near.0 == min_cx)
+                        && (scan.cx != near.1 || near.1 == max_cx)
+                        && (scan.cy != near.2 || near.2 == min_cy)
+                        && (scan.cy != near.3 || near.3 == max_cy) =>
+                {
+                    scan
+                }
+                _ => scan_window(full),
+            }
+        }
+        None => scan_window(full),
+    };
+
     // --- Threshold Check ---
-    if best_score < -200000 {
+    if failed(&scan) {
         return None;
     }
+    let best_cx = scan.cx;
+    let best_cy = scan.cy;
+    let mut best_stats = scan.stats;
 
     // Refine Stats with Outlier Filtering (Top 40% Variance Removal)
-    if let Some((_, _, _)) = best_stats {
+    let mut finish = BeadFinish::Opaque;
+    let mut sparkle = 0;
+    let mut secondary: Option<(Rgb, u8)> = None;
+    let mut channel_variance = [0u32; 3];
+    let mut confidence = 0;
+    if let Some((_, _, ring_variance)) = best_stats {
         let cx = best_cx;
         let cy = best_cy;
 
@@ -444,42 +2512,28 @@ pub fn analyze_image_debug(
         let mut sum_g = 0u32;
         let mut sum_b = 0u32;
 
-        let min_y = (cy - r_outer).max(0);
-        let max_y = (cy + r_outer).min(height as i32 - 1);
-        let min_x = (cx - r_outer).max(0);
-        let max_x = (cx + r_outer).min(width as i32 - 1);
-
-        for y in min_y..=max_y {
-            for x in min_x..=max_x {
-                let dy = y - cy;
-                let dx = x - cx;
-                let dist_sq = dx * dx + dy * dy;
-
-                if dist_sq >= r_inner_sq && dist_sq <= r_outer_sq {
-                    let idx = (y as usize * width + x as usize) * 2;
-                    if idx + 1 >= data.len() {
-                        continue;
-                    }
+        for i in ring_pixels(ring, (cx, cy), width, height) {
+            let idx = i * 2;
+            if idx + 1 >= data.len() {
+                continue;
+            }
 
-                    if p_count < 256 {
-                        let p = u16::from_be_bytes([data[idx], data[idx + 1]]);
-                        pixels[p_count] = (p, 0, idx / 2); // Store mask index
+            if p_count < 256 {
+                let p = u16::from_be_bytes([data[idx], data[idx + 1]]);
+                pixels[p_count] = (p, 0, i); // Store mask index
 
-                        let rgb = Rgb::from_rgb565(p);
-                        sum_r += rgb.r as u32;
-                        sum_g += rgb.g as u32;
-                        sum_b += rgb.b as u32;
-                        p_count += 1;
-                    }
-                }
+                let rgb = Rgb::from_rgb565(p);
+                sum_r += rgb.r as u32;
+                sum_g += rgb.g as u32;
+                sum_b += rgb.b as u32;
+                p_count += 1;
             }
         }
 
-        if let Some(m) = &mut mask {
-            m[cy as usize * width + cx as usize] = 4; // Blue Center
-        }
-
         if p_count > 0 {
+            sparkle = count_sparkles(data, width, height, ring, (cx, cy), &pixels[..p_count]);
+            finish = classify_finish(&pixels[..p_count], ring_variance, sparkle);
+
             let mean_r = (sum_r / p_count as u32) as i32;
             let mean_g = (sum_g / p_count as u32) as i32;
             let mean_b = (sum_b / p_count as u32) as i32;
@@ -492,6 +2546,11 @@ pub fn analyze_image_debug(
                 let db = (rgb.b as i32 - mean_b).pow(2);
                 *dist = (dr + dg + db) as u32;
             }
+            let agreeing = pixels[..p_count]
+                .iter()
+                .filter(|&&(_, dist, _)| dist <= CONFIDENT_DIST)
+                .count();
+            confidence = (agreeing * 100 / p_count) as u8;
 
             // 3. Sort by Distance (Simple Insertion Sort for small N)
             for i in 1..p_count {
@@ -502,8 +2561,20 @@ pub fn analyze_image_debug(
                 }
             }
 
-            // 4. Keep Best N% (Configurable)
-            let keep_count = (p_count as u32 * config.filter_percent as u32 / 100).max(1) as usize;
+            // 4. Keep Best N% (Configurable), or everything within the MAD cutoff
+            let keep_count = match config.mad_k {
+                Some(k) => mad_keep_count(&pixels[..p_count], k),
+                None => (p_count as u32 * config.filter_percent as u32 / 100) as usize,
+            }
+            .max(1);
+
+            if let Some(m) = &mut mask {
+                for &(_, _, m_idx) in &pixels[keep_count..p_count] {
+                    if let Some(m) = m.get_mut(m_idx) {
+                        *m = MaskPixel::Rejected as u8;
+                    }
+                }
+            }
 
             let mut f_sum_r = 0u32;
             let mut f_sum_g = 0u32;
@@ -511,6 +2582,7 @@ pub fn analyze_image_debug(
             let mut f_sum_sq_r = 0u32;
             let mut f_sum_sq_g = 0u32;
             let mut f_sum_sq_b = 0u32;
+            let mut lin_sum = [0u32; 3];
 
             for (p, _, m_idx) in pixels.iter().copied().take(keep_count) {
                 let rgb = Rgb::from_rgb565(p);
@@ -524,12 +2596,16 @@ pub fn analyze_image_debug(
                 f_sum_sq_r += r * r;
                 f_sum_sq_g += g * g;
                 f_sum_sq_b += b * b;
+                if config.linear_average {
+                    lin_sum[0] += to_linear(rgb.r);
+                    lin_sum[1] += to_linear(rgb.g);
+                    lin_sum[2] += to_linear(rgb.b);
+                }
 
-                // Update Mask with Kept Pixels
                 if let Some(m) = &mut mask
                     && m_idx < m.len()
                 {
-                    m[m_idx] = 1; // Green
+                    m[m_idx] = MaskPixel::Kept as u8;
                 }
             }
 
@@ -537,10 +2613,19 @@ pub fn analyze_image_debug(
             let f_mean_g = f_sum_g / keep_count as u32;
             let f_mean_b = f_sum_b / keep_count as u32;
 
-            let f_avg = Rgb {
-                r: f_mean_r as u8,
-                g: f_mean_g as u8,
-                b: f_mean_b as u8,
+            let f_avg = if config.linear_average {
+                let keep = keep_count as u32;
+                Rgb {
+                    r: from_linear(lin_sum[0] / keep),
+                    g: from_linear(lin_sum[1] / keep),
+                    b: from_linear(lin_sum[2] / keep),
+                }
+            } else {
+                Rgb {
+                    r: f_mean_r as u8,
+                    g: f_mean_g as u8,
+                    b: f_mean_b as u8,
+                }
             };
 
             let f_var_r = (f_sum_sq_r / keep_count as u32).saturating_sub(f_mean_r * f_mean_r);
@@ -548,17 +2633,89 @@ pub fn analyze_image_debug(
             let f_var_b = (f_sum_sq_b / keep_count as u32).saturating_sub(f_mean_b * f_mean_b);
             let f_total_variance = f_var_r + f_var_g + f_var_b;
 
+            let estimate = || match config.color_estimator {
+                ColorEstimator::FilteredMean => f_avg,
+                ColorEstimator::Median => {
+                    trimmed_mean(&pixels[..p_count], (p_count - 1) / 2, false)
+                }
+                ColorEstimator::TrimmedMean(pct) => trimmed_mean(
+                    &pixels[..p_count],
+                    p_count * pct as usize / 100,
+                    config.linear_average,
+                ),
+            };
+            let f_avg = match config.color_mode {
+                ColorMode::Mean => estimate(),
+                ColorMode::Dominant => {
+                    let (dominant, found) = dominant_colors(&pixels[..p_count]);
+                    secondary = found;
+                    dominant
+                }
+                // All ring pixels: the outlier filter would drop the minority color.
+                ColorMode::TwoTone => match two_tone_split(&pixels[..p_count]) {
+                    Some((major, minor, percent)) => {
+                        secondary = Some((minor, percent));
+                        major
+                    }
+                    None => estimate(),
+                },
+            };
+
             best_stats = Some((f_avg, keep_count as u32, f_total_variance));
+            channel_variance = [f_var_r, f_var_g, f_var_b];
         } else {
             best_stats = None; // No pixels found in the best ring, so no stats
         }
     }
 
+    if let Some(m) = &mut mask
+        && let Some(m) = m.get_mut(best_cy as usize * width + best_cx as usize)
+    {
+        *m = MaskPixel::Center as u8;
+    }
+
     if let Some((avg, count, var)) = best_stats {
+        let secondary_percent = secondary.map_or(0, |(_, p)| p);
+        let secondary_color = secondary.map(|(c, _)| c);
+        let (avg, secondary_color) = match &config.white_balance {
+            Some(wb) => (wb.apply(avg), secondary_color.map(|c| wb.apply(c))),
+            None => (avg, secondary_color),
+        };
+        if count < config.min_pixel_count || config.max_variance.is_some_and(|max| var > max) {
+            return None;
+        }
+        let shape = measure_shape(
+            data,
+            width,
+            height,
+            (best_cx, best_cy),
+            bg_color,
+            background,
+            &config,
+        );
+        if config
+            .max_eccentricity
+            .is_some_and(|max| shape.eccentricity > max)
+        {
+            return None;
+        }
+        let diameter_px = shape.diameter;
         Some(BeadAnalysis {
             average_color: avg,
             pixel_count: count,
             variance: var,
+            var_r: channel_variance[0],
+            var_g: channel_variance[1],
+            var_b: channel_variance[2],
+            center: (best_cx, best_cy),
+            finish,
+            sparkle,
+            secondary_color,
+            secondary_percent,
+            diameter_px,
+            diameter_mm: config.camera.map(|c| c.to_mm(diameter_px)),
+            eccentricity: shape.eccentricity,
+            confidence,
         })
     } else {
         None
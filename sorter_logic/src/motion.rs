@@ -0,0 +1,79 @@
+//! Velocity profiles for servo moves.
+//!
+//! A [`Trapezoid`] speeds up at a constant acceleration, cruises at the top speed and slows
+//! down at the same rate, so a move starts as gently as it ends. A move too short to reach the
+//! top speed makes a triangle instead.
+//!
+//! ```
+//! use sorter_logic::motion::Trapezoid;
+//!
+//! // 1000us at up to 2000us/s, accelerating at 8000us/s²: a quarter second each of speeding
+//! // up, cruising and slowing down.
+//! let move_ = Trapezoid::new(1000, 2000, 8000);
+//! assert_eq!(move_.duration_ms(), 750);
+//! assert_eq!(move_.progress(375), 0.5);
+//! assert_eq!(move_.progress(750), 1.0);
+//! ```
+
+// Unused wherever std is linked (tests, and host builds that pull it in through a
+// dependency), as std's own f32 methods take precedence.
+#[allow(unused_imports)]
+use micromath::F32Ext;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trapezoid {
+    distance: f32,
+    accel: f32,
+    // Time spent speeding up (and again slowing down), and cruising, in seconds.
+    ramp: f32,
+    cruise: f32,
+}
+
+impl Trapezoid {
+    /// A move over `distance` at up to `max_speed` per second, speeding up and slowing down
+    /// at `accel` per second squared.
+    pub fn new(distance: u32, max_speed: u32, accel: u32) -> Self {
+        let distance = distance as f32;
+        let speed = max_speed.max(1) as f32;
+        let accel = accel.max(1) as f32;
+        let mut ramp = speed / accel;
+        let mut cruise = 0.0;
+        // Both ramps together cover accel * ramp².
+        if accel * ramp * ramp > distance {
+            ramp = (distance / accel).sqrt();
+        } else {
+            cruise = (distance - accel * ramp * ramp) / speed;
+        }
+        Self {
+            distance,
+            accel,
+            ramp,
+            cruise,
+        }
+    }
+
+    pub fn duration_ms(&self) -> u32 {
+        ((2.0 * self.ramp + self.cruise) * 1000.0 + 0.5) as u32
+    }
+
+    /// Fraction of the distance covered `t_ms` into the move, from 0 to 1.
+    pub fn progress(&self, t_ms: u32) -> f32 {
+        if self.distance == 0.0 {
+            return 1.0;
+        }
+        let t = t_ms as f32 / 1000.0;
+        let total = 2.0 * self.ramp + self.cruise;
+        let ramp_distance = 0.5 * self.accel * self.ramp * self.ramp;
+        let covered = if t <= self.ramp {
+            0.5 * self.accel * t * t
+        } else if t <= self.ramp + self.cruise {
+            ramp_distance + self.accel * self.ramp * (t - self.ramp)
+        } else if t < total {
+            let left = total - t;
+            self.distance - 0.5 * self.accel * left * left
+        } else {
+            self.distance
+        };
+        (covered / self.distance).clamp(0.0, 1.0)
+    }
+}
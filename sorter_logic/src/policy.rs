@@ -0,0 +1,213 @@
+//! Where each bead goes, as the firmware and the virtual sorter both decide it: a
+//! [`SortPolicy`] wraps the [`TubeRouter`] with the stored settings, the reject tube, and the
+//! collect and extract modes.
+//!
+//! The router numbers its tubes over the layout without the reject tube;
+//! [`SortPolicy::layout_tube`] shifts the ones at or after the reject tube up by one, so
+//! everything the policy hands out is a layout tube.
+//!
+//! ```
+//! # use sorter_logic::doc_frame as frame;
+//! use sorter_logic::analyze_image;
+//! use sorter_logic::policy::{Decision, SortPolicy};
+//! use sorter_logic::settings::{Setting, Settings};
+//!
+//! let mut settings = Settings::default();
+//! settings.set(Setting::RejectTube, 0).unwrap();
+//! let mut policy = SortPolicy::new(4);
+//! policy.apply_settings(&settings);
+//!
+//! let red = analyze_image(&frame((200, 20, 30)), 40, 30).unwrap();
+//! let decision = policy.decide(&red, None, None);
+//! assert!(matches!(decision, Decision::Routed { tube: 1, .. }));
+//! assert_eq!(policy.drop_tube(decision.tube(), false), 1);
+//! assert_eq!(policy.drop_tube(None, false), 0);
+//! ```
+
+use crate::collect::{Collection, Extraction};
+use crate::profile::Profile;
+use crate::router::{Route, StateError, TubeRouter};
+use crate::settings::Settings;
+use crate::{BeadAnalysis, Rgb};
+
+/// What [`SortPolicy::decide`] made of a bead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// The router routed it to layout tube `tube`. `route` is in router tubes (see
+    /// [`SortPolicy::layout_tube`]).
+    Routed { tube: u8, route: Route },
+    /// Close to the extraction color: into the first tube.
+    Extracted,
+    /// Not the color being collected or extracted: passed through.
+    Passed,
+    /// Its color confidence is under `Setting::RejectConfidence`.
+    Unreliable,
+    /// The router turned it down (a full palette, or past the reject distance).
+    NotRouted,
+}
+
+impl Decision {
+    /// The layout tube the bead was sorted into, `None` if it was not.
+    pub fn tube(&self) -> Option<u8> {
+        match self {
+            Decision::Routed { tube, .. } => Some(*tube),
+            Decision::Extracted => Some(0),
+            Decision::Passed | Decision::Unreliable | Decision::NotRouted => None,
+        }
+    }
+}
+
+pub struct SortPolicy {
+    router: TubeRouter,
+    settings: Settings,
+    profile: Profile,
+    tube_count: usize,
+    // Where unrouted beads go, if the settings name a tube in the layout.
+    reject_tube: Option<u8>,
+}
+
+impl SortPolicy {
+    /// Sort over the `tube_count` tubes of the layout, with the default settings and profile.
+    pub fn new(tube_count: usize) -> Self {
+        Self {
+            router: TubeRouter::new(tube_count),
+            settings: Settings::default(),
+            profile: Profile::default(),
+            tube_count,
+            reject_tube: None,
+        }
+    }
+
+    /// Use the stored reject, router and threshold settings. A reject tube outside the layout
+    /// is ignored. Changing the reject tube shifts the tubes after it, so set it before
+    /// sorting.
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.settings = *settings;
+        self.reject_tube = settings
+            .reject_tube()
+            .filter(|&t| (t as usize) < self.tube_count);
+        self.router
+            .set_tube_count(self.tube_count - self.reject_tube.is_some() as usize);
+        self.router.set_reject_distance(settings.reject_distance());
+        self.router.set_strategy(settings.tube_strategy());
+        self.router.set_split_finishes(settings.split_finishes());
+        self.router.set_capacity(settings.tube_capacity());
+        self.set_profile(self.profile);
+    }
+
+    /// Switch palette mode and thresholds; the learned palette is kept. A stored match
+    /// threshold or tube merge margin overrides the profile's.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+        self.router.set_profile(profile);
+        if let Some(threshold) = self.settings.match_threshold() {
+            self.router.set_match_threshold(threshold);
+        }
+        if let Some(margin) = self.settings.tube_merge_margin() {
+            self.router.set_merge_margin(margin);
+        }
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    /// Override the profile's match threshold and merge margin until the next profile change.
+    pub fn set_thresholds(&mut self, match_threshold: u32, merge_margin: u32) {
+        self.router.set_match_threshold(match_threshold);
+        self.router.set_merge_margin(merge_margin);
+    }
+
+    pub fn router(&self) -> &TubeRouter {
+        &self.router
+    }
+
+    /// Replace the learned palette and tube map (see [`TubeRouter::restore_state`]).
+    pub fn restore_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        self.router.restore_state(bytes)
+    }
+
+    /// The layout tube kept for unrouted beads, if the settings name one in the layout.
+    pub fn reject_tube(&self) -> Option<u8> {
+        self.reject_tube
+    }
+
+    /// The layout tube of router tube `router_tube`.
+    pub fn layout_tube(&self, router_tube: u8) -> u8 {
+        let slot = self.router.slot(router_tube);
+        match self.reject_tube {
+            Some(reject) if slot >= reject => slot + 1,
+            _ => slot,
+        }
+    }
+
+    /// The purity estimate of layout tube `tube`, once beads have been routed to it.
+    pub fn purity(&self, tube: u8) -> Option<u8> {
+        (0..self.router.tubes().len() as u8)
+            .find(|&t| self.layout_tube(t) == tube)
+            .and_then(|t| self.router.purity(t))
+    }
+
+    /// Distance from `color` to the nearest learned palette entry, if any.
+    pub fn match_distance(&self, color: &Rgb) -> Option<u32> {
+        self.router.palette().nearest(color).map(|(_, d)| d)
+    }
+
+    /// True if `collection` is after this bead, judged with the palette match threshold.
+    pub fn is_wanted(&self, collection: &Collection, bead: &BeadAnalysis) -> bool {
+        let threshold = self
+            .settings
+            .match_threshold()
+            .unwrap_or(self.profile.settings().match_threshold);
+        collection.wants(self.router.palette(), &bead.average_color, threshold)
+    }
+
+    /// Decide where `bead` goes while collecting, extracting or neither. Only a bead the
+    /// router routes teaches it anything.
+    pub fn decide(
+        &mut self,
+        bead: &BeadAnalysis,
+        collection: Option<&Collection>,
+        extraction: Option<&Extraction>,
+    ) -> Decision {
+        if collection.is_some_and(|c| !self.is_wanted(c, bead)) {
+            return Decision::Passed;
+        }
+        if let Some(extraction) = extraction {
+            return if extraction.wants(&bead.average_color) {
+                Decision::Extracted
+            } else {
+                Decision::Passed
+            };
+        }
+        if self
+            .settings
+            .reject_confidence()
+            .is_some_and(|min| bead.confidence < min)
+        {
+            return Decision::Unreliable;
+        }
+        match self
+            .router
+            .route_finish(bead.average_color, bead.variance, bead.finish)
+        {
+            Some(route) => Decision::Routed {
+                tube: self.layout_tube(route.tube),
+                route,
+            },
+            None => Decision::NotRouted,
+        }
+    }
+
+    /// The layout tube for a bead sorted into `routed` (see [`Decision::tube`]), or not
+    /// sorted: while extracting the rest goes back in bulk to the last tube, otherwise to the
+    /// reject tube. Without one, an unsorted bead shares tube 0, so collecting (which passes
+    /// beads through) needs a reject tube.
+    pub fn drop_tube(&self, routed: Option<u8>, extracting: bool) -> u8 {
+        match (routed, extracting) {
+            (Some(tube), _) => tube,
+            (None, true) => (self.tube_count - 1) as u8,
+            (None, false) => self.reject_tube.unwrap_or(0),
+        }
+    }
+}
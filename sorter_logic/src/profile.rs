@@ -0,0 +1,86 @@
+//! Named sorting profiles: one switch instead of several settings when moving between
+//! palette-building runs and production runs.
+
+use crate::PaletteMode;
+use crate::router::{DEFAULT_TUBE_MERGE_MARGIN, MATCH_THRESHOLD};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    /// Build the palette: unmatched colors become new entries (and tubes).
+    #[default]
+    Learning,
+    /// Sort into the palette learned so far, which is frozen; every bead goes to its nearest
+    /// entry. An empty palette still learns, as there is nothing to sort into.
+    Production,
+    /// Like production with a tighter match, but beads not clearly matching an entry are not
+    /// routed (the firmware drops unrouted beads into tube 0), and palette entries never share
+    /// a tube through the merge margin.
+    Strict,
+}
+
+/// What a [`Profile`] sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileSettings {
+    /// [`PaletteMode::Learning`] to create palette entries for unmatched colors,
+    /// [`PaletteMode::Frozen`] to only sort into the entries there are.
+    pub palette_mode: PaletteMode,
+    /// Palette match threshold (squared Lab).
+    pub match_threshold: u32,
+    /// Reject beads with no entry within `match_threshold` instead of taking the nearest.
+    pub reject_unmatched: bool,
+    /// See [`crate::router::TubeRouter::set_merge_margin`].
+    pub tube_merge_margin: u32,
+}
+
+impl Profile {
+    pub const ALL: [Profile; 3] = [Profile::Learning, Profile::Production, Profile::Strict];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Learning => "learning",
+            Profile::Production => "production",
+            Profile::Strict => "strict",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == name)
+    }
+
+    /// Stable id for config storage and the USB command.
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+
+    /// The profile after this one, wrapping around (for cycling with a button).
+    pub fn next(self) -> Self {
+        Self::ALL[(self.id() as usize + 1) % Self::ALL.len()]
+    }
+
+    pub fn settings(self) -> ProfileSettings {
+        match self {
+            Profile::Learning => ProfileSettings {
+                palette_mode: PaletteMode::Learning,
+                match_threshold: MATCH_THRESHOLD,
+                reject_unmatched: false,
+                tube_merge_margin: DEFAULT_TUBE_MERGE_MARGIN,
+            },
+            Profile::Production => ProfileSettings {
+                palette_mode: PaletteMode::Frozen,
+                match_threshold: MATCH_THRESHOLD,
+                reject_unmatched: false,
+                tube_merge_margin: DEFAULT_TUBE_MERGE_MARGIN,
+            },
+            Profile::Strict => ProfileSettings {
+                palette_mode: PaletteMode::Frozen,
+                match_threshold: 10,
+                reject_unmatched: true,
+                tube_merge_margin: 0,
+            },
+        }
+    }
+}
@@ -0,0 +1,681 @@
+//! Tube routing: learns a palette of bead colors and assigns each palette entry a physical
+//! tube, optionally respecting how many beads a tube can hold. A [`TubeStrategy`] decides
+//! when a color gets a tube of its own, and which slot in the rack that tube is.
+//!
+//! Tubes are numbered in the order they were claimed; [`TubeRouter::slot`] maps them onto
+//! rack slots. Only [`TubeStrategy::HueOrdered`] leaves gaps, so that the rack ends up in
+//! rainbow order however the colors arrive.
+//!
+//! With [`TubeRouter::set_split_finishes`] on, a bead's [`BeadFinish`] keeps it apart from beads
+//! of the same color with another finish: they get palette entries and tubes of their own.
+//!
+//! The learned state (palette, palette → tube table, tube centroids and slots) can be saved with
+//! [`TubeRouter::encode_state`] and brought back after a reboot with
+//! [`TubeRouter::restore_state`], so beads keep going to the same tubes.
+
+use sorter_protocol::crc16;
+
+use crate::layout::MAX_TUBES;
+use crate::profile::{Profile, ProfileSettings};
+use crate::{BeadFinish, ColorMetric, Palette, PaletteEntry, PaletteMatch, PaletteMode, Rgb};
+
+pub const PALETTE_SIZE: usize = 128;
+/// Palette match threshold (squared Lab, a Delta E of about 4).
+pub const MATCH_THRESHOLD: u32 = 15;
+/// New palette entries closer than this (squared Lab) to an existing tube share that tube
+/// instead of claiming a fresh one.
+pub const DEFAULT_TUBE_MERGE_MARGIN: u32 = 40;
+/// A bead counts toward its tube's purity if it lands within this squared Lab distance of the
+/// tube centroid (a Delta E of 6).
+pub const PURITY_RADIUS: u32 = 36;
+
+const UNASSIGNED: u8 = 0xFF;
+
+const STATE_MAGIC: [u8; 4] = *b"ROUT";
+const STATE_VERSION: u8 = 3;
+// Before finishes were saved; every entry and tube is opaque.
+const STATE_VERSION_NO_FINISHES: u8 = 2;
+// Before tube slots were saved; every tube is in the slot of its number.
+const STATE_VERSION_NO_SLOTS: u8 = 1;
+const STATE_HEADER: usize = 8;
+// Sums and count of a `PaletteEntry`.
+const ENTRY_BYTES: usize = 24;
+/// Largest [`TubeRouter::encode_state`]: header, a full palette with its tube table and
+/// finishes, every tube's centroid, purity count, slot and finish, CRC-16.
+pub const ROUTER_STATE_MAX: usize =
+    STATE_HEADER + PALETTE_SIZE * (ENTRY_BYTES + 2) + MAX_TUBES * (ENTRY_BYTES + 6) + 2;
+
+/// Hue bands of [`TubeStrategy::HueOrdered`]: twelve of 30 degrees from red, then the grays.
+pub const HUE_BANDS: usize = 13;
+// Below this HSV saturation or value a color is a gray (white and black included): its hue
+// means little.
+const GRAY_SATURATION: u8 = 48;
+const GRAY_VALUE: u8 = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// Stored bytes are not router state (blank flash, or an older format).
+    BadHeader,
+    /// The record is damaged.
+    BadCrc,
+    /// The state uses more palette entries or tubes than this router has, or maps an entry
+    /// to a tube it does not use.
+    DoesNotFit,
+}
+
+/// How tubes are handed out. Changing it keeps the palette and tubes learned so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TubeStrategy {
+    /// A new color claims the next empty tube, unless it is within the merge margin of a
+    /// tube; a full tube spills into an empty tube, which takes the color over.
+    #[default]
+    FirstCome,
+    /// Like first come, but a full tube spills into the nearest-color tube with room, keeping
+    /// the empty tubes for new colors.
+    NearestOverflow,
+    /// The tubes are those of a seeded or uploaded palette: no tube is claimed, and new
+    /// colors and spillover go to the nearest-color tube.
+    Fixed,
+    /// Like first come, but the slots are shared out between [`HUE_BANDS`] hue bands and a
+    /// new tube takes the free slot nearest its hue (or, for grays, lightness), so the rack
+    /// reads as a rainbow followed by the grays.
+    HueOrdered,
+}
+
+impl TubeStrategy {
+    pub const ALL: [TubeStrategy; 4] = [
+        TubeStrategy::FirstCome,
+        TubeStrategy::NearestOverflow,
+        TubeStrategy::Fixed,
+        TubeStrategy::HueOrdered,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TubeStrategy::FirstCome => "first_come",
+            TubeStrategy::NearestOverflow => "nearest_overflow",
+            TubeStrategy::Fixed => "fixed",
+            TubeStrategy::HueOrdered => "hue_ordered",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.name() == name)
+    }
+
+    /// Stable id, the value of `Setting::TubeStrategy`.
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+}
+
+/// Why a bead went to the tube it did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteReason {
+    /// The bead's palette entry already had a tube.
+    Mapped,
+    /// A new palette entry claimed an empty tube.
+    NewTube,
+    /// A new palette entry was within the merge margin of this tube (squared Lab distance).
+    Merged(u32),
+    /// A new palette entry found no empty tube it could claim and went to the closest one.
+    NoFreeTube,
+    /// The preferred tube (`from`) was at capacity.
+    Spillover { from: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub tube: u8,
+    pub palette_index: usize,
+    pub reason: RouteReason,
+}
+
+/// Palette plus palette-entry → tube table.
+///
+/// With a capacity set, a bead whose tube is full claims an empty tube (which then takes over
+/// that color) or, failing that, goes to the nearest-color tube with room.
+pub struct TubeRouter {
+    palette: Palette<PALETTE_SIZE>,
+    tubes: [PaletteEntry; MAX_TUBES],
+    // Beads that landed within PURITY_RADIUS of their tube's centroid at the time.
+    within: [u32; MAX_TUBES],
+    // Rack slot of each tube in use.
+    slots: [u8; MAX_TUBES],
+    // Finish of each palette entry and tube; all opaque unless finishes are split.
+    entry_finishes: [BeadFinish; PALETTE_SIZE],
+    tube_finishes: [BeadFinish; MAX_TUBES],
+    split_finishes: bool,
+    used: usize,
+    tube_count: usize,
+    palette_to_tube: [u8; PALETTE_SIZE],
+    merge_margin: u32,
+    capacity: Option<u32>,
+    reject_distance: Option<u32>,
+    strategy: TubeStrategy,
+    settings: ProfileSettings,
+}
+
+impl TubeRouter {
+    /// Route over `tube_count` tubes (at most [`MAX_TUBES`]) with no capacity limit.
+    pub fn new(tube_count: usize) -> Self {
+        Self::with_metric(tube_count, ColorMetric::Lab)
+    }
+
+    /// [`TubeRouter::new`] with the palette comparing colors under `metric`.
+    pub fn with_metric(tube_count: usize, metric: ColorMetric) -> Self {
+        Self {
+            palette: Palette::with_metric(metric),
+            tubes: [PaletteEntry::new(BLACK, 0); MAX_TUBES],
+            within: [0; MAX_TUBES],
+            slots: [0; MAX_TUBES],
+            entry_finishes: [BeadFinish::Opaque; PALETTE_SIZE],
+            tube_finishes: [BeadFinish::Opaque; MAX_TUBES],
+            split_finishes: false,
+            used: 0,
+            tube_count: tube_count.min(MAX_TUBES),
+            palette_to_tube: [UNASSIGNED; PALETTE_SIZE],
+            merge_margin: DEFAULT_TUBE_MERGE_MARGIN,
+            capacity: None,
+            reject_distance: None,
+            strategy: TubeStrategy::FirstCome,
+            settings: Profile::Learning.settings(),
+        }
+    }
+
+    /// Switch palette mode, threshold and merge margin to `profile`'s. The palette and tube
+    /// assignments learned so far are kept.
+    ///
+    /// A profile that freezes the palette would route nothing while the palette is empty, so
+    /// an empty palette keeps learning until the profile is set again or a palette is
+    /// restored.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.settings = profile.settings();
+        self.merge_margin = self.settings.tube_merge_margin;
+        self.apply_palette_mode();
+    }
+
+    // The profile's palette mode, unless the palette is empty (see `set_profile`).
+    fn apply_palette_mode(&mut self) {
+        let mode = match self.palette.len() {
+            0 => PaletteMode::Learning,
+            _ => self.settings.palette_mode,
+        };
+        self.palette.set_mode(mode);
+    }
+
+    /// Override the profile's palette match threshold (squared Lab) until the next
+    /// [`TubeRouter::set_profile`].
+    pub fn set_match_threshold(&mut self, threshold: u32) {
+        self.settings.match_threshold = threshold;
+    }
+
+    /// Set the similarity guard margin; 0 disables it.
+    pub fn set_merge_margin(&mut self, margin: u32) {
+        self.merge_margin = margin;
+    }
+
+    /// Beads a tube holds before spilling over; `None` for unlimited.
+    pub fn set_capacity(&mut self, capacity: Option<u32>) {
+        self.capacity = capacity;
+    }
+
+    /// Beads farther than this (squared Lab) from every palette entry are not routed, whether
+    /// the palette is learning or frozen; `None` to route (or learn) them as usual. The first
+    /// bead into an empty palette is always taken.
+    pub fn set_reject_distance(&mut self, distance: Option<u32>) {
+        self.reject_distance = distance;
+    }
+
+    pub fn set_strategy(&mut self, strategy: TubeStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Keep beads of each [`BeadFinish`] in palette entries and tubes of their own. Off, the
+    /// finish is ignored. Entries and tubes learned while off count as opaque.
+    pub fn set_split_finishes(&mut self, split: bool) {
+        self.split_finishes = split;
+    }
+
+    /// Change how many tubes new palette entries may claim (at most [`MAX_TUBES`]). A tube in
+    /// use whose slot is past the new count moves to the free slot nearest it; once none is
+    /// free, it is merged into the tube nearest its color, and its palette entries route there.
+    pub fn set_tube_count(&mut self, tube_count: usize) {
+        self.tube_count = tube_count.min(MAX_TUBES);
+        let mut t = 0;
+        while t < self.used {
+            let slot = self.slots[t];
+            if (slot as usize) < self.tube_count {
+                t += 1;
+            } else if let Some(free) = self.free_slot(slot as usize) {
+                self.slots[t] = free;
+                t += 1;
+            } else {
+                self.merge_away(t);
+            }
+        }
+    }
+
+    // Fold tube `t` into the tube nearest its color whose slot is below the tube count (or
+    // drop it, unrouted, if there is none), and close the gap it leaves in the tube order.
+    fn merge_away(&mut self, t: usize) {
+        let color = self.tubes[t].avg().0;
+        let (slots, tube_count) = (self.slots, self.tube_count);
+        let into = self
+            .nearest_tube(&color, |u| {
+                u as usize != t && (slots[u as usize] as usize) < tube_count
+            })
+            .map(|(u, _)| u as usize);
+        if let Some(u) = into {
+            let from = self.tubes[t];
+            let to = &mut self.tubes[u];
+            to.sum_r += from.sum_r;
+            to.sum_g += from.sum_g;
+            to.sum_b += from.sum_b;
+            to.sum_var += from.sum_var;
+            to.count += from.count;
+            self.within[u] += self.within[t];
+        }
+        let used = self.used;
+        self.tubes.copy_within(t + 1..used, t);
+        self.within.copy_within(t + 1..used, t);
+        self.slots.copy_within(t + 1..used, t);
+        self.tube_finishes.copy_within(t + 1..used, t);
+        self.used -= 1;
+        let renumber = |u: usize| (if u > t { u - 1 } else { u }) as u8;
+        for tube in &mut self.palette_to_tube {
+            *tube = match *tube {
+                UNASSIGNED => UNASSIGNED,
+                old if old as usize == t => into.map_or(UNASSIGNED, renumber),
+                old => renumber(old as usize),
+            };
+        }
+    }
+
+    /// Tubes in use, in tube order.
+    pub fn tubes(&self) -> &[PaletteEntry] {
+        &self.tubes[..self.used]
+    }
+
+    /// The rack slot of `tube`. Tubes in use always have one below the tube count; tubes not in
+    /// use are their own slot.
+    pub fn slot(&self, tube: u8) -> u8 {
+        self.slots[..self.used]
+            .get(tube as usize)
+            .copied()
+            .unwrap_or(tube)
+    }
+
+    /// Online purity estimate for `tube`: percentage of its beads that were within
+    /// [`PURITY_RADIUS`] of the tube's centroid when they arrived. `None` for an unused tube.
+    pub fn purity(&self, tube: u8) -> Option<u8> {
+        let count = self.tubes().get(tube as usize)?.count;
+        Some((self.within[tube as usize] * 100 / count.max(1)) as u8)
+    }
+
+    pub fn palette(&self) -> &Palette<PALETTE_SIZE> {
+        &self.palette
+    }
+
+    /// The tube palette entry `palette_index` routes to, if it has one yet.
+    pub fn tube_of(&self, palette_index: usize) -> Option<u8> {
+        match *self.palette_to_tube.get(palette_index)? {
+            UNASSIGNED => None,
+            tube => Some(tube),
+        }
+    }
+
+    /// Start over from known colors: each becomes a palette entry with a tube of its own, in
+    /// order, until the palette or the tubes run out. A tube starts with its color as one
+    /// sample. Returns how many colors were taken.
+    ///
+    /// ```
+    /// use sorter_logic::Rgb;
+    /// use sorter_logic::router::{RouteReason, TubeRouter};
+    ///
+    /// let red = Rgb { r: 200, g: 20, b: 30 };
+    /// let blue = Rgb { r: 30, g: 60, b: 200 };
+    /// let mut router = TubeRouter::new(30);
+    /// assert_eq!(router.seed_tubes(&[red, blue]), 2);
+    /// let route = router.route(Rgb { r: 34, g: 60, b: 196 }, 0).unwrap();
+    /// assert_eq!((route.tube, route.reason), (1, RouteReason::Mapped));
+    /// ```
+    pub fn seed_tubes(&mut self, colors: &[Rgb]) -> usize {
+        self.palette = Palette::with_metric(self.palette.metric());
+        self.palette_to_tube = [UNASSIGNED; PALETTE_SIZE];
+        self.entry_finishes = [BeadFinish::Opaque; PALETTE_SIZE];
+        self.used = 0;
+        for &color in colors.iter().take(self.tube_count) {
+            let Some(index) = self.palette.seed_entry(PaletteEntry::new(color, 0)) else {
+                break;
+            };
+            self.palette_to_tube[index] = self
+                .claim(color, 0, BeadFinish::Opaque)
+                .unwrap_or(UNASSIGNED);
+        }
+        self.apply_palette_mode();
+        self.used
+    }
+
+    /// Pick a tube for an opaque bead and count it there. `None` if the palette is full,
+    /// every tube is at capacity, or the profile or reject distance rejects the bead.
+    pub fn route(&mut self, color: Rgb, variance: u32) -> Option<Route> {
+        self.route_finish(color, variance, BeadFinish::Opaque)
+    }
+
+    /// [`TubeRouter::route`] for a bead of any finish. With finishes split, the bead only
+    /// matches palette entries and merges into tubes of its own finish; when no tube is left
+    /// for a new entry, or the palette is not learning and has no entry of that finish, it
+    /// goes by color alone.
+    pub fn route_finish(&mut self, color: Rgb, variance: u32, finish: BeadFinish) -> Option<Route> {
+        let finish = if self.split_finishes {
+            finish
+        } else {
+            BeadFinish::Opaque
+        };
+        let finishes = &self.entry_finishes;
+        let same_finish = |i: usize| finishes[i] == finish;
+        let threshold = self.settings.match_threshold;
+        let nearest = self
+            .palette
+            .nearest_where(&color, same_finish)
+            .or_else(|| self.palette.nearest(&color));
+        if nearest.is_some_and(|(_, d)| self.reject_distance.is_some_and(|r| d >= r)) {
+            return None;
+        }
+        let palette_index = if !self.palette.is_frozen() {
+            match self
+                .palette
+                .match_color_where(&color, variance, threshold, same_finish)
+            {
+                PaletteMatch::Match(i) => i,
+                PaletteMatch::NewEntry(i) => {
+                    self.entry_finishes[i] = finish;
+                    i
+                }
+                PaletteMatch::Full => return None,
+            }
+        } else {
+            match nearest? {
+                (_, d) if self.settings.reject_unmatched && d >= threshold => return None,
+                (i, _) => i,
+            }
+        };
+        self.palette.add_sample(palette_index, &color, variance);
+
+        let (tube, reason) = match self.palette_to_tube[palette_index] {
+            UNASSIGNED => {
+                let nearest =
+                    self.nearest_tube(&color, |t| self.tube_finishes[t as usize] == finish);
+                match nearest {
+                    Some((t, d)) if d < self.merge_margin => (t, RouteReason::Merged(d)),
+                    _ if self.strategy != TubeStrategy::Fixed && self.used < self.tube_count => {
+                        let tube = self.claim(color, variance, finish)?;
+                        self.palette_to_tube[palette_index] = tube;
+                        return Some(Route {
+                            tube,
+                            palette_index,
+                            reason: RouteReason::NewTube,
+                        });
+                    }
+                    _ => match nearest.or_else(|| self.nearest_tube(&color, |_| true)) {
+                        Some((t, _)) => (t, RouteReason::NoFreeTube),
+                        None => return None,
+                    },
+                }
+            }
+            t => (t, RouteReason::Mapped),
+        };
+        self.palette_to_tube[palette_index] = tube;
+
+        if self.is_full(tube) {
+            let from = tube;
+            let claims = matches!(
+                self.strategy,
+                TubeStrategy::FirstCome | TubeStrategy::HueOrdered
+            );
+            if claims && self.used < self.tube_count {
+                // The empty tube takes over this color from here on.
+                let tube = self.claim(color, variance, finish)?;
+                self.palette_to_tube[palette_index] = tube;
+                return Some(Route {
+                    tube,
+                    palette_index,
+                    reason: RouteReason::Spillover { from },
+                });
+            }
+            let (tube, _) = self.nearest_tube(&color, |t| !self.is_full(t))?;
+            self.add(tube, color, variance);
+            return Some(Route {
+                tube,
+                palette_index,
+                reason: RouteReason::Spillover { from },
+            });
+        }
+
+        self.add(tube, color, variance);
+        Some(Route {
+            tube,
+            palette_index,
+            reason,
+        })
+    }
+
+    fn is_full(&self, tube: u8) -> bool {
+        self.capacity
+            .is_some_and(|cap| self.tubes[tube as usize].count >= cap)
+    }
+
+    fn add(&mut self, tube: u8, color: Rgb, variance: u32) {
+        let t = tube as usize;
+        if color.dist_lab(&self.tubes[t].avg().0) <= PURITY_RADIUS {
+            self.within[t] += 1;
+        }
+        self.tubes[t].add(color, variance);
+    }
+
+    // Start a new tube holding this bead, in the free slot the strategy prefers. Every tube in
+    // use has a slot below the tube count, so one is free while fewer tubes are in use than
+    // that; `None` otherwise.
+    fn claim(&mut self, color: Rgb, variance: u32, finish: BeadFinish) -> Option<u8> {
+        let preferred = match self.strategy {
+            TubeStrategy::HueOrdered => rainbow_slot(&color, self.tube_count),
+            _ => 0,
+        };
+        self.slots[self.used] = self.free_slot(preferred)?;
+        self.tubes[self.used] = PaletteEntry::new(color, variance);
+        self.tube_finishes[self.used] = finish;
+        self.within[self.used] = 1;
+        self.used += 1;
+        Some((self.used - 1) as u8)
+    }
+
+    // The free slot below the tube count closest to `preferred`, the lower on a tie.
+    fn free_slot(&self, preferred: usize) -> Option<u8> {
+        let taken = &self.slots[..self.used];
+        (0..self.tube_count as u8)
+            .filter(|s| !taken.contains(s))
+            .min_by_key(|&s| ((s as usize).abs_diff(preferred), s))
+    }
+
+    // Closest tube in use (squared Lab) among those `eligible` accepts.
+    fn nearest_tube(&self, color: &Rgb, eligible: impl Fn(u8) -> bool) -> Option<(u8, u32)> {
+        self.tubes()
+            .iter()
+            .enumerate()
+            .map(|(t, entry)| (t as u8, color.dist_lab(&entry.avg().0)))
+            .filter(|&(t, _)| eligible(t))
+            .min_by_key(|&(_, d)| d)
+    }
+
+    /// Write the learned state: magic `ROUT`, version, palette length, tubes used, a reserved
+    /// byte, the palette entries, each entry's tube (`0xFF` for none), the tubes and their
+    /// purity counts, the tubes' slots, the entries' and then the tubes' [`BeadFinish::id`],
+    /// then a CRC-16 of everything before it. Sums and counts are little endian. Returns the
+    /// length.
+    ///
+    /// ```
+    /// use sorter_logic::Rgb;
+    /// use sorter_logic::router::{ROUTER_STATE_MAX, RouteReason, TubeRouter};
+    ///
+    /// let red = Rgb { r: 200, g: 20, b: 30 };
+    /// let blue = Rgb { r: 30, g: 60, b: 200 };
+    /// let mut router = TubeRouter::new(30);
+    /// router.route(red, 0);
+    /// router.route(blue, 0);
+    ///
+    /// let mut state = [0u8; ROUTER_STATE_MAX];
+    /// let len = router.encode_state(&mut state);
+    /// let mut rebooted = TubeRouter::new(30);
+    /// rebooted.restore_state(&state[..len]).unwrap();
+    /// let route = rebooted.route(blue, 0).unwrap();
+    /// assert_eq!((route.tube, route.reason), (1, RouteReason::Mapped));
+    /// ```
+    pub fn encode_state(&self, out: &mut [u8; ROUTER_STATE_MAX]) -> usize {
+        let palette_len = self.palette.len();
+        out[..4].copy_from_slice(&STATE_MAGIC);
+        out[4] = STATE_VERSION;
+        out[5] = palette_len as u8;
+        out[6] = self.used as u8;
+        out[7] = 0;
+        let mut at = STATE_HEADER;
+        for i in 0..palette_len {
+            let entry = self
+                .palette
+                .get_entry(i)
+                .unwrap_or(PaletteEntry::new(BLACK, 0));
+            write_entry(&mut out[at..at + ENTRY_BYTES], &entry);
+            at += ENTRY_BYTES;
+        }
+        out[at..at + palette_len].copy_from_slice(&self.palette_to_tube[..palette_len]);
+        at += palette_len;
+        for (entry, within) in self.tubes().iter().zip(&self.within) {
+            write_entry(&mut out[at..at + ENTRY_BYTES], entry);
+            out[at + ENTRY_BYTES..at + ENTRY_BYTES + 4].copy_from_slice(&within.to_le_bytes());
+            at += ENTRY_BYTES + 4;
+        }
+        out[at..at + self.used].copy_from_slice(&self.slots[..self.used]);
+        at += self.used;
+        let finishes = self.entry_finishes[..palette_len]
+            .iter()
+            .chain(&self.tube_finishes[..self.used]);
+        for finish in finishes {
+            out[at] = finish.id();
+            at += 1;
+        }
+        let crc = crc16(&out[..at]);
+        out[at..at + 2].copy_from_slice(&crc.to_le_bytes());
+        at + 2
+    }
+
+    /// Replace the palette, tube table and tubes with state from
+    /// [`TubeRouter::encode_state`]. The profile, capacity and merge margin are kept. State
+    /// saved before tubes had slots puts every tube in the slot of its number, and state saved
+    /// before finishes makes every entry and tube opaque. Nothing changes on error.
+    pub fn restore_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let header = bytes.get(..STATE_HEADER).ok_or(StateError::BadHeader)?;
+        if header[..4] != STATE_MAGIC {
+            return Err(StateError::BadHeader);
+        }
+        let (has_slots, has_finishes) = match header[4] {
+            STATE_VERSION => (true, true),
+            STATE_VERSION_NO_FINISHES => (true, false),
+            STATE_VERSION_NO_SLOTS => (false, false),
+            _ => return Err(StateError::BadHeader),
+        };
+        let (palette_len, used) = (header[5] as usize, header[6] as usize);
+        let slot_bytes = if has_slots { used } else { 0 };
+        let finish_bytes = if has_finishes { palette_len + used } else { 0 };
+        let end = STATE_HEADER
+            + palette_len * (ENTRY_BYTES + 1)
+            + used * (ENTRY_BYTES + 4)
+            + slot_bytes
+            + finish_bytes;
+        let record = bytes.get(..end + 2).ok_or(StateError::BadHeader)?;
+        if crc16(&record[..end]).to_le_bytes() != record[end..] {
+            return Err(StateError::BadCrc);
+        }
+        if palette_len > PALETTE_SIZE || used > self.tube_count {
+            return Err(StateError::DoesNotFit);
+        }
+        let (entries, rest) = record[STATE_HEADER..end].split_at(palette_len * ENTRY_BYTES);
+        let (table, rest) = rest.split_at(palette_len);
+        let (tubes, rest) = rest.split_at(used * (ENTRY_BYTES + 4));
+        let (slots, finishes) = rest.split_at(slot_bytes);
+        let mut entry_finishes = [BeadFinish::Opaque; PALETTE_SIZE];
+        let mut tube_finishes = [BeadFinish::Opaque; MAX_TUBES];
+        let all = entry_finishes[..palette_len]
+            .iter_mut()
+            .chain(&mut tube_finishes[..used]);
+        for (finish, &id) in all.zip(finishes) {
+            *finish = BeadFinish::from_id(id).ok_or(StateError::BadHeader)?;
+        }
+        if table.iter().any(|&t| t != UNASSIGNED && t as usize >= used) {
+            return Err(StateError::DoesNotFit);
+        }
+        let mut restored = [0u8; MAX_TUBES];
+        for (t, slot) in restored[..used].iter_mut().enumerate() {
+            *slot = if has_slots { slots[t] } else { t as u8 };
+        }
+        for (t, &slot) in restored[..used].iter().enumerate() {
+            if slot as usize >= self.tube_count || restored[..t].contains(&slot) {
+                return Err(StateError::DoesNotFit);
+            }
+        }
+
+        self.palette = Palette::with_metric(self.palette.metric());
+        for chunk in entries.chunks_exact(ENTRY_BYTES) {
+            self.palette.seed_entry(read_entry(chunk));
+        }
+        self.palette_to_tube = [UNASSIGNED; PALETTE_SIZE];
+        self.palette_to_tube[..palette_len].copy_from_slice(table);
+        self.within = [0; MAX_TUBES];
+        for (t, chunk) in tubes.chunks_exact(ENTRY_BYTES + 4).enumerate() {
+            self.tubes[t] = read_entry(&chunk[..ENTRY_BYTES]);
+            self.within[t] = u32::from_le_bytes(chunk[ENTRY_BYTES..].try_into().unwrap());
+        }
+        self.slots = restored;
+        self.entry_finishes = entry_finishes;
+        self.tube_finishes = tube_finishes;
+        self.used = used;
+        self.apply_palette_mode();
+        Ok(())
+    }
+}
+
+const BLACK: Rgb = Rgb { r: 0, g: 0, b: 0 };
+
+fn write_entry(out: &mut [u8], entry: &PaletteEntry) {
+    out[0..4].copy_from_slice(&entry.sum_r.to_le_bytes());
+    out[4..8].copy_from_slice(&entry.sum_g.to_le_bytes());
+    out[8..12].copy_from_slice(&entry.sum_b.to_le_bytes());
+    out[12..20].copy_from_slice(&entry.sum_var.to_le_bytes());
+    out[20..24].copy_from_slice(&entry.count.to_le_bytes());
+}
+
+fn read_entry(bytes: &[u8]) -> PaletteEntry {
+    let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+    PaletteEntry {
+        sum_r: u32_at(0),
+        sum_g: u32_at(4),
+        sum_b: u32_at(8),
+        sum_var: u64::from_le_bytes(bytes[12..20].try_into().unwrap()),
+        count: u32_at(20),
+    }
+}
+
+// The slot `color` would have in a rainbow over `slots` tubes: its hue band's share of the
+// slots, and its place within the band by hue, or for the grays by lightness.
+fn rainbow_slot(color: &Rgb, slots: usize) -> usize {
+    let (hue, saturation, value) = color.to_hsv();
+    let (band, within) = if saturation < GRAY_SATURATION || value < GRAY_VALUE {
+        (HUE_BANDS - 1, value as usize)
+    } else {
+        (hue as usize / 30, hue as usize % 30 * 256 / 30)
+    };
+    (band * 256 + within) * slots / (HUE_BANDS * 256)
+}
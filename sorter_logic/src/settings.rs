@@ -0,0 +1,619 @@
+//! Machine settings kept in flash next to the [tube layout](crate::layout): servo endpoints,
+//! hopper stops, analysis thresholds, the palette match threshold, the stall limit, the
+//! reject tube, how many photos to take of each bead, where to park while paused, how hard
+//! the hopper accelerates while agitating, when a run pauses itself, how low the supply
+//! may sag before the sorter eases off, how much servo current means a stall, how bright the
+//! camera LED keeps the background, whether each photo fuses two exposures, how tubes are
+//! handed out, how long the machine idles before it sleeps, how close a new color may be to
+//! an existing tube before it shares it, whether bead finishes get tubes of their own, how
+//! many beads a tube holds, and the size of the ring of pixels the analysis reads.
+//!
+//! Every setting is a `u16` addressed by a [`Setting`] id, so the host can read them all and
+//! change one at a time over the USB command protocol, either saving the change or only
+//! trying it out until the next save or reboot. Chute positions are part of the tube layout,
+//! not of these settings.
+//!
+//! ```
+//! use sorter_logic::settings::{Setting, Settings};
+//!
+//! let mut settings = Settings::default();
+//! settings.set(Setting::EdgeThreshold, 55).unwrap();
+//! let stored = settings.to_bytes();
+//! assert_eq!(Settings::from_bytes(&stored), Ok(settings));
+//! assert_eq!(settings.analysis_config().edge_threshold, 55);
+//! ```
+
+use sorter_protocol::{SETTINGS_MAGIC, crc16};
+
+use crate::exposure::DEFAULT_TARGET_LUMA;
+use crate::hopper::DEFAULT_STALL_AFTER;
+use crate::layout::MAX_TUBES;
+use crate::router::TubeStrategy;
+use crate::supply::DEFAULT_BROWNOUT_MV;
+use crate::{AnalysisConfig, MAX_RING_OUTER};
+
+/// [`Setting::RejectTube`] value for no reject tube.
+pub const NO_REJECT_TUBE: u16 = 0xFFFF;
+/// [`Setting::TubeMergeMargin`] value that keeps the profile's margin.
+pub const PROFILE_MERGE_MARGIN: u16 = 0xFFFF;
+/// Largest [`Setting::Retakes`].
+pub const MAX_RETAKES: u16 = 3;
+/// Largest [`Setting::CameraFrames`].
+pub const MAX_CAMERA_FRAMES: u16 = 3;
+
+const RECORD_MAGIC: [u8; 4] = *b"SETS";
+const RECORD_VERSION: u8 = 1;
+/// Size of [`Settings::to_bytes`]: magic, version, count, the values, CRC-16.
+pub const SETTINGS_BYTES: usize = 6 + Setting::ALL.len() * 2 + 2;
+/// Size of [`Settings::encode`]: magic, count, the values.
+pub const SETTINGS_PACKET_MAX: usize = 5 + Setting::ALL.len() * 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// Hopper servo pulse range, in microseconds.
+    HopperMin,
+    HopperMax,
+    /// Hopper stops, in microseconds.
+    HopperPickup,
+    HopperCamera,
+    HopperDrop,
+    /// Chutes servo pulse range, in microseconds.
+    ChutesMin,
+    ChutesMax,
+    /// [`AnalysisConfig::edge_threshold`].
+    EdgeThreshold,
+    /// [`AnalysisConfig::min_pixel_count`].
+    MinPixelCount,
+    /// [`AnalysisConfig::background_min_contrast`].
+    BackgroundMinContrast,
+    /// Smallest [`crate::EmptyResult::confidence`] that counts as an empty slot.
+    EmptyConfidence,
+    /// Palette match threshold (squared Lab); 0 keeps the profile's.
+    MatchThreshold,
+    /// [`PickupMonitor::set_stall_after`](crate::hopper::PickupMonitor::set_stall_after).
+    StallAfter,
+    /// Tube for beads that are not routed, [`NO_REJECT_TUBE`] for none (they go to tube 0).
+    RejectTube,
+    /// [`TubeRouter::set_reject_distance`](crate::router::TubeRouter::set_reject_distance);
+    /// 0 for none. Needs a [`Setting::RejectTube`].
+    RejectDistance,
+    /// Beads whose [`BeadAnalysis::confidence`](crate::BeadAnalysis::confidence) is below
+    /// this percentage are rejected as unreliable; 0 to keep them all. Needs a
+    /// [`Setting::RejectTube`].
+    RejectConfidence,
+    /// Extra photos of a doubtful bead, up to [`MAX_RETAKES`]. A bead is doubtful when its
+    /// analysis fails or its variance is above [`Setting::RetakeVariance`].
+    Retakes,
+    /// Variance above which a bead is photographed again; 0 to retake only failed analyses.
+    RetakeVariance,
+    /// Frames captured back to back at the camera stop and fused, 1 to
+    /// [`MAX_CAMERA_FRAMES`].
+    CameraFrames,
+    /// [`AnalysisConfig::filter_percent`], 1 to 100.
+    FilterPercent,
+    /// [`AnalysisConfig::max_variance`]; 0 for none.
+    MaxVariance,
+    /// [`AnalysisConfig::mad_k`] in tenths; 0 to use `filter_percent` instead.
+    MadK,
+    /// [`AnalysisConfig::max_eccentricity`] in thousandths, up to 1000; 0 for none.
+    MaxEccentricity,
+    /// [`AnalysisConfig::warm_start_radius`], in pixels.
+    WarmStartRadius,
+    /// [`AnalysisConfig::pixel_budget`]; 0 reads every ring pixel.
+    PixelBudget,
+    /// Hopper stop while paused, in microseconds.
+    HopperPark,
+    /// 1 to stop the servo pulses while paused so the servos go limp, 0 to hold them.
+    RelaxOnPause,
+    /// Acceleration of the hopper's agitation moves, in hundreds of microseconds per second
+    /// squared; at least 1.
+    AgitationAccel,
+    /// Beads sorted before a run pauses itself; 0 for no limit.
+    RunLimitBeads,
+    /// Minutes before a run pauses itself; 0 for no limit.
+    RunLimitMinutes,
+    /// Supply voltage, in millivolts, below which the servos slow down and a warning is
+    /// logged; 0 for none.
+    BrownoutMv,
+    /// Current-sense voltage, in millivolts, that means a servo has stalled if it lasts
+    /// after a move; 0 when no sense resistor is fitted.
+    StallSenseMv,
+    /// Background patch luma (0..=255) the camera LED brightness is steered to; 0 for a
+    /// fixed 50% duty.
+    LedTargetLuma,
+    /// 1 to fuse a short and a long exposure into each photo ([`crate::hdr`]), 0 for one
+    /// exposure.
+    HdrCapture,
+    /// [`TubeStrategy::id`] of how the router hands out tubes.
+    TubeStrategy,
+    /// Minutes without a bead, a button press or a host command before the machine stops
+    /// sorting and sleeps; 0 to stay awake.
+    IdleSleepMinutes,
+    /// [`TubeRouter::set_merge_margin`](crate::router::TubeRouter::set_merge_margin); 0 turns
+    /// the guard off, [`PROFILE_MERGE_MARGIN`] keeps the profile's.
+    TubeMergeMargin,
+    /// 1 to give each [`BeadFinish`](crate::BeadFinish) its own palette entries and tubes
+    /// ([`TubeRouter::set_split_finishes`](crate::router::TubeRouter::set_split_finishes)),
+    /// 0 to sort by color alone.
+    SplitFinishes,
+    /// Beads a tube holds before new beads of its color spill over to another tube
+    /// ([`TubeRouter::set_capacity`](crate::router::TubeRouter::set_capacity)); 0 for no
+    /// limit.
+    TubeCapacity,
+    /// [`AnalysisConfig::ring_inner`], in pixels; under `RingOuter`.
+    RingInner,
+    /// [`AnalysisConfig::ring_outer`], in pixels, up to [`MAX_RING_OUTER`].
+    RingOuter,
+}
+
+impl Setting {
+    /// In id order. New settings go at the end so stored records stay readable.
+    pub const ALL: [Setting; 41] = [
+        Setting::HopperMin,
+        Setting::HopperMax,
+        Setting::HopperPickup,
+        Setting::HopperCamera,
+        Setting::HopperDrop,
+        Setting::ChutesMin,
+        Setting::ChutesMax,
+        Setting::EdgeThreshold,
+        Setting::MinPixelCount,
+        Setting::BackgroundMinContrast,
+        Setting::EmptyConfidence,
+        Setting::MatchThreshold,
+        Setting::StallAfter,
+        Setting::RejectTube,
+        Setting::RejectDistance,
+        Setting::RejectConfidence,
+        Setting::Retakes,
+        Setting::RetakeVariance,
+        Setting::CameraFrames,
+        Setting::FilterPercent,
+        Setting::MaxVariance,
+        Setting::MadK,
+        Setting::MaxEccentricity,
+        Setting::WarmStartRadius,
+        Setting::PixelBudget,
+        Setting::HopperPark,
+        Setting::RelaxOnPause,
+        Setting::AgitationAccel,
+        Setting::RunLimitBeads,
+        Setting::RunLimitMinutes,
+        Setting::BrownoutMv,
+        Setting::StallSenseMv,
+        Setting::LedTargetLuma,
+        Setting::HdrCapture,
+        Setting::TubeStrategy,
+        Setting::IdleSleepMinutes,
+        Setting::TubeMergeMargin,
+        Setting::SplitFinishes,
+        Setting::TubeCapacity,
+        Setting::RingInner,
+        Setting::RingOuter,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Setting::HopperMin => "hopper_min",
+            Setting::HopperMax => "hopper_max",
+            Setting::HopperPickup => "hopper_pickup",
+            Setting::HopperCamera => "hopper_camera",
+            Setting::HopperDrop => "hopper_drop",
+            Setting::ChutesMin => "chutes_min",
+            Setting::ChutesMax => "chutes_max",
+            Setting::EdgeThreshold => "edge_threshold",
+            Setting::MinPixelCount => "min_pixel_count",
+            Setting::BackgroundMinContrast => "background_min_contrast",
+            Setting::EmptyConfidence => "empty_confidence",
+            Setting::MatchThreshold => "match_threshold",
+            Setting::StallAfter => "stall_after",
+            Setting::RejectTube => "reject_tube",
+            Setting::RejectDistance => "reject_distance",
+            Setting::RejectConfidence => "reject_confidence",
+            Setting::Retakes => "retakes",
+            Setting::RetakeVariance => "retake_variance",
+            Setting::CameraFrames => "camera_frames",
+            Setting::FilterPercent => "filter_percent",
+            Setting::MaxVariance => "max_variance",
+            Setting::MadK => "mad_k",
+            Setting::MaxEccentricity => "max_eccentricity",
+            Setting::WarmStartRadius => "warm_start_radius",
+            Setting::PixelBudget => "pixel_budget",
+            Setting::HopperPark => "hopper_park",
+            Setting::RelaxOnPause => "relax_on_pause",
+            Setting::AgitationAccel => "agitation_accel",
+            Setting::RunLimitBeads => "run_limit_beads",
+            Setting::RunLimitMinutes => "run_limit_minutes",
+            Setting::BrownoutMv => "brownout_mv",
+            Setting::StallSenseMv => "stall_sense_mv",
+            Setting::LedTargetLuma => "led_target_luma",
+            Setting::HdrCapture => "hdr_capture",
+            Setting::TubeStrategy => "tube_strategy",
+            Setting::IdleSleepMinutes => "idle_sleep_minutes",
+            Setting::TubeMergeMargin => "tube_merge_margin",
+            Setting::SplitFinishes => "split_finishes",
+            Setting::TubeCapacity => "tube_capacity",
+            Setting::RingInner => "ring_inner",
+            Setting::RingOuter => "ring_outer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.name() == name)
+    }
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+}
+
+/// When a run pauses itself: after [`Setting::RunLimitBeads`] beads or
+/// [`Setting::RunLimitMinutes`] minutes, whichever comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RunLimit {
+    pub beads: Option<u16>,
+    pub minutes: Option<u16>,
+}
+
+impl RunLimit {
+    /// True once a run that has sorted `beads` in `elapsed_ms` should stop.
+    pub fn reached(&self, beads: u32, elapsed_ms: u64) -> bool {
+        self.beads.is_some_and(|limit| beads >= limit as u32)
+            || self
+                .minutes
+                .is_some_and(|limit| elapsed_ms >= limit as u64 * 60_000)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingsError {
+    /// Stored bytes are not a settings record (blank flash, or an older format).
+    BadHeader,
+    /// The record is damaged.
+    BadCrc,
+    /// The value is out of range or inconsistent with another setting.
+    Invalid(Setting),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    values: [u16; Setting::ALL.len()],
+}
+
+impl Default for Settings {
+    /// The original build's servo positions and [`AnalysisConfig::default`]'s thresholds.
+    fn default() -> Self {
+        let analysis = AnalysisConfig::default();
+        Self {
+            values: [
+                500,
+                2266,
+                760,
+                1493,
+                1613,
+                500,
+                1167,
+                analysis.edge_threshold as u16,
+                analysis.min_pixel_count as u16,
+                analysis.background_min_contrast as u16,
+                50,
+                0,
+                DEFAULT_STALL_AFTER,
+                NO_REJECT_TUBE,
+                0,
+                0,
+                1,
+                0,
+                2,
+                analysis.filter_percent as u16,
+                analysis.max_variance.unwrap_or(0) as u16,
+                analysis.mad_k.map_or(0, |k| (k * 10.0) as u16),
+                analysis.max_eccentricity.map_or(0, |e| (e * 1000.0) as u16),
+                analysis.warm_start_radius as u16,
+                analysis.pixel_budget.unwrap_or(0),
+                1613,
+                0,
+                300,
+                0,
+                0,
+                DEFAULT_BROWNOUT_MV,
+                0,
+                DEFAULT_TARGET_LUMA,
+                0,
+                TubeStrategy::FirstCome.id() as u16,
+                0,
+                PROFILE_MERGE_MARGIN,
+                0,
+                0,
+                analysis.ring_inner as u16,
+                analysis.ring_outer as u16,
+            ],
+        }
+    }
+}
+
+impl Settings {
+    pub fn get(&self, setting: Setting) -> u16 {
+        self.values[setting as usize]
+    }
+
+    /// Change one setting. Nothing changes if the result would not [`validate`](Self::validate).
+    pub fn set(&mut self, setting: Setting, value: u16) -> Result<(), SettingsError> {
+        let mut next = *self;
+        next.values[setting as usize] = value;
+        next.validate()?;
+        *self = next;
+        Ok(())
+    }
+
+    /// Servo ranges must be non-empty, the hopper stops inside the hopper range, the
+    /// confidence and filter percentages, the reject tube a tube (and set if beads are
+    /// rejected by distance or confidence, which would otherwise land in tube 0), and the
+    /// retakes, camera frames, eccentricity and ring radii within their limits, [`Setting::RelaxOnPause`],
+    /// [`Setting::HdrCapture`] and [`Setting::SplitFinishes`] 0 or 1,
+    /// [`Setting::AgitationAccel`] not 0,
+    /// [`Setting::LedTargetLuma`] a luma, and [`Setting::TubeStrategy`] a strategy.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        use Setting::*;
+        let v = |s: Setting| self.get(s);
+        if v(HopperMin) >= v(HopperMax) {
+            return Err(SettingsError::Invalid(HopperMax));
+        }
+        if v(ChutesMin) >= v(ChutesMax) {
+            return Err(SettingsError::Invalid(ChutesMax));
+        }
+        for stop in [HopperPickup, HopperCamera, HopperDrop, HopperPark] {
+            if !(v(HopperMin)..=v(HopperMax)).contains(&v(stop)) {
+                return Err(SettingsError::Invalid(stop));
+            }
+        }
+        for confidence in [EmptyConfidence, RejectConfidence] {
+            if v(confidence) > 100 {
+                return Err(SettingsError::Invalid(confidence));
+            }
+        }
+        if v(RejectTube) != NO_REJECT_TUBE && v(RejectTube) as usize >= MAX_TUBES {
+            return Err(SettingsError::Invalid(RejectTube));
+        }
+        for reject in [RejectDistance, RejectConfidence] {
+            if v(reject) != 0 && v(RejectTube) == NO_REJECT_TUBE {
+                return Err(SettingsError::Invalid(reject));
+            }
+        }
+        if v(Retakes) > MAX_RETAKES {
+            return Err(SettingsError::Invalid(Retakes));
+        }
+        if !(1..=MAX_CAMERA_FRAMES).contains(&v(CameraFrames)) {
+            return Err(SettingsError::Invalid(CameraFrames));
+        }
+        if !(1..=100).contains(&v(FilterPercent)) {
+            return Err(SettingsError::Invalid(FilterPercent));
+        }
+        if v(MaxEccentricity) > 1000 {
+            return Err(SettingsError::Invalid(MaxEccentricity));
+        }
+        if !(1..=MAX_RING_OUTER as u16).contains(&v(RingOuter)) {
+            return Err(SettingsError::Invalid(RingOuter));
+        }
+        if v(RingInner) >= v(RingOuter) {
+            return Err(SettingsError::Invalid(RingInner));
+        }
+        for flag in [RelaxOnPause, HdrCapture, SplitFinishes] {
+            if v(flag) > 1 {
+                return Err(SettingsError::Invalid(flag));
+            }
+        }
+        if v(AgitationAccel) == 0 {
+            return Err(SettingsError::Invalid(AgitationAccel));
+        }
+        if v(LedTargetLuma) > 255 {
+            return Err(SettingsError::Invalid(LedTargetLuma));
+        }
+        let strategy = u8::try_from(v(TubeStrategy)).ok();
+        if strategy
+            .and_then(crate::router::TubeStrategy::from_id)
+            .is_none()
+        {
+            return Err(SettingsError::Invalid(TubeStrategy));
+        }
+        Ok(())
+    }
+
+    pub fn hopper_range(&self) -> (u16, u16) {
+        (self.get(Setting::HopperMin), self.get(Setting::HopperMax))
+    }
+
+    pub fn chutes_range(&self) -> (u16, u16) {
+        (self.get(Setting::ChutesMin), self.get(Setting::ChutesMax))
+    }
+
+    /// [`AnalysisConfig::default`] with the stored thresholds.
+    pub fn analysis_config(&self) -> AnalysisConfig {
+        let nonzero = |s: Setting| Some(self.get(s)).filter(|&v| v != 0);
+        AnalysisConfig {
+            edge_threshold: self.get(Setting::EdgeThreshold) as i32,
+            min_pixel_count: self.get(Setting::MinPixelCount) as u32,
+            background_min_contrast: self.get(Setting::BackgroundMinContrast) as u32,
+            filter_percent: self.get(Setting::FilterPercent) as u8,
+            max_variance: nonzero(Setting::MaxVariance).map(u32::from),
+            mad_k: nonzero(Setting::MadK).map(|k| k as f32 / 10.0),
+            max_eccentricity: nonzero(Setting::MaxEccentricity).map(|e| e as f32 / 1000.0),
+            warm_start_radius: self.get(Setting::WarmStartRadius) as i32,
+            ring_inner: self.get(Setting::RingInner) as i32,
+            ring_outer: self.get(Setting::RingOuter) as i32,
+            pixel_budget: nonzero(Setting::PixelBudget),
+            ..Default::default()
+        }
+    }
+
+    /// [`Setting::AgitationAccel`] in microseconds per second squared.
+    pub fn agitation_accel(&self) -> u32 {
+        self.get(Setting::AgitationAccel) as u32 * 100
+    }
+
+    pub fn run_limit(&self) -> RunLimit {
+        let nonzero = |s: Setting| Some(self.get(s)).filter(|&v| v != 0);
+        RunLimit {
+            beads: nonzero(Setting::RunLimitBeads),
+            minutes: nonzero(Setting::RunLimitMinutes),
+        }
+    }
+
+    /// [`Setting::BrownoutMv`], if set.
+    pub fn brownout_mv(&self) -> Option<u16> {
+        match self.get(Setting::BrownoutMv) {
+            0 => None,
+            mv => Some(mv),
+        }
+    }
+
+    /// [`Setting::StallSenseMv`], if a sense resistor is fitted.
+    pub fn stall_sense_mv(&self) -> Option<u16> {
+        match self.get(Setting::StallSenseMv) {
+            0 => None,
+            mv => Some(mv),
+        }
+    }
+
+    /// [`Setting::LedTargetLuma`], if the LED is steered.
+    pub fn led_target_luma(&self) -> Option<u8> {
+        match self.get(Setting::LedTargetLuma) {
+            0 => None,
+            luma => Some(luma as u8),
+        }
+    }
+
+    /// [`Setting::IdleSleepMinutes`] in milliseconds, if the machine sleeps.
+    pub fn idle_sleep_ms(&self) -> Option<u64> {
+        match self.get(Setting::IdleSleepMinutes) {
+            0 => None,
+            minutes => Some(minutes as u64 * 60_000),
+        }
+    }
+
+    pub fn tube_strategy(&self) -> TubeStrategy {
+        TubeStrategy::from_id(self.get(Setting::TubeStrategy) as u8).unwrap_or_default()
+    }
+
+    pub fn reject_tube(&self) -> Option<u8> {
+        match self.get(Setting::RejectTube) {
+            NO_REJECT_TUBE => None,
+            t => Some(t as u8),
+        }
+    }
+
+    pub fn reject_distance(&self) -> Option<u32> {
+        match self.get(Setting::RejectDistance) {
+            0 => None,
+            d => Some(d as u32),
+        }
+    }
+
+    pub fn reject_confidence(&self) -> Option<u8> {
+        match self.get(Setting::RejectConfidence) {
+            0 => None,
+            c => Some(c as u8),
+        }
+    }
+
+    pub fn retake_variance(&self) -> Option<u32> {
+        match self.get(Setting::RetakeVariance) {
+            0 => None,
+            v => Some(v as u32),
+        }
+    }
+
+    /// The match threshold override, if set.
+    pub fn match_threshold(&self) -> Option<u32> {
+        match self.get(Setting::MatchThreshold) {
+            0 => None,
+            t => Some(t as u32),
+        }
+    }
+
+    pub fn split_finishes(&self) -> bool {
+        self.get(Setting::SplitFinishes) == 1
+    }
+
+    /// [`Setting::TubeCapacity`], if tubes have a limit.
+    pub fn tube_capacity(&self) -> Option<u32> {
+        match self.get(Setting::TubeCapacity) {
+            0 => None,
+            c => Some(c as u32),
+        }
+    }
+
+    /// The tube merge margin override, if set.
+    pub fn tube_merge_margin(&self) -> Option<u32> {
+        match self.get(Setting::TubeMergeMargin) {
+            PROFILE_MERGE_MARGIN => None,
+            m => Some(m as u32),
+        }
+    }
+
+    /// Serialize for storage in flash: magic `SETS`, version, value count, the values as
+    /// `u16` LE in id order, then a CRC-16 of everything before it.
+    pub fn to_bytes(&self) -> [u8; SETTINGS_BYTES] {
+        let mut out = [0u8; SETTINGS_BYTES];
+        out[..4].copy_from_slice(&RECORD_MAGIC);
+        out[4] = RECORD_VERSION;
+        out[5] = self.values.len() as u8;
+        for (chunk, v) in out[6..].chunks_exact_mut(2).zip(&self.values) {
+            chunk.copy_from_slice(&v.to_le_bytes());
+        }
+        let crc = crc16(&out[..SETTINGS_BYTES - 2]);
+        out[SETTINGS_BYTES - 2..].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// Parse and validate a stored record. A record from a build with fewer settings keeps
+    /// the defaults for the newer ones.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SettingsError> {
+        let header = bytes.get(..6).ok_or(SettingsError::BadHeader)?;
+        if header[..4] != RECORD_MAGIC || header[4] != RECORD_VERSION {
+            return Err(SettingsError::BadHeader);
+        }
+        let count = (header[5] as usize).min(Setting::ALL.len());
+        let end = 6 + header[5] as usize * 2;
+        let record = bytes.get(..end + 2).ok_or(SettingsError::BadHeader)?;
+        if crc16(&record[..end]).to_le_bytes() != record[end..] {
+            return Err(SettingsError::BadCrc);
+        }
+        let mut settings = Self::default();
+        for (v, c) in settings.values[..count]
+            .iter_mut()
+            .zip(record[6..end].chunks_exact(2))
+        {
+            *v = u16::from_le_bytes([c[0], c[1]]);
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Write a settings reply packet: [`SETTINGS_MAGIC`], the value count, then the values as
+    /// `u16` LE in id order. Returns the length.
+    pub fn encode(&self, out: &mut [u8; SETTINGS_PACKET_MAX]) -> usize {
+        out[..4].copy_from_slice(&SETTINGS_MAGIC);
+        out[4] = self.values.len() as u8;
+        for (chunk, v) in out[5..].chunks_exact_mut(2).zip(&self.values) {
+            chunk.copy_from_slice(&v.to_le_bytes());
+        }
+        SETTINGS_PACKET_MAX
+    }
+
+    /// Decode the body of a settings reply (everything after the magic). Values for ids this
+    /// build does not know are skipped; returns `None` if the body is truncated.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let (&n, rest) = body.split_first()?;
+        let values = rest.get(..n as usize * 2)?;
+        let mut settings = Self::default();
+        for (v, c) in settings.values.iter_mut().zip(values.chunks_exact(2)) {
+            *v = u16::from_le_bytes([c[0], c[1]]);
+        }
+        Some(settings)
+    }
+}
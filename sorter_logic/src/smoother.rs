@@ -0,0 +1,114 @@
+//! Combine several classifications of the same physical bead into one decision.
+//!
+//! With multi-shot capture each frame is classified on its own; a glint or a bead still
+//! rolling can put one shot in the wrong class. [`ClassSmoother`] collects the shots' votes
+//! and returns the class with the most weight, so the firmware and the simulations make
+//! the same call from the same shots.
+
+/// The smoothed decision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Smoothed {
+    pub class: usize,
+    /// Weight behind `class`.
+    pub weight: u32,
+    /// Weight of all votes held.
+    pub total: u32,
+}
+
+impl Smoothed {
+    /// Share of the vote weight behind the decision, 0..=100.
+    pub fn agreement(&self) -> u8 {
+        (self.weight as u64 * 100 / self.total.max(1) as u64) as u8
+    }
+}
+
+/// The last `K` votes for one bead.
+///
+/// Each vote is a class (a palette index, a tube) and a weight: 1 for a plain majority, or
+/// a confidence so that clear shots count for more than marginal ones. Once `K` votes are
+/// held, a new vote replaces the oldest.
+///
+/// ```
+/// use sorter_logic::smoother::ClassSmoother;
+///
+/// let mut smoother: ClassSmoother<5> = ClassSmoother::new();
+/// for class in [3, 3, 7, 3] {
+///     smoother.push(class, 1);
+/// }
+/// let decision = smoother.decision().unwrap();
+/// assert_eq!(decision.class, 3);
+/// assert_eq!(decision.agreement(), 75);
+/// ```
+pub struct ClassSmoother<const K: usize> {
+    votes: [(usize, u32); K],
+    len: usize,
+    // Where the next vote goes once the buffer is full.
+    next: usize,
+}
+
+impl<const K: usize> Default for ClassSmoother<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const K: usize> ClassSmoother<K> {
+    pub const fn new() -> Self {
+        Self {
+            votes: [(0, 0); K],
+            len: 0,
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, class: usize, weight: u32) {
+        if K == 0 {
+            return;
+        }
+        self.votes[self.next] = (class, weight);
+        self.next = (self.next + 1) % K;
+        self.len = (self.len + 1).min(K);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Forget all votes, ready for the next bead.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.next = 0;
+    }
+
+    /// The class with the most vote weight. On a tie the class voted for most recently
+    /// wins. `None` without votes.
+    pub fn decision(&self) -> Option<Smoothed> {
+        if self.len == 0 {
+            return None;
+        }
+        let total = self.votes[..self.len].iter().map(|&(_, w)| w).sum();
+
+        // Walk from the newest vote back so that, on a tie, the more recent class is kept.
+        let mut best: Option<Smoothed> = None;
+        for age in 0..self.len {
+            let (class, _) = self.votes[(self.next + K - 1 - age) % K];
+            let weight = self.votes[..self.len]
+                .iter()
+                .filter(|&&(c, _)| c == class)
+                .map(|&(_, w)| w)
+                .sum();
+            if best.is_none_or(|b| weight > b.weight) {
+                best = Some(Smoothed {
+                    class,
+                    weight,
+                    total,
+                });
+            }
+        }
+        best
+    }
+}
@@ -0,0 +1,64 @@
+//! Servo stall detection from an optional current-sense resistor in the servo supply.
+//!
+//! A servo draws a burst of current while it moves and little once it gets where it was
+//! sent. One that keeps drawing after its move has ended is pushing against something, such
+//! as a bead wedged in the hopper slot, and will strip its gears if left to grind.
+//!
+//! ```
+//! use sorter_logic::stall::{STALL_SAMPLES, StallDetector};
+//!
+//! let mut stall = StallDetector::new(Some(400));
+//! // High current mid-move is expected.
+//! assert!(!stall.record(900, true));
+//! for _ in 1..STALL_SAMPLES {
+//!     assert!(!stall.record(600, false));
+//! }
+//! assert!(stall.record(600, false));
+//! ```
+
+/// Readings in a row at or above the threshold, with no servo moving, that make a stall.
+pub const STALL_SAMPLES: u16 = 20;
+
+/// Millivolts at the sense pin for a 12-bit ADC reading against the 3.3 V reference.
+pub const fn sense_mv(raw: u16) -> u16 {
+    ((raw as u32 & 0xFFF) * 3300 / 4096) as u16
+}
+
+/// Watches the servo current for a move that ended but kept drawing.
+#[derive(Debug, Clone, Copy)]
+pub struct StallDetector {
+    threshold_mv: Option<u16>,
+    // Readings in a row at or above the threshold since the servos stopped.
+    high: u16,
+}
+
+impl StallDetector {
+    /// Stall at or above `threshold_mv`; `None` when no sense resistor is fitted.
+    pub const fn new(threshold_mv: Option<u16>) -> Self {
+        Self {
+            threshold_mv,
+            high: 0,
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold_mv: Option<u16>) {
+        self.threshold_mv = threshold_mv;
+        self.high = 0;
+    }
+
+    /// Record a reading, in millivolts, taken while a servo is `moving` or not. True, once,
+    /// when the current has stayed high for [`STALL_SAMPLES`] readings since the last move
+    /// ended; it takes another move or a low reading to arm it again.
+    pub fn record(&mut self, mv: u16, moving: bool) -> bool {
+        match self.threshold_mv {
+            Some(threshold) if !moving && mv >= threshold => {
+                self.high = self.high.saturating_add(1);
+                self.high == STALL_SAMPLES
+            }
+            _ => {
+                self.high = 0;
+                false
+            }
+        }
+    }
+}
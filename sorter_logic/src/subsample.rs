@@ -0,0 +1,92 @@
+//! Read a fixed number of pixels from a region instead of all of them.
+//!
+//! The ring search reads every ring pixel for every candidate center, so analysis time grows
+//! with the square of the ring radius and would grow with the camera resolution.
+//! [`Subsample`] picks at most `budget` of `total` candidates: the candidates are cut into
+//! `budget` equal runs and one pixel is picked at random from each run. The picks cover the
+//! region evenly (independent random picks clump; a fixed stride can line up with the
+//! sensor's Bayer pattern) and come out in order, so a caller walking the region can take
+//! them as it goes.
+//!
+//! The generator is a seeded xorshift, so the same frame always gets the same pixels and the
+//! same analysis.
+
+/// Marsaglia's 32-bit xorshift generator.
+#[derive(Debug, Clone)]
+pub struct XorShift32(u32);
+
+impl XorShift32 {
+    /// A zero seed (which would only ever produce zeros) is replaced by a fixed one.
+    pub const fn new(seed: u32) -> Self {
+        Self(if seed == 0 { 0x9E37_79B9 } else { seed })
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// Uniform in `0..n` (`0` when `n` is 0).
+    pub fn below(&mut self, n: u32) -> u32 {
+        ((self.next_u32() as u64 * n as u64) >> 32) as u32
+    }
+}
+
+/// Ascending indices in `0..total`, one from each of `budget` equal runs. Yields every index
+/// when `budget >= total`.
+///
+/// ```
+/// use sorter_logic::subsample::Subsample;
+///
+/// let picks: Vec<u32> = Subsample::new(1000, 10, 1).collect();
+/// assert_eq!(picks.len(), 10);
+/// for (run, &i) in picks.iter().enumerate() {
+///     assert!((run as u32 * 100..(run as u32 + 1) * 100).contains(&i));
+/// }
+/// assert_eq!(Subsample::new(5, 8, 1).collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Subsample {
+    total: u32,
+    budget: u32,
+    run: u32,
+    rng: XorShift32,
+}
+
+impl Subsample {
+    pub fn new(total: u32, budget: u32, seed: u32) -> Self {
+        Self {
+            total,
+            budget: budget.min(total),
+            run: 0,
+            rng: XorShift32::new(seed),
+        }
+    }
+
+    fn run_start(&self, run: u32) -> u32 {
+        (run as u64 * self.total as u64 / self.budget as u64) as u32
+    }
+}
+
+impl Iterator for Subsample {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.run >= self.budget {
+            return None;
+        }
+        let start = self.run_start(self.run);
+        let end = self.run_start(self.run + 1);
+        self.run += 1;
+        Some(start + self.rng.below(end - start))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = (self.budget - self.run) as usize;
+        (left, Some(left))
+    }
+}
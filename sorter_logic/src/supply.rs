@@ -0,0 +1,104 @@
+//! Supply voltage monitoring: notices when servo moves drag the supply rail down far enough
+//! to risk a brownout reset, so the sorter can ease off and the host can see why it rebooted.
+//!
+//! The firmware reads VSYS through the Pico's divide-by-three sense pin and records the
+//! lowest reading of each cycle.
+//!
+//! ```
+//! use sorter_logic::supply::{SupplyEvent, SupplyMonitor, vsys_mv};
+//!
+//! assert_eq!(vsys_mv(2048), 4950);
+//!
+//! let mut supply = SupplyMonitor::new(Some(4300));
+//! assert_eq!(supply.record(4900), SupplyEvent::None);
+//! assert_eq!(supply.record(4150), SupplyEvent::Sagged);
+//! assert_eq!(supply.record(4350), SupplyEvent::None);
+//! assert_eq!(supply.record(4450), SupplyEvent::Recovered);
+//! assert_eq!(supply.lowest_mv(), 4150);
+//! ```
+
+/// Default for [`crate::settings::Setting::BrownoutMv`].
+pub const DEFAULT_BROWNOUT_MV: u16 = 4300;
+/// How far above the threshold the supply must come back before it counts as recovered.
+pub const RECOVERY_MARGIN_MV: u16 = 100;
+
+/// Millivolts on VSYS for a 12-bit ADC reading of VSYS/3 against the 3.3 V reference.
+pub const fn vsys_mv(raw: u16) -> u16 {
+    ((raw as u32 & 0xFFF) * 3 * 3300 / 4096) as u16
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupplyEvent {
+    None,
+    /// The supply dropped below the threshold.
+    Sagged,
+    /// The supply came back up after sagging.
+    Recovered,
+}
+
+/// Tracks the supply voltage against a brownout warning threshold.
+#[derive(Debug, Clone, Copy)]
+pub struct SupplyMonitor {
+    threshold_mv: Option<u16>,
+    latest_mv: u16,
+    lowest_mv: u16,
+    sagging: bool,
+}
+
+impl SupplyMonitor {
+    /// Warn below `threshold_mv`; `None` only keeps the readings.
+    pub const fn new(threshold_mv: Option<u16>) -> Self {
+        Self {
+            threshold_mv,
+            latest_mv: 0,
+            lowest_mv: u16::MAX,
+            sagging: false,
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold_mv: Option<u16>) {
+        self.threshold_mv = threshold_mv;
+    }
+
+    /// Record a reading, in millivolts.
+    pub fn record(&mut self, mv: u16) -> SupplyEvent {
+        self.latest_mv = mv;
+        self.lowest_mv = self.lowest_mv.min(mv);
+        let Some(threshold) = self.threshold_mv else {
+            let recovered = self.sagging;
+            self.sagging = false;
+            return if recovered {
+                SupplyEvent::Recovered
+            } else {
+                SupplyEvent::None
+            };
+        };
+        if !self.sagging && mv < threshold {
+            self.sagging = true;
+            SupplyEvent::Sagged
+        } else if self.sagging && mv >= threshold.saturating_add(RECOVERY_MARGIN_MV) {
+            self.sagging = false;
+            SupplyEvent::Recovered
+        } else {
+            SupplyEvent::None
+        }
+    }
+
+    pub fn is_sagging(&self) -> bool {
+        self.sagging
+    }
+
+    /// The last reading, 0 before the first.
+    pub fn latest_mv(&self) -> u16 {
+        self.latest_mv
+    }
+
+    /// The lowest reading so far, 0 before the first.
+    pub fn lowest_mv(&self) -> u16 {
+        if self.lowest_mv == u16::MAX {
+            0
+        } else {
+            self.lowest_mv
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! High-water marks for the firmware's fixed-capacity structures.
+//!
+//! Everything on the device is sized at compile time; when a structure fills, new data is
+//! dropped, squeezed in somewhere else or held up without any error. Recording the peak fill of each
+//! one over a long run lets the capacity constants be sized from data.
+//!
+//! The host asks for a report with [`CMD_TELEMETRY`]; the reply is [`TELEMETRY_MAGIC`], an
+//! entry count, then per structure `[id, peak (u16 LE), capacity (u16 LE), full (u32 LE)]`.
+
+pub use sorter_protocol::{CMD_TELEMETRY, TELEMETRY_MAGIC};
+pub const TELEMETRY_ENTRY_BYTES: usize = 9;
+pub const TELEMETRY_PACKET_MAX: usize = 5 + Bounded::ALL.len() * TELEMETRY_ENTRY_BYTES;
+
+/// A fixed-capacity structure whose fill is tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bounded {
+    /// Learned palette entries ([`crate::router::PALETTE_SIZE`]). Once full, new colors are
+    /// not learned.
+    Palette,
+    /// Tubes holding a color, out of the layout's tube count. Once full, new colors share
+    /// the nearest tube.
+    Tubes,
+    /// Bytes of one host command read. A read that fills the buffer may have been cut short.
+    CommandBuffer,
+    /// Entries in the [event log](crate::event_log::EventLog). Once full, each new event
+    /// overwrites the oldest.
+    EventLog,
+    /// Host commands queued for the sort loop. Once full, the command reader waits and the
+    /// host's writes back up.
+    Commands,
+    /// Commands queued for a servo's task, the fuller of the two. Once full, senders wait.
+    ServoCommands,
+}
+
+impl Bounded {
+    pub const ALL: [Bounded; 6] = [
+        Bounded::Palette,
+        Bounded::Tubes,
+        Bounded::CommandBuffer,
+        Bounded::EventLog,
+        Bounded::Commands,
+        Bounded::ServoCommands,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Bounded::Palette => "palette",
+            Bounded::Tubes => "tubes",
+            Bounded::CommandBuffer => "command buffer",
+            Bounded::EventLog => "event log",
+            Bounded::Commands => "command queue",
+            Bounded::ServoCommands => "servo commands",
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+}
+
+/// Fill statistics for one structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HighWater {
+    /// Largest length seen.
+    pub peak: u16,
+    pub capacity: u16,
+    /// Samples taken while the structure was full.
+    pub full: u32,
+}
+
+impl HighWater {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.min(u16::MAX as usize) as u16,
+            ..Default::default()
+        }
+    }
+
+    /// Record the current length. Returns true the first time the structure is seen full.
+    pub fn record(&mut self, len: usize) -> bool {
+        let len = len.min(u16::MAX as usize) as u16;
+        let newly_full = len >= self.capacity && self.peak < self.capacity;
+        self.peak = self.peak.max(len);
+        if len >= self.capacity {
+            self.full = self.full.saturating_add(1);
+        }
+        newly_full
+    }
+}
+
+/// High-water marks for every [`Bounded`] structure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Telemetry {
+    marks: [HighWater; Bounded::ALL.len()],
+}
+
+impl Telemetry {
+    /// `capacities` gives each structure's capacity, in [`Bounded::ALL`] order.
+    pub fn new(capacities: [usize; Bounded::ALL.len()]) -> Self {
+        Self {
+            marks: capacities.map(HighWater::new),
+        }
+    }
+
+    /// See [`HighWater::record`].
+    pub fn record(&mut self, which: Bounded, len: usize) -> bool {
+        self.marks[which as usize].record(len)
+    }
+
+    pub fn get(&self, which: Bounded) -> HighWater {
+        self.marks[which as usize]
+    }
+
+    /// Write a telemetry packet (magic included). Returns the length.
+    pub fn encode(&self, out: &mut [u8; TELEMETRY_PACKET_MAX]) -> usize {
+        out[..4].copy_from_slice(&TELEMETRY_MAGIC);
+        out[4] = self.marks.len() as u8;
+        let mut len = 5;
+        for (which, mark) in Bounded::ALL.iter().zip(self.marks.iter()) {
+            let entry = &mut out[len..len + TELEMETRY_ENTRY_BYTES];
+            entry[0] = which.id();
+            entry[1..3].copy_from_slice(&mark.peak.to_le_bytes());
+            entry[3..5].copy_from_slice(&mark.capacity.to_le_bytes());
+            entry[5..9].copy_from_slice(&mark.full.to_le_bytes());
+            len += TELEMETRY_ENTRY_BYTES;
+        }
+        len
+    }
+
+    /// Decode the body of a telemetry packet (everything after the magic). Unknown ids are
+    /// skipped; returns `None` if the body is truncated.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let (&n, rest) = body.split_first()?;
+        let entries = rest.get(..n as usize * TELEMETRY_ENTRY_BYTES)?;
+        let mut telemetry = Self::new([0; Bounded::ALL.len()]);
+        for e in entries.chunks_exact(TELEMETRY_ENTRY_BYTES) {
+            let Some(which) = Bounded::from_id(e[0]) else {
+                continue;
+            };
+            telemetry.marks[which as usize] = HighWater {
+                peak: u16::from_le_bytes([e[1], e[2]]),
+                capacity: u16::from_le_bytes([e[3], e[4]]),
+                full: u32::from_le_bytes([e[5], e[6], e[7], e[8]]),
+            };
+        }
+        Some(telemetry)
+    }
+}
@@ -0,0 +1,51 @@
+//! The OV7670's color bar test pattern, for checking the camera path at power-up.
+//!
+//! With the pattern on, the sensor replaces the picture with eight vertical bars. They go
+//! through the same scaling, RGB565 packing, pixel bus and DMA as a real frame, so getting the
+//! bars back in order means all of those work.
+
+use crate::decode_rgb565_be;
+
+/// The bars from left to right (white, yellow, cyan, green, magenta, red, blue, black), as
+/// which of red, green and blue are lit.
+pub const COLOR_BARS: [(bool, bool, bool); 8] = [
+    (true, true, true),
+    (true, true, false),
+    (false, true, true),
+    (false, true, false),
+    (true, false, true),
+    (true, false, false),
+    (false, false, true),
+    (false, false, false),
+];
+
+/// How many of the [`COLOR_BARS`] show up where they should in a `w` x `h` RGB565 frame.
+///
+/// Each bar is judged by its middle columns, so a blurred edge between bars does not count
+/// against it; a channel is lit if it averages over half scale.
+pub fn color_bars_matched(frame: &[u8], w: usize, h: usize) -> usize {
+    let bar_w = w / COLOR_BARS.len();
+    if bar_w == 0 || frame.len() < w * h * 2 {
+        return 0;
+    }
+    let margin = bar_w / 4;
+    COLOR_BARS
+        .iter()
+        .enumerate()
+        .filter(|&(i, &expected)| {
+            let cols = i * bar_w + margin..(i + 1) * bar_w - margin;
+            let mut sum = [0u32; 3];
+            let mut n = 0;
+            for row in frame[..w * h * 2].chunks_exact(w * 2) {
+                for px in decode_rgb565_be(&row[cols.start * 2..cols.end * 2]) {
+                    sum[0] += px.r as u32;
+                    sum[1] += px.g as u32;
+                    sum[2] += px.b as u32;
+                    n += 1;
+                }
+            }
+            let lit = |s: u32| s > n * 128;
+            (lit(sum[0]), lit(sum[1]), lit(sum[2])) == expected
+        })
+        .count()
+}
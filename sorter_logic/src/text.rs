@@ -0,0 +1,198 @@
+//! Operator-facing text and units.
+//!
+//! Anything shown to the person running the sorter (reports, status lines, logs meant for the
+//! operator) is looked up by [`Msg`] id through a [`Locale`] instead of being written inline.
+//! To add a language, implement [`Locale::translate`]; ids it leaves untranslated fall back
+//! to [`English`]. Messages are short labels; callers append values (`"{label}: {value}"`) so
+//! no language needs to agree with another on word order.
+//!
+//! ```
+//! use sorter_logic::text::{Locale, Msg};
+//!
+//! struct German;
+//!
+//! impl Locale for German {
+//!     fn translate(&self, id: Msg) -> Option<&'static str> {
+//!         match id {
+//!             Msg::Paused => Some("Pausiert"),
+//!             _ => None,
+//!         }
+//!     }
+//! }
+//!
+//! assert_eq!(German.msg(Msg::Paused), "Pausiert");
+//! assert_eq!(German.msg(Msg::PaletteFull), "Palette full");
+//! ```
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Msg {
+    // Status
+    Paused,
+    Profile,
+    SlotEmptyRetrying,
+    RefillHopper,
+    HopperRefilled,
+    HopperStalled,
+    ServoStalled,
+    PaletteFull,
+    TubeSpillover,
+    TubePurityLow,
+    // Session report
+    ReportTitle,
+    Summary,
+    Started,
+    Duration,
+    Frames,
+    BeadsSorted,
+    Throughput,
+    Colors,
+    Color,
+    Rgb,
+    Count,
+    Events,
+    NoEvents,
+    Time,
+    Type,
+    Message,
+    LowConfidenceBeads,
+    Confidence,
+    PaletteEntry,
+    Tube,
+    Purity,
+    Bead,
+    RecheckTitle,
+    NothingToRecheck,
+    // Event kinds
+    EventInfo,
+    EventWarning,
+    EventError,
+    EventJam,
+    // Events
+    ConsecutiveEmptyCaptures,
+    PickupsResumed,
+    FrameTimeout,
+    SerialReadError,
+    // Capture tool
+    Replaying,
+    GeneratingFrames,
+    OpeningPort,
+    ListeningForFrames,
+    FrameReceived,
+    EndOfFrames,
+    Saved,
+    ImageSaveError,
+    SessionLogError,
+    SessionReport,
+    SessionReportError,
+    RecheckList,
+    RecheckListError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Beads,
+    BeadsPerMinute,
+    Percent,
+    Milliseconds,
+    Seconds,
+    Baud,
+}
+
+/// A set of translations for [`Msg`] and [`Unit`].
+pub trait Locale {
+    /// This locale's text for `id`, or `None` where it has no translation yet.
+    fn translate(&self, id: Msg) -> Option<&'static str>;
+
+    /// The text for `id`, in [`English`] if this locale does not translate it.
+    fn msg(&self, id: Msg) -> &'static str {
+        self.translate(id).unwrap_or_else(|| English::text(id))
+    }
+
+    fn unit(&self, unit: Unit) -> &'static str {
+        English.unit(unit)
+    }
+}
+
+/// The built-in (and fallback) locale.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct English;
+
+impl English {
+    /// The English text for `id`; every id has one.
+    pub const fn text(id: Msg) -> &'static str {
+        match id {
+            Msg::Paused => "Paused",
+            Msg::Profile => "Profile",
+            Msg::SlotEmptyRetrying => "Slot empty, retrying pickup",
+            Msg::RefillHopper => "Hopper empty, please refill",
+            Msg::HopperRefilled => "Hopper refilled, resuming",
+            Msg::HopperStalled => "Hopper jammed or empty, stopped",
+            Msg::ServoStalled => "Servo stalled, stopped",
+            Msg::PaletteFull => "Palette full",
+            Msg::TubeSpillover => "Tube full, spilling over",
+            Msg::TubePurityLow => "Tube purity low",
+            Msg::ReportTitle => "Bead Sorter Session Report",
+            Msg::Summary => "Summary",
+            Msg::Started => "Started",
+            Msg::Duration => "Duration",
+            Msg::Frames => "Frames",
+            Msg::BeadsSorted => "Beads Sorted",
+            Msg::Throughput => "Throughput",
+            Msg::Colors => "Colors",
+            Msg::Color => "Color",
+            Msg::Rgb => "RGB",
+            Msg::Count => "Count",
+            Msg::Events => "Events",
+            Msg::NoEvents => "No events.",
+            Msg::Time => "Time",
+            Msg::Type => "Type",
+            Msg::Message => "Message",
+            Msg::LowConfidenceBeads => "Low Confidence Beads",
+            Msg::Confidence => "Confidence",
+            Msg::PaletteEntry => "Palette",
+            Msg::Tube => "Tube",
+            Msg::Purity => "Purity",
+            Msg::Bead => "Bead",
+            Msg::RecheckTitle => "Re-check these tubes",
+            Msg::NothingToRecheck => "No low-confidence beads",
+            Msg::EventInfo => "info",
+            Msg::EventWarning => "warning",
+            Msg::EventError => "error",
+            Msg::EventJam => "jam",
+            Msg::ConsecutiveEmptyCaptures => "Consecutive empty captures",
+            Msg::PickupsResumed => "Pickups resumed after empty captures",
+            Msg::FrameTimeout => "Timeout reading frame data",
+            Msg::SerialReadError => "Serial read error",
+            Msg::Replaying => "Replaying",
+            Msg::GeneratingFrames => "Generating synthetic frames",
+            Msg::OpeningPort => "Opening",
+            Msg::ListeningForFrames => "Listening for BEAD frames",
+            Msg::FrameReceived => "RX OK",
+            Msg::EndOfFrames => "End of frames",
+            Msg::Saved => "Saved",
+            Msg::ImageSaveError => "Error saving image",
+            Msg::SessionLogError => "Error creating session log",
+            Msg::SessionReport => "Session report",
+            Msg::SessionReportError => "Error writing session report",
+            Msg::RecheckList => "Re-check list",
+            Msg::RecheckListError => "Error writing re-check list",
+        }
+    }
+}
+
+impl Locale for English {
+    fn translate(&self, id: Msg) -> Option<&'static str> {
+        Some(Self::text(id))
+    }
+
+    fn unit(&self, unit: Unit) -> &'static str {
+        match unit {
+            Unit::Beads => "beads",
+            Unit::BeadsPerMinute => "beads/min",
+            Unit::Percent => "%",
+            Unit::Milliseconds => "ms",
+            Unit::Seconds => "s",
+            Unit::Baud => "baud",
+        }
+    }
+}
@@ -0,0 +1,1339 @@
+mod common;
+
+mod acceptance_gate {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_painted, frame_with_bead};
+    use sorter_logic::{AnalysisConfig, BeadAnalysis, Rgb, analyze_image_debug};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    const BLUE: Rgb = Rgb {
+        r: 30,
+        g: 60,
+        b: 200,
+    };
+
+    fn analyze(frame: &[u8], config: AnalysisConfig) -> Option<BeadAnalysis> {
+        analyze_image_debug(frame, WIDTH, HEIGHT, None, None, config)
+    }
+
+    #[test]
+    fn test_min_pixel_count() {
+        let frame = frame_with_bead(BACKGROUND, RED);
+        let kept = analyze(&frame, AnalysisConfig::default())
+            .expect("default gate passes a whole bead")
+            .pixel_count;
+
+        let strict = AnalysisConfig {
+            min_pixel_count: kept + 1,
+            ..Default::default()
+        };
+        assert!(analyze(&frame, strict).is_none());
+    }
+
+    #[test]
+    fn test_max_variance_rejects_mixed_region() {
+        // Two beads' worth of color in one ring: keep every pixel so the mix shows in the variance.
+        let mixed = frame_painted(BACKGROUND, |dx, _| if dx < 0 { RED } else { BLUE });
+        let solid = frame_with_bead(BACKGROUND, RED);
+        let config = AnalysisConfig {
+            filter_percent: 100,
+            max_variance: Some(1000),
+            ..Default::default()
+        };
+
+        let ungated = AnalysisConfig {
+            max_variance: None,
+            ..config
+        };
+        assert!(analyze(&mixed, ungated).unwrap().variance > 1000);
+        assert!(analyze(&mixed, config).is_none());
+        assert!(analyze(&solid, config).is_some());
+    }
+
+    #[test]
+    fn test_confidence_drops_on_mixed_region() {
+        let mixed = frame_painted(BACKGROUND, |dx, _| if dx < 0 { RED } else { BLUE });
+        let solid = frame_with_bead(BACKGROUND, RED);
+        let config = AnalysisConfig {
+            filter_percent: 100,
+            ..Default::default()
+        };
+
+        assert_eq!(analyze(&solid, config).unwrap().confidence, 100);
+        assert!(analyze(&mixed, config).unwrap().confidence < 50);
+    }
+}
+
+mod background {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, empty_frame, frame_with_bead, frame_with_bead_at};
+    use sorter_logic::{AnalysisConfig, BackgroundModel, Rgb, analyze_image, analyze_image_debug};
+
+    const SHADOW: Rgb = Rgb {
+        r: 90,
+        g: 90,
+        b: 80,
+    };
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+
+    #[test]
+    fn test_empty_slot_matches_background() {
+        let empty = empty_frame(BACKGROUND);
+        let model = BackgroundModel::from_frame(&empty, WIDTH, HEIGHT).unwrap();
+        let result = analyze_image_debug(
+            &empty,
+            WIDTH,
+            HEIGHT,
+            None,
+            Some(&model),
+            Default::default(),
+        );
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_static_shadow_is_not_a_bead() {
+        // A dark patch in the slot that is present in the reference capture too.
+        let shadowed = frame_with_bead_at(BACKGROUND, SHADOW, 20, 17);
+        assert!(analyze_image(&shadowed, WIDTH, HEIGHT).is_some());
+
+        let model = BackgroundModel::from_frame(&shadowed, WIDTH, HEIGHT).unwrap();
+        let result = analyze_image_debug(
+            &shadowed,
+            WIDTH,
+            HEIGHT,
+            None,
+            Some(&model),
+            AnalysisConfig::default(),
+        );
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_bead_detected_against_background() {
+        let model = BackgroundModel::from_frame(&empty_frame(BACKGROUND), WIDTH, HEIGHT).unwrap();
+        let bead = frame_with_bead(BACKGROUND, RED);
+        let result =
+            analyze_image_debug(&bead, WIDTH, HEIGHT, None, Some(&model), Default::default())
+                .unwrap();
+        assert!(result.average_color.dist_lab(&RED) < 30);
+    }
+
+    #[test]
+    fn test_model_size_checks() {
+        let empty = empty_frame(BACKGROUND);
+        assert!(BackgroundModel::from_frame(&empty, WIDTH, HEIGHT + 1).is_none());
+        let model = BackgroundModel::from_frame(&empty, WIDTH, HEIGHT - 1).unwrap();
+        let result = analyze_image_debug(
+            &empty,
+            WIDTH,
+            HEIGHT,
+            None,
+            Some(&model),
+            Default::default(),
+        );
+        assert!(result.is_none());
+    }
+}
+
+mod bead_size {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, to_rgb565};
+    use sorter_logic::{AnalysisConfig, BeadSize, CameraCalibration, Rgb, analyze_image_debug};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+
+    // A tube bead at (20, 17): `outer_sq` and `hole_sq` are squared radii in pixels.
+    fn tube_bead(outer_sq: i32, hole_sq: i32) -> Vec<u8> {
+        let mut data = Vec::with_capacity(WIDTH * HEIGHT * 2);
+        for y in 0..HEIGHT as i32 {
+            for x in 0..WIDTH as i32 {
+                let d2 = (x - 20).pow(2) + (y - 17).pow(2);
+                let c = if d2 <= outer_sq && d2 > hole_sq {
+                    RED
+                } else {
+                    BACKGROUND
+                };
+                data.extend_from_slice(&to_rgb565(c));
+            }
+        }
+        data
+    }
+
+    fn diameter(frame: &[u8], camera: Option<CameraCalibration>) -> (f32, Option<f32>) {
+        let config = AnalysisConfig {
+            camera,
+            ..Default::default()
+        };
+        let a = analyze_image_debug(frame, WIDTH, HEIGHT, None, None, config).expect("bead found");
+        (a.diameter_px, a.diameter_mm)
+    }
+
+    #[test]
+    fn test_diameter_in_pixels() {
+        // Radius 7 with a hole of radius 2: the hole does not shorten the measurement.
+        let (px, mm) = diameter(&tube_bead(49, 4), None);
+        assert!((13.0..=15.5).contains(&px), "diameter {}", px);
+        assert_eq!(mm, None);
+    }
+
+    #[test]
+    fn test_mini_and_midi_separate() {
+        let midi = tube_bead(49, 4);
+        // 2.6 mm at the same scale: radius about 3.6 px.
+        let mini = tube_bead(13, 1);
+
+        let (midi_px, _) = diameter(&midi, None);
+        let camera = CameraCalibration::from_reference(midi_px, 5.0);
+
+        let (_, midi_mm) = diameter(&midi, Some(camera));
+        let (_, mini_mm) = diameter(&mini, Some(camera));
+        let (midi_mm, mini_mm) = (midi_mm.unwrap(), mini_mm.unwrap());
+        assert!((midi_mm - 5.0).abs() < 0.01);
+        assert!((2.0..3.3).contains(&mini_mm), "mini {} mm", mini_mm);
+
+        assert_eq!(BeadSize::from_diameter_mm(midi_mm), BeadSize::Midi);
+        assert_eq!(BeadSize::from_diameter_mm(mini_mm), BeadSize::Mini);
+    }
+}
+
+mod channel_variance {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_painted};
+    use sorter_logic::{AnalysisConfig, BeadAnalysis, Rgb, analyze_image_debug};
+
+    fn analyze(frame: &[u8]) -> BeadAnalysis {
+        // Keep every ring pixel so the stripes survive the outlier filter.
+        let config = AnalysisConfig {
+            filter_percent: 100,
+            ..Default::default()
+        };
+        analyze_image_debug(frame, WIDTH, HEIGHT, None, None, config).unwrap()
+    }
+
+    #[test]
+    fn test_variance_is_sum_of_channels() {
+        let frame = frame_painted(BACKGROUND, |dx, _| Rgb {
+            r: (120 + dx * 8) as u8,
+            g: 40,
+            b: 90,
+        });
+        let a = analyze(&frame);
+        assert_eq!(a.variance, a.var_r + a.var_g + a.var_b);
+    }
+
+    #[test]
+    fn test_chroma_spread_stays_in_one_channel() {
+        // Red varies across the bead; green and blue are flat.
+        let frame = frame_painted(BACKGROUND, |dx, _| Rgb {
+            r: (120 + dx * 8) as u8,
+            g: 40,
+            b: 90,
+        });
+        let a = analyze(&frame);
+        assert!(a.var_r > 100, "{:?}", a);
+        assert!(a.var_g < 10 && a.var_b < 10, "{:?}", a);
+    }
+
+    #[test]
+    fn test_shading_spreads_across_channels() {
+        // Brightness varies; hue does not.
+        let frame = frame_painted(BACKGROUND, |_, dy| {
+            let v = (100 + dy * 8) as u8;
+            Rgb {
+                r: v,
+                g: v / 2,
+                b: v / 4,
+            }
+        });
+        let a = analyze(&frame);
+        assert!(a.var_r > 100 && a.var_g > 20, "{:?}", a);
+    }
+}
+
+mod color_estimator {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_painted, frame_with_bead};
+    use sorter_logic::{AnalysisConfig, ColorEstimator, Rgb, analyze_image_debug};
+
+    const BLUE: Rgb = Rgb {
+        r: 30,
+        g: 60,
+        b: 200,
+    };
+    const WHITE: Rgb = Rgb {
+        r: 255,
+        g: 255,
+        b: 255,
+    };
+
+    fn analyze(frame: &[u8], color_estimator: ColorEstimator) -> Rgb {
+        let config = AnalysisConfig {
+            color_estimator,
+            ..Default::default()
+        };
+        analyze_image_debug(frame, WIDTH, HEIGHT, None, None, config)
+            .unwrap()
+            .average_color
+    }
+
+    #[test]
+    fn test_estimators_agree_on_solid_bead() {
+        let frame = frame_with_bead(BACKGROUND, BLUE);
+        let mean = analyze(&frame, ColorEstimator::FilteredMean);
+        for estimator in [ColorEstimator::Median, ColorEstimator::TrimmedMean(20)] {
+            assert_eq!(analyze(&frame, estimator), mean, "{:?}", estimator);
+        }
+    }
+
+    #[test]
+    fn test_median_ignores_highlight() {
+        // A specular streak across the upper half of the ring.
+        let frame = frame_painted(BACKGROUND, |dx, dy| {
+            if dy < 0 && dx.abs() <= 2 { WHITE } else { BLUE }
+        });
+        let solid = analyze(
+            &frame_with_bead(BACKGROUND, BLUE),
+            ColorEstimator::FilteredMean,
+        );
+
+        let median = analyze(&frame, ColorEstimator::Median);
+        assert_eq!(median, solid);
+
+        let trimmed = analyze(&frame, ColorEstimator::TrimmedMean(25));
+        assert!(trimmed.dist_lab(&solid) < 25, "{:?}", trimmed);
+
+        // The untrimmed mean is pulled toward white.
+        let mean = analyze(&frame, ColorEstimator::TrimmedMean(0));
+        assert!(mean.dist_lab(&solid) > median.dist_lab(&solid));
+    }
+}
+
+mod dominant_color {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_painted, frame_with_bead};
+    use sorter_logic::{AnalysisConfig, ColorMode, Rgb, analyze_image, analyze_image_debug};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    const YELLOW: Rgb = Rgb {
+        r: 230,
+        g: 210,
+        b: 20,
+    };
+
+    fn dominant() -> AnalysisConfig {
+        AnalysisConfig {
+            color_mode: ColorMode::Dominant,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_solid_bead_same_as_mean() {
+        let frame = frame_with_bead(BACKGROUND, RED);
+        let mean = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        let dom = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, dominant()).unwrap();
+        assert!(dom.average_color.dist_lab(&mean.average_color) < 25);
+        assert_eq!(dom.secondary_color, None);
+        assert_eq!(mean.secondary_color, None);
+    }
+
+    #[test]
+    fn test_striped_bead_keeps_both_colors() {
+        // Mostly red with a yellow band across the middle.
+        let frame = frame_painted(BACKGROUND, |_, dy| if dy.abs() <= 1 { YELLOW } else { RED });
+        let dom = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, dominant()).unwrap();
+        assert!(dom.average_color.dist_lab(&RED) < 100, "{:?}", dom);
+        let secondary = dom.secondary_color.expect("yellow band");
+        assert!(secondary.dist_lab(&YELLOW) < 100, "{:?}", secondary);
+
+        // Mean mode never reports a secondary color.
+        let mean = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        assert_eq!(mean.secondary_color, None);
+    }
+
+    #[test]
+    fn test_small_fleck_is_not_secondary() {
+        let frame = frame_painted(
+            BACKGROUND,
+            |dx, dy| if (dx, dy) == (5, 0) { YELLOW } else { RED },
+        );
+        let dom = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, dominant()).unwrap();
+        assert_eq!(dom.secondary_color, None);
+    }
+
+    fn two_tone() -> AnalysisConfig {
+        AnalysisConfig {
+            color_mode: ColorMode::TwoTone,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_two_tone_splits_striped_bead() {
+        // Red with a yellow band over roughly a third of the ring.
+        let frame = frame_painted(BACKGROUND, |_, dy| if dy >= 2 { YELLOW } else { RED });
+        let split = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, two_tone()).unwrap();
+        assert!(split.average_color.dist_lab(&RED) < 100, "{:?}", split);
+        let secondary = split.secondary_color.expect("yellow band");
+        assert!(secondary.dist_lab(&YELLOW) < 100, "{:?}", secondary);
+        assert!((25..50).contains(&split.secondary_percent), "{:?}", split);
+
+        let mean = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        assert_eq!(mean.secondary_percent, 0);
+    }
+
+    #[test]
+    fn test_two_tone_solid_bead_same_as_mean() {
+        let frame = frame_with_bead(BACKGROUND, RED);
+        let mean = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        let split = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, two_tone()).unwrap();
+        assert_eq!(split.average_color, mean.average_color);
+        assert_eq!(split.secondary_color, None);
+        assert_eq!(split.secondary_percent, 0);
+
+        let fleck = frame_painted(
+            BACKGROUND,
+            |dx, dy| if (dx, dy) == (5, 0) { YELLOW } else { RED },
+        );
+        let split = analyze_image_debug(&fleck, WIDTH, HEIGHT, None, None, two_tone()).unwrap();
+        assert_eq!(split.secondary_color, None);
+    }
+}
+
+mod eccentricity {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_with_bead, to_rgb565};
+    use sorter_logic::{AnalysisConfig, BeadAnalysis, Rgb, analyze_image_debug};
+
+    const LINT: Rgb = Rgb {
+        r: 40,
+        g: 40,
+        b: 60,
+    };
+
+    // A fiber across the slot: 16 x 3 pixels through the search window.
+    fn lint_frame() -> Vec<u8> {
+        let mut data = Vec::with_capacity(WIDTH * HEIGHT * 2);
+        for y in 0..HEIGHT as i32 {
+            for x in 0..WIDTH as i32 {
+                let c = if (12..28).contains(&x) && (16..19).contains(&y) {
+                    LINT
+                } else {
+                    BACKGROUND
+                };
+                data.extend_from_slice(&to_rgb565(c));
+            }
+        }
+        data
+    }
+
+    fn analyze(frame: &[u8], max_eccentricity: Option<f32>) -> Option<BeadAnalysis> {
+        let config = AnalysisConfig {
+            max_eccentricity,
+            ..Default::default()
+        };
+        analyze_image_debug(frame, WIDTH, HEIGHT, None, None, config)
+    }
+
+    #[test]
+    fn test_round_bead_is_not_eccentric() {
+        let frame = frame_with_bead(BACKGROUND, LINT);
+        let a = analyze(&frame, None).expect("bead found");
+        assert!(a.eccentricity < 0.4, "eccentricity {}", a.eccentricity);
+        assert!(analyze(&frame, Some(0.8)).is_some());
+    }
+
+    #[test]
+    fn test_lint_is_rejected() {
+        let frame = lint_frame();
+        let a = analyze(&frame, None).expect("ungated, lint passes as a bead");
+        assert!(a.eccentricity > 0.9, "eccentricity {}", a.eccentricity);
+        assert!(analyze(&frame, Some(0.8)).is_none());
+    }
+
+    #[test]
+    fn test_broken_bead_is_rejected() {
+        // Only the upper half of the bead is left.
+        let frame =
+            common::frame_painted(BACKGROUND, |_, dy| if dy < 0 { LINT } else { BACKGROUND });
+        assert!(analyze(&frame, Some(0.8)).is_none());
+    }
+}
+
+mod empty_detect {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, empty_frame, frame_with_bead, frame_with_bead_at};
+    use sorter_logic::{AnalysisConfig, BackgroundModel, EmptyResult, Rgb, detect_empty};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    const SHADOW: Rgb = Rgb {
+        r: 90,
+        g: 90,
+        b: 80,
+    };
+
+    fn detect(frame: &[u8], model: &BackgroundModel) -> EmptyResult {
+        detect_empty(frame, WIDTH, HEIGHT, model, AnalysisConfig::default())
+    }
+
+    #[test]
+    fn test_empty_slot() {
+        let empty = empty_frame(BACKGROUND);
+        let model = BackgroundModel::from_frame(&empty, WIDTH, HEIGHT).unwrap();
+        let result = detect(&empty, &model);
+        assert!(result.empty);
+        assert_eq!(result.confidence, 100);
+    }
+
+    #[test]
+    fn test_bead_present() {
+        let model = BackgroundModel::from_frame(&empty_frame(BACKGROUND), WIDTH, HEIGHT).unwrap();
+        let result = detect(&frame_with_bead(BACKGROUND, RED), &model);
+        assert!(!result.empty);
+        assert!(result.confidence > 50);
+    }
+
+    #[test]
+    fn test_static_shadow_is_empty() {
+        let shadowed = frame_with_bead_at(BACKGROUND, SHADOW, 20, 17);
+        let model = BackgroundModel::from_frame(&shadowed, WIDTH, HEIGHT).unwrap();
+        assert!(detect(&shadowed, &model).empty);
+    }
+
+    #[test]
+    fn test_threshold_comes_from_config() {
+        let model = BackgroundModel::from_frame(&empty_frame(BACKGROUND), WIDTH, HEIGHT).unwrap();
+        let frame = frame_with_bead(BACKGROUND, RED);
+        let config = AnalysisConfig {
+            background_min_contrast: u16::MAX as u32,
+            ..Default::default()
+        };
+        assert!(detect_empty(&frame, WIDTH, HEIGHT, &model, config).empty);
+    }
+
+    #[test]
+    fn test_mismatched_model_is_unknown() {
+        let model =
+            BackgroundModel::from_frame(&empty_frame(BACKGROUND), WIDTH, HEIGHT - 1).unwrap();
+        let result = detect(&empty_frame(BACKGROUND), &model);
+        assert!(!result.empty);
+        assert_eq!(result.confidence, 0);
+    }
+
+    #[test]
+    fn test_captured_slots() {
+        let empties = common::captures("full_sorted/empty");
+        let model = BackgroundModel::from_frame(&empties[0], WIDTH, HEIGHT).unwrap();
+        for frame in &empties[1..] {
+            let result = detect(frame, &model);
+            assert!(result.empty, "{:?}", result);
+        }
+
+        // Each sorted run also filed a few empty slots with its beads. Clear beads are left
+        // out: against this background too many of them look like an empty slot.
+        let beads = |folder: &str| {
+            common::captures(folder)
+                .iter()
+                .filter(|frame| !detect(frame, &model).empty)
+                .count()
+        };
+        assert_eq!(beads("sorted/black"), 32);
+        assert_eq!(beads("sorted/dark-purple"), 45);
+        assert_eq!(beads("sorted/gray"), 44);
+        assert_eq!(beads("sorted/light-purple"), 38);
+        assert_eq!(beads("sorted/white"), 34);
+    }
+}
+
+mod finish {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_painted, frame_with_bead};
+    use sorter_logic::{BeadFinish, Rgb, analyze_image};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    const IVORY: Rgb = Rgb {
+        r: 210,
+        g: 200,
+        b: 180,
+    };
+    const HIGHLIGHT: Rgb = Rgb {
+        r: 255,
+        g: 255,
+        b: 255,
+    };
+
+    #[test]
+    fn test_uniform_bead_is_opaque() {
+        let a = analyze_image(&frame_with_bead(BACKGROUND, RED), WIDTH, HEIGHT).unwrap();
+        assert_eq!(a.finish, BeadFinish::Opaque);
+    }
+
+    #[test]
+    fn test_see_through_bead_is_translucent() {
+        // Every other pixel shows the (pale) background through a saturated bead.
+        let frame = frame_painted(BACKGROUND, |dx, dy| {
+            if (dx + dy) % 2 == 0 { RED } else { BACKGROUND }
+        });
+        let a = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        assert_eq!(a.finish, BeadFinish::Translucent);
+    }
+
+    #[test]
+    fn test_few_highlights_is_pearl() {
+        let frame = frame_painted(BACKGROUND, |dx, dy| match (dx, dy) {
+            (4, 0) | (4, 1) | (-4, 0) => HIGHLIGHT,
+            _ => IVORY,
+        });
+        let a = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        assert_eq!(a.finish, BeadFinish::Pearl);
+    }
+
+    #[test]
+    fn test_scattered_flecks_is_glitter() {
+        let frame = frame_painted(BACKGROUND, |dx, dy| {
+            if (dx * 7 + dy * 3) % 11 == 0 {
+                HIGHLIGHT
+            } else {
+                RED
+            }
+        });
+        let a = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        assert_eq!(a.finish, BeadFinish::Glitter);
+    }
+
+    #[test]
+    fn test_captured_white_beads_have_no_highlights() {
+        // A white bead is bright all over; that is its color, not a sheen.
+        for frame in common::captures("sorted/white") {
+            if let Some(a) = analyze_image(&frame, WIDTH, HEIGHT) {
+                assert!(!matches!(a.finish, BeadFinish::Glitter | BeadFinish::Pearl));
+            }
+        }
+    }
+}
+
+mod linear_average {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_painted, frame_with_bead};
+    use sorter_logic::{AnalysisConfig, ColorEstimator, Rgb, analyze_image_debug};
+
+    fn analyze(frame: &[u8], linear_average: bool, color_estimator: ColorEstimator) -> Rgb {
+        let config = AnalysisConfig {
+            linear_average,
+            color_estimator,
+            filter_percent: 100,
+            ..Default::default()
+        };
+        analyze_image_debug(frame, WIDTH, HEIGHT, None, None, config)
+            .unwrap()
+            .average_color
+    }
+
+    #[test]
+    fn test_solid_bead_unchanged() {
+        let bead = Rgb {
+            r: 200,
+            g: 20,
+            b: 30,
+        };
+        let frame = frame_with_bead(BACKGROUND, bead);
+        for estimator in [
+            ColorEstimator::FilteredMean,
+            ColorEstimator::TrimmedMean(10),
+        ] {
+            assert_eq!(
+                analyze(&frame, true, estimator),
+                analyze(&frame, false, estimator)
+            );
+        }
+    }
+
+    #[test]
+    fn test_linear_average_is_brighter() {
+        // Half light, half dark grey: the sRGB mean sits at the byte midpoint, the linear-light
+        // mean well above it.
+        let light = Rgb {
+            r: 230,
+            g: 230,
+            b: 230,
+        };
+        let dark = Rgb {
+            r: 40,
+            g: 40,
+            b: 40,
+        };
+        let frame = frame_painted(BACKGROUND, |dx, _| if dx < 0 { dark } else { light });
+        for estimator in [
+            ColorEstimator::FilteredMean,
+            ColorEstimator::TrimmedMean(10),
+        ] {
+            let srgb = analyze(&frame, false, estimator);
+            let linear = analyze(&frame, true, estimator);
+            assert!(linear.g > srgb.g + 20, "{:?} vs {:?}", linear, srgb);
+        }
+    }
+}
+
+mod mad_filter {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_painted, frame_with_bead};
+    use sorter_logic::{AnalysisConfig, BeadAnalysis, Rgb, analyze_image_debug};
+
+    const GREEN: Rgb = Rgb {
+        r: 40,
+        g: 170,
+        b: 60,
+    };
+    const WHITE: Rgb = Rgb {
+        r: 255,
+        g: 255,
+        b: 255,
+    };
+
+    fn analyze(frame: &[u8], mad_k: Option<f32>) -> BeadAnalysis {
+        let config = AnalysisConfig {
+            mad_k,
+            ..Default::default()
+        };
+        analyze_image_debug(frame, WIDTH, HEIGHT, None, None, config).unwrap()
+    }
+
+    #[test]
+    fn test_clean_bead_keeps_more_pixels() {
+        let frame = frame_with_bead(BACKGROUND, GREEN);
+        let percent = analyze(&frame, None);
+        let mad = analyze(&frame, Some(2.5));
+        assert!(
+            mad.pixel_count > percent.pixel_count,
+            "{} vs {}",
+            mad.pixel_count,
+            percent.pixel_count
+        );
+        assert_eq!(mad.average_color, percent.average_color);
+    }
+
+    #[test]
+    fn test_highlights_rejected() {
+        // A few specular pixels on the ring.
+        let frame = frame_painted(BACKGROUND, |dx, dy| {
+            if dy == -5 && dx.abs() <= 1 {
+                WHITE
+            } else {
+                GREEN
+            }
+        });
+        let solid = analyze(&frame_with_bead(BACKGROUND, GREEN), Some(2.5));
+        let mad = analyze(&frame, Some(2.5));
+        assert_eq!(mad.average_color, solid.average_color);
+        assert!(mad.pixel_count < solid.pixel_count);
+    }
+}
+
+mod mask {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, empty_frame, frame_with_bead};
+    use sorter_logic::{AnalysisConfig, MaskPixel, Rgb, analyze_image_debug};
+
+    fn mask_of(frame: &[u8]) -> (bool, Vec<MaskPixel>) {
+        let mut mask = vec![0xFF; WIDTH * HEIGHT];
+        let found = analyze_image_debug(
+            frame,
+            WIDTH,
+            HEIGHT,
+            Some(&mut mask),
+            None,
+            AnalysisConfig::default(),
+        )
+        .is_some();
+        let mask = mask
+            .into_iter()
+            .map(|v| MaskPixel::from_u8(v).unwrap())
+            .collect();
+        (found, mask)
+    }
+
+    fn count(mask: &[MaskPixel], which: MaskPixel) -> usize {
+        mask.iter().filter(|&&m| m == which).count()
+    }
+
+    #[test]
+    fn test_mask_stages() {
+        let red = Rgb {
+            r: 200,
+            g: 20,
+            b: 30,
+        };
+        let (found, mask) = mask_of(&frame_with_bead(BACKGROUND, red));
+        assert!(found);
+
+        // The whole 6x4 background patch, clear of the bead.
+        assert_eq!(count(&mask, MaskPixel::Background), 24);
+        assert_eq!(mask[3 * WIDTH + 10], MaskPixel::Background);
+        assert_eq!(count(&mask, MaskPixel::Center), 1);
+        assert_eq!(mask[17 * WIDTH + 20], MaskPixel::Center);
+
+        // Every ring pixel is either kept or rejected, in the configured proportion.
+        let kept = count(&mask, MaskPixel::Kept);
+        let rejected = count(&mask, MaskPixel::Rejected);
+        assert!(kept > 0 && rejected > 0);
+        let ring = kept + rejected;
+        let percent = AnalysisConfig::default().filter_percent as usize;
+        assert_eq!(kept, ring * percent / 100);
+
+        // Window pixels under the ring or center are overwritten; the rest remain.
+        assert!(count(&mask, MaskPixel::SearchWindow) > 0);
+        assert_eq!(mask[0], MaskPixel::Unused);
+    }
+
+    #[test]
+    fn test_mask_kept_when_rejected() {
+        let config = AnalysisConfig {
+            min_pixel_count: u32::MAX,
+            ..Default::default()
+        };
+        let mut mask = vec![0; WIDTH * HEIGHT];
+        let frame = frame_with_bead(
+            BACKGROUND,
+            Rgb {
+                r: 20,
+                g: 40,
+                b: 200,
+            },
+        );
+        assert!(
+            analyze_image_debug(&frame, WIDTH, HEIGHT, Some(&mut mask), None, config).is_none()
+        );
+        // A bead turned away by a gate still shows how far the analysis got.
+        assert!(mask.contains(&(MaskPixel::Kept as u8)));
+        assert!(mask.contains(&(MaskPixel::Center as u8)));
+    }
+
+    #[test]
+    fn test_mask_overwrites_stale_values() {
+        let (_, mask) = mask_of(&empty_frame(BACKGROUND));
+        assert_eq!(count(&mask, MaskPixel::Background), 24);
+        assert_eq!(mask[WIDTH * HEIGHT - 1], MaskPixel::Unused);
+    }
+}
+
+mod multi_frame {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_with_bead};
+    use sorter_logic::{
+        BeadAnalysis, BeadFinish, FrameAccumulator, Rgb, analyze_frames, analyze_image,
+    };
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    const BLUE: Rgb = Rgb {
+        r: 20,
+        g: 40,
+        b: 200,
+    };
+    const GREEN: Rgb = Rgb {
+        r: 30,
+        g: 180,
+        b: 40,
+    };
+
+    #[test]
+    fn test_fused_frames_match_single_frame() {
+        let frame = frame_with_bead(BACKGROUND, RED);
+        let single = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        let fused = analyze_frames(&[&frame, &frame, &frame], WIDTH, HEIGHT).unwrap();
+        assert_eq!(fused.average_color, single.average_color);
+    }
+
+    #[test]
+    fn test_disagreeing_frame_is_rejected() {
+        let red = frame_with_bead(BACKGROUND, RED);
+        let blue = frame_with_bead(BACKGROUND, BLUE);
+        let single = analyze_image(&red, WIDTH, HEIGHT).unwrap();
+        let fused = analyze_frames(&[&red, &blue, &red], WIDTH, HEIGHT).unwrap();
+        assert_eq!(fused.average_color, single.average_color);
+    }
+
+    #[test]
+    fn test_no_consensus() {
+        let red = frame_with_bead(BACKGROUND, RED);
+        let blue = frame_with_bead(BACKGROUND, BLUE);
+        let green = frame_with_bead(BACKGROUND, GREEN);
+        assert!(analyze_frames(&[&red, &blue, &green], WIDTH, HEIGHT).is_none());
+        assert!(analyze_frames(&[], WIDTH, HEIGHT).is_none());
+    }
+
+    #[test]
+    fn test_accumulator_capacity() {
+        let red = analyze_image(&frame_with_bead(BACKGROUND, RED), WIDTH, HEIGHT).unwrap();
+        let blue = analyze_image(&frame_with_bead(BACKGROUND, BLUE), WIDTH, HEIGHT).unwrap();
+
+        let mut acc: FrameAccumulator<4> = FrameAccumulator::new();
+        assert!(acc.fuse(100).is_none());
+        acc.push(red);
+        acc.push(blue);
+        acc.push(blue);
+        acc.push(red);
+        assert!(!acc.push(red)); // Full
+        // Two agree with the medoid, two don't: exactly half is still accepted.
+        assert!(acc.fuse(100).is_some());
+    }
+
+    #[test]
+    fn test_failed_frames_count_as_disagreeing() {
+        let red = frame_with_bead(BACKGROUND, RED);
+        // Cut short, so nothing is found in it.
+        let cut = &red[..red.len() / 2];
+        assert!(analyze_frames(&[&red, cut, cut], WIDTH, HEIGHT).is_none());
+        assert!(analyze_frames(&[&red, cut, &red], WIDTH, HEIGHT).is_some());
+
+        let mut acc: FrameAccumulator<4> = FrameAccumulator::new();
+        acc.push(analyze_image(&red, WIDTH, HEIGHT).unwrap());
+        acc.push_failed();
+        assert_eq!(acc.len(), 2);
+        assert!(acc.fuse(100).is_some());
+        acc.push_failed();
+        assert!(acc.fuse(100).is_none());
+        acc.clear();
+        acc.push_failed();
+        assert!(acc.fuse(100).is_none());
+    }
+
+    #[test]
+    fn test_fused_shape_and_finish_come_from_the_kept_frames() {
+        let red = analyze_image(&frame_with_bead(BACKGROUND, RED), WIDTH, HEIGHT).unwrap();
+        let blue = analyze_image(&frame_with_bead(BACKGROUND, BLUE), WIDTH, HEIGHT).unwrap();
+        let shot = |finish, sparkle, diameter_px, diameter_mm| BeadAnalysis {
+            finish,
+            sparkle,
+            diameter_px,
+            diameter_mm,
+            eccentricity: diameter_px / 100.0,
+            ..red
+        };
+        let mut acc: FrameAccumulator<4> = FrameAccumulator::new();
+        acc.push(shot(BeadFinish::Glitter, 10, 10.0, Some(2.0)));
+        acc.push(shot(BeadFinish::Opaque, 0, 12.0, None));
+        acc.push(shot(BeadFinish::Glitter, 20, 14.0, Some(3.0)));
+        // Dropped as an outlier, whatever it measured.
+        acc.push(BeadAnalysis {
+            diameter_px: 40.0,
+            ..blue
+        });
+
+        let fused = acc.fuse(100).unwrap();
+        assert_eq!(fused.finish, BeadFinish::Glitter);
+        assert_eq!(fused.sparkle, 10);
+        assert_eq!(fused.diameter_px, 12.0);
+        assert_eq!(fused.diameter_mm, Some(2.5));
+        assert!((fused.eccentricity - 0.12).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_captured_outlier_frame_is_dropped() {
+        let black = common::captures("sorted/black");
+        let white = common::captures("sorted/white");
+        let fused = analyze_frames(&[&black[0], &black[1]], WIDTH, HEIGHT).unwrap();
+        let with_outlier = analyze_frames(&[&black[0], &white[0], &black[1]], WIDTH, HEIGHT);
+        assert_eq!(with_outlier.unwrap().average_color, fused.average_color);
+    }
+}
+
+mod ring {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_with_bead};
+    use sorter_logic::{AnalysisConfig, BeadAnalysis, Rgb, analyze_image_debug};
+
+    const GREEN: Rgb = Rgb {
+        r: 30,
+        g: 180,
+        b: 60,
+    };
+
+    fn analyze(ring_inner: i32, ring_outer: i32) -> BeadAnalysis {
+        let config = AnalysisConfig {
+            filter_percent: 100,
+            ring_inner,
+            ring_outer,
+            ..Default::default()
+        };
+        let frame = frame_with_bead(BACKGROUND, GREEN);
+        analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, config).unwrap()
+    }
+
+    #[test]
+    fn test_smaller_ring_reads_fewer_pixels() {
+        let default = analyze(3, 7);
+        let small = analyze(2, 5);
+        assert!(small.pixel_count < default.pixel_count);
+        assert_eq!(small.average_color, default.average_color);
+    }
+
+    #[test]
+    fn test_ring_past_the_bead_takes_in_background() {
+        assert_eq!(analyze(3, 7).confidence, 100);
+        assert!(analyze(3, 9).confidence < 100);
+    }
+}
+
+mod sparkle {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_painted, frame_with_bead};
+    use sorter_logic::{BeadFinish, Rgb, analyze_image};
+
+    const BLUE: Rgb = Rgb {
+        r: 20,
+        g: 40,
+        b: 200,
+    };
+    const FLECK: Rgb = Rgb {
+        r: 250,
+        g: 250,
+        b: 240,
+    };
+
+    #[test]
+    fn test_plain_bead_has_no_sparkle() {
+        let a = analyze_image(&frame_with_bead(BACKGROUND, BLUE), WIDTH, HEIGHT).unwrap();
+        assert_eq!(a.sparkle, 0);
+    }
+
+    #[test]
+    fn test_captured_beads_have_no_sparkle() {
+        // None of the captured runs are glitter beads.
+        for folder in ["sorted/black", "sorted/gray", "sorted/white"] {
+            for frame in common::captures(folder) {
+                if let Some(a) = analyze_image(&frame, WIDTH, HEIGHT) {
+                    assert_eq!(a.sparkle, 0, "{}", folder);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_isolated_flecks_are_counted() {
+        // Flecks on the ring, none touching another.
+        let flecks = [(5, 0), (-5, 0), (0, 5), (0, -5), (4, 4), (-4, -4)];
+        let frame = frame_painted(BACKGROUND, |dx, dy| {
+            if flecks.contains(&(dx, dy)) {
+                FLECK
+            } else {
+                BLUE
+            }
+        });
+        let a = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        assert_eq!(a.sparkle, flecks.len() as u32);
+        assert_eq!(a.finish, BeadFinish::Glitter);
+        // Flecks are dropped by the outlier filter, so the base color is unaffected.
+        assert!(a.average_color.dist_lab(&BLUE) < 30);
+    }
+
+    #[test]
+    fn test_bright_patch_is_not_sparkle() {
+        // A single large highlight is not glitter.
+        let frame = frame_painted(BACKGROUND, |dx, dy| {
+            if (3..=6).contains(&dx) && (-1..=1).contains(&dy) {
+                FLECK
+            } else {
+                BLUE
+            }
+        });
+        let a = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        assert_eq!(a.sparkle, 0);
+    }
+}
+
+mod subsample {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_painted, frame_with_bead};
+    use sorter_logic::subsample::{Subsample, XorShift32};
+    use sorter_logic::{AnalysisConfig, Rgb, analyze_image, analyze_image_debug};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+
+    fn budget(pixels: u16) -> AnalysisConfig {
+        AnalysisConfig {
+            pixel_budget: Some(pixels),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_picks_one_per_run_in_order() {
+        for (total, budget) in [(1000, 1024), (100_000, 1024), (125, 32), (7, 3)] {
+            let picks: Vec<u32> = Subsample::new(total, budget, 42).collect();
+            assert_eq!(picks.len() as u32, budget.min(total));
+            assert!(picks.windows(2).all(|w| w[0] < w[1]));
+            assert!(picks.iter().all(|&i| i < total));
+            // Same seed, same picks.
+            assert_eq!(picks, Subsample::new(total, budget, 42).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_xorshift_below_stays_in_range() {
+        let mut rng = XorShift32::new(0);
+        let mut seen = [false; 10];
+        for _ in 0..1000 {
+            let v = rng.below(10);
+            assert!(v < 10);
+            seen[v as usize] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+        assert_eq!(rng.below(0), 0);
+    }
+
+    #[test]
+    fn test_budget_limits_pixels_read() {
+        let frame = frame_with_bead(BACKGROUND, RED);
+        let full = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        let thin = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, budget(32)).unwrap();
+        // 60% of the 32 sampled ring pixels are kept.
+        assert!(thin.pixel_count <= 32, "{}", thin.pixel_count);
+        assert!(thin.pixel_count < full.pixel_count);
+        assert_eq!(thin.center, full.center);
+        assert!(thin.average_color.dist(&full.average_color) < 50);
+    }
+
+    #[test]
+    fn test_budget_is_deterministic_and_spread() {
+        // Left half red, right half blue: a sample bunched on one side would skew the color.
+        let frame = frame_painted(BACKGROUND, |dx, _| {
+            if dx < 0 {
+                RED
+            } else {
+                Rgb {
+                    r: 20,
+                    g: 40,
+                    b: 200,
+                }
+            }
+        });
+        let config = AnalysisConfig {
+            filter_percent: 100,
+            ..budget(40)
+        };
+        let a = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, config).unwrap();
+        let b = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, config).unwrap();
+        assert_eq!(a, b);
+        let full = analyze_image_debug(
+            &frame,
+            WIDTH,
+            HEIGHT,
+            None,
+            None,
+            AnalysisConfig {
+                filter_percent: 100,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(
+            a.average_color.dist(&full.average_color) < 300,
+            "{:?} {:?}",
+            a.average_color,
+            full.average_color
+        );
+    }
+
+    #[test]
+    fn test_budget_above_ring_size_changes_nothing() {
+        let frame = frame_with_bead(BACKGROUND, RED);
+        let full = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        let wide = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, budget(1024)).unwrap();
+        assert_eq!(wide, full);
+    }
+}
+
+mod warm_start {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, empty_frame, frame_with_bead_at};
+    use sorter_logic::{AnalysisConfig, Rgb, analyze_image, analyze_image_debug};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+
+    fn warm(center: (i32, i32)) -> AnalysisConfig {
+        AnalysisConfig {
+            warm_start: Some(center),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_reports_center() {
+        let frame = frame_with_bead_at(BACKGROUND, RED, 21, 17);
+        let cold = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        assert_eq!(cold.center, (21, 17));
+    }
+
+    #[test]
+    fn test_warm_start_matches_cold_search() {
+        let frame = frame_with_bead_at(BACKGROUND, RED, 21, 17);
+        let cold = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        let warm =
+            analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, warm(cold.center)).unwrap();
+        assert_eq!(warm, cold);
+    }
+
+    #[test]
+    fn test_stale_center_falls_back_to_full_search() {
+        // Previous bead was at the far left; this one landed on the right.
+        let frame = frame_with_bead_at(BACKGROUND, RED, 23, 17);
+        let cold = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        let warm = analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, warm((16, 16))).unwrap();
+        assert_eq!(warm, cold);
+        assert_eq!(warm.center, (23, 17));
+    }
+
+    #[test]
+    fn test_warm_start_matches_cold_search_on_captures() {
+        for folder in ["sorted/clear", "sorted/dark-purple", "sorted/white"] {
+            for frame in common::captures(folder) {
+                let Some(cold) = analyze_image(&frame, WIDTH, HEIGHT) else {
+                    continue;
+                };
+                let warm =
+                    analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, warm(cold.center));
+                assert_eq!(warm, Some(cold), "{}", folder);
+            }
+        }
+    }
+
+    #[test]
+    fn test_warm_start_empty_frame() {
+        let frame = empty_frame(BACKGROUND);
+        assert_eq!(
+            analyze_image(&frame, WIDTH, HEIGHT),
+            analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, warm((20, 17)))
+        );
+    }
+}
+
+mod hdr {
+    use sorter_logic::{Rgb, decode_rgb565_be, hdr};
+
+    fn frame(pixels: &[Rgb]) -> Vec<u8> {
+        pixels
+            .iter()
+            .flat_map(|p| p.to_rgb565().to_be_bytes())
+            .collect()
+    }
+
+    fn gray(v: u8) -> Rgb {
+        Rgb { r: v, g: v, b: v }
+    }
+
+    #[test]
+    fn test_shadows_are_stretched() {
+        // Black and navy: a short frame barely tells them apart.
+        let mut short = frame(&[gray(8), Rgb { r: 8, g: 8, b: 16 }]);
+        let long = frame(&[
+            gray(33),
+            Rgb {
+                r: 33,
+                g: 33,
+                b: 66,
+            },
+        ]);
+        hdr::merge(&mut short, &long);
+        let merged: Vec<Rgb> = decode_rgb565_be(&short).collect();
+        // Further apart than at the locked exposure's 16 and 33.
+        assert!(merged[1].b - merged[0].b > 17, "{merged:?}");
+    }
+
+    #[test]
+    fn test_clipped_pixels_come_from_the_short_frame() {
+        // White and pearl: the long frame clips both.
+        let mut short = frame(&[
+            gray(120),
+            Rgb {
+                r: 110,
+                g: 105,
+                b: 100,
+            },
+        ]);
+        let long = frame(&[gray(255), gray(255)]);
+        hdr::merge(&mut short, &long);
+        let merged: Vec<Rgb> = decode_rgb565_be(&short).collect();
+        assert!(merged[0].r > merged[1].r, "{merged:?}");
+        assert!(merged[1].r > merged[1].b, "{merged:?}");
+        assert!(merged[0].r < 255, "{merged:?}");
+    }
+
+    #[test]
+    fn test_fusion_keeps_the_order_of_brightness() {
+        let levels: Vec<u8> = (0..=255).step_by(4).map(|v| v as u8).collect();
+        let mut short = frame(&levels.iter().map(|&v| gray(v / 2)).collect::<Vec<_>>());
+        let long = frame(
+            &levels
+                .iter()
+                .map(|&v| gray(v.saturating_mul(2)))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(hdr::merge(&mut short, &long), levels.len());
+        let merged: Vec<u8> = decode_rgb565_be(&short).map(|p| p.g).collect();
+        assert!(merged.windows(2).all(|w| w[0] <= w[1]), "{merged:?}");
+        assert_eq!(merged[0], 0);
+    }
+
+    #[test]
+    fn test_merges_what_both_frames_hold() {
+        let mut short = frame(&[gray(10); 3]);
+        let long = frame(&[gray(40); 2]);
+        let untouched = short[4..].to_vec();
+        assert_eq!(hdr::merge(&mut short, &long), 2);
+        assert_eq!(short[4..], untouched);
+    }
+}
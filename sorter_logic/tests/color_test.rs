@@ -0,0 +1,537 @@
+mod common;
+
+mod rgb565 {
+    use sorter_logic::{Rgb, decode_rgb565_be, decode_rgb565_be_into};
+
+    #[test]
+    fn test_rgb565_expansion_is_exact() {
+        for p in 0..=u16::MAX {
+            let r = (p >> 11) & 0x1F;
+            let g = (p >> 5) & 0x3F;
+            let b = p & 0x1F;
+            let expected = Rgb {
+                r: (r * 255 / 31) as u8,
+                g: (g * 255 / 63) as u8,
+                b: (b * 255 / 31) as u8,
+            };
+            assert_eq!(Rgb::from_rgb565(p), expected, "{:#06x}", p);
+        }
+    }
+
+    #[test]
+    fn test_decode_frame() {
+        let pixels: Vec<u16> = (0..1200u32).map(|i| (i * 53) as u16).collect();
+        let data: Vec<u8> = pixels.iter().flat_map(|p| p.to_be_bytes()).collect();
+
+        let decoded: Vec<Rgb> = decode_rgb565_be(&data).collect();
+        let expected: Vec<Rgb> = pixels.iter().map(|&p| Rgb::from_rgb565(p)).collect();
+        assert_eq!(decoded, expected);
+
+        // A trailing odd byte is not a pixel.
+        assert_eq!(decode_rgb565_be(&data[..5]).count(), 2);
+
+        let mut buffer = [Rgb { r: 0, g: 0, b: 0 }; 1500];
+        assert_eq!(decode_rgb565_be_into(&data, &mut buffer), 1200);
+        assert_eq!(buffer[..1200], expected[..]);
+        let mut small = [Rgb { r: 0, g: 0, b: 0 }; 10];
+        assert_eq!(decode_rgb565_be_into(&data, &mut small), 10);
+        assert_eq!(small[..], expected[..10]);
+    }
+
+    #[test]
+    fn test_rgb565_encoding_undoes_expansion() {
+        for p in (0..=u16::MAX).step_by(7) {
+            assert_eq!(Rgb::from_rgb565(p).to_rgb565(), p, "{:#06x}", p);
+        }
+        assert_eq!(Rgb::from_rgb565(0xFFFF).to_rgb565(), 0xFFFF);
+    }
+}
+
+mod hue {
+    use sorter_logic::{ColorMetric, Palette, PaletteMatch, Rgb};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    // Same bead under dimmer light.
+    const DIM_RED: Rgb = Rgb {
+        r: 150,
+        g: 15,
+        b: 22,
+    };
+    const ORANGE: Rgb = Rgb {
+        r: 220,
+        g: 110,
+        b: 20,
+    };
+
+    #[test]
+    fn test_to_hsv() {
+        assert_eq!(Rgb { r: 255, g: 0, b: 0 }.to_hsv(), (0, 255, 255));
+        assert_eq!(Rgb { r: 0, g: 255, b: 0 }.to_hsv(), (120, 255, 255));
+        assert_eq!(Rgb { r: 0, g: 0, b: 255 }.to_hsv(), (240, 255, 255));
+        assert_eq!(
+            Rgb {
+                r: 100,
+                g: 100,
+                b: 100
+            }
+            .to_hsv(),
+            (0, 0, 100)
+        );
+        // Magenta-ish red wraps around to the top of the hue circle.
+        assert_eq!(RED.to_hsv().0, 357);
+    }
+
+    #[test]
+    fn test_hue_distance_ignores_brightness() {
+        assert!(RED.dist_hue(&DIM_RED) < 15);
+        assert!(RED.dist_lab(&DIM_RED) > 15);
+        assert!(RED.dist_hue(&ORANGE) > 100);
+    }
+
+    #[test]
+    fn test_palette_hue_metric_keeps_one_entry_under_drift() {
+        let mut palette: Palette<4> = Palette::with_metric(ColorMetric::HueWeighted);
+        assert_eq!(palette.match_color(&RED, 0, 15), PaletteMatch::NewEntry(0));
+        assert_eq!(palette.match_color(&DIM_RED, 0, 15), PaletteMatch::Match(0));
+        assert_eq!(
+            palette.match_color(&ORANGE, 0, 15),
+            PaletteMatch::NewEntry(1)
+        );
+
+        let mut lab: Palette<4> = Palette::new();
+        lab.match_color(&RED, 0, 15);
+        assert_eq!(lab.match_color(&DIM_RED, 0, 15), PaletteMatch::NewEntry(1));
+    }
+}
+
+mod oklab {
+    use sorter_logic::{ColorMetric, Palette, PaletteMatch, Rgb};
+
+    #[test]
+    fn test_oklab_reference_points() {
+        assert_eq!(Rgb { r: 0, g: 0, b: 0 }.to_oklab(), (0, 0, 0));
+        assert_eq!(
+            Rgb {
+                r: 255,
+                g: 255,
+                b: 255
+            }
+            .to_oklab(),
+            (100, 0, 0)
+        );
+        // sRGB red is (0.628, 0.225, 0.126) in OKLab.
+        assert_eq!(Rgb { r: 255, g: 0, b: 0 }.to_oklab(), (63, 22, 13));
+    }
+
+    // Ottosson's conversion in f64 with std's powf and cbrt.
+    fn reference(c: Rgb) -> (i32, i32, i32) {
+        let linear = |c: u8| {
+            let c = c as f64 / 255.0;
+            if c > 0.04045 {
+                ((c + 0.055) / 1.055).powf(2.4)
+            } else {
+                c / 12.92
+            }
+        };
+        let (r, g, b) = (linear(c.r), linear(c.g), linear(c.b));
+        let l = (0.412_221_470_8 * r + 0.536_332_536_3 * g + 0.051_445_992_9 * b).cbrt();
+        let m = (0.211_903_498_2 * r + 0.680_699_545_1 * g + 0.107_396_956_6 * b).cbrt();
+        let s = (0.088_302_461_9 * r + 0.281_718_837_6 * g + 0.629_978_700_5 * b).cbrt();
+        let scaled = |v: f64| (v * 100.0).round() as i32;
+        (
+            scaled(0.210_454_255_3 * l + 0.793_617_785 * m - 0.004_072_046_8 * s),
+            scaled(1.977_998_495_1 * l - 2.428_592_205 * m + 0.450_593_709_9 * s),
+            scaled(0.025_904_037_1 * l + 0.782_771_766_2 * m - 0.808_675_766 * s),
+        )
+    }
+
+    #[test]
+    fn test_matches_reference_conversion() {
+        // The lookup table and cube root approximation only move values across a rounding
+        // boundary.
+        for r in (0..=255u8).step_by(5) {
+            for g in (0..=255u8).step_by(5) {
+                for b in (0..=255u8).step_by(5) {
+                    let c = Rgb { r, g, b };
+                    let (got, want) = (c.to_oklab(), reference(c));
+                    let off = [got.0 - want.0, got.1 - want.1, got.2 - want.2];
+                    assert!(
+                        off.iter().all(|d| d.abs() <= 1),
+                        "{:?} {:?} {:?}",
+                        c,
+                        got,
+                        want
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_grays_have_no_chroma() {
+        for v in [32u8, 96, 128, 200] {
+            let (_, a, b) = Rgb { r: v, g: v, b: v }.to_oklab();
+            assert!(
+                a.abs() <= 1 && b.abs() <= 1,
+                "gray {} -> a={} b={}",
+                v,
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn test_palette_with_oklab_metric() {
+        let mut palette: Palette<4> = Palette::with_metric(ColorMetric::OkLab);
+        assert_eq!(palette.metric(), ColorMetric::OkLab);
+
+        let pink = Rgb {
+            r: 250,
+            g: 180,
+            b: 200,
+        };
+        let pink2 = Rgb {
+            r: 245,
+            g: 178,
+            b: 196,
+        };
+        let peach = Rgb {
+            r: 250,
+            g: 200,
+            b: 160,
+        };
+        assert!(pink.dist_oklab(&pink2) < pink.dist_oklab(&peach));
+
+        assert_eq!(palette.match_color(&pink, 0, 15), PaletteMatch::NewEntry(0));
+        assert_eq!(palette.match_color(&pink2, 0, 15), PaletteMatch::Match(0));
+        assert_eq!(
+            palette.match_color(&peach, 0, 15),
+            PaletteMatch::NewEntry(1)
+        );
+    }
+}
+
+mod dark {
+    use super::common;
+    use sorter_logic::{
+        AnalysisConfig, ColorMetric, Palette, PaletteMatch, Rgb, analyze_image_debug,
+    };
+
+    // Black, navy and dark purple beads as the camera sees them in the slot: dim, with little
+    // chroma left.
+    const BLACK: Rgb = Rgb {
+        r: 22,
+        g: 22,
+        b: 24,
+    };
+    const NAVY: Rgb = Rgb {
+        r: 18,
+        g: 24,
+        b: 40,
+    };
+    const DARK_PURPLE: Rgb = Rgb {
+        r: 34,
+        g: 20,
+        b: 40,
+    };
+
+    // Palette entry each dark color lands in, sorting them one after another.
+    fn entries_for(metric: ColorMetric) -> Vec<usize> {
+        let mut palette: Palette<16> = Palette::with_metric(metric);
+        [BLACK, NAVY, DARK_PURPLE, NAVY, BLACK, DARK_PURPLE]
+            .iter()
+            .map(|rgb| match palette.match_color(rgb, 0, 15) {
+                PaletteMatch::Match(i) | PaletteMatch::NewEntry(i) => {
+                    palette.add_sample(i, rgb, 0);
+                    i
+                }
+                PaletteMatch::Full => panic!("palette full"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dark_colors_collapse_in_lab() {
+        // Navy and dark purple share an entry.
+        assert_eq!(entries_for(ColorMetric::Lab), vec![0, 1, 1, 1, 0, 1]);
+        assert!(NAVY.dist_lab(&DARK_PURPLE) < 15);
+    }
+
+    #[test]
+    fn test_lab_dark_separates_dark_colors() {
+        assert_eq!(entries_for(ColorMetric::LAB_DARK), vec![0, 1, 2, 1, 0, 2]);
+        let metric = ColorMetric::LAB_DARK;
+        for (a, b) in [(BLACK, NAVY), (BLACK, DARK_PURPLE), (NAVY, DARK_PURPLE)] {
+            assert!(
+                a.dist_metric(&b, metric) >= 4 * a.dist_lab(&b),
+                "{:?} {:?}",
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn test_lab_dark_leaves_bright_colors_alone() {
+        let red = Rgb {
+            r: 200,
+            g: 20,
+            b: 30,
+        };
+        let pink = Rgb {
+            r: 230,
+            g: 90,
+            b: 140,
+        };
+        assert_eq!(red.to_lab_dark(35, 3), red.to_lab());
+        assert_eq!(red.dist_lab_dark(&pink, 35, 3), red.dist_lab(&pink));
+        // Gain 1 turns the boost off.
+        assert_eq!(NAVY.to_lab_dark(35, 1), NAVY.to_lab());
+    }
+
+    // Average colors of the captures in one labeled folder of image_data/sorted.
+    fn captured(category: &str) -> Vec<Rgb> {
+        common::captures(&format!("sorted/{}", category))
+            .iter()
+            .filter_map(|frame| {
+                let config = AnalysisConfig::default();
+                analyze_image_debug(frame, common::WIDTH, common::HEIGHT, None, None, config)
+                    .map(|a| a.average_color)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lab_dark_on_captured_dark_beads() {
+        let black = captured("black");
+        let purple = captured("dark-purple");
+        assert_eq!((black.len(), purple.len()), (36, 56));
+
+        // Mean distance between the two colors over the mean distance within each.
+        let separation = |metric: ColorMetric| {
+            let mean = |pairs: Vec<(Rgb, Rgb)>| {
+                let n = pairs.len() as f32;
+                pairs
+                    .iter()
+                    .map(|(a, b)| a.dist_metric(b, metric) as f32)
+                    .sum::<f32>()
+                    / n
+            };
+            let within = |v: &[Rgb]| {
+                let pairs = v
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, &a)| v[i + 1..].iter().map(move |&b| (a, b)));
+                mean(pairs.collect())
+            };
+            let between = black
+                .iter()
+                .flat_map(|&a| purple.iter().map(move |&b| (a, b)));
+            mean(between.collect()) / ((within(&black) + within(&purple)) / 2.0)
+        };
+        // Lab leaves the two colors 3.3 spreads apart; the boost widens that to 3.7.
+        assert_eq!((separation(ColorMetric::Lab) * 10.0) as u32, 33);
+        assert_eq!((separation(ColorMetric::LAB_DARK) * 10.0) as u32, 37);
+    }
+}
+
+#[cfg(feature = "catalog")]
+mod catalog {
+    use sorter_logic::catalog::{Catalog, CatalogError};
+    use sorter_logic::{Palette, Rgb};
+
+    const CHART: &str = "\
+# Colors from a test chart
+White,#F0F0F0
+Red,191,46,64
+Yellow,#ECD800
+
+Dark Blue, 43, 63, 135
+Black,#28282C
+";
+
+    fn chart() -> Catalog {
+        Catalog::parse("Test", CHART).unwrap()
+    }
+
+    #[test]
+    fn test_nearest_catalog_color() {
+        let chart = chart();
+        let red = Rgb {
+            r: 180,
+            g: 50,
+            b: 60,
+        };
+        assert_eq!(chart.nearest(&red).unwrap().color.name, "Red");
+
+        let black = Rgb {
+            r: 40,
+            g: 40,
+            b: 44,
+        };
+        let m = chart.nearest(&black).unwrap();
+        assert_eq!(m.color.name, "Black");
+        assert_eq!(m.dist, 0);
+    }
+
+    #[test]
+    fn test_label_entries() {
+        let chart = chart();
+        let mut palette: Palette<4> = Palette::new();
+        palette.match_color(
+            &Rgb {
+                r: 236,
+                g: 216,
+                b: 0,
+            },
+            0,
+            15,
+        );
+        palette.match_color(
+            &Rgb {
+                r: 43,
+                g: 63,
+                b: 135,
+            },
+            0,
+            15,
+        );
+
+        let labels = palette.label_entries(&chart);
+        assert_eq!(labels[0].unwrap().color.name, "Yellow");
+        assert_eq!(labels[1].unwrap().color.name, "Dark Blue");
+        assert!(labels[2].is_none());
+    }
+
+    #[test]
+    fn test_parse_chart() {
+        let chart = chart();
+        assert_eq!(chart.name, "Test");
+        assert_eq!(chart.colors.len(), 5);
+        let white = Rgb {
+            r: 0xF0,
+            g: 0xF0,
+            b: 0xF0,
+        };
+        assert_eq!(chart.by_name("white").unwrap().lab, white.to_lab());
+        assert!(chart.by_name("Lime").is_none());
+
+        assert_eq!(
+            Catalog::parse("Bad", "White,#F0F0F0\nRed,191,46\n"),
+            Err(CatalogError::BadLine(2))
+        );
+        assert_eq!(
+            Catalog::parse("Bad", ",#F0F0F0"),
+            Err(CatalogError::BadLine(1))
+        );
+        assert_eq!(
+            Catalog::parse("Bad", "Red,#BF2E4"),
+            Err(CatalogError::BadLine(1))
+        );
+        assert_eq!(
+            Catalog::parse("Empty", "# nothing here\n"),
+            Err(CatalogError::Empty)
+        );
+    }
+}
+
+#[cfg(feature = "lab_lut")]
+mod lab_lut {
+    use sorter_logic::{Rgb, Rgb565};
+
+    // The generated table must agree with the float conversion for every pixel value.
+    #[test]
+    fn test_lab_lut_matches_to_lab() {
+        for p in 0..=u16::MAX {
+            assert_eq!(
+                Rgb565(p).to_lab_lut(),
+                Rgb::from_rgb565(p).to_lab(),
+                "{:#06x}",
+                p
+            );
+        }
+    }
+}
+
+mod white_balance {
+    use super::common;
+
+    use common::{HEIGHT, WIDTH, empty_frame, frame_with_bead};
+    use sorter_logic::{AnalysisConfig, Rgb, WhiteBalance, analyze_image_debug};
+
+    const GRAY_CARD: Rgb = Rgb {
+        r: 160,
+        g: 160,
+        b: 160,
+    };
+    const BACKGROUND: Rgb = Rgb {
+        r: 230,
+        g: 230,
+        b: 220,
+    };
+    const BEAD: Rgb = Rgb {
+        r: 60,
+        g: 150,
+        b: 90,
+    };
+
+    // What the camera sees under a color cast.
+    fn tint(c: Rgb, (r, g, b): (f32, f32, f32)) -> Rgb {
+        Rgb {
+            r: (c.r as f32 * r).min(255.0) as u8,
+            g: (c.g as f32 * g).min(255.0) as u8,
+            b: (c.b as f32 * b).min(255.0) as u8,
+        }
+    }
+
+    fn measure(cast: (f32, f32, f32), balance: bool) -> Rgb {
+        let white_balance = if balance {
+            WhiteBalance::from_frame(&empty_frame(tint(GRAY_CARD, cast)), WIDTH, HEIGHT)
+        } else {
+            None
+        };
+        let config = AnalysisConfig {
+            white_balance,
+            ..Default::default()
+        };
+        let frame = frame_with_bead(tint(BACKGROUND, cast), tint(BEAD, cast));
+        analyze_image_debug(&frame, WIDTH, HEIGHT, None, None, config)
+            .unwrap()
+            .average_color
+    }
+
+    #[test]
+    fn test_neutral_card_is_identity() {
+        let wb = WhiteBalance::from_frame(&empty_frame(GRAY_CARD), WIDTH, HEIGHT).unwrap();
+        for gain in [wb.gain_r, wb.gain_g, wb.gain_b] {
+            assert!((gain - 1.0).abs() < 0.05, "{:?}", wb);
+        }
+    }
+
+    #[test]
+    fn test_balanced_sessions_agree() {
+        let warm = (1.15, 1.0, 0.8);
+        let cool = (0.85, 1.0, 1.2);
+
+        let drifted = measure(warm, false).dist_lab(&measure(cool, false));
+        let balanced = measure(warm, true).dist_lab(&measure(cool, true));
+        assert!(drifted > 100, "{}", drifted);
+        // RGB565 quantization leaves a small residual.
+        assert!(balanced < 30, "{}", balanced);
+    }
+
+    #[test]
+    fn test_dark_card_rejected() {
+        let black = Rgb { r: 5, g: 5, b: 5 };
+        assert_eq!(
+            WhiteBalance::from_frame(&empty_frame(black), WIDTH, HEIGHT),
+            None
+        );
+        assert_eq!(WhiteBalance::from_frame(&[0; 10], WIDTH, HEIGHT), None);
+    }
+}
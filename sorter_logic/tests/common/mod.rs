@@ -0,0 +1,86 @@
+#![allow(dead_code)]
+
+use sorter_logic::Rgb;
+
+pub const WIDTH: usize = 40;
+pub const HEIGHT: usize = 30;
+
+pub fn to_rgb565(c: Rgb) -> [u8; 2] {
+    let r = (c.r as u16 * 31) / 255;
+    let g = (c.g as u16 * 63) / 255;
+    let b = (c.b as u16 * 31) / 255;
+    ((r << 11) | (g << 5) | b).to_be_bytes()
+}
+
+/// 40x30 RGB565 frame of `bg` with a bead of color `bead` (outer radius 7) centered at (cx, cy).
+pub fn frame_with_bead_at(bg: Rgb, bead: Rgb, cx: i32, cy: i32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(WIDTH * HEIGHT * 2);
+    for y in 0..HEIGHT as i32 {
+        for x in 0..WIDTH as i32 {
+            let d2 = (x - cx).pow(2) + (y - cy).pow(2);
+            let c = if d2 <= 49 { bead } else { bg };
+            data.extend_from_slice(&to_rgb565(c));
+        }
+    }
+    data
+}
+
+pub fn frame_with_bead(bg: Rgb, bead: Rgb) -> Vec<u8> {
+    frame_with_bead_at(bg, bead, 20, 17)
+}
+
+pub fn empty_frame(bg: Rgb) -> Vec<u8> {
+    frame_with_bead_at(bg, bg, 20, 17)
+}
+
+pub const BACKGROUND: Rgb = Rgb {
+    r: 200,
+    g: 200,
+    b: 190,
+};
+
+/// 40x30 frame of `bg` with a bead (outer radius 7) at (20, 17) whose pixels are chosen by
+/// `paint(dx, dy)`, relative to the center.
+pub fn frame_painted(bg: Rgb, paint: impl Fn(i32, i32) -> Rgb) -> Vec<u8> {
+    let mut data = Vec::with_capacity(WIDTH * HEIGHT * 2);
+    for y in 0..HEIGHT as i32 {
+        for x in 0..WIDTH as i32 {
+            let (dx, dy) = (x - 20, y - 17);
+            let c = if dx * dx + dy * dy <= 49 {
+                paint(dx, dy)
+            } else {
+                bg
+            };
+            data.extend_from_slice(&to_rgb565(c));
+        }
+    }
+    data
+}
+
+/// The captures in one folder of `image_data` (e.g. `sorted/black`, `full_sorted/empty`), as
+/// RGB565 frames, in file name order.
+pub fn captures(folder: &str) -> Vec<Vec<u8>> {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("image_data")
+        .join(folder);
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("{:?}: {}", dir, e))
+        .map(|e| e.unwrap().path())
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| {
+            let img = image::open(path).unwrap().into_rgb8();
+            img.pixels()
+                .flat_map(|p| {
+                    to_rgb565(Rgb {
+                        r: p[0],
+                        g: p[1],
+                        b: p[2],
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
@@ -0,0 +1,1096 @@
+mod common;
+
+mod cycle {
+    use std::cell::RefCell;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    use sorter_logic::cycle::{
+        AGITATION_OFFSETS_US, Action, CycleConfig, CycleEnd, CycleError, Event, HopperMove,
+        Inspection, RETAKE_NUDGE_US, SorterFsm, State,
+    };
+    use sorter_logic::hardware::{BeadCamera, Positioner};
+    use sorter_logic::layout::TubeLayout;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Call {
+        Hopper(u16, HopperMove),
+        Pose(u16, u16),
+        Settle(u32),
+        Capture(u8),
+    }
+
+    struct MockServos<'a> {
+        calls: &'a RefCell<Vec<Call>>,
+        // An emergency stop after this many calls.
+        stop_after: Option<usize>,
+    }
+
+    impl Positioner for MockServos<'_> {
+        async fn move_hopper(&mut self, us: u16, how: HopperMove) {
+            self.calls.borrow_mut().push(Call::Hopper(us, how));
+        }
+
+        async fn move_to_pose(&mut self, hopper: u16, chutes: u16) {
+            self.calls.borrow_mut().push(Call::Pose(hopper, chutes));
+        }
+
+        async fn settle(&mut self, ms: u32) {
+            self.calls.borrow_mut().push(Call::Settle(ms));
+        }
+
+        fn is_stopped(&self) -> bool {
+            self.stop_after
+                .is_some_and(|n| self.calls.borrow().len() >= n)
+        }
+    }
+
+    struct MockCamera<'a> {
+        calls: &'a RefCell<Vec<Call>>,
+        // What each photo shows, in order; the last repeats.
+        photos: Vec<Inspection>,
+    }
+
+    impl BeadCamera for MockCamera<'_> {
+        async fn capture(&mut self, retake: u8) -> Inspection {
+            self.calls.borrow_mut().push(Call::Capture(retake));
+            let photo = self.photos[0];
+            if self.photos.len() > 1 {
+                self.photos.remove(0);
+            }
+            photo
+        }
+    }
+
+    // The mocks never wait, so one poll finishes a drive.
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("a mock waited"),
+        }
+    }
+
+    struct Run {
+        end: CycleEnd,
+        calls: Vec<Call>,
+        classified: bool,
+    }
+
+    impl Run {
+        fn hopper_moves(&self, how: HopperMove) -> Vec<u16> {
+            self.calls
+                .iter()
+                .filter_map(|&c| match c {
+                    Call::Hopper(us, h) if h == how => Some(us),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    fn config() -> CycleConfig {
+        CycleConfig {
+            pickup_us: 1500,
+            camera_us: 1900,
+            drop_us: 1200,
+            agitation_level: 0,
+            retakes: 0,
+            layout: TubeLayout::default(),
+        }
+    }
+
+    fn run(
+        fsm: &mut SorterFsm,
+        config: CycleConfig,
+        photos: &[Inspection],
+        tube: u8,
+        stop_after: Option<usize>,
+    ) -> Run {
+        let calls = RefCell::new(Vec::new());
+        let mut servos = MockServos {
+            calls: &calls,
+            stop_after,
+        };
+        let mut camera = MockCamera {
+            calls: &calls,
+            photos: photos.to_vec(),
+        };
+        let mut classified = false;
+        let end = block_on(fsm.run(config, &mut servos, &mut camera, &mut || {
+            classified = true;
+            tube
+        }));
+        Run {
+            end,
+            calls: calls.into_inner(),
+            classified,
+        }
+    }
+
+    const CLEAR: Inspection = Inspection::Bead { doubtful: false };
+    const DOUBTFUL: Inspection = Inspection::Bead { doubtful: true };
+
+    #[test]
+    fn test_cycle_drops_the_bead_in_its_tube() {
+        let mut fsm = SorterFsm::new();
+        let run = run(&mut fsm, config(), &[CLEAR], 16, None);
+        assert_eq!(run.end, CycleEnd::Dropped { tube: 16 });
+        assert_eq!(fsm.state(), State::Idle);
+
+        let spot = TubeLayout::default().locate(16).unwrap();
+        let agitation = [1250, 1750, 1350, 1650, 1425, 1575, 1500];
+        let mut expected: Vec<_> = agitation
+            .iter()
+            .map(|&us| Call::Hopper(us, HopperMove::Agitate))
+            .collect();
+        expected.extend([
+            Call::Settle(100),
+            Call::Hopper(1900, HopperMove::Approach),
+            Call::Settle(200),
+            Call::Capture(0),
+            Call::Pose(spot.drop_position, spot.chute_position),
+            Call::Settle(200),
+            Call::Hopper(1200, HopperMove::Release),
+            Call::Settle(150),
+        ]);
+        assert_eq!(run.calls, expected);
+    }
+
+    #[test]
+    fn test_agitation_widens_with_the_level() {
+        let mut fsm = SorterFsm::new();
+        let config = CycleConfig {
+            agitation_level: 2,
+            ..config()
+        };
+        let run = run(&mut fsm, config, &[CLEAR], 0, None);
+        let wide = AGITATION_OFFSETS_US[0];
+        assert_eq!(
+            run.hopper_moves(HopperMove::Agitate)[..6],
+            [
+                1500 - wide,
+                1500 + wide,
+                1500 - wide,
+                1500 + wide,
+                1250,
+                1750
+            ]
+        );
+        assert_eq!(run.hopper_moves(HopperMove::Agitate).len(), 11);
+    }
+
+    #[test]
+    fn test_doubtful_bead_is_retaken_then_sorted_anyway() {
+        let mut fsm = SorterFsm::new();
+        let config = CycleConfig {
+            retakes: 2,
+            ..config()
+        };
+
+        // Clear on the first retake.
+        let run_1 = run(&mut fsm, config, &[DOUBTFUL, CLEAR], 3, None);
+        assert_eq!(run_1.end, CycleEnd::Dropped { tube: 3 });
+        let captures: Vec<_> = run_1
+            .calls
+            .iter()
+            .filter(|c| matches!(c, Call::Capture(_)))
+            .collect();
+        assert_eq!(captures, [&Call::Capture(0), &Call::Capture(1)]);
+        assert_eq!(
+            run_1.hopper_moves(HopperMove::Approach),
+            [1900, 1900 - RETAKE_NUDGE_US, 1900]
+        );
+
+        // Never clear: out of retakes, sorted as it is.
+        let run_2 = run(&mut fsm, config, &[DOUBTFUL], 3, None);
+        assert!(run_2.calls.contains(&Call::Capture(2)));
+        assert!(!run_2.calls.contains(&Call::Capture(3)));
+        assert!(run_2.classified);
+    }
+
+    #[test]
+    fn test_empty_slot_and_missing_frame_end_before_classifying() {
+        let mut fsm = SorterFsm::new();
+        let empty = run(&mut fsm, config(), &[Inspection::Empty], 0, None);
+        assert_eq!(empty.end, CycleEnd::Empty);
+        assert!(!empty.classified);
+        assert_eq!(empty.calls.last(), Some(&Call::Capture(0)));
+
+        let no_frame = run(&mut fsm, config(), &[Inspection::NoFrame], 0, None);
+        assert_eq!(no_frame.end, CycleEnd::Failed(CycleError::NoFrame));
+        assert_eq!(fsm.state(), State::Error(CycleError::NoFrame));
+        assert!(!no_frame.classified);
+    }
+
+    #[test]
+    fn test_emergency_stop_abandons_the_cycle() {
+        let mut fsm = SorterFsm::new();
+        let run = run(&mut fsm, config(), &[CLEAR], 0, Some(3));
+        assert_eq!(run.end, CycleEnd::Failed(CycleError::Stopped));
+        assert_eq!(run.calls.len(), 3);
+        assert!(!run.classified);
+    }
+
+    #[test]
+    fn test_tube_outside_the_layout_is_not_delivered() {
+        let mut fsm = SorterFsm::new();
+        let run = run(&mut fsm, config(), &[CLEAR], 30, None);
+        assert_eq!(run.end, CycleEnd::Failed(CycleError::NotInLayout(30)));
+        assert!(!run.calls.iter().any(|c| matches!(c, Call::Pose(..))));
+    }
+
+    #[test]
+    fn test_drive_hands_back_for_a_tube_then_finishes() {
+        let calls = RefCell::new(Vec::new());
+        let mut servos = MockServos {
+            calls: &calls,
+            stop_after: None,
+        };
+        let mut camera = MockCamera {
+            calls: &calls,
+            photos: vec![CLEAR],
+        };
+        let mut fsm = SorterFsm::new();
+        let start = fsm.start(config());
+        let action = block_on(fsm.drive(start, &mut servos, &mut camera));
+        assert_eq!(action, Action::Classify);
+        assert_eq!(calls.borrow().last(), Some(&Call::Capture(0)));
+
+        let delivered = fsm.next(Event::Classified(3));
+        let action = block_on(fsm.drive(delivered, &mut servos, &mut camera));
+        assert_eq!(action, Action::Finish(CycleEnd::Dropped { tube: 3 }));
+    }
+}
+
+mod button {
+    use sorter_logic::button::{DOUBLE_PRESS_MS, Gesture, GestureDetector, LONG_PRESS_MS};
+
+    #[test]
+    fn test_short_press_waits_out_the_double_window() {
+        let mut b = GestureDetector::default();
+        assert_eq!(b.press(1000), None);
+        assert_eq!(b.release(1100), None);
+        assert_eq!(b.deadline(), Some(1100 + DOUBLE_PRESS_MS));
+        assert_eq!(b.poll(1100 + DOUBLE_PRESS_MS - 1), None);
+        assert_eq!(b.poll(1100 + DOUBLE_PRESS_MS), Some(Gesture::Short));
+        assert_eq!(b.deadline(), None);
+    }
+
+    #[test]
+    fn test_double_press() {
+        let mut b = GestureDetector::default();
+        b.press(0);
+        b.release(80);
+        assert_eq!(b.press(200), None);
+        assert_eq!(b.release(280), None);
+        // Decided once no third press follows.
+        assert_eq!(b.poll(280 + DOUBLE_PRESS_MS), Some(Gesture::Double));
+        assert_eq!(b.poll(10_000), None);
+    }
+
+    #[test]
+    fn test_triple_press_fires_on_the_third_press() {
+        let mut b = GestureDetector::default();
+        b.press(0);
+        b.release(80);
+        b.press(200);
+        b.release(280);
+        assert_eq!(b.press(400), Some(Gesture::Triple));
+        // Neither its release nor holding it fires anything else.
+        assert_eq!(b.poll(400 + LONG_PRESS_MS), None);
+        assert_eq!(b.release(400 + LONG_PRESS_MS), None);
+        assert_eq!(b.poll(10_000), None);
+        assert_eq!(b.deadline(), None);
+    }
+
+    #[test]
+    fn test_long_press_fires_while_held() {
+        let mut b = GestureDetector::default();
+        b.press(0);
+        assert_eq!(b.deadline(), Some(LONG_PRESS_MS));
+        assert_eq!(b.poll(LONG_PRESS_MS), Some(Gesture::Long));
+        assert_eq!(b.poll(LONG_PRESS_MS * 3), None);
+        assert_eq!(b.release(LONG_PRESS_MS * 3), None);
+        assert_eq!(b.poll(LONG_PRESS_MS * 5), None);
+
+        // A tap then a held press is a long press, not a double.
+        b.press(10_000);
+        b.release(10_050);
+        b.press(10_200);
+        assert_eq!(b.poll(10_200 + LONG_PRESS_MS), Some(Gesture::Long));
+        assert_eq!(b.release(10_200 + LONG_PRESS_MS + 10), None);
+    }
+
+    #[test]
+    fn test_late_second_press_is_two_short_presses() {
+        let mut b = GestureDetector::default();
+        b.press(0);
+        b.release(50);
+        // Not polled in time: the first press is reported on the next edge.
+        assert_eq!(b.press(50 + DOUBLE_PRESS_MS + 100), Some(Gesture::Short));
+        assert_eq!(b.release(50 + DOUBLE_PRESS_MS + 150), None);
+        assert_eq!(b.poll(10_000), Some(Gesture::Short));
+    }
+}
+
+mod motion {
+    use sorter_logic::motion::Trapezoid;
+
+    #[test]
+    fn test_trapezoid_starts_and_ends_gently() {
+        let m = Trapezoid::new(1000, 2000, 8000);
+        let total = m.duration_ms();
+        assert_eq!(m.progress(0), 0.0);
+        // Constant acceleration: after 10ms, 0.5 * 8000 * 0.01² = 0.4us of 1000.
+        assert!((m.progress(10) - 0.0004).abs() < 1e-6);
+        // Symmetric: the end mirrors the start.
+        for t in [10, 100, 200, 300] {
+            assert!((m.progress(t) + m.progress(total - t) - 1.0).abs() < 1e-4);
+        }
+        let mut last = 0.0;
+        for t in 0..=total {
+            let p = m.progress(t);
+            assert!(p >= last);
+            last = p;
+        }
+        assert_eq!(m.progress(total + 100), 1.0);
+    }
+
+    #[test]
+    fn test_short_move_never_reaches_top_speed() {
+        // 100us at 8000us/s²: ramps of sqrt(100 / 8000) s, about 112ms each, no cruise (give or
+        // take micromath's square root).
+        let m = Trapezoid::new(100, 2000, 8000);
+        assert!((222..=226).contains(&m.duration_ms()));
+        assert!((m.progress(m.duration_ms() / 2) - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_zero_length_move_is_done() {
+        let m = Trapezoid::new(0, 2000, 8000);
+        assert_eq!(m.duration_ms(), 0);
+        assert_eq!(m.progress(0), 1.0);
+    }
+}
+
+mod stall {
+    use sorter_logic::stall::{STALL_SAMPLES, StallDetector, sense_mv};
+
+    fn settle(stall: &mut StallDetector, mv: u16, readings: u16) -> bool {
+        (0..readings).any(|_| stall.record(mv, false))
+    }
+
+    #[test]
+    fn test_sense_scales_the_raw_reading() {
+        assert_eq!(sense_mv(0), 0);
+        assert_eq!(sense_mv(2048), 1650);
+        assert_eq!(sense_mv(4095), 3299);
+    }
+
+    #[test]
+    fn test_current_while_moving_is_not_a_stall() {
+        let mut stall = StallDetector::new(Some(400));
+        for _ in 0..STALL_SAMPLES * 3 {
+            assert!(!stall.record(1200, true));
+        }
+    }
+
+    #[test]
+    fn test_stall_is_reported_once_until_rearmed() {
+        let mut stall = StallDetector::new(Some(400));
+        assert!(settle(&mut stall, 500, STALL_SAMPLES));
+        assert!(!settle(&mut stall, 500, STALL_SAMPLES * 2));
+        // A new move rearms it.
+        stall.record(900, true);
+        assert!(settle(&mut stall, 500, STALL_SAMPLES));
+        // So does the current dropping.
+        stall.record(100, false);
+        assert!(settle(&mut stall, 500, STALL_SAMPLES));
+    }
+
+    #[test]
+    fn test_a_dip_restarts_the_count() {
+        let mut stall = StallDetector::new(Some(400));
+        assert!(!settle(&mut stall, 500, STALL_SAMPLES - 1));
+        assert!(!stall.record(399, false));
+        assert!(!settle(&mut stall, 500, STALL_SAMPLES - 1));
+        assert!(stall.record(500, false));
+    }
+
+    #[test]
+    fn test_no_threshold_never_stalls() {
+        let mut stall = StallDetector::new(None);
+        assert!(!settle(&mut stall, u16::MAX, STALL_SAMPLES * 2));
+        stall.set_threshold(Some(400));
+        assert!(settle(&mut stall, 500, STALL_SAMPLES));
+    }
+}
+
+mod supply {
+    use sorter_logic::supply::{RECOVERY_MARGIN_MV, SupplyEvent, SupplyMonitor, vsys_mv};
+
+    #[test]
+    fn test_vsys_scales_the_divided_reading() {
+        assert_eq!(vsys_mv(0), 0);
+        assert_eq!(vsys_mv(4095), 9897);
+        // A 5 V supply reads a little over 1.66 V at the pin.
+        assert_eq!(vsys_mv(2069), 5000);
+    }
+
+    #[test]
+    fn test_sag_is_reported_once_until_recovered_past_the_margin() {
+        let mut supply = SupplyMonitor::new(Some(4300));
+        assert_eq!((supply.latest_mv(), supply.lowest_mv()), (0, 0));
+        assert_eq!(supply.record(4200), SupplyEvent::Sagged);
+        assert_eq!(supply.record(4000), SupplyEvent::None);
+        assert!(supply.is_sagging());
+        assert_eq!(
+            supply.record(4300 + RECOVERY_MARGIN_MV - 1),
+            SupplyEvent::None
+        );
+        assert_eq!(
+            supply.record(4300 + RECOVERY_MARGIN_MV),
+            SupplyEvent::Recovered
+        );
+        assert_eq!((supply.latest_mv(), supply.lowest_mv()), (4400, 4000));
+    }
+
+    #[test]
+    fn test_no_threshold_only_keeps_readings() {
+        let mut supply = SupplyMonitor::new(Some(4300));
+        supply.record(3900);
+        supply.set_threshold(None);
+        assert_eq!(supply.record(3800), SupplyEvent::Recovered);
+        assert_eq!(supply.record(3700), SupplyEvent::None);
+        assert!(!supply.is_sagging());
+        assert_eq!(supply.lowest_mv(), 3700);
+    }
+}
+
+mod event_log {
+    use sorter_logic::Rgb;
+    use sorter_logic::event_log::{self, EventLog, LOG_MAGIC, LogEntry, LogEvent};
+
+    #[test]
+    fn test_full_log_keeps_the_newest_entries_in_order() {
+        let mut log: EventLog<3> = EventLog::new();
+        assert!(log.is_empty());
+        for (at_ms, beads) in [(10, 1), (20, 2), (30, 3), (40, 4), (50, 5)] {
+            log.push(at_ms, LogEvent::RunLimitReached { beads });
+        }
+        assert_eq!(log.len(), 3);
+        let times: Vec<u32> = log.iter().map(|e| e.at_ms).collect();
+        assert_eq!(times, [30, 40, 50]);
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let events = [
+            LogEvent::Started,
+            LogEvent::Stopped,
+            LogEvent::EmergencyStop,
+            LogEvent::WatchdogReset,
+            LogEvent::Sorted {
+                tube: 17,
+                color: Rgb {
+                    r: 12,
+                    g: 200,
+                    b: 99,
+                },
+            },
+            LogEvent::Rejected,
+            LogEvent::CameraError,
+            LogEvent::HopperStalled { empty_pickups: 300 },
+            LogEvent::RunLimitReached { beads: 1000 },
+            LogEvent::DryRun(true),
+            LogEvent::DatasetMode(false),
+            LogEvent::Collect { count: 40 },
+            LogEvent::Extract(true),
+            LogEvent::SupplySag { mv: 4150 },
+            LogEvent::ServoStalled { mv: 620 },
+            LogEvent::Asleep(true),
+        ];
+        let mut log: EventLog<16> = EventLog::new();
+        for (i, event) in events.into_iter().enumerate() {
+            log.push(i as u32 * 1000 + 70_000, event);
+        }
+
+        let mut packet = [0u8; event_log::packet_len(16)];
+        let len = log.encode(&mut packet);
+        assert_eq!(len, event_log::packet_len(events.len()));
+        assert_eq!(packet[..4], LOG_MAGIC);
+        let decoded: Vec<LogEntry> = event_log::decode(&packet[4..len]).unwrap().collect();
+        assert_eq!(decoded, log.iter().copied().collect::<Vec<_>>());
+
+        assert!(event_log::decode(&packet[4..len - 1]).is_none());
+        // An event code from a newer build is skipped.
+        packet[9] = 0xEE;
+        assert_eq!(
+            event_log::decode(&packet[4..len]).unwrap().count(),
+            events.len() - 1
+        );
+    }
+}
+
+mod telemetry {
+    use sorter_logic::telemetry::{
+        Bounded, HighWater, TELEMETRY_MAGIC, TELEMETRY_PACKET_MAX, Telemetry,
+    };
+
+    #[test]
+    fn test_high_water_keeps_peak_and_counts_full_samples() {
+        let mut mark = HighWater::new(4);
+        assert!(!mark.record(2));
+        assert!(!mark.record(1));
+        assert_eq!(mark.peak, 2);
+        assert_eq!(mark.full, 0);
+
+        // Only the first time at capacity is reported.
+        assert!(mark.record(4));
+        assert!(!mark.record(4));
+        assert!(!mark.record(3));
+        assert_eq!(mark.peak, 4);
+        assert_eq!(mark.full, 2);
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let mut telemetry = Telemetry::new([128, 30, 64, 64, 4, 4]);
+        telemetry.record(Bounded::Palette, 97);
+        telemetry.record(Bounded::Tubes, 30);
+        telemetry.record(Bounded::Tubes, 30);
+        telemetry.record(Bounded::CommandBuffer, 2);
+        telemetry.record(Bounded::EventLog, 64);
+        telemetry.record(Bounded::ServoCommands, 1);
+
+        let mut packet = [0u8; TELEMETRY_PACKET_MAX];
+        let len = telemetry.encode(&mut packet);
+        assert_eq!(len, TELEMETRY_PACKET_MAX);
+        assert_eq!(packet[..4], TELEMETRY_MAGIC);
+
+        let decoded = Telemetry::decode(&packet[4..len]).expect("complete packet");
+        assert_eq!(decoded, telemetry);
+        assert_eq!(
+            decoded.get(Bounded::Tubes),
+            HighWater {
+                peak: 30,
+                capacity: 30,
+                full: 2
+            }
+        );
+
+        assert_eq!(Telemetry::decode(&packet[4..len - 1]), None);
+    }
+}
+
+mod settings {
+    use sorter_logic::settings::{
+        MAX_CAMERA_FRAMES, MAX_RETAKES, NO_REJECT_TUBE, PROFILE_MERGE_MARGIN, RunLimit,
+        SETTINGS_PACKET_MAX, Setting, Settings, SettingsError,
+    };
+    use sorter_logic::{AnalysisConfig, MAX_RING_OUTER};
+
+    #[test]
+    fn test_defaults_match_analysis_defaults() {
+        let settings = Settings::default();
+        assert!(settings.validate().is_ok());
+        assert_eq!(settings.analysis_config(), AnalysisConfig::default());
+        assert_eq!(settings.match_threshold(), None);
+        assert_eq!(settings.run_limit(), RunLimit::default());
+        assert_eq!(settings.idle_sleep_ms(), None);
+        for setting in Setting::ALL {
+            assert_eq!(Setting::from_id(setting.id()), Some(setting));
+            assert_eq!(Setting::from_name(setting.name()), Some(setting));
+        }
+    }
+
+    #[test]
+    fn test_set_rejects_inconsistent_values() {
+        let mut settings = Settings::default();
+        assert_eq!(
+            settings.set(Setting::HopperCamera, 3000),
+            Err(SettingsError::Invalid(Setting::HopperCamera))
+        );
+        assert_eq!(
+            settings.set(Setting::HopperMax, 400),
+            Err(SettingsError::Invalid(Setting::HopperMax))
+        );
+        assert_eq!(
+            settings.set(Setting::Retakes, MAX_RETAKES + 1),
+            Err(SettingsError::Invalid(Setting::Retakes))
+        );
+        assert_eq!(
+            settings.set(Setting::CameraFrames, 0),
+            Err(SettingsError::Invalid(Setting::CameraFrames))
+        );
+        assert_eq!(
+            settings.set(Setting::CameraFrames, MAX_CAMERA_FRAMES + 1),
+            Err(SettingsError::Invalid(Setting::CameraFrames))
+        );
+        assert_eq!(
+            settings.set(Setting::HopperPark, 100),
+            Err(SettingsError::Invalid(Setting::HopperPark))
+        );
+        assert_eq!(
+            settings.set(Setting::RejectConfidence, 101),
+            Err(SettingsError::Invalid(Setting::RejectConfidence))
+        );
+        assert_eq!(
+            settings.set(Setting::RelaxOnPause, 2),
+            Err(SettingsError::Invalid(Setting::RelaxOnPause))
+        );
+        assert_eq!(
+            settings.set(Setting::AgitationAccel, 0),
+            Err(SettingsError::Invalid(Setting::AgitationAccel))
+        );
+        assert_eq!(
+            settings.set(Setting::TubeStrategy, 200),
+            Err(SettingsError::Invalid(Setting::TubeStrategy))
+        );
+        assert_eq!(
+            settings.set(Setting::SplitFinishes, 2),
+            Err(SettingsError::Invalid(Setting::SplitFinishes))
+        );
+        // A rejected change leaves the settings as they were.
+        assert_eq!(settings, Settings::default());
+        settings.set(Setting::MatchThreshold, 20).unwrap();
+        assert_eq!(settings.match_threshold(), Some(20));
+    }
+
+    #[test]
+    fn test_merge_margin_zero_is_off_not_the_profile_default() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.tube_merge_margin(), None);
+        settings.set(Setting::TubeMergeMargin, 0).unwrap();
+        assert_eq!(settings.tube_merge_margin(), Some(0));
+        settings
+            .set(Setting::TubeMergeMargin, PROFILE_MERGE_MARGIN)
+            .unwrap();
+        assert_eq!(settings.tube_merge_margin(), None);
+    }
+
+    #[test]
+    fn test_tube_capacity_zero_is_unlimited() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.tube_capacity(), None);
+        settings.set(Setting::TubeCapacity, 150).unwrap();
+        assert_eq!(settings.tube_capacity(), Some(150));
+    }
+
+    #[test]
+    fn test_ring_radii() {
+        let mut settings = Settings::default();
+        settings.set(Setting::RingOuter, 8).unwrap();
+        settings.set(Setting::RingInner, 2).unwrap();
+        let config = settings.analysis_config();
+        assert_eq!((config.ring_inner, config.ring_outer), (2, 8));
+
+        let outer = MAX_RING_OUTER as u16 + 1;
+        assert_eq!(
+            settings.set(Setting::RingOuter, outer),
+            Err(SettingsError::Invalid(Setting::RingOuter))
+        );
+        assert_eq!(
+            settings.set(Setting::RingInner, 8),
+            Err(SettingsError::Invalid(Setting::RingInner))
+        );
+    }
+
+    #[test]
+    fn test_stored_record_round_trip_and_damage() {
+        let mut settings = Settings::default();
+        settings.set(Setting::HopperDrop, 1600).unwrap();
+        let mut bytes = settings.to_bytes();
+        assert_eq!(Settings::from_bytes(&bytes), Ok(settings));
+
+        bytes[8] ^= 1;
+        assert_eq!(Settings::from_bytes(&bytes), Err(SettingsError::BadCrc));
+        // Blank flash.
+        assert_eq!(
+            Settings::from_bytes(&[0xFF; 64]),
+            Err(SettingsError::BadHeader)
+        );
+    }
+
+    #[test]
+    fn test_older_record_keeps_new_defaults() {
+        // A record written before MatchThreshold existed: one value fewer.
+        let mut settings = Settings::default();
+        settings.set(Setting::EdgeThreshold, 60).unwrap();
+        let full = settings.to_bytes();
+        let count = Setting::ALL.len() - 1;
+        let end = 6 + count * 2;
+        let mut old = full[..end].to_vec();
+        old[5] = count as u8;
+        let crc = sorter_protocol::crc16(&old);
+        old.extend_from_slice(&crc.to_le_bytes());
+
+        let loaded = Settings::from_bytes(&old).unwrap();
+        assert_eq!(loaded.get(Setting::EdgeThreshold), 60);
+        assert_eq!(loaded.get(Setting::MatchThreshold), 0);
+    }
+
+    #[test]
+    fn test_reply_packet_round_trip() {
+        let mut settings = Settings::default();
+        settings.set(Setting::MinPixelCount, 12).unwrap();
+        let mut packet = [0u8; SETTINGS_PACKET_MAX];
+        let len = settings.encode(&mut packet);
+        assert_eq!(packet[..4], sorter_protocol::SETTINGS_MAGIC);
+        assert_eq!(Settings::decode(&packet[4..len]), Some(settings));
+        assert_eq!(Settings::decode(&packet[4..len - 1]), None);
+    }
+
+    #[test]
+    fn test_reject_tube_must_be_a_tube() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.reject_tube(), None);
+        settings.set(Setting::RejectTube, 29).unwrap();
+        assert_eq!(settings.reject_tube(), Some(29));
+        assert_eq!(
+            settings.set(Setting::RejectTube, 200),
+            Err(SettingsError::Invalid(Setting::RejectTube))
+        );
+        settings.set(Setting::RejectTube, NO_REJECT_TUBE).unwrap();
+        assert_eq!(settings.reject_tube(), None);
+    }
+
+    #[test]
+    fn test_rejecting_beads_needs_a_reject_tube() {
+        let mut settings = Settings::default();
+        for (reject, value) in [
+            (Setting::RejectConfidence, 60),
+            (Setting::RejectDistance, 200),
+        ] {
+            assert_eq!(
+                settings.set(reject, value),
+                Err(SettingsError::Invalid(reject))
+            );
+        }
+        settings.set(Setting::RejectTube, 0).unwrap();
+        settings.set(Setting::RejectConfidence, 60).unwrap();
+        settings.set(Setting::RejectDistance, 200).unwrap();
+        assert_eq!(settings.reject_confidence(), Some(60));
+
+        // Not while beads are still rejected.
+        assert_eq!(
+            settings.set(Setting::RejectTube, NO_REJECT_TUBE),
+            Err(SettingsError::Invalid(Setting::RejectDistance))
+        );
+        settings.set(Setting::RejectDistance, 0).unwrap();
+        settings.set(Setting::RejectConfidence, 0).unwrap();
+        settings.set(Setting::RejectTube, NO_REJECT_TUBE).unwrap();
+    }
+
+    #[test]
+    fn test_analysis_settings_map_to_config() {
+        let mut settings = Settings::default();
+        settings.set(Setting::FilterPercent, 75).unwrap();
+        settings.set(Setting::MadK, 25).unwrap();
+        settings.set(Setting::MaxEccentricity, 400).unwrap();
+        settings.set(Setting::PixelBudget, 64).unwrap();
+        let config = settings.analysis_config();
+        assert_eq!(config.filter_percent, 75);
+        assert_eq!(config.mad_k, Some(2.5));
+        assert_eq!(config.max_eccentricity, Some(0.4));
+        assert_eq!(config.pixel_budget, Some(64));
+        assert_eq!(config.max_variance, None);
+        assert_eq!(
+            settings.set(Setting::FilterPercent, 0),
+            Err(SettingsError::Invalid(Setting::FilterPercent))
+        );
+    }
+
+    #[test]
+    fn test_run_limit_stops_at_whichever_comes_first() {
+        let mut settings = Settings::default();
+        assert!(!settings.run_limit().reached(u32::MAX, u64::MAX));
+
+        settings.set(Setting::RunLimitBeads, 100).unwrap();
+        settings.set(Setting::RunLimitMinutes, 30).unwrap();
+        let limit = settings.run_limit();
+        assert!(!limit.reached(99, 29 * 60_000));
+        assert!(limit.reached(100, 0));
+        assert!(limit.reached(0, 30 * 60_000));
+
+        settings.set(Setting::IdleSleepMinutes, 15).unwrap();
+        assert_eq!(settings.idle_sleep_ms(), Some(15 * 60_000));
+    }
+}
+
+mod config {
+    use sorter_logic::config::{
+        self, CONFIG_BYTES, CONFIG_VERSION, LAYOUT_OFFSET, MigrateError, PROFILE_OFFSET,
+        SETTINGS_OFFSET,
+    };
+    use sorter_logic::layout::{LAYOUT_BYTES, TubeLayout};
+    use sorter_logic::profile::Profile;
+    use sorter_logic::settings::{Setting, Settings};
+    use sorter_protocol::crc16;
+
+    // A config sector as firmware from before the header left it: a layout without a CRC, a
+    // profile, and settings from a build that had only the first 30.
+    fn version_1_sector(layout: &TubeLayout) -> [u8; CONFIG_BYTES] {
+        let mut sector = [0xFF; CONFIG_BYTES];
+
+        let record = &mut sector[LAYOUT_OFFSET..];
+        record[..8].copy_from_slice(&[b'T', b'U', b'B', b'E', 1, layout.slices, layout.rows, 0]);
+        let positions = layout.chute_positions.iter().chain(&layout.drop_positions);
+        for (chunk, p) in record[8..].chunks_exact_mut(2).zip(positions) {
+            chunk.copy_from_slice(&p.to_le_bytes());
+        }
+
+        sector[PROFILE_OFFSET..PROFILE_OFFSET + 5].copy_from_slice(&[b'P', b'R', b'O', b'F', 2]);
+
+        let count = 30;
+        let end = 6 + count * 2;
+        let record = &mut sector[SETTINGS_OFFSET..];
+        record[..6].copy_from_slice(&[b'S', b'E', b'T', b'S', 1, count as u8]);
+        for (chunk, setting) in record[6..end].chunks_exact_mut(2).zip(Setting::ALL) {
+            let value = match setting {
+                Setting::EdgeThreshold => 55,
+                _ => Settings::default().get(setting),
+            };
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        let crc = crc16(&record[..end]);
+        record[end..end + 2].copy_from_slice(&crc.to_le_bytes());
+        sector
+    }
+
+    fn calibrated_layout() -> TubeLayout {
+        let mut layout = TubeLayout::default();
+        layout.chute_positions[3] += 12;
+        layout.drop_positions[1] -= 20;
+        layout
+    }
+
+    #[test]
+    fn test_version_1_sector_keeps_its_calibration() {
+        let layout = calibrated_layout();
+        let mut sector = version_1_sector(&layout);
+        assert_eq!(config::version(&sector), Some(1));
+
+        // Readable before migrating...
+        assert_eq!(TubeLayout::from_bytes(&sector[LAYOUT_OFFSET..]), Ok(layout));
+
+        assert_eq!(config::migrate(&mut sector), Ok(Some(1)));
+        assert_eq!(config::version(&sector), Some(CONFIG_VERSION));
+
+        // ...and after, in the current format.
+        let stored = &sector[LAYOUT_OFFSET..LAYOUT_OFFSET + LAYOUT_BYTES];
+        assert_eq!(stored, layout.to_bytes());
+        assert_eq!(TubeLayout::from_bytes(stored), Ok(layout));
+        assert_eq!(config::profile(&sector), Some(Profile::Strict));
+        let settings = Settings::from_bytes(&sector[SETTINGS_OFFSET..]).unwrap();
+        assert_eq!(settings.get(Setting::EdgeThreshold), 55);
+        assert_eq!(settings.get(Setting::IdleSleepMinutes), 0);
+        assert!(sector[SETTINGS_OFFSET..].starts_with(&settings.to_bytes()));
+
+        assert_eq!(config::migrate(&mut sector), Ok(None));
+    }
+
+    #[test]
+    fn test_unreadable_records_are_left_alone() {
+        let mut sector = version_1_sector(&calibrated_layout());
+        sector[SETTINGS_OFFSET + 8] ^= 1;
+        let damaged = sector;
+        assert_eq!(config::migrate(&mut sector), Ok(Some(1)));
+        assert_eq!(
+            sector[SETTINGS_OFFSET..CONFIG_BYTES - 8],
+            damaged[SETTINGS_OFFSET..CONFIG_BYTES - 8]
+        );
+    }
+
+    #[test]
+    fn test_blank_and_newer_sectors_are_not_migrated() {
+        let mut sector = [0xFF; CONFIG_BYTES];
+        assert_eq!(config::migrate(&mut sector), Ok(None));
+        assert_eq!(sector, [0xFF; CONFIG_BYTES]);
+
+        config::stamp(&mut sector);
+        sector[CONFIG_BYTES - 4] = CONFIG_VERSION + 1;
+        let newer = sector;
+        assert_eq!(
+            config::migrate(&mut sector),
+            Err(MigrateError::Newer(CONFIG_VERSION + 1))
+        );
+        assert_eq!(sector, newer);
+    }
+}
+
+mod dataset {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, frame_with_bead};
+    use sorter_logic::dataset::{self, LABEL_BYTES, Label, Measurement};
+    use sorter_logic::{BeadFinish, Rgb, analyze_image};
+
+    #[test]
+    fn test_label_round_trip_with_measurement() {
+        let frame = frame_with_bead(
+            BACKGROUND,
+            Rgb {
+                r: 30,
+                g: 60,
+                b: 200,
+            },
+        );
+        let analysis = analyze_image(&frame, WIDTH, HEIGHT).unwrap();
+        let label = Label {
+            tube: Some(7),
+            bead: Some(Measurement::from(&analysis)),
+        };
+
+        let header = dataset::header(&label, frame.len() as u16);
+        let mut packet = header.to_vec();
+        packet.extend_from_slice(&frame);
+        packet.extend_from_slice(&dataset::crc(&header, &frame));
+        let (decoded, decoded_frame) = dataset::decode(&packet).unwrap();
+        assert_eq!(decoded, label);
+        assert_eq!(decoded_frame, &frame[..]);
+        assert_eq!(decoded.bead.unwrap().center, analysis.center);
+    }
+
+    #[test]
+    fn test_rejected_bead_and_unknown_finish() {
+        let label = Label {
+            tube: None,
+            bead: Some(Measurement {
+                color: Rgb { r: 1, g: 2, b: 3 },
+                variance: 900,
+                channel_variance: [300, 300, 300],
+                pixel_count: 120,
+                center: (-1, 29),
+                finish: BeadFinish::Glitter,
+                sparkle: 4,
+                diameter_px: 11.5,
+                eccentricity: 0.25,
+            }),
+        };
+        let mut bytes = label.encode();
+        assert_eq!(Label::decode(&bytes), Some(label));
+        bytes[33] = 0xEE;
+        assert_eq!(Label::decode(&bytes), None);
+        assert_eq!(
+            Label::decode(&[0; LABEL_BYTES]),
+            Some(Label {
+                tube: Some(0),
+                bead: None
+            })
+        );
+    }
+}
+
+mod test_pattern {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, empty_frame, to_rgb565};
+    use sorter_logic::Rgb;
+    use sorter_logic::test_pattern::{COLOR_BARS, color_bars_matched};
+
+    fn bars(order: impl Fn(usize) -> usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(WIDTH * HEIGHT * 2);
+        for _ in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let (r, g, b) = COLOR_BARS[order(x * COLOR_BARS.len() / WIDTH)];
+                let level = |lit: bool| if lit { 230 } else { 20 };
+                data.extend_from_slice(&to_rgb565(Rgb {
+                    r: level(r),
+                    g: level(g),
+                    b: level(b),
+                }));
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_color_bars_match() {
+        assert_eq!(
+            color_bars_matched(&bars(|i| i), WIDTH, HEIGHT),
+            COLOR_BARS.len()
+        );
+    }
+
+    #[test]
+    fn test_wrong_frames_do_not_match() {
+        // Mirrored, as if the sensor were flipped.
+        let mirrored = bars(|i| COLOR_BARS.len() - 1 - i);
+        assert!(color_bars_matched(&mirrored, WIDTH, HEIGHT) < COLOR_BARS.len());
+
+        // Byte-swapped pixels.
+        let mut swapped = bars(|i| i);
+        for px in swapped.chunks_exact_mut(2) {
+            px.swap(0, 1);
+        }
+        assert!(color_bars_matched(&swapped, WIDTH, HEIGHT) < COLOR_BARS.len());
+
+        // The real picture instead of the pattern, and a short frame.
+        assert!(color_bars_matched(&empty_frame(BACKGROUND), WIDTH, HEIGHT) <= 1);
+        assert_eq!(color_bars_matched(&[0; 10], WIDTH, HEIGHT), 0);
+    }
+}
+
+mod exposure {
+    use sorter_logic::Rgb;
+    use sorter_logic::exposure::{DEFAULT_LED_DUTY, LED_TOP, LUMA_BAND, LedControl, MIN_LED_DUTY};
+
+    fn gray(v: u8) -> Rgb {
+        Rgb { r: v, g: v, b: v }
+    }
+
+    #[test]
+    fn test_inside_the_band_leaves_the_duty() {
+        let mut led = LedControl::new(Some(150));
+        assert_eq!(led.update(gray(150 - LUMA_BAND)), None);
+        assert_eq!(led.update(gray(150 + LUMA_BAND)), None);
+        assert_eq!(led.duty(), DEFAULT_LED_DUTY);
+    }
+
+    #[test]
+    fn test_steps_are_limited() {
+        let mut led = LedControl::new(Some(150));
+        // A near-black patch asks for far more than one step.
+        assert_eq!(led.update(gray(10)), Some(625));
+        assert_eq!(led.update(gray(250)), Some(469));
+    }
+
+    #[test]
+    fn test_converges_on_a_proportional_patch() {
+        let mut led = LedControl::new(Some(150));
+        // A patch that reads 0.2 luma per thousandth of duty.
+        let patch = |duty: u16| gray((duty / 5).min(255) as u8);
+        for _ in 0..10 {
+            let duty = led.duty();
+            led.update(patch(duty));
+        }
+        let settled = patch(led.duty());
+        assert!(settled.r.abs_diff(150) <= LUMA_BAND, "{settled:?}");
+        assert_eq!(led.update(settled), None);
+    }
+
+    #[test]
+    fn test_duty_stays_in_range() {
+        let mut led = LedControl::new(Some(250));
+        for _ in 0..20 {
+            led.update(gray(1));
+        }
+        assert_eq!(led.duty(), LED_TOP);
+        led.set_target(Some(20));
+        for _ in 0..40 {
+            led.update(gray(255));
+        }
+        assert_eq!(led.duty(), MIN_LED_DUTY);
+    }
+
+    #[test]
+    fn test_no_target_holds_the_default() {
+        let mut led = LedControl::new(Some(150));
+        led.update(gray(40));
+        assert_ne!(led.duty(), DEFAULT_LED_DUTY);
+        led.set_target(None);
+        assert_eq!(led.duty(), DEFAULT_LED_DUTY);
+        assert_eq!(led.update(gray(40)), None);
+    }
+}
@@ -1,3 +1,5 @@
+mod common;
+
 use sorter_logic::{Palette, PaletteMatch, Rgb};
 
 #[test]
@@ -59,3 +61,670 @@ fn test_full_palette() {
         _ => panic!("Expected Full"),
     }
 }
+
+// The pruned search must agree with comparing against every centroid.
+#[test]
+fn test_nearest_matches_exhaustive_search() {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use sorter_logic::ColorMetric;
+
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut random_rgb = || Rgb {
+        r: rng.r#gen(),
+        g: rng.r#gen(),
+        b: rng.r#gen(),
+    };
+
+    for metric in [
+        ColorMetric::Lab,
+        ColorMetric::OkLab,
+        ColorMetric::HueWeighted,
+        ColorMetric::LAB_DARK,
+    ] {
+        let mut palette: Palette<128> = Palette::with_metric(metric);
+        while palette.len() < 128 {
+            let rgb = random_rgb();
+            if let PaletteMatch::NewEntry(idx) = palette.match_color(&rgb, 0, 1) {
+                // Move the centroid so cached coordinates must follow it.
+                palette.add_sample(idx, &random_rgb(), 0);
+            }
+        }
+
+        for _ in 0..500 {
+            let rgb = random_rgb();
+            let expected = (0..palette.len())
+                .map(|i| (i, rgb.dist_metric(&palette.get(i).unwrap(), metric)))
+                .min_by_key(|&(_, d)| d);
+            assert_eq!(palette.nearest(&rgb), expected, "{:?} {:?}", metric, rgb);
+            // The palette is full, so a miss reports Full instead of adding an entry.
+            for threshold in [15, 200] {
+                let expected = match expected {
+                    Some((i, d)) if d < threshold => PaletteMatch::Match(i),
+                    _ => PaletteMatch::Full,
+                };
+                assert_eq!(palette.match_color(&rgb, 0, threshold), expected);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dist_lab_less_than() {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::seed_from_u64(11);
+    for _ in 0..2000 {
+        let a = Rgb {
+            r: rng.r#gen(),
+            g: rng.r#gen(),
+            b: rng.r#gen(),
+        };
+        let b = Rgb {
+            r: a.r.saturating_add(rng.gen_range(0..40)),
+            g: a.g.saturating_sub(rng.gen_range(0..40)),
+            b: rng.r#gen(),
+        };
+        let d = a.dist_lab(&b);
+        for limit in [0, 1, 15, 100, 1000, d, d + 1] {
+            assert_eq!(
+                a.dist_lab_less_than(&b, limit),
+                d < limit,
+                "{:?} {:?}",
+                a,
+                b
+            );
+        }
+    }
+}
+
+#[test]
+fn test_stats_for_distinct_colors() {
+    let mut palette: Palette<8> = Palette::new();
+    let red = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    let blue = Rgb {
+        r: 20,
+        g: 40,
+        b: 200,
+    };
+    palette.seed_from(&[red, blue]);
+    for shade in [196, 200, 204, 200] {
+        palette.add_sample(
+            0,
+            &Rgb {
+                r: shade,
+                g: 20,
+                b: 30,
+            },
+            0,
+        );
+    }
+
+    let stats = palette.stats();
+    assert_eq!(stats.len, 2);
+    assert_eq!(stats.counts[..2], [5, 1]);
+    assert!(stats.within[0] > 0);
+    assert_eq!(stats.within[1], 0);
+    assert_eq!(stats.mean_within(), stats.within[0]);
+    let (a, b, d) = stats.closest_pair.unwrap();
+    assert_eq!((a, b), (0, 1));
+    assert_eq!(d, red.dist_lab(&blue));
+    assert_eq!(stats.merge_candidate(), None);
+}
+
+#[cfg(feature = "alloc")]
+mod dyn_palette {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use sorter_logic::dyn_palette::DynPalette;
+    use sorter_logic::{ColorMetric, Palette, PaletteMatch, PaletteMode, Rgb};
+
+    #[test]
+    fn test_dyn_palette_matches_fixed_palette() {
+        for metric in [
+            ColorMetric::Lab,
+            ColorMetric::OkLab,
+            ColorMetric::HueWeighted,
+            ColorMetric::LAB_DARK,
+        ] {
+            let mut rng = StdRng::seed_from_u64(7);
+            let mut fixed: Palette<256> = Palette::with_metric(metric);
+            let mut dynamic = DynPalette::with_metric(metric);
+
+            for _ in 0..2000 {
+                let rgb = Rgb {
+                    r: rng.r#gen(),
+                    g: rng.r#gen(),
+                    b: rng.r#gen(),
+                };
+                let m = fixed.match_color(&rgb, 0, 200);
+                assert_eq!(
+                    dynamic.match_color(&rgb, 0, 200),
+                    m,
+                    "{:?} {:?}",
+                    metric,
+                    rgb
+                );
+                if let PaletteMatch::Match(i) | PaletteMatch::NewEntry(i) = m {
+                    fixed.add_sample(i, &rgb, 0);
+                    dynamic.add_sample(i, &rgb, 0);
+                }
+            }
+            assert_eq!(dynamic.len(), fixed.len());
+            for i in 0..fixed.len() {
+                assert_eq!(dynamic.get_entry(i), fixed.get_entry(i));
+            }
+
+            let (a, b) = (fixed.stats(), dynamic.stats());
+            assert_eq!(b.closest_pair, a.closest_pair);
+            assert_eq!(b.counts, a.counts[..a.len]);
+            assert_eq!(b.within, a.within[..a.len]);
+            assert_eq!(b.mean_within(), a.mean_within());
+            assert_eq!(b.merge_candidate(), a.merge_candidate());
+        }
+    }
+
+    #[test]
+    fn test_dyn_palette_grows_past_fixed_sizes() {
+        let mut palette = DynPalette::new();
+        let mut added = 0;
+        for r in (0..=255).step_by(16) {
+            for g in (0..=255).step_by(16) {
+                for b in (0..=255).step_by(16) {
+                    let rgb = Rgb { r, g, b };
+                    if let PaletteMatch::NewEntry(i) = palette.match_color(&rgb, 0, 1) {
+                        assert_eq!(i, added);
+                        added += 1;
+                    }
+                }
+            }
+        }
+        assert!(palette.len() > 3000, "only {} entries", palette.len());
+        assert_eq!(palette.len(), added);
+    }
+
+    #[test]
+    fn test_dyn_palette_freezes() {
+        let red = Rgb {
+            r: 200,
+            g: 20,
+            b: 30,
+        };
+        let blue = Rgb {
+            r: 20,
+            g: 40,
+            b: 200,
+        };
+        let mut palette = DynPalette::new();
+        palette.seed_from(&[red]);
+        palette.set_mode(PaletteMode::TrainFor(1));
+        palette.add_sample(0, &red, 0);
+        assert!(palette.is_frozen());
+
+        assert_eq!(palette.match_color(&blue, 0, 15), PaletteMatch::Full);
+        palette.add_sample(0, &blue, 0);
+        assert_eq!(palette.get_entry(0).unwrap().count, 2);
+        assert_eq!(palette.len(), 1);
+    }
+}
+
+mod ewma {
+    use sorter_logic::{CentroidUpdate, Palette, PaletteEntry, PaletteMatch, Rgb};
+
+    fn grey(v: u8) -> Rgb {
+        Rgb { r: v, g: v, b: v }
+    }
+
+    #[test]
+    fn test_ewma_is_plain_mean_while_young() {
+        let mut plain = PaletteEntry::new(grey(90), 10);
+        let mut ewma = PaletteEntry::new(grey(90), 10);
+        for v in [100, 110, 120] {
+            plain.add(grey(v), 20);
+            ewma.add_ewma(grey(v), 20, 0.1);
+        }
+        assert_eq!(ewma.avg(), plain.avg());
+        assert_eq!(ewma.count, 4);
+    }
+
+    #[test]
+    fn test_ewma_palette_follows_drift() {
+        // Lighting slowly brightens one color by 40 levels over 200 beads.
+        let stream: Vec<Rgb> = (0..200u32)
+            .map(|i| Rgb {
+                r: (150 + i / 5) as u8,
+                g: (40 + i / 5) as u8,
+                b: (40 + i / 5) as u8,
+            })
+            .collect();
+        let last = *stream.last().unwrap();
+
+        let run = |update: CentroidUpdate| {
+            let mut palette: Palette<8> = Palette::new();
+            palette.set_centroid_update(update);
+            for rgb in &stream {
+                match palette.match_color(rgb, 0, 400) {
+                    PaletteMatch::Match(i) | PaletteMatch::NewEntry(i) => {
+                        palette.add_sample(i, rgb, 0)
+                    }
+                    PaletteMatch::Full => panic!("palette full"),
+                }
+            }
+            palette
+        };
+
+        let cumulative = run(CentroidUpdate::Cumulative);
+        let ewma = run(CentroidUpdate::Ewma { alpha: 0.1 });
+        assert_eq!(ewma.len(), 1);
+
+        let lag = |p: &Palette<8>| last.r - p.get(0).unwrap().r;
+        assert!(
+            lag(&cumulative) >= 15,
+            "cumulative lag {}",
+            lag(&cumulative)
+        );
+        assert!(lag(&ewma) <= 3, "ewma lag {}", lag(&ewma));
+        // Every sample is still counted.
+        assert_eq!(ewma.get_entry(0).unwrap().count, 201);
+    }
+}
+
+mod index {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use sorter_logic::index::PaletteIndex;
+    use sorter_logic::{ColorMetric, Palette, PaletteMatch, Rgb};
+
+    fn random_rgb(rng: &mut StdRng) -> Rgb {
+        Rgb {
+            r: rng.r#gen(),
+            g: rng.r#gen(),
+            b: rng.r#gen(),
+        }
+    }
+
+    #[test]
+    fn test_index_agrees_with_linear_scan() {
+        let mut rng = StdRng::seed_from_u64(11);
+        for metric in [
+            ColorMetric::Lab,
+            ColorMetric::OkLab,
+            ColorMetric::HueWeighted,
+            ColorMetric::LAB_DARK,
+        ] {
+            let mut palette: Palette<1024> = Palette::with_metric(metric);
+            let mut index = PaletteIndex::new();
+            while palette.len() < 1024 {
+                let rgb = random_rgb(&mut rng);
+                let _ = palette.match_color(&rgb, 0, 1);
+            }
+
+            for _ in 0..300 {
+                let rgb = random_rgb(&mut rng);
+                assert_eq!(index.nearest(&palette, &rgb), palette.nearest(&rgb));
+            }
+
+            // A moved centroid is picked up on the next lookup.
+            let moved = random_rgb(&mut rng);
+            for _ in 0..50 {
+                palette.add_sample(5, &moved, 0);
+            }
+            assert_eq!(index.nearest(&palette, &moved), palette.nearest(&moved));
+        }
+    }
+
+    #[test]
+    fn test_match_color_indexed() {
+        let mut palette: Palette<4> = Palette::new();
+        let mut index = PaletteIndex::new();
+        let red = Rgb {
+            r: 200,
+            g: 20,
+            b: 30,
+        };
+        let darker_red = Rgb {
+            r: 196,
+            g: 20,
+            b: 30,
+        };
+        let blue = Rgb {
+            r: 20,
+            g: 40,
+            b: 200,
+        };
+
+        assert_eq!(
+            palette.match_color_indexed(&mut index, &red, 0, 15),
+            PaletteMatch::NewEntry(0)
+        );
+        assert_eq!(
+            palette.match_color_indexed(&mut index, &blue, 0, 15),
+            PaletteMatch::NewEntry(1)
+        );
+        assert_eq!(
+            palette.match_color_indexed(&mut index, &darker_red, 0, 15),
+            PaletteMatch::Match(0)
+        );
+        assert_eq!(
+            palette.match_color_indexed(&mut index, &blue, 0, 15),
+            PaletteMatch::Match(1)
+        );
+    }
+
+    // Many entries at the same distance: the lowest index must win, as in the linear scan.
+    #[test]
+    fn test_ties_pick_lowest_index() {
+        let mut palette: Palette<64> = Palette::new();
+        let mut index = PaletteIndex::new();
+        let gray = Rgb {
+            r: 128,
+            g: 128,
+            b: 128,
+        };
+        for _ in 0..64 {
+            // A threshold of 0 never matches, so duplicates become separate entries.
+            let _ = palette.match_color(&gray, 0, 0);
+        }
+        assert_eq!(index.nearest(&palette, &gray), Some((0, 0)));
+    }
+}
+
+mod freeze {
+    use sorter_logic::{Palette, PaletteMatch, PaletteMode, Rgb};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    const BLUE: Rgb = Rgb {
+        r: 20,
+        g: 40,
+        b: 200,
+    };
+    const GREEN: Rgb = Rgb {
+        r: 30,
+        g: 180,
+        b: 40,
+    };
+
+    // The firmware's bead step: match, then fold the bead into its entry.
+    fn sort(palette: &mut Palette<8>, rgb: Rgb) -> PaletteMatch {
+        let m = palette.match_color(&rgb, 0, 15);
+        if let PaletteMatch::Match(i) | PaletteMatch::NewEntry(i) = m {
+            palette.add_sample(i, &rgb, 0);
+        }
+        m
+    }
+
+    #[test]
+    fn test_train_then_freeze() {
+        let mut palette: Palette<8> = Palette::new();
+        palette.set_mode(PaletteMode::TrainFor(3));
+
+        assert_eq!(sort(&mut palette, RED), PaletteMatch::NewEntry(0));
+        assert_eq!(sort(&mut palette, BLUE), PaletteMatch::NewEntry(1));
+        assert_eq!(palette.mode(), PaletteMode::TrainFor(1));
+        assert_eq!(sort(&mut palette, RED), PaletteMatch::Match(0));
+        assert!(palette.is_frozen());
+
+        // Known colors still classify; a new one is no longer learned.
+        assert_eq!(sort(&mut palette, BLUE), PaletteMatch::Match(1));
+        assert_eq!(sort(&mut palette, GREEN), PaletteMatch::Full);
+        assert_eq!(palette.len(), 2);
+
+        // The same sequence with a learning palette keeps growing.
+        let mut learning: Palette<8> = Palette::new();
+        for rgb in [RED, BLUE, RED, BLUE] {
+            sort(&mut learning, rgb);
+        }
+        assert_eq!(sort(&mut learning, GREEN), PaletteMatch::NewEntry(2));
+    }
+
+    #[test]
+    fn test_samples_for_no_entry_do_not_count_toward_training() {
+        let mut palette: Palette<8> = Palette::new();
+        assert_eq!(palette.seed_from(&[RED]), 1);
+        palette.set_mode(PaletteMode::TrainFor(2));
+
+        palette.add_sample(1, &BLUE, 0);
+        palette.add_sample(8, &BLUE, 0);
+        assert_eq!(palette.mode(), PaletteMode::TrainFor(2));
+        palette.add_sample(0, &RED, 0);
+        assert_eq!(palette.mode(), PaletteMode::TrainFor(1));
+    }
+
+    #[test]
+    fn test_add_sample_ignored_when_frozen() {
+        let mut palette: Palette<8> = Palette::new();
+        assert_eq!(palette.seed_from(&[RED]), 1);
+        palette.set_mode(PaletteMode::Frozen);
+
+        let darker_red = Rgb {
+            r: 150,
+            g: 20,
+            b: 30,
+        };
+        palette.add_sample(0, &darker_red, 0);
+        assert_eq!(palette.get(0), Some(RED));
+        assert_eq!(palette.get_entry(0).unwrap().count, 1);
+
+        // Unfrozen, the same sample moves the centroid.
+        palette.set_mode(PaletteMode::Learning);
+        palette.add_sample(0, &darker_red, 0);
+        assert_eq!(
+            palette.get(0),
+            Some(Rgb {
+                r: 175,
+                g: 20,
+                b: 30
+            })
+        );
+    }
+
+    #[test]
+    fn test_seed_stops_when_full() {
+        let mut palette: Palette<2> = Palette::new();
+        assert_eq!(palette.seed_from(&[RED, BLUE, GREEN]), 2);
+        assert_eq!(palette.seed_from(&[GREEN]), 0);
+        assert_eq!(palette.nearest(&BLUE), Some((1, 0)));
+    }
+}
+
+mod calibrate {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use sorter_logic::calibrate::{THRESHOLD_SWEEP, calibrate_threshold, score_threshold};
+    use sorter_logic::{ColorMetric, Rgb};
+
+    const KINDS: [(u8, u8, u8); 5] = [
+        (200, 20, 30),
+        (30, 60, 200),
+        (40, 170, 60),
+        (240, 200, 20),
+        (130, 50, 160),
+    ];
+
+    // Noisy measurements of a few bead kinds, labeled with the kind.
+    fn labeled(n: usize, noise: i32) -> Vec<(Rgb, usize)> {
+        let mut rng = StdRng::seed_from_u64(3);
+        (0..n)
+            .map(|_| {
+                let kind = rng.gen_range(0..KINDS.len());
+                let (r, g, b) = KINDS[kind];
+                let mut jitter =
+                    |c: u8| (c as i32 + rng.gen_range(-noise..=noise)).clamp(0, 255) as u8;
+                let rgb = Rgb {
+                    r: jitter(r),
+                    g: jitter(g),
+                    b: jitter(b),
+                };
+                (rgb, kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_extremes_split_or_collide() {
+        let samples = labeled(400, 8);
+
+        let tight = score_threshold::<128, _>(&samples, 1, ColorMetric::Lab);
+        assert!(tight.splits > 0);
+        assert_eq!(tight.collisions, 0);
+
+        let loose = score_threshold::<128, _>(&samples, 100_000, ColorMetric::Lab);
+        assert_eq!(loose.splits, 0);
+        assert_eq!(loose.entries, 1);
+        assert!(loose.collisions > 0);
+    }
+
+    #[test]
+    fn test_calibration_picks_the_best_of_the_sweep() {
+        let samples = labeled(400, 8);
+        let best =
+            calibrate_threshold::<128, _>(&samples, THRESHOLD_SWEEP, ColorMetric::Lab).unwrap();
+
+        for t in THRESHOLD_SWEEP {
+            let score = score_threshold::<128, _>(&samples, t, ColorMetric::Lab);
+            assert!(
+                best.errors() <= score.errors(),
+                "{:?} beats {:?}",
+                score,
+                best
+            );
+        }
+        assert!(best.accuracy() >= 95, "{:?}", best);
+        assert_eq!(
+            calibrate_threshold::<128, usize>(&samples, [], ColorMetric::Lab),
+            None
+        );
+    }
+}
+
+mod smoother {
+    use sorter_logic::smoother::{ClassSmoother, Smoothed};
+
+    #[test]
+    fn test_majority_and_weighted_votes() {
+        let mut smoother: ClassSmoother<4> = ClassSmoother::new();
+        assert_eq!(smoother.decision(), None);
+
+        smoother.push(2, 1);
+        smoother.push(5, 1);
+        smoother.push(2, 1);
+        assert_eq!(
+            smoother.decision(),
+            Some(Smoothed {
+                class: 2,
+                weight: 2,
+                total: 3
+            })
+        );
+
+        // One confident shot outweighs two marginal ones.
+        smoother.clear();
+        smoother.push(2, 20);
+        smoother.push(2, 20);
+        smoother.push(5, 90);
+        assert_eq!(smoother.decision().unwrap().class, 5);
+    }
+
+    #[test]
+    fn test_tie_goes_to_most_recent_class() {
+        let mut smoother: ClassSmoother<4> = ClassSmoother::new();
+        for class in [1, 4, 4, 1] {
+            smoother.push(class, 1);
+        }
+        assert_eq!(smoother.decision().unwrap().class, 1);
+        assert_eq!(smoother.decision().unwrap().agreement(), 50);
+    }
+
+    #[test]
+    fn test_oldest_votes_roll_off() {
+        let mut smoother: ClassSmoother<3> = ClassSmoother::new();
+        for class in [7, 7, 7, 9, 9] {
+            smoother.push(class, 1);
+        }
+        assert_eq!(smoother.len(), 3);
+        assert_eq!(
+            smoother.decision(),
+            Some(Smoothed {
+                class: 9,
+                weight: 2,
+                total: 3
+            })
+        );
+    }
+}
+
+mod drift {
+    use super::common;
+
+    use common::{BACKGROUND, HEIGHT, WIDTH, empty_frame, frame_with_bead};
+    use sorter_logic::{AnalysisConfig, DriftTracker, Rgb, analyze_image_debug};
+
+    const BEAD: Rgb = Rgb {
+        r: 200,
+        g: 90,
+        b: 40,
+    };
+
+    fn scaled(c: Rgb, (r, g, b): (f32, f32, f32)) -> Rgb {
+        Rgb {
+            r: (c.r as f32 * r) as u8,
+            g: (c.g as f32 * g) as u8,
+            b: (c.b as f32 * b) as u8,
+        }
+    }
+
+    fn measure(frame: &[u8], drift: Option<&DriftTracker>) -> Rgb {
+        let config = AnalysisConfig {
+            white_balance: drift.map(|d| d.correction()),
+            ..Default::default()
+        };
+        analyze_image_debug(frame, WIDTH, HEIGHT, None, None, config)
+            .unwrap()
+            .average_color
+    }
+
+    #[test]
+    fn test_no_drift_is_identity() {
+        let mut drift = DriftTracker::from_frame(&empty_frame(BACKGROUND), WIDTH, HEIGHT).unwrap();
+        let frame = frame_with_bead(BACKGROUND, BEAD);
+        for _ in 0..10 {
+            assert!(drift.update(&frame, WIDTH, HEIGHT));
+        }
+        assert_eq!(drift.current(), measure(&empty_frame(BACKGROUND), None));
+        assert!(measure(&frame, Some(&drift)).dist_lab(&measure(&frame, None)) <= 2);
+    }
+
+    #[test]
+    fn test_warm_up_is_compensated() {
+        let reference = measure(&frame_with_bead(BACKGROUND, BEAD), None);
+        let mut drift = DriftTracker::from_frame(&empty_frame(BACKGROUND), WIDTH, HEIGHT).unwrap();
+
+        // The LED slowly warms: red up, blue down, over a hundred frames.
+        let mut frame = Vec::new();
+        for i in 1..=100 {
+            let t = i as f32 / 100.0;
+            let cast = (1.0 + 0.1 * t, 1.0, 1.0 - 0.2 * t);
+            frame = frame_with_bead(scaled(BACKGROUND, cast), scaled(BEAD, cast));
+            drift.update(&frame, WIDTH, HEIGHT);
+        }
+
+        let raw = measure(&frame, None).dist_lab(&reference);
+        let corrected = measure(&frame, Some(&drift)).dist_lab(&reference);
+        assert!(raw > 30, "{}", raw);
+        assert!(corrected < raw / 3, "{} vs {}", corrected, raw);
+    }
+
+    #[test]
+    fn test_single_odd_frame_barely_moves() {
+        let mut drift = DriftTracker::from_frame(&empty_frame(BACKGROUND), WIDTH, HEIGHT).unwrap();
+        let black = Rgb { r: 0, g: 0, b: 0 };
+        drift.update(&empty_frame(black), WIDTH, HEIGHT);
+        let gains = drift.correction();
+        assert!(gains.gain_r < 1.1 && gains.gain_g < 1.1 && gains.gain_b < 1.1);
+    }
+}
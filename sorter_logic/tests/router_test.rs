@@ -0,0 +1,827 @@
+use sorter_logic::profile::Profile;
+use sorter_logic::router::{
+    HUE_BANDS, ROUTER_STATE_MAX, RouteReason, StateError, TubeRouter, TubeStrategy,
+};
+use sorter_logic::{BeadFinish, Rgb};
+use sorter_protocol::crc16;
+
+const RED: Rgb = Rgb {
+    r: 200,
+    g: 20,
+    b: 30,
+};
+const ORANGE: Rgb = Rgb {
+    r: 250,
+    g: 120,
+    b: 20,
+};
+const BLUE: Rgb = Rgb {
+    r: 30,
+    g: 60,
+    b: 200,
+};
+
+#[test]
+fn test_new_colors_claim_tubes() {
+    let mut router = TubeRouter::new(30);
+    let red = router.route(RED, 0).unwrap();
+    assert_eq!((red.tube, red.reason), (0, RouteReason::NewTube));
+    let blue = router.route(BLUE, 0).unwrap();
+    assert_eq!((blue.tube, blue.reason), (1, RouteReason::NewTube));
+    let red = router.route(RED, 0).unwrap();
+    assert_eq!((red.tube, red.reason), (0, RouteReason::Mapped));
+    assert_eq!(router.tubes()[0].count, 2);
+}
+
+#[test]
+fn test_tube_of_palette_entry() {
+    let mut router = TubeRouter::new(1);
+    router.route(RED, 0).unwrap();
+    // No tube left for blue, so its entry is mapped to red's tube.
+    assert_eq!(
+        router.route(BLUE, 0).unwrap().reason,
+        RouteReason::NoFreeTube
+    );
+    assert_eq!(router.tube_of(0), Some(0));
+    assert_eq!(router.tube_of(1), Some(0));
+    assert_eq!(router.tube_of(2), None);
+}
+
+#[test]
+fn test_match_threshold_override() {
+    let mut router = TubeRouter::new(30);
+    router.route(RED, 0);
+    // Orange is far outside the default threshold; a loose one takes it into red's entry.
+    router.set_match_threshold(RED.dist_lab(&ORANGE) + 1);
+    let orange = router.route(ORANGE, 0).unwrap();
+    assert_eq!((orange.tube, orange.reason), (0, RouteReason::Mapped));
+    assert_eq!(router.palette().len(), 1);
+}
+
+#[test]
+fn test_no_free_tube_goes_to_nearest() {
+    let mut router = TubeRouter::new(2);
+    router.route(RED, 0);
+    router.route(BLUE, 0);
+    let orange = router.route(ORANGE, 0).unwrap();
+    assert_eq!((orange.tube, orange.reason), (0, RouteReason::NoFreeTube));
+}
+
+#[test]
+fn test_full_tube_spills_to_empty_tube() {
+    let mut router = TubeRouter::new(3);
+    router.set_capacity(Some(2));
+    router.route(RED, 0);
+    router.route(RED, 0);
+    let spill = router.route(RED, 0).unwrap();
+    assert_eq!(
+        (spill.tube, spill.reason),
+        (1, RouteReason::Spillover { from: 0 })
+    );
+    // The overflow tube now takes red.
+    let next = router.route(RED, 0).unwrap();
+    assert_eq!((next.tube, next.reason), (1, RouteReason::Mapped));
+}
+
+#[test]
+fn test_full_tube_spills_to_nearest_color_with_room() {
+    let mut router = TubeRouter::new(3);
+    router.set_capacity(Some(2));
+    router.route(RED, 0);
+    router.route(BLUE, 0);
+    router.route(ORANGE, 0);
+    router.route(RED, 0);
+
+    // Red is full and no tube is free: orange is closer than blue.
+    let spill = router.route(RED, 0).unwrap();
+    assert_eq!(
+        (spill.tube, spill.reason),
+        (2, RouteReason::Spillover { from: 0 })
+    );
+
+    // Fill everything; then there is nowhere left.
+    router.route(BLUE, 0);
+    assert_eq!(router.route(RED, 0), None);
+}
+
+#[test]
+fn test_nearest_overflow_keeps_empty_tubes_for_new_colors() {
+    let mut router = TubeRouter::new(4);
+    router.set_strategy(TubeStrategy::NearestOverflow);
+    router.set_capacity(Some(2));
+    router.route(RED, 0);
+    router.route(BLUE, 0);
+    router.route(ORANGE, 0);
+    router.route(RED, 0);
+
+    // Red is full: it spills into orange rather than the empty tube.
+    let spill = router.route(RED, 0).unwrap();
+    assert_eq!(
+        (spill.tube, spill.reason),
+        (2, RouteReason::Spillover { from: 0 })
+    );
+    assert_eq!(router.tubes().len(), 3);
+}
+
+#[test]
+fn test_fixed_strategy_never_claims_a_tube() {
+    let mut router = TubeRouter::new(30);
+    router.set_strategy(TubeStrategy::Fixed);
+    assert_eq!(router.seed_tubes(&[RED, BLUE]), 2);
+
+    // Orange is a new palette entry, but shares red's tube.
+    let orange = router.route(ORANGE, 0).unwrap();
+    assert_eq!((orange.tube, orange.reason), (0, RouteReason::NoFreeTube));
+    assert_eq!(router.tubes().len(), 2);
+    assert_eq!(router.palette().len(), 3);
+
+    // Going back to first come lets the next new color claim a tube.
+    router.set_strategy(TubeStrategy::FirstCome);
+    let green = Rgb {
+        r: 40,
+        g: 180,
+        b: 60,
+    };
+    let route = router.route(green, 0).unwrap();
+    assert_eq!((route.tube, route.reason), (2, RouteReason::NewTube));
+}
+
+#[test]
+fn test_hue_ordered_tubes_follow_the_rainbow() {
+    let gray = Rgb {
+        r: 128,
+        g: 128,
+        b: 128,
+    };
+    let yellow = Rgb {
+        r: 220,
+        g: 200,
+        b: 30,
+    };
+    // One slot per hue band.
+    let mut router = TubeRouter::new(HUE_BANDS);
+    router.set_strategy(TubeStrategy::HueOrdered);
+    for color in [BLUE, gray, ORANGE, yellow] {
+        router.route(color, 0);
+    }
+    // Tubes are numbered by arrival, but sit in hue order.
+    let slots: Vec<u8> = (0..4).map(|t| router.slot(t)).collect();
+    assert_eq!(slots, [7, 12, 0, 1]);
+
+    // Fewer slots than bands: a band that is taken gives way to the nearest free slot.
+    let mut router = TubeRouter::new(3);
+    router.set_strategy(TubeStrategy::HueOrdered);
+    for color in [gray, ORANGE, yellow] {
+        router.route(color, 0);
+    }
+    let slots: Vec<u8> = (0..3).map(|t| router.slot(t)).collect();
+    assert_eq!(slots, [2, 0, 1]);
+
+    // Slots are kept across a reboot.
+    let mut state = [0u8; ROUTER_STATE_MAX];
+    let len = router.encode_state(&mut state);
+    let mut restored = TubeRouter::new(3);
+    restored.restore_state(&state[..len]).unwrap();
+    assert_eq!(restored.slot(0), 2);
+}
+
+#[test]
+fn test_fewer_tubes_keep_slots_in_range() {
+    // A tube past the new count moves to the nearest free slot.
+    let mut router = TubeRouter::new(HUE_BANDS);
+    router.set_strategy(TubeStrategy::HueOrdered);
+    router.route(BLUE, 0);
+    router.route(ORANGE, 0);
+    assert_eq!((router.slot(0), router.slot(1)), (7, 0));
+    router.set_tube_count(4);
+    assert_eq!((router.slot(0), router.slot(1)), (3, 0));
+    let blue = router.route(BLUE, 0).unwrap();
+    assert_eq!((blue.tube, blue.reason), (0, RouteReason::Mapped));
+
+    // With no slot free, it joins the nearest color's tube.
+    let mut router = TubeRouter::new(3);
+    for color in [RED, BLUE, ORANGE] {
+        router.route(color, 0);
+    }
+    router.set_tube_count(2);
+    assert_eq!(router.tubes().len(), 2);
+    assert_eq!((router.slot(0), router.slot(1)), (0, 1));
+    assert_eq!(router.tubes()[0].count, 2);
+    let orange = router.route(ORANGE, 0).unwrap();
+    assert_eq!((orange.tube, orange.reason), (0, RouteReason::Mapped));
+
+    let mut state = [0u8; ROUTER_STATE_MAX];
+    let len = router.encode_state(&mut state);
+    let mut restored = TubeRouter::new(2);
+    restored.restore_state(&state[..len]).unwrap();
+    assert_eq!(restored.route(BLUE, 0).unwrap().tube, 1);
+}
+
+#[test]
+fn test_strategy_names_and_ids() {
+    for strategy in TubeStrategy::ALL {
+        assert_eq!(TubeStrategy::from_name(strategy.name()), Some(strategy));
+        assert_eq!(TubeStrategy::from_id(strategy.id()), Some(strategy));
+    }
+    assert_eq!(TubeStrategy::from_id(TubeStrategy::ALL.len() as u8), None);
+}
+
+#[test]
+fn test_purity_drops_when_tube_is_shared() {
+    let mut router = TubeRouter::new(1);
+    assert_eq!(router.purity(0), None);
+    for _ in 0..3 {
+        router.route(RED, 0);
+    }
+    assert_eq!(router.purity(0), Some(100));
+
+    // One tube only: blue has to share it.
+    router.route(BLUE, 0);
+    assert_eq!(router.purity(0), Some(75));
+}
+
+#[test]
+fn test_state_round_trip() {
+    let mut router = TubeRouter::new(30);
+    for color in [RED, BLUE, RED, ORANGE] {
+        router.route(color, 0);
+    }
+    let mut state = [0u8; ROUTER_STATE_MAX];
+    let len = router.encode_state(&mut state);
+
+    let mut restored = TubeRouter::new(30);
+    restored.restore_state(&state[..len]).unwrap();
+    assert_eq!(restored.palette().len(), router.palette().len());
+    assert_eq!(restored.tubes(), router.tubes());
+    assert_eq!(restored.purity(0), router.purity(0));
+    let orange = restored.route(ORANGE, 0).unwrap();
+    assert_eq!((orange.tube, orange.reason), (2, RouteReason::Mapped));
+}
+
+#[test]
+fn test_state_rejects_damage_and_smaller_layouts() {
+    let mut router = TubeRouter::new(30);
+    router.route(RED, 0);
+    router.route(BLUE, 0);
+    let mut state = [0u8; ROUTER_STATE_MAX];
+    let len = router.encode_state(&mut state);
+
+    // Two tubes in use do not fit a one-tube build.
+    let mut small = TubeRouter::new(1);
+    assert_eq!(
+        small.restore_state(&state[..len]),
+        Err(StateError::DoesNotFit)
+    );
+    assert!(small.palette().is_empty());
+
+    state[10] ^= 1;
+    let mut restored = TubeRouter::new(30);
+    assert_eq!(
+        restored.restore_state(&state[..len]),
+        Err(StateError::BadCrc)
+    );
+    assert_eq!(
+        restored.restore_state(&[0xFF; 64]),
+        Err(StateError::BadHeader)
+    );
+}
+
+#[test]
+fn test_reject_distance_while_learning_and_frozen() {
+    let mut router = TubeRouter::new(30);
+    router.set_reject_distance(Some(RED.dist_lab(&ORANGE)));
+    // Nothing to be far from yet.
+    assert_eq!(router.route(RED, 0).unwrap().reason, RouteReason::NewTube);
+    // Learning, but orange is too far from red for an entry of its own.
+    assert_eq!(router.route(ORANGE, 0), None);
+    assert_eq!(router.palette().len(), 1);
+
+    let mut router = TubeRouter::new(30);
+    router.route(RED, 0);
+    router.route(BLUE, 0);
+    router.set_profile(Profile::Production);
+    router.set_reject_distance(Some(RED.dist_lab(&ORANGE)));
+    assert_eq!(router.route(ORANGE, 0), None);
+    let near_red = Rgb { r: 204, ..RED };
+    assert_eq!(router.route(near_red, 0).unwrap().tube, 0);
+}
+
+#[test]
+fn test_state_from_before_slots() {
+    let mut router = TubeRouter::new(30);
+    for color in [RED, BLUE, ORANGE] {
+        router.route(color, 0);
+    }
+    let mut state = [0u8; ROUTER_STATE_MAX];
+    let len = router.encode_state(&mut state);
+
+    // The older record: version 1, without the slot table or the finishes.
+    let mut old = state[..len - 2 - 3 - 6].to_vec();
+    old[4] = 1;
+    let crc = crc16(&old);
+    old.extend_from_slice(&crc.to_le_bytes());
+
+    let mut restored = TubeRouter::new(30);
+    restored.restore_state(&old).unwrap();
+    assert_eq!(restored.tubes(), router.tubes());
+    assert_eq!(
+        (0..3).map(|t| restored.slot(t)).collect::<Vec<_>>(),
+        [0, 1, 2]
+    );
+}
+
+#[test]
+fn test_split_finishes_keep_glitter_apart() {
+    let mut router = TubeRouter::new(30);
+    router.route(RED, 0);
+    // Off, the finish is ignored.
+    let glitter = router.route_finish(RED, 0, BeadFinish::Glitter).unwrap();
+    assert_eq!((glitter.tube, glitter.reason), (0, RouteReason::Mapped));
+
+    router.set_split_finishes(true);
+    let glitter = router.route_finish(RED, 0, BeadFinish::Glitter).unwrap();
+    assert_eq!((glitter.tube, glitter.reason), (1, RouteReason::NewTube));
+    assert_eq!(glitter.palette_index, 1);
+    let opaque = router.route(RED, 0).unwrap();
+    assert_eq!((opaque.tube, opaque.reason), (0, RouteReason::Mapped));
+
+    // The finishes survive a reboot.
+    let mut state = [0u8; ROUTER_STATE_MAX];
+    let len = router.encode_state(&mut state);
+    let mut restored = TubeRouter::new(30);
+    restored.set_split_finishes(true);
+    restored.restore_state(&state[..len]).unwrap();
+    let glitter = restored.route_finish(RED, 0, BeadFinish::Glitter).unwrap();
+    assert_eq!((glitter.tube, glitter.reason), (1, RouteReason::Mapped));
+}
+
+#[test]
+fn test_split_finishes_do_not_merge_across_finishes() {
+    let mut router = TubeRouter::new(30);
+    router.set_split_finishes(true);
+    router.set_merge_margin(RED.dist_lab(&ORANGE) + 1);
+    router.route(RED, 0);
+    let orange = router.route(ORANGE, 0).unwrap();
+    assert_eq!(orange.tube, 0);
+    let pearl = router.route_finish(ORANGE, 0, BeadFinish::Pearl).unwrap();
+    assert_eq!((pearl.tube, pearl.reason), (1, RouteReason::NewTube));
+
+    // With no tube to spare, a new finish shares the nearest tube after all.
+    let mut router = TubeRouter::new(1);
+    router.set_split_finishes(true);
+    router.route(RED, 0);
+    let glitter = router.route_finish(RED, 0, BeadFinish::Glitter).unwrap();
+    assert_eq!((glitter.tube, glitter.reason), (0, RouteReason::NoFreeTube));
+}
+
+mod layout {
+    use sorter_logic::layout::{LAYOUT_BYTES, LAYOUT_PACKET_LEN, LayoutError, TubeLayout};
+    use sorter_protocol::LAYOUT_MAGIC;
+
+    fn build(rows: u8) -> TubeLayout {
+        TubeLayout {
+            rows,
+            drop_positions: [2153, 2020, 1887, 1780, 1650, 1540],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_supported_builds() {
+        for (rows, tubes) in [(1, 15), (2, 30), (3, 45)] {
+            let layout = build(rows);
+            assert_eq!(layout.validate(), Ok(()));
+            assert_eq!(layout.tube_count(), tubes);
+            assert!(layout.locate(tubes as u8 - 1).is_some());
+            assert_eq!(layout.locate(tubes as u8), None);
+        }
+    }
+
+    #[test]
+    fn test_default_matches_original_mapping() {
+        let layout = TubeLayout::default();
+        for tube in 0..30u8 {
+            let spot = layout.locate(tube).unwrap();
+            let row_index = ((tube / 15) << 1) | ((tube % 15) & 1);
+            assert_eq!(spot.drop_row, row_index);
+            assert_eq!(spot.slice, tube % 15);
+        }
+        assert_eq!(layout.locate(16).unwrap().drop_position, 1780);
+    }
+
+    #[test]
+    fn test_validate_reports_gaps() {
+        // The default only has drop positions for two rows.
+        let layout = TubeLayout {
+            rows: 3,
+            ..Default::default()
+        };
+        assert_eq!(layout.validate(), Err(LayoutError::MissingDropPosition(4)));
+
+        let mut layout = TubeLayout::default();
+        layout.chute_positions[7] = 0;
+        assert_eq!(layout.validate(), Err(LayoutError::MissingChutePosition(7)));
+
+        let layout = TubeLayout {
+            rows: 4,
+            ..Default::default()
+        };
+        assert_eq!(layout.validate(), Err(LayoutError::TooLarge));
+
+        let layout = TubeLayout {
+            slices: 0,
+            ..Default::default()
+        };
+        assert_eq!(layout.validate(), Err(LayoutError::Empty));
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let layout = build(3);
+        assert_eq!(TubeLayout::from_bytes(&layout.to_bytes()), Ok(layout));
+
+        // Erased flash.
+        assert_eq!(
+            TubeLayout::from_bytes(&[0xFF; LAYOUT_BYTES]),
+            Err(LayoutError::BadHeader)
+        );
+
+        let mut damaged = layout.to_bytes();
+        damaged[10] ^= 1;
+        assert_eq!(TubeLayout::from_bytes(&damaged), Err(LayoutError::BadCrc));
+
+        // Stored but invalid layouts are rejected too.
+        let bad = TubeLayout {
+            rows: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            TubeLayout::from_bytes(&bad.to_bytes()),
+            Err(LayoutError::MissingDropPosition(4))
+        );
+    }
+
+    #[test]
+    fn test_packet_keeps_unfinished_edits() {
+        // Halfway through adding a third row.
+        let editing = TubeLayout {
+            rows: 3,
+            ..Default::default()
+        };
+        let mut packet = [0u8; LAYOUT_PACKET_LEN];
+        let len = editing.encode(&mut packet);
+        assert_eq!(packet[..4], LAYOUT_MAGIC);
+        assert_eq!(TubeLayout::decode(&packet[4..len]), Some(editing));
+
+        assert_eq!(TubeLayout::decode(&packet[4..len - 1]), None);
+        assert_eq!(TubeLayout::decode(&[0xFF; LAYOUT_BYTES]), None);
+    }
+
+    #[test]
+    fn test_fits_servo_ranges() {
+        let layout = TubeLayout::default();
+        assert!(layout.fits((500, 2500), (500, 2500)));
+        // The first slice is at 545 and the top drop row at 2153.
+        assert!(!layout.fits((600, 2500), (500, 2500)));
+        assert!(!layout.fits((500, 2500), (500, 2100)));
+        // Positions past the layout's shape are not checked.
+        assert!(build(2).fits((500, 2500), (1700, 2200)));
+    }
+}
+
+mod profile {
+    use sorter_logic::Rgb;
+    use sorter_logic::profile::Profile;
+    use sorter_logic::router::{ROUTER_STATE_MAX, TubeRouter};
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    const BLUE: Rgb = Rgb {
+        r: 30,
+        g: 60,
+        b: 200,
+    };
+    const PURPLE: Rgb = Rgb {
+        r: 130,
+        g: 40,
+        b: 160,
+    };
+
+    #[test]
+    fn test_names_and_ids_round_trip() {
+        for p in Profile::ALL {
+            assert_eq!(Profile::from_name(p.name()), Some(p));
+            assert_eq!(Profile::from_id(p.id()), Some(p));
+        }
+        assert_eq!(Profile::from_name("fast"), None);
+        assert_eq!(Profile::from_id(3), None);
+        assert_eq!(Profile::Strict.next(), Profile::Learning);
+    }
+
+    fn trained() -> TubeRouter {
+        let mut router = TubeRouter::new(30);
+        router.route(RED, 0);
+        router.route(BLUE, 0);
+        router
+    }
+
+    #[test]
+    fn test_learning_adds_entries() {
+        let mut router = trained();
+        router.route(PURPLE, 0);
+        assert_eq!(router.palette().len(), 3);
+        assert_eq!(router.tubes().len(), 3);
+    }
+
+    #[test]
+    fn test_production_uses_nearest_entry() {
+        let mut router = trained();
+        router.set_profile(Profile::Production);
+        let route = router.route(PURPLE, 0).unwrap();
+        assert!(route.tube < 2);
+        assert_eq!(router.palette().len(), 2);
+        assert_eq!(router.tubes().len(), 2);
+    }
+
+    #[test]
+    fn test_strict_rejects_unmatched() {
+        let mut router = trained();
+        router.set_profile(Profile::Strict);
+        assert_eq!(router.route(PURPLE, 0), None);
+        assert_eq!(router.route(RED, 0).map(|r| r.tube), Some(0));
+
+        // Back to learning picks up where it left off.
+        router.set_profile(Profile::Learning);
+        assert_eq!(router.route(PURPLE, 0).map(|r| r.tube), Some(2));
+    }
+
+    #[test]
+    fn test_empty_palette_learns_under_any_profile() {
+        for profile in [Profile::Production, Profile::Strict] {
+            let mut router = TubeRouter::new(30);
+            router.set_profile(profile);
+            assert!(!router.palette().is_frozen());
+            assert_eq!(router.route(RED, 0).map(|r| r.tube), Some(0));
+            assert_eq!(router.route(BLUE, 0).map(|r| r.tube), Some(1));
+
+            // With entries to sort into, the profile freezes the palette.
+            router.set_profile(profile);
+            assert!(router.palette().is_frozen());
+            router.route(PURPLE, 0);
+            assert_eq!(router.palette().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_restored_palette_takes_the_profile_mode() {
+        let mut state = [0u8; ROUTER_STATE_MAX];
+        let len = trained().encode_state(&mut state);
+        let mut router = TubeRouter::new(30);
+        router.set_profile(Profile::Production);
+        router.restore_state(&state[..len]).unwrap();
+        assert!(router.palette().is_frozen());
+        assert!(router.route(PURPLE, 0).unwrap().tube < 2);
+    }
+}
+
+mod collect {
+    use sorter_logic::collect::{Collection, Extraction};
+    use sorter_logic::{Palette, PaletteEntry, Rgb};
+    use sorter_protocol::CollectTarget;
+
+    const RED: Rgb = Rgb {
+        r: 200,
+        g: 20,
+        b: 30,
+    };
+    const BLUE: Rgb = Rgb {
+        r: 30,
+        g: 60,
+        b: 200,
+    };
+
+    #[test]
+    fn test_palette_entry_target_wants_its_nearest_beads() {
+        let mut palette: Palette<8> = Palette::new();
+        palette.seed_entry(PaletteEntry::new(RED, 0));
+        palette.seed_entry(PaletteEntry::new(BLUE, 0));
+        let collection = Collection::new(CollectTarget::PaletteEntry(1), 10);
+
+        let near_blue = Rgb {
+            r: 34,
+            g: 60,
+            b: 196,
+        };
+        let cyan = Rgb {
+            r: 30,
+            g: 200,
+            b: 200,
+        };
+        assert!(collection.wants(&palette, &near_blue, 15));
+        assert!(!collection.wants(&palette, &RED, 15));
+        // Nearest to the target entry, but too far from it to count as a match.
+        assert!(!collection.wants(&palette, &cyan, 15));
+        // With nothing learned yet, no bead matches an entry.
+        assert!(!collection.wants(&Palette::<8>::new(), &BLUE, 15));
+    }
+
+    #[test]
+    fn test_record_counts_up_to_the_wanted_number() {
+        let mut collection = Collection::new(CollectTarget::PaletteEntry(0), 3);
+        assert!(!collection.record());
+        assert!(!collection.record());
+        assert!(!collection.is_complete());
+        assert!(collection.record());
+        assert!(collection.record());
+        assert_eq!((collection.collected(), collection.wanted()), (3, 3));
+    }
+
+    #[test]
+    fn test_extraction_wants_beads_within_tolerance() {
+        let (l, a, b) = RED.to_lab();
+        let lab = (l as u8, a as i8, b as i8);
+        let near_red = Rgb {
+            r: 190,
+            g: 25,
+            b: 35,
+        };
+        assert!(Extraction::new(lab, 6).wants(&RED));
+        assert!(Extraction::new(lab, 6).wants(&near_red));
+        assert!(!Extraction::new(lab, 1).wants(&near_red));
+        assert!(!Extraction::new(lab, 6).wants(&BLUE));
+    }
+}
+
+mod policy {
+    use sorter_logic::collect::{Collection, Extraction};
+    use sorter_logic::policy::{Decision, SortPolicy};
+    use sorter_logic::settings::{Setting, Settings};
+    use sorter_logic::{BeadAnalysis, analyze_image, doc_frame};
+    use sorter_protocol::CollectTarget;
+
+    fn bead(rgb: (u8, u8, u8)) -> BeadAnalysis {
+        analyze_image(&doc_frame(rgb), 40, 30).unwrap()
+    }
+
+    // Sorting over four tubes with tube 1 kept for rejects.
+    fn with_reject_tube() -> SortPolicy {
+        let mut settings = Settings::default();
+        settings.set(Setting::RejectTube, 1).unwrap();
+        let mut policy = SortPolicy::new(4);
+        policy.apply_settings(&settings);
+        policy
+    }
+
+    #[test]
+    fn test_reject_tube_shifts_the_tubes_after_it() {
+        let mut policy = with_reject_tube();
+        let red = policy.decide(&bead((200, 20, 30)), None, None);
+        let blue = policy.decide(&bead((30, 60, 200)), None, None);
+        assert_eq!((red.tube(), blue.tube()), (Some(0), Some(2)));
+        assert_eq!(policy.reject_tube(), Some(1));
+        assert_eq!(policy.drop_tube(None, false), 1);
+        assert!(policy.purity(2).is_some());
+        assert_eq!(policy.purity(1), None);
+
+        // Only three tubes left to route to; the fourth color shares one.
+        let green = policy.decide(&bead((30, 200, 40)), None, None);
+        let yellow = policy.decide(&bead((230, 220, 30)), None, None);
+        assert_eq!(green.tube(), Some(3));
+        assert_ne!(yellow.tube(), Some(1));
+        assert_eq!(policy.router().tubes().len(), 3);
+    }
+
+    #[test]
+    fn test_reject_tube_outside_the_layout_is_ignored() {
+        let mut settings = Settings::default();
+        settings.set(Setting::RejectTube, 4).unwrap();
+        let mut policy = SortPolicy::new(4);
+        policy.apply_settings(&settings);
+        assert_eq!(policy.reject_tube(), None);
+        assert_eq!(policy.drop_tube(None, false), 0);
+    }
+
+    #[test]
+    fn test_collection_and_extraction_pass_other_beads() {
+        let mut policy = with_reject_tube();
+        let (red, blue) = (bead((200, 20, 30)), bead((30, 60, 200)));
+        policy.decide(&red, None, None);
+        policy.decide(&blue, None, None);
+
+        let collection = Collection::new(CollectTarget::PaletteEntry(0), 5);
+        let collect = |policy: &mut SortPolicy, b| policy.decide(b, Some(&collection), None);
+        assert!(matches!(
+            collect(&mut policy, &red),
+            Decision::Routed { tube: 0, .. }
+        ));
+        assert_eq!(collect(&mut policy, &blue), Decision::Passed);
+
+        let (l, a, b) = red.average_color.to_lab();
+        let extraction = Extraction::new((l as u8, a as i8, b as i8), 6);
+        assert_eq!(
+            policy.decide(&red, None, Some(&extraction)),
+            Decision::Extracted
+        );
+        assert_eq!(
+            policy.decide(&blue, None, Some(&extraction)),
+            Decision::Passed
+        );
+        // The rest goes back in bulk, to the last tube.
+        assert_eq!(policy.drop_tube(None, true), 3);
+        assert_eq!(policy.drop_tube(Decision::Extracted.tube(), true), 0);
+    }
+
+    #[test]
+    fn test_unreliable_beads_are_not_learned() {
+        let mut settings = Settings::default();
+        settings.set(Setting::RejectTube, 1).unwrap();
+        settings.set(Setting::RejectConfidence, 50).unwrap();
+        let mut policy = SortPolicy::new(4);
+        policy.apply_settings(&settings);
+
+        let unsure = BeadAnalysis {
+            confidence: 40,
+            ..bead((200, 20, 30))
+        };
+        assert_eq!(policy.decide(&unsure, None, None), Decision::Unreliable);
+        assert!(policy.router().palette().is_empty());
+    }
+}
+
+mod hopper {
+    use sorter_logic::hopper::{HopperEvent, MAX_AGITATION, PickupMonitor};
+
+    #[test]
+    fn test_agitation_escalates() {
+        let mut m = PickupMonitor::new(2, 4);
+        assert_eq!(m.agitation_level(), 0);
+        m.record(false);
+        assert_eq!(m.agitation_level(), 0);
+        m.record(false);
+        assert_eq!(m.agitation_level(), 1);
+        for _ in 0..10 {
+            m.record(false);
+        }
+        assert_eq!(m.agitation_level(), MAX_AGITATION);
+
+        m.record(true);
+        assert_eq!(m.agitation_level(), 0);
+    }
+
+    #[test]
+    fn test_refill_prompt_and_resume() {
+        let mut m = PickupMonitor::new(2, 4);
+        // 6 empties to reach max agitation, then 4 more.
+        for _ in 0..9 {
+            assert_eq!(m.record(false), HopperEvent::None);
+        }
+        assert!(!m.needs_refill());
+        assert_eq!(m.record(false), HopperEvent::RefillNeeded);
+        assert!(m.needs_refill());
+        // Only reported once.
+        assert_eq!(m.record(false), HopperEvent::None);
+
+        assert_eq!(m.record(true), HopperEvent::Refilled);
+        assert!(!m.needs_refill());
+        assert_eq!(m.record(true), HopperEvent::None);
+    }
+
+    #[test]
+    fn test_occasional_empties_do_not_trigger() {
+        let mut m = PickupMonitor::default();
+        for _ in 0..100 {
+            m.record(false);
+            m.record(false);
+            assert_eq!(m.record(true), HopperEvent::None);
+        }
+    }
+
+    #[test]
+    fn test_stall_after_refill_prompt_and_resume() {
+        let mut m = PickupMonitor::new(2, 4);
+        m.set_stall_after(3);
+        for _ in 0..10 {
+            m.record(false);
+        }
+        assert!(m.needs_refill());
+        assert_eq!(m.record(false), HopperEvent::None);
+        assert_eq!(m.record(false), HopperEvent::None);
+        assert_eq!(m.record(false), HopperEvent::Stalled);
+        assert!(m.is_stalled());
+        assert_eq!(m.record(false), HopperEvent::None);
+
+        // After a resume the operator gets the full limit again.
+        m.resume();
+        assert!(!m.is_stalled() && m.needs_refill());
+        assert_eq!(m.agitation_level(), MAX_AGITATION);
+        assert_eq!(m.record(false), HopperEvent::None);
+        assert_eq!(m.record(false), HopperEvent::None);
+        assert_eq!(m.record(false), HopperEvent::Stalled);
+
+        assert_eq!(m.record(true), HopperEvent::Refilled);
+        assert!(!m.is_stalled());
+    }
+}
@@ -0,0 +1,529 @@
+//! Host to device commands and their framing.
+
+use crate::ServoId;
+
+/// First byte of a command frame.
+pub const SYNC: u8 = 0xC5;
+/// Longest frame body (opcode and arguments).
+pub const MAX_BODY: usize = 16;
+/// Longest frame: sync, length, body and checksum.
+pub const MAX_FRAME: usize = MAX_BODY + 3;
+/// Most data bytes in one [`Command::UploadChunk`].
+pub const UPLOAD_CHUNK: usize = MAX_BODY - 3;
+
+pub const CMD_EXPORT_INVENTORY: u8 = 0x02;
+/// Followed by a profile id.
+pub const CMD_SET_PROFILE: u8 = 0x03;
+pub const CMD_TELEMETRY: u8 = 0x04;
+pub const CMD_START: u8 = 0x10;
+pub const CMD_STOP: u8 = 0x11;
+pub const CMD_REQUEST_FRAME: u8 = 0x12;
+pub const CMD_QUERY_STATUS: u8 = 0x13;
+/// Followed by the match threshold and the tube merge margin, each a u16 LE.
+pub const CMD_SET_THRESHOLDS: u8 = 0x14;
+pub const CMD_HOME: u8 = 0x15;
+pub const CMD_GET_SETTINGS: u8 = 0x16;
+/// Followed by a setting id and its value (u16 LE).
+pub const CMD_SET_SETTING: u8 = 0x17;
+/// Followed by the offset (u16 LE) and up to [`UPLOAD_CHUNK`] data bytes.
+pub const CMD_UPLOAD_CHUNK: u8 = 0x18;
+/// Followed by the uploaded length (u16 LE).
+pub const CMD_LOAD_PALETTE: u8 = 0x19;
+pub const CMD_GET_STATS: u8 = 0x1A;
+/// Followed by a servo id and the pulse width in microseconds (u16 LE).
+pub const CMD_SET_SERVO: u8 = 0x1B;
+/// Followed by a servo id.
+pub const CMD_GET_SERVO: u8 = 0x1C;
+/// Followed by 1 to turn dataset mode on, 0 to turn it off.
+pub const CMD_DATASET_MODE: u8 = 0x1D;
+/// Followed by the most frames the device may send before waiting for a [`CMD_FRAME_ACK`];
+/// 0 turns flow control off.
+pub const CMD_FRAME_WINDOW: u8 = 0x1E;
+pub const CMD_FRAME_ACK: u8 = 0x1F;
+/// Followed by a setting id and its value (u16 LE).
+pub const CMD_TUNE_SETTING: u8 = 0x20;
+pub const CMD_SAVE_SETTINGS: u8 = 0x21;
+pub const CMD_RESET_TO_BOOTLOADER: u8 = 0x22;
+pub const CMD_GET_INFO: u8 = 0x23;
+pub const CMD_GET_SELF_TEST: u8 = 0x24;
+/// Followed by the bead count (u16 LE, 0 to stop collecting) and a [`CollectTarget`]: 0 and a
+/// palette entry, or 1 and a Lab color (L as a u8, a and b as i8).
+pub const CMD_COLLECT: u8 = 0x25;
+/// Followed by a Lab color (L as a u8, a and b as i8) and a tolerance (Delta E, u8).
+pub const CMD_EXTRACT: u8 = 0x26;
+pub const CMD_DUMP_LOG: u8 = 0x27;
+/// Followed by a [`LogLevel`] id.
+pub const CMD_SET_LOG_LEVEL: u8 = 0x28;
+pub const CMD_AUTO_EXPOSURE: u8 = 0x29;
+pub const CMD_GET_LAYOUT: u8 = 0x2A;
+/// Followed by the number of chute slices and of tube rows.
+pub const CMD_SET_LAYOUT_SHAPE: u8 = 0x2B;
+/// Followed by a chute slice and the chutes servo pulse width in microseconds (u16 LE).
+pub const CMD_SET_CHUTE_POSITION: u8 = 0x2C;
+/// Followed by a drop row and the hopper servo pulse width in microseconds (u16 LE).
+pub const CMD_SET_DROP_POSITION: u8 = 0x2D;
+pub const CMD_SAVE_LAYOUT: u8 = 0x2E;
+pub const CMD_FACTORY_RESET: u8 = 0x2F;
+
+const TARGET_PALETTE_ENTRY: u8 = 0;
+const TARGET_LAB: u8 = 1;
+
+/// How much the firmware writes to its debug console, for [`Command::SetLogLevel`]. Each
+/// level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 6] = [
+        LogLevel::Off,
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|l| l.name() == name)
+    }
+}
+
+/// Which beads [`Command::Collect`] wants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollectTarget {
+    /// Beads whose nearest palette entry is this one.
+    PaletteEntry(u8),
+    /// Beads close to this CIELAB color (same scale as `sorter_logic::Rgb::to_lab`), such as
+    /// a catalog color.
+    Lab { l: u8, a: i8, b: i8 },
+}
+
+/// A piece of an upload: `bytes()` go at `offset` in the device's upload buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    pub offset: u16,
+    len: u8,
+    data: [u8; UPLOAD_CHUNK],
+}
+
+impl Chunk {
+    /// `None` if `bytes` is empty or longer than [`UPLOAD_CHUNK`].
+    pub fn new(offset: u16, bytes: &[u8]) -> Option<Self> {
+        if bytes.is_empty() || bytes.len() > UPLOAD_CHUNK {
+            return None;
+        }
+        let mut data = [0u8; UPLOAD_CHUNK];
+        data[..bytes.len()].copy_from_slice(bytes);
+        Some(Self {
+            offset,
+            len: bytes.len() as u8,
+            data,
+        })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Reply with an inventory packet.
+    ExportInventory,
+    /// Reply with a telemetry packet.
+    Telemetry,
+    /// Switch to the profile with this id (`sorter_logic::profile::Profile::id`).
+    SetProfile(u8),
+    /// Resume sorting after [`Command::Stop`], releasing the servos. A pause from the button
+    /// still holds.
+    Start,
+    /// Emergency stop: halt the servos where they are, mid-move if need be, and abandon the
+    /// current bead. They stay put until [`Command::Start`].
+    Stop,
+    /// Capture a frame now and send it, even while stopped.
+    RequestFrame,
+    /// Reply with a status packet.
+    QueryStatus,
+    /// Override the profile's values until the next profile change.
+    SetThresholds {
+        match_threshold: u16,
+        merge_margin: u16,
+    },
+    /// Move both servos to their home positions.
+    Home,
+    /// Reply with a settings packet.
+    GetSettings,
+    /// Change one stored setting (`sorter_logic::settings::Setting::id`) and save it.
+    SetSetting { id: u8, value: u16 },
+    /// Store part of an upload.
+    UploadChunk(Chunk),
+    /// Replace the learned palette and tube map with the first `len` uploaded bytes
+    /// (`sorter_logic::router::TubeRouter::encode_state`) and save them. Replies with a status
+    /// packet, which shows whether the palette was taken.
+    LoadPalette { len: u16 },
+    /// Reply with a stats packet.
+    GetStats,
+    /// Stop sorting and move one servo, then reply with a servo packet.
+    SetServo { servo: ServoId, us: u16 },
+    /// Reply with a servo packet.
+    GetServo(ServoId),
+    /// While on, send a labeled capture (`sorter_logic::dataset`) for every bead sorted.
+    DatasetMode(bool),
+    /// Turn on frame flow control: at most this many frame (or dataset) packets are sent
+    /// before the host acknowledges one. 0 turns it off and frames stream without waiting.
+    FrameWindow(u8),
+    /// The host has received (or given up on) one frame packet.
+    FrameAck,
+    /// Change one setting like [`Command::SetSetting`] without saving it, for trying values
+    /// out. It lasts until a reboot, or is kept by the next save.
+    TuneSetting { id: u8, value: u16 },
+    /// Save the settings as they are now, tuned values included.
+    SaveSettings,
+    /// Reboot into the USB bootloader so new firmware can be copied on.
+    ResetToBootloader,
+    /// Ask for the firmware's build and protocol version.
+    GetInfo,
+    /// Ask for the results of the power-on self test.
+    GetSelfTest,
+    /// Sort only beads matching `target`, passing the rest to the reject tube, until `count`
+    /// of them are sorted; then send an `Event::TargetReached` and stop. A count of 0 goes
+    /// back to sorting everything. Ignored without a reject tube, as the rest would share a
+    /// tube with sorted beads; removing the reject tube ends the collection.
+    Collect { target: CollectTarget, count: u16 },
+    /// Pick out one color: beads within `tolerance` (Delta E) of the CIELAB color go to tube
+    /// 0, and everything else to the last tube, for a bulk return bin. Nothing is learned. A
+    /// tolerance of 0 goes back to sorting everything.
+    Extract { l: u8, a: i8, b: i8, tolerance: u8 },
+    /// Reply with the event log (`sorter_logic::event_log`), oldest entry first.
+    DumpLog,
+    /// Only write messages at this level or more severe to the debug console, until the next
+    /// reboot. Messages the firmware was built without stay out whatever the level.
+    SetLogLevel(LogLevel),
+    /// Let the camera's automatic exposure and gain settle again, then lock what they found,
+    /// as at power-up. The empty slot reference is retaken under the new exposure.
+    AutoExposure,
+    /// Reply with the tube layout being edited (`sorter_logic::layout`): the saved one, with
+    /// any changes not yet saved.
+    GetLayout,
+    /// Change the number of chute slices and tube rows in the layout being edited.
+    SetLayoutShape { slices: u8, rows: u8 },
+    /// Move one chute slice of the layout being edited.
+    SetChutePosition { slice: u8, us: u16 },
+    /// Move one drop row of the layout being edited. Rows count both parities, so there are
+    /// twice as many as tube rows.
+    SetDropPosition { row: u8, us: u16 },
+    /// Check the layout being edited against the servo ranges and save it. A layout with the
+    /// same shape is used straight away; a new shape takes effect at the next power-up.
+    SaveLayout,
+    /// Erase everything the sorter keeps in flash (settings, tube layout, profile, learned
+    /// palette and tube map) and reboot with the defaults.
+    FactoryReset,
+}
+
+impl Command {
+    /// Decode a frame body. `None` for an unknown opcode or missing arguments.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let (&op, args) = body.split_first()?;
+        let u16_at = |i: usize| Some(u16::from_le_bytes([*args.get(i)?, *args.get(i + 1)?]));
+        Some(match op {
+            CMD_EXPORT_INVENTORY => Command::ExportInventory,
+            CMD_TELEMETRY => Command::Telemetry,
+            CMD_SET_PROFILE => Command::SetProfile(*args.first()?),
+            CMD_START => Command::Start,
+            CMD_STOP => Command::Stop,
+            CMD_REQUEST_FRAME => Command::RequestFrame,
+            CMD_QUERY_STATUS => Command::QueryStatus,
+            CMD_SET_THRESHOLDS => Command::SetThresholds {
+                match_threshold: u16_at(0)?,
+                merge_margin: u16_at(2)?,
+            },
+            CMD_HOME => Command::Home,
+            CMD_GET_SETTINGS => Command::GetSettings,
+            CMD_SET_SETTING => Command::SetSetting {
+                id: *args.first()?,
+                value: u16_at(1)?,
+            },
+            CMD_UPLOAD_CHUNK => Command::UploadChunk(Chunk::new(u16_at(0)?, &args[2..])?),
+            CMD_LOAD_PALETTE => Command::LoadPalette { len: u16_at(0)? },
+            CMD_GET_STATS => Command::GetStats,
+            CMD_SET_SERVO => Command::SetServo {
+                servo: ServoId::from_id(*args.first()?)?,
+                us: u16_at(1)?,
+            },
+            CMD_GET_SERVO => Command::GetServo(ServoId::from_id(*args.first()?)?),
+            CMD_DATASET_MODE => Command::DatasetMode(*args.first()? != 0),
+            CMD_FRAME_WINDOW => Command::FrameWindow(*args.first()?),
+            CMD_FRAME_ACK => Command::FrameAck,
+            CMD_TUNE_SETTING => Command::TuneSetting {
+                id: *args.first()?,
+                value: u16_at(1)?,
+            },
+            CMD_SAVE_SETTINGS => Command::SaveSettings,
+            CMD_RESET_TO_BOOTLOADER => Command::ResetToBootloader,
+            CMD_GET_INFO => Command::GetInfo,
+            CMD_GET_SELF_TEST => Command::GetSelfTest,
+            CMD_DUMP_LOG => Command::DumpLog,
+            CMD_SET_LOG_LEVEL => Command::SetLogLevel(LogLevel::from_id(*args.first()?)?),
+            CMD_AUTO_EXPOSURE => Command::AutoExposure,
+            CMD_GET_LAYOUT => Command::GetLayout,
+            CMD_SET_LAYOUT_SHAPE => Command::SetLayoutShape {
+                slices: *args.first()?,
+                rows: *args.get(1)?,
+            },
+            CMD_SET_CHUTE_POSITION => Command::SetChutePosition {
+                slice: *args.first()?,
+                us: u16_at(1)?,
+            },
+            CMD_SET_DROP_POSITION => Command::SetDropPosition {
+                row: *args.first()?,
+                us: u16_at(1)?,
+            },
+            CMD_SAVE_LAYOUT => Command::SaveLayout,
+            CMD_FACTORY_RESET => Command::FactoryReset,
+            CMD_COLLECT => Command::Collect {
+                count: u16_at(0)?,
+                target: match *args.get(2)? {
+                    TARGET_PALETTE_ENTRY => CollectTarget::PaletteEntry(*args.get(3)?),
+                    TARGET_LAB => CollectTarget::Lab {
+                        l: *args.get(3)?,
+                        a: *args.get(4)? as i8,
+                        b: *args.get(5)? as i8,
+                    },
+                    _ => return None,
+                },
+            },
+            CMD_EXTRACT => Command::Extract {
+                l: *args.first()?,
+                a: *args.get(1)? as i8,
+                b: *args.get(2)? as i8,
+                tolerance: *args.get(3)?,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Write the frame body. Returns the length.
+    pub fn encode_body(&self, out: &mut [u8; MAX_BODY]) -> usize {
+        let (op, args): (u8, &[u8]) = match *self {
+            Command::ExportInventory => (CMD_EXPORT_INVENTORY, &[]),
+            Command::Telemetry => (CMD_TELEMETRY, &[]),
+            Command::SetProfile(id) => (CMD_SET_PROFILE, &[id]),
+            Command::Start => (CMD_START, &[]),
+            Command::Stop => (CMD_STOP, &[]),
+            Command::RequestFrame => (CMD_REQUEST_FRAME, &[]),
+            Command::QueryStatus => (CMD_QUERY_STATUS, &[]),
+            Command::SetThresholds {
+                match_threshold,
+                merge_margin,
+            } => {
+                let [a, b] = match_threshold.to_le_bytes();
+                let [c, d] = merge_margin.to_le_bytes();
+                out[..5].copy_from_slice(&[CMD_SET_THRESHOLDS, a, b, c, d]);
+                return 5;
+            }
+            Command::Home => (CMD_HOME, &[]),
+            Command::GetSettings => (CMD_GET_SETTINGS, &[]),
+            Command::GetStats => (CMD_GET_STATS, &[]),
+            Command::GetServo(servo) => (CMD_GET_SERVO, &[servo.id()]),
+            Command::DatasetMode(on) => (CMD_DATASET_MODE, &[on as u8]),
+            Command::FrameWindow(frames) => (CMD_FRAME_WINDOW, &[frames]),
+            Command::FrameAck => (CMD_FRAME_ACK, &[]),
+            Command::SetServo { servo, us } => {
+                let [a, b] = us.to_le_bytes();
+                out[..4].copy_from_slice(&[CMD_SET_SERVO, servo.id(), a, b]);
+                return 4;
+            }
+            Command::SaveSettings => (CMD_SAVE_SETTINGS, &[]),
+            Command::ResetToBootloader => (CMD_RESET_TO_BOOTLOADER, &[]),
+            Command::GetInfo => (CMD_GET_INFO, &[]),
+            Command::GetSelfTest => (CMD_GET_SELF_TEST, &[]),
+            Command::DumpLog => (CMD_DUMP_LOG, &[]),
+            Command::SetLogLevel(level) => (CMD_SET_LOG_LEVEL, &[level.id()]),
+            Command::AutoExposure => (CMD_AUTO_EXPOSURE, &[]),
+            Command::GetLayout => (CMD_GET_LAYOUT, &[]),
+            Command::SetLayoutShape { slices, rows } => {
+                out[..3].copy_from_slice(&[CMD_SET_LAYOUT_SHAPE, slices, rows]);
+                return 3;
+            }
+            Command::SetChutePosition { slice: index, us }
+            | Command::SetDropPosition { row: index, us } => {
+                let op = match self {
+                    Command::SetChutePosition { .. } => CMD_SET_CHUTE_POSITION,
+                    _ => CMD_SET_DROP_POSITION,
+                };
+                let [a, b] = us.to_le_bytes();
+                out[..4].copy_from_slice(&[op, index, a, b]);
+                return 4;
+            }
+            Command::SaveLayout => (CMD_SAVE_LAYOUT, &[]),
+            Command::FactoryReset => (CMD_FACTORY_RESET, &[]),
+            Command::Extract { l, a, b, tolerance } => {
+                out[..5].copy_from_slice(&[CMD_EXTRACT, l, a as u8, b as u8, tolerance]);
+                return 5;
+            }
+            Command::SetSetting { id, value } | Command::TuneSetting { id, value } => {
+                let op = match self {
+                    Command::SetSetting { .. } => CMD_SET_SETTING,
+                    _ => CMD_TUNE_SETTING,
+                };
+                let [a, b] = value.to_le_bytes();
+                out[..4].copy_from_slice(&[op, id, a, b]);
+                return 4;
+            }
+            Command::UploadChunk(chunk) => {
+                let [a, b] = chunk.offset.to_le_bytes();
+                let data = chunk.bytes();
+                out[..3].copy_from_slice(&[CMD_UPLOAD_CHUNK, a, b]);
+                out[3..3 + data.len()].copy_from_slice(data);
+                return 3 + data.len();
+            }
+            Command::LoadPalette { len } => {
+                let [a, b] = len.to_le_bytes();
+                out[..3].copy_from_slice(&[CMD_LOAD_PALETTE, a, b]);
+                return 3;
+            }
+            Command::Collect { target, count } => {
+                let [c0, c1] = count.to_le_bytes();
+                out[..3].copy_from_slice(&[CMD_COLLECT, c0, c1]);
+                return match target {
+                    CollectTarget::PaletteEntry(entry) => {
+                        out[3..5].copy_from_slice(&[TARGET_PALETTE_ENTRY, entry]);
+                        5
+                    }
+                    CollectTarget::Lab { l, a, b } => {
+                        out[3..7].copy_from_slice(&[TARGET_LAB, l, a as u8, b as u8]);
+                        7
+                    }
+                };
+            }
+        };
+        out[0] = op;
+        out[1..1 + args.len()].copy_from_slice(args);
+        1 + args.len()
+    }
+
+    /// Write the whole frame. Returns the length.
+    ///
+    /// ```
+    /// use sorter_protocol::{Command, MAX_FRAME, Parser};
+    ///
+    /// let command = Command::SetThresholds { match_threshold: 20, merge_margin: 0 };
+    /// let mut frame = [0u8; MAX_FRAME];
+    /// let len = command.encode(&mut frame);
+    ///
+    /// let mut parser = Parser::new();
+    /// let parsed: Vec<Command> = frame[..len].iter().filter_map(|&b| parser.push(b)).collect();
+    /// assert_eq!(parsed, [command]);
+    /// ```
+    pub fn encode(&self, out: &mut [u8; MAX_FRAME]) -> usize {
+        let mut body = [0u8; MAX_BODY];
+        let len = self.encode_body(&mut body);
+        out[0] = SYNC;
+        out[1] = len as u8;
+        out[2..2 + len].copy_from_slice(&body[..len]);
+        out[2 + len] = checksum(&body[..len]);
+        len + 3
+    }
+}
+
+fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |acc, b| acc ^ b)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Idle,
+    // Bare CMD_SET_PROFILE, waiting for the id.
+    ProfileId,
+    Length,
+    Body { len: usize, got: usize },
+    Checksum { len: usize },
+}
+
+/// Byte-at-a-time command reader.
+///
+/// Outside a frame, the bare bytes [`CMD_EXPORT_INVENTORY`], [`CMD_TELEMETRY`] and
+/// [`CMD_SET_PROFILE`] (plus a profile id) are commands of their own; anything else is
+/// skipped. A frame with a bad length or checksum, or an unknown opcode, is dropped.
+#[derive(Debug, Clone)]
+pub struct Parser {
+    state: State,
+    body: [u8; MAX_BODY],
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Idle,
+            body: [0; MAX_BODY],
+        }
+    }
+
+    /// Feed one byte; returns a command when one is complete.
+    pub fn push(&mut self, byte: u8) -> Option<Command> {
+        let (state, command) = match self.state {
+            State::Idle => match byte {
+                SYNC => (State::Length, None),
+                CMD_SET_PROFILE => (State::ProfileId, None),
+                CMD_EXPORT_INVENTORY | CMD_TELEMETRY => (State::Idle, Command::decode(&[byte])),
+                _ => (State::Idle, None),
+            },
+            State::ProfileId => (State::Idle, Some(Command::SetProfile(byte))),
+            State::Length => match byte as usize {
+                len @ 1..=MAX_BODY => (State::Body { len, got: 0 }, None),
+                _ => (State::Idle, None),
+            },
+            State::Body { len, got } => {
+                self.body[got] = byte;
+                if got + 1 == len {
+                    (State::Checksum { len }, None)
+                } else {
+                    (State::Body { len, got: got + 1 }, None)
+                }
+            }
+            State::Checksum { len } => {
+                let body = &self.body[..len];
+                let command = if checksum(body) == byte {
+                    Command::decode(body)
+                } else {
+                    None
+                };
+                (State::Idle, command)
+            }
+        };
+        self.state = state;
+        command
+    }
+}
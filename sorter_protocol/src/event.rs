@@ -0,0 +1,50 @@
+//! Unsolicited event packets.
+
+use crate::EVENT_MAGIC;
+
+/// Event packet length: magic, event code and a u16 argument.
+pub const EVENT_PACKET_LEN: usize = 7;
+
+const EVENT_HOPPER_STALLED: u8 = 0x01;
+const EVENT_TARGET_REACHED: u8 = 0x02;
+const EVENT_SERVO_STALLED: u8 = 0x03;
+
+/// Something the device reports without being asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Pickups kept coming back empty and the device stopped sorting.
+    HopperStalled { empty_pickups: u16 },
+    /// A `Command::Collect` has sorted all the beads it asked for, and the device stopped.
+    TargetReached { beads: u16 },
+    /// A servo kept drawing current after its move ended, and the device stopped sorting
+    /// and let the servos go limp. The current-sense voltage, in millivolts.
+    ServoStalled { sense_mv: u16 },
+}
+
+impl Event {
+    /// Write an event packet (magic included). Returns the length.
+    pub fn encode(&self, out: &mut [u8; EVENT_PACKET_LEN]) -> usize {
+        let (code, arg) = match *self {
+            Event::HopperStalled { empty_pickups } => (EVENT_HOPPER_STALLED, empty_pickups),
+            Event::TargetReached { beads } => (EVENT_TARGET_REACHED, beads),
+            Event::ServoStalled { sense_mv } => (EVENT_SERVO_STALLED, sense_mv),
+        };
+        out[..4].copy_from_slice(&EVENT_MAGIC);
+        out[4] = code;
+        out[5..7].copy_from_slice(&arg.to_le_bytes());
+        EVENT_PACKET_LEN
+    }
+
+    /// Decode the body of an event packet (everything after the magic). `None` if truncated
+    /// or the event is unknown.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let b = body.get(..EVENT_PACKET_LEN - 4)?;
+        let arg = u16::from_le_bytes([b[1], b[2]]);
+        match b[0] {
+            EVENT_HOPPER_STALLED => Some(Event::HopperStalled { empty_pickups: arg }),
+            EVENT_TARGET_REACHED => Some(Event::TargetReached { beads: arg }),
+            EVENT_SERVO_STALLED => Some(Event::ServoStalled { sense_mv: arg }),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Camera frame packet: [`FRAME_MAGIC`], the payload length (u16 LE), the payload, then the
+//! CRC-16 of the length bytes and payload (u16 LE).
+//!
+//! A byte dropped on the serial link shifts everything after it; the length and CRC let the
+//! host reject the damaged packet and look for the next magic instead of decoding a shifted
+//! image.
+
+use crate::{Crc16, FRAME_BYTES, FRAME_MAGIC};
+
+/// Magic and length.
+pub const HEADER_LEN: usize = 6;
+pub const CRC_LEN: usize = 2;
+/// A packet carrying one [`FRAME_BYTES`] frame.
+pub const PACKET_LEN: usize = HEADER_LEN + FRAME_BYTES + CRC_LEN;
+
+pub fn header(len: u16) -> [u8; HEADER_LEN] {
+    let [l0, l1] = len.to_le_bytes();
+    let [m0, m1, m2, m3] = FRAME_MAGIC;
+    [m0, m1, m2, m3, l0, l1]
+}
+
+/// The trailing CRC for `payload`.
+pub fn crc(payload: &[u8]) -> [u8; CRC_LEN] {
+    let mut crc = Crc16::new();
+    crc.update(&(payload.len() as u16).to_le_bytes());
+    crc.update(payload);
+    crc.finish().to_le_bytes()
+}
+
+/// Check a packet that starts with [`FRAME_MAGIC`] and return its payload. `None` if the
+/// packet is incomplete, its length is not [`FRAME_BYTES`], or the CRC does not match.
+///
+/// ```
+/// use sorter_protocol::{FRAME_BYTES, image};
+///
+/// let frame = [0x5Au8; FRAME_BYTES];
+/// let mut packet = image::header(FRAME_BYTES as u16).to_vec();
+/// packet.extend_from_slice(&frame);
+/// packet.extend_from_slice(&image::crc(&frame));
+/// assert_eq!(image::decode(&packet), Some(&frame[..]));
+///
+/// packet.remove(100); // a dropped byte
+/// assert_eq!(image::decode(&packet), None);
+/// ```
+pub fn decode(packet: &[u8]) -> Option<&[u8]> {
+    let header = packet.get(..HEADER_LEN)?;
+    if header[..4] != FRAME_MAGIC
+        || u16::from_le_bytes([header[4], header[5]]) as usize != FRAME_BYTES
+    {
+        return None;
+    }
+    let payload = packet.get(HEADER_LEN..HEADER_LEN + FRAME_BYTES)?;
+    let trailer = packet.get(HEADER_LEN + FRAME_BYTES..PACKET_LEN)?;
+    (trailer == crc(payload)).then_some(payload)
+}
@@ -0,0 +1,111 @@
+//! Firmware identity reply, so host tools can check they speak the same protocol, and what
+//! the firmware last panicked with.
+
+use crate::{INFO_MAGIC, PROTOCOL_VERSION};
+
+/// Longest git hash or BSP revision; longer text is cut short.
+pub const INFO_TEXT_LEN: usize = 16;
+/// Longest panic message kept across the reboot.
+pub const PANIC_TEXT_LEN: usize = 28;
+/// Info packet length: magic, protocol version (u16 LE), build time (u64 LE), the two
+/// NUL-padded texts and the NUL-padded panic message.
+pub const INFO_PACKET_LEN: usize = 4 + 2 + 8 + 2 * INFO_TEXT_LEN + PANIC_TEXT_LEN;
+// Before the panic message.
+const INFO_PACKET_LEN_NO_PANIC: usize = INFO_PACKET_LEN - PANIC_TEXT_LEN;
+
+/// Reply to [`crate::Command::GetInfo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Info {
+    /// The [`PROTOCOL_VERSION`] the firmware was built with.
+    pub protocol_version: u16,
+    /// Unix time of the build, in seconds.
+    pub build_time: u64,
+    git_hash: [u8; INFO_TEXT_LEN],
+    bsp_revision: [u8; INFO_TEXT_LEN],
+    last_panic: [u8; PANIC_TEXT_LEN],
+}
+
+impl Info {
+    /// Info for a build of this crate's [`PROTOCOL_VERSION`].
+    pub fn new(build_time: u64, git_hash: &str, bsp_revision: &str) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            build_time,
+            git_hash: text(git_hash),
+            bsp_revision: text(bsp_revision),
+            last_panic: [0; PANIC_TEXT_LEN],
+        }
+    }
+
+    /// Report that the firmware panicked with `message` before its last reboot.
+    pub fn with_panic(mut self, message: &str) -> Self {
+        self.last_panic = text(message);
+        self
+    }
+
+    /// The commit the firmware was built from, `-dirty` if it had local changes.
+    pub fn git_hash(&self) -> &str {
+        as_str(&self.git_hash)
+    }
+
+    /// The board support package version.
+    pub fn bsp_revision(&self) -> &str {
+        as_str(&self.bsp_revision)
+    }
+
+    /// What the firmware panicked with before its last reboot, cut short; `None` if it did not
+    /// (or is too old to say).
+    pub fn last_panic(&self) -> Option<&str> {
+        Some(as_str(&self.last_panic)).filter(|s| !s.is_empty())
+    }
+
+    /// True if the firmware speaks this crate's [`PROTOCOL_VERSION`].
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+
+    /// Write an info packet (magic included). Returns the length.
+    pub fn encode(&self, out: &mut [u8; INFO_PACKET_LEN]) -> usize {
+        out[..4].copy_from_slice(&INFO_MAGIC);
+        out[4..6].copy_from_slice(&self.protocol_version.to_le_bytes());
+        out[6..14].copy_from_slice(&self.build_time.to_le_bytes());
+        out[14..14 + INFO_TEXT_LEN].copy_from_slice(&self.git_hash);
+        out[14 + INFO_TEXT_LEN..INFO_PACKET_LEN_NO_PANIC].copy_from_slice(&self.bsp_revision);
+        out[INFO_PACKET_LEN_NO_PANIC..].copy_from_slice(&self.last_panic);
+        INFO_PACKET_LEN
+    }
+
+    /// Decode the body of an info packet (everything after the magic). `None` if truncated.
+    /// Firmware from before the panic message sends none.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let b = body.get(..INFO_PACKET_LEN_NO_PANIC - 4)?;
+        let last_panic = match body.get(b.len()..INFO_PACKET_LEN - 4) {
+            Some(p) => p.try_into().ok()?,
+            None => [0; PANIC_TEXT_LEN],
+        };
+        Some(Self {
+            protocol_version: u16::from_le_bytes([b[0], b[1]]),
+            build_time: u64::from_le_bytes(b[2..10].try_into().ok()?),
+            git_hash: b[10..10 + INFO_TEXT_LEN].try_into().ok()?,
+            bsp_revision: b[10 + INFO_TEXT_LEN..].try_into().ok()?,
+            last_panic,
+        })
+    }
+}
+
+// Cut short at a character boundary.
+fn text<const N: usize>(s: &str) -> [u8; N] {
+    let mut out = [0u8; N];
+    let mut len = s.len().min(N);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    out[..len].copy_from_slice(&s.as_bytes()[..len]);
+    out
+}
+
+// Up to the first NUL; empty if the firmware sent something that is not UTF-8.
+fn as_str<const N: usize>(b: &[u8; N]) -> &str {
+    let len = b.iter().position(|&c| c == 0).unwrap_or(N);
+    core::str::from_utf8(&b[..len]).unwrap_or("")
+}
@@ -0,0 +1,64 @@
+//! Inventory reply: [`INVENTORY_MAGIC`](crate::INVENTORY_MAGIC), an entry count, then one
+//! [`ENTRY_BYTES`] entry per palette entry.
+
+pub const ENTRY_BYTES: usize = 8;
+
+/// `tube` of an entry whose beads have not been given a tube yet.
+pub const NO_TUBE: u8 = 0xFF;
+
+/// Packet length for `entries` palette entries.
+pub const fn packet_len(entries: usize) -> usize {
+    5 + entries * ENTRY_BYTES
+}
+
+/// One palette entry: `[tube, r, g, b, count (u32 LE)]`, where `tube` is the layout tube its
+/// beads go to ([`NO_TUBE`] for none) and `count` the beads matched to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub tube: u8,
+    pub rgb: (u8, u8, u8),
+    pub count: u32,
+}
+
+impl Entry {
+    pub fn encode(&self) -> [u8; ENTRY_BYTES] {
+        let [c0, c1, c2, c3] = self.count.to_le_bytes();
+        let (r, g, b) = self.rgb;
+        [self.tube, r, g, b, c0, c1, c2, c3]
+    }
+
+    pub fn decode(e: &[u8; ENTRY_BYTES]) -> Self {
+        Self {
+            tube: e[0],
+            rgb: (e[1], e[2], e[3]),
+            count: u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+        }
+    }
+}
+
+/// Write an inventory packet (magic included) into `out`, which must hold
+/// [`packet_len`]`(n)` bytes for `n` entries. Returns the length.
+pub fn encode(entries: impl IntoIterator<Item = Entry>, out: &mut [u8]) -> usize {
+    out[..4].copy_from_slice(&crate::INVENTORY_MAGIC);
+    let mut count = 0u8;
+    let mut len = 5;
+    for entry in entries {
+        out[len..len + ENTRY_BYTES].copy_from_slice(&entry.encode());
+        len += ENTRY_BYTES;
+        count += 1;
+    }
+    out[4] = count;
+    len
+}
+
+/// The entries in the body of an inventory packet (everything after the magic). `None` if
+/// the body is truncated.
+pub fn decode(body: &[u8]) -> Option<impl Iterator<Item = Entry> + '_> {
+    let (&n, rest) = body.split_first()?;
+    let entries = rest.get(..n as usize * ENTRY_BYTES)?;
+    Some(
+        entries
+            .chunks_exact(ENTRY_BYTES)
+            .map(|e| Entry::decode(e.try_into().unwrap())),
+    )
+}
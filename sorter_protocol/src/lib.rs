@@ -0,0 +1,101 @@
+//! The wire format on the sorter's data port, shared by the firmware and the host tools.
+//!
+//! Device to host, every packet starts with a four-byte magic `BE AD 1F <kind>`:
+//!
+//! - [`FRAME_MAGIC`]: a camera frame, 40x30 RGB565 pixels, big endian, row major, with a
+//!   length and CRC ([`image`]);
+//! - [`INVENTORY_MAGIC`]: the palette's colors, tubes and counts ([`inventory`]);
+//! - [`TELEMETRY_MAGIC`]: buffer high-water marks (encoded by `sorter_logic::telemetry`);
+//! - [`STATUS_MAGIC`]: a [`Status`];
+//! - [`SETTINGS_MAGIC`]: the stored settings (encoded by `sorter_logic::settings`);
+//! - [`EVENT_MAGIC`]: an [`Event`], sent unprompted;
+//! - [`STATS_MAGIC`]: sorting totals and cycle timing ([`stats`]);
+//! - [`SERVO_MAGIC`]: a [`ServoPosition`];
+//! - [`DATASET_MAGIC`]: a frame and what the sorter made of it (encoded by
+//!   `sorter_logic::dataset`);
+//! - [`INFO_MAGIC`]: the firmware's build, [`PROTOCOL_VERSION`] and last panic ([`Info`]);
+//! - [`SELF_TEST_MAGIC`]: the power-on self test results ([`SelfTestReport`]);
+//! - [`LOG_MAGIC`]: the event log (encoded by `sorter_logic::event_log`);
+//! - [`RECORD_MAGIC`]: a [`CycleRecord`] of one sort cycle, sent unprompted ([`record`]);
+//! - [`LAYOUT_MAGIC`]: the tube layout being edited (encoded by `sorter_logic::layout`).
+//!
+//! Host to device, a [`Command`] travels in a frame: [`SYNC`], the body length, the body (an
+//! opcode and its arguments) and the XOR of the body bytes. [`Parser`] reads frames a byte at
+//! a time, and also the original bare command bytes so older host tools keep working. Data
+//! too large for one frame goes up as [`Command::UploadChunk`]s for a later command such as
+//! [`Command::LoadPalette`] to use.
+//!
+//! The crate is `no_std` and allocation free. Cycle records use postcard; everything else is
+//! laid out by hand.
+
+#![no_std]
+
+mod command;
+mod crc;
+mod event;
+pub mod image;
+mod info;
+pub mod inventory;
+pub mod record;
+mod selftest;
+mod servo;
+pub mod stats;
+mod status;
+
+pub use command::{
+    CMD_AUTO_EXPOSURE, CMD_COLLECT, CMD_DATASET_MODE, CMD_DUMP_LOG, CMD_EXPORT_INVENTORY,
+    CMD_EXTRACT, CMD_FACTORY_RESET, CMD_FRAME_ACK, CMD_FRAME_WINDOW, CMD_GET_INFO, CMD_GET_LAYOUT,
+    CMD_GET_SELF_TEST, CMD_GET_SERVO, CMD_GET_SETTINGS, CMD_GET_STATS, CMD_HOME, CMD_LOAD_PALETTE,
+    CMD_QUERY_STATUS, CMD_REQUEST_FRAME, CMD_RESET_TO_BOOTLOADER, CMD_SAVE_LAYOUT,
+    CMD_SAVE_SETTINGS, CMD_SET_CHUTE_POSITION, CMD_SET_DROP_POSITION, CMD_SET_LAYOUT_SHAPE,
+    CMD_SET_LOG_LEVEL, CMD_SET_PROFILE, CMD_SET_SERVO, CMD_SET_SETTING, CMD_SET_THRESHOLDS,
+    CMD_START, CMD_STOP, CMD_TELEMETRY, CMD_TUNE_SETTING, CMD_UPLOAD_CHUNK,
+};
+pub use command::{
+    Chunk, CollectTarget, Command, LogLevel, MAX_BODY, MAX_FRAME, Parser, SYNC, UPLOAD_CHUNK,
+};
+pub use crc::{Crc16, crc16};
+pub use event::{EVENT_PACKET_LEN, Event};
+pub use info::{INFO_PACKET_LEN, INFO_TEXT_LEN, Info, PANIC_TEXT_LEN};
+pub use record::{CycleRecord, CycleResult, RECORD_PACKET_MAX};
+pub use selftest::{SELF_TEST_PACKET_LEN, SelfTestItem, SelfTestReport};
+pub use servo::{SERVO_PACKET_LEN, ServoId, ServoPosition};
+pub use status::{STATUS_PACKET_LEN, Status};
+
+/// Packet magic that precedes a camera frame (`BE AD 1F 01`).
+pub const FRAME_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x01];
+/// Packet magic for an inventory reply (`BE AD 1F 02`).
+pub const INVENTORY_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x02];
+/// Packet magic for a telemetry reply (`BE AD 1F 03`).
+pub const TELEMETRY_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x03];
+/// Packet magic for a status reply (`BE AD 1F 04`).
+pub const STATUS_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x04];
+/// Packet magic for a settings reply (`BE AD 1F 05`).
+pub const SETTINGS_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x05];
+/// Packet magic for an event (`BE AD 1F 06`).
+pub const EVENT_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x06];
+/// Packet magic for a stats reply (`BE AD 1F 07`).
+pub const STATS_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x07];
+/// Packet magic for a servo position reply (`BE AD 1F 08`).
+pub const SERVO_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x08];
+/// Packet magic for a labeled capture in dataset mode (`BE AD 1F 09`).
+pub const DATASET_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x09];
+/// Packet magic for a firmware info reply (`BE AD 1F 0A`).
+pub const INFO_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x0A];
+/// Packet magic for a self test report (`BE AD 1F 0B`).
+pub const SELF_TEST_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x0B];
+/// Packet magic for an event log reply (`BE AD 1F 0C`).
+pub const LOG_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x0C];
+/// Packet magic for a cycle record (`BE AD 1F 0D`).
+pub const RECORD_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x0D];
+/// Packet magic for the tube layout reply (`BE AD 1F 0E`).
+pub const LAYOUT_MAGIC: [u8; 4] = [0xBE, 0xAD, 0x1F, 0x0E];
+
+/// Version of this wire format, reported by [`Command::GetInfo`]. Bump it whenever a packet
+/// or command changes in a way older host tools or firmware would misread.
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Camera frame size (the [`image`] packet payload).
+pub const FRAME_WIDTH: usize = 40;
+pub const FRAME_HEIGHT: usize = 30;
+pub const FRAME_BYTES: usize = FRAME_WIDTH * FRAME_HEIGHT * 2;
@@ -0,0 +1,85 @@
+//! Cycle records: what one sort cycle did, sent unprompted at the end of every cycle so a
+//! host dashboard can follow the machine without parsing its debug log.
+//!
+//! A record packet is [`RECORD_MAGIC`](crate::RECORD_MAGIC), the body length (u8), then a
+//! [`CycleRecord`] in [postcard](https://docs.rs/postcard) encoding. Fields are only ever
+//! added at the end, so a host can read records from older firmware by giving new fields a
+//! default.
+//!
+//! ```
+//! use sorter_protocol::{CycleRecord, CycleResult, RECORD_PACKET_MAX};
+//!
+//! let record = CycleRecord {
+//!     uptime_ms: 61_500,
+//!     result: CycleResult::Sorted,
+//!     tube: Some(3),
+//!     color: Some([200, 20, 30]),
+//!     distance: Some(12),
+//!     pickup_ms: 640,
+//!     camera_ms: 310,
+//!     cycle_ms: 1450,
+//! };
+//! let mut packet = [0u8; RECORD_PACKET_MAX];
+//! let len = record.encode(&mut packet);
+//! assert_eq!(CycleRecord::decode(&packet[4..len]), Some(record));
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use crate::RECORD_MAGIC;
+
+/// Largest encoded [`CycleRecord`]: a u32 takes up to five bytes as a postcard varint.
+pub const RECORD_BODY_MAX: usize = 40;
+/// Largest record packet: magic, length, body.
+pub const RECORD_PACKET_MAX: usize = 5 + RECORD_BODY_MAX;
+
+/// How a cycle ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CycleResult {
+    /// The bead went to the tube picked for it.
+    Sorted,
+    /// The bead could not be analyzed or routed, or was passed over, and went to the reject
+    /// (or bulk return) tube.
+    Rejected,
+    /// The pickup came back empty.
+    Empty,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CycleRecord {
+    /// When the cycle ended, in milliseconds since boot.
+    pub uptime_ms: u32,
+    pub result: CycleResult,
+    /// Tube the bead was dropped into.
+    pub tube: Option<u8>,
+    /// The bead's measured color, RGB.
+    pub color: Option<[u8; 3]>,
+    /// Distance to the nearest learned palette entry before this bead was learned, under the
+    /// palette's color metric, if there was one.
+    pub distance: Option<u32>,
+    /// From the start of the cycle until the bead was at the camera.
+    pub pickup_ms: u32,
+    /// Photographing and analyzing the bead.
+    pub camera_ms: u32,
+    /// The whole cycle, drop included.
+    pub cycle_ms: u32,
+}
+
+impl CycleRecord {
+    /// Write a record packet (magic included). Returns the length.
+    pub fn encode(&self, out: &mut [u8; RECORD_PACKET_MAX]) -> usize {
+        out[..4].copy_from_slice(&RECORD_MAGIC);
+        let body = postcard::to_slice(self, &mut out[5..])
+            .expect("every record fits RECORD_BODY_MAX")
+            .len();
+        out[4] = body as u8;
+        5 + body
+    }
+
+    /// Decode the body of a record packet (everything after the magic). `None` if truncated
+    /// or malformed.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let (&len, rest) = body.split_first()?;
+        postcard::from_bytes(rest.get(..len as usize)?).ok()
+    }
+}
@@ -0,0 +1,96 @@
+//! Power-on self test results.
+
+use crate::SELF_TEST_MAGIC;
+
+/// Self test packet length: magic, then a bit per [`SelfTestItem`] for the items that were
+/// checked and for those that failed.
+pub const SELF_TEST_PACKET_LEN: usize = 6;
+
+/// One check of the power-on self test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestItem {
+    /// The camera answers on SCCB with the OV7670's product id.
+    CameraId,
+    /// A frame of the camera's color bar pattern comes back with the bars in order.
+    TestPattern,
+    /// The hopper servo swept to both endpoints.
+    HopperServo,
+    /// The chutes servo swept to both endpoints.
+    ChutesServo,
+    /// The neopixel cycled through red, green and blue.
+    Neopixel,
+}
+
+impl SelfTestItem {
+    pub const ALL: [SelfTestItem; 5] = [
+        SelfTestItem::CameraId,
+        SelfTestItem::TestPattern,
+        SelfTestItem::HopperServo,
+        SelfTestItem::ChutesServo,
+        SelfTestItem::Neopixel,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SelfTestItem::CameraId => "camera id",
+            SelfTestItem::TestPattern => "test pattern",
+            SelfTestItem::HopperServo => "hopper servo",
+            SelfTestItem::ChutesServo => "chutes servo",
+            SelfTestItem::Neopixel => "neopixel",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Reply to [`crate::Command::GetSelfTest`], also sent once at power-up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    checked: u8,
+    failed: u8,
+}
+
+impl SelfTestReport {
+    pub fn record(&mut self, item: SelfTestItem, passed: bool) {
+        self.checked |= item.bit();
+        if passed {
+            self.failed &= !item.bit();
+        } else {
+            self.failed |= item.bit();
+        }
+    }
+
+    /// `None` if the item was not checked.
+    pub fn result(&self, item: SelfTestItem) -> Option<bool> {
+        (self.checked & item.bit() != 0).then_some(self.failed & item.bit() == 0)
+    }
+
+    /// True if nothing that was checked failed.
+    pub fn passed(&self) -> bool {
+        self.failed == 0
+    }
+
+    /// Write a self test packet (magic included). Returns the length.
+    pub fn encode(&self, out: &mut [u8; SELF_TEST_PACKET_LEN]) -> usize {
+        out[..4].copy_from_slice(&SELF_TEST_MAGIC);
+        out[4] = self.checked;
+        out[5] = self.failed;
+        SELF_TEST_PACKET_LEN
+    }
+
+    /// Decode the body of a self test packet (everything after the magic). `None` if
+    /// truncated or it names unknown items.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let b = body.get(..SELF_TEST_PACKET_LEN - 4)?;
+        let known = SelfTestItem::ALL.iter().fold(0, |m, i| m | i.bit());
+        if (b[0] | b[1]) & !known != 0 || b[1] & !b[0] != 0 {
+            return None;
+        }
+        Some(Self {
+            checked: b[0],
+            failed: b[1],
+        })
+    }
+}
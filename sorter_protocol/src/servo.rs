@@ -0,0 +1,65 @@
+//! Servo position reply, for positioning the hardware by hand.
+
+use crate::SERVO_MAGIC;
+
+/// Servo packet length: magic, servo id and the pulse width (u16 LE).
+pub const SERVO_PACKET_LEN: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServoId {
+    /// Carries a bead from the pickup past the camera to the drop rows.
+    Hopper,
+    /// Swings the chutes to a tube column.
+    Chutes,
+}
+
+impl ServoId {
+    pub const ALL: [ServoId; 2] = [ServoId::Hopper, ServoId::Chutes];
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ServoId::Hopper => "hopper",
+            ServoId::Chutes => "chutes",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.name() == name)
+    }
+}
+
+/// Reply to [`crate::Command::GetServo`] and [`crate::Command::SetServo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServoPosition {
+    pub servo: ServoId,
+    /// Pulse width in microseconds, after clamping to the servo's range.
+    pub us: u16,
+}
+
+impl ServoPosition {
+    /// Write a servo packet (magic included). Returns the length.
+    pub fn encode(&self, out: &mut [u8; SERVO_PACKET_LEN]) -> usize {
+        out[..4].copy_from_slice(&SERVO_MAGIC);
+        out[4] = self.servo.id();
+        out[5..7].copy_from_slice(&self.us.to_le_bytes());
+        SERVO_PACKET_LEN
+    }
+
+    /// Decode the body of a servo packet (everything after the magic). `None` if truncated
+    /// or the servo is unknown.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let b = body.get(..SERVO_PACKET_LEN - 4)?;
+        Some(Self {
+            servo: ServoId::from_id(b[0])?,
+            us: u16::from_le_bytes([b[1], b[2]]),
+        })
+    }
+}
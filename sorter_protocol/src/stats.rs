@@ -0,0 +1,133 @@
+//! Stats reply: [`STATS_MAGIC`](crate::STATS_MAGIC), a [`Summary`], a tube count, then one
+//! [`TUBE_BYTES`] [`Tube`] per tube.
+
+/// Size of an encoded [`Summary`].
+pub const SUMMARY_BYTES: usize = 24;
+/// Size of an encoded [`Tube`].
+pub const TUBE_BYTES: usize = 5;
+
+/// Purity byte of a tube with no estimate yet.
+pub const NO_PURITY: u8 = 0xFF;
+
+/// Packet length for `tubes` tubes.
+pub const fn packet_len(tubes: usize) -> usize {
+    4 + SUMMARY_BYTES + 1 + tubes * TUBE_BYTES
+}
+
+/// Sorting totals since power-up. All fields are u32 LE on the wire, in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Summary {
+    pub uptime_secs: u32,
+    /// Beads dropped into the tube picked for them.
+    pub beads_sorted: u32,
+    /// Pickups that came back empty.
+    pub empties: u32,
+    /// Beads that could not be analyzed or routed.
+    pub rejects: u32,
+    /// Mean time from pickup to drop (or to the empty-slot check), in milliseconds.
+    pub avg_cycle_ms: u32,
+    /// Mean time per cycle saved by running the pipeline's stages side by side, in
+    /// milliseconds: the chutes turning while the hopper travels, and the next pickup starting
+    /// while the last bead falls. `avg_cycle_ms` already has it taken off.
+    pub avg_overlap_ms: u32,
+}
+
+impl Summary {
+    pub fn encode(&self) -> [u8; SUMMARY_BYTES] {
+        let mut out = [0u8; SUMMARY_BYTES];
+        let fields = [
+            self.uptime_secs,
+            self.beads_sorted,
+            self.empties,
+            self.rejects,
+            self.avg_cycle_ms,
+            self.avg_overlap_ms,
+        ];
+        for (chunk, v) in out.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&v.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn decode(b: &[u8; SUMMARY_BYTES]) -> Self {
+        let u32_at = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        Self {
+            uptime_secs: u32_at(0),
+            beads_sorted: u32_at(4),
+            empties: u32_at(8),
+            rejects: u32_at(12),
+            avg_cycle_ms: u32_at(16),
+            avg_overlap_ms: u32_at(20),
+        }
+    }
+}
+
+/// One tube: `[count (u32 LE), purity]`, where `count` is the beads dropped into it and
+/// `purity` the estimated share of them that match its color, in percent ([`NO_PURITY`] for
+/// none).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Tube {
+    pub count: u32,
+    pub purity: Option<u8>,
+}
+
+impl Tube {
+    pub fn encode(&self) -> [u8; TUBE_BYTES] {
+        let [c0, c1, c2, c3] = self.count.to_le_bytes();
+        [c0, c1, c2, c3, self.purity.unwrap_or(NO_PURITY)]
+    }
+
+    pub fn decode(t: &[u8; TUBE_BYTES]) -> Self {
+        Self {
+            count: u32::from_le_bytes([t[0], t[1], t[2], t[3]]),
+            purity: (t[4] != NO_PURITY).then_some(t[4]),
+        }
+    }
+}
+
+/// Write a stats packet (magic included) into `out`, which must hold
+/// [`packet_len`]`(n)` bytes for `n` tubes. Returns the length.
+///
+/// ```
+/// use sorter_protocol::stats::{self, Summary, Tube};
+///
+/// let summary = Summary { uptime_secs: 90, beads_sorted: 7, ..Default::default() };
+/// let tubes = [
+///     Tube { count: 4, purity: Some(100) },
+///     Tube { count: 0, purity: None },
+///     Tube { count: 3, purity: Some(67) },
+/// ];
+/// let mut packet = [0u8; stats::packet_len(3)];
+/// let len = stats::encode(&summary, tubes, &mut packet);
+///
+/// let (decoded, decoded_tubes) = stats::decode(&packet[4..len]).unwrap();
+/// assert_eq!(decoded, summary);
+/// assert_eq!(decoded_tubes.collect::<Vec<_>>(), tubes);
+/// ```
+pub fn encode(summary: &Summary, tubes: impl IntoIterator<Item = Tube>, out: &mut [u8]) -> usize {
+    out[..4].copy_from_slice(&crate::STATS_MAGIC);
+    out[4..4 + SUMMARY_BYTES].copy_from_slice(&summary.encode());
+    let mut count = 0u8;
+    let mut len = 5 + SUMMARY_BYTES;
+    for tube in tubes {
+        out[len..len + TUBE_BYTES].copy_from_slice(&tube.encode());
+        len += TUBE_BYTES;
+        count += 1;
+    }
+    out[4 + SUMMARY_BYTES] = count;
+    len
+}
+
+/// The summary and tubes in the body of a stats packet (everything after the magic). `None`
+/// if the body is truncated.
+pub fn decode(body: &[u8]) -> Option<(Summary, impl Iterator<Item = Tube> + '_)> {
+    let summary = Summary::decode(body.get(..SUMMARY_BYTES)?.try_into().unwrap());
+    let (&n, rest) = body[SUMMARY_BYTES..].split_first()?;
+    let tubes = rest.get(..n as usize * TUBE_BYTES)?;
+    Some((
+        summary,
+        tubes
+            .chunks_exact(TUBE_BYTES)
+            .map(|t| Tube::decode(t.try_into().unwrap())),
+    ))
+}
@@ -0,0 +1,54 @@
+//! Status reply.
+
+use crate::STATUS_MAGIC;
+
+/// Status packet length: magic, four one-byte fields, a u32 and two u16s.
+pub const STATUS_PACKET_LEN: usize = 16;
+
+/// Reply to [`crate::Command::QueryStatus`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    /// False after [`crate::Command::Stop`]. A pause from the button is not reflected.
+    pub running: bool,
+    pub profile_id: u8,
+    pub palette_entries: u8,
+    pub tubes_used: u8,
+    /// Beads routed to a tube since power-up.
+    pub beads_sorted: u32,
+    /// Lowest supply voltage during the last sort cycle, in millivolts; 0 before the first
+    /// reading.
+    pub supply_mv: u16,
+    /// Lowest supply voltage since power-up, in millivolts.
+    pub supply_min_mv: u16,
+}
+
+impl Status {
+    /// Write a status packet (magic included). Returns the length.
+    pub fn encode(&self, out: &mut [u8; STATUS_PACKET_LEN]) -> usize {
+        out[..4].copy_from_slice(&STATUS_MAGIC);
+        out[4..8].copy_from_slice(&[
+            self.running as u8,
+            self.profile_id,
+            self.palette_entries,
+            self.tubes_used,
+        ]);
+        out[8..12].copy_from_slice(&self.beads_sorted.to_le_bytes());
+        out[12..14].copy_from_slice(&self.supply_mv.to_le_bytes());
+        out[14..16].copy_from_slice(&self.supply_min_mv.to_le_bytes());
+        STATUS_PACKET_LEN
+    }
+
+    /// Decode the body of a status packet (everything after the magic). `None` if truncated.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let b = body.get(..STATUS_PACKET_LEN - 4)?;
+        Some(Self {
+            running: b[0] != 0,
+            profile_id: b[1],
+            palette_entries: b[2],
+            tubes_used: b[3],
+            beads_sorted: u32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+            supply_mv: u16::from_le_bytes([b[8], b[9]]),
+            supply_min_mv: u16::from_le_bytes([b[10], b[11]]),
+        })
+    }
+}
@@ -0,0 +1,313 @@
+use sorter_protocol::{
+    CMD_EXPORT_INVENTORY, CMD_SET_PROFILE, CMD_TELEMETRY, Chunk, CollectTarget, Command,
+    CycleRecord, CycleResult, EVENT_MAGIC, EVENT_PACKET_LEN, Event, INFO_MAGIC, INFO_PACKET_LEN,
+    Info, LogLevel, MAX_FRAME, PANIC_TEXT_LEN, Parser, RECORD_MAGIC, RECORD_PACKET_MAX,
+    SELF_TEST_MAGIC, SELF_TEST_PACKET_LEN, SERVO_MAGIC, SERVO_PACKET_LEN, STATUS_MAGIC,
+    STATUS_PACKET_LEN, SelfTestItem, SelfTestReport, ServoId, ServoPosition, Status, UPLOAD_CHUNK,
+    inventory,
+};
+
+const ALL: [Command; 34] = [
+    Command::ExportInventory,
+    Command::Telemetry,
+    Command::SetProfile(2),
+    Command::Start,
+    Command::Stop,
+    Command::RequestFrame,
+    Command::QueryStatus,
+    Command::SetThresholds {
+        match_threshold: 300,
+        merge_margin: 40,
+    },
+    Command::Home,
+    Command::GetSettings,
+    Command::SetSetting {
+        id: 7,
+        value: 0xBEEF,
+    },
+    Command::GetStats,
+    Command::SetServo {
+        servo: ServoId::Chutes,
+        us: 1500,
+    },
+    Command::GetServo(ServoId::Hopper),
+    Command::DatasetMode(true),
+    Command::FrameWindow(2),
+    Command::FrameAck,
+    Command::TuneSetting { id: 20, value: 25 },
+    Command::SaveSettings,
+    Command::ResetToBootloader,
+    Command::GetInfo,
+    Command::GetSelfTest,
+    Command::Collect {
+        target: CollectTarget::PaletteEntry(5),
+        count: 200,
+    },
+    Command::Collect {
+        target: CollectTarget::Lab {
+            l: 52,
+            a: 60,
+            b: -40,
+        },
+        count: 0,
+    },
+    Command::Extract {
+        l: 45,
+        a: -30,
+        b: 25,
+        tolerance: 6,
+    },
+    Command::DumpLog,
+    Command::SetLogLevel(LogLevel::Warn),
+    Command::AutoExposure,
+    Command::GetLayout,
+    Command::SetLayoutShape { slices: 9, rows: 4 },
+    Command::SetChutePosition { slice: 3, us: 1320 },
+    Command::SetDropPosition { row: 7, us: 1880 },
+    Command::SaveLayout,
+    Command::FactoryReset,
+];
+
+fn parse(parser: &mut Parser, bytes: &[u8]) -> Vec<Command> {
+    bytes.iter().filter_map(|&b| parser.push(b)).collect()
+}
+
+#[test]
+fn test_frames_round_trip_back_to_back() {
+    let mut stream = Vec::new();
+    for command in ALL {
+        let mut frame = [0u8; MAX_FRAME];
+        let len = command.encode(&mut frame);
+        stream.extend_from_slice(&frame[..len]);
+    }
+    // Split the stream at every point, as USB packets might.
+    for split in 0..stream.len() {
+        let mut parser = Parser::new();
+        let mut parsed = parse(&mut parser, &stream[..split]);
+        parsed.extend(parse(&mut parser, &stream[split..]));
+        assert_eq!(parsed, ALL, "split at {}", split);
+    }
+}
+
+#[test]
+fn test_bad_checksum_is_dropped() {
+    let mut frame = [0u8; MAX_FRAME];
+    let len = Command::Home.encode(&mut frame);
+    frame[len - 1] ^= 0xFF;
+    let mut parser = Parser::new();
+    assert!(parse(&mut parser, &frame[..len]).is_empty());
+    // The parser recovers for the next frame.
+    let len = Command::Stop.encode(&mut frame);
+    assert_eq!(parse(&mut parser, &frame[..len]), [Command::Stop]);
+}
+
+#[test]
+fn test_bare_command_bytes() {
+    let mut parser = Parser::new();
+    let bytes = [
+        CMD_EXPORT_INVENTORY,
+        0x7F,
+        CMD_SET_PROFILE,
+        1,
+        CMD_TELEMETRY,
+    ];
+    assert_eq!(
+        parse(&mut parser, &bytes),
+        [
+            Command::ExportInventory,
+            Command::SetProfile(1),
+            Command::Telemetry
+        ]
+    );
+}
+
+#[test]
+fn test_status_round_trip() {
+    let status = Status {
+        running: true,
+        profile_id: 1,
+        palette_entries: 17,
+        tubes_used: 12,
+        beads_sorted: 70_000,
+        supply_mv: 4720,
+        supply_min_mv: 4310,
+    };
+    let mut packet = [0u8; STATUS_PACKET_LEN];
+    assert_eq!(status.encode(&mut packet), STATUS_PACKET_LEN);
+    assert_eq!(packet[..4], STATUS_MAGIC);
+    assert_eq!(Status::decode(&packet[4..]), Some(status));
+    assert_eq!(Status::decode(&packet[4..STATUS_PACKET_LEN - 1]), None);
+}
+
+#[test]
+fn test_inventory_round_trip() {
+    let entries = [
+        inventory::Entry {
+            tube: 0,
+            rgb: (200, 20, 30),
+            count: 5,
+        },
+        inventory::Entry {
+            tube: 1,
+            rgb: (30, 60, 200),
+            count: 100_000,
+        },
+    ];
+    let mut packet = [0u8; inventory::packet_len(2)];
+    let len = inventory::encode(entries.iter().copied(), &mut packet);
+    assert_eq!(len, packet.len());
+    let decoded: Vec<_> = inventory::decode(&packet[4..]).unwrap().collect();
+    assert_eq!(decoded, entries);
+    assert!(inventory::decode(&packet[4..len - 1]).is_none());
+}
+
+#[test]
+fn test_upload_chunks_round_trip() {
+    let data: Vec<u8> = (0..40).collect();
+    let mut stream = Vec::new();
+    let mut sent = Vec::new();
+    for (i, piece) in data.chunks(UPLOAD_CHUNK).enumerate() {
+        let command = Command::UploadChunk(Chunk::new((i * UPLOAD_CHUNK) as u16, piece).unwrap());
+        let mut frame = [0u8; MAX_FRAME];
+        let len = command.encode(&mut frame);
+        stream.extend_from_slice(&frame[..len]);
+        sent.push(command);
+    }
+    let commit = Command::LoadPalette { len: 40 };
+    let mut frame = [0u8; MAX_FRAME];
+    let len = commit.encode(&mut frame);
+    stream.extend_from_slice(&frame[..len]);
+    sent.push(commit);
+
+    let parsed = parse(&mut Parser::new(), &stream);
+    assert_eq!(parsed, sent);
+    let mut rebuilt = vec![0u8; 40];
+    for command in &parsed {
+        if let Command::UploadChunk(chunk) = command {
+            let at = chunk.offset as usize;
+            rebuilt[at..at + chunk.bytes().len()].copy_from_slice(chunk.bytes());
+        }
+    }
+    assert_eq!(rebuilt, data);
+
+    assert_eq!(Chunk::new(0, &[]), None);
+    assert_eq!(Chunk::new(0, &[0; UPLOAD_CHUNK + 1]), None);
+}
+
+#[test]
+fn test_event_round_trip() {
+    for event in [
+        Event::HopperStalled { empty_pickups: 22 },
+        Event::TargetReached { beads: 200 },
+        Event::ServoStalled { sense_mv: 620 },
+    ] {
+        let mut packet = [0u8; EVENT_PACKET_LEN];
+        assert_eq!(event.encode(&mut packet), EVENT_PACKET_LEN);
+        assert_eq!(packet[..4], EVENT_MAGIC);
+        assert_eq!(Event::decode(&packet[4..]), Some(event));
+    }
+    let mut packet = [0u8; EVENT_PACKET_LEN];
+    Event::HopperStalled { empty_pickups: 22 }.encode(&mut packet);
+    assert_eq!(Event::decode(&packet[4..6]), None);
+    assert_eq!(Event::decode(&[0xEE, 0, 0]), None);
+}
+
+#[test]
+fn test_servo_round_trip() {
+    let position = ServoPosition {
+        servo: ServoId::Chutes,
+        us: 1167,
+    };
+    let mut packet = [0u8; SERVO_PACKET_LEN];
+    assert_eq!(position.encode(&mut packet), SERVO_PACKET_LEN);
+    assert_eq!(packet[..4], SERVO_MAGIC);
+    assert_eq!(ServoPosition::decode(&packet[4..]), Some(position));
+    assert_eq!(ServoPosition::decode(&packet[4..6]), None);
+    assert_eq!(ServoPosition::decode(&[9, 0, 0]), None);
+    for servo in ServoId::ALL {
+        assert_eq!(ServoId::from_name(servo.name()), Some(servo));
+    }
+}
+
+#[test]
+fn test_info_round_trip() {
+    let info = Info::new(1_700_000_000, "3f2a9c1e-dirty", "0.1.0");
+    let mut packet = [0u8; INFO_PACKET_LEN];
+    assert_eq!(info.encode(&mut packet), INFO_PACKET_LEN);
+    assert_eq!(packet[..4], INFO_MAGIC);
+    let decoded = Info::decode(&packet[4..]).unwrap();
+    assert_eq!(decoded, info);
+    assert_eq!(decoded.git_hash(), "3f2a9c1e-dirty");
+    assert_eq!(decoded.bsp_revision(), "0.1.0");
+    assert!(decoded.is_compatible());
+    assert_eq!(decoded.last_panic(), None);
+    assert_eq!(Info::decode(&packet[4..20]), None);
+
+    let info = info.with_panic("main.rs:812 index out of bounds: the len is 3");
+    info.encode(&mut packet);
+    let decoded = Info::decode(&packet[4..]).unwrap();
+    assert_eq!(decoded.last_panic(), Some("main.rs:812 index out of bou"));
+    assert_eq!(decoded.last_panic().unwrap().len(), PANIC_TEXT_LEN);
+
+    // Firmware from before the panic message.
+    let decoded = Info::decode(&packet[4..INFO_PACKET_LEN - PANIC_TEXT_LEN]).unwrap();
+    assert_eq!(decoded.git_hash(), "3f2a9c1e-dirty");
+    assert_eq!(decoded.last_panic(), None);
+
+    // Too long to fit is cut short; another protocol version is not compatible.
+    let mut info = Info::new(0, "0123456789abcdef-dirty", "");
+    assert_eq!(info.git_hash(), "0123456789abcdef");
+    info.protocol_version += 1;
+    assert!(!info.is_compatible());
+}
+
+#[test]
+fn test_self_test_round_trip() {
+    let mut report = SelfTestReport::default();
+    assert!(report.passed());
+    report.record(SelfTestItem::CameraId, true);
+    report.record(SelfTestItem::TestPattern, false);
+    assert!(!report.passed());
+    assert_eq!(report.result(SelfTestItem::CameraId), Some(true));
+    assert_eq!(report.result(SelfTestItem::TestPattern), Some(false));
+    assert_eq!(report.result(SelfTestItem::Neopixel), None);
+
+    let mut packet = [0u8; SELF_TEST_PACKET_LEN];
+    assert_eq!(report.encode(&mut packet), SELF_TEST_PACKET_LEN);
+    assert_eq!(packet[..4], SELF_TEST_MAGIC);
+    assert_eq!(SelfTestReport::decode(&packet[4..]), Some(report));
+    assert_eq!(SelfTestReport::decode(&packet[4..5]), None);
+    // An unknown item, and a failure of an item that was not checked.
+    assert_eq!(SelfTestReport::decode(&[0x80, 0]), None);
+    assert_eq!(SelfTestReport::decode(&[0x01, 0x02]), None);
+}
+
+#[test]
+fn test_cycle_record_round_trip() {
+    // Every field at its largest encoding.
+    let record = CycleRecord {
+        uptime_ms: u32::MAX,
+        result: CycleResult::Rejected,
+        tube: Some(u8::MAX),
+        color: Some([255, 255, 255]),
+        distance: Some(u32::MAX),
+        pickup_ms: u32::MAX,
+        camera_ms: u32::MAX,
+        cycle_ms: u32::MAX,
+    };
+    let mut packet = [0u8; RECORD_PACKET_MAX];
+    let len = record.encode(&mut packet);
+    assert_eq!(packet[..4], RECORD_MAGIC);
+    assert_eq!(CycleRecord::decode(&packet[4..len]), Some(record));
+    assert_eq!(CycleRecord::decode(&packet[4..len - 1]), None);
+
+    let empty = CycleRecord {
+        result: CycleResult::Empty,
+        tube: None,
+        color: None,
+        distance: None,
+        ..record
+    };
+    let len = empty.encode(&mut packet);
+    assert_eq!(CycleRecord::decode(&packet[4..len]), Some(empty));
+}
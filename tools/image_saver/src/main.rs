@@ -1,26 +1,59 @@
 use clap::Parser;
 use image::{Rgb, RgbImage};
 use minifb::{Key, Window, WindowOptions};
-use std::io::{self, Read, Write};
+use std::io;
+use std::path::Path;
 use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::thread;
 use std::time::Duration;
 
+use sorter_host::frame_source::{
+    FrameSource, PngDirSource, SerialSource, SessionLogSource, SyntheticSource, HEIGHT, WIDTH,
+};
+use sorter_host::report::EventKind;
+use sorter_logic::decode_rgb565_be;
+use sorter_logic::text::{English, Locale, Msg, Unit};
+
+mod session;
+
+use crate::session::Session;
+
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(short, long)]
-    port: String,
+    port: Option<String>,
+
+    /// Replay a folder of saved captures or a `session_<id>.csv` log instead of a device.
+    #[arg(long, conflicts_with_all = ["port", "synthetic"])]
+    replay: Option<String>,
+
+    /// Generate test frames instead of reading a device.
+    #[arg(long, conflicts_with = "port")]
+    synthetic: bool,
 
     #[arg(short, long, default_value_t = 115200)]
     baud: u32,
 
     #[arg(short, long, default_value = "images")]
     output: String,
+
+    /// Ask the sorter to send at most this many frames ahead of the ones received, so none
+    /// are lost on a slow host. Without it the sorter streams without waiting.
+    #[arg(long, requires = "port")]
+    window: Option<u8>,
+
+    /// Color chart (one `name,#rrggbb` per line) used to name the tubes in the report.
+    #[arg(long)]
+    catalog: Option<String>,
 }
 
-const WIDTH: usize = 40;
-const HEIGHT: usize = 30;
+const REPLAY_FRAME_INTERVAL: Duration = Duration::from_millis(200);
+
+enum SerialMsg {
+    Frame(Vec<u8>),
+    Event(EventKind, String),
+}
 
 fn main() {
     let args = Args::parse();
@@ -29,12 +62,12 @@ fn main() {
     // Create images directory
     std::fs::create_dir_all(&args.output).unwrap();
 
-    let (tx, rx): (mpsc::Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
+    let (tx, rx): (mpsc::Sender<SerialMsg>, Receiver<SerialMsg>) = mpsc::channel();
 
-    // Spawn Serial Reader Thread
-    let args_clone = args.clone();
+    // Spawn Frame Reader Thread
+    let source = open_source(&args);
     thread::spawn(move || {
-        serial_loop(args_clone, tx);
+        source_loop(source, tx);
     });
 
     // GUI Loop
@@ -56,18 +89,24 @@ fn main() {
     window.limit_update_rate(Some(std::time::Duration::from_micros(33300)));
 
     let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+    let catalog = args
+        .catalog
+        .as_deref()
+        .map(|path| sorter_host::catalog::load(Path::new(path)).expect("Failed to load catalog"));
+    let mut session = Session::new(&args.output, catalog);
 
-    while window.is_open() && !window.is_key_down(Key::Escape) {
+    'gui: while window.is_open() && !window.is_key_down(Key::Escape) {
         // Check for new frames
         loop {
             match rx.try_recv() {
-                Ok(frame_data) => {
-                    // Convert frame to ARGB buffer and save to disk
+                Ok(SerialMsg::Frame(frame_data)) => {
                     // Convert frame to ARGB buffer and save to disk
-                    process_frame(&frame_data, &mut buffer, &args.output);
+                    let saved = process_frame(&frame_data, &mut buffer, &args.output);
+                    session.record_frame(&frame_data, saved);
                 }
+                Ok(SerialMsg::Event(kind, message)) => session.record_event(kind, message),
                 Err(TryRecvError::Empty) => break,
-                Err(TryRecvError::Disconnected) => return,
+                Err(TryRecvError::Disconnected) => break 'gui,
             }
         }
 
@@ -78,79 +117,105 @@ fn main() {
         // If we pass 40,30 to update_with_buffer, minifb will scale it up to window size.
         window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
     }
+
+    // Run ended (window closed or device disconnected)
+    session.write_report();
 }
 
-fn serial_loop(args: Args, tx: mpsc::Sender<Vec<u8>>) {
-    println!("Opening {} at {} baud...", args.port, args.baud);
-    let mut port = serialport::new(&args.port, args.baud)
+fn open_source(args: &Args) -> Box<dyn FrameSource + Send> {
+    if let Some(path) = &args.replay {
+        let path = Path::new(path);
+        let source: io::Result<Box<dyn FrameSource + Send>> = if path.is_dir() {
+            PngDirSource::new(path).map(|s| Box::new(s) as _)
+        } else {
+            SessionLogSource::new(path).map(|s| Box::new(s) as _)
+        };
+        println!("{}: {}", English.msg(Msg::Replaying), path.display());
+        return Paced::wrap(source.expect("Failed to open replay source"));
+    }
+
+    if args.synthetic {
+        println!("{}", English.msg(Msg::GeneratingFrames));
+        return Paced::wrap(Box::new(SyntheticSource::new(
+            (200, 200, 190),
+            vec![
+                Some((200, 20, 30)),
+                Some((30, 60, 200)),
+                None,
+                Some((240, 240, 240)),
+            ],
+            25,
+        )));
+    }
+
+    let port = args
+        .port
+        .as_deref()
+        .expect("--port, --replay or --synthetic is required");
+    println!(
+        "{}: {} ({} {})",
+        English.msg(Msg::OpeningPort),
+        port,
+        args.baud,
+        English.unit(Unit::Baud)
+    );
+    let port = serialport::new(port, args.baud)
         .timeout(Duration::from_millis(2000))
         .open()
         .expect("Failed to open unique port");
+    println!("{}", English.msg(Msg::ListeningForFrames));
+    let Some(window) = args.window else {
+        return Box::new(SerialSource::new(port));
+    };
+    let acks = port
+        .try_clone()
+        .expect("Failed to clone port for frame acks");
+    let source = SerialSource::new(port)
+        .with_acks(Box::new(acks), window)
+        .expect("Failed to turn on frame flow control");
+    Box::new(source)
+}
 
-    println!("Listening for BEAD frames...");
+// Recorded and generated frames are released at roughly camera speed so the live view
+// stays watchable.
+struct Paced(Box<dyn FrameSource + Send>);
 
-    let mut buf = [0u8; 1];
-    let mut state = 0;
+impl Paced {
+    fn wrap(source: Box<dyn FrameSource + Send>) -> Box<dyn FrameSource + Send> {
+        Box::new(Self(source))
+    }
+}
 
+impl FrameSource for Paced {
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        thread::sleep(REPLAY_FRAME_INTERVAL);
+        self.0.next_frame()
+    }
+}
+
+fn source_loop(mut source: Box<dyn FrameSource + Send>, tx: mpsc::Sender<SerialMsg>) {
     loop {
-        match port.read_exact(&mut buf) {
-            Ok(_) => {
-                let b = buf[0];
-                match state {
-                    0 => {
-                        if b == 0xBE {
-                            state = 1;
-                        } else {
-                            state = 0;
-                        }
-                    }
-                    1 => {
-                        if b == 0xAD {
-                            state = 2;
-                        } else if b == 0xBE {
-                            state = 1;
-                        } else {
-                            state = 0;
-                        }
-                    }
-                    2 => {
-                        if b == 0x1F {
-                            state = 3;
-                        } else if b == 0xBE {
-                            state = 1;
-                        } else {
-                            state = 0;
-                        }
-                    }
-                    3 => {
-                        if b == 0x01 {
-                            print!("Header found! Capturing frame... ");
-                            io::stdout().flush().unwrap();
-
-                            // Frame size: 40 * 30 * 2 = 2400 bytes
-                            let mut frame_buf = vec![0u8; WIDTH * HEIGHT * 2];
-                            if port.read_exact(&mut frame_buf).is_ok() {
-                                println!("RX OK.");
-                                // Send to main thread
-                                if tx.send(frame_buf).is_err() {
-                                    break;
-                                }
-                            } else {
-                                println!("Timeout reading frame data.");
-                            }
-                            state = 0;
-                        } else if b == 0xBE {
-                            state = 1;
-                        } else {
-                            state = 0;
-                        }
-                    }
-                    _ => state = 0,
+        match source.next_frame() {
+            Ok(Some(frame)) => {
+                println!("{}", English.msg(Msg::FrameReceived));
+                // Send to main thread
+                if tx.send(SerialMsg::Frame(frame)).is_err() {
+                    break;
                 }
             }
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Ok(None) => {
+                println!("{}", English.msg(Msg::EndOfFrames));
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                let message = English.msg(Msg::FrameTimeout);
+                println!("{}", message);
+                let _ = tx.send(SerialMsg::Event(EventKind::Warning, message.to_string()));
+            }
             Err(e) => {
-                eprintln!("Serial Read Error: {:?}", e);
+                let message = format!("{}: {}", English.msg(Msg::SerialReadError), e);
+                eprintln!("{}", message);
+                let _ = tx.send(SerialMsg::Event(EventKind::Error, message));
                 // Try to reopen? Or just break.
                 // For now break, retrying logic is complex.
                 break;
@@ -159,28 +224,18 @@ fn serial_loop(args: Args, tx: mpsc::Sender<Vec<u8>>) {
     }
 }
 
-fn process_frame(data: &[u8], buffer: &mut [u32], output_dir: &str) {
+// Returns the saved file name (relative to output_dir) on success.
+fn process_frame(data: &[u8], buffer: &mut [u32], output_dir: &str) -> Option<String> {
     let width = WIDTH as u32;
     let height = HEIGHT as u32;
     let mut img = RgbImage::new(width, height);
 
-    for (i, chunk) in data.chunks(2).enumerate() {
+    // User confirmed Big Endian from Camera
+    for (i, rgb) in decode_rgb565_be(data).enumerate() {
         if i >= buffer.len() {
             break;
         }
-
-        // User confirmed Big Endian from Camera
-        let p = u16::from_be_bytes([chunk[0], chunk[1]]);
-
-        // RGB565: RRRRR(5) GGGGGG(6) BBBBB(5)
-        let r = ((p >> 11) & 0x1F) as u8;
-        let g = ((p >> 5) & 0x3F) as u8;
-        let b = (p & 0x1F) as u8;
-
-        // Expand to 8-bit (Scale up)
-        let r8 = ((r as u16 * 255) / 31) as u8;
-        let g8 = ((g as u16 * 255) / 63) as u8;
-        let b8 = ((b as u16 * 255) / 31) as u8;
+        let (r8, g8, b8) = (rgb.r, rgb.g, rgb.b);
 
         // Update display buffer (0x00RRGGBB)
         buffer[i] = ((r8 as u32) << 16) | ((g8 as u32) << 8) | (b8 as u32);
@@ -195,9 +250,16 @@ fn process_frame(data: &[u8], buffer: &mut [u32], output_dir: &str) {
 
     // Save to disk
     let timestamp = chrono::Utc::now().timestamp_millis();
-    let name = format!("{}/bead_{}.png", output_dir, timestamp);
+    let file_name = format!("bead_{}.png", timestamp);
+    let name = format!("{}/{}", output_dir, file_name);
     match img.save(&name) {
-        Ok(_) => println!("Saved: {}", name),
-        Err(e) => println!("Error saving image: {}", e),
+        Ok(_) => {
+            println!("{}: {}", English.msg(Msg::Saved), name);
+            Some(file_name)
+        }
+        Err(e) => {
+            println!("{}: {}", English.msg(Msg::ImageSaveError), e);
+            None
+        }
     }
 }
@@ -0,0 +1,281 @@
+use sorter_host::report::{
+    BeadThumbnail, ColorCount, EventKind, RecheckReport, SessionEvent, SessionReport, PURITY_WARN,
+};
+use sorter_logic::analyze_image;
+use sorter_logic::catalog::Catalog;
+use sorter_logic::router::{TubeRouter, MATCH_THRESHOLD};
+use sorter_logic::text::{English, Locale, Msg};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::{HEIGHT, WIDTH};
+
+// Tubes in the firmware's default layout.
+const TUBE_COUNT: usize = 30;
+// Tubes with fewer beads than this are too young for a purity warning.
+const PURITY_MIN_BEADS: u32 = 10;
+
+// Beads below this confidence are listed in the report for a second look.
+const LOW_CONFIDENCE: u8 = 50;
+// Consecutive empty captures before we flag a likely jam / empty hopper.
+const JAM_EMPTY_STREAK: u32 = 10;
+
+/// Tracks one image_saver run and produces the session report when it ends.
+///
+/// Beads are routed to tubes the way the firmware does it, and each tube's purity is tracked
+/// as the run goes; a tube that looks contaminated is flagged in the event log. The report
+/// lists the palette entries, each with the purity of its tube.
+///
+/// Every frame's decision is also logged to `session_<id>.csv` (image, palette entry or
+/// `empty`/`full`, tube, measured color) so the run can be replayed offline with different settings
+/// (`cargo run --example replay` in sorter_logic).
+pub struct Session {
+    id: i64,
+    output_dir: String,
+    log: Option<File>,
+    start: Instant,
+    started: String,
+    router: TubeRouter,
+    // Names tubes in the report, if the user gave a color chart.
+    catalog: Option<Catalog>,
+    // Tubes already reported as contaminated.
+    impure: Vec<u8>,
+    frames: u32,
+    // Beads routed to a tube so far; a bead's number is its position in this count.
+    beads: u32,
+    empty_streak: u32,
+    events: Vec<SessionEvent>,
+    low_confidence: Vec<BeadThumbnail>,
+}
+
+impl Session {
+    pub fn new(output_dir: &str, catalog: Option<Catalog>) -> Self {
+        let id = chrono::Utc::now().timestamp_millis();
+        let log_path = Path::new(output_dir).join(format!("session_{}.csv", id));
+        let log = match File::create(&log_path) {
+            Ok(mut f) => {
+                let _ = writeln!(f, "# threshold={} metric=lab", MATCH_THRESHOLD);
+                let _ = writeln!(f, "image,decision,tube,r,g,b");
+                Some(f)
+            }
+            Err(e) => {
+                eprintln!(
+                    "{} {}: {}",
+                    English.msg(Msg::SessionLogError),
+                    log_path.display(),
+                    e
+                );
+                None
+            }
+        };
+
+        Self {
+            id,
+            output_dir: output_dir.to_string(),
+            log,
+            start: Instant::now(),
+            started: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            router: TubeRouter::new(TUBE_COUNT),
+            catalog,
+            impure: Vec::new(),
+            frames: 0,
+            beads: 0,
+            empty_streak: 0,
+            events: Vec::new(),
+            low_confidence: Vec::new(),
+        }
+    }
+
+    pub fn record_event(&mut self, kind: EventKind, message: String) {
+        self.events.push(SessionEvent {
+            at: self.start.elapsed(),
+            kind,
+            message,
+        });
+    }
+
+    fn log_decision(
+        &mut self,
+        image: &Option<String>,
+        decision: &str,
+        tube: Option<u8>,
+        rgb: (u8, u8, u8),
+    ) {
+        if let Some(log) = &mut self.log {
+            let image = image.as_deref().unwrap_or("");
+            let tube = tube.map(|t| t.to_string()).unwrap_or_default();
+            let _ = writeln!(
+                log,
+                "{},{},{},{},{},{}",
+                image, decision, tube, rgb.0, rgb.1, rgb.2
+            );
+        }
+    }
+
+    /// Classify a received frame. `image` is the saved file name, relative to the output dir.
+    pub fn record_frame(&mut self, data: &[u8], image: Option<String>) {
+        self.frames += 1;
+
+        let Some(analysis) = analyze_image(data, WIDTH, HEIGHT) else {
+            self.log_decision(&image, "empty", None, (0, 0, 0));
+            self.empty_streak += 1;
+            if self.empty_streak == JAM_EMPTY_STREAK {
+                self.record_event(
+                    EventKind::Jam,
+                    format!(
+                        "{}: {}",
+                        English.msg(Msg::ConsecutiveEmptyCaptures),
+                        JAM_EMPTY_STREAK
+                    ),
+                );
+            }
+            return;
+        };
+
+        if self.empty_streak >= JAM_EMPTY_STREAK {
+            self.record_event(
+                EventKind::Info,
+                format!(
+                    "{}: {}",
+                    English.msg(Msg::PickupsResumed),
+                    self.empty_streak
+                ),
+            );
+        }
+        self.empty_streak = 0;
+
+        let color = analysis.average_color;
+        let Some(route) = self.router.route(color, analysis.variance) else {
+            self.log_decision(&image, "full", None, (color.r, color.g, color.b));
+            self.record_event(
+                EventKind::Warning,
+                English.msg(Msg::PaletteFull).to_string(),
+            );
+            return;
+        };
+        let (idx, tube) = (route.palette_index, route.tube);
+        self.beads += 1;
+
+        // Confidence falls off linearly with distance from the entry's centroid.
+        let center = self.router.palette().get(idx).unwrap_or(color);
+        let dist = color.dist_lab(&center).min(MATCH_THRESHOLD);
+        let confidence = (100 * (MATCH_THRESHOLD - dist) / MATCH_THRESHOLD) as u8;
+
+        self.log_decision(
+            &image,
+            &idx.to_string(),
+            Some(tube),
+            (color.r, color.g, color.b),
+        );
+        self.check_purity(tube);
+
+        if confidence < LOW_CONFIDENCE {
+            self.low_confidence.push(BeadThumbnail {
+                bead: self.beads,
+                tube: Some(tube),
+                image: image.unwrap_or_default(),
+                label: label(Msg::Tube, tube as usize, self.color_name(&color)),
+                rgb: (color.r, color.g, color.b),
+                confidence,
+            });
+        }
+    }
+
+    // Flag a tube the first time its purity estimate drops below PURITY_WARN.
+    fn check_purity(&mut self, tube: u8) {
+        let count = self.router.tubes()[tube as usize].count;
+        let Some(purity) = self.router.purity(tube) else {
+            return;
+        };
+        if count >= PURITY_MIN_BEADS && purity < PURITY_WARN && !self.impure.contains(&tube) {
+            self.impure.push(tube);
+            self.record_event(
+                EventKind::Warning,
+                format!(
+                    "{}: {} {}%",
+                    English.msg(Msg::TubePurityLow),
+                    label(Msg::Tube, tube as usize, None),
+                    purity
+                ),
+            );
+        }
+    }
+
+    pub fn report(&self) -> SessionReport {
+        let palette = self.router.palette();
+        let colors = (0..palette.len())
+            .filter_map(|i| {
+                let entry = palette.get_entry(i)?;
+                let (rgb, _) = entry.avg();
+                Some(ColorCount {
+                    label: label(Msg::PaletteEntry, i, self.color_name(&rgb)),
+                    rgb: (rgb.r, rgb.g, rgb.b),
+                    count: entry.count,
+                    purity: self.router.tube_of(i).and_then(|t| self.router.purity(t)),
+                })
+            })
+            .collect();
+
+        SessionReport {
+            title: English.msg(Msg::ReportTitle).to_string(),
+            started: self.started.clone(),
+            duration: self.start.elapsed(),
+            frames: self.frames,
+            colors,
+            events: self.events.clone(),
+            low_confidence: self.low_confidence.clone(),
+        }
+    }
+
+    pub fn write_report(&self) {
+        let name = format!("session_{}.html", self.id);
+        let path = Path::new(&self.output_dir).join(name);
+        match self.report().write(&path) {
+            Ok(_) => println!("{}: {}", English.msg(Msg::SessionReport), path.display()),
+            Err(e) => eprintln!("{}: {}", English.msg(Msg::SessionReportError), e),
+        }
+        self.write_recheck();
+    }
+
+    /// Low-confidence beads grouped by tube, for a manual pass over those tubes.
+    pub fn recheck(&self) -> RecheckReport {
+        RecheckReport::from_beads(
+            English.msg(Msg::RecheckTitle).to_string(),
+            &self.low_confidence,
+            |t| {
+                let rgb = self.router.tubes()[t as usize].avg().0;
+                label(Msg::Tube, t as usize, self.color_name(&rgb))
+            },
+        )
+    }
+
+    // The closest color on the user's chart.
+    fn color_name(&self, rgb: &sorter_logic::Rgb) -> Option<&str> {
+        let m = self.catalog.as_ref()?.nearest(rgb)?;
+        Some(&m.color.name)
+    }
+
+    fn write_recheck(&self) {
+        let recheck = self.recheck();
+        if recheck.is_empty() {
+            return;
+        }
+        println!("{}:\n{}", English.msg(Msg::RecheckTitle), recheck.to_text());
+        let path = Path::new(&self.output_dir).join(format!("recheck_{}.html", self.id));
+        match recheck.write(&path) {
+            Ok(_) => println!("{}: {}", English.msg(Msg::RecheckList), path.display()),
+            Err(e) => eprintln!("{}: {}", English.msg(Msg::RecheckListError), e),
+        }
+    }
+}
+
+// "Tube 3 (Cherry)", "Palette 3 (Cherry)"
+fn label(kind: Msg, index: usize, catalog_name: Option<&str>) -> String {
+    let entry = English.msg(kind);
+    match catalog_name {
+        Some(name) => format!("{} {} ({})", entry, index, name),
+        None => format!("{} {}", entry, index),
+    }
+}
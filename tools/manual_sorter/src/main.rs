@@ -6,15 +6,23 @@ use axum::{
     Router,
 };
 use serde::{Deserialize, Serialize};
-use sorter_logic::{analyze_image_debug, AnalysisConfig, Palette, PaletteMatch};
+use sorter_logic::catalog::Catalog;
+use sorter_logic::dyn_palette::DynPalette;
+use sorter_logic::layout::MAX_TUBES;
+use sorter_logic::router::{TubeRouter, ROUTER_STATE_MAX};
+use sorter_logic::{analyze_image_debug, AnalysisConfig, PaletteMatch, Rgb};
 use std::{
+    collections::HashMap,
     net::SocketAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use tower_http::{cors::CorsLayer, services::ServeDir};
 use walkdir::WalkDir;
 
+mod palette;
+
 #[derive(Clone, Serialize, Deserialize)]
 struct Bead {
     id: usize,
@@ -27,8 +35,11 @@ struct Bead {
 
 struct AppState {
     beads: Vec<Bead>,
+    palette_names: HashMap<String, String>, // "p3" -> "Cherry Red"
     input_dir: PathBuf,
     output_dir: PathBuf,
+    port: Option<String>,     // sorter data port for pushing the palette
+    catalog: Option<Catalog>, // color chart for naming palette entries
 }
 
 #[tokio::main]
@@ -36,24 +47,37 @@ async fn main() {
     tracing_subscriber::fmt::init();
 
     let args: Vec<String> = std::env::args().collect();
-    // usage: manual_sorter --input <dir> --output <dir>
+    // usage: manual_sorter --input <dir> --output <dir> [--port <sorter data port>]
+    //                      [--catalog <color chart>]
     let mut input_dir = PathBuf::from("image_data/assorted");
     let mut output_dir = PathBuf::from("sorted_output");
+    let mut port = None;
+    let mut catalog = None;
 
     let mut i = 1;
     while i < args.len() {
         match args[i].as_str() {
-            "--input" | "-i" => {
-                if i + 1 < args.len() {
-                    input_dir = PathBuf::from(&args[i + 1]);
-                    i += 1;
-                }
+            "--input" | "-i" if i + 1 < args.len() => {
+                input_dir = PathBuf::from(&args[i + 1]);
+                i += 1;
+            }
+            "--output" | "-o" if i + 1 < args.len() => {
+                output_dir = PathBuf::from(&args[i + 1]);
+                i += 1;
             }
-            "--output" | "-o" => {
-                if i + 1 < args.len() {
-                    output_dir = PathBuf::from(&args[i + 1]);
-                    i += 1;
+            "--port" | "-p" if i + 1 < args.len() => {
+                port = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--catalog" | "-c" if i + 1 < args.len() => {
+                match sorter_host::catalog::load(Path::new(&args[i + 1])) {
+                    Ok(c) => catalog = Some(c),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        return;
+                    }
                 }
+                i += 1;
             }
             _ => {}
         }
@@ -75,8 +99,11 @@ async fn main() {
 
     let state = Arc::new(Mutex::new(AppState {
         beads,
+        palette_names: HashMap::new(),
         input_dir: input_dir.clone(),
         output_dir,
+        port,
+        catalog,
     }));
 
     let app = Router::new()
@@ -84,6 +111,12 @@ async fn main() {
         .route("/api/state", get(get_state))
         .route("/api/move", post(move_bead))
         .route("/api/finalize", post(finalize_sort))
+        .route("/api/palette", get(get_palette))
+        .route("/api/palette/merge", post(merge_palette))
+        .route("/api/palette/split", post(split_palette))
+        .route("/api/palette/rename", post(rename_palette))
+        .route("/api/palette/export", post(export_palette))
+        .route("/api/palette/push", post(push_palette))
         .nest_service("/images", ServeDir::new(input_dir)) // Serve raw images
         .layer(CorsLayer::permissive())
         .with_state(state);
@@ -97,7 +130,7 @@ async fn main() {
 // Logic to run sorter_logic pass
 fn initial_sort(path: &PathBuf) -> Vec<Bead> {
     let mut beads = Vec::new();
-    let mut palette: Palette<128> = Palette::new();
+    let mut palette = DynPalette::new();
     let config = AnalysisConfig::default(); // 60% filter
 
     let mut id_counter = 0;
@@ -127,7 +160,8 @@ fn initial_sort(path: &PathBuf) -> Vec<Bead> {
             let mut variance = 0;
             let mut rgb_disp = (0, 0, 0);
 
-            if let Some(analysis) = analyze_image_debug(&data, w as usize, h as usize, None, config)
+            if let Some(analysis) =
+                analyze_image_debug(&data, w as usize, h as usize, None, None, config)
             {
                 let match_result =
                     palette.match_color(&analysis.average_color, analysis.variance, 30);
@@ -190,6 +224,174 @@ async fn move_bead(
     }
 }
 
+async fn get_palette(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Json<Vec<palette::PaletteSummary>> {
+    let state = state.lock().unwrap();
+    Json(palette::summarize(
+        &state.beads,
+        &state.palette_names,
+        state.catalog.as_ref(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct MergeReq {
+    source: String,
+    target: String,
+}
+
+async fn merge_palette(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(payload): Json<MergeReq>,
+) -> StatusCode {
+    if payload.source == payload.target
+        || !palette::is_palette_id(&payload.source)
+        || !palette::is_palette_id(&payload.target)
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+    let mut state = state.lock().unwrap();
+    if palette::merge(&mut state.beads, &payload.source, &payload.target) == 0 {
+        return StatusCode::NOT_FOUND;
+    }
+    state.palette_names.remove(&payload.source);
+    StatusCode::OK
+}
+
+#[derive(Deserialize)]
+struct SplitReq {
+    entry: String,
+}
+
+async fn split_palette(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(payload): Json<SplitReq>,
+) -> Result<String, StatusCode> {
+    let mut state = state.lock().unwrap();
+    palette::split(&mut state.beads, &payload.entry).ok_or(StatusCode::UNPROCESSABLE_ENTITY)
+}
+
+#[derive(Deserialize)]
+struct RenameReq {
+    entry: String,
+    name: String,
+}
+
+async fn rename_palette(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(payload): Json<RenameReq>,
+) -> StatusCode {
+    if !palette::is_palette_id(&payload.entry) {
+        return StatusCode::BAD_REQUEST;
+    }
+    let mut state = state.lock().unwrap();
+    let name = payload.name.trim();
+    if name.is_empty() {
+        state.palette_names.remove(&payload.entry);
+    } else {
+        state.palette_names.insert(payload.entry, name.to_string());
+    }
+    StatusCode::OK
+}
+
+// Writes the curated palette to <output>/palette.json for loading onto the device.
+async fn export_palette(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, String) {
+    let state = state.lock().unwrap();
+    let entries = palette::summarize(&state.beads, &state.palette_names, state.catalog.as_ref());
+
+    std::fs::create_dir_all(&state.output_dir).ok();
+    let path = state.output_dir.join("palette.json");
+    let json = serde_json::to_string_pretty(&entries).unwrap();
+    if let Err(e) = std::fs::write(&path, json) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to write {:?}: {}", path, e),
+        );
+    }
+
+    // The same entries as a tube map for `sorterctl load-palette`.
+    let (router_state, seeded) = router_state(&entries);
+    let bin_path = state.output_dir.join("palette.bin");
+    match std::fs::write(&bin_path, &router_state) {
+        Ok(_) => (
+            StatusCode::OK,
+            format!(
+                "Exported {} palette entries to {:?} ({} as tubes in {:?})",
+                entries.len(),
+                path,
+                seeded,
+                bin_path
+            ),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to write {:?}: {}", bin_path, e),
+        ),
+    }
+}
+
+// Sends the curated palette straight to the sorter on --port, as `sorterctl load-palette` would.
+async fn push_palette(State(state): State<Arc<Mutex<AppState>>>) -> (StatusCode, String) {
+    let (port, entries) = {
+        let state = state.lock().unwrap();
+        let entries =
+            palette::summarize(&state.beads, &state.palette_names, state.catalog.as_ref());
+        (state.port.clone(), entries)
+    };
+    let Some(port) = port else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "No sorter port; restart with --port <device>".to_string(),
+        );
+    };
+    let (router_state, seeded) = router_state(&entries);
+
+    let upload = tokio::task::spawn_blocking(move || {
+        let mut link = serialport::new(&port, 115200)
+            .timeout(Duration::from_millis(100))
+            .open()?;
+        sorter_host::link::upload_palette(link.as_mut(), &router_state)
+    })
+    .await
+    .unwrap();
+
+    match upload {
+        Ok(status) if status.tubes_used as usize == seeded => (
+            StatusCode::OK,
+            format!("Loaded {} palette entries into the sorter", seeded),
+        ),
+        Ok(status) => (
+            StatusCode::CONFLICT,
+            format!(
+                "The sorter kept its palette ({} entries, {} tubes); does it fit the layout?",
+                status.palette_entries, status.tubes_used
+            ),
+        ),
+        Err(e) => (
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to upload palette: {}", e),
+        ),
+    }
+}
+
+// Entry i goes to tube i. Returns the encoded router state and how many entries got a tube.
+fn router_state(entries: &[palette::PaletteSummary]) -> (Vec<u8>, usize) {
+    let colors: Vec<Rgb> = entries
+        .iter()
+        .map(|e| Rgb {
+            r: e.rgb.0,
+            g: e.rgb.1,
+            b: e.rgb.2,
+        })
+        .collect();
+    let mut router = TubeRouter::new(MAX_TUBES);
+    let seeded = router.seed_tubes(&colors);
+    let mut state = [0u8; ROUTER_STATE_MAX];
+    let len = router.encode_state(&mut state);
+    (state[..len].to_vec(), seeded)
+}
+
 async fn finalize_sort(State(state): State<Arc<Mutex<AppState>>>) -> String {
     let state = state.lock().unwrap();
     let out_base = &state.output_dir;
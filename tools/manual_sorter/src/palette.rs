@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+use sorter_logic::catalog::Catalog;
+use sorter_logic::Rgb;
+use std::collections::HashMap;
+
+use crate::Bead;
+
+/// One palette entry as shown in the editor, derived from the beads currently assigned to it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PaletteSummary {
+    pub id: String, // "p0", "p1", ...
+    pub name: String,
+    pub rgb: (u8, u8, u8),
+    pub count: usize,
+}
+
+pub fn is_palette_id(assignment: &str) -> bool {
+    assignment.len() > 1
+        && assignment.starts_with('p')
+        && assignment[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+fn palette_index(assignment: &str) -> Option<usize> {
+    if is_palette_id(assignment) {
+        assignment[1..].parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Builds the palette view: one entry per palette assignment, centered on the mean of its beads.
+pub fn summarize(
+    beads: &[Bead],
+    names: &HashMap<String, String>,
+    catalog: Option<&Catalog>,
+) -> Vec<PaletteSummary> {
+    let mut sums: HashMap<&str, (u32, u32, u32, usize)> = HashMap::new();
+    for bead in beads.iter().filter(|b| is_palette_id(&b.assignment)) {
+        let s = sums.entry(bead.assignment.as_str()).or_default();
+        s.0 += bead.rgb.0 as u32;
+        s.1 += bead.rgb.1 as u32;
+        s.2 += bead.rgb.2 as u32;
+        s.3 += 1;
+    }
+
+    let mut entries: Vec<PaletteSummary> = sums
+        .into_iter()
+        .map(|(id, (r, g, b, count))| {
+            let rgb = Rgb {
+                r: (r / count as u32) as u8,
+                g: (g / count as u32) as u8,
+                b: (b / count as u32) as u8,
+            };
+            PaletteSummary {
+                id: id.to_string(),
+                name: names
+                    .get(id)
+                    .cloned()
+                    .unwrap_or_else(|| default_name(id, &rgb, catalog)),
+                rgb: (rgb.r, rgb.g, rgb.b),
+                count,
+            }
+        })
+        .collect();
+    entries.sort_by_key(|e| palette_index(&e.id));
+    entries
+}
+
+/// Unrenamed entries are labelled with the closest color on the user's chart, e.g.
+/// "Cherry (p3)", or just their id without one.
+fn default_name(id: &str, rgb: &Rgb, catalog: Option<&Catalog>) -> String {
+    match catalog.and_then(|c| c.nearest(rgb)) {
+        Some(m) => format!("{} ({})", m.color.name, id),
+        None => id.to_string(),
+    }
+}
+
+/// Moves every bead of `source` into `target`. Returns the number of beads moved.
+pub fn merge(beads: &mut [Bead], source: &str, target: &str) -> usize {
+    let mut moved = 0;
+    for bead in beads.iter_mut().filter(|b| b.assignment == source) {
+        bead.assignment = target.to_string();
+        moved += 1;
+    }
+    moved
+}
+
+/// Palette id one past the highest id currently in use.
+pub fn next_free_id(beads: &[Bead]) -> String {
+    let next = beads
+        .iter()
+        .filter_map(|b| palette_index(&b.assignment))
+        .max()
+        .map_or(0, |i| i + 1);
+    format!("p{}", next)
+}
+
+/// Re-clusters the members of `entry` into two groups (2-means in Lab space) and moves the
+/// smaller group to a fresh palette id. Returns the new id, or `None` if the entry cannot be split.
+pub fn split(beads: &mut [Bead], entry: &str) -> Option<String> {
+    let members: Vec<usize> = beads
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.assignment == entry)
+        .map(|(i, _)| i)
+        .collect();
+    if members.len() < 2 {
+        return None;
+    }
+
+    let colors: Vec<Rgb> = members
+        .iter()
+        .map(|&i| {
+            let (r, g, b) = beads[i].rgb;
+            Rgb { r, g, b }
+        })
+        .collect();
+
+    // Seed with the two members furthest apart so the result is deterministic.
+    let mut seeds = (colors[0], colors[1]);
+    let mut max_d = 0;
+    for (i, a) in colors.iter().enumerate() {
+        for b in &colors[i + 1..] {
+            let d = a.dist_lab(b);
+            if d > max_d {
+                max_d = d;
+                seeds = (*a, *b);
+            }
+        }
+    }
+    if max_d == 0 {
+        return None;
+    }
+
+    let mut centers = [seeds.0, seeds.1];
+    let mut labels = vec![0usize; colors.len()];
+    for _ in 0..10 {
+        for (label, c) in labels.iter_mut().zip(&colors) {
+            *label = if c.dist_lab(&centers[0]) <= c.dist_lab(&centers[1]) {
+                0
+            } else {
+                1
+            };
+        }
+        for (k, center) in centers.iter_mut().enumerate() {
+            let (mut r, mut g, mut b, mut n) = (0u32, 0u32, 0u32, 0u32);
+            for c in colors
+                .iter()
+                .zip(&labels)
+                .filter(|(_, l)| **l == k)
+                .map(|(c, _)| c)
+            {
+                r += c.r as u32;
+                g += c.g as u32;
+                b += c.b as u32;
+                n += 1;
+            }
+            if let (Some(r), Some(g), Some(b)) =
+                (r.checked_div(n), g.checked_div(n), b.checked_div(n))
+            {
+                *center = Rgb {
+                    r: r as u8,
+                    g: g as u8,
+                    b: b as u8,
+                };
+            }
+        }
+    }
+
+    let ones = labels.iter().filter(|l| **l == 1).count();
+    if ones == 0 || ones == labels.len() {
+        return None;
+    }
+    let moved_label = if ones * 2 <= labels.len() { 1 } else { 0 };
+
+    let new_id = next_free_id(beads);
+    for (&i, &label) in members.iter().zip(&labels) {
+        if label == moved_label {
+            beads[i].assignment = new_id.clone();
+        }
+    }
+    Some(new_id)
+}
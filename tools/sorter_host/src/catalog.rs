@@ -0,0 +1,20 @@
+use sorter_logic::catalog::{Catalog, CatalogError};
+use std::io;
+use std::path::Path;
+
+/// Loads a color chart (see `sorter_logic::catalog`), named after the file: `perler.csv`
+/// becomes the "perler" catalog.
+pub fn load(path: &Path) -> io::Result<Catalog> {
+    let chart = std::fs::read_to_string(path)?;
+    let name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Catalog::parse(&name, &chart).map_err(|e| {
+        let message = match e {
+            CatalogError::BadLine(line) => format!("{}:{}: bad color", path.display(), line),
+            CatalogError::Empty => format!("{}: no colors", path.display()),
+        };
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })
+}
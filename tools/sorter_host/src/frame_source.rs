@@ -0,0 +1,373 @@
+//! Where camera frames come from.
+//!
+//! Every host tool consumes frames through [`FrameSource`], so the same code path can run
+//! against a live sorter, a folder of saved captures, a logged session, or generated test
+//! frames. A frame is always the firmware's wire format: `WIDTH * HEIGHT` RGB565 pixels, big
+//! endian, row major.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sorter_protocol::image as packet;
+use sorter_protocol::{Command, MAX_FRAME};
+
+pub use sorter_protocol::{FRAME_BYTES, FRAME_HEIGHT as HEIGHT, FRAME_MAGIC, FRAME_WIDTH as WIDTH};
+
+pub trait FrameSource {
+    /// The next frame, or `Ok(None)` once the source is exhausted.
+    ///
+    /// An `Err` of kind [`io::ErrorKind::TimedOut`] means a frame started but did not finish;
+    /// the source can still be polled again.
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Frames streamed by the firmware over a serial port (or any other byte stream), as
+/// [`sorter_protocol::image`] packets.
+///
+/// Bytes are skipped until [`FRAME_MAGIC`]; read timeouts while waiting for a header are
+/// retried. A packet with a bad length or CRC (bytes dropped on the link) is discarded and
+/// the search for the next magic resumes one byte after the bad one's, so a good frame that
+/// starts inside the damaged packet is still found.
+///
+/// ```
+/// use sorter_host::frame_source::{FRAME_BYTES, FrameSource, SerialSource};
+/// use sorter_protocol::image as packet;
+///
+/// let packet = |fill: u8| {
+///     let frame = vec![fill; FRAME_BYTES];
+///     let mut p = packet::header(FRAME_BYTES as u16).to_vec();
+///     p.extend_from_slice(&frame);
+///     p.extend_from_slice(&packet::crc(&frame));
+///     p
+/// };
+/// let mut damaged = packet(1);
+/// damaged.remove(500);
+/// let stream = [damaged, packet(2)].concat();
+///
+/// let mut source = SerialSource::new(&stream[..]);
+/// assert_eq!(source.next_frame().unwrap(), Some(vec![2; FRAME_BYTES]));
+/// assert_eq!(source.dropped(), 1);
+/// assert_eq!(source.next_frame().unwrap(), None);
+/// ```
+pub struct SerialSource<R> {
+    port: R,
+    buf: Vec<u8>,
+    dropped: u32,
+    acks: Option<Box<dyn Write + Send>>,
+}
+
+impl<R: Read> SerialSource<R> {
+    pub fn new(port: R) -> Self {
+        Self {
+            port,
+            buf: Vec::new(),
+            dropped: 0,
+            acks: None,
+        }
+    }
+
+    /// Turn on the firmware's frame flow control: it sends at most `window` frames before
+    /// waiting for an ack, so a slow reader loses none. Every packet, good or damaged, is
+    /// acknowledged through `writer` (usually a clone of the port).
+    ///
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    /// use sorter_host::frame_source::{FRAME_BYTES, FrameSource, SerialSource};
+    /// use sorter_protocol::{Command, MAX_FRAME, image as packet};
+    /// # struct Shared(Arc<Mutex<Vec<u8>>>);
+    /// # impl std::io::Write for Shared {
+    /// #     fn write(&mut self, b: &[u8]) -> std::io::Result<usize> {
+    /// #         self.0.lock().unwrap().write(b)
+    /// #     }
+    /// #     fn flush(&mut self) -> std::io::Result<()> {
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    /// let encode = |c: Command| {
+    ///     let mut f = [0u8; MAX_FRAME];
+    ///     let n = c.encode(&mut f);
+    ///     f[..n].to_vec()
+    /// };
+    ///
+    /// let frame = vec![7; FRAME_BYTES];
+    /// let mut stream = packet::header(FRAME_BYTES as u16).to_vec();
+    /// stream.extend_from_slice(&frame);
+    /// stream.extend_from_slice(&packet::crc(&frame));
+    ///
+    /// let sent = Arc::new(Mutex::new(Vec::new()));
+    /// let mut source = SerialSource::new(&stream[..])
+    ///     .with_acks(Box::new(Shared(sent.clone())), 2)
+    ///     .unwrap();
+    /// assert_eq!(source.next_frame().unwrap(), Some(frame));
+    /// let expected = [encode(Command::FrameWindow(2)), encode(Command::FrameAck)].concat();
+    /// assert_eq!(*sent.lock().unwrap(), expected);
+    /// ```
+    pub fn with_acks(mut self, writer: Box<dyn Write + Send>, window: u8) -> io::Result<Self> {
+        self.acks = Some(writer);
+        self.send(Command::FrameWindow(window))?;
+        Ok(self)
+    }
+
+    fn send(&mut self, command: Command) -> io::Result<()> {
+        let Some(writer) = &mut self.acks else {
+            return Ok(());
+        };
+        let mut frame = [0u8; MAX_FRAME];
+        let len = command.encode(&mut frame);
+        writer.write_all(&frame[..len])?;
+        writer.flush()
+    }
+
+    /// Packets discarded for a bad length or CRC.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    // Append what the port has. Ok(false) on a clean end of stream.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0u8; 4096];
+        let n = self.port.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+}
+
+impl<R: Read> FrameSource for SerialSource<R> {
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let Some(start) = self
+                .buf
+                .windows(FRAME_MAGIC.len())
+                .position(|w| w == FRAME_MAGIC)
+            else {
+                // Keep a tail that could be the start of a magic split across reads.
+                let keep = self.buf.len().min(FRAME_MAGIC.len() - 1);
+                self.buf.drain(..self.buf.len() - keep);
+                match self.fill() {
+                    Ok(true) => continue,
+                    Ok(false) => return Ok(None),
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(e) => return Err(e),
+                }
+            };
+            self.buf.drain(..start);
+            if self.buf.len() < packet::PACKET_LEN {
+                // Partial data stays buffered across a timeout.
+                if !self.fill()? {
+                    return Ok(None);
+                }
+                continue;
+            }
+            if let Some(frame) = packet::decode(&self.buf) {
+                let frame = frame.to_vec();
+                self.buf.drain(..packet::PACKET_LEN);
+                self.send(Command::FrameAck)?;
+                return Ok(Some(frame));
+            }
+            self.dropped += 1;
+            self.send(Command::FrameAck)?;
+            self.buf.drain(..1);
+        }
+    }
+}
+
+/// Saved captures (`*.png`, as written by image_saver), in file name order.
+///
+/// image_saver names captures by timestamp, so name order is capture order. Segmentation
+/// masks saved next to labeled captures (`*_mask.png`) are not frames and are skipped.
+pub struct PngDirSource {
+    files: std::vec::IntoIter<PathBuf>,
+}
+
+impl PngDirSource {
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("png")))
+            .filter(|p| {
+                !p.file_stem()
+                    .is_some_and(|s| s.to_string_lossy().ends_with("_mask"))
+            })
+            .collect();
+        files.sort();
+        Ok(Self {
+            files: files.into_iter(),
+        })
+    }
+}
+
+impl FrameSource for PngDirSource {
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.files.next().map(|p| load_png(&p)).transpose()
+    }
+}
+
+/// The frames of an image_saver session, replayed from its `session_<id>.csv` log.
+///
+/// Rows without a saved image are skipped.
+pub struct SessionLogSource {
+    dir: PathBuf,
+    images: std::vec::IntoIter<String>,
+}
+
+impl SessionLogSource {
+    pub fn new(log: impl AsRef<Path>) -> io::Result<Self> {
+        let log = log.as_ref();
+        let images: Vec<String> = fs::read_to_string(log)?
+            .lines()
+            .filter(|l| !l.starts_with('#') && !l.starts_with("image,"))
+            .filter_map(|l| l.split(',').next())
+            .filter(|image| !image.is_empty())
+            .map(str::to_string)
+            .collect();
+        Ok(Self {
+            dir: log.parent().unwrap_or(Path::new(".")).to_path_buf(),
+            images: images.into_iter(),
+        })
+    }
+}
+
+impl FrameSource for SessionLogSource {
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.images
+            .next()
+            .map(|image| load_png(&self.dir.join(image)))
+            .transpose()
+    }
+}
+
+/// Another source's frames, from the start again each time it runs out.
+///
+/// The frames are kept as they are first read, so the source is only read once.
+///
+/// ```
+/// use sorter_host::frame_source::{FrameSource, Looping, SyntheticSource};
+///
+/// let synthetic = || SyntheticSource::new((200, 200, 190), vec![Some((200, 20, 30)), None], 1);
+/// let (red, empty) = {
+///     let mut once = synthetic();
+///     (once.next_frame().unwrap(), once.next_frame().unwrap())
+/// };
+/// let mut looping = Looping::new(Box::new(synthetic()));
+/// assert_eq!(looping.next_frame().unwrap(), red);
+/// assert_eq!(looping.next_frame().unwrap(), empty);
+/// assert_eq!(looping.next_frame().unwrap(), red);
+/// ```
+pub struct Looping {
+    source: Box<dyn FrameSource + Send>,
+    seen: Vec<Vec<u8>>,
+    // Index into `seen` once the source has run out.
+    replay: Option<usize>,
+}
+
+impl Looping {
+    pub fn new(source: Box<dyn FrameSource + Send>) -> Self {
+        Self {
+            source,
+            seen: Vec::new(),
+            replay: None,
+        }
+    }
+}
+
+impl FrameSource for Looping {
+    /// `Ok(None)` only if the source had no frames at all.
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.replay.is_none() {
+            if let Some(frame) = self.source.next_frame()? {
+                self.seen.push(frame.clone());
+                return Ok(Some(frame));
+            }
+            if self.seen.is_empty() {
+                return Ok(None);
+            }
+            self.replay = Some(0);
+        }
+        let i = self.replay.as_mut().unwrap();
+        let frame = self.seen[*i].clone();
+        *i = (*i + 1) % self.seen.len();
+        Ok(Some(frame))
+    }
+}
+
+/// Generated frames: a bead of each color in turn (`None` for an empty slot) on a flat
+/// background, cycling through the list `rounds` times.
+pub struct SyntheticSource {
+    background: (u8, u8, u8),
+    beads: Vec<Option<(u8, u8, u8)>>,
+    remaining: usize,
+    next: usize,
+}
+
+impl SyntheticSource {
+    /// Bead outer radius in pixels; matches the analysis ring.
+    pub const BEAD_RADIUS: i32 = 7;
+
+    pub fn new(background: (u8, u8, u8), beads: Vec<Option<(u8, u8, u8)>>, rounds: usize) -> Self {
+        let remaining = beads.len() * rounds;
+        Self {
+            background,
+            beads,
+            remaining,
+            next: 0,
+        }
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let bead = self.beads[self.next];
+        self.next = (self.next + 1) % self.beads.len();
+
+        let (cx, cy) = (WIDTH as i32 / 2, HEIGHT as i32 / 2 + 2);
+        let mut frame = Vec::with_capacity(FRAME_BYTES);
+        for y in 0..HEIGHT as i32 {
+            for x in 0..WIDTH as i32 {
+                let inside = (x - cx).pow(2) + (y - cy).pow(2) <= Self::BEAD_RADIUS.pow(2);
+                let c = match bead {
+                    Some(c) if inside => c,
+                    _ => self.background,
+                };
+                frame.extend_from_slice(&to_rgb565(c));
+            }
+        }
+        Ok(Some(frame))
+    }
+}
+
+/// Pack an 8-bit color into big-endian RGB565.
+pub fn to_rgb565((r, g, b): (u8, u8, u8)) -> [u8; 2] {
+    let r = (r as u16 * 31) / 255;
+    let g = (g as u16 * 63) / 255;
+    let b = (b as u16 * 31) / 255;
+    ((r << 11) | (g << 5) | b).to_be_bytes()
+}
+
+/// Read a capture saved by image_saver as a frame.
+pub fn load_png(path: &Path) -> io::Result<Vec<u8>> {
+    let img = image::open(path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        .to_rgb8();
+    if img.width() as usize != WIDTH || img.height() as usize != HEIGHT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{}: expected {}x{}, got {}x{}",
+                path.display(),
+                WIDTH,
+                HEIGHT,
+                img.width(),
+                img.height()
+            ),
+        ));
+    }
+    Ok(img
+        .pixels()
+        .flat_map(|p| to_rgb565((p[0], p[1], p[2])))
+        .collect())
+}
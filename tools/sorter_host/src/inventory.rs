@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use sorter_protocol::inventory;
+
+pub use sorter_protocol::INVENTORY_MAGIC;
+
+/// One color the pattern planner can build with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InventoryItem {
+    pub name: String,
+    /// `#rrggbb`
+    pub hex: String,
+    pub rgb: (u8, u8, u8),
+    pub count: u32,
+}
+
+/// Pattern planner inventory file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Inventory {
+    pub source: String,
+    pub items: Vec<InventoryItem>,
+}
+
+impl Inventory {
+    /// Decode the body of an inventory packet (everything after the magic). Returns `None` if
+    /// the body is truncated.
+    pub fn from_packet(body: &[u8]) -> Option<Self> {
+        let items = inventory::decode(body)?
+            .enumerate()
+            .map(|(i, e)| {
+                let rgb = e.rgb;
+                let name = match e.tube {
+                    inventory::NO_TUBE => format!("Palette {}", i),
+                    tube => format!("Palette {} (tube {})", i, tube),
+                };
+                InventoryItem {
+                    name,
+                    hex: format!("#{:02x}{:02x}{:02x}", rgb.0, rgb.1, rgb.2),
+                    rgb,
+                    count: e.count,
+                }
+            })
+            .collect();
+
+        Some(Self {
+            source: "bead_sorter".to_string(),
+            items,
+        })
+    }
+
+    pub fn total(&self) -> u32 {
+        self.items.iter().map(|i| i.count).sum()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
@@ -0,0 +1,7 @@
+//! Shared helpers for the host-side bead sorter tools.
+
+pub mod catalog;
+pub mod frame_source;
+pub mod inventory;
+pub mod link;
+pub mod report;
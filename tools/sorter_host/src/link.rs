@@ -0,0 +1,70 @@
+use sorter_protocol::{
+    self as protocol, Chunk, Status, MAX_FRAME, STATUS_MAGIC, STATUS_PACKET_LEN, UPLOAD_CHUNK,
+};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// How long to wait for a reply; the firmware checks for commands once per sort cycle.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Frames and writes one command to the sorter's data port.
+pub fn send<P: Read + Write + ?Sized>(port: &mut P, command: protocol::Command) -> io::Result<()> {
+    let mut frame = [0u8; MAX_FRAME];
+    let len = command.encode(&mut frame);
+    port.write_all(&frame[..len])?;
+    port.flush()
+}
+
+/// Sends a command and skips anything else on the port (image frames) until the reply's magic
+/// shows up.
+pub fn send_and_wait<P: Read + Write + ?Sized>(
+    port: &mut P,
+    command: protocol::Command,
+    magic: &[u8; 4],
+) -> io::Result<()> {
+    send(port, command)?;
+    wait_for(port, magic, Some(Instant::now() + REPLY_TIMEOUT))
+}
+
+/// Skips everything on the port until `magic` shows up, giving up at `deadline` if there is one.
+pub fn wait_for<P: Read + ?Sized>(
+    port: &mut P,
+    magic: &[u8; 4],
+    deadline: Option<Instant>,
+) -> io::Result<()> {
+    let mut matched = 0;
+    let mut byte = [0u8; 1];
+    while matched < magic.len() {
+        if deadline.is_some_and(|d| Instant::now() > d) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no reply"));
+        }
+        match port.read_exact(&mut byte) {
+            Ok(_) => {
+                matched = if byte[0] == magic[matched] {
+                    matched + 1
+                } else if byte[0] == magic[0] {
+                    1
+                } else {
+                    0
+                };
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Uploads a saved router state (`TubeRouter::encode_state`) in chunks and has the sorter load
+/// it. Returns the sorter's status afterwards, so the caller can check the palette was taken.
+pub fn upload_palette<P: Read + Write + ?Sized>(port: &mut P, state: &[u8]) -> io::Result<Status> {
+    for (i, piece) in state.chunks(UPLOAD_CHUNK).enumerate() {
+        let chunk = Chunk::new((i * UPLOAD_CHUNK) as u16, piece).unwrap();
+        send(port, protocol::Command::UploadChunk(chunk))?;
+    }
+    let len = state.len() as u16;
+    send_and_wait(port, protocol::Command::LoadPalette { len }, &STATUS_MAGIC)?;
+    let mut body = [0u8; STATUS_PACKET_LEN - 4];
+    port.read_exact(&mut body)?;
+    Status::decode(&body).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad status"))
+}
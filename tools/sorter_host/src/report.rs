@@ -0,0 +1,408 @@
+use sorter_logic::text::{English, Locale, Msg, Unit};
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::Duration;
+
+/// Number of beads routed to one color (palette entry or tube).
+#[derive(Clone, Debug)]
+pub struct ColorCount {
+    pub label: String,
+    pub rgb: (u8, u8, u8),
+    pub count: u32,
+    /// Estimated share of the beads in the color's tube that match it, in percent, once the
+    /// color has a tube.
+    pub purity: Option<u8>,
+}
+
+/// Purity below this is highlighted in the report.
+pub const PURITY_WARN: u8 = 80;
+
+/// Something notable that happened during the run (errors, jams, empty streaks...).
+#[derive(Clone, Debug)]
+pub struct SessionEvent {
+    /// Time since the start of the session.
+    pub at: Duration,
+    pub kind: EventKind,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Info,
+    Warning,
+    Error,
+    Jam,
+}
+
+impl EventKind {
+    fn msg(self) -> Msg {
+        match self {
+            EventKind::Info => Msg::EventInfo,
+            EventKind::Warning => Msg::EventWarning,
+            EventKind::Error => Msg::EventError,
+            EventKind::Jam => Msg::EventJam,
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            EventKind::Info => "#8af",
+            EventKind::Warning => "#fc5",
+            EventKind::Error => "#f55",
+            EventKind::Jam => "#f8f",
+        }
+    }
+}
+
+/// A bead worth a second look, shown with its captured frame.
+#[derive(Clone, Debug)]
+pub struct BeadThumbnail {
+    /// Bead number within the run (1-based, in sort order).
+    pub bead: u32,
+    /// Tube the bead was dropped into.
+    pub tube: Option<u8>,
+    /// Image path, relative to the report file; empty if the frame was not saved.
+    pub image: String,
+    pub label: String,
+    pub rgb: (u8, u8, u8),
+    /// 0 (no idea) ..= 100 (certain)
+    pub confidence: u8,
+}
+
+/// Summary of one sorting run, rendered as a standalone HTML page.
+#[derive(Clone, Debug, Default)]
+pub struct SessionReport {
+    pub title: String,
+    pub started: String,
+    pub duration: Duration,
+    pub frames: u32,
+    pub colors: Vec<ColorCount>,
+    pub events: Vec<SessionEvent>,
+    pub low_confidence: Vec<BeadThumbnail>,
+}
+
+const STYLE: &str = r#"
+    body { font-family: sans-serif; background: #222; color: #eee; margin: 20px; }
+    section { margin-bottom: 20px; border: 1px solid #444; padding: 10px; }
+    h2 { margin-top: 0; }
+    table { border-collapse: collapse; }
+    td, th { padding: 4px 10px; border-bottom: 1px solid #444; text-align: left; }
+    .swatch { display: inline-block; width: 20px; height: 20px; border: 1px solid #fff; vertical-align: middle; }
+    .bead-container { display: flex; flex-wrap: wrap; gap: 10px; }
+    .bead-card { background: #333; border-radius: 8px; padding: 5px; width: 140px; text-align: center; }
+    .bead-card img { width: 128px; height: 128px; object-fit: contain; image-rendering: pixelated; }
+    .meta { font-size: 10px; color: #aaa; margin-top: 4px; }
+    .impure { color: orange; font-weight: bold; }
+"#;
+
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&#39;")
+        .replace('"', "&quot;")
+}
+
+pub fn swatch(rgb: (u8, u8, u8)) -> String {
+    format!(
+        "<span class='swatch' style='background-color: rgb({},{},{})'></span>",
+        rgb.0, rgb.1, rgb.2
+    )
+}
+
+impl SessionReport {
+    pub fn to_html(&self) -> String {
+        self.to_html_in(&English)
+    }
+
+    /// Render the report with headings and units taken from `locale`.
+    pub fn to_html_in(&self, locale: &dyn Locale) -> String {
+        let t = |id| locale.msg(id);
+        let mut html = String::new();
+        let sorted: u32 = self.colors.iter().map(|c| c.count).sum();
+
+        writeln!(
+            html,
+            "<!DOCTYPE html>\n<html><head><meta charset='UTF-8'><title>{}</title><style>{}</style></head><body>",
+            escape(&self.title),
+            STYLE
+        )
+        .unwrap();
+        writeln!(html, "<h1>{}</h1>", escape(&self.title)).unwrap();
+
+        // Summary
+        writeln!(html, "<section><h2>{}</h2><table>", t(Msg::Summary)).unwrap();
+        writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            t(Msg::Started),
+            escape(&self.started)
+        )
+        .unwrap();
+        writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            t(Msg::Duration),
+            format_duration(self.duration)
+        )
+        .unwrap();
+        writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            t(Msg::Frames),
+            self.frames
+        )
+        .unwrap();
+        writeln!(
+            html,
+            "<tr><th>{}</th><td>{} {}</td></tr>",
+            t(Msg::BeadsSorted),
+            sorted,
+            locale.unit(Unit::Beads)
+        )
+        .unwrap();
+        if !self.duration.is_zero() {
+            writeln!(
+                html,
+                "<tr><th>{}</th><td>{:.1} {}</td></tr>",
+                t(Msg::Throughput),
+                sorted as f64 * 60.0 / self.duration.as_secs_f64(),
+                locale.unit(Unit::BeadsPerMinute)
+            )
+            .unwrap();
+        }
+        writeln!(html, "</table></section>").unwrap();
+
+        // Per-color counts
+        writeln!(
+            html,
+            "<section><h2>{} ({})</h2><table>",
+            t(Msg::Colors),
+            self.colors.len()
+        )
+        .unwrap();
+        writeln!(
+            html,
+            "<tr><th></th><th>{}</th><th>{}</th><th>{}</th><th>{}</th></tr>",
+            t(Msg::Color),
+            t(Msg::Rgb),
+            t(Msg::Count),
+            t(Msg::Purity)
+        )
+        .unwrap();
+        for c in &self.colors {
+            let purity = match c.purity {
+                Some(p) if p < PURITY_WARN => {
+                    format!(
+                        "<span class='impure'>{}{}</span>",
+                        p,
+                        locale.unit(Unit::Percent)
+                    )
+                }
+                Some(p) => format!("{}{}", p, locale.unit(Unit::Percent)),
+                None => String::new(),
+            };
+            writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{},{},{}</td><td>{}</td><td>{}</td></tr>",
+                swatch(c.rgb),
+                escape(&c.label),
+                c.rgb.0,
+                c.rgb.1,
+                c.rgb.2,
+                c.count,
+                purity
+            )
+            .unwrap();
+        }
+        writeln!(html, "</table></section>").unwrap();
+
+        // Events timeline
+        writeln!(
+            html,
+            "<section><h2>{} ({})</h2>",
+            t(Msg::Events),
+            self.events.len()
+        )
+        .unwrap();
+        if self.events.is_empty() {
+            writeln!(html, "<p>{}</p>", t(Msg::NoEvents)).unwrap();
+        } else {
+            writeln!(
+                html,
+                "<table><tr><th>{}</th><th>{}</th><th>{}</th></tr>",
+                t(Msg::Time),
+                t(Msg::Type),
+                t(Msg::Message)
+            )
+            .unwrap();
+            for e in &self.events {
+                writeln!(
+                    html,
+                    "<tr><td>{}</td><td style='color: {}'>{}</td><td>{}</td></tr>",
+                    format_duration(e.at),
+                    e.kind.color(),
+                    t(e.kind.msg()),
+                    escape(&e.message)
+                )
+                .unwrap();
+            }
+            writeln!(html, "</table>").unwrap();
+        }
+        writeln!(html, "</section>").unwrap();
+
+        // Low-confidence beads
+        writeln!(
+            html,
+            "<section><h2>{} ({})</h2><div class='bead-container'>",
+            t(Msg::LowConfidenceBeads),
+            self.low_confidence.len()
+        )
+        .unwrap();
+        for b in &self.low_confidence {
+            bead_card(&mut html, b, locale);
+        }
+        writeln!(html, "</div></section>").unwrap();
+
+        writeln!(html, "</body></html>").unwrap();
+        html
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        self.write_in(path, &English)
+    }
+
+    pub fn write_in(&self, path: &Path, locale: &dyn Locale) -> std::io::Result<()> {
+        std::fs::write(path, self.to_html_in(locale))
+    }
+}
+
+fn bead_card(html: &mut String, b: &BeadThumbnail, locale: &dyn Locale) {
+    let img = if b.image.is_empty() {
+        String::new()
+    } else {
+        format!("<img src='{}'>", escape(&b.image))
+    };
+    writeln!(
+        html,
+        "<div class='bead-card'>{}<div>{} {}</div><div class='meta'>{} #{} &middot; {}: {}{}</div></div>",
+        img,
+        swatch(b.rgb),
+        escape(&b.label),
+        locale.msg(Msg::Bead),
+        b.bead,
+        locale.msg(Msg::Confidence),
+        b.confidence,
+        locale.unit(Unit::Percent)
+    )
+    .unwrap();
+}
+
+/// Low-confidence beads of one tube.
+#[derive(Clone, Debug)]
+pub struct RecheckTube {
+    pub tube: u8,
+    pub label: String,
+    pub beads: Vec<BeadThumbnail>,
+}
+
+/// End-of-run "manually re-check these tubes" list: low-confidence beads grouped by the tube
+/// they were dropped into.
+#[derive(Clone, Debug, Default)]
+pub struct RecheckReport {
+    pub title: String,
+    pub tubes: Vec<RecheckTube>,
+}
+
+impl RecheckReport {
+    /// Group `beads` by tube, in tube order. Beads that were not routed are left out;
+    /// `tube_label` names each tube.
+    pub fn from_beads(
+        title: String,
+        beads: &[BeadThumbnail],
+        tube_label: impl Fn(u8) -> String,
+    ) -> Self {
+        let mut tubes: Vec<RecheckTube> = Vec::new();
+        for b in beads {
+            let Some(tube) = b.tube else {
+                continue;
+            };
+            match tubes.iter_mut().find(|t| t.tube == tube) {
+                Some(t) => t.beads.push(b.clone()),
+                None => tubes.push(RecheckTube {
+                    tube,
+                    label: tube_label(tube),
+                    beads: vec![b.clone()],
+                }),
+            }
+        }
+        tubes.sort_by_key(|t| t.tube);
+        Self { title, tubes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tubes.is_empty()
+    }
+
+    /// One line per tube: `Tube 3 (Cherry): 12, 40, 41`.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for t in &self.tubes {
+            let beads: Vec<String> = t.beads.iter().map(|b| b.bead.to_string()).collect();
+            writeln!(text, "{}: {}", t.label, beads.join(", ")).unwrap();
+        }
+        text
+    }
+
+    pub fn to_html(&self) -> String {
+        self.to_html_in(&English)
+    }
+
+    pub fn to_html_in(&self, locale: &dyn Locale) -> String {
+        let mut html = String::new();
+        writeln!(
+            html,
+            "<!DOCTYPE html>\n<html><head><meta charset='UTF-8'><title>{}</title><style>{}</style></head><body>",
+            escape(&self.title),
+            STYLE
+        )
+        .unwrap();
+        writeln!(html, "<h1>{}</h1>", escape(&self.title)).unwrap();
+        if self.tubes.is_empty() {
+            writeln!(html, "<p>{}</p>", locale.msg(Msg::NothingToRecheck)).unwrap();
+        }
+        for t in &self.tubes {
+            writeln!(
+                html,
+                "<section><h2>{} ({})</h2><div class='bead-container'>",
+                escape(&t.label),
+                t.beads.len()
+            )
+            .unwrap();
+            for b in &t.beads {
+                bead_card(&mut html, b, locale);
+            }
+            writeln!(html, "</div></section>").unwrap();
+        }
+        writeln!(html, "</body></html>").unwrap();
+        html
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        self.write_in(path, &English)
+    }
+
+    pub fn write_in(&self, path: &Path, locale: &dyn Locale) -> std::io::Result<()> {
+        std::fs::write(path, self.to_html_in(locale))
+    }
+}
@@ -0,0 +1,824 @@
+use clap::{Parser, Subcommand};
+use serialport::SerialPort;
+use sorter_host::inventory::Inventory;
+use sorter_host::link::{send, send_and_wait, upload_palette, wait_for};
+use sorter_logic::dataset::{self, Label, DATASET_MAGIC};
+use sorter_logic::decode_rgb565_be;
+use sorter_logic::event_log::{self, LogEntry, LOG_ENTRY_BYTES, LOG_MAGIC};
+use sorter_logic::layout::{TubeLayout, LAYOUT_PACKET_LEN, MAX_TUBES};
+use sorter_logic::profile::Profile;
+use sorter_logic::router::TubeRouter;
+use sorter_logic::settings::{Setting, Settings};
+use sorter_logic::telemetry::{Bounded, Telemetry, TELEMETRY_ENTRY_BYTES};
+use sorter_protocol::{
+    self as protocol, stats, CollectTarget, CycleRecord, Info, LogLevel, SelfTestItem,
+    SelfTestReport, ServoId, ServoPosition, Status, INFO_MAGIC, INFO_PACKET_LEN, INVENTORY_MAGIC,
+    LAYOUT_MAGIC, RECORD_MAGIC, SELF_TEST_MAGIC, SELF_TEST_PACKET_LEN, SERVO_MAGIC,
+    SERVO_PACKET_LEN, SETTINGS_MAGIC, STATS_MAGIC, STATUS_MAGIC, STATUS_PACKET_LEN,
+    TELEMETRY_MAGIC,
+};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Command-line control of a running bead sorter over its USB data port.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long)]
+    port: String,
+
+    #[arg(short, long, default_value_t = 115200)]
+    baud: u32,
+
+    /// Color chart (one `name,#rrggbb` per line) that `--color` names are looked up in.
+    #[arg(long)]
+    catalog: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Export the palette colors, with their tubes and bead counts, in the pattern planner's
+    /// inventory format.
+    Inventory {
+        /// Write to this file instead of stdout.
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Switch the sorting profile (learning, production or strict); the sorter remembers it.
+    Profile { name: String },
+    /// Show how full each fixed-size buffer on the sorter has been since power-up.
+    Telemetry,
+    /// Show the machine settings, or change one (`settings edge_threshold 55`). The sorter
+    /// keeps them in flash; servo endpoints take effect at its next boot.
+    Settings {
+        name: Option<String>,
+        value: Option<u16>,
+        /// Try the value out without saving it; it lasts until a reboot or `--save`.
+        #[arg(long, requires = "value")]
+        live: bool,
+        /// Save the settings as they are now, including values set with `--live`.
+        #[arg(long, conflicts_with = "name")]
+        save: bool,
+    },
+    /// Replace the sorter's learned palette and tube map with a saved one (written by the
+    /// simulate example's `--save` or manual_sorter's palette export).
+    LoadPalette { file: String },
+    /// Show sorting totals, cycle time and per-tube counts since the sorter powered up.
+    Stats,
+    /// Show a servo's position (hopper or chutes), or move it (`servo hopper 1493`) to set up
+    /// or test the machine. Moving a servo stops sorting until `start`.
+    Servo { name: String, us: Option<u16> },
+    /// Show the tube layout, or edit it after rebuilding the machine: `layout --shape 12 3`,
+    /// `layout --chute 4 760`, `layout --drop 1 2020`, then `layout --save`. Find positions
+    /// with `servo`. Edits are lost at a reboot unless saved.
+    Layout {
+        /// Number of chute slices and tube rows.
+        #[arg(long, num_args = 2, value_names = ["SLICES", "ROWS"])]
+        shape: Option<Vec<u8>>,
+        /// Chutes servo position for a slice.
+        #[arg(long, num_args = 2, value_names = ["SLICE", "US"])]
+        chute: Option<Vec<u16>>,
+        /// Hopper servo position for a drop row (`row * 2 + slice parity`).
+        #[arg(long, num_args = 2, value_names = ["ROW", "US"])]
+        drop: Option<Vec<u16>>,
+        /// Save the layout; a new shape takes effect at the sorter's next boot.
+        #[arg(long)]
+        save: bool,
+    },
+    /// Resume sorting after `stop` or a servo move.
+    Start,
+    /// Stop the servos immediately, mid-move if need be, until `start`.
+    Stop,
+    /// Let the camera find its exposure and gain again, for new lighting, and lock them.
+    Autoexpose,
+    /// Save the next COUNT sorted beads as PNG frames in DIR, with what the sorter made of
+    /// each in DIR/labels.csv.
+    Dataset {
+        dir: String,
+        #[arg(short, long, default_value_t = 100)]
+        count: usize,
+    },
+    /// Reboot the sorter into its USB bootloader so new firmware can be copied on.
+    Bootloader,
+    /// Erase everything the sorter has stored (settings, tube layout, profile, learned
+    /// palette) and reboot it with the defaults. Holding its button for ten seconds at
+    /// power-up does the same.
+    FactoryReset {
+        /// Confirm; nothing is erased without it.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Show the firmware's build and protocol version.
+    Info,
+    /// Show whether the sorter is running, what it has learned, and its supply voltage.
+    Status,
+    /// Show the results of the sorter's power-on self test.
+    Selftest,
+    /// Show the sorter's recent events, oldest first.
+    Log,
+    /// Print a line for every sort cycle as the sorter finishes it, until interrupted.
+    Watch,
+    /// Set how much the sorter writes to its debug console until it reboots: off, error,
+    /// warn, info, debug or trace.
+    Loglevel { level: String },
+    /// Sort only COUNT beads of one color, passing the rest to the reject tube, then stop.
+    /// Needs a reject tube (the `reject_tube` setting).
+    /// The color is a palette entry (`--entry 3`) or a color from the `--catalog` chart
+    /// (`--color Red`).
+    /// `collect 0` goes back to sorting everything.
+    Collect {
+        count: u16,
+        #[arg(long, conflicts_with = "color")]
+        entry: Option<u8>,
+        #[arg(long)]
+        color: Option<String>,
+    },
+    /// Send every bead within TOLERANCE (Delta E) of a `--catalog` color to tube 0 and the rest
+    /// to the last tube. `extract` with no color goes back to sorting everything.
+    Extract {
+        color: Option<String>,
+        #[arg(long, default_value_t = 6)]
+        tolerance: u8,
+    },
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let mut port = serialport::new(&args.port, args.baud)
+        .timeout(Duration::from_millis(500))
+        .open()
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to open {}: {}", args.port, e);
+            std::process::exit(1);
+        });
+    // The firmware only streams to the data port while DTR is asserted.
+    let _ = port.write_data_terminal_ready(true);
+
+    // Refuse to misread replies from firmware that speaks another protocol. Reflashing and
+    // asking which firmware it is still work.
+    if !matches!(args.command, Command::Bootloader | Command::Info) {
+        match request_info(port.as_mut()) {
+            Ok(info) if info.is_compatible() => {}
+            Ok(info) => {
+                eprintln!(
+                    "The sorter's firmware ({}) speaks protocol {}, but sorterctl speaks {}; \
+                     update whichever is older",
+                    info.git_hash(),
+                    info.protocol_version,
+                    protocol::PROTOCOL_VERSION
+                );
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Failed to identify the firmware (too old?): {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    match args.command {
+        Command::Inventory { output } => {
+            let inventory = match request_inventory(port.as_mut()) {
+                Ok(inventory) => inventory,
+                Err(e) => {
+                    eprintln!("Failed to read inventory: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let json = inventory.to_json();
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, json).unwrap();
+                    eprintln!(
+                        "Wrote {} colors ({} beads) to {}",
+                        inventory.items.len(),
+                        inventory.total(),
+                        path
+                    );
+                }
+                None => println!("{}", json),
+            }
+        }
+        Command::Profile { name } => {
+            let Some(profile) = Profile::from_name(&name) else {
+                let names: Vec<&str> = Profile::ALL.iter().map(|p| p.name()).collect();
+                eprintln!(
+                    "Unknown profile {}; expected one of {}",
+                    name,
+                    names.join(", ")
+                );
+                std::process::exit(1);
+            };
+            if let Err(e) = send(port.as_mut(), protocol::Command::SetProfile(profile.id())) {
+                eprintln!("Failed to send profile: {}", e);
+                std::process::exit(1);
+            }
+            eprintln!("Profile set to {}", profile.name());
+        }
+        Command::Telemetry => {
+            let telemetry = match request_telemetry(port.as_mut()) {
+                Ok(telemetry) => telemetry,
+                Err(e) => {
+                    eprintln!("Failed to read telemetry: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            println!(
+                "{:<16} {:>6} {:>8} {:>10}",
+                "structure", "peak", "capacity", "full"
+            );
+            for which in Bounded::ALL {
+                let mark = telemetry.get(which);
+                println!(
+                    "{:<16} {:>6} {:>8} {:>10}",
+                    which.name(),
+                    mark.peak,
+                    mark.capacity,
+                    mark.full
+                );
+            }
+        }
+        Command::Settings {
+            name,
+            value,
+            live,
+            save,
+        } => {
+            if let Some(name) = name {
+                let Some(setting) = Setting::from_name(&name) else {
+                    let names: Vec<&str> = Setting::ALL.iter().map(|s| s.name()).collect();
+                    eprintln!(
+                        "Unknown setting {}; expected one of {}",
+                        name,
+                        names.join(", ")
+                    );
+                    std::process::exit(1);
+                };
+                let Some(value) = value else {
+                    eprintln!("Missing a value for {}", setting.name());
+                    std::process::exit(1);
+                };
+                let id = setting.id();
+                let command = if live {
+                    protocol::Command::TuneSetting { id, value }
+                } else {
+                    protocol::Command::SetSetting { id, value }
+                };
+                if let Err(e) = send(port.as_mut(), command) {
+                    eprintln!("Failed to send setting: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            if save {
+                if let Err(e) = send(port.as_mut(), protocol::Command::SaveSettings) {
+                    eprintln!("Failed to save settings: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            // Read back, which also shows whether the sorter accepted a change.
+            let settings = match request_settings(port.as_mut()) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    eprintln!("Failed to read settings: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            for setting in Setting::ALL {
+                println!("{:<24} {:>6}", setting.name(), settings.get(setting));
+            }
+        }
+        Command::LoadPalette { file } => {
+            let state = std::fs::read(&file).unwrap_or_else(|e| {
+                eprintln!("Failed to read {}: {}", file, e);
+                std::process::exit(1);
+            });
+            let mut router = TubeRouter::new(MAX_TUBES);
+            if let Err(e) = router.restore_state(&state) {
+                eprintln!("{} is not a saved palette: {:?}", file, e);
+                std::process::exit(1);
+            }
+            let status = match upload_palette(port.as_mut(), &state) {
+                Ok(status) => status,
+                Err(e) => {
+                    eprintln!("Failed to upload palette: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let (entries, tubes) = (router.palette().len(), router.tubes().len());
+            if (status.palette_entries as usize, status.tubes_used as usize) != (entries, tubes) {
+                eprintln!(
+                    "The sorter kept its palette ({} entries, {} tubes); does {} fit its layout?",
+                    status.palette_entries, status.tubes_used, file
+                );
+                std::process::exit(1);
+            }
+            eprintln!("Loaded {} palette entries into {} tubes", entries, tubes);
+        }
+        Command::Stats => {
+            let (summary, tubes) = match request_stats(port.as_mut()) {
+                Ok(stats) => stats,
+                Err(e) => {
+                    eprintln!("Failed to read stats: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            println!(
+                "uptime        {:>8.1} min",
+                summary.uptime_secs as f32 / 60.0
+            );
+            println!("sorted        {:>8}", summary.beads_sorted);
+            println!("empty         {:>8}", summary.empties);
+            println!("rejected      {:>8}", summary.rejects);
+            println!("cycle         {:>8} ms", summary.avg_cycle_ms);
+            println!("overlapped    {:>8} ms", summary.avg_overlap_ms);
+            println!(
+                "throughput    {:>8.1} beads/min",
+                summary.beads_sorted as f32 * 60.0 / summary.uptime_secs.max(1) as f32
+            );
+            println!("\n{:>4} {:>6} {:>6}", "tube", "beads", "purity");
+            for (t, tube) in tubes.iter().enumerate() {
+                let purity = tube.purity.map(|p| format!("{}%", p)).unwrap_or_default();
+                println!("{:>4} {:>6} {:>6}", t, tube.count, purity);
+            }
+        }
+        Command::Servo { name, us } => {
+            let Some(servo) = ServoId::from_name(&name) else {
+                let names: Vec<&str> = ServoId::ALL.iter().map(|s| s.name()).collect();
+                eprintln!(
+                    "Unknown servo {}; expected one of {}",
+                    name,
+                    names.join(", ")
+                );
+                std::process::exit(1);
+            };
+            let command = match us {
+                Some(us) => protocol::Command::SetServo { servo, us },
+                None => protocol::Command::GetServo(servo),
+            };
+            match request_servo(port.as_mut(), command) {
+                Ok(position) => println!("{} {} us", position.servo.name(), position.us),
+                Err(e) => {
+                    eprintln!("Failed to read servo position: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Layout {
+            shape,
+            chute,
+            drop,
+            save,
+        } => {
+            let mut commands = Vec::new();
+            if let Some([slices, rows]) = shape.as_deref() {
+                commands.push(protocol::Command::SetLayoutShape {
+                    slices: *slices,
+                    rows: *rows,
+                });
+            }
+            if let Some([slice, us]) = chute.as_deref() {
+                commands.push(protocol::Command::SetChutePosition {
+                    slice: *slice as u8,
+                    us: *us,
+                });
+            }
+            if let Some([row, us]) = drop.as_deref() {
+                commands.push(protocol::Command::SetDropPosition {
+                    row: *row as u8,
+                    us: *us,
+                });
+            }
+            if save {
+                commands.push(protocol::Command::SaveLayout);
+            }
+            for command in commands {
+                if let Err(e) = send(port.as_mut(), command) {
+                    eprintln!("Failed to send {:?}: {}", command, e);
+                    std::process::exit(1);
+                }
+            }
+            let layout = match request_layout(port.as_mut()) {
+                Ok(layout) => layout,
+                Err(e) => {
+                    eprintln!("Failed to read the tube layout: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            println!(
+                "{} slices x {} rows, {} tubes",
+                layout.slices,
+                layout.rows,
+                layout.tube_count()
+            );
+            println!("\n{:>5} {:>6}", "slice", "chute");
+            for (s, us) in layout.chute_positions.iter().enumerate() {
+                if s < layout.slices as usize || *us != 0 {
+                    println!("{:>5} {:>6}", s, us);
+                }
+            }
+            println!("\n{:>5} {:>6}", "row", "drop");
+            for (d, us) in layout.drop_positions.iter().enumerate() {
+                if d < layout.rows as usize * 2 || *us != 0 {
+                    println!("{:>5} {:>6}", d, us);
+                }
+            }
+            if let Err(e) = layout.validate() {
+                println!("\nnot ready to save: {:?}", e);
+            }
+        }
+        Command::Dataset { dir, count } => {
+            if let Err(e) = capture_dataset(port.as_mut(), &dir, count) {
+                eprintln!("Dataset capture failed: {}", e);
+                std::process::exit(1);
+            }
+            eprintln!("Saved {} beads to {}", count, dir);
+        }
+        Command::Start | Command::Stop | Command::Autoexpose | Command::Bootloader => {
+            let command = match args.command {
+                Command::Start => protocol::Command::Start,
+                Command::Stop => protocol::Command::Stop,
+                Command::Autoexpose => protocol::Command::AutoExposure,
+                _ => protocol::Command::ResetToBootloader,
+            };
+            if let Err(e) = send(port.as_mut(), command) {
+                eprintln!("Failed to send {:?}: {}", command, e);
+                std::process::exit(1);
+            }
+        }
+        Command::FactoryReset { yes } => {
+            if !yes {
+                eprintln!("This erases the sorter's settings and learned colors; add --yes");
+                std::process::exit(1);
+            }
+            let command = protocol::Command::FactoryReset;
+            if let Err(e) = send(port.as_mut(), command) {
+                eprintln!("Failed to send {:?}: {}", command, e);
+                std::process::exit(1);
+            }
+        }
+        Command::Collect {
+            count,
+            entry,
+            color,
+        } => {
+            let target = match (entry, color) {
+                (Some(entry), _) => CollectTarget::PaletteEntry(entry),
+                (None, Some(name)) => {
+                    let (l, a, b) =
+                        catalog_lab(args.catalog.as_deref(), &name).unwrap_or_else(|e| {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        });
+                    CollectTarget::Lab { l, a, b }
+                }
+                (None, None) if count == 0 => CollectTarget::PaletteEntry(0),
+                (None, None) => {
+                    eprintln!("Say which beads to collect with --entry or --color");
+                    std::process::exit(1);
+                }
+            };
+            let command = protocol::Command::Collect { target, count };
+            if let Err(e) = send(port.as_mut(), command) {
+                eprintln!("Failed to send {:?}: {}", command, e);
+                std::process::exit(1);
+            }
+        }
+        Command::Extract { color, tolerance } => {
+            let command = match color {
+                Some(name) => {
+                    let (l, a, b) =
+                        catalog_lab(args.catalog.as_deref(), &name).unwrap_or_else(|e| {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        });
+                    protocol::Command::Extract { l, a, b, tolerance }
+                }
+                None => protocol::Command::Extract {
+                    l: 0,
+                    a: 0,
+                    b: 0,
+                    tolerance: 0,
+                },
+            };
+            if let Err(e) = send(port.as_mut(), command) {
+                eprintln!("Failed to send {:?}: {}", command, e);
+                std::process::exit(1);
+            }
+        }
+        Command::Watch => loop {
+            let record = match read_record(port.as_mut()) {
+                Ok(record) => record,
+                Err(e) => {
+                    eprintln!("Failed to read a cycle record: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let Some(record) = record else {
+                continue;
+            };
+            let tube = record.tube.map_or("-".into(), |t| t.to_string());
+            let color = record.color.map_or("-".into(), |[r, g, b]| {
+                format!("#{:02x}{:02x}{:02x}", r, g, b)
+            });
+            let distance = record.distance.map_or("-".into(), |d| d.to_string());
+            println!(
+                "{:>10.3} s  {:<8} tube {:>3}  {:<7}  dist {:>5}  pickup {:>4} ms  camera {:>4} ms  cycle {:>5} ms",
+                record.uptime_ms as f64 / 1000.0,
+                format!("{:?}", record.result),
+                tube,
+                color,
+                distance,
+                record.pickup_ms,
+                record.camera_ms,
+                record.cycle_ms,
+            );
+        },
+        Command::Loglevel { level } => {
+            let Some(level) = LogLevel::from_name(&level) else {
+                let names: Vec<&str> = LogLevel::ALL.iter().map(|l| l.name()).collect();
+                eprintln!(
+                    "Unknown log level {}; expected one of {}",
+                    level,
+                    names.join(", ")
+                );
+                std::process::exit(1);
+            };
+            let command = protocol::Command::SetLogLevel(level);
+            if let Err(e) = send(port.as_mut(), command) {
+                eprintln!("Failed to send {:?}: {}", command, e);
+                std::process::exit(1);
+            }
+        }
+        Command::Log => {
+            let entries = match request_log(port.as_mut()) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    eprintln!("Failed to read the event log: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            for entry in entries {
+                println!("{:>10.3} s  {:?}", entry.at_ms as f64 / 1000.0, entry.event);
+            }
+        }
+        Command::Status => {
+            let status = match request_status(port.as_mut()) {
+                Ok(status) => status,
+                Err(e) => {
+                    eprintln!("Failed to read status: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            println!("running       {:>8}", status.running);
+            println!("profile       {:>8}", status.profile_id);
+            println!("colors        {:>8}", status.palette_entries);
+            println!("tubes used    {:>8}", status.tubes_used);
+            println!("sorted        {:>8}", status.beads_sorted);
+            println!("supply        {:>8} mV", status.supply_mv);
+            println!("supply low    {:>8} mV", status.supply_min_mv);
+        }
+        Command::Selftest => {
+            let report = match request_self_test(port.as_mut()) {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("Failed to read self test results: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            for item in SelfTestItem::ALL {
+                let result = match report.result(item) {
+                    Some(true) => "pass",
+                    Some(false) => "FAIL",
+                    None => "not run",
+                };
+                println!("{:<14} {}", item.name(), result);
+            }
+            if !report.passed() {
+                std::process::exit(1);
+            }
+        }
+        Command::Info => {
+            let info = match request_info(port.as_mut()) {
+                Ok(info) => info,
+                Err(e) => {
+                    eprintln!("Failed to read firmware info: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let built = chrono::DateTime::from_timestamp(info.build_time as i64, 0).map_or_else(
+                || "unknown".into(),
+                |t| t.format("%Y-%m-%d %H:%M UTC").to_string(),
+            );
+            println!("firmware      {}", info.git_hash());
+            println!("built         {}", built);
+            println!("bsp           {}", info.bsp_revision());
+            println!(
+                "protocol      {} (sorterctl {})",
+                info.protocol_version,
+                protocol::PROTOCOL_VERSION
+            );
+            if let Some(message) = info.last_panic() {
+                println!("last panic    {}", message);
+            }
+        }
+    }
+}
+
+// The Lab color of a color on the user's chart, clamped to what the protocol carries.
+fn catalog_lab(chart: Option<&str>, name: &str) -> Result<(u8, i8, i8), String> {
+    let chart = chart.ok_or("--color needs a color chart (--catalog)")?;
+    let catalog = sorter_host::catalog::load(Path::new(chart)).map_err(|e| e.to_string())?;
+    let color = catalog
+        .by_name(name)
+        .ok_or_else(|| format!("{} has no color named {}", chart, name))?;
+    let (l, a, b) = color.lab;
+    Ok((
+        l.clamp(0, 255) as u8,
+        a.clamp(-128, 127) as i8,
+        b.clamp(-128, 127) as i8,
+    ))
+}
+
+// Read a reply body: an entry count, then that many fixed-size entries.
+fn read_body(port: &mut dyn SerialPort, entry_bytes: usize) -> io::Result<Vec<u8>> {
+    let mut count = [0u8; 1];
+    port.read_exact(&mut count)?;
+    let mut body = vec![0u8; 1 + count[0] as usize * entry_bytes];
+    body[0] = count[0];
+    port.read_exact(&mut body[1..])?;
+    Ok(body)
+}
+
+fn request_inventory(port: &mut dyn SerialPort) -> io::Result<Inventory> {
+    send_and_wait(port, protocol::Command::ExportInventory, &INVENTORY_MAGIC)?;
+    let body = read_body(port, 8)?;
+    Inventory::from_packet(&body)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated inventory"))
+}
+
+fn request_telemetry(port: &mut dyn SerialPort) -> io::Result<Telemetry> {
+    send_and_wait(port, protocol::Command::Telemetry, &TELEMETRY_MAGIC)?;
+    let body = read_body(port, TELEMETRY_ENTRY_BYTES)?;
+    Telemetry::decode(&body)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated telemetry"))
+}
+
+fn request_settings(port: &mut dyn SerialPort) -> io::Result<Settings> {
+    send_and_wait(port, protocol::Command::GetSettings, &SETTINGS_MAGIC)?;
+    let body = read_body(port, 2)?;
+    Settings::decode(&body)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated settings"))
+}
+
+fn request_status(port: &mut dyn SerialPort) -> io::Result<Status> {
+    send_and_wait(port, protocol::Command::QueryStatus, &STATUS_MAGIC)?;
+    let mut body = [0u8; STATUS_PACKET_LEN - 4];
+    port.read_exact(&mut body)?;
+    Status::decode(&body).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad status"))
+}
+
+fn request_layout(port: &mut dyn SerialPort) -> io::Result<TubeLayout> {
+    send_and_wait(port, protocol::Command::GetLayout, &LAYOUT_MAGIC)?;
+    let mut body = [0u8; LAYOUT_PACKET_LEN - 4];
+    port.read_exact(&mut body)?;
+    TubeLayout::decode(&body)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad layout reply"))
+}
+
+fn request_info(port: &mut dyn SerialPort) -> io::Result<Info> {
+    send_and_wait(port, protocol::Command::GetInfo, &INFO_MAGIC)?;
+    let mut body = [0u8; INFO_PACKET_LEN - 4];
+    port.read_exact(&mut body)?;
+    Info::decode(&body).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad info"))
+}
+
+fn request_self_test(port: &mut dyn SerialPort) -> io::Result<SelfTestReport> {
+    send_and_wait(port, protocol::Command::GetSelfTest, &SELF_TEST_MAGIC)?;
+    let mut body = [0u8; SELF_TEST_PACKET_LEN - 4];
+    port.read_exact(&mut body)?;
+    SelfTestReport::decode(&body)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad self test reply"))
+}
+
+// Wait for the next cycle record. `None` if it was damaged.
+fn read_record(port: &mut dyn SerialPort) -> io::Result<Option<CycleRecord>> {
+    wait_for(port, &RECORD_MAGIC, None)?;
+    let mut len = [0u8; 1];
+    port.read_exact(&mut len)?;
+    let mut body = vec![0u8; 1 + len[0] as usize];
+    body[0] = len[0];
+    port.read_exact(&mut body[1..])?;
+    Ok(CycleRecord::decode(&body))
+}
+
+fn request_log(port: &mut dyn SerialPort) -> io::Result<Vec<LogEntry>> {
+    send_and_wait(port, protocol::Command::DumpLog, &LOG_MAGIC)?;
+    let body = read_body(port, LOG_ENTRY_BYTES)?;
+    event_log::decode(&body)
+        .map(|entries| entries.collect())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated event log"))
+}
+
+fn request_servo(
+    port: &mut dyn SerialPort,
+    command: protocol::Command,
+) -> io::Result<ServoPosition> {
+    send_and_wait(port, command, &SERVO_MAGIC)?;
+    let mut body = [0u8; SERVO_PACKET_LEN - 4];
+    port.read_exact(&mut body)?;
+    ServoPosition::decode(&body)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad servo reply"))
+}
+
+// Turn on dataset mode, save `count` labeled captures, and turn it off again.
+fn capture_dataset(port: &mut dyn SerialPort, dir: &str, count: usize) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut labels = fs::File::create(format!("{}/labels.csv", dir))?;
+    writeln!(
+        labels,
+        "file,tube,found,r,g,b,variance,var_r,var_g,var_b,pixel_count,center_x,center_y,\
+         finish,sparkle,diameter_px,eccentricity"
+    )?;
+    send(port, protocol::Command::DatasetMode(true))?;
+    let mut buf = Vec::new();
+    let mut saved = 0;
+    while saved < count {
+        let mut chunk = [0u8; 4096];
+        match port.read(&mut chunk) {
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        }
+        // Frames and other replies are interleaved; keep only dataset packets.
+        while let Some(start) = buf.windows(4).position(|w| w == DATASET_MAGIC) {
+            buf.drain(..start);
+            if buf.len() < dataset::PACKET_LEN {
+                break;
+            }
+            let Some((label, frame)) = dataset::decode(&buf) else {
+                buf.drain(..1);
+                continue;
+            };
+            let file = format!("bead_{:05}.png", saved);
+            save_png(frame, &format!("{}/{}", dir, file))?;
+            writeln!(labels, "{},{}", file, label_csv(&label))?;
+            eprintln!("{}: tube {:?}", file, label.tube);
+            saved += 1;
+            buf.drain(..dataset::PACKET_LEN);
+        }
+    }
+    send(port, protocol::Command::DatasetMode(false))
+}
+
+fn save_png(frame: &[u8], path: &str) -> io::Result<()> {
+    let pixels: Vec<u8> = decode_rgb565_be(frame)
+        .flat_map(|c| [c.r, c.g, c.b])
+        .collect();
+    let (w, h) = (protocol::FRAME_WIDTH as u32, protocol::FRAME_HEIGHT as u32);
+    image::RgbImage::from_raw(w, h, pixels)
+        .expect("frame is WIDTH x HEIGHT")
+        .save(path)
+        .map_err(io::Error::other)
+}
+
+fn label_csv(label: &Label) -> String {
+    let tube = label.tube.map(|t| t.to_string()).unwrap_or_default();
+    let Some(m) = label.bead else {
+        return format!("{},0{}", tube, ",".repeat(14));
+    };
+    format!(
+        "{},1,{},{},{},{},{},{},{},{},{},{},{:?},{},{:.2},{:.3}",
+        tube,
+        m.color.r,
+        m.color.g,
+        m.color.b,
+        m.variance,
+        m.channel_variance[0],
+        m.channel_variance[1],
+        m.channel_variance[2],
+        m.pixel_count,
+        m.center.0,
+        m.center.1,
+        m.finish,
+        m.sparkle,
+        m.diameter_px,
+        m.eccentricity
+    )
+}
+
+fn request_stats(port: &mut dyn SerialPort) -> io::Result<(stats::Summary, Vec<stats::Tube>)> {
+    send_and_wait(port, protocol::Command::GetStats, &STATS_MAGIC)?;
+    let mut summary = [0u8; stats::SUMMARY_BYTES];
+    port.read_exact(&mut summary)?;
+    let mut body = summary.to_vec();
+    body.extend(read_body(port, stats::TUBE_BYTES)?);
+    let (summary, tubes) = stats::decode(&body)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated stats"))?;
+    Ok((summary, tubes.collect()))
+}
@@ -0,0 +1,779 @@
+//! The sorter behind the port: the firmware's command handling and sort cycle, with the
+//! servos and the camera simulated.
+//!
+//! Each pickup photographs the next frame of the source, which starts over once it runs
+//! out. Servos move at a steady rate and the cycle settles for as long as the firmware's does,
+//! both sped up by [`Device::new`]'s `speed`; the port is read all the while, so an emergency
+//! stop cuts a cycle short as it does on the machine. There is no button, no supply to sag
+//! and nothing to stall, and the camera's exposure and the lighting never change.
+
+use std::io;
+use std::time::{Duration, Instant, SystemTime};
+
+use sorter_logic::collect::{Collection, Extraction};
+use sorter_logic::cycle::{
+    Action, CycleConfig, CycleEnd, CycleError, Event, Inspection, SorterFsm,
+};
+use sorter_logic::dataset::{Label, Measurement};
+use sorter_logic::event_log::{self, EventLog, LogEvent};
+use sorter_logic::hopper::{HopperEvent, PickupMonitor};
+use sorter_logic::layout::{TubeLayout, LAYOUT_PACKET_LEN, MAX_TUBES};
+use sorter_logic::policy::{Decision, SortPolicy};
+use sorter_logic::profile::Profile;
+use sorter_logic::router::{PALETTE_SIZE, ROUTER_STATE_MAX};
+use sorter_logic::settings::{Setting, Settings, SETTINGS_PACKET_MAX};
+use sorter_logic::telemetry::{Bounded, Telemetry, TELEMETRY_PACKET_MAX};
+use sorter_logic::{analyze_image_debug, detect_empty, BackgroundModel, BeadAnalysis};
+use sorter_protocol::stats::{self, Summary, Tube};
+use sorter_protocol::{
+    inventory, Command, CycleRecord, CycleResult, Event as DeviceEvent, Info, SelfTestItem,
+    SelfTestReport, ServoId, ServoPosition, Status, EVENT_PACKET_LEN, FRAME_HEIGHT, FRAME_WIDTH,
+    INFO_PACKET_LEN, SELF_TEST_PACKET_LEN, SERVO_PACKET_LEN, STATUS_PACKET_LEN,
+};
+
+use sorter_host::frame_source::{FrameSource, Looping};
+
+use crate::flash::Flash;
+use crate::link::Link;
+
+// How long to read the port between commands while not sorting.
+const IDLE_POLL: Duration = Duration::from_millis(10);
+// Servo travel per millisecond of a move.
+const SLEW_US_PER_MS: u64 = 4;
+// Reported as the supply voltage, which never sags.
+const SUPPLY_MV: u16 = 5000;
+// Save the learned palette at most this often while sorting (and whenever stopped).
+const SAVE_LEARNED_SECS: u64 = 300;
+// As the firmware's.
+const EVENT_LOG_LEN: usize = 64;
+const COMMAND_BUFFER_LEN: usize = 64;
+const COMMAND_QUEUE_LEN: usize = 4;
+const SERVO_QUEUE_LEN: usize = 4;
+
+// Capacities as the firmware's. Commands are handled as they arrive, so the queues never
+// fill here.
+fn telemetry(tube_count: usize) -> Telemetry {
+    Telemetry::new([
+        PALETTE_SIZE,
+        tube_count,
+        COMMAND_BUFFER_LEN,
+        EVENT_LOG_LEN,
+        COMMAND_QUEUE_LEN,
+        SERVO_QUEUE_LEN,
+    ])
+}
+
+pub struct Device {
+    link: Link,
+    flash: Flash,
+    camera: Looping,
+    // The source's first frame, until the first pickup photographs it.
+    first: Option<Vec<u8>>,
+    // The frame of the bead in the slot.
+    frame: Vec<u8>,
+    background: Option<BackgroundModel>,
+    speed: f64,
+    booted: Instant,
+    settings: Settings,
+    layout: TubeLayout,
+    // Edited by the layout commands until saved.
+    pending_layout: TubeLayout,
+    policy: SortPolicy,
+    telemetry: Telemetry,
+    pickups: PickupMonitor,
+    fsm: SorterFsm,
+    event_log: EventLog<EVENT_LOG_LEN>,
+    stats: Stats,
+    hopper_us: u16,
+    chutes_us: u16,
+    running: bool,
+    dataset_mode: bool,
+    collection: Option<Collection>,
+    extraction: Option<Extraction>,
+    // Filled by upload chunks, for a following load command.
+    upload: Vec<u8>,
+    // Beads routed since the learned state was last saved, and when it was.
+    unsaved: bool,
+    last_save: Instant,
+    // The bead being sorted.
+    bead: Option<BeadAnalysis>,
+    photographed: Instant,
+}
+
+impl Device {
+    /// A sorter photographing the frames of `source` in turn (from the start again once they
+    /// run out), which finds the slot empty when a frame looks like `background`. `speed`
+    /// multiplies the pace of its moves and waits. Fails if `source` has no frames.
+    pub fn new(
+        link: Link,
+        flash: Flash,
+        source: Box<dyn FrameSource + Send>,
+        background: Option<&[u8]>,
+        speed: f64,
+    ) -> io::Result<Self> {
+        let mut camera = Looping::new(source);
+        let first = camera.next_frame()?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no frames to photograph")
+        })?;
+        let layout = TubeLayout::default();
+        let mut device = Self {
+            link,
+            flash,
+            frame: first.clone(),
+            first: Some(first),
+            camera,
+            background: background
+                .and_then(|b| BackgroundModel::from_frame(b, FRAME_WIDTH, FRAME_HEIGHT)),
+            speed,
+            booted: Instant::now(),
+            settings: Settings::default(),
+            layout,
+            pending_layout: layout,
+            policy: SortPolicy::new(layout.tube_count()),
+            telemetry: telemetry(layout.tube_count()),
+            pickups: PickupMonitor::default(),
+            fsm: SorterFsm::new(),
+            event_log: EventLog::new(),
+            stats: Stats::new(layout.tube_count()),
+            hopper_us: 0,
+            chutes_us: 0,
+            running: true,
+            dataset_mode: false,
+            collection: None,
+            extraction: None,
+            upload: vec![0; ROUTER_STATE_MAX],
+            unsaved: false,
+            last_save: Instant::now(),
+            bead: None,
+            photographed: Instant::now(),
+        };
+        device.boot();
+        Ok(device)
+    }
+
+    // Start from what the flash holds, as the firmware does at power-up.
+    fn boot(&mut self) {
+        self.booted = Instant::now();
+        self.settings = self.flash.settings();
+        let layout = self
+            .flash
+            .layout(self.settings.chutes_range(), self.settings.hopper_range());
+        self.layout = layout;
+        self.pending_layout = layout;
+        self.policy = SortPolicy::new(layout.tube_count());
+        self.telemetry = telemetry(layout.tube_count());
+        self.pickups = PickupMonitor::default();
+        self.fsm = SorterFsm::new();
+        self.event_log = EventLog::new();
+        self.stats = Stats::new(layout.tube_count());
+        self.hopper_us = self.settings.get(Setting::HopperDrop);
+        self.chutes_us = layout.chute_positions[layout.slices as usize / 2];
+        self.running = true;
+        self.dataset_mode = false;
+        self.collection = None;
+        self.extraction = None;
+        self.unsaved = false;
+        self.apply_settings();
+        self.policy.set_profile(self.flash.profile());
+        match self.policy.restore_state(self.flash.router_state()) {
+            Ok(()) => eprintln!(
+                "learned state: {} palette entries, {} tubes",
+                self.policy.router().palette().len(),
+                self.policy.router().tubes().len()
+            ),
+            Err(e) => eprintln!("learned state: {:?}, starting fresh", e),
+        }
+        eprintln!(
+            "tube layout: {} slices x {} rows, profile {}",
+            layout.slices,
+            layout.rows,
+            self.policy.profile().name()
+        );
+    }
+
+    /// Carry out the host's commands, then sort one bead if running.
+    pub fn step(&mut self) -> io::Result<()> {
+        self.link.poll(IDLE_POLL)?;
+        while let Some(command) = self.link.next_command() {
+            self.handle(command)?;
+        }
+        if self.running {
+            self.cycle()?;
+        }
+        let save_due = self.last_save.elapsed() >= Duration::from_secs(SAVE_LEARNED_SECS);
+        if !self.running || save_due {
+            self.save_learned()?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, command: Command) -> io::Result<()> {
+        match command {
+            Command::SetProfile(id) => {
+                let Some(profile) = Profile::from_id(id) else {
+                    eprintln!("unknown profile id {}", id);
+                    return Ok(());
+                };
+                self.policy.set_profile(profile);
+                self.flash.save_profile(profile)?;
+                eprintln!("profile: {}", profile.name());
+                if profile != Profile::Learning && !self.policy.router().palette().is_frozen() {
+                    eprintln!("palette is empty, learning one first");
+                }
+            }
+            Command::ExportInventory => {
+                let mut packet = [0u8; inventory::packet_len(PALETTE_SIZE)];
+                let router = self.policy.router();
+                let palette = router.palette();
+                let entries = (0..palette.len()).filter_map(|i| {
+                    let entry = palette.get_entry(i)?;
+                    let (rgb, _) = entry.avg();
+                    Some(inventory::Entry {
+                        tube: router
+                            .tube_of(i)
+                            .map_or(inventory::NO_TUBE, |t| self.policy.layout_tube(t)),
+                        rgb: (rgb.r, rgb.g, rgb.b),
+                        count: entry.count,
+                    })
+                });
+                let len = inventory::encode(entries, &mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::Telemetry => {
+                let mut packet = [0u8; TELEMETRY_PACKET_MAX];
+                let len = self.telemetry.encode(&mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::Start => {
+                self.link.release_stop();
+                self.running = true;
+                self.pickups.resume();
+                self.log(LogEvent::Started);
+            }
+            // The link has already stopped the servos.
+            Command::Stop => {
+                self.running = false;
+                self.log(LogEvent::EmergencyStop);
+            }
+            Command::RequestFrame => {
+                let frame = self.frame.clone();
+                self.link.send_frame(&frame)?;
+            }
+            Command::QueryStatus | Command::LoadPalette { .. } => {
+                if let Command::LoadPalette { len } = command {
+                    self.load_palette(len as usize)?;
+                }
+                let mut packet = [0u8; STATUS_PACKET_LEN];
+                let len = self.status().encode(&mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::Collect { count, .. } if count > 0 && self.policy.reject_tube().is_none() => {
+                eprintln!("no reject tube to pass the other beads to, not collecting");
+            }
+            Command::Collect { target, count } => {
+                self.collection = (count > 0).then(|| Collection::new(target, count));
+                self.extraction = None;
+                self.log(LogEvent::Collect { count });
+                eprintln!("collecting {} beads of {:?}", count, target);
+            }
+            Command::Extract { l, a, b, tolerance } => {
+                self.extraction = (tolerance > 0).then(|| Extraction::new((l, a, b), tolerance));
+                self.collection = None;
+                self.log(LogEvent::Extract(self.extraction.is_some()));
+                eprintln!("extracting ({}, {}, {}) within {}", l, a, b, tolerance);
+            }
+            Command::AutoExposure => eprintln!("no camera exposure to set"),
+            Command::DumpLog => {
+                let mut packet = [0u8; event_log::packet_len(EVENT_LOG_LEN)];
+                let len = self.event_log.encode(&mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::SetLogLevel(level) => eprintln!("log level {}", level.name()),
+            Command::GetSelfTest => {
+                let mut report = SelfTestReport::default();
+                for item in SelfTestItem::ALL {
+                    report.record(item, true);
+                }
+                let mut packet = [0u8; SELF_TEST_PACKET_LEN];
+                let len = report.encode(&mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::GetInfo => {
+                let built = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                let info = Info::new(built, "virtual", "virtual");
+                let mut packet = [0u8; INFO_PACKET_LEN];
+                let len = info.encode(&mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::SetThresholds {
+                match_threshold,
+                merge_margin,
+            } => {
+                self.policy
+                    .set_thresholds(match_threshold as u32, merge_margin as u32);
+            }
+            Command::GetSettings => {
+                let mut packet = [0u8; SETTINGS_PACKET_MAX];
+                let len = self.settings.encode(&mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::SetSetting { id, value } | Command::TuneSetting { id, value } => {
+                let Some(setting) = Setting::from_id(id) else {
+                    eprintln!("unknown setting id {}", id);
+                    return Ok(());
+                };
+                if self.settings.set(setting, value).is_err() {
+                    eprintln!("rejected {} = {}", setting.name(), value);
+                    return Ok(());
+                }
+                self.apply_settings();
+                let save = matches!(command, Command::SetSetting { .. });
+                if save {
+                    self.flash.save_settings(&self.settings)?;
+                }
+                eprintln!("{} = {} (saved: {})", setting.name(), value, save);
+            }
+            Command::SaveSettings => self.flash.save_settings(&self.settings)?,
+            Command::GetStats => {
+                let mut packet = [0u8; stats::packet_len(MAX_TUBES)];
+                let tubes = self
+                    .stats
+                    .tube_counts
+                    .iter()
+                    .enumerate()
+                    .map(|(t, &count)| Tube {
+                        count,
+                        purity: self.policy.purity(t as u8),
+                    });
+                let len = stats::encode(&self.stats.summary(self.booted), tubes, &mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::UploadChunk(chunk) => {
+                let data = chunk.bytes();
+                let at = chunk.offset as usize;
+                match self.upload.get_mut(at..at + data.len()) {
+                    Some(dst) => dst.copy_from_slice(data),
+                    None => eprintln!("upload chunk at {} out of range", at),
+                }
+            }
+            Command::SetServo { servo, .. } | Command::GetServo(servo) => {
+                if let Command::SetServo { us, .. } = command {
+                    // Hand the machine to the host until the next start.
+                    self.running = false;
+                    match servo {
+                        ServoId::Hopper => self.move_servos(Some(us), None)?,
+                        ServoId::Chutes => self.move_servos(None, Some(us))?,
+                    }
+                    eprintln!("jog {} to {}", servo.name(), us);
+                }
+                let us = match servo {
+                    ServoId::Hopper => self.hopper_us,
+                    ServoId::Chutes => self.chutes_us,
+                };
+                let mut packet = [0u8; SERVO_PACKET_LEN];
+                let len = ServoPosition { servo, us }.encode(&mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::DatasetMode(on) => {
+                self.dataset_mode = on;
+                self.log(LogEvent::DatasetMode(on));
+            }
+            // Taken care of by the link.
+            Command::FrameWindow(_) | Command::FrameAck => {}
+            Command::ResetToBootloader => eprintln!("no bootloader to reboot into"),
+            Command::Home => {
+                let home = self.layout.chute_positions[self.layout.slices as usize / 2];
+                self.move_servos(Some(self.settings.get(Setting::HopperDrop)), Some(home))?;
+            }
+            Command::FactoryReset => {
+                eprintln!("factory reset: erasing the stored configuration");
+                self.flash.erase()?;
+                self.boot();
+            }
+            Command::GetLayout => {
+                let mut packet = [0u8; LAYOUT_PACKET_LEN];
+                let len = self.pending_layout.encode(&mut packet);
+                self.link.send_packet(&packet[..len])?;
+            }
+            Command::SetLayoutShape { slices, rows } => {
+                self.pending_layout.slices = slices;
+                self.pending_layout.rows = rows;
+            }
+            Command::SetChutePosition { slice, us } => {
+                match self.pending_layout.chute_positions.get_mut(slice as usize) {
+                    Some(p) => *p = us,
+                    None => eprintln!("no chute slice {}", slice),
+                }
+            }
+            Command::SetDropPosition { row, us } => {
+                match self.pending_layout.drop_positions.get_mut(row as usize) {
+                    Some(p) => *p = us,
+                    None => eprintln!("no drop row {}", row),
+                }
+            }
+            Command::SaveLayout => self.save_layout()?,
+        }
+        Ok(())
+    }
+
+    fn save_layout(&mut self) -> io::Result<()> {
+        let layout = self.pending_layout;
+        if let Err(e) = layout.validate() {
+            eprintln!("layout not saved: {:?}", e);
+            return Ok(());
+        }
+        if !layout.fits(self.settings.chutes_range(), self.settings.hopper_range()) {
+            eprintln!("layout not saved: position outside servo range");
+            return Ok(());
+        }
+        self.flash.save_layout(&layout)?;
+        // The router and stats are sized by the tube count, so a new shape waits for a
+        // restart.
+        if (layout.slices, layout.rows) == (self.layout.slices, self.layout.rows) {
+            self.layout = layout;
+            eprintln!("layout saved");
+        } else {
+            eprintln!(
+                "layout saved: {} slices x {} rows after a restart",
+                layout.slices, layout.rows
+            );
+        }
+        Ok(())
+    }
+
+    fn load_palette(&mut self, len: usize) -> io::Result<()> {
+        let state = self.upload.get(..len).unwrap_or(&[]);
+        if let Err(e) = self.policy.restore_state(state) {
+            eprintln!("uploaded palette rejected: {:?}", e);
+            return Ok(());
+        }
+        eprintln!(
+            "uploaded palette: {} entries, {} tubes",
+            self.policy.router().palette().len(),
+            self.policy.router().tubes().len()
+        );
+        self.unsaved = true;
+        self.save_learned()
+    }
+
+    fn save_learned(&mut self) -> io::Result<()> {
+        if !self.unsaved {
+            return Ok(());
+        }
+        let mut state = [0u8; ROUTER_STATE_MAX];
+        let len = self.policy.router().encode_state(&mut state);
+        self.flash.save_router_state(&state[..len])?;
+        self.unsaved = false;
+        self.last_save = Instant::now();
+        Ok(())
+    }
+
+    fn apply_settings(&mut self) {
+        self.policy.apply_settings(&self.settings);
+        if self.collection.is_some() && self.policy.reject_tube().is_none() {
+            eprintln!("no reject tube left, collection ended");
+            self.collection = None;
+            self.log(LogEvent::Collect { count: 0 });
+        }
+        self.pickups
+            .set_stall_after(self.settings.get(Setting::StallAfter));
+    }
+
+    fn status(&self) -> Status {
+        let router = self.policy.router();
+        let tubes = router.tubes();
+        Status {
+            running: self.running,
+            profile_id: self.policy.profile().id(),
+            palette_entries: router.palette().len() as u8,
+            tubes_used: tubes.len() as u8,
+            beads_sorted: tubes.iter().map(|t| t.count).sum(),
+            supply_mv: SUPPLY_MV,
+            supply_min_mv: SUPPLY_MV,
+        }
+    }
+
+    fn log(&mut self, event: LogEvent) {
+        let at_ms = self.booted.elapsed().as_millis() as u32;
+        self.event_log.push(at_ms, event);
+        self.telemetry
+            .record(Bounded::EventLog, self.event_log.len());
+    }
+
+    fn send_event(&mut self, event: DeviceEvent) -> io::Result<()> {
+        let mut packet = [0u8; EVENT_PACKET_LEN];
+        let len = event.encode(&mut packet);
+        self.link.send_packet(&packet[..len])
+    }
+
+    // Wait `ms` of machine time, reading the port meanwhile.
+    fn wait(&mut self, ms: u64) -> io::Result<()> {
+        let wait = Duration::from_millis(ms).div_f64(self.speed);
+        self.link.wait(wait)
+    }
+
+    // Move the servos together, within their ranges, and wait until both are there. Nothing
+    // moves during an emergency stop.
+    fn move_servos(&mut self, hopper: Option<u16>, chutes: Option<u16>) -> io::Result<()> {
+        if self.link.is_stopped() {
+            return Ok(());
+        }
+        let clamp = |us: u16, (min, max): (u16, u16)| us.clamp(min, max);
+        let hopper = hopper.map_or(self.hopper_us, |us| clamp(us, self.settings.hopper_range()));
+        let chutes = chutes.map_or(self.chutes_us, |us| clamp(us, self.settings.chutes_range()));
+        let travel = self
+            .hopper_us
+            .abs_diff(hopper)
+            .max(self.chutes_us.abs_diff(chutes));
+        (self.hopper_us, self.chutes_us) = (hopper, chutes);
+        self.wait(travel as u64 / SLEW_US_PER_MS)
+    }
+
+    // One sort cycle, sequenced by the cycle FSM as on the machine.
+    fn cycle(&mut self) -> io::Result<()> {
+        let cycle_start = Instant::now();
+        self.bead = None;
+        self.photographed = cycle_start;
+        let mut decided = cycle_start;
+        let mut routed = None;
+        let mut distance = None;
+        let mut action = self.fsm.start(CycleConfig {
+            pickup_us: self.settings.get(Setting::HopperPickup),
+            camera_us: self.settings.get(Setting::HopperCamera),
+            drop_us: self.settings.get(Setting::HopperDrop),
+            agitation_level: self.pickups.agitation_level(),
+            retakes: self.settings.get(Setting::Retakes) as u8,
+            layout: self.layout,
+        });
+        let end = loop {
+            let event = match action {
+                Action::MoveHopper { us, .. } => {
+                    self.move_servos(Some(us), None)?;
+                    Event::Done
+                }
+                Action::MoveToPose { hopper, chutes } => {
+                    self.move_servos(Some(hopper), Some(chutes))?;
+                    Event::Done
+                }
+                Action::Settle { ms } => {
+                    self.wait(ms as u64)?;
+                    Event::Done
+                }
+                Action::Capture { retake } => Event::Inspected(self.capture(retake)?),
+                Action::Classify => {
+                    distance = self
+                        .bead
+                        .as_ref()
+                        .and_then(|b| self.policy.match_distance(&b.average_color));
+                    routed = self.route();
+                    decided = Instant::now();
+                    if self.dataset_mode {
+                        let label = Label {
+                            tube: routed,
+                            bead: self.bead.as_ref().map(Measurement::from),
+                        };
+                        let frame = self.frame.clone();
+                        self.link.send_dataset(&label, &frame)?;
+                    }
+                    Event::Classified(self.policy.drop_tube(routed, self.extraction.is_some()))
+                }
+                Action::Finish(end) => break end,
+            };
+            action = self.fsm.next(if self.link.is_stopped() {
+                Event::Stopped
+            } else {
+                event
+            });
+        };
+
+        let tube = match end {
+            CycleEnd::Dropped { tube } => tube,
+            CycleEnd::Empty => {
+                self.stats.record(None, false, cycle_start);
+                let record = CycleRecord {
+                    uptime_ms: self.booted.elapsed().as_millis() as u32,
+                    result: CycleResult::Empty,
+                    tube: None,
+                    color: None,
+                    distance: None,
+                    pickup_ms: (self.photographed - cycle_start).as_millis() as u32,
+                    camera_ms: self.photographed.elapsed().as_millis() as u32,
+                    cycle_ms: cycle_start.elapsed().as_millis() as u32,
+                };
+                return self.link.send_record(&record);
+            }
+            CycleEnd::Failed(CycleError::Stopped) => {
+                eprintln!("emergency stop, cycle abandoned");
+                return Ok(());
+            }
+            CycleEnd::Failed(CycleError::NotInLayout(tube)) => {
+                eprintln!("tube {} is not in the layout", tube);
+                self.stats.record(None, true, cycle_start);
+                return Ok(());
+            }
+            CycleEnd::Failed(e) => {
+                eprintln!("sort cycle failed: {:?}", e);
+                return Ok(());
+            }
+        };
+        self.stats.record(routed, true, cycle_start);
+        let color = self.bead.map(|b| b.average_color);
+        self.log(match (routed, color) {
+            (Some(tube), Some(color)) => LogEvent::Sorted { tube, color },
+            _ => LogEvent::Rejected,
+        });
+        let record = CycleRecord {
+            uptime_ms: self.booted.elapsed().as_millis() as u32,
+            result: match routed {
+                Some(_) => CycleResult::Sorted,
+                None => CycleResult::Rejected,
+            },
+            tube: Some(tube),
+            color: color.map(|c| [c.r, c.g, c.b]),
+            distance,
+            pickup_ms: (self.photographed - cycle_start).as_millis() as u32,
+            camera_ms: (decided - self.photographed).as_millis() as u32,
+            cycle_ms: cycle_start.elapsed().as_millis() as u32,
+        };
+        self.link.send_record(&record)?;
+
+        let collected = routed.is_some() && self.collection.as_mut().is_some_and(|c| c.record());
+        if let Some(done) = self.collection.filter(|_| collected) {
+            eprintln!("collected {} beads", done.collected());
+            self.collection = None;
+            self.running = false;
+            self.send_event(DeviceEvent::TargetReached {
+                beads: done.collected(),
+            })?;
+        }
+        Ok(())
+    }
+
+    // Photograph the bead in the slot: the next frame of the source, or the same one again
+    // for a retake.
+    fn capture(&mut self, retake: u8) -> io::Result<Inspection> {
+        if retake == 0 {
+            self.frame = match self.first.take() {
+                Some(frame) => frame,
+                None => self
+                    .camera
+                    .next_frame()?
+                    .ok_or_else(|| io::Error::other("frame source ran out"))?,
+            };
+        }
+        self.photographed = Instant::now();
+        let frame = self.frame.clone();
+        self.link.send_frame(&frame)?;
+        if retake > 0 {
+            eprintln!("doubtful bead, retake {}", retake);
+        } else {
+            let empty = self.is_slot_empty();
+            match self.pickups.record(!empty) {
+                HopperEvent::Stalled => {
+                    eprintln!("hopper stalled, stopping");
+                    self.running = false;
+                    let empty_pickups = self.pickups.empty_streak();
+                    self.log(LogEvent::HopperStalled { empty_pickups });
+                    self.send_event(DeviceEvent::HopperStalled { empty_pickups })?;
+                }
+                HopperEvent::RefillNeeded => eprintln!("hopper needs a refill"),
+                HopperEvent::Refilled | HopperEvent::None => {}
+            }
+            if empty {
+                return Ok(Inspection::Empty);
+            }
+        }
+        let config = self.settings.analysis_config();
+        let background = self.background.as_ref();
+        self.bead =
+            analyze_image_debug(&frame, FRAME_WIDTH, FRAME_HEIGHT, None, background, config);
+        let doubtful = match (&self.bead, self.settings.retake_variance()) {
+            (None, _) => true,
+            (Some(b), Some(limit)) => b.variance > limit,
+            (Some(_), None) => false,
+        };
+        Ok(Inspection::Bead { doubtful })
+    }
+
+    fn is_slot_empty(&self) -> bool {
+        let Some(background) = &self.background else {
+            return false;
+        };
+        let config = self.settings.analysis_config();
+        let result = detect_empty(&self.frame, FRAME_WIDTH, FRAME_HEIGHT, background, config);
+        result.empty && result.confidence >= self.settings.get(Setting::EmptyConfidence) as u8
+    }
+
+    // The layout tube for the bead, or `None` if it is not sorted.
+    fn route(&mut self) -> Option<u8> {
+        let bead = self.bead?;
+        let decision =
+            self.policy
+                .decide(&bead, self.collection.as_ref(), self.extraction.as_ref());
+        if let Decision::Routed { .. } = decision {
+            self.unsaved = true;
+            let router = self.policy.router();
+            let (palette_len, tubes_len) = (router.palette().len(), router.tubes().len());
+            self.telemetry.record(Bounded::Palette, palette_len);
+            self.telemetry.record(Bounded::Tubes, tubes_len);
+        }
+        decision.tube()
+    }
+}
+
+/// Sorting counters and cycle timing since the start, as the firmware keeps them.
+struct Stats {
+    beads_sorted: u32,
+    empties: u32,
+    rejects: u32,
+    tube_counts: Vec<u32>,
+    cycles: u32,
+    cycle_total: Duration,
+}
+
+impl Stats {
+    fn new(tube_count: usize) -> Self {
+        Self {
+            beads_sorted: 0,
+            empties: 0,
+            rejects: 0,
+            tube_counts: vec![0; tube_count.min(MAX_TUBES)],
+            cycles: 0,
+            cycle_total: Duration::ZERO,
+        }
+    }
+
+    // Count a cycle that started at `start`: sorted into a tube, rejected after picking up a
+    // bead, or empty.
+    fn record(&mut self, sorted: Option<u8>, picked_up: bool, start: Instant) {
+        self.cycles += 1;
+        self.cycle_total += start.elapsed();
+        match sorted {
+            Some(tube) => {
+                self.beads_sorted += 1;
+                if let Some(count) = self.tube_counts.get_mut(tube as usize) {
+                    *count += 1;
+                }
+            }
+            None if picked_up => self.rejects += 1,
+            None => self.empties += 1,
+        }
+    }
+
+    fn summary(&self, booted: Instant) -> Summary {
+        Summary {
+            uptime_secs: booted.elapsed().as_secs() as u32,
+            beads_sorted: self.beads_sorted,
+            empties: self.empties,
+            rejects: self.rejects,
+            avg_cycle_ms: match self.cycles {
+                0 => 0,
+                n => (self.cycle_total.as_millis() / n as u128) as u32,
+            },
+            // Nothing overlaps: the next pickup waits for the drop.
+            avg_overlap_ms: 0,
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! What the virtual sorter keeps across restarts, in place of the firmware's flash.
+//!
+//! The file holds the config sector, laid out as in `sorter_logic::config`, followed by the
+//! learned palette and tube map. Without a file everything is kept in memory, as if the
+//! flash were blank at every start.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use sorter_logic::config::{self, CONFIG_BYTES, LAYOUT_OFFSET, SETTINGS_OFFSET};
+use sorter_logic::layout::{TubeLayout, LAYOUT_BYTES};
+use sorter_logic::profile::Profile;
+use sorter_logic::settings::{Settings, SETTINGS_BYTES};
+
+pub struct Flash {
+    path: Option<PathBuf>,
+    sector: [u8; CONFIG_BYTES],
+    router_state: Vec<u8>,
+}
+
+impl Flash {
+    /// Read `path`, bringing a sector from older firmware up to date, or start blank if it
+    /// does not exist yet.
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut flash = Self {
+            path,
+            sector: [0xFF; CONFIG_BYTES],
+            router_state: Vec::new(),
+        };
+        let bytes = match &flash.path {
+            Some(path) if path.exists() => fs::read(path)?,
+            _ => return Ok(flash),
+        };
+        let Some(sector) = bytes.get(..CONFIG_BYTES) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shorter than a config sector",
+            ));
+        };
+        flash.sector.copy_from_slice(sector);
+        flash.router_state = bytes[CONFIG_BYTES..].to_vec();
+        match config::migrate(&mut flash.sector) {
+            Ok(None) => {}
+            Ok(Some(from)) => {
+                eprintln!("config: migrated from version {}", from);
+                flash.write()?;
+            }
+            Err(e) => eprintln!("config: {:?}", e),
+        }
+        Ok(flash)
+    }
+
+    /// The saved layout, or the default if there is none or it does not fit the servos.
+    pub fn layout(&self, chute_range: (u16, u16), hopper_range: (u16, u16)) -> TubeLayout {
+        TubeLayout::from_bytes(&self.sector[LAYOUT_OFFSET..])
+            .ok()
+            .filter(|layout| layout.fits(chute_range, hopper_range))
+            .unwrap_or_default()
+    }
+
+    pub fn save_layout(&mut self, layout: &TubeLayout) -> io::Result<()> {
+        self.sector[LAYOUT_OFFSET..LAYOUT_OFFSET + LAYOUT_BYTES]
+            .copy_from_slice(&layout.to_bytes());
+        self.save()
+    }
+
+    pub fn profile(&self) -> Profile {
+        config::profile(&self.sector).unwrap_or_default()
+    }
+
+    pub fn save_profile(&mut self, profile: Profile) -> io::Result<()> {
+        config::set_profile(&mut self.sector, profile);
+        self.save()
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings::from_bytes(&self.sector[SETTINGS_OFFSET..]).unwrap_or_default()
+    }
+
+    pub fn save_settings(&mut self, settings: &Settings) -> io::Result<()> {
+        self.sector[SETTINGS_OFFSET..SETTINGS_OFFSET + SETTINGS_BYTES]
+            .copy_from_slice(&settings.to_bytes());
+        self.save()
+    }
+
+    /// The saved palette and tube map (`sorter_logic::router::TubeRouter::encode_state`);
+    /// empty if none was saved.
+    pub fn router_state(&self) -> &[u8] {
+        &self.router_state
+    }
+
+    pub fn save_router_state(&mut self, state: &[u8]) -> io::Result<()> {
+        self.router_state = state.to_vec();
+        self.write()
+    }
+
+    /// Forget everything, as a factory reset erases the flash.
+    pub fn erase(&mut self) -> io::Result<()> {
+        self.sector = [0xFF; CONFIG_BYTES];
+        self.router_state.clear();
+        self.write()
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        config::stamp(&mut self.sector);
+        self.write()
+    }
+
+    fn write(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => fs::write(path, [&self.sector[..], &self.router_state].concat()),
+            None => Ok(()),
+        }
+    }
+}
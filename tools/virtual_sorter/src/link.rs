@@ -0,0 +1,201 @@
+//! The device end of the data port, as the firmware's `protocol` module and command reader
+//! see it.
+//!
+//! The host opens the other end of a pseudo-terminal. Nothing is sent while no host has it
+//! open, as the firmware sends nothing while the host does not hold DTR, and a host that goes
+//! away leaves the parser and the frame flow control to start over for the next one.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+use sorter_logic::dataset::{self, Label};
+use sorter_protocol::{image, Command, CycleRecord, Parser, RECORD_PACKET_MAX};
+
+/// How long a frame waits for the host to acknowledge an earlier one before the device
+/// assumes the acks were lost and sends anyway.
+const FRAME_ACK_TIMEOUT: Duration = Duration::from_millis(500);
+// How long a write may wait on a host that is not reading before the rest of the packet is
+// dropped, as bytes would be on a stalled USB link.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(200);
+
+pub struct Link {
+    port: Box<dyn SerialPort>,
+    parser: Parser,
+    commands: VecDeque<Command>,
+    // A host has the port open.
+    connected: bool,
+    // An emergency stop arrived and `Command::Start` has not released it.
+    stopped: bool,
+    // Frame flow control; a window of 0 is off.
+    window: u8,
+    in_flight: u8,
+}
+
+impl Link {
+    pub fn new(port: Box<dyn SerialPort>) -> Self {
+        Self {
+            port,
+            parser: Parser::new(),
+            commands: VecDeque::new(),
+            connected: false,
+            stopped: false,
+            window: 0,
+            in_flight: 0,
+        }
+    }
+
+    /// Read what the host sends for `wait`, or less once something arrives. Frame acks and
+    /// windows are taken care of here, and an emergency stop takes effect right away; other
+    /// commands queue for [`Link::next_command`].
+    pub fn poll(&mut self, wait: Duration) -> io::Result<()> {
+        self.port.set_timeout(wait)?;
+        let mut buf = [0u8; 64];
+        match self.port.read(&mut buf) {
+            Ok(n) => {
+                self.connected = true;
+                for &byte in &buf[..n] {
+                    if let Some(command) = self.parser.push(byte) {
+                        self.received(command);
+                    }
+                }
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                self.connected = true;
+                Ok(())
+            }
+            // No host has the port open.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                self.disconnected();
+                std::thread::sleep(wait);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Keep reading the host for `wait`.
+    pub fn wait(&mut self, wait: Duration) -> io::Result<()> {
+        let until = Instant::now() + wait;
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(());
+            }
+            self.poll(left)?;
+        }
+    }
+
+    // Start over for the next host, which turns flow control on itself.
+    fn disconnected(&mut self) {
+        if self.connected {
+            eprintln!("host disconnected");
+            self.connected = false;
+            self.parser = Parser::new();
+            self.window = 0;
+            self.in_flight = 0;
+        }
+    }
+
+    fn received(&mut self, command: Command) {
+        match command {
+            Command::FrameAck => self.in_flight = self.in_flight.saturating_sub(1),
+            Command::FrameWindow(window) => {
+                self.window = window;
+                self.in_flight = 0;
+                eprintln!("frame window {}", window);
+            }
+            _ => {
+                if command == Command::Stop {
+                    self.stopped = true;
+                }
+                self.commands.push_back(command);
+            }
+        }
+    }
+
+    pub fn next_command(&mut self) -> Option<Command> {
+        self.commands.pop_front()
+    }
+
+    /// True while an emergency stop holds the servos.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Let the servos move again after an emergency stop.
+    pub fn release_stop(&mut self) {
+        self.stopped = false;
+    }
+
+    /// Write `packet`. Does nothing while no host has the port open.
+    pub fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        if !self.connected {
+            return Ok(());
+        }
+        self.port.set_timeout(WRITE_TIMEOUT)?;
+        match self.port.write_all(packet) {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                eprintln!("host not reading, packet cut short");
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                self.disconnected();
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Send what a sort cycle did as a `sorter_protocol::record` packet.
+    pub fn send_record(&mut self, record: &CycleRecord) -> io::Result<()> {
+        let mut packet = [0u8; RECORD_PACKET_MAX];
+        let len = record.encode(&mut packet);
+        self.send_packet(&packet[..len])
+    }
+
+    /// Send a frame as a `sorter_protocol::image` packet. With flow control on, first waits
+    /// for the host to make room.
+    pub fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if !self.connected {
+            return Ok(());
+        }
+        self.take_frame_credit()?;
+        let packet = [
+            &image::header(frame.len() as u16)[..],
+            frame,
+            &image::crc(frame),
+        ]
+        .concat();
+        self.send_packet(&packet)
+    }
+
+    /// Send a frame and what the sorter made of it as one `sorter_logic::dataset` packet.
+    pub fn send_dataset(&mut self, label: &Label, frame: &[u8]) -> io::Result<()> {
+        if !self.connected {
+            return Ok(());
+        }
+        self.take_frame_credit()?;
+        let header = dataset::header(label, frame.len() as u16);
+        let packet = [&header[..], frame, &dataset::crc(&header, frame)].concat();
+        self.send_packet(&packet)
+    }
+
+    // Wait for room in the host's window and count one more frame in flight.
+    fn take_frame_credit(&mut self) -> io::Result<()> {
+        let until = Instant::now() + FRAME_ACK_TIMEOUT;
+        while self.window != 0 && self.in_flight >= self.window {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                eprintln!("no frame ack from the host, resetting the frame window");
+                self.in_flight = 0;
+                break;
+            }
+            self.poll(left)?;
+        }
+        self.in_flight = self.in_flight.saturating_add(1);
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+//! A bead sorter without the hardware, for developing and testing the host tools.
+//!
+//! It serves the firmware's end of the USB data port on a pseudo-terminal, so image_saver,
+//! sorterctl and anything else that opens a serial port can be pointed at it instead of a
+//! sorter.
+
+use clap::Parser;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sorter_host::frame_source::{
+    load_png, FrameSource, PngDirSource, SessionLogSource, SyntheticSource,
+};
+
+mod device;
+mod flash;
+mod link;
+
+use crate::device::Device;
+use crate::flash::Flash;
+use crate::link::Link;
+
+/// Run a simulated sorter on a pseudo-terminal. Point the host tools at the port it prints
+/// (`sorterctl --port <port> status`, `image_saver --port <port>`), or at `--link`.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Photograph a folder of saved captures or the frames of a `session_<id>.csv` log, in
+    /// turn, instead of generated beads.
+    #[arg(long)]
+    frames: Option<String>,
+
+    /// A capture of the empty hopper slot, for telling empty pickups from beads. Generated
+    /// beads come with their own.
+    #[arg(long, requires = "frames")]
+    background: Option<String>,
+
+    /// Keep the settings, layout and learned palette in this file across restarts, as the
+    /// sorter keeps them in flash.
+    #[arg(long)]
+    flash: Option<PathBuf>,
+
+    /// Also make the port reachable at this path, which stays the same between runs.
+    #[arg(long)]
+    link: Option<PathBuf>,
+
+    /// Run this many times faster than the machine.
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+}
+
+// The colors image_saver's synthetic source uses, and its empty slot.
+const BACKGROUND: (u8, u8, u8) = (200, 200, 190);
+const BEADS: [Option<(u8, u8, u8)>; 4] = [
+    Some((200, 20, 30)),
+    Some((30, 60, 200)),
+    None,
+    Some((240, 240, 240)),
+];
+
+fn main() {
+    let args = Args::parse();
+    if args.speed.is_nan() || args.speed <= 0.0 {
+        eprintln!("--speed must be more than 0");
+        std::process::exit(2);
+    }
+
+    let (source, background) = match (open_source(&args), load_background(&args)) {
+        (Ok(source), Ok(background)) => (source, background),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Failed to load frames: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let flash = Flash::open(args.flash.clone()).unwrap_or_else(|e| {
+        eprintln!(
+            "Failed to open {}: {}",
+            args.flash.as_ref().unwrap().display(),
+            e
+        );
+        std::process::exit(1);
+    });
+    let port = open_port(args.link.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Failed to open a pseudo-terminal: {}", e);
+        std::process::exit(1);
+    });
+
+    let mut device = Device::new(
+        Link::new(port),
+        flash,
+        source,
+        background.as_deref(),
+        args.speed,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to load frames: {}", e);
+        std::process::exit(1);
+    });
+    loop {
+        if let Err(e) = device.step() {
+            eprintln!("Virtual sorter stopped: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Where the camera's frames come from.
+fn open_source(args: &Args) -> io::Result<Box<dyn FrameSource + Send>> {
+    let Some(path) = &args.frames else {
+        let source = SyntheticSource::new(BACKGROUND, BEADS.to_vec(), 1);
+        return Ok(Box::new(source));
+    };
+    let path = Path::new(path);
+    Ok(if path.is_dir() {
+        Box::new(PngDirSource::new(path)?)
+    } else {
+        Box::new(SessionLogSource::new(path)?)
+    })
+}
+
+// The empty slot, if known.
+fn load_background(args: &Args) -> io::Result<Option<Vec<u8>>> {
+    match (&args.frames, &args.background) {
+        (None, _) => SyntheticSource::new(BACKGROUND, vec![None], 1).next_frame(),
+        (Some(_), Some(path)) => load_png(Path::new(path)).map(Some),
+        (Some(_), None) => Ok(None),
+    }
+}
+
+// The device end of a new pseudo-terminal. The host end is left closed until a host opens it,
+// which is how the link tells whether one is there.
+#[cfg(unix)]
+fn open_port(link: Option<&Path>) -> io::Result<Box<dyn serialport::SerialPort>> {
+    use serialport::{SerialPort, TTYPort};
+
+    let (device, host) = TTYPort::pair()?;
+    let name = host
+        .name()
+        .ok_or_else(|| io::Error::other("pseudo-terminal has no name"))?;
+    drop(host);
+    if let Some(link) = link {
+        if link
+            .symlink_metadata()
+            .is_ok_and(|m| m.file_type().is_symlink())
+        {
+            std::fs::remove_file(link)?;
+        }
+        std::os::unix::fs::symlink(&name, link)?;
+        println!("Virtual sorter on {} ({})", name, link.display());
+    } else {
+        println!("Virtual sorter on {}", name);
+    }
+    Ok(Box::new(device))
+}
+
+#[cfg(not(unix))]
+fn open_port(_link: Option<&Path>) -> io::Result<Box<dyn serialport::SerialPort>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pseudo-terminals need a Unix host",
+    ))
+}
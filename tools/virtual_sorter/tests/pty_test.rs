@@ -0,0 +1,200 @@
+//! Drives the virtual sorter through its pseudo-terminal, as the host tools do.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command as Process, Stdio};
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+use sorter_host::link::{send, send_and_wait, wait_for, REPLY_TIMEOUT};
+use sorter_logic::event_log::{self, LogEvent, LOG_ENTRY_BYTES};
+use sorter_logic::settings::{Setting, Settings, MAX_RETAKES};
+use sorter_protocol::{
+    image, CollectTarget, Command, ServoId, ServoPosition, Status, FRAME_BYTES, FRAME_MAGIC,
+    LOG_MAGIC, SERVO_MAGIC, SERVO_PACKET_LEN, SETTINGS_MAGIC, STATUS_MAGIC, STATUS_PACKET_LEN,
+};
+
+// A running virtual sorter, stopped when dropped.
+struct Sorter {
+    process: Child,
+    port: Box<dyn SerialPort>,
+}
+
+impl Sorter {
+    // Start one on the port `link` in `dir`, keeping its flash there, and open the port.
+    fn start(dir: &Path) -> Self {
+        let link = dir.join("port");
+        let process = Process::new(env!("CARGO_BIN_EXE_virtual_sorter"))
+            .arg("--link")
+            .arg(&link)
+            .arg("--flash")
+            .arg(dir.join("flash.bin"))
+            .args(["--speed", "20"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        // Until the sorter is up, the link is missing or left over from the last one.
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let port = loop {
+            let port = serialport::new(link.to_string_lossy(), 115_200)
+                .timeout(Duration::from_millis(100))
+                .open();
+            if let Ok(port) = port {
+                break port;
+            }
+            assert!(Instant::now() < deadline, "no port at {}", link.display());
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        Self { process, port }
+    }
+
+    fn send(&mut self, command: Command) {
+        send(&mut self.port, command).unwrap();
+    }
+
+    fn status(&mut self) -> Status {
+        send_and_wait(&mut self.port, Command::QueryStatus, &STATUS_MAGIC).unwrap();
+        let mut body = [0u8; STATUS_PACKET_LEN - 4];
+        self.port.read_exact(&mut body).unwrap();
+        Status::decode(&body).unwrap()
+    }
+
+    fn servo(&mut self, command: Command) -> ServoPosition {
+        send_and_wait(&mut self.port, command, &SERVO_MAGIC).unwrap();
+        let mut body = [0u8; SERVO_PACKET_LEN - 4];
+        self.port.read_exact(&mut body).unwrap();
+        ServoPosition::decode(&body).unwrap()
+    }
+
+    fn settings(&mut self) -> Settings {
+        send_and_wait(&mut self.port, Command::GetSettings, &SETTINGS_MAGIC).unwrap();
+        let mut count = [0u8; 1];
+        self.port.read_exact(&mut count).unwrap();
+        let mut body = vec![0u8; 1 + count[0] as usize * 2];
+        body[0] = count[0];
+        self.port.read_exact(&mut body[1..]).unwrap();
+        Settings::decode(&body).unwrap()
+    }
+
+    fn events(&mut self) -> Vec<LogEvent> {
+        send_and_wait(&mut self.port, Command::DumpLog, &LOG_MAGIC).unwrap();
+        let mut count = [0u8; 1];
+        self.port.read_exact(&mut count).unwrap();
+        let mut body = vec![0u8; 1 + count[0] as usize * LOG_ENTRY_BYTES];
+        body[0] = count[0];
+        self.port.read_exact(&mut body[1..]).unwrap();
+        event_log::decode(&body).unwrap().map(|e| e.event).collect()
+    }
+}
+
+impl Drop for Sorter {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+// An empty directory of its own for each test.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("virtual_sorter_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_status_servo_and_frame() {
+    let dir = scratch_dir("status");
+    let mut sorter = Sorter::start(&dir);
+
+    // Sorting from power-up.
+    assert!(sorter.status().running);
+
+    // Jogging a servo hands the machine to the host.
+    let jogged = sorter.servo(Command::SetServo {
+        servo: ServoId::Hopper,
+        us: 1600,
+    });
+    assert_eq!(
+        jogged,
+        ServoPosition {
+            servo: ServoId::Hopper,
+            us: 1600
+        }
+    );
+    assert_eq!(sorter.servo(Command::GetServo(ServoId::Hopper)).us, 1600);
+    assert!(!sorter.status().running);
+
+    // Nothing is sorting any more, so the next frame is the one asked for.
+    sorter.send(Command::RequestFrame);
+    wait_for(
+        &mut sorter.port,
+        &FRAME_MAGIC,
+        Some(Instant::now() + REPLY_TIMEOUT),
+    )
+    .unwrap();
+    let mut packet = FRAME_MAGIC.to_vec();
+    packet.resize(image::PACKET_LEN, 0);
+    sorter.port.read_exact(&mut packet[4..]).unwrap();
+    assert_eq!(image::decode(&packet).map(<[u8]>::len), Some(FRAME_BYTES));
+
+    drop(sorter);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_settings_round_trip_and_survive_a_restart() {
+    let dir = scratch_dir("settings");
+    let mut sorter = Sorter::start(&dir);
+    assert_eq!(sorter.settings(), Settings::default());
+
+    let retakes = Setting::Retakes.id();
+    sorter.send(Command::SetSetting {
+        id: retakes,
+        value: 2,
+    });
+    // Out of range, so not taken.
+    let value = MAX_RETAKES + 1;
+    sorter.send(Command::SetSetting { id: retakes, value });
+    assert_eq!(sorter.settings().get(Setting::Retakes), 2);
+
+    // A tuned value lasts until the restart; the saved one is back after it.
+    sorter.send(Command::TuneSetting {
+        id: retakes,
+        value: 1,
+    });
+    assert_eq!(sorter.settings().get(Setting::Retakes), 1);
+    drop(sorter);
+    let mut sorter = Sorter::start(&dir);
+    assert_eq!(sorter.settings().get(Setting::Retakes), 2);
+
+    drop(sorter);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_collect_needs_a_reject_tube() {
+    let dir = scratch_dir("collect");
+    let mut sorter = Sorter::start(&dir);
+    sorter.send(Command::Stop);
+    let collect = Command::Collect {
+        target: CollectTarget::PaletteEntry(0),
+        count: 5,
+    };
+    let collecting = |events: Vec<LogEvent>| events.contains(&LogEvent::Collect { count: 5 });
+
+    // The beads not collected would land in a tube being sorted into.
+    sorter.send(collect);
+    assert!(!collecting(sorter.events()));
+
+    sorter.send(Command::TuneSetting {
+        id: Setting::RejectTube.id(),
+        value: 0,
+    });
+    sorter.send(collect);
+    assert!(collecting(sorter.events()));
+
+    drop(sorter);
+    std::fs::remove_dir_all(&dir).unwrap();
+}